@@ -0,0 +1,708 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Point-in-time backup of the disks.
+//!
+//! The backup job copies the disk to the target file cluster by cluster in its
+//! own thread. The block device calls [`CopyBeforeWrite::before_write`] before
+//! submitting a request which changes the disk, the old data of the clusters
+//! not copied yet is copied to the target first, so the target keeps the
+//! content of the disk at the time the job starts.
+//!
+//! The incremental backup only copies the clusters marked in the dirty bitmap,
+//! the other clusters are treated as copied when the job starts.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+use once_cell::sync::Lazy;
+
+use crate::dirty_bitmap::{block_dirty_bitmaps, BlockDirtyBitmaps, DirtyBitmap};
+use crate::file::SyncFile;
+use crate::BLOCK_DEVICES;
+use machine_manager::config::{DiskFormat, DriveFile, VmConfig};
+use machine_manager::event;
+use machine_manager::qmp::{qmp_schema, QmpChannel};
+use util::bitmap::Bitmap;
+
+/// Size of the cluster, which is the unit to copy the disk.
+const BACKUP_CLUSTER_SIZE: u64 = 64 * 1024;
+/// Max interval to check the cancellation while the job is limited by the speed.
+const BACKUP_SLEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Hook of the block device to copy the old data before it is overwritten.
+#[derive(Default)]
+pub struct CopyBeforeWrite {
+    /// The backup job running on the block device.
+    job: Mutex<Option<Arc<BackupJob>>>,
+}
+
+impl CopyBeforeWrite {
+    /// Copy the clusters in the range to the target of the running backup job,
+    /// called before the range of the disk is written, discarded or zeroed.
+    ///
+    /// If the copy fails, the backup job fails and the write goes on, as the
+    /// guest should not be affected by the backup.
+    pub fn before_write(&self, offset: u64, len: u64) {
+        let job = match self.job.lock().unwrap().clone() {
+            Some(job) => job,
+            None => return,
+        };
+        if let Err(e) = job.copy_range(offset, len) {
+            error!("Backup job {} failed to copy before write: {:?}", job.id, e);
+            job.fail(format!("{:?}", e));
+        }
+    }
+
+    /// Cancel the running backup job, the guest writes are not delayed anymore.
+    pub(crate) fn cancel(&self) {
+        if let Some(job) = self.job.lock().unwrap().take() {
+            job.cancelled.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Detach the backup job `job` from the block device.
+    fn detach(&self, job: &Arc<BackupJob>) {
+        let mut locked_job = self.job.lock().unwrap();
+        if matches!(locked_job.as_ref(), Some(j) if Arc::ptr_eq(j, job)) {
+            *locked_job = None;
+        }
+    }
+}
+
+/// The dirty ranges copied by the incremental backup job.
+struct IncrementalBitmap {
+    /// Name of the dirty bitmap.
+    name: String,
+    /// The dirty ranges when the job starts.
+    bitmap: DirtyBitmap,
+    /// The dirty bitmaps of the block device.
+    dirty_bitmaps: Arc<BlockDirtyBitmaps>,
+}
+
+impl IncrementalBitmap {
+    /// The dirty ranges are not backed up, merge them back to the bitmap.
+    fn restore(&self) {
+        self.dirty_bitmaps.restore(&self.name, &self.bitmap);
+    }
+}
+
+/// The backup job of a block device.
+struct BackupJob {
+    /// Id of the job.
+    id: String,
+    /// The drive file of the block device.
+    source: SyncFile,
+    /// The target file.
+    target: SyncFile,
+    /// Length of the disk to copy.
+    len: u64,
+    /// Speed limit in bytes per second, 0 means unlimited.
+    speed: u64,
+    /// Don't write the zero clusters to the target, as the target is created empty.
+    skip_zeroes: bool,
+    /// Clusters which have been copied to the target.
+    copied: Mutex<Bitmap<u64>>,
+    /// Length of the copied data, including the clean clusters skipped by
+    /// the incremental backup.
+    offset: AtomicU64,
+    cancelled: AtomicBool,
+    /// Error of copying before write.
+    error: Mutex<Option<String>>,
+    /// The hook of the block device, which the job is attached to.
+    cbw: Arc<CopyBeforeWrite>,
+    /// The dirty bitmap of the incremental backup, None for the full backup.
+    incremental: Option<Arc<IncrementalBitmap>>,
+}
+
+impl BackupJob {
+    fn new(
+        id: &str,
+        source: SyncFile,
+        target: SyncFile,
+        speed: u64,
+        skip_zeroes: bool,
+        cbw: Arc<CopyBeforeWrite>,
+        incremental: Option<Arc<IncrementalBitmap>>,
+    ) -> Result<Self> {
+        let len = source.file_size()?;
+        target.set_len(len)?;
+        let cluster_num = len.div_ceil(BACKUP_CLUSTER_SIZE);
+        let mut copied = Bitmap::new(cluster_num as usize / u64::BITS as usize + 1);
+        let mut offset = 0;
+        if let Some(inc) = incremental.as_ref() {
+            for index in 0..cluster_num {
+                let start = index * BACKUP_CLUSTER_SIZE;
+                let count = BACKUP_CLUSTER_SIZE.min(len - start);
+                if !inc.bitmap.is_dirty(start, count) {
+                    copied.set(index as usize)?;
+                    offset += count;
+                }
+            }
+        }
+        Ok(BackupJob {
+            id: id.to_string(),
+            source,
+            target,
+            len,
+            speed,
+            skip_zeroes,
+            copied: Mutex::new(copied),
+            offset: AtomicU64::new(offset),
+            cancelled: AtomicBool::new(false),
+            error: Mutex::new(None),
+            cbw,
+            incremental,
+        })
+    }
+
+    fn cluster_num(&self) -> usize {
+        self.len.div_ceil(BACKUP_CLUSTER_SIZE) as usize
+    }
+
+    fn copy_cluster(&self, copied: &mut Bitmap<u64>, index: usize) -> Result<()> {
+        if copied.contain(index)? {
+            return Ok(());
+        }
+        let start = index as u64 * BACKUP_CLUSTER_SIZE;
+        let count = BACKUP_CLUSTER_SIZE.min(self.len - start);
+        let mut buf = vec![0_u8; count as usize];
+        self.source.read_at(&mut buf, start)?;
+        if !self.skip_zeroes || buf.iter().any(|b| *b != 0) {
+            self.target.write_at(&buf, start)?;
+        }
+        copied.set(index)?;
+        self.offset.fetch_add(count, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn copy_range(&self, offset: u64, len: u64) -> Result<()> {
+        if len == 0 || offset >= self.len {
+            return Ok(());
+        }
+        let end = self.len.min(offset.saturating_add(len));
+        let mut copied = self.copied.lock().unwrap();
+        let first = offset / BACKUP_CLUSTER_SIZE;
+        let last = (end - 1) / BACKUP_CLUSTER_SIZE;
+        for index in first..=last {
+            self.copy_cluster(&mut copied, index as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Fail the job for the error of copying before write.
+    fn fail(self: &Arc<Self>, err: String) {
+        self.error.lock().unwrap().get_or_insert(err);
+        self.cbw.detach(self);
+    }
+
+    /// Wait until the copied data doesn't exceed the speed limit.
+    fn throttle(&self, start: Instant, copied: u64) {
+        if self.speed == 0 {
+            return;
+        }
+        let expected = Duration::from_secs_f64(copied as f64 / self.speed as f64);
+        while !self.cancelled.load(Ordering::SeqCst) {
+            let elapsed = start.elapsed();
+            if elapsed >= expected {
+                break;
+            }
+            thread::sleep(BACKUP_SLEEP_INTERVAL.min(expected - elapsed));
+        }
+    }
+
+    fn run(&self) -> Result<()> {
+        let start = Instant::now();
+        let mut copied_len = 0;
+        for index in 0..self.cluster_num() {
+            if self.cancelled.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            if let Some(e) = self.error.lock().unwrap().as_ref() {
+                bail!("{}", e);
+            }
+            let mut copied = self.copied.lock().unwrap();
+            // The clusters skipped or copied before write are not limited by the speed.
+            if copied.contain(index)? {
+                continue;
+            }
+            self.copy_cluster(&mut copied, index)?;
+            drop(copied);
+            copied_len += BACKUP_CLUSTER_SIZE;
+            self.throttle(start, copied_len);
+        }
+        self.target.sync()
+    }
+
+    /// Finish the job with the result `res` and report it by the event.
+    fn complete(self: &Arc<Self>, res: Result<()>) {
+        self.cbw.detach(self);
+        BLOCK_JOBS.lock().unwrap().remove(&self.id);
+
+        let offset = self.offset.load(Ordering::SeqCst);
+        let error = match res {
+            Err(e) => Some(format!("{:?}", e)),
+            Ok(()) => self.error.lock().unwrap().clone(),
+        };
+        let cancelled = self.cancelled.load(Ordering::SeqCst);
+        if error.is_some() || cancelled {
+            if let Some(inc) = self.incremental.as_ref() {
+                inc.restore();
+            }
+        }
+        if error.is_none() && cancelled {
+            info!("Backup job {} is cancelled", self.id);
+            let cancelled = qmp_schema::BlockJobCancelled {
+                job_type: "backup".to_string(),
+                device: self.id.clone(),
+                len: self.len,
+                offset,
+                speed: self.speed,
+            };
+            event!(BlockJobCancelled; cancelled);
+            return;
+        }
+        match error.as_ref() {
+            Some(e) => error!("Backup job {} failed: {}", self.id, e),
+            None => info!("Backup job {} is completed", self.id),
+        }
+        let completed = qmp_schema::BlockJobCompleted {
+            job_type: "backup".to_string(),
+            device: self.id.clone(),
+            len: self.len,
+            offset,
+            speed: self.speed,
+            error,
+        };
+        event!(BlockJobCompleted; completed);
+    }
+
+    fn info(&self) -> qmp_schema::BlockJobInfo {
+        qmp_schema::BlockJobInfo {
+            job_type: "backup".to_string(),
+            device: self.id.clone(),
+            len: self.len,
+            offset: self.offset.load(Ordering::SeqCst),
+            busy: true,
+            paused: false,
+            speed: self.speed,
+            io_status: "ok".to_string(),
+            ready: false,
+        }
+    }
+}
+
+/// The running block jobs, indexed by the job id.
+static BLOCK_JOBS: Lazy<Mutex<BTreeMap<String, Arc<BackupJob>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Start the backup job of the block device `args.device`.
+pub fn qmp_drive_backup(
+    args: &qmp_schema::DriveBackupArgument,
+    drive_files: &Arc<Mutex<HashMap<String, DriveFile>>>,
+) -> Result<()> {
+    match args.sync.as_str() {
+        "full" if args.bitmap.is_some() => bail!("Bitmap is only used by incremental sync"),
+        "full" => (),
+        "incremental" if args.bitmap.is_none() => bail!("Incremental sync requires a bitmap"),
+        "incremental" => (),
+        s => bail!(
+            "Unsupported sync mode {}, only full and incremental are supported",
+            s
+        ),
+    }
+    if let Some(format) = args.format.as_ref() {
+        if format != "raw" {
+            bail!(
+                "Unsupported target format {}, only raw is supported",
+                format
+            );
+        }
+    }
+    let skip_zeroes = match args.mode.as_deref().unwrap_or("absolute-paths") {
+        "absolute-paths" => true,
+        "existing" => false,
+        m => bail!("Invalid mode {} of the target file", m),
+    };
+    let id = args.job_id.as_ref().unwrap_or(&args.device);
+    if BLOCK_JOBS.lock().unwrap().contains_key(id) {
+        bail!("Block job {} already exists", id);
+    }
+
+    let (prop, cbw) = {
+        let locked_devices = BLOCK_DEVICES.lock().unwrap();
+        let info = locked_devices
+            .get(&args.device)
+            .with_context(|| format!("Block device {} not found", args.device))?;
+        (info.prop.clone(), info.backup.clone())
+    };
+    let cbw =
+        cbw.with_context(|| format!("Block device {} doesn't support backup", args.device))?;
+    if prop.format != DiskFormat::Raw {
+        bail!(
+            "Block device {} with {} format can not be backed up, only raw is supported",
+            args.device,
+            prop.format
+        );
+    }
+    let file = VmConfig::fetch_drive_file(&drive_files.lock().unwrap(), &prop.path)
+        .with_context(|| format!("Block device {} has no drive file", args.device))?;
+    let source = SyncFile::new(file, prop.req_align, prop.buf_align);
+
+    let target = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(skip_zeroes)
+        .truncate(skip_zeroes)
+        .open(&args.target)
+        .with_context(|| format!("Failed to open backup target {}", args.target))?;
+    let target = SyncFile::new(target, 1, 1);
+
+    let mut locked_job = cbw.job.lock().unwrap();
+    if locked_job.is_some() {
+        bail!("Block device {} is being backed up", args.device);
+    }
+    // The bitmap is cleared to track the writes after the point-in-time of the
+    // backup. The block device marks the bitmap after calling the hook, so the
+    // writes copied before write by the job are always tracked by the bitmap.
+    let incremental = match args.bitmap.as_ref() {
+        Some(name) => {
+            let dirty_bitmaps = block_dirty_bitmaps(&args.device)?;
+            let bitmap = dirty_bitmaps.take(name)?;
+            Some(Arc::new(IncrementalBitmap {
+                name: name.clone(),
+                bitmap,
+                dirty_bitmaps,
+            }))
+        }
+        None => None,
+    };
+    let job = BackupJob::new(
+        id,
+        source,
+        target,
+        args.speed.unwrap_or(0),
+        skip_zeroes,
+        cbw.clone(),
+        incremental.clone(),
+    );
+    let job = match job {
+        Ok(job) => Arc::new(job),
+        Err(e) => {
+            if let Some(inc) = incremental.as_ref() {
+                inc.restore();
+            }
+            return Err(e);
+        }
+    };
+    {
+        let mut locked_jobs = BLOCK_JOBS.lock().unwrap();
+        if locked_jobs.contains_key(id) {
+            if let Some(inc) = job.incremental.as_ref() {
+                inc.restore();
+            }
+            bail!("Block job {} already exists", id);
+        }
+        locked_jobs.insert(id.clone(), job.clone());
+        *locked_job = Some(job.clone());
+    }
+    drop(locked_job);
+
+    let cloned_job = job.clone();
+    if let Err(e) = thread::Builder::new()
+        .name(format!("backup-{}", id))
+        .spawn(move || {
+            let res = cloned_job.run();
+            cloned_job.complete(res);
+        })
+    {
+        cbw.detach(&job);
+        BLOCK_JOBS.lock().unwrap().remove(id);
+        if let Some(inc) = job.incremental.as_ref() {
+            inc.restore();
+        }
+        bail!("Failed to create backup thread: {:?}", e);
+    }
+    info!(
+        "Backup job {} of block device {} to {} is started",
+        id, args.device, args.target
+    );
+    Ok(())
+}
+
+/// Cancel the block job `id`, the job stops asynchronously.
+pub fn qmp_block_job_cancel(id: &str) -> Result<()> {
+    let job = BLOCK_JOBS
+        .lock()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .with_context(|| format!("Block job {} not found", id))?;
+    job.cancelled.store(true, Ordering::SeqCst);
+    job.cbw.detach(&job);
+    Ok(())
+}
+
+/// Get the information of the running block jobs.
+pub fn qmp_query_block_jobs() -> Vec<qmp_schema::BlockJobInfo> {
+    BLOCK_JOBS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|job| job.info())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::os::unix::fs::FileExt;
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::{register_block_device, unregister_block_device, BlockDevInfo, BlockProperty};
+    use util::aio::{AioEngine, WriteZeroesState};
+
+    const DISK_SIZE: u64 = 1 << 20;
+
+    type DriveFiles = Arc<Mutex<HashMap<String, DriveFile>>>;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("backup-{}-{}.img", name, std::process::id()))
+    }
+
+    fn create_disk(path: &Path) -> (File, Vec<u8>) {
+        let data: Vec<u8> = (0..DISK_SIZE).map(|i| (i % 251) as u8 + 1).collect();
+        std::fs::write(path, &data).unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        (file, data)
+    }
+
+    fn register_device(
+        id: &str,
+        path: &Path,
+        file: File,
+    ) -> (Arc<CopyBeforeWrite>, Arc<BlockDirtyBitmaps>, DriveFiles) {
+        let path = path.to_str().unwrap().to_string();
+        let drive_file = DriveFile {
+            file,
+            count: 1,
+            path: path.clone(),
+            read_only: false,
+            locked: false,
+            req_align: 1,
+            buf_align: 1,
+        };
+        let drive_files = Arc::new(Mutex::new(HashMap::new()));
+        drive_files.lock().unwrap().insert(path.clone(), drive_file);
+        let cbw = Arc::new(CopyBeforeWrite::default());
+        let dirty_bitmaps = Arc::new(BlockDirtyBitmaps::default());
+        dirty_bitmaps.attach(&path, DISK_SIZE);
+        register_block_device(BlockDevInfo {
+            prop: BlockProperty {
+                id: id.to_string(),
+                path,
+                format: DiskFormat::Raw,
+                iothread: None,
+                direct: false,
+                req_align: 1,
+                buf_align: 1,
+                discard: false,
+                write_zeroes: WriteZeroesState::Off,
+            },
+            read_only: false,
+            aio: AioEngine::Off,
+            throttle: None,
+            throttle_group: None,
+            removable: false,
+            stats: Arc::new(Default::default()),
+            resize: None,
+            backup: Some(cbw.clone()),
+            dirty_bitmaps: Some(dirty_bitmaps.clone()),
+            medium: None,
+        });
+        (cbw, dirty_bitmaps, drive_files)
+    }
+
+    fn backup_args(
+        device: &str,
+        target: &Path,
+        speed: Option<u64>,
+    ) -> qmp_schema::DriveBackupArgument {
+        qmp_schema::DriveBackupArgument {
+            job_id: None,
+            device: device.to_string(),
+            target: target.to_str().unwrap().to_string(),
+            sync: "full".to_string(),
+            mode: None,
+            format: None,
+            speed,
+            bitmap: None,
+        }
+    }
+
+    fn wait_job_finished(id: &str) {
+        let start = Instant::now();
+        while qmp_query_block_jobs().iter().any(|job| job.device == id) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_copy_before_write() {
+        QmpChannel::object_init();
+        let source_path = temp_path("cbw-source");
+        let target_path = temp_path("cbw-target");
+        let (file, data) = create_disk(&source_path);
+        let source = SyncFile::new(file.try_clone().unwrap(), 1, 1);
+        let target = SyncFile::new(File::create(&target_path).unwrap(), 1, 1);
+        let cbw = Arc::new(CopyBeforeWrite::default());
+        let job =
+            Arc::new(BackupJob::new("cbw", source, target, 0, true, cbw.clone(), None).unwrap());
+        *cbw.job.lock().unwrap() = Some(job.clone());
+
+        // The old data is copied before the guest writes the disk.
+        cbw.before_write(BACKUP_CLUSTER_SIZE + 100, 200);
+        file.write_all_at(&[0xff; 200], BACKUP_CLUSTER_SIZE + 100)
+            .unwrap();
+        assert_eq!(job.offset.load(Ordering::SeqCst), BACKUP_CLUSTER_SIZE);
+        job.run().unwrap();
+        assert_eq!(job.offset.load(Ordering::SeqCst), DISK_SIZE);
+        job.complete(Ok(()));
+        assert!(cbw.job.lock().unwrap().is_none());
+        assert_eq!(std::fs::read(&target_path).unwrap(), data);
+
+        std::fs::remove_file(&source_path).unwrap();
+        std::fs::remove_file(&target_path).unwrap();
+    }
+
+    #[test]
+    fn test_drive_backup() {
+        QmpChannel::object_init();
+        let source_path = temp_path("source");
+        let target_path = temp_path("target");
+        let (file, data) = create_disk(&source_path);
+        let (cbw, _, drive_files) = register_device("backup0", &source_path, file);
+
+        let mut args = backup_args("backup0", &target_path, None);
+        args.sync = "top".to_string();
+        assert!(qmp_drive_backup(&args, &drive_files).is_err());
+        let args = backup_args("backup1", &target_path, None);
+        assert!(qmp_drive_backup(&args, &drive_files).is_err());
+        assert!(qmp_block_job_cancel("backup0").is_err());
+
+        let args = backup_args("backup0", &target_path, None);
+        qmp_drive_backup(&args, &drive_files).unwrap();
+        wait_job_finished("backup0");
+        assert!(cbw.job.lock().unwrap().is_none());
+        assert_eq!(std::fs::read(&target_path).unwrap(), data);
+
+        // The slow job is cancelled, and the device can be backed up again.
+        let args = backup_args("backup0", &target_path, Some(1));
+        qmp_drive_backup(&args, &drive_files).unwrap();
+        assert!(qmp_drive_backup(&args, &drive_files).is_err());
+        let info = qmp_query_block_jobs()
+            .into_iter()
+            .find(|job| job.device == "backup0")
+            .unwrap();
+        assert_eq!(info.len, DISK_SIZE);
+        assert_eq!(info.speed, 1);
+        qmp_block_job_cancel("backup0").unwrap();
+        assert!(cbw.job.lock().unwrap().is_none());
+        wait_job_finished("backup0");
+
+        // The job is cancelled when the block device is removed.
+        qmp_drive_backup(&args, &drive_files).unwrap();
+        unregister_block_device("backup0");
+        wait_job_finished("backup0");
+
+        std::fs::remove_file(&source_path).unwrap();
+        std::fs::remove_file(&target_path).unwrap();
+    }
+
+    fn dirty_count(device: &str) -> u64 {
+        let info = crate::qmp_query_named_block_nodes()
+            .into_iter()
+            .find(|node| node.node_name == device)
+            .unwrap();
+        info.dirty_bitmaps.map_or(0, |bitmaps| bitmaps[0].count)
+    }
+
+    #[test]
+    fn test_incremental_backup() {
+        QmpChannel::object_init();
+        let source_path = temp_path("inc-source");
+        let target_path = temp_path("inc-target");
+        let (file, data) = create_disk(&source_path);
+        let (_, dirty_bitmaps, drive_files) = register_device("backup-inc", &source_path, file);
+        let add_args = qmp_schema::BlockDirtyBitmapAddArgument {
+            node: "backup-inc".to_string(),
+            name: "bitmap0".to_string(),
+            granularity: None,
+            persistent: None,
+        };
+        crate::dirty_bitmap::qmp_block_dirty_bitmap_add(&add_args).unwrap();
+
+        let mut args = backup_args("backup-inc", &target_path, None);
+        args.sync = "incremental".to_string();
+        assert!(qmp_drive_backup(&args, &drive_files).is_err());
+        args.bitmap = Some("bitmap1".to_string());
+        assert!(qmp_drive_backup(&args, &drive_files).is_err());
+        args.sync = "full".to_string();
+        args.bitmap = Some("bitmap0".to_string());
+        assert!(qmp_drive_backup(&args, &drive_files).is_err());
+
+        // The bitmap is kept if the job fails to start.
+        dirty_bitmaps.mark_dirty(BACKUP_CLUSTER_SIZE + 100, 200);
+        assert_eq!(dirty_count("backup-inc"), BACKUP_CLUSTER_SIZE);
+        args.sync = "incremental".to_string();
+        args.target = "/dev/null".to_string();
+        args.mode = Some("existing".to_string());
+        assert!(qmp_drive_backup(&args, &drive_files).is_err());
+        assert_eq!(dirty_count("backup-inc"), BACKUP_CLUSTER_SIZE);
+
+        // Only the dirty cluster is copied, and the bitmap is cleared.
+        args.target = target_path.to_str().unwrap().to_string();
+        args.mode = None;
+        qmp_drive_backup(&args, &drive_files).unwrap();
+        wait_job_finished("backup-inc");
+        assert_eq!(dirty_count("backup-inc"), 0);
+        let mut expected = vec![0_u8; DISK_SIZE as usize];
+        let range = BACKUP_CLUSTER_SIZE as usize..2 * BACKUP_CLUSTER_SIZE as usize;
+        expected[range.clone()].copy_from_slice(&data[range]);
+        assert_eq!(std::fs::read(&target_path).unwrap(), expected);
+
+        // The dirty ranges are merged back to the bitmap if the job is cancelled.
+        dirty_bitmaps.mark_dirty(0, DISK_SIZE);
+        args.speed = Some(1);
+        qmp_drive_backup(&args, &drive_files).unwrap();
+        assert_eq!(dirty_count("backup-inc"), 0);
+        qmp_block_job_cancel("backup-inc").unwrap();
+        wait_job_finished("backup-inc");
+        assert_eq!(dirty_count("backup-inc"), DISK_SIZE);
+
+        unregister_block_device("backup-inc");
+        std::fs::remove_file(&source_path).unwrap();
+        std::fs::remove_file(&target_path).unwrap();
+    }
+}
@@ -0,0 +1,512 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Dirty bitmaps tracking the ranges of the disk written by the guest, which
+//! are used by the incremental backup.
+//!
+//! The persistent bitmaps are saved to the file `<image>.dirty-bitmaps` when
+//! the VM shuts down or the block device is removed, and loaded when the block
+//! device is realized. The file is removed once it is loaded, so the bitmaps
+//! are dropped rather than trusted if the VM doesn't shut down properly.
+
+use std::collections::BTreeMap;
+use std::fs::{remove_file, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use log::{error, info, warn};
+
+use crate::BLOCK_DEVICES;
+use machine_manager::qmp::qmp_schema;
+
+/// Default size of the range tracked by one bit.
+pub const DIRTY_BITMAP_DEFAULT_GRANULARITY: u64 = 64 * 1024;
+const DIRTY_BITMAP_MIN_GRANULARITY: u64 = 512;
+const DIRTY_BITMAP_MAX_GRANULARITY: u64 = 64 * 1024 * 1024;
+const DIRTY_BITMAP_MAX_NAME_LEN: usize = 1023;
+/// Suffix of the file saving the persistent bitmaps next to the image.
+const DIRTY_BITMAP_FILE_SUFFIX: &str = ".dirty-bitmaps";
+const DIRTY_BITMAP_FILE_MAGIC: &[u8; 8] = b"SVDIRTY\0";
+const DIRTY_BITMAP_FILE_VERSION: u32 = 1;
+
+/// Bitmap of the dirty ranges, one bit for a range of `granularity` bytes.
+#[derive(Clone)]
+pub struct DirtyBitmap {
+    granularity: u64,
+    persistent: bool,
+    bits: Vec<u64>,
+}
+
+impl DirtyBitmap {
+    fn new(granularity: u64, persistent: bool, disk_size: u64) -> Self {
+        DirtyBitmap {
+            granularity,
+            persistent,
+            bits: vec![0; Self::words(granularity, disk_size)],
+        }
+    }
+
+    /// Number of the words needed to track the disk of `disk_size` bytes.
+    fn words(granularity: u64, disk_size: u64) -> usize {
+        disk_size
+            .div_ceil(granularity)
+            .div_ceil(u64::from(u64::BITS)) as usize
+    }
+
+    fn mark(&mut self, offset: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let first = offset / self.granularity;
+        let last = (offset.saturating_add(len) - 1) / self.granularity;
+        let words = (last / u64::from(u64::BITS) + 1) as usize;
+        // The disk may be grown online.
+        if words > self.bits.len() {
+            self.bits.resize(words, 0);
+        }
+        for bit in first..=last {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Whether any part of the range is dirty.
+    pub fn is_dirty(&self, offset: u64, len: u64) -> bool {
+        if len == 0 {
+            return false;
+        }
+        let first = offset / self.granularity;
+        let last = (offset.saturating_add(len) - 1) / self.granularity;
+        (first..=last).any(|bit| {
+            matches!(self.bits.get((bit / 64) as usize), Some(word) if word & (1 << (bit % 64)) != 0)
+        })
+    }
+
+    /// Number of the dirty bytes.
+    fn count(&self) -> u64 {
+        self.bits
+            .iter()
+            .map(|word| u64::from(word.count_ones()))
+            .sum::<u64>()
+            * self.granularity
+    }
+
+    fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+    }
+
+    fn merge(&mut self, other: &DirtyBitmap) {
+        if other.bits.len() > self.bits.len() {
+            self.bits.resize(other.bits.len(), 0);
+        }
+        for (word, other_word) in self.bits.iter_mut().zip(other.bits.iter()) {
+            *word |= other_word;
+        }
+    }
+}
+
+#[derive(Default)]
+struct DirtyBitmapsInner {
+    /// Path of the image, None if the block device has no disk.
+    path: Option<String>,
+    /// Size of the disk in bytes.
+    disk_size: u64,
+    bitmaps: BTreeMap<String, DirtyBitmap>,
+}
+
+/// Dirty bitmaps of a block device, indexed by the bitmap name.
+#[derive(Default)]
+pub struct BlockDirtyBitmaps {
+    inner: Mutex<DirtyBitmapsInner>,
+}
+
+impl BlockDirtyBitmaps {
+    /// Mark the range dirty in all bitmaps, called when the range of the disk
+    /// is written, discarded or zeroed.
+    pub fn mark_dirty(&self, offset: u64, len: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        for bitmap in inner.bitmaps.values_mut() {
+            bitmap.mark(offset, len);
+        }
+    }
+
+    /// Track the disk of the image `path`. The bitmaps of the old image are
+    /// saved, and the persistent bitmaps of the new image are loaded.
+    pub fn attach(&self, path: &str, disk_size: u64) {
+        self.detach();
+        let mut inner = self.inner.lock().unwrap();
+        inner.path = Some(path.to_string());
+        inner.disk_size = disk_size;
+        let file_path = format!("{}{}", path, DIRTY_BITMAP_FILE_SUFFIX);
+        if !Path::new(&file_path).exists() {
+            return;
+        }
+        match load_bitmaps(&file_path, disk_size) {
+            Ok(bitmaps) => {
+                info!("Load {} dirty bitmaps of {}", bitmaps.len(), path);
+                inner.bitmaps = bitmaps;
+            }
+            Err(e) => warn!("Dirty bitmaps of {} are dropped: {:?}", path, e),
+        }
+        // The bitmaps in the file are out of date once the guest writes the disk.
+        if let Err(e) = remove_file(&file_path) {
+            error!("Failed to remove dirty bitmap file {}: {:?}", file_path, e);
+        }
+    }
+
+    /// Stop tracking the disk, the persistent bitmaps are saved.
+    pub fn detach(&self) {
+        if let Err(e) = self.save() {
+            error!("{:?}", e);
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.path = None;
+        inner.bitmaps.clear();
+    }
+
+    /// Save the persistent bitmaps to the file next to the image.
+    pub fn save(&self) -> Result<()> {
+        let inner = self.inner.lock().unwrap();
+        let path = match inner.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let persistent: Vec<(&String, &DirtyBitmap)> = inner
+            .bitmaps
+            .iter()
+            .filter(|(_, bitmap)| bitmap.persistent)
+            .collect();
+        if persistent.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = DIRTY_BITMAP_FILE_MAGIC.to_vec();
+        let mut header = [0_u8; 8];
+        LittleEndian::write_u32(&mut header[0..4], DIRTY_BITMAP_FILE_VERSION);
+        LittleEndian::write_u32(&mut header[4..8], persistent.len() as u32);
+        buf.extend_from_slice(&header);
+        for (name, bitmap) in persistent {
+            let mut entry = [0_u8; 20];
+            LittleEndian::write_u32(&mut entry[0..4], name.len() as u32);
+            LittleEndian::write_u64(&mut entry[4..12], bitmap.granularity);
+            LittleEndian::write_u64(&mut entry[12..20], bitmap.bits.len() as u64);
+            buf.extend_from_slice(&entry);
+            buf.extend_from_slice(name.as_bytes());
+            for word in bitmap.bits.iter() {
+                buf.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+
+        let file_path = format!("{}{}", path, DIRTY_BITMAP_FILE_SUFFIX);
+        let mut file = File::create(&file_path)
+            .with_context(|| format!("Failed to create dirty bitmap file {}", file_path))?;
+        file.write_all(&buf)
+            .and_then(|_| file.sync_data())
+            .with_context(|| format!("Failed to save dirty bitmaps to {}", file_path))?;
+        info!("Save dirty bitmaps of {} to {}", path, file_path);
+        Ok(())
+    }
+
+    fn add(&self, name: &str, granularity: u64, persistent: bool) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.path.is_none() {
+            bail!("No disk to track");
+        }
+        if inner.bitmaps.contains_key(name) {
+            bail!("Dirty bitmap {} already exists", name);
+        }
+        let bitmap = DirtyBitmap::new(granularity, persistent, inner.disk_size);
+        inner.bitmaps.insert(name.to_string(), bitmap);
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .bitmaps
+            .remove(name)
+            .with_context(|| format!("Dirty bitmap {} not found", name))?;
+        Ok(())
+    }
+
+    fn clear(&self, name: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .bitmaps
+            .get_mut(name)
+            .with_context(|| format!("Dirty bitmap {} not found", name))?
+            .clear();
+        Ok(())
+    }
+
+    /// Get the dirty ranges of the bitmap for the incremental backup, and
+    /// clear the bitmap to track the writes after the backup.
+    pub(crate) fn take(&self, name: &str) -> Result<DirtyBitmap> {
+        let mut inner = self.inner.lock().unwrap();
+        let bitmap = inner
+            .bitmaps
+            .get_mut(name)
+            .with_context(|| format!("Dirty bitmap {} not found", name))?;
+        let taken = bitmap.clone();
+        bitmap.clear();
+        Ok(taken)
+    }
+
+    /// Merge the dirty ranges back to the bitmap if the incremental backup fails.
+    pub(crate) fn restore(&self, name: &str, taken: &DirtyBitmap) {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.bitmaps.get_mut(name) {
+            Some(bitmap) if bitmap.granularity == taken.granularity => bitmap.merge(taken),
+            _ => warn!("Dirty bitmap {} is changed during backup", name),
+        }
+    }
+
+    fn info(&self) -> Vec<qmp_schema::BlockDirtyInfo> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .bitmaps
+            .iter()
+            .map(|(name, bitmap)| qmp_schema::BlockDirtyInfo {
+                name: name.clone(),
+                count: bitmap.count(),
+                granularity: bitmap.granularity,
+                recording: true,
+                busy: false,
+                persistent: bitmap.persistent,
+            })
+            .collect()
+    }
+}
+
+fn load_bitmaps(file_path: &str, disk_size: u64) -> Result<BTreeMap<String, DirtyBitmap>> {
+    let mut buf = Vec::new();
+    File::open(file_path)
+        .and_then(|mut file| file.read_to_end(&mut buf))
+        .with_context(|| format!("Failed to read dirty bitmap file {}", file_path))?;
+    let mut reader = buf.as_slice();
+
+    if take(&mut reader, 8)? != DIRTY_BITMAP_FILE_MAGIC {
+        bail!("Invalid magic of dirty bitmap file {}", file_path);
+    }
+    let header = take(&mut reader, 8)?;
+    let version = LittleEndian::read_u32(&header[0..4]);
+    if version != DIRTY_BITMAP_FILE_VERSION {
+        bail!("Unsupported version {} of dirty bitmap file", version);
+    }
+    let count = LittleEndian::read_u32(&header[4..8]);
+    let mut bitmaps = BTreeMap::new();
+    for _ in 0..count {
+        let entry = take(&mut reader, 20)?;
+        let name_len = LittleEndian::read_u32(&entry[0..4]) as usize;
+        let granularity = LittleEndian::read_u64(&entry[4..12]);
+        let words = LittleEndian::read_u64(&entry[12..20]) as usize;
+        check_granularity(granularity)?;
+        if name_len > DIRTY_BITMAP_MAX_NAME_LEN {
+            bail!("Too long name of dirty bitmap");
+        }
+        let name = String::from_utf8(take(&mut reader, name_len)?.to_vec())
+            .with_context(|| "Invalid name of dirty bitmap")?;
+        // The bitmap is invalid if the image is shrunk.
+        let expected = DirtyBitmap::words(granularity, disk_size);
+        if words > expected {
+            bail!("Dirty bitmap {} doesn't match the size of the disk", name);
+        }
+        let data = take(
+            &mut reader,
+            words
+                .checked_mul(8)
+                .with_context(|| "Invalid bitmap size")?,
+        )?;
+        let mut bitmap = DirtyBitmap::new(granularity, true, disk_size);
+        for (word, bytes) in bitmap.bits.iter_mut().zip(data.chunks_exact(8)) {
+            *word = LittleEndian::read_u64(bytes);
+        }
+        bitmaps.insert(name, bitmap);
+    }
+    Ok(bitmaps)
+}
+
+fn take<'a>(reader: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if reader.len() < len {
+        bail!("Dirty bitmap file is truncated");
+    }
+    let (data, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(data)
+}
+
+fn check_granularity(granularity: u64) -> Result<()> {
+    if !granularity.is_power_of_two()
+        || !(DIRTY_BITMAP_MIN_GRANULARITY..=DIRTY_BITMAP_MAX_GRANULARITY).contains(&granularity)
+    {
+        bail!(
+            "Invalid granularity {}, it should be a power of 2 between {} and {}",
+            granularity,
+            DIRTY_BITMAP_MIN_GRANULARITY,
+            DIRTY_BITMAP_MAX_GRANULARITY
+        );
+    }
+    Ok(())
+}
+
+/// Get the dirty bitmaps of the block device `node`.
+pub(crate) fn block_dirty_bitmaps(node: &str) -> Result<Arc<BlockDirtyBitmaps>> {
+    BLOCK_DEVICES
+        .lock()
+        .unwrap()
+        .get(node)
+        .with_context(|| format!("Block device {} not found", node))?
+        .dirty_bitmaps
+        .clone()
+        .with_context(|| format!("Block device {} doesn't support dirty bitmaps", node))
+}
+
+/// Get the dirty bitmaps of the block device for qmp query.
+pub(crate) fn dirty_bitmaps_info(
+    dirty_bitmaps: &Option<Arc<BlockDirtyBitmaps>>,
+) -> Option<Vec<qmp_schema::BlockDirtyInfo>> {
+    dirty_bitmaps
+        .as_ref()
+        .map(|bitmaps| bitmaps.info())
+        .filter(|info| !info.is_empty())
+}
+
+pub fn qmp_block_dirty_bitmap_add(args: &qmp_schema::BlockDirtyBitmapAddArgument) -> Result<()> {
+    if args.name.is_empty() || args.name.len() > DIRTY_BITMAP_MAX_NAME_LEN {
+        bail!(
+            "Invalid name of dirty bitmap, its length should be between 1 and {}",
+            DIRTY_BITMAP_MAX_NAME_LEN
+        );
+    }
+    let granularity = args.granularity.unwrap_or(DIRTY_BITMAP_DEFAULT_GRANULARITY);
+    check_granularity(granularity)?;
+    block_dirty_bitmaps(&args.node)?.add(&args.name, granularity, args.persistent.unwrap_or(false))
+}
+
+pub fn qmp_block_dirty_bitmap_remove(node: &str, name: &str) -> Result<()> {
+    block_dirty_bitmaps(node)?.remove(name)
+}
+
+pub fn qmp_block_dirty_bitmap_clear(node: &str, name: &str) -> Result<()> {
+    block_dirty_bitmaps(node)?.clear(name)
+}
+
+/// Save the persistent bitmaps of all block devices, called when the VM shuts down.
+pub fn save_dirty_bitmaps() {
+    let dirty_bitmaps: Vec<Arc<BlockDirtyBitmaps>> = BLOCK_DEVICES
+        .lock()
+        .unwrap()
+        .values()
+        .filter_map(|info| info.dirty_bitmaps.clone())
+        .collect();
+    for bitmaps in dirty_bitmaps {
+        if let Err(e) = bitmaps.save() {
+            error!("{:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISK_SIZE: u64 = 1 << 30;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("dirty-bitmap-{}-{}.img", name, std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_dirty_bitmap_mark() {
+        let mut bitmap = DirtyBitmap::new(DIRTY_BITMAP_DEFAULT_GRANULARITY, false, DISK_SIZE);
+        assert_eq!(bitmap.count(), 0);
+        bitmap.mark(65535, 2);
+        assert_eq!(bitmap.count(), 2 * DIRTY_BITMAP_DEFAULT_GRANULARITY);
+        assert!(bitmap.is_dirty(0, 1));
+        assert!(bitmap.is_dirty(65536, 65536));
+        assert!(!bitmap.is_dirty(131072, 65536));
+        assert!(!bitmap.is_dirty(0, 0));
+
+        // The range beyond the disk is tracked once the disk grows.
+        bitmap.mark(DISK_SIZE, 512);
+        assert!(bitmap.is_dirty(DISK_SIZE, 1));
+        assert_eq!(bitmap.count(), 3 * DIRTY_BITMAP_DEFAULT_GRANULARITY);
+
+        let mut other = DirtyBitmap::new(DIRTY_BITMAP_DEFAULT_GRANULARITY, false, DISK_SIZE);
+        other.mark(DISK_SIZE / 2, 1);
+        bitmap.clear();
+        assert_eq!(bitmap.count(), 0);
+        bitmap.merge(&other);
+        assert!(bitmap.is_dirty(DISK_SIZE / 2, 1));
+        assert_eq!(bitmap.count(), DIRTY_BITMAP_DEFAULT_GRANULARITY);
+
+        assert!(check_granularity(512).is_ok());
+        assert!(check_granularity(64 * 1024 * 1024).is_ok());
+        assert!(check_granularity(256).is_err());
+        assert!(check_granularity(3 * 1024).is_err());
+        assert!(check_granularity(128 * 1024 * 1024).is_err());
+    }
+
+    #[test]
+    fn test_dirty_bitmap_persistent() {
+        let path = temp_path("persistent");
+        let file_path = format!("{}{}", path, DIRTY_BITMAP_FILE_SUFFIX);
+        let bitmaps = BlockDirtyBitmaps::default();
+        assert!(bitmaps.add("bitmap0", 4096, true).is_err());
+        bitmaps.attach(&path, DISK_SIZE);
+        bitmaps.add("bitmap0", 4096, true).unwrap();
+        bitmaps.add("bitmap1", 65536, false).unwrap();
+        assert!(bitmaps.add("bitmap0", 4096, true).is_err());
+        bitmaps.mark_dirty(8192, 4096);
+        bitmaps.mark_dirty(DISK_SIZE - 1, 1);
+
+        // Only the persistent bitmap is saved.
+        bitmaps.detach();
+        assert!(Path::new(&file_path).exists());
+        assert!(bitmaps.info().is_empty());
+        bitmaps.attach(&path, DISK_SIZE);
+        assert!(!Path::new(&file_path).exists());
+        let info = bitmaps.info();
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0].name, "bitmap0");
+        assert_eq!(info[0].granularity, 4096);
+        assert_eq!(info[0].count, 2 * 4096);
+        assert!(info[0].persistent);
+
+        // The bitmap of the larger disk is dropped.
+        bitmaps.detach();
+        bitmaps.attach(&path, DISK_SIZE / 2);
+        assert!(bitmaps.info().is_empty());
+        assert!(!Path::new(&file_path).exists());
+
+        // The bitmap removed is not saved.
+        bitmaps.add("bitmap0", 4096, true).unwrap();
+        bitmaps.clear("bitmap0").unwrap();
+        bitmaps.remove("bitmap0").unwrap();
+        assert!(bitmaps.remove("bitmap0").is_err());
+        assert!(bitmaps.clear("bitmap0").is_err());
+        bitmaps.detach();
+        assert!(!Path::new(&file_path).exists());
+
+        // The corrupted file is dropped.
+        std::fs::write(&file_path, b"SVDIRTY\0\x01\0\0\0\x01\0\0\0").unwrap();
+        bitmaps.attach(&path, DISK_SIZE);
+        assert!(bitmaps.info().is_empty());
+        assert!(!Path::new(&file_path).exists());
+    }
+}
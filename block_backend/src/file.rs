@@ -0,0 +1,463 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use log::error;
+use vmm_sys_util::epoll::EventSet;
+
+use crate::{BlockIoErrorCallback, BlockProperty};
+use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
+use util::aio::{
+    raw_datasync, raw_discard, raw_read, raw_write, Aio, AioCb, AioEngine, Iovec, OpCode,
+};
+use util::loop_context::{
+    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+
+/// A request on the host file, split from the request on the guest disk.
+pub struct CombineRequest {
+    pub iov: Vec<Iovec>,
+    pub offset: u64,
+    pub nbytes: u64,
+}
+
+impl CombineRequest {
+    pub fn new(iov: Vec<Iovec>, offset: u64, nbytes: u64) -> Self {
+        Self {
+            iov,
+            offset,
+            nbytes,
+        }
+    }
+}
+
+/// The driver of the host file, submitting requests to the aio engine.
+pub struct FileDriver<T: Clone + 'static> {
+    /// The image file opened.
+    pub file: File,
+    /// Aio context.
+    aio: Rc<RefCell<Aio<T>>>,
+    /// Fds of the registered io completion events.
+    delete_evts: Vec<RawFd>,
+    /// Properties of the block backend.
+    pub block_prop: BlockProperty,
+}
+
+impl<T: Clone + 'static> FileDriver<T> {
+    pub fn new(file: File, mut aio: Aio<T>, block_prop: BlockProperty) -> Self {
+        // The file is kept open as long as the aio, it is fine to use it as fixed file.
+        if let Err(e) = aio.register_file(file.as_raw_fd()) {
+            error!("{:?}", e);
+        }
+        Self {
+            file,
+            aio: Rc::new(RefCell::new(aio)),
+            delete_evts: Vec::new(),
+            block_prop,
+        }
+    }
+
+    fn package_aiocb(
+        &self,
+        opcode: OpCode,
+        iovec: Vec<Iovec>,
+        offset: usize,
+        nbytes: u64,
+        iocompletecb: T,
+    ) -> AioCb<T> {
+        AioCb {
+            direct: self.block_prop.direct,
+            req_align: self.block_prop.req_align,
+            buf_align: self.block_prop.buf_align,
+            file_fd: self.file.as_raw_fd(),
+            opcode,
+            iovec,
+            offset,
+            nbytes,
+            user_data: 0,
+            iocompletecb,
+            discard: self.block_prop.discard,
+            write_zeroes: self.block_prop.write_zeroes,
+            write_zeroes_unmap: false,
+            combine_req: None,
+        }
+    }
+
+    fn process_request(
+        &mut self,
+        opcode: OpCode,
+        req_list: Vec<CombineRequest>,
+        completecb: T,
+    ) -> Result<()> {
+        if req_list.is_empty() {
+            return self.complete_request(opcode, 0, completecb);
+        }
+
+        let single_req = req_list.len() == 1;
+        let cnt = Arc::new(AtomicU32::new(req_list.len() as u32));
+        let res = Arc::new(AtomicI64::new(0));
+        for req in req_list {
+            let mut aiocb = self.package_aiocb(
+                opcode,
+                req.iov,
+                req.offset as usize,
+                req.nbytes,
+                completecb.clone(),
+            );
+            if !single_req {
+                aiocb.combine_req = Some((cnt.clone(), res.clone()));
+            }
+            self.aio.borrow_mut().submit_request(aiocb)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_vectored(&mut self, req_list: Vec<CombineRequest>, completecb: T) -> Result<()> {
+        self.process_request(OpCode::Preadv, req_list, completecb)
+    }
+
+    pub fn write_vectored(&mut self, req_list: Vec<CombineRequest>, completecb: T) -> Result<()> {
+        self.process_request(OpCode::Pwritev, req_list, completecb)
+    }
+
+    /// Complete the request directly with the result `res`, used by the requests
+    /// which need not access the host file or have been handled synchronously.
+    pub fn complete_request(&mut self, opcode: OpCode, res: i64, completecb: T) -> Result<()> {
+        let aiocb = self.package_aiocb(opcode, Vec::new(), 0, 0, completecb);
+        self.aio.borrow().complete_func(&aiocb, res)
+    }
+
+    pub fn datasync(&mut self, completecb: T) -> Result<()> {
+        let aiocb = self.package_aiocb(OpCode::Fdsync, Vec::new(), 0, 0, completecb);
+        self.aio.borrow_mut().submit_request(aiocb)
+    }
+
+    pub fn discard(&mut self, offset: usize, nbytes: u64, completecb: T) -> Result<()> {
+        let aiocb = self.package_aiocb(OpCode::Discard, Vec::new(), offset, nbytes, completecb);
+        self.aio.borrow_mut().submit_request(aiocb)
+    }
+
+    pub fn write_zeroes(
+        &mut self,
+        offset: usize,
+        nbytes: u64,
+        completecb: T,
+        unmap: bool,
+    ) -> Result<()> {
+        let mut aiocb =
+            self.package_aiocb(OpCode::WriteZeroes, Vec::new(), offset, nbytes, completecb);
+        aiocb.write_zeroes_unmap = unmap;
+        self.aio.borrow_mut().submit_request(aiocb)
+    }
+
+    pub fn flush_request(&mut self) -> Result<()> {
+        self.aio.borrow_mut().flush_request()
+    }
+
+    /// Whether all the submitted requests are completed.
+    pub fn is_idle(&self) -> bool {
+        let aio = self.aio.borrow();
+        aio.aio_in_queue.len == 0 && aio.aio_in_flight.len == 0
+    }
+
+    pub fn register_io_event(
+        &mut self,
+        broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Result<()> {
+        let handler = FileIoHandler::new(self.aio.clone(), broken, error_cb);
+        let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
+        register_event_helper(
+            notifiers,
+            self.block_prop.iothread.as_ref(),
+            &mut self.delete_evts,
+        )
+    }
+
+    pub fn unregister_io_event(&mut self) -> Result<()> {
+        unregister_event_helper(self.block_prop.iothread.as_ref(), &mut self.delete_evts)
+    }
+
+    pub fn disk_size(&mut self) -> Result<u64> {
+        self.file
+            .seek(SeekFrom::End(0))
+            .with_context(|| "Failed to seek the end for file")
+    }
+}
+
+/// Handler of the io completion events of the aio engine.
+struct FileIoHandler<T: Clone + 'static> {
+    aio: Rc<RefCell<Aio<T>>>,
+    broken: Arc<AtomicBool>,
+    error_cb: BlockIoErrorCallback,
+}
+
+// SAFETY: the aio context is only accessed in the iothread of the block device.
+unsafe impl<T: Clone + 'static> Send for FileIoHandler<T> {}
+
+impl<T: Clone + 'static> FileIoHandler<T> {
+    fn new(
+        aio: Rc<RefCell<Aio<T>>>,
+        broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Self {
+        Self {
+            aio,
+            broken,
+            error_cb,
+        }
+    }
+
+    fn aio_complete_handler(&mut self) -> Result<bool> {
+        self.aio
+            .borrow_mut()
+            .handle_complete()
+            .inspect_err(|_| (self.error_cb)())
+    }
+}
+
+fn build_event_notifier(
+    fd: RawFd,
+    handlers: Vec<Rc<NotifierCallback>>,
+    handler_poll: Option<Box<NotifierCallback>>,
+) -> EventNotifier {
+    let mut notifier = EventNotifier::new(
+        NotifierOperation::AddShared,
+        fd,
+        None,
+        EventSet::IN,
+        handlers,
+    );
+    notifier.handler_poll = handler_poll;
+    notifier
+}
+
+impl<T: Clone + 'static> EventNotifierHelper for FileIoHandler<T> {
+    fn internal_notifiers(handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let handler_raw = handler.lock().unwrap();
+        let mut notifiers = Vec::new();
+
+        // Register event notifier for aio.
+        let h_clone = handler.clone();
+        let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            let mut h_lock = h_clone.lock().unwrap();
+            if h_lock.broken.load(Ordering::SeqCst) {
+                return None;
+            }
+            if let Err(ref e) = h_lock.aio_complete_handler() {
+                error!("Failed to handle aio {:?}", e);
+            }
+            None
+        });
+        let h_clone = handler.clone();
+        let handler_iopoll: Box<NotifierCallback> = Box::new(move |_, _fd: RawFd| {
+            let mut h_lock = h_clone.lock().unwrap();
+            if h_lock.broken.load(Ordering::SeqCst) {
+                return None;
+            }
+            if h_lock.aio.borrow().get_engine() == AioEngine::Off {
+                return None;
+            }
+            match h_lock.aio_complete_handler() {
+                Ok(done) => {
+                    if done {
+                        Some(Vec::new())
+                    } else {
+                        None
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to handle aio {:?}", e);
+                    None
+                }
+            }
+        });
+        let fd = handler_raw.aio.borrow().fd.as_raw_fd();
+        notifiers.push(build_event_notifier(fd, vec![h], Some(handler_iopoll)));
+
+        notifiers
+    }
+}
+
+/// Synchronous access to the host file, which takes care of the alignment
+/// requirement of direct io. It is used to access the metadata of images.
+pub struct SyncFile {
+    file: File,
+    /// The align requirement of request(offset/len).
+    req_align: u64,
+    /// The align requirement of buffer(iova_base).
+    buf_align: u64,
+}
+
+impl SyncFile {
+    pub fn new(file: File, req_align: u32, buf_align: u32) -> Self {
+        Self {
+            file,
+            req_align: u64::from(req_align),
+            buf_align: u64::from(buf_align),
+        }
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    fn aligned(&self, addr: u64, offset: u64, len: u64) -> bool {
+        addr & (self.buf_align - 1) == 0
+            && offset & (self.req_align - 1) == 0
+            && len & (self.req_align - 1) == 0
+    }
+
+    /// Read until `buf` is full or the end of file is reached, return the read length.
+    fn read_full(&self, addr: u64, len: u64, offset: u64) -> Result<u64> {
+        let mut done = 0;
+        while done < len {
+            let ret = raw_read(
+                self.file.as_raw_fd(),
+                addr + done,
+                (len - done) as usize,
+                (offset + done) as usize,
+            );
+            if ret < 0 {
+                return Err(std::io::Error::from_raw_os_error(-ret as i32))
+                    .with_context(|| format!("Failed to read file at offset {}", offset + done));
+            }
+            if ret == 0 {
+                break;
+            }
+            done += ret as u64;
+        }
+        Ok(done)
+    }
+
+    fn write_full(&self, addr: u64, len: u64, offset: u64) -> Result<()> {
+        let mut done = 0;
+        while done < len {
+            let ret = raw_write(
+                self.file.as_raw_fd(),
+                addr + done,
+                (len - done) as usize,
+                (offset + done) as usize,
+            );
+            if ret < 0 {
+                return Err(std::io::Error::from_raw_os_error(-ret as i32))
+                    .with_context(|| format!("Failed to write file at offset {}", offset + done));
+            }
+            if ret == 0 {
+                bail!("Failed to write file at offset {}", offset + done);
+            }
+            done += ret as u64;
+        }
+        Ok(())
+    }
+
+    /// Read data at `offset` into `buf`, the part beyond the end of file is filled with zero.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let len = buf.len() as u64;
+        if self.aligned(buf.as_ptr() as u64, offset, len) {
+            let done = self.read_full(buf.as_mut_ptr() as u64, len, offset)?;
+            buf[done as usize..].fill(0);
+            return Ok(());
+        }
+
+        let start = offset & !(self.req_align - 1);
+        let end = (offset + len + self.req_align - 1) & !(self.req_align - 1);
+        let mut bounce = AlignedBuffer::new((end - start) as usize, self.buf_align as usize);
+        let data = bounce.as_mut_slice();
+        let done = self.read_full(data.as_mut_ptr() as u64, end - start, start)?;
+        data[done as usize..].fill(0);
+        let pos = (offset - start) as usize;
+        buf.copy_from_slice(&data[pos..pos + len as usize]);
+        Ok(())
+    }
+
+    /// Write `buf` at `offset`, misaligned head and tail are read and merged first.
+    pub fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        let len = buf.len() as u64;
+        if self.aligned(buf.as_ptr() as u64, offset, len) {
+            return self.write_full(buf.as_ptr() as u64, len, offset);
+        }
+
+        let start = offset & !(self.req_align - 1);
+        let end = (offset + len + self.req_align - 1) & !(self.req_align - 1);
+        let mut bounce = AlignedBuffer::new((end - start) as usize, self.buf_align as usize);
+        let data = bounce.as_mut_slice();
+        if start != offset || end != offset + len {
+            let done = self.read_full(data.as_mut_ptr() as u64, end - start, start)?;
+            data[done as usize..].fill(0);
+        }
+        let pos = (offset - start) as usize;
+        data[pos..pos + len as usize].copy_from_slice(buf);
+        self.write_full(data.as_ptr() as u64, end - start, start)
+    }
+
+    /// Free the space of the range in host file.
+    pub fn discard(&self, offset: u64, len: u64) -> Result<()> {
+        if raw_discard(self.file.as_raw_fd(), offset as usize, len) < 0 {
+            bail!("Failed to discard file at offset {} len {}", offset, len);
+        }
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        if raw_datasync(self.file.as_raw_fd()) < 0 {
+            bail!("Failed to sync file");
+        }
+        Ok(())
+    }
+
+    pub fn file_size(&self) -> Result<u64> {
+        Ok(self
+            .file
+            .metadata()
+            .with_context(|| "Failed to get the metadata of file")?
+            .len())
+    }
+
+    pub fn set_len(&self, len: u64) -> Result<()> {
+        self.file
+            .set_len(len)
+            .with_context(|| format!("Failed to set the length of file to {}", len))
+    }
+}
+
+/// Memory buffer whose start address satisfies the alignment requirement.
+pub struct AlignedBuffer {
+    data: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    pub fn new(len: usize, align: usize) -> Self {
+        let data = vec![0_u8; len + align];
+        let start = data.as_ptr().align_offset(align);
+        Self { data, start, len }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[self.start..self.start + self.len]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data[self.start..self.start + self.len]
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Handle the failed requests of the block devices according to the
+//! `werror`/`rerror` policies of the drives.
+//!
+//! With the `stop` action, the block device keeps the failed requests and asks
+//! the machine to pause the VM. The kept requests are retried after the VM is
+//! resumed by the `cont` command.
+
+use std::sync::{Arc, Mutex};
+
+use log::error;
+use once_cell::sync::Lazy;
+use vmm_sys_util::eventfd::EventFd;
+
+use machine_manager::config::BlockErrorPolicy;
+use machine_manager::event;
+use machine_manager::qmp::{qmp_schema, QmpChannel};
+
+/// Action taken for the failed request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockErrorAction {
+    /// Complete the request with an error.
+    Report,
+    /// Complete the request successfully.
+    Ignore,
+    /// Keep the request and pause the VM.
+    Stop,
+}
+
+impl BlockErrorAction {
+    /// Get the action for the request failed with `errno` under the `policy`.
+    pub fn new(policy: BlockErrorPolicy, errno: i32) -> Self {
+        match policy {
+            BlockErrorPolicy::Report => BlockErrorAction::Report,
+            BlockErrorPolicy::Ignore => BlockErrorAction::Ignore,
+            BlockErrorPolicy::Stop => BlockErrorAction::Stop,
+            BlockErrorPolicy::Enospc if errno == libc::ENOSPC => BlockErrorAction::Stop,
+            BlockErrorPolicy::Enospc => BlockErrorAction::Report,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            BlockErrorAction::Report => "report",
+            BlockErrorAction::Ignore => "ignore",
+            BlockErrorAction::Stop => "stop",
+        }
+    }
+}
+
+/// Eventfd registered by the machine to pause the VM in the main loop.
+static VM_STOP_REQ: Lazy<Mutex<Option<Arc<EventFd>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Eventfds of the block devices holding the stopped requests, written when the VM resumes.
+static STOPPED_DEVICES: Lazy<Mutex<Vec<Arc<EventFd>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Set the eventfd which is written to pause the VM for the stopped requests.
+pub fn set_vm_stop_req(stop_req: Arc<EventFd>) {
+    *VM_STOP_REQ.lock().unwrap() = Some(stop_req);
+}
+
+/// Send the `BLOCK_IO_ERROR` event for the request failed with `errno`.
+///
+/// # Arguments
+///
+/// * `drive_id` - Id of the drive.
+/// * `is_write` - The request is a write request or not.
+/// * `action` - Action taken for the failed request.
+/// * `errno` - Errno of the host.
+pub fn report_block_io_error(drive_id: &str, is_write: bool, action: BlockErrorAction, errno: i32) {
+    let operation = if is_write { "write" } else { "read" };
+    let reason = std::io::Error::from_raw_os_error(errno).to_string();
+    error!(
+        "Block device {} {} request failed: {}, action {}",
+        drive_id,
+        operation,
+        reason,
+        action.as_str()
+    );
+    let io_error = qmp_schema::BlockIoError {
+        device: drive_id.to_string(),
+        node_name: drive_id.to_string(),
+        operation: operation.to_string(),
+        action: action.as_str().to_string(),
+        nospace: errno == libc::ENOSPC,
+        reason,
+        errno,
+    };
+    event!(BlockIoError; io_error);
+}
+
+/// Pause the VM for the requests stopped by the block device, `retry_evt` is
+/// written to retry the requests after the VM is resumed.
+pub fn stop_vm_for_block_io_error(retry_evt: &Arc<EventFd>) {
+    let mut stopped_devices = STOPPED_DEVICES.lock().unwrap();
+    if !stopped_devices
+        .iter()
+        .any(|evt| Arc::ptr_eq(evt, retry_evt))
+    {
+        stopped_devices.push(retry_evt.clone());
+    }
+    drop(stopped_devices);
+
+    match VM_STOP_REQ.lock().unwrap().as_ref() {
+        Some(stop_req) => {
+            if let Err(e) = stop_req.write(1) {
+                error!("Failed to request pausing VM for block I/O error: {:?}", e);
+            }
+        }
+        None => error!("Pausing VM for block I/O error is not supported by the machine"),
+    }
+}
+
+/// Remove the block device from the devices waiting for the VM to resume,
+/// used when the stopped requests of the device are dropped.
+pub fn cancel_stopped_block_requests(retry_evt: &Arc<EventFd>) {
+    STOPPED_DEVICES
+        .lock()
+        .unwrap()
+        .retain(|evt| !Arc::ptr_eq(evt, retry_evt));
+}
+
+/// Retry the stopped requests of all block devices, called after the VM is resumed.
+pub fn retry_stopped_block_requests() {
+    let stopped_devices: Vec<Arc<EventFd>> = STOPPED_DEVICES.lock().unwrap().drain(..).collect();
+    for retry_evt in stopped_devices {
+        if let Err(e) = retry_evt.write(1) {
+            error!("Failed to retry the stopped block requests: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_error_action() {
+        let eio = libc::EIO;
+        let enospc = libc::ENOSPC;
+        assert_eq!(
+            BlockErrorAction::new(BlockErrorPolicy::Report, enospc),
+            BlockErrorAction::Report
+        );
+        assert_eq!(
+            BlockErrorAction::new(BlockErrorPolicy::Ignore, eio),
+            BlockErrorAction::Ignore
+        );
+        assert_eq!(
+            BlockErrorAction::new(BlockErrorPolicy::Stop, eio),
+            BlockErrorAction::Stop
+        );
+        assert_eq!(
+            BlockErrorAction::new(BlockErrorPolicy::Enospc, enospc),
+            BlockErrorAction::Stop
+        );
+        assert_eq!(
+            BlockErrorAction::new(BlockErrorPolicy::Enospc, eio),
+            BlockErrorAction::Report
+        );
+    }
+
+    #[test]
+    fn test_retry_stopped_block_requests() {
+        let retry_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        stop_vm_for_block_io_error(&retry_evt);
+        stop_vm_for_block_io_error(&retry_evt);
+        retry_stopped_block_requests();
+        assert_eq!(retry_evt.read().unwrap(), 1);
+
+        stop_vm_for_block_io_error(&retry_evt);
+        cancel_stopped_block_requests(&retry_evt);
+        retry_stopped_block_requests();
+        assert!(retry_evt.read().is_err());
+    }
+}
@@ -0,0 +1,452 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! # Block Backend
+//!
+//! The block backend sits between the block devices (virtio-blk, scsi-hd,
+//! usb-storage...) and the aio engines. It translates the requests of the
+//! guest disk into the requests of the host file according to the format of
+//! the image.
+
+pub mod backup;
+pub mod dirty_bitmap;
+pub mod file;
+pub mod io_error;
+pub mod nbd;
+pub mod qcow2;
+pub mod raw;
+pub mod snapshot;
+pub mod stats;
+pub mod throttle;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use once_cell::sync::Lazy;
+
+use backup::CopyBeforeWrite;
+use dirty_bitmap::BlockDirtyBitmaps;
+use machine_manager::config::{ConfigCheck, DiskFormat, DriveFile, ThrottleConfig, ThrottleLimit};
+use machine_manager::qmp::qmp_schema;
+use nbd::NbdDriver;
+use qcow2::Qcow2Driver;
+use raw::RawDriver;
+use stats::BlockStats;
+use throttle::Throttle;
+use util::aio::{Aio, AioEngine, Iovec, WriteZeroesState};
+
+/// Callback used to report the failure of handling io completion events.
+pub type BlockIoErrorCallback = Arc<dyn Fn() + Send + Sync>;
+
+/// Callback used to grow the disk of the block device to the new size in bytes,
+/// the block device is responsible for notifying the guest.
+pub type BlockResizeCallback = Arc<dyn Fn(u64) -> Result<()> + Send + Sync>;
+
+/// The operations on the removable medium of the block device, such as the CD-ROM.
+pub trait BlockMediumOps: Send + Sync {
+    /// The tray of the device is open or not.
+    fn tray_open(&self) -> bool;
+
+    /// The guest prevents the medium from being removed or not.
+    fn locked(&self) -> bool;
+
+    /// Open the tray and remove the medium. If the medium is locked by the guest, the
+    /// guest is asked to eject it, and it's removed anyway only if `force`.
+    fn eject(&self, force: bool) -> Result<()>;
+
+    /// Replace the medium with the image `path` of `format` and close the tray.
+    fn change_medium(&self, path: &str, format: DiskFormat) -> Result<()>;
+}
+
+/// The size of the disk must be a multiple of the sector size.
+const SECTOR_SIZE: u64 = 512;
+
+/// Properties of the block backend.
+#[derive(Debug, Clone)]
+pub struct BlockProperty {
+    /// Id of the drive.
+    pub id: String,
+    /// Path of the image file, used to find the relative backing file.
+    pub path: String,
+    /// Format of the image file.
+    pub format: DiskFormat,
+    /// The iothread in which the io completion events are handled.
+    pub iothread: Option<String>,
+    /// If use direct access io.
+    pub direct: bool,
+    /// The align requirement of request(offset/len).
+    pub req_align: u32,
+    /// The align requirement of buffer(iova_base).
+    pub buf_align: u32,
+    /// Supporting discard or not.
+    pub discard: bool,
+    /// The write-zeroes state.
+    pub write_zeroes: WriteZeroesState,
+}
+
+/// The operations of the block backend, `T` is the complete callback of requests.
+pub trait BlockDriverOps<T: Clone>: Send {
+    /// Get the virtual size of the disk in bytes.
+    fn disk_size(&mut self) -> Result<u64>;
+
+    /// Grow the virtual size of the disk to `new_size` bytes, shrinking is refused.
+    fn resize(&mut self, new_size: u64) -> Result<()>;
+
+    /// Read data from the disk at `offset` into `iovec`.
+    fn read_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()>;
+
+    /// Write data of `iovec` into the disk at `offset`.
+    fn write_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()>;
+
+    /// Flush the data of the disk to the storage.
+    fn datasync(&mut self, completecb: T) -> Result<()>;
+
+    /// Discard the data range of the disk.
+    fn discard(&mut self, offset: usize, nbytes: u64, completecb: T) -> Result<()>;
+
+    /// Write zeroes to the data range of the disk, it is allowed to free the space if `unmap`.
+    fn write_zeroes(
+        &mut self,
+        offset: usize,
+        nbytes: u64,
+        completecb: T,
+        unmap: bool,
+    ) -> Result<()>;
+
+    /// Submit the requests which are queued in aio.
+    fn flush_request(&mut self) -> Result<()>;
+
+    /// Register the io completion events to the event loop.
+    fn register_io_event(
+        &mut self,
+        broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Result<()>;
+
+    /// Unregister the io completion events from the event loop.
+    fn unregister_io_event(&mut self) -> Result<()>;
+}
+
+/// Create the block backend of the image file according to its format.
+pub fn create_block_backend<T: Clone + 'static>(
+    file: File,
+    aio: Aio<T>,
+    prop: BlockProperty,
+) -> Result<Arc<Mutex<dyn BlockDriverOps<T>>>> {
+    match prop.format {
+        DiskFormat::Raw => {
+            let raw_file = RawDriver::new(file, aio, prop);
+            Ok(Arc::new(Mutex::new(raw_file)))
+        }
+        DiskFormat::Qcow2 => {
+            let path = prop.path.clone();
+            let qcow2 = Qcow2Driver::new(file, aio, prop)
+                .with_context(|| format!("Failed to open qcow2 image {}", path))?;
+            Ok(Arc::new(Mutex::new(qcow2)))
+        }
+    }
+}
+
+/// Create the block backend of the disk exported by an NBD server, `prop.path` is
+/// the `nbd:` path of the drive.
+pub fn create_nbd_backend<T: Clone + 'static>(
+    aio: Aio<T>,
+    prop: BlockProperty,
+) -> Result<Arc<Mutex<dyn BlockDriverOps<T>>>> {
+    let path = prop.path.clone();
+    let nbd =
+        NbdDriver::new(aio, prop).with_context(|| format!("Failed to open NBD export {}", path))?;
+    Ok(Arc::new(Mutex::new(nbd)))
+}
+
+/// Information of the block device reported by qmp.
+#[derive(Clone)]
+pub struct BlockDevInfo {
+    /// Properties of the block backend.
+    pub prop: BlockProperty,
+    /// The block device is read only or not.
+    pub read_only: bool,
+    /// The aio engine used by the block backend.
+    pub aio: AioEngine,
+    /// I/O throttling of the block device, None if not supported.
+    pub throttle: Option<Arc<Mutex<Throttle>>>,
+    /// The throttle group which the block device joins.
+    pub throttle_group: Option<String>,
+    /// The medium of the block device is removable or not.
+    pub removable: bool,
+    /// I/O statistics of the block device.
+    pub stats: Arc<BlockStats>,
+    /// Callback to resize the disk, None if not supported.
+    pub resize: Option<BlockResizeCallback>,
+    /// Hook to copy the old data for the backup job, None if not supported.
+    pub backup: Option<Arc<CopyBeforeWrite>>,
+    /// Dirty bitmaps tracking the writes of the guest, None if not supported.
+    pub dirty_bitmaps: Option<Arc<BlockDirtyBitmaps>>,
+    /// Operations on the removable medium, None if the medium is not removable.
+    pub medium: Option<Arc<dyn BlockMediumOps>>,
+}
+
+impl BlockDevInfo {
+    /// The device has a medium or not, the removable device may be empty.
+    fn inserted(&self) -> bool {
+        !self.prop.path.is_empty()
+    }
+
+    fn device_info(&self) -> qmp_schema::BlockDeviceInfo {
+        let aio = match self.aio {
+            AioEngine::Off => "threads",
+            AioEngine::Native => "native",
+            AioEngine::IoUring => "io_uring",
+        };
+        let detect_zeroes = match self.prop.write_zeroes {
+            WriteZeroesState::Off => "off",
+            WriteZeroesState::On => "on",
+            WriteZeroesState::Unmap => "unmap",
+        };
+        let throttle = self
+            .throttle
+            .as_ref()
+            .map(|t| t.lock().unwrap().config())
+            .unwrap_or_default();
+        let max = |limit: &ThrottleLimit| (limit.max != 0).then_some(limit.max);
+        let max_length = |limit: &ThrottleLimit| (limit.max != 0).then_some(limit.max_length);
+        qmp_schema::BlockDeviceInfo {
+            file: self.prop.path.clone(),
+            node_name: self.prop.id.clone(),
+            ro: self.read_only,
+            drv: self.prop.format.to_string(),
+            aio: aio.to_string(),
+            encrypted: false,
+            detect_zeroes: detect_zeroes.to_string(),
+            bps: throttle.bps_total.avg,
+            bps_rd: throttle.bps_read.avg,
+            bps_wr: throttle.bps_write.avg,
+            iops: throttle.iops_total.avg,
+            iops_rd: throttle.iops_read.avg,
+            iops_wr: throttle.iops_write.avg,
+            bps_max: max(&throttle.bps_total),
+            bps_rd_max: max(&throttle.bps_read),
+            bps_wr_max: max(&throttle.bps_write),
+            iops_max: max(&throttle.iops_total),
+            iops_rd_max: max(&throttle.iops_read),
+            iops_wr_max: max(&throttle.iops_write),
+            bps_max_length: max_length(&throttle.bps_total),
+            bps_rd_max_length: max_length(&throttle.bps_read),
+            bps_wr_max_length: max_length(&throttle.bps_write),
+            iops_max_length: max_length(&throttle.iops_total),
+            iops_rd_max_length: max_length(&throttle.iops_read),
+            iops_wr_max_length: max_length(&throttle.iops_write),
+            group: self.throttle_group.clone(),
+            cache: qmp_schema::BlockDeviceCacheInfo {
+                writeback: true,
+                direct: self.prop.direct,
+                no_flush: false,
+            },
+            dirty_bitmaps: dirty_bitmap::dirty_bitmaps_info(&self.dirty_bitmaps),
+        }
+    }
+}
+
+/// All the block devices which have been realized, indexed by the device id.
+static BLOCK_DEVICES: Lazy<Mutex<BTreeMap<String, BlockDevInfo>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Register the block device to make it visible to the qmp queries, the old
+/// one with the same id is replaced.
+pub fn register_block_device(info: BlockDevInfo) {
+    let old = BLOCK_DEVICES
+        .lock()
+        .unwrap()
+        .insert(info.prop.id.clone(), info);
+    cancel_backup(old);
+}
+
+/// Update the properties of the registered block device after its medium is changed.
+pub fn update_block_device(id: &str, prop: BlockProperty) {
+    if let Some(info) = BLOCK_DEVICES.lock().unwrap().get_mut(id) {
+        info.prop = prop;
+    }
+}
+
+pub fn unregister_block_device(id: &str) {
+    let old = BLOCK_DEVICES.lock().unwrap().remove(id);
+    cancel_backup(old);
+}
+
+/// The backup job can't go on once the disk of the block device is changed or removed.
+fn cancel_backup(info: Option<BlockDevInfo>) {
+    if let Some(backup) = info.and_then(|info| info.backup) {
+        backup.cancel();
+    }
+}
+
+pub fn qmp_block_set_io_throttle(args: &qmp_schema::BlockSetIoThrottleArgument) -> Result<()> {
+    let id = args
+        .device
+        .as_ref()
+        .or(args.id.as_ref())
+        .with_context(|| "Neither device nor id of the block device is specified")?;
+    let config = ThrottleConfig::from_qmp_args(args);
+    config.check()?;
+
+    let locked_devices = BLOCK_DEVICES.lock().unwrap();
+    let info = locked_devices
+        .get(id)
+        .with_context(|| format!("Block device {} not found", id))?;
+    let throttle = match args.group.as_ref() {
+        // The limits of the group are shared by all the block devices of it.
+        Some(group) => {
+            if info.throttle_group.as_ref() != Some(group) {
+                bail!("Block device {} doesn't join throttle group {}", id, group);
+            }
+            throttle::find_throttle_group(group)
+                .with_context(|| format!("Throttle group {} not found", group))?
+        }
+        None => info
+            .throttle
+            .clone()
+            .with_context(|| format!("Block device {} doesn't support I/O throttling", id))?,
+    };
+    throttle.lock().unwrap().set_config(&config);
+    Ok(())
+}
+
+pub fn qmp_block_resize(
+    device: &str,
+    size: u64,
+    drive_files: &Arc<Mutex<HashMap<String, DriveFile>>>,
+) -> Result<()> {
+    if size == 0 || size % SECTOR_SIZE != 0 {
+        bail!(
+            "Invalid size {}, it should be a non-zero multiple of {}",
+            size,
+            SECTOR_SIZE
+        );
+    }
+
+    // Don't hold the lock of block devices when resizing, the block device may
+    // be realized at the same time.
+    let (path, read_only, resize) = {
+        let locked_devices = BLOCK_DEVICES.lock().unwrap();
+        let info = locked_devices
+            .get(device)
+            .with_context(|| format!("Block device {} not found", device))?;
+        (info.prop.path.clone(), info.read_only, info.resize.clone())
+    };
+    if read_only {
+        bail!("Block device {} is read only", device);
+    }
+    let resize =
+        resize.with_context(|| format!("Block device {} doesn't support resizing", device))?;
+    // The clients of NBD export keep using the size got in the handshake.
+    if nbd::server::is_nbd_exported(device) {
+        bail!(
+            "Block device {} is exported by NBD server, it can't be resized",
+            device
+        );
+    }
+    {
+        let locked_files = drive_files.lock().unwrap();
+        let drive_file = locked_files
+            .get(&path)
+            .with_context(|| format!("Drive file {} is not registered", path))?;
+        if drive_file.read_only {
+            bail!("Drive file {} is read only", path);
+        }
+        if drive_file.count > 1 {
+            bail!(
+                "Drive file {} is shared by {} drives, it can't be resized",
+                path,
+                drive_file.count
+            );
+        }
+    }
+
+    resize(size)
+}
+
+/// Get the operations on the removable medium of the block device `device`.
+fn block_medium(device: &str) -> Result<Arc<dyn BlockMediumOps>> {
+    let locked_devices = BLOCK_DEVICES.lock().unwrap();
+    let info = locked_devices
+        .get(device)
+        .with_context(|| format!("Block device {} not found", device))?;
+    info.medium
+        .clone()
+        .with_context(|| format!("Block device {} is not removable", device))
+}
+
+pub fn qmp_eject(args: &qmp_schema::eject) -> Result<()> {
+    let device = args
+        .device
+        .as_ref()
+        .or(args.id.as_ref())
+        .with_context(|| "Neither device nor id of the block device is specified")?;
+    // Don't hold the lock of block devices, the medium is registered again once changed.
+    block_medium(device)?.eject(args.force.unwrap_or(false))
+}
+
+pub fn qmp_blockdev_change_medium(args: &qmp_schema::blockdev_change_medium) -> Result<()> {
+    let device = args
+        .device
+        .as_ref()
+        .or(args.id.as_ref())
+        .with_context(|| "Neither device nor id of the block device is specified")?;
+    let format = args
+        .format
+        .as_deref()
+        .unwrap_or("raw")
+        .parse::<DiskFormat>()
+        .map_err(|_| anyhow!("Unsupported image format {:?}", args.format))?;
+    block_medium(device)?.change_medium(&args.filename, format)
+}
+
+pub fn qmp_query_block() -> Vec<qmp_schema::BlockInfo> {
+    let locked_devices = BLOCK_DEVICES.lock().unwrap();
+    locked_devices
+        .values()
+        .map(|info| qmp_schema::BlockInfo {
+            device: info.prop.id.clone(),
+            qdev: info.prop.id.clone(),
+            block_type: "unknown".to_string(),
+            removable: info.removable,
+            locked: info.medium.as_ref().is_some_and(|m| m.locked()),
+            tray_open: info.medium.as_ref().map(|m| m.tray_open()),
+            inserted: info.inserted().then(|| info.device_info()),
+        })
+        .collect()
+}
+
+pub fn qmp_query_named_block_nodes() -> Vec<qmp_schema::BlockDeviceInfo> {
+    let locked_devices = BLOCK_DEVICES.lock().unwrap();
+    locked_devices
+        .values()
+        .filter(|info| info.inserted())
+        .map(|info| info.device_info())
+        .collect()
+}
+
+pub fn qmp_query_blockstats() -> Vec<qmp_schema::BlockStats> {
+    let locked_devices = BLOCK_DEVICES.lock().unwrap();
+    locked_devices
+        .values()
+        .map(|info| qmp_schema::BlockStats {
+            device: info.prop.id.clone(),
+            qdev: info.prop.id.clone(),
+            node_name: info.prop.id.clone(),
+            stats: info.stats.query(),
+        })
+        .collect()
+}
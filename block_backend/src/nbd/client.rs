@@ -0,0 +1,701 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use log::{error, warn};
+
+use super::*;
+use machine_manager::config::{NbdConfig, NbdServerAddr};
+use util::aio::{iov_from_buf_direct, iov_slice, iov_to_buf_direct, Aio, AioCb, OpCode};
+
+/// Timeout of connecting and the handshake with the NBD server.
+const NBD_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The requests are kept for resending within this time after the connection is lost,
+/// then they are failed while reconnecting continues.
+pub const NBD_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// Size of the buffer used to receive the replies from the socket.
+const NBD_RECV_BUF_SIZE: usize = 64 * 1024;
+/// Max length of the structured reply chunk, the payload with its header.
+const NBD_MAX_REPLY_LEN: usize = NBD_MAX_PAYLOAD_LEN as usize + 4096;
+/// Max length of the message of the option reply.
+const NBD_MAX_OPT_REPLY_LEN: u32 = 64 * 1024;
+
+/// Socket connected to the NBD server.
+enum NbdStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl NbdStream {
+    fn connect(addr: &NbdServerAddr) -> Result<Self> {
+        let stream = match addr {
+            NbdServerAddr::Unix(path) => NbdStream::Unix(UnixStream::connect(path)?),
+            NbdServerAddr::Tcp(host, port) => {
+                let stream = connect_tcp(host, *port)?;
+                stream.set_nodelay(true)?;
+                NbdStream::Tcp(stream)
+            }
+        };
+        Ok(stream)
+    }
+
+    fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        match self {
+            NbdStream::Unix(s) => {
+                s.set_read_timeout(timeout)?;
+                s.set_write_timeout(timeout)?;
+            }
+            NbdStream::Tcp(s) => {
+                s.set_read_timeout(timeout)?;
+                s.set_write_timeout(timeout)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        match self {
+            NbdStream::Unix(s) => s.set_nonblocking(nonblocking)?,
+            NbdStream::Tcp(s) => s.set_nonblocking(nonblocking)?,
+        }
+        Ok(())
+    }
+}
+
+/// Connect to the first reachable address of the host, each one is tried
+/// within the timeout.
+fn connect_tcp(host: &str, port: u16) -> Result<TcpStream> {
+    let mut last_err = None;
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, NBD_HANDSHAKE_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    match last_err {
+        Some(e) => Err(e.into()),
+        None => bail!("No address is resolved for host {}", host),
+    }
+}
+
+impl Read for NbdStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            NbdStream::Unix(s) => s.read(buf),
+            NbdStream::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for NbdStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            NbdStream::Unix(s) => s.write(buf),
+            NbdStream::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            NbdStream::Unix(s) => s.flush(),
+            NbdStream::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+impl AsRawFd for NbdStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            NbdStream::Unix(s) => s.as_raw_fd(),
+            NbdStream::Tcp(s) => s.as_raw_fd(),
+        }
+    }
+}
+
+/// Export negotiated with the NBD server.
+struct NbdExport {
+    stream: NbdStream,
+    size: u64,
+    flags: u16,
+    structured_reply: bool,
+}
+
+fn send_option(stream: &mut NbdStream, option: u32, data: &[u8]) -> Result<()> {
+    let mut buf = vec![0_u8; 16];
+    BigEndian::write_u64(&mut buf[0..8], NBD_OPTS_MAGIC);
+    BigEndian::write_u32(&mut buf[8..12], option);
+    BigEndian::write_u32(&mut buf[12..16], data.len() as u32);
+    buf.extend_from_slice(data);
+    stream
+        .write_all(&buf)
+        .with_context(|| format!("Failed to send NBD option {}", option))
+}
+
+/// Receive the reply of the `option`, return the reply type and the data.
+fn recv_option_reply(stream: &mut NbdStream, option: u32) -> Result<(u32, Vec<u8>)> {
+    let mut header = [0_u8; 20];
+    stream
+        .read_exact(&mut header)
+        .with_context(|| format!("Failed to receive the reply of NBD option {}", option))?;
+    if BigEndian::read_u64(&header[0..8]) != NBD_REP_MAGIC {
+        bail!("Invalid magic in the reply of NBD option {}", option);
+    }
+    if BigEndian::read_u32(&header[8..12]) != option {
+        bail!("Unexpected option in the reply of NBD option {}", option);
+    }
+    let reply = BigEndian::read_u32(&header[12..16]);
+    let len = BigEndian::read_u32(&header[16..20]);
+    if len > NBD_MAX_OPT_REPLY_LEN {
+        bail!("Too long reply {} of NBD option {}", len, option);
+    }
+    let mut data = vec![0_u8; len as usize];
+    stream
+        .read_exact(&mut data)
+        .with_context(|| format!("Failed to receive the reply of NBD option {}", option))?;
+    Ok((reply, data))
+}
+
+/// Ask the server to send structured replies, return false if it is not supported.
+fn negotiate_structured_reply(stream: &mut NbdStream) -> Result<bool> {
+    send_option(stream, NBD_OPT_STRUCTURED_REPLY, &[])?;
+    let (reply, _) = recv_option_reply(stream, NBD_OPT_STRUCTURED_REPLY)?;
+    Ok(reply == NBD_REP_ACK)
+}
+
+/// Select the export by `NBD_OPT_GO`, return None if the option is not supported.
+fn negotiate_go(stream: &mut NbdStream, export: &str) -> Result<Option<(u64, u16)>> {
+    let mut data = vec![0_u8; 4];
+    BigEndian::write_u32(&mut data[0..4], export.len() as u32);
+    data.extend_from_slice(export.as_bytes());
+    // No information request, the server always sends NBD_INFO_EXPORT.
+    data.extend_from_slice(&[0, 0]);
+    send_option(stream, NBD_OPT_GO, &data)?;
+
+    let mut info = None;
+    loop {
+        let (reply, data) = recv_option_reply(stream, NBD_OPT_GO)?;
+        match reply {
+            NBD_REP_ACK => break,
+            NBD_REP_INFO => {
+                if data.len() >= 12 && BigEndian::read_u16(&data[0..2]) == NBD_INFO_EXPORT {
+                    info = Some((
+                        BigEndian::read_u64(&data[2..10]),
+                        BigEndian::read_u16(&data[10..12]),
+                    ));
+                }
+            }
+            NBD_REP_ERR_UNSUP => return Ok(None),
+            _ if reply & NBD_REP_FLAG_ERROR != 0 => bail!(
+                "NBD server refused export \"{}\": error {:#x} {}",
+                export,
+                reply,
+                String::from_utf8_lossy(&data)
+            ),
+            _ => bail!("Unexpected reply {:#x} of NBD option GO", reply),
+        }
+    }
+    info.map(Some)
+        .with_context(|| "NBD server didn't send the information of export")
+}
+
+/// Select the export by `NBD_OPT_EXPORT_NAME`, the server closes the connection on failure.
+fn negotiate_export_name(
+    stream: &mut NbdStream,
+    export: &str,
+    no_zeroes: bool,
+) -> Result<(u64, u16)> {
+    send_option(stream, NBD_OPT_EXPORT_NAME, export.as_bytes())?;
+    let mut data = vec![0_u8; if no_zeroes { 10 } else { 134 }];
+    stream
+        .read_exact(&mut data)
+        .with_context(|| format!("NBD server refused export \"{}\"", export))?;
+    Ok((
+        BigEndian::read_u64(&data[0..8]),
+        BigEndian::read_u16(&data[8..10]),
+    ))
+}
+
+/// Connect to the NBD server and negotiate the export with the fixed newstyle handshake.
+fn connect_export(config: &NbdConfig) -> Result<NbdExport> {
+    let mut stream = NbdStream::connect(&config.addr)
+        .with_context(|| format!("Failed to connect to NBD server {}", config.addr))?;
+    stream.set_timeout(Some(NBD_HANDSHAKE_TIMEOUT))?;
+
+    let mut greeting = [0_u8; 18];
+    stream
+        .read_exact(&mut greeting)
+        .with_context(|| "Failed to receive the greeting of NBD server")?;
+    if BigEndian::read_u64(&greeting[0..8]) != NBD_INIT_MAGIC {
+        bail!("Invalid magic in the greeting of NBD server");
+    }
+    if BigEndian::read_u64(&greeting[8..16]) != NBD_OPTS_MAGIC {
+        bail!("NBD server with oldstyle handshake is not supported");
+    }
+    let handshake_flags = BigEndian::read_u16(&greeting[16..18]);
+    if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+        bail!("NBD server doesn't support fixed newstyle handshake");
+    }
+    let no_zeroes = handshake_flags & NBD_FLAG_NO_ZEROES != 0;
+    let mut client_flags = u32::from(NBD_FLAG_FIXED_NEWSTYLE);
+    if no_zeroes {
+        client_flags |= u32::from(NBD_FLAG_NO_ZEROES);
+    }
+    stream
+        .write_all(&client_flags.to_be_bytes())
+        .with_context(|| "Failed to send the flags of NBD client")?;
+
+    let structured_reply = negotiate_structured_reply(&mut stream)?;
+    let (size, flags) = match negotiate_go(&mut stream, &config.export)? {
+        Some(info) => info,
+        None => negotiate_export_name(&mut stream, &config.export, no_zeroes)?,
+    };
+
+    stream.set_timeout(None)?;
+    stream.set_nonblocking(true)?;
+    Ok(NbdExport {
+        stream,
+        size,
+        flags,
+        structured_reply,
+    })
+}
+
+/// Convert the errno of NBD protocol to the errno of the host.
+fn nbd_errno_to_host(errno: u32) -> i32 {
+    match errno {
+        NBD_EPERM => libc::EPERM,
+        NBD_EIO => libc::EIO,
+        NBD_ENOMEM => libc::ENOMEM,
+        NBD_EINVAL => libc::EINVAL,
+        NBD_ENOSPC => libc::ENOSPC,
+        NBD_EOVERFLOW => libc::EOVERFLOW,
+        NBD_ENOTSUP => libc::ENOTSUP,
+        NBD_ESHUTDOWN => libc::ESHUTDOWN,
+        _ => libc::EIO,
+    }
+}
+
+/// A request sent to the NBD server.
+struct NbdRequest<T: Clone> {
+    /// The request of the block backend.
+    cb: AioCb<T>,
+    /// Command of NBD protocol.
+    cmd: u16,
+    /// Command flags of NBD protocol.
+    flags: u16,
+    /// The first error reported by the structured reply chunks.
+    error: i64,
+}
+
+/// Append the request with its payload to `buf`.
+fn pack_request<T: Clone>(buf: &mut Vec<u8>, handle: u64, req: &NbdRequest<T>) -> Result<()> {
+    let mut header = [0_u8; 28];
+    BigEndian::write_u32(&mut header[0..4], NBD_REQUEST_MAGIC);
+    BigEndian::write_u16(&mut header[4..6], req.flags);
+    BigEndian::write_u16(&mut header[6..8], req.cmd);
+    BigEndian::write_u64(&mut header[8..16], handle);
+    BigEndian::write_u64(&mut header[16..24], req.cb.offset as u64);
+    BigEndian::write_u32(&mut header[24..28], req.cb.nbytes as u32);
+    buf.extend_from_slice(&header);
+
+    if req.cmd == NBD_CMD_WRITE {
+        let start = buf.len();
+        buf.resize(start + req.cb.nbytes as usize, 0);
+        // The payload is zeroes if the server doesn't support NBD_CMD_WRITE_ZEROES.
+        if req.cb.opcode == OpCode::Pwritev {
+            iov_to_buf_direct(&req.cb.iovec, &mut buf[start..])?;
+        }
+    }
+    Ok(())
+}
+
+/// Client of the NBD server, which keeps the requests until they are replied.
+pub struct NbdClient<T: Clone + 'static> {
+    config: NbdConfig,
+    stream: Option<NbdStream>,
+    /// Size of the export in bytes.
+    size: u64,
+    /// Transmission flags of the export.
+    flags: u16,
+    structured_reply: bool,
+    next_handle: u64,
+    /// Requests which are not replied yet, indexed by the handle.
+    inflight: BTreeMap<u64, NbdRequest<T>>,
+    send_buf: Vec<u8>,
+    send_off: usize,
+    recv_buf: Vec<u8>,
+    /// The time when the connection was lost.
+    disconnected_since: Option<Instant>,
+    /// Thread connecting to the server again, so that the iothread isn't blocked
+    /// by the handshake.
+    connecting: Option<JoinHandle<Result<NbdExport>>>,
+    /// Fd of the socket registered in the event loop.
+    pub event_fd: Option<RawFd>,
+    /// The io events are wanted by the block backend or not.
+    pub registered: bool,
+    aio: Aio<T>,
+}
+
+// SAFETY: Send is not auto-implemented for raw pointer type in Aio. The client
+// is shared behind a mutex, and the aio context is never used concurrently.
+unsafe impl<T: Clone + 'static> Send for NbdClient<T> {}
+
+impl<T: Clone + 'static> NbdClient<T> {
+    pub fn new(config: NbdConfig, aio: Aio<T>) -> Result<Self> {
+        let export = connect_export(&config)?;
+        Ok(Self {
+            config,
+            stream: Some(export.stream),
+            size: export.size,
+            flags: export.flags,
+            structured_reply: export.structured_reply,
+            next_handle: 0,
+            inflight: BTreeMap::new(),
+            send_buf: Vec::new(),
+            send_off: 0,
+            recv_buf: Vec::new(),
+            disconnected_since: None,
+            connecting: None,
+            event_fd: None,
+            registered: false,
+            aio,
+        })
+    }
+
+    pub fn addr(&self) -> &NbdServerAddr {
+        &self.config.addr
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Check whether the export supports the transmission flag.
+    pub fn has_flag(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn stream_fd(&self) -> Option<RawFd> {
+        self.stream.as_ref().map(|s| s.as_raw_fd())
+    }
+
+    /// Queue the request, it is sent by `flush_send`. The request is kept while
+    /// reconnecting, and failed if the connection is lost for too long.
+    pub fn submit(&mut self, cb: AioCb<T>, cmd: u16, flags: u16) -> Result<()> {
+        if self.stream.is_none() && self.reconnect_expired() {
+            return self.aio.complete_func(&cb, -i64::from(libc::EIO));
+        }
+
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        let req = NbdRequest {
+            cb,
+            cmd,
+            flags,
+            error: 0,
+        };
+        if self.stream.is_some() {
+            pack_request(&mut self.send_buf, handle, &req)?;
+        }
+        self.inflight.insert(handle, req);
+        Ok(())
+    }
+
+    /// Send the queued requests until the socket is full.
+    pub fn flush_send(&mut self) -> Result<()> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        while self.send_off < self.send_buf.len() {
+            match stream.write(&self.send_buf[self.send_off..]) {
+                Ok(0) => bail!("NBD server closed the connection"),
+                Ok(n) => self.send_off += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).with_context(|| "Failed to send NBD requests"),
+            }
+        }
+        self.send_buf.clear();
+        self.send_off = 0;
+        Ok(())
+    }
+
+    /// Handle the event of the socket, an error means the connection is lost.
+    pub fn handle_event(&mut self) -> Result<()> {
+        self.flush_send()?;
+
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let mut buf = vec![0_u8; NBD_RECV_BUF_SIZE];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => bail!("NBD server closed the connection"),
+                Ok(n) => self.recv_buf.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).with_context(|| "Failed to receive NBD replies"),
+            }
+        }
+
+        let mut recv_buf = std::mem::take(&mut self.recv_buf);
+        let mut pos = 0;
+        while let Some(len) = self.handle_reply(&recv_buf[pos..])? {
+            pos += len;
+        }
+        recv_buf.drain(..pos);
+        self.recv_buf = recv_buf;
+        Ok(())
+    }
+
+    /// Handle one reply at the beginning of `buf`, return its length or None if
+    /// the reply is not received completely.
+    fn handle_reply(&mut self, buf: &[u8]) -> Result<Option<usize>> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        match BigEndian::read_u32(&buf[0..4]) {
+            NBD_SIMPLE_REPLY_MAGIC => self.handle_simple_reply(buf),
+            NBD_STRUCTURED_REPLY_MAGIC if self.structured_reply => {
+                self.handle_structured_reply(buf)
+            }
+            magic => bail!("Invalid magic {:#x} in NBD reply", magic),
+        }
+    }
+
+    fn inflight_request(&mut self, handle: u64) -> Result<&mut NbdRequest<T>> {
+        self.inflight
+            .get_mut(&handle)
+            .with_context(|| format!("Unknown handle {} in NBD reply", handle))
+    }
+
+    fn handle_simple_reply(&mut self, buf: &[u8]) -> Result<Option<usize>> {
+        if buf.len() < 16 {
+            return Ok(None);
+        }
+        let errno = BigEndian::read_u32(&buf[4..8]);
+        let handle = BigEndian::read_u64(&buf[8..16]);
+        let structured_reply = self.structured_reply;
+        let req = self.inflight_request(handle)?;
+        let mut len = 16;
+        if req.cmd == NBD_CMD_READ && errno == 0 {
+            if structured_reply {
+                bail!("Simple reply to NBD read command after structured reply is negotiated");
+            }
+            len += req.cb.nbytes as usize;
+            if buf.len() < len {
+                return Ok(None);
+            }
+            iov_from_buf_direct(&req.cb.iovec, &buf[16..len])?;
+        }
+        if errno != 0 {
+            req.error = -i64::from(nbd_errno_to_host(errno));
+        }
+        self.complete_request(handle)?;
+        Ok(Some(len))
+    }
+
+    fn handle_structured_reply(&mut self, buf: &[u8]) -> Result<Option<usize>> {
+        if buf.len() < 20 {
+            return Ok(None);
+        }
+        let flags = BigEndian::read_u16(&buf[4..6]);
+        let reply_type = BigEndian::read_u16(&buf[6..8]);
+        let handle = BigEndian::read_u64(&buf[8..16]);
+        let len = 20 + BigEndian::read_u32(&buf[16..20]) as usize;
+        if len > NBD_MAX_REPLY_LEN {
+            bail!("Too long NBD reply chunk {}", len);
+        }
+        if buf.len() < len {
+            return Ok(None);
+        }
+        let payload = &buf[20..len];
+        let req = self.inflight_request(handle)?;
+
+        match reply_type {
+            NBD_REPLY_TYPE_NONE => {}
+            NBD_REPLY_TYPE_OFFSET_DATA | NBD_REPLY_TYPE_OFFSET_HOLE => {
+                if req.cmd != NBD_CMD_READ || payload.len() < 8 {
+                    bail!("Invalid NBD reply chunk type {}", reply_type);
+                }
+                let offset = BigEndian::read_u64(&payload[0..8]);
+                let size = if reply_type == NBD_REPLY_TYPE_OFFSET_DATA {
+                    payload.len() as u64 - 8
+                } else if payload.len() == 12 {
+                    u64::from(BigEndian::read_u32(&payload[8..12]))
+                } else {
+                    bail!("Invalid length of NBD hole chunk");
+                };
+                let start = req.cb.offset as u64;
+                if offset < start || offset + size > start + req.cb.nbytes {
+                    bail!("NBD reply chunk is out of the range of request");
+                }
+                let iov = iov_slice(&req.cb.iovec, offset - start, size);
+                if reply_type == NBD_REPLY_TYPE_OFFSET_DATA {
+                    iov_from_buf_direct(&iov, &payload[8..])?;
+                } else {
+                    iov_from_buf_direct(&iov, &vec![0_u8; size as usize])?;
+                }
+            }
+            NBD_REPLY_TYPE_ERROR | NBD_REPLY_TYPE_ERROR_OFFSET => {
+                if payload.len() < 6 {
+                    bail!("Invalid length of NBD error chunk");
+                }
+                let errno = nbd_errno_to_host(BigEndian::read_u32(&payload[0..4]));
+                let msg_len = usize::from(BigEndian::read_u16(&payload[4..6]));
+                let msg = payload.get(6..6 + msg_len).unwrap_or_default();
+                warn!(
+                    "NBD request failed: errno {}, {}",
+                    errno,
+                    String::from_utf8_lossy(msg)
+                );
+                if req.error == 0 {
+                    req.error = -i64::from(errno);
+                }
+            }
+            _ if reply_type & NBD_REPLY_TYPE_ERROR_BIT != 0 => {
+                if req.error == 0 {
+                    req.error = -i64::from(libc::EIO);
+                }
+            }
+            _ => bail!("Unknown NBD reply chunk type {}", reply_type),
+        }
+
+        if flags & NBD_REPLY_FLAG_DONE != 0 {
+            self.complete_request(handle)?;
+        }
+        Ok(Some(len))
+    }
+
+    /// Call the complete function of the request with the result `res`.
+    pub fn complete_func(&self, cb: &AioCb<T>, res: i64) -> Result<()> {
+        self.aio.complete_func(cb, res)
+    }
+
+    fn complete_request(&mut self, handle: u64) -> Result<()> {
+        let req = self.inflight.remove(&handle).unwrap();
+        let res = if req.error < 0 {
+            req.error
+        } else if req.cmd == NBD_CMD_READ || req.cmd == NBD_CMD_WRITE {
+            req.cb.nbytes as i64
+        } else {
+            0
+        };
+        self.aio.complete_func(&req.cb, res)
+    }
+
+    /// Drop the lost connection, the requests are kept for resending.
+    pub fn disconnect(&mut self) {
+        self.stream = None;
+        self.send_buf.clear();
+        self.send_off = 0;
+        self.recv_buf.clear();
+        if self.disconnected_since.is_none() {
+            self.disconnected_since = Some(Instant::now());
+        }
+    }
+
+    fn reconnect_expired(&self) -> bool {
+        self.disconnected_since
+            .is_some_and(|since| since.elapsed() >= NBD_RECONNECT_DELAY)
+    }
+
+    /// Connect to the server again in a thread, it's called periodically until the
+    /// connection is set up. Return true once the thread has connected, and the
+    /// requests which are not replied are resent.
+    pub fn reconnect(&mut self) -> Result<bool> {
+        if let Some(connecting) = self.connecting.take() {
+            if !connecting.is_finished() {
+                self.connecting = Some(connecting);
+                return Ok(false);
+            }
+            match connecting.join() {
+                Ok(Ok(export)) => {
+                    self.resume(export)?;
+                    return Ok(true);
+                }
+                Ok(Err(e)) => error!("Failed to reconnect to NBD server: {:?}", e),
+                Err(_) => error!("Thread reconnecting to NBD server panicked"),
+            }
+        }
+
+        let config = self.config.clone();
+        let connecting = thread::Builder::new()
+            .name("nbd-reconnect".to_string())
+            .spawn(move || connect_export(&config))
+            .with_context(|| "Failed to create thread to reconnect to NBD server")?;
+        self.connecting = Some(connecting);
+        Ok(false)
+    }
+
+    /// Use the new connection and resend the requests which are not replied.
+    fn resume(&mut self, export: NbdExport) -> Result<()> {
+        if export.size != self.size {
+            bail!(
+                "Size of NBD export changed from {} to {}",
+                self.size,
+                export.size
+            );
+        }
+        self.stream = Some(export.stream);
+        self.flags = export.flags;
+        self.structured_reply = export.structured_reply;
+        self.disconnected_since = None;
+
+        for (handle, req) in self.inflight.iter_mut() {
+            req.error = 0;
+            pack_request(&mut self.send_buf, *handle, req)?;
+        }
+        self.flush_send()
+    }
+
+    /// Fail the kept requests if the connection is lost for too long.
+    pub fn fail_expired_requests(&mut self) -> Result<()> {
+        if self.stream.is_some() || !self.reconnect_expired() {
+            return Ok(());
+        }
+        let inflight = std::mem::take(&mut self.inflight);
+        for req in inflight.into_values() {
+            self.aio.complete_func(&req.cb, -i64::from(libc::EIO))?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Clone + 'static> Drop for NbdClient<T> {
+    fn drop(&mut self) {
+        // Don't break the request being sent partially.
+        if self.send_off != 0 {
+            return;
+        }
+        if let Some(stream) = self.stream.as_mut() {
+            let mut header = [0_u8; 28];
+            BigEndian::write_u32(&mut header[0..4], NBD_REQUEST_MAGIC);
+            BigEndian::write_u16(&mut header[6..8], NBD_CMD_DISC);
+            BigEndian::write_u64(&mut header[8..16], self.next_handle);
+            if let Err(e) = stream.write(&header) {
+                error!("Failed to disconnect from NBD server: {:?}", e);
+            }
+        }
+    }
+}
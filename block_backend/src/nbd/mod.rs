@@ -0,0 +1,755 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Driver of the disk exported by an NBD (Network Block Device) server.
+//!
+//! The client negotiates the export with the fixed newstyle handshake and
+//! prefers structured replies. The requests are sent to the server without
+//! waiting, and completed when the replies arrive in the iothread. If the
+//! connection is lost, the client reconnects in a separate thread, so that the
+//! other devices of the iothread are not blocked, and resends the requests
+//! which are not replied.
+
+pub mod client;
+pub mod server;
+
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use log::{error, info};
+use vmm_sys_util::epoll::EventSet;
+
+use crate::{BlockDriverOps, BlockIoErrorCallback, BlockProperty};
+use client::NbdClient;
+use machine_manager::config::parse_nbd_path;
+use machine_manager::event_loop::EventLoop;
+use util::aio::{get_iov_size, iov_slice, Aio, AioCb, Iovec, OpCode};
+use util::loop_context::{
+    gen_delete_notifiers, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+
+/// Magic of the greeting sent by the server.
+pub const NBD_INIT_MAGIC: u64 = 0x4e42_444d_4147_4943;
+/// Magic of the options sent by the client, "IHAVEOPT".
+pub const NBD_OPTS_MAGIC: u64 = 0x4948_4156_454f_5054;
+/// Magic of the option replies sent by the server.
+pub const NBD_REP_MAGIC: u64 = 0x0003_e889_0455_65a9;
+pub const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+pub const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+pub const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+
+/// Handshake flags.
+pub const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+pub const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+
+/// Transmission flags of the export.
+pub const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+pub const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+pub const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+pub const NBD_FLAG_SEND_FUA: u16 = 1 << 3;
+pub const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+pub const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+/// Options of the handshake.
+pub const NBD_OPT_EXPORT_NAME: u32 = 1;
+pub const NBD_OPT_ABORT: u32 = 2;
+pub const NBD_OPT_GO: u32 = 7;
+pub const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
+
+/// Types of the option replies.
+pub const NBD_REP_ACK: u32 = 1;
+pub const NBD_REP_INFO: u32 = 3;
+pub const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+pub const NBD_REP_ERR_UNSUP: u32 = NBD_REP_FLAG_ERROR | 1;
+pub const NBD_REP_ERR_INVALID: u32 = NBD_REP_FLAG_ERROR | 3;
+pub const NBD_REP_ERR_UNKNOWN: u32 = NBD_REP_FLAG_ERROR | 6;
+
+/// Type of the information of the export.
+pub const NBD_INFO_EXPORT: u16 = 0;
+
+/// Commands of the transmission.
+pub const NBD_CMD_READ: u16 = 0;
+pub const NBD_CMD_WRITE: u16 = 1;
+pub const NBD_CMD_DISC: u16 = 2;
+pub const NBD_CMD_FLUSH: u16 = 3;
+pub const NBD_CMD_TRIM: u16 = 4;
+pub const NBD_CMD_WRITE_ZEROES: u16 = 6;
+
+/// Flags of the commands.
+pub const NBD_CMD_FLAG_FUA: u16 = 1 << 0;
+pub const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+/// Flag of the last chunk of the structured reply.
+pub const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
+
+/// Types of the structured reply chunks.
+pub const NBD_REPLY_TYPE_NONE: u16 = 0;
+pub const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+pub const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+pub const NBD_REPLY_TYPE_ERROR_BIT: u16 = 1 << 15;
+pub const NBD_REPLY_TYPE_ERROR: u16 = NBD_REPLY_TYPE_ERROR_BIT | 1;
+pub const NBD_REPLY_TYPE_ERROR_OFFSET: u16 = NBD_REPLY_TYPE_ERROR_BIT | 2;
+
+/// Errno of NBD protocol.
+pub const NBD_EPERM: u32 = 1;
+pub const NBD_EIO: u32 = 5;
+pub const NBD_ENOMEM: u32 = 12;
+pub const NBD_EINVAL: u32 = 22;
+pub const NBD_ENOSPC: u32 = 28;
+pub const NBD_EOVERFLOW: u32 = 75;
+pub const NBD_ENOTSUP: u32 = 95;
+pub const NBD_ESHUTDOWN: u32 = 108;
+
+/// Max length of the payload of one request, bigger requests are split.
+pub const NBD_MAX_PAYLOAD_LEN: u64 = 32 * 1024 * 1024;
+/// Max length of the request without payload, i.e. trim and write zeroes.
+const NBD_MAX_REQUEST_LEN: u64 = 1 << 30;
+/// Interval of reconnecting to the server.
+const NBD_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Driver of the disk exported by an NBD server.
+pub struct NbdDriver<T: Clone + 'static> {
+    client: Arc<Mutex<NbdClient<T>>>,
+    /// Properties of the block backend.
+    block_prop: BlockProperty,
+}
+
+impl<T: Clone + 'static> NbdDriver<T> {
+    pub fn new(aio: Aio<T>, block_prop: BlockProperty) -> Result<Self> {
+        let config = parse_nbd_path(&block_prop.path)?;
+        let client = NbdClient::new(config, aio)?;
+        Ok(Self {
+            client: Arc::new(Mutex::new(client)),
+            block_prop,
+        })
+    }
+
+    fn package_aiocb(
+        &self,
+        opcode: OpCode,
+        iovec: Vec<Iovec>,
+        offset: usize,
+        nbytes: u64,
+        iocompletecb: T,
+    ) -> AioCb<T> {
+        AioCb {
+            direct: false,
+            req_align: 1,
+            buf_align: 1,
+            // There is no host file, the request is sent by the client.
+            file_fd: -1,
+            opcode,
+            iovec,
+            offset,
+            nbytes,
+            user_data: 0,
+            iocompletecb,
+            discard: self.block_prop.discard,
+            write_zeroes: self.block_prop.write_zeroes,
+            write_zeroes_unmap: false,
+            combine_req: None,
+        }
+    }
+
+    /// Send the request in pieces of at most `max_len` bytes.
+    #[allow(clippy::too_many_arguments)]
+    fn process_request(
+        &mut self,
+        opcode: OpCode,
+        iovec: Vec<Iovec>,
+        offset: usize,
+        nbytes: u64,
+        completecb: T,
+        cmd: u16,
+        flags: u16,
+        max_len: u64,
+    ) -> Result<()> {
+        let mut client = self.client.lock().unwrap();
+        if nbytes <= max_len {
+            let aiocb = self.package_aiocb(opcode, iovec, offset, nbytes, completecb);
+            return client.submit(aiocb, cmd, flags);
+        }
+
+        let cnt = Arc::new(AtomicU32::new(nbytes.div_ceil(max_len) as u32));
+        let res = Arc::new(AtomicI64::new(0));
+        let mut pos = 0;
+        while pos < nbytes {
+            let len = (nbytes - pos).min(max_len);
+            let iov = if iovec.is_empty() {
+                Vec::new()
+            } else {
+                iov_slice(&iovec, pos, len)
+            };
+            let mut aiocb =
+                self.package_aiocb(opcode, iov, offset + pos as usize, len, completecb.clone());
+            aiocb.combine_req = Some((cnt.clone(), res.clone()));
+            client.submit(aiocb, cmd, flags)?;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Complete the request directly, used by the requests not supported by the server.
+    fn complete_request(&mut self, opcode: OpCode, res: i64, completecb: T) -> Result<()> {
+        let aiocb = self.package_aiocb(opcode, Vec::new(), 0, 0, completecb);
+        self.client.lock().unwrap().complete_func(&aiocb, res)
+    }
+}
+
+impl<T: Clone + 'static> BlockDriverOps<T> for NbdDriver<T> {
+    fn disk_size(&mut self) -> Result<u64> {
+        Ok(self.client.lock().unwrap().size())
+    }
+
+    fn resize(&mut self, _new_size: u64) -> Result<()> {
+        bail!("Resizing the disk exported by NBD server is not supported");
+    }
+
+    fn read_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()> {
+        let nbytes = get_iov_size(&iovec);
+        self.process_request(
+            OpCode::Preadv,
+            iovec,
+            offset,
+            nbytes,
+            completecb,
+            NBD_CMD_READ,
+            0,
+            NBD_MAX_PAYLOAD_LEN,
+        )
+    }
+
+    fn write_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()> {
+        let nbytes = get_iov_size(&iovec);
+        self.process_request(
+            OpCode::Pwritev,
+            iovec,
+            offset,
+            nbytes,
+            completecb,
+            NBD_CMD_WRITE,
+            0,
+            NBD_MAX_PAYLOAD_LEN,
+        )
+    }
+
+    fn datasync(&mut self, completecb: T) -> Result<()> {
+        if !self.client.lock().unwrap().has_flag(NBD_FLAG_SEND_FLUSH) {
+            return self.complete_request(OpCode::Fdsync, 0, completecb);
+        }
+        let aiocb = self.package_aiocb(OpCode::Fdsync, Vec::new(), 0, 0, completecb);
+        self.client.lock().unwrap().submit(aiocb, NBD_CMD_FLUSH, 0)
+    }
+
+    fn discard(&mut self, offset: usize, nbytes: u64, completecb: T) -> Result<()> {
+        // Discard is only a hint, ignore it if the server doesn't support trim.
+        if !self.block_prop.discard || !self.client.lock().unwrap().has_flag(NBD_FLAG_SEND_TRIM) {
+            return self.complete_request(OpCode::Discard, 0, completecb);
+        }
+        self.process_request(
+            OpCode::Discard,
+            Vec::new(),
+            offset,
+            nbytes,
+            completecb,
+            NBD_CMD_TRIM,
+            0,
+            NBD_MAX_REQUEST_LEN,
+        )
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: usize,
+        nbytes: u64,
+        completecb: T,
+        unmap: bool,
+    ) -> Result<()> {
+        if !self
+            .client
+            .lock()
+            .unwrap()
+            .has_flag(NBD_FLAG_SEND_WRITE_ZEROES)
+        {
+            // Write the zeroes as the payload.
+            return self.process_request(
+                OpCode::WriteZeroes,
+                Vec::new(),
+                offset,
+                nbytes,
+                completecb,
+                NBD_CMD_WRITE,
+                0,
+                NBD_MAX_PAYLOAD_LEN,
+            );
+        }
+        let flags = if unmap { 0 } else { NBD_CMD_FLAG_NO_HOLE };
+        self.process_request(
+            OpCode::WriteZeroes,
+            Vec::new(),
+            offset,
+            nbytes,
+            completecb,
+            NBD_CMD_WRITE_ZEROES,
+            flags,
+            NBD_MAX_REQUEST_LEN,
+        )
+    }
+
+    fn flush_request(&mut self) -> Result<()> {
+        // The lost connection is handled in the event of the socket.
+        if let Err(e) = self.client.lock().unwrap().flush_send() {
+            error!("Failed to send requests to NBD server: {:?}", e);
+        }
+        Ok(())
+    }
+
+    fn register_io_event(
+        &mut self,
+        broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Result<()> {
+        let mut client = self.client.lock().unwrap();
+        client.registered = true;
+        let fd = match client.stream_fd() {
+            Some(fd) => fd,
+            None => return Ok(()),
+        };
+        client.event_fd = Some(fd);
+        drop(client);
+
+        let handler = NbdIoHandler {
+            client: self.client.clone(),
+            iothread: self.block_prop.iothread.clone(),
+            broken,
+            error_cb,
+        };
+        let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
+        EventLoop::update_event(notifiers, self.block_prop.iothread.as_ref())
+    }
+
+    fn unregister_io_event(&mut self) -> Result<()> {
+        let mut client = self.client.lock().unwrap();
+        client.registered = false;
+        if let Some(fd) = client.event_fd.take() {
+            EventLoop::update_event(
+                gen_delete_notifiers(&[fd]),
+                self.block_prop.iothread.as_ref(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Handler of the events of the socket connected to the NBD server.
+struct NbdIoHandler<T: Clone + 'static> {
+    client: Arc<Mutex<NbdClient<T>>>,
+    iothread: Option<String>,
+    broken: Arc<AtomicBool>,
+    error_cb: BlockIoErrorCallback,
+}
+
+impl<T: Clone + 'static> NbdIoHandler<T> {
+    /// Try to reconnect to the server, return the notifiers of the new socket.
+    /// Check again later if not connected yet, until the io events are unregistered.
+    fn reconnect(handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let h_lock = handler.lock().unwrap();
+        let mut client = h_lock.client.lock().unwrap();
+        if !client.registered || client.event_fd.is_some() {
+            return Vec::new();
+        }
+        match client.reconnect() {
+            Ok(true) => {
+                info!("Reconnected to NBD server {}", client.addr());
+                client.event_fd = client.stream_fd();
+                drop(client);
+                drop(h_lock);
+                return EventNotifierHelper::internal_notifiers(handler);
+            }
+            Ok(false) => {}
+            Err(e) => error!("Failed to reconnect to NBD server: {:?}", e),
+        }
+        if let Err(e) = client.fail_expired_requests() {
+            error!("Failed to fail the NBD requests: {:?}", e);
+            (h_lock.error_cb)();
+        }
+        drop(client);
+
+        let iothread = h_lock.iothread.clone();
+        drop(h_lock);
+        let cloned_iothread = iothread.clone();
+        let reconnect = Box::new(move || {
+            let notifiers = Self::reconnect(handler.clone());
+            if notifiers.is_empty() {
+                return;
+            }
+            if let Err(e) = EventLoop::update_event(notifiers, cloned_iothread.as_ref()) {
+                error!("Failed to register the socket of NBD client: {:?}", e);
+            }
+        });
+        if let Some(ctx) = EventLoop::get_ctx(iothread.as_ref()) {
+            ctx.delay_call(reconnect, NBD_RECONNECT_INTERVAL);
+        } else {
+            error!("Failed to get ctx to delay reconnecting to NBD server");
+        }
+        Vec::new()
+    }
+}
+
+impl<T: Clone + 'static> EventNotifierHelper for NbdIoHandler<T> {
+    fn internal_notifiers(handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let fd = match handler.lock().unwrap().client.lock().unwrap().event_fd {
+            Some(fd) => fd,
+            None => return Vec::new(),
+        };
+
+        let h_clone = handler.clone();
+        let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            let h_lock = h_clone.lock().unwrap();
+            if h_lock.broken.load(Ordering::SeqCst) {
+                return None;
+            }
+            let mut client = h_lock.client.lock().unwrap();
+            let err = match client.handle_event() {
+                Ok(()) => return None,
+                Err(e) => e,
+            };
+            error!(
+                "Lost the connection to NBD server {}: {:?}",
+                client.addr(),
+                err
+            );
+            client.disconnect();
+            client.event_fd = None;
+            drop(client);
+            drop(h_lock);
+
+            let mut notifiers = gen_delete_notifiers(&[fd]);
+            notifiers.append(&mut Self::reconnect(h_clone.clone()));
+            Some(notifiers)
+        });
+
+        vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            fd,
+            None,
+            EventSet::IN | EventSet::OUT | EventSet::HANG_UP | EventSet::EDGE_TRIGGERED,
+            vec![h],
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::thread::{self, JoinHandle};
+    use std::time::Instant;
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    use super::*;
+    use machine_manager::config::DiskFormat;
+    use util::aio::{AioEngine, WriteZeroesState};
+
+    const DISK_SIZE: usize = 1 << 20;
+    const EXPORT_NAME: &str = "foo";
+
+    pub(super) fn socket_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("nbd-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn send_option_reply(stream: &mut UnixStream, option: u32, reply: u32, data: &[u8]) {
+        let mut buf = vec![0_u8; 20];
+        BigEndian::write_u64(&mut buf[0..8], NBD_REP_MAGIC);
+        BigEndian::write_u32(&mut buf[8..12], option);
+        BigEndian::write_u32(&mut buf[12..16], reply);
+        BigEndian::write_u32(&mut buf[16..20], data.len() as u32);
+        buf.extend_from_slice(data);
+        stream.write_all(&buf).unwrap();
+    }
+
+    /// Handshake of the test server, return false if the export is refused.
+    fn server_handshake(stream: &mut UnixStream) -> bool {
+        let mut greeting = vec![0_u8; 18];
+        BigEndian::write_u64(&mut greeting[0..8], NBD_INIT_MAGIC);
+        BigEndian::write_u64(&mut greeting[8..16], NBD_OPTS_MAGIC);
+        BigEndian::write_u16(
+            &mut greeting[16..18],
+            NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES,
+        );
+        stream.write_all(&greeting).unwrap();
+        let mut client_flags = [0_u8; 4];
+        stream.read_exact(&mut client_flags).unwrap();
+
+        let flags = NBD_FLAG_HAS_FLAGS
+            | NBD_FLAG_SEND_FLUSH
+            | NBD_FLAG_SEND_TRIM
+            | NBD_FLAG_SEND_WRITE_ZEROES;
+        loop {
+            let mut header = [0_u8; 16];
+            stream.read_exact(&mut header).unwrap();
+            assert_eq!(BigEndian::read_u64(&header[0..8]), NBD_OPTS_MAGIC);
+            let option = BigEndian::read_u32(&header[8..12]);
+            let mut data = vec![0_u8; BigEndian::read_u32(&header[12..16]) as usize];
+            stream.read_exact(&mut data).unwrap();
+            match option {
+                NBD_OPT_STRUCTURED_REPLY => send_option_reply(stream, option, NBD_REP_ACK, &[]),
+                NBD_OPT_GO => {
+                    let name_len = BigEndian::read_u32(&data[0..4]) as usize;
+                    if &data[4..4 + name_len] != EXPORT_NAME.as_bytes() {
+                        send_option_reply(stream, option, NBD_REP_ERR_UNKNOWN, b"unknown");
+                        return false;
+                    }
+                    let mut info = vec![0_u8; 12];
+                    BigEndian::write_u16(&mut info[0..2], NBD_INFO_EXPORT);
+                    BigEndian::write_u64(&mut info[2..10], DISK_SIZE as u64);
+                    BigEndian::write_u16(&mut info[10..12], flags);
+                    send_option_reply(stream, option, NBD_REP_INFO, &info);
+                    send_option_reply(stream, option, NBD_REP_ACK, &[]);
+                    return true;
+                }
+                _ => send_option_reply(stream, option, NBD_REP_ERR_UNSUP, &[]),
+            }
+        }
+    }
+
+    fn send_chunk(stream: &mut UnixStream, flags: u16, chunk_type: u16, handle: u64, data: &[u8]) {
+        let mut buf = vec![0_u8; 20];
+        BigEndian::write_u32(&mut buf[0..4], NBD_STRUCTURED_REPLY_MAGIC);
+        BigEndian::write_u16(&mut buf[4..6], flags);
+        BigEndian::write_u16(&mut buf[6..8], chunk_type);
+        BigEndian::write_u64(&mut buf[8..16], handle);
+        BigEndian::write_u32(&mut buf[16..20], data.len() as u32);
+        buf.extend_from_slice(data);
+        stream.write_all(&buf).unwrap();
+    }
+
+    fn send_simple_reply(stream: &mut UnixStream, errno: u32, handle: u64) {
+        let mut buf = vec![0_u8; 16];
+        BigEndian::write_u32(&mut buf[0..4], NBD_SIMPLE_REPLY_MAGIC);
+        BigEndian::write_u32(&mut buf[4..8], errno);
+        BigEndian::write_u64(&mut buf[8..16], handle);
+        stream.write_all(&buf).unwrap();
+    }
+
+    /// Reply the read request in two chunks, the zero part is replied as a hole.
+    fn server_read(stream: &mut UnixStream, disk: &[u8], handle: u64, offset: usize, len: usize) {
+        let half = len / 2;
+        for (start, end) in [(offset, offset + half), (offset + half, offset + len)] {
+            let flags = if end == offset + len {
+                NBD_REPLY_FLAG_DONE
+            } else {
+                0
+            };
+            let mut data = vec![0_u8; 8];
+            BigEndian::write_u64(&mut data[0..8], start as u64);
+            if disk[start..end].iter().all(|b| *b == 0) {
+                data.extend_from_slice(&((end - start) as u32).to_be_bytes());
+                send_chunk(stream, flags, NBD_REPLY_TYPE_OFFSET_HOLE, handle, &data);
+            } else {
+                data.extend_from_slice(&disk[start..end]);
+                send_chunk(stream, flags, NBD_REPLY_TYPE_OFFSET_DATA, handle, &data);
+            }
+        }
+    }
+
+    /// Serve the commands, close the connection without reply after receiving
+    /// `max_cmds` commands.
+    fn server_transmission(stream: &mut UnixStream, disk: &Mutex<Vec<u8>>, max_cmds: usize) {
+        for _ in 0..max_cmds {
+            let mut header = [0_u8; 28];
+            if stream.read_exact(&mut header).is_err() {
+                return;
+            }
+            assert_eq!(BigEndian::read_u32(&header[0..4]), NBD_REQUEST_MAGIC);
+            let cmd = BigEndian::read_u16(&header[6..8]);
+            let handle = BigEndian::read_u64(&header[8..16]);
+            let offset = BigEndian::read_u64(&header[16..24]) as usize;
+            let len = BigEndian::read_u32(&header[24..28]) as usize;
+            let mut payload = vec![0_u8; if cmd == NBD_CMD_WRITE { len } else { 0 }];
+            stream.read_exact(&mut payload).unwrap();
+
+            let mut disk = disk.lock().unwrap();
+            if offset + len > disk.len() {
+                let mut data = vec![0_u8; 6];
+                BigEndian::write_u32(&mut data[0..4], NBD_EINVAL);
+                send_chunk(
+                    stream,
+                    NBD_REPLY_FLAG_DONE,
+                    NBD_REPLY_TYPE_ERROR,
+                    handle,
+                    &data,
+                );
+                continue;
+            }
+            match cmd {
+                NBD_CMD_READ => server_read(stream, &disk, handle, offset, len),
+                NBD_CMD_WRITE => {
+                    disk[offset..offset + len].copy_from_slice(&payload);
+                    send_simple_reply(stream, 0, handle);
+                }
+                NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES => {
+                    disk[offset..offset + len].fill(0);
+                    send_chunk(
+                        stream,
+                        NBD_REPLY_FLAG_DONE,
+                        NBD_REPLY_TYPE_NONE,
+                        handle,
+                        &[],
+                    );
+                }
+                NBD_CMD_FLUSH => send_simple_reply(stream, 0, handle),
+                NBD_CMD_DISC => return,
+                _ => send_simple_reply(stream, NBD_EINVAL, handle),
+            }
+        }
+    }
+
+    /// Start the server serving the connections in turn, each one handles at most `max_cmds`.
+    fn start_server(path: &str, disk: Arc<Mutex<Vec<u8>>>, max_cmds: Vec<usize>) -> JoinHandle<()> {
+        let listener = UnixListener::bind(path).unwrap();
+        thread::spawn(move || {
+            for max in max_cmds {
+                let (mut stream, _) = listener.accept().unwrap();
+                if server_handshake(&mut stream) {
+                    server_transmission(&mut stream, &disk, max);
+                }
+            }
+        })
+    }
+
+    pub(super) fn create_driver(path: &str, export: &str) -> Result<NbdDriver<Arc<AtomicI64>>> {
+        let prop = BlockProperty {
+            id: "drive0".to_string(),
+            path: format!("nbd:unix:{}:exportname={}", path, export),
+            format: DiskFormat::Raw,
+            iothread: None,
+            direct: false,
+            req_align: 1,
+            buf_align: 1,
+            discard: true,
+            write_zeroes: WriteZeroesState::Off,
+        };
+        let complete_func = |cb: &AioCb<Arc<AtomicI64>>, res: i64| -> Result<()> {
+            cb.iocompletecb.store(res, Ordering::SeqCst);
+            Ok(())
+        };
+        let aio = Aio::new(Arc::new(complete_func), AioEngine::Off).unwrap();
+        NbdDriver::new(aio, prop)
+    }
+
+    /// Handle the events of the socket until the request completes.
+    pub(super) fn wait_complete(
+        driver: &mut NbdDriver<Arc<AtomicI64>>,
+        res: &AtomicI64,
+    ) -> Result<i64> {
+        driver.flush_request()?;
+        let start = Instant::now();
+        while res.load(Ordering::SeqCst) == i64::MIN {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            driver.client.lock().unwrap().handle_event()?;
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(res.swap(i64::MIN, Ordering::SeqCst))
+    }
+
+    #[test]
+    fn test_nbd_driver_rw() {
+        let path = socket_path("rw");
+        let disk = Arc::new(Mutex::new(vec![0_u8; DISK_SIZE]));
+        let server = start_server(&path, disk.clone(), vec![0, usize::MAX]);
+        // The export name is checked by the server.
+        assert!(create_driver(&path, "bar").is_err());
+        let mut driver = create_driver(&path, EXPORT_NAME).unwrap();
+        assert_eq!(driver.disk_size().unwrap(), DISK_SIZE as u64);
+        let res = Arc::new(AtomicI64::new(i64::MIN));
+
+        let wbuf: Vec<u8> = (0..10000).map(|i| (i % 251) as u8 + 1).collect();
+        let iovec = vec![
+            Iovec::new(wbuf.as_ptr() as u64, 3000),
+            Iovec::new(wbuf.as_ptr() as u64 + 3000, 7000),
+        ];
+        driver.write_vectored(iovec, 1000, res.clone()).unwrap();
+        assert_eq!(wait_complete(&mut driver, &res).unwrap(), 10000);
+        assert_eq!(disk.lock().unwrap()[1000..11000], wbuf[..]);
+
+        // The second half is replied as a hole.
+        let mut rbuf = vec![0xff_u8; 20000];
+        let iovec = vec![Iovec::new(rbuf.as_mut_ptr() as u64, 20000)];
+        driver.read_vectored(iovec, 1000, res.clone()).unwrap();
+        assert_eq!(wait_complete(&mut driver, &res).unwrap(), 20000);
+        assert_eq!(rbuf[..10000], wbuf[..]);
+        assert!(rbuf[10000..].iter().all(|b| *b == 0));
+
+        driver.write_zeroes(2000, 1000, res.clone(), false).unwrap();
+        assert_eq!(wait_complete(&mut driver, &res).unwrap(), 0);
+        driver.discard(4000, 1000, res.clone()).unwrap();
+        assert_eq!(wait_complete(&mut driver, &res).unwrap(), 0);
+        driver.datasync(res.clone()).unwrap();
+        assert_eq!(wait_complete(&mut driver, &res).unwrap(), 0);
+        let disk_data = disk.lock().unwrap()[1000..11000].to_vec();
+        assert!(disk_data[1000..2000].iter().all(|b| *b == 0));
+        assert!(disk_data[3000..4000].iter().all(|b| *b == 0));
+        assert_eq!(disk_data[2000..3000], wbuf[2000..3000]);
+
+        // The error of the server is reported to the request.
+        let iovec = vec![Iovec::new(rbuf.as_mut_ptr() as u64, 4096)];
+        driver
+            .read_vectored(iovec, DISK_SIZE - 512, res.clone())
+            .unwrap();
+        assert_eq!(
+            wait_complete(&mut driver, &res).unwrap(),
+            -i64::from(libc::EINVAL)
+        );
+
+        drop(driver);
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_nbd_driver_reconnect() {
+        let path = socket_path("reconnect");
+        let disk = Arc::new(Mutex::new(vec![0_u8; DISK_SIZE]));
+        // The first connection is closed when the write request is received.
+        let server = start_server(&path, disk.clone(), vec![1, usize::MAX]);
+        let mut driver = create_driver(&path, EXPORT_NAME).unwrap();
+        let res = Arc::new(AtomicI64::new(i64::MIN));
+
+        let wbuf = vec![0x5a_u8; 4096];
+        let iovec = vec![Iovec::new(wbuf.as_ptr() as u64, 4096)];
+        driver.write_vectored(iovec, 8192, res.clone()).unwrap();
+        assert!(wait_complete(&mut driver, &res).is_err());
+        assert_eq!(res.load(Ordering::SeqCst), i64::MIN);
+
+        // The request is resent after reconnecting in the thread.
+        driver.client.lock().unwrap().disconnect();
+        let start = Instant::now();
+        while !driver.client.lock().unwrap().reconnect().unwrap() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(wait_complete(&mut driver, &res).unwrap(), 4096);
+        assert_eq!(disk.lock().unwrap()[8192..12288], wbuf[..]);
+
+        drop(driver);
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
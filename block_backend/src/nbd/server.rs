@@ -0,0 +1,671 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Built-in NBD server exporting the disks of the VM.
+//!
+//! The exports are served by reading the drive file of the block device
+//! directly, every connection is handled in its own thread with blocking io,
+//! so the iothreads of the VM are not disturbed. Only simple replies are
+//! used in the transmission phase.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::remove_file;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+
+use super::*;
+use crate::file::SyncFile;
+use crate::BLOCK_DEVICES;
+use machine_manager::config::{DiskFormat, DriveFile, VmConfig};
+use machine_manager::qmp::qmp_schema;
+
+/// Max length of the export name or other option data sent by the client.
+const NBD_MAX_OPTION_LEN: u32 = 4096;
+/// Default max number of the connections served at the same time.
+const NBD_DEFAULT_MAX_CONNECTIONS: u32 = 16;
+
+/// The disk exported by the server.
+struct NbdServerExport {
+    /// Id of the exported block device.
+    device: String,
+    /// The drive file of the block device.
+    file: SyncFile,
+    size: u64,
+    /// Writes are allowed only while the VM is paused. The writes hold the read
+    /// lock until they are done, so revoking waits for the in-flight writes.
+    writable: RwLock<bool>,
+    /// Connections using the export, indexed by the connection id.
+    connections: Mutex<HashMap<u64, UnixStream>>,
+}
+
+impl NbdServerExport {
+    fn transmission_flags(&self) -> u16 {
+        let mut flags = NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH;
+        if *self.writable.read().unwrap() {
+            flags |= NBD_FLAG_SEND_TRIM | NBD_FLAG_SEND_WRITE_ZEROES;
+        } else {
+            flags |= NBD_FLAG_READ_ONLY;
+        }
+        flags
+    }
+
+    /// Handle the command of `len` bytes at `offset`, `data` is the payload
+    /// of read and write. Return the errno of NBD protocol on failure.
+    fn handle_command(&self, cmd: u16, offset: u64, len: u64, data: &mut [u8]) -> u32 {
+        if cmd != NBD_CMD_FLUSH && (offset > self.size || len > self.size - offset) {
+            return NBD_EINVAL;
+        }
+        let is_write = matches!(cmd, NBD_CMD_WRITE | NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES);
+        let _writable = if is_write {
+            let writable = self.writable.read().unwrap();
+            if !*writable {
+                return NBD_EPERM;
+            }
+            Some(writable)
+        } else {
+            None
+        };
+        let res = match cmd {
+            NBD_CMD_READ => self.file.read_at(data, offset),
+            NBD_CMD_WRITE => self.file.write_at(data, offset),
+            NBD_CMD_FLUSH => self.file.sync(),
+            NBD_CMD_TRIM => self.file.discard(offset, len),
+            NBD_CMD_WRITE_ZEROES => self.write_zeroes(offset, len),
+            _ => return NBD_EINVAL,
+        };
+        match res {
+            Ok(()) => 0,
+            Err(e) => {
+                error!("NBD export of {} failed: {:?}", self.device, e);
+                NBD_EIO
+            }
+        }
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> Result<()> {
+        let zeroes = vec![0_u8; len.min(NBD_MAX_PAYLOAD_LEN) as usize];
+        let mut pos = 0;
+        while pos < len {
+            let count = (len - pos).min(zeroes.len() as u64);
+            self.file
+                .write_at(&zeroes[..count as usize], offset + pos)?;
+            pos += count;
+        }
+        Ok(())
+    }
+}
+
+/// The NBD server listening on the unix socket.
+struct NbdServer {
+    path: String,
+    exports: Arc<Mutex<BTreeMap<String, Arc<NbdServerExport>>>>,
+}
+
+/// The NBD server of the VM, at most one server is running.
+static NBD_SERVER: Lazy<Mutex<Option<NbdServer>>> = Lazy::new(|| Mutex::new(None));
+
+/// Id of the next connection.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Number of the connections being served, each one has its own thread.
+static CONNECTION_COUNT: AtomicU32 = AtomicU32::new(0);
+
+fn send_option_reply(stream: &mut UnixStream, option: u32, reply: u32, data: &[u8]) -> Result<()> {
+    let mut buf = vec![0_u8; 20];
+    BigEndian::write_u64(&mut buf[0..8], NBD_REP_MAGIC);
+    BigEndian::write_u32(&mut buf[8..12], option);
+    BigEndian::write_u32(&mut buf[12..16], reply);
+    BigEndian::write_u32(&mut buf[16..20], data.len() as u32);
+    buf.extend_from_slice(data);
+    stream
+        .write_all(&buf)
+        .with_context(|| "Failed to send NBD option reply")
+}
+
+fn send_export_info(stream: &mut UnixStream, option: u32, export: &NbdServerExport) -> Result<()> {
+    let mut info = vec![0_u8; 12];
+    BigEndian::write_u16(&mut info[0..2], NBD_INFO_EXPORT);
+    BigEndian::write_u64(&mut info[2..10], export.size);
+    BigEndian::write_u16(&mut info[10..12], export.transmission_flags());
+    send_option_reply(stream, option, NBD_REP_INFO, &info)
+}
+
+/// Negotiate the export with the client, return None if the client aborts.
+fn server_handshake(
+    stream: &mut UnixStream,
+    exports: &Mutex<BTreeMap<String, Arc<NbdServerExport>>>,
+) -> Result<Option<Arc<NbdServerExport>>> {
+    let mut greeting = vec![0_u8; 18];
+    BigEndian::write_u64(&mut greeting[0..8], NBD_INIT_MAGIC);
+    BigEndian::write_u64(&mut greeting[8..16], NBD_OPTS_MAGIC);
+    BigEndian::write_u16(
+        &mut greeting[16..18],
+        NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES,
+    );
+    stream.write_all(&greeting)?;
+    let mut client_flags = [0_u8; 4];
+    stream.read_exact(&mut client_flags)?;
+    let no_zeroes = BigEndian::read_u32(&client_flags) & u32::from(NBD_FLAG_NO_ZEROES) != 0;
+
+    loop {
+        let mut header = [0_u8; 16];
+        stream.read_exact(&mut header)?;
+        if BigEndian::read_u64(&header[0..8]) != NBD_OPTS_MAGIC {
+            bail!("Invalid magic of NBD option");
+        }
+        let option = BigEndian::read_u32(&header[8..12]);
+        let len = BigEndian::read_u32(&header[12..16]);
+        if len > NBD_MAX_OPTION_LEN {
+            bail!("Too long NBD option {}", len);
+        }
+        let mut data = vec![0_u8; len as usize];
+        stream.read_exact(&mut data)?;
+
+        match option {
+            NBD_OPT_EXPORT_NAME => {
+                let name = String::from_utf8_lossy(&data).to_string();
+                let export = exports
+                    .lock()
+                    .unwrap()
+                    .get(&name)
+                    .cloned()
+                    .with_context(|| format!("NBD export \"{}\" not found", name))?;
+                let mut reply = vec![0_u8; if no_zeroes { 10 } else { 134 }];
+                BigEndian::write_u64(&mut reply[0..8], export.size);
+                BigEndian::write_u16(&mut reply[8..10], export.transmission_flags());
+                stream.write_all(&reply)?;
+                return Ok(Some(export));
+            }
+            NBD_OPT_GO => {
+                let name = data
+                    .get(0..4)
+                    .map(|l| BigEndian::read_u32(l) as usize)
+                    .and_then(|l| data.get(4..4 + l))
+                    .map(|name| String::from_utf8_lossy(name).to_string());
+                let name = match name {
+                    Some(name) => name,
+                    None => {
+                        send_option_reply(stream, option, NBD_REP_ERR_INVALID, &[])?;
+                        continue;
+                    }
+                };
+                let export = exports.lock().unwrap().get(&name).cloned();
+                match export {
+                    Some(export) => {
+                        send_export_info(stream, option, &export)?;
+                        send_option_reply(stream, option, NBD_REP_ACK, &[])?;
+                        return Ok(Some(export));
+                    }
+                    None => {
+                        let msg = format!("export \"{}\" not found", name);
+                        send_option_reply(stream, option, NBD_REP_ERR_UNKNOWN, msg.as_bytes())?;
+                    }
+                }
+            }
+            NBD_OPT_ABORT => {
+                send_option_reply(stream, option, NBD_REP_ACK, &[])?;
+                return Ok(None);
+            }
+            _ => send_option_reply(stream, option, NBD_REP_ERR_UNSUP, &[])?,
+        }
+    }
+}
+
+/// Serve the commands of the client until it disconnects.
+fn server_transmission(stream: &mut UnixStream, export: &NbdServerExport) -> Result<()> {
+    loop {
+        let mut header = [0_u8; 28];
+        stream.read_exact(&mut header)?;
+        if BigEndian::read_u32(&header[0..4]) != NBD_REQUEST_MAGIC {
+            bail!("Invalid magic of NBD request");
+        }
+        let cmd = BigEndian::read_u16(&header[6..8]);
+        let handle = BigEndian::read_u64(&header[8..16]);
+        let offset = BigEndian::read_u64(&header[16..24]);
+        let len = u64::from(BigEndian::read_u32(&header[24..28]));
+        if cmd == NBD_CMD_DISC {
+            return Ok(());
+        }
+
+        let with_data = cmd == NBD_CMD_READ || cmd == NBD_CMD_WRITE;
+        if with_data && len > NBD_MAX_PAYLOAD_LEN {
+            bail!("Too long NBD request {}", len);
+        }
+        let mut data = vec![0_u8; if with_data { len as usize } else { 0 }];
+        if cmd == NBD_CMD_WRITE {
+            stream.read_exact(&mut data)?;
+        }
+        let errno = export.handle_command(cmd, offset, len, &mut data);
+
+        let mut reply = vec![0_u8; 16];
+        BigEndian::write_u32(&mut reply[0..4], NBD_SIMPLE_REPLY_MAGIC);
+        BigEndian::write_u32(&mut reply[4..8], errno);
+        BigEndian::write_u64(&mut reply[8..16], handle);
+        if cmd == NBD_CMD_READ && errno == 0 {
+            reply.extend_from_slice(&data);
+        }
+        stream.write_all(&reply)?;
+    }
+}
+
+fn handle_connection(
+    mut stream: UnixStream,
+    exports: Arc<Mutex<BTreeMap<String, Arc<NbdServerExport>>>>,
+) {
+    let export = match server_handshake(&mut stream, &exports) {
+        Ok(Some(export)) => export,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("NBD handshake failed: {:?}", e);
+            return;
+        }
+    };
+    let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst);
+    match stream.try_clone() {
+        Ok(s) => {
+            export.connections.lock().unwrap().insert(id, s);
+        }
+        Err(e) => {
+            error!("Failed to clone the NBD connection: {:?}", e);
+            return;
+        }
+    }
+    info!("NBD client connected to export of {}", export.device);
+    if let Err(e) = server_transmission(&mut stream, &export) {
+        info!(
+            "NBD client disconnected from export of {}: {:?}",
+            export.device, e
+        );
+    }
+    export.connections.lock().unwrap().remove(&id);
+}
+
+/// Start the NBD server listening on the unix socket `addr`, the connections
+/// beyond `max_connections` are closed at once.
+pub fn qmp_nbd_server_start(
+    addr: &qmp_schema::AddrOptions,
+    max_connections: Option<u32>,
+) -> Result<()> {
+    if addr.addr_type != "unix" {
+        bail!(
+            "Unsupported address type {} of NBD server, only unix is supported",
+            addr.addr_type
+        );
+    }
+    let max_connections = max_connections.unwrap_or(NBD_DEFAULT_MAX_CONNECTIONS);
+    if max_connections == 0 {
+        bail!("The max connections of NBD server should not be 0");
+    }
+    let path = addr.addr_data.path.as_str();
+    let mut server = NBD_SERVER.lock().unwrap();
+    if let Some(server) = server.as_ref() {
+        bail!("NBD server is already running on {}", server.path);
+    }
+    if Path::new(path).exists() {
+        remove_file(path).with_context(|| format!("Failed to remove socket file {}", path))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind NBD server socket {}", path))?;
+
+    let exports = Arc::new(Mutex::new(BTreeMap::new()));
+    let cloned_exports = exports.clone();
+    thread::Builder::new()
+        .name("nbd-server".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("Failed to accept NBD connection: {:?}", e);
+                        continue;
+                    }
+                };
+                if CONNECTION_COUNT.fetch_add(1, Ordering::SeqCst) >= max_connections {
+                    CONNECTION_COUNT.fetch_sub(1, Ordering::SeqCst);
+                    warn!(
+                        "NBD connection is refused, at most {} connections are allowed",
+                        max_connections
+                    );
+                    continue;
+                }
+                let exports = cloned_exports.clone();
+                let serve = move || {
+                    handle_connection(stream, exports);
+                    CONNECTION_COUNT.fetch_sub(1, Ordering::SeqCst);
+                };
+                if let Err(e) = thread::Builder::new()
+                    .name("nbd-conn".to_string())
+                    .spawn(serve)
+                {
+                    CONNECTION_COUNT.fetch_sub(1, Ordering::SeqCst);
+                    error!("Failed to create NBD connection thread: {:?}", e);
+                }
+            }
+        })
+        .with_context(|| "Failed to create NBD server thread")?;
+
+    *server = Some(NbdServer {
+        path: path.to_string(),
+        exports,
+    });
+    Ok(())
+}
+
+/// Export the disk of the block device `device` as `name`, the export is
+/// writable only if `writable` and the VM is paused (`vm_paused`).
+pub fn qmp_nbd_server_add(
+    device: &str,
+    name: Option<&str>,
+    writable: bool,
+    vm_paused: bool,
+    drive_files: &Arc<Mutex<HashMap<String, DriveFile>>>,
+) -> Result<()> {
+    if writable && !vm_paused {
+        bail!("Writable NBD export is only allowed while the VM is paused");
+    }
+    let server = NBD_SERVER.lock().unwrap();
+    let server = server
+        .as_ref()
+        .with_context(|| "NBD server is not running")?;
+    let name = name.unwrap_or(device);
+    if server.exports.lock().unwrap().contains_key(name) {
+        bail!("NBD export \"{}\" already exists", name);
+    }
+
+    let prop = {
+        let locked_devices = BLOCK_DEVICES.lock().unwrap();
+        let info = locked_devices
+            .get(device)
+            .with_context(|| format!("Block device {} not found", device))?;
+        if writable && info.read_only {
+            bail!("Block device {} is read only", device);
+        }
+        info.prop.clone()
+    };
+    if prop.format != DiskFormat::Raw {
+        bail!(
+            "Block device {} with {} format can not be exported, only raw is supported",
+            device,
+            prop.format
+        );
+    }
+    let file = VmConfig::fetch_drive_file(&drive_files.lock().unwrap(), &prop.path)
+        .with_context(|| format!("Block device {} has no drive file", device))?;
+    let file = SyncFile::new(file, prop.req_align, prop.buf_align);
+    let size = file.file_size()?;
+
+    let export = NbdServerExport {
+        device: device.to_string(),
+        file,
+        size,
+        writable: RwLock::new(writable),
+        connections: Mutex::new(HashMap::new()),
+    };
+    server
+        .exports
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), Arc::new(export));
+    Ok(())
+}
+
+/// Remove the export `name`. With the `hard` mode, the connections using it
+/// are closed, while the default `safe` mode fails if it is in use.
+pub fn qmp_nbd_server_remove(name: &str, mode: Option<&str>) -> Result<()> {
+    let hard = match mode.unwrap_or("safe") {
+        "safe" => false,
+        "hard" => true,
+        m => bail!("Invalid mode {} to remove NBD export", m),
+    };
+    let server = NBD_SERVER.lock().unwrap();
+    let server = server
+        .as_ref()
+        .with_context(|| "NBD server is not running")?;
+    let mut exports = server.exports.lock().unwrap();
+    let export = exports
+        .get(name)
+        .with_context(|| format!("NBD export \"{}\" not found", name))?;
+    let connections = export.connections.lock().unwrap();
+    if !connections.is_empty() {
+        if !hard {
+            bail!(
+                "NBD export \"{}\" is in use by {} clients",
+                name,
+                connections.len()
+            );
+        }
+        for stream in connections.values() {
+            if let Err(e) = stream.shutdown(Shutdown::Both) {
+                warn!("Failed to close NBD connection: {:?}", e);
+            }
+        }
+    }
+    drop(connections);
+    exports.remove(name);
+    Ok(())
+}
+
+/// Whether the block device `device` is exported by the NBD server.
+pub fn is_nbd_exported(device: &str) -> bool {
+    NBD_SERVER.lock().unwrap().as_ref().is_some_and(|server| {
+        server
+            .exports
+            .lock()
+            .unwrap()
+            .values()
+            .any(|export| export.device == device)
+    })
+}
+
+/// Revoke the write permission of all exports, called when the VM is resumed.
+/// It returns after the in-flight writes of the exports are done.
+pub fn revoke_nbd_export_writable() {
+    if let Some(server) = NBD_SERVER.lock().unwrap().as_ref() {
+        for (name, export) in server.exports.lock().unwrap().iter() {
+            let mut writable = export.writable.write().unwrap();
+            if std::mem::replace(&mut *writable, false) {
+                warn!(
+                    "NBD export \"{}\" becomes read only as the VM resumes",
+                    name
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::FileExt;
+    use std::sync::atomic::AtomicI64;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::nbd::tests::{create_driver, socket_path, wait_complete};
+    use crate::{BlockDevInfo, BlockDriverOps, BlockProperty};
+    use util::aio::{AioEngine, Iovec, WriteZeroesState};
+
+    const DEVICE_ID: &str = "nbd-export0";
+    const DISK_SIZE: u64 = 1 << 20;
+
+    fn register_device(path: &str) -> Arc<Mutex<HashMap<String, DriveFile>>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        file.set_len(DISK_SIZE).unwrap();
+        let drive_file = DriveFile {
+            file,
+            count: 1,
+            path: path.to_string(),
+            read_only: false,
+            locked: false,
+            req_align: 1,
+            buf_align: 1,
+        };
+        let drive_files = Arc::new(Mutex::new(HashMap::new()));
+        drive_files
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), drive_file);
+        crate::register_block_device(BlockDevInfo {
+            prop: BlockProperty {
+                id: DEVICE_ID.to_string(),
+                path: path.to_string(),
+                format: DiskFormat::Raw,
+                iothread: None,
+                direct: false,
+                req_align: 1,
+                buf_align: 1,
+                discard: false,
+                write_zeroes: WriteZeroesState::Off,
+            },
+            read_only: false,
+            aio: AioEngine::Off,
+            throttle: None,
+            throttle_group: None,
+            removable: false,
+            stats: Arc::new(Default::default()),
+            resize: Some(Arc::new(|_| Ok(()))),
+            backup: None,
+            dirty_bitmaps: None,
+            medium: None,
+        });
+        drive_files
+    }
+
+    fn wait_connections(count: u32) {
+        let start = Instant::now();
+        while CONNECTION_COUNT.load(Ordering::SeqCst) != count {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_nbd_server_export() {
+        let img_path = std::env::temp_dir()
+            .join(format!("nbd-export-{}.img", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let drive_files = register_device(&img_path);
+        let data: Vec<u8> = (0..8192).map(|i| (i % 251) as u8 + 1).collect();
+        drive_files.lock().unwrap()[&img_path]
+            .file
+            .write_all_at(&data, 0)
+            .unwrap();
+
+        let sock_path = socket_path("server");
+        let addr = qmp_schema::AddrOptions {
+            addr_type: "unix".to_string(),
+            addr_data: qmp_schema::AddrDataOptions {
+                path: sock_path.clone(),
+            },
+        };
+        assert!(qmp_nbd_server_add(DEVICE_ID, None, false, false, &drive_files).is_err());
+        assert!(qmp_nbd_server_start(&addr, Some(0)).is_err());
+        qmp_nbd_server_start(&addr, Some(2)).unwrap();
+        assert!(qmp_nbd_server_start(&addr, None).is_err());
+        assert!(qmp_nbd_server_add("none", None, false, false, &drive_files).is_err());
+        // Writable export is refused while the VM is running.
+        assert!(qmp_nbd_server_add(DEVICE_ID, None, true, false, &drive_files).is_err());
+        qmp_nbd_server_add(DEVICE_ID, Some("ro"), false, false, &drive_files).unwrap();
+        assert!(qmp_nbd_server_add(DEVICE_ID, Some("ro"), false, false, &drive_files).is_err());
+        // The exported device can't be resized.
+        assert!(is_nbd_exported(DEVICE_ID));
+        assert!(crate::qmp_block_resize(DEVICE_ID, DISK_SIZE * 2, &drive_files).is_err());
+
+        let mut driver = create_driver(&sock_path, "ro").unwrap();
+        assert_eq!(driver.disk_size().unwrap(), DISK_SIZE);
+        // The connection beyond the max connections is refused.
+        let driver2 = create_driver(&sock_path, "ro").unwrap();
+        wait_connections(2);
+        assert!(create_driver(&sock_path, "ro").is_err());
+        drop(driver2);
+        wait_connections(1);
+        let res = Arc::new(AtomicI64::new(i64::MIN));
+        let mut rbuf = vec![0_u8; 8192];
+        let iovec = vec![Iovec::new(rbuf.as_mut_ptr() as u64, 8192)];
+        driver.read_vectored(iovec, 0, res.clone()).unwrap();
+        assert_eq!(wait_complete(&mut driver, &res).unwrap(), 8192);
+        assert_eq!(rbuf, data);
+        let iovec = vec![Iovec::new(rbuf.as_ptr() as u64, 512)];
+        driver.write_vectored(iovec, 0, res.clone()).unwrap();
+        assert_eq!(
+            wait_complete(&mut driver, &res).unwrap(),
+            -i64::from(libc::EPERM)
+        );
+        // The export in use is removed only in hard mode.
+        assert!(qmp_nbd_server_remove("ro", None).is_err());
+        assert!(qmp_nbd_server_remove("ro", Some("force")).is_err());
+        qmp_nbd_server_remove("ro", Some("hard")).unwrap();
+        assert!(qmp_nbd_server_remove("ro", Some("hard")).is_err());
+        assert!(!is_nbd_exported(DEVICE_ID));
+        crate::qmp_block_resize(DEVICE_ID, DISK_SIZE * 2, &drive_files).unwrap();
+        drop(driver);
+        wait_connections(0);
+
+        // The writable export becomes read only once the VM resumes.
+        qmp_nbd_server_add(DEVICE_ID, None, true, true, &drive_files).unwrap();
+        let mut driver = create_driver(&sock_path, DEVICE_ID).unwrap();
+        let wbuf = vec![0x5a_u8; 4096];
+        let iovec = vec![Iovec::new(wbuf.as_ptr() as u64, 4096)];
+        driver
+            .write_vectored(iovec.clone(), 4096, res.clone())
+            .unwrap();
+        assert_eq!(wait_complete(&mut driver, &res).unwrap(), 4096);
+        driver.write_zeroes(0, 1024, res.clone(), false).unwrap();
+        assert_eq!(wait_complete(&mut driver, &res).unwrap(), 0);
+        driver.datasync(res.clone()).unwrap();
+        assert_eq!(wait_complete(&mut driver, &res).unwrap(), 0);
+        let img = std::fs::read(&img_path).unwrap();
+        assert!(img[..1024].iter().all(|b| *b == 0));
+        assert_eq!(img[1024..4096], data[1024..4096]);
+        assert_eq!(img[4096..8192], wbuf[..]);
+
+        revoke_nbd_export_writable();
+        driver.write_vectored(iovec, 0, res.clone()).unwrap();
+        assert_eq!(
+            wait_complete(&mut driver, &res).unwrap(),
+            -i64::from(libc::EPERM)
+        );
+        // Out of range request is failed.
+        let iovec = vec![Iovec::new(rbuf.as_mut_ptr() as u64, 4096)];
+        driver
+            .read_vectored(iovec, DISK_SIZE as usize - 512, res.clone())
+            .unwrap();
+        assert_eq!(
+            wait_complete(&mut driver, &res).unwrap(),
+            -i64::from(libc::EINVAL)
+        );
+        drop(driver);
+
+        crate::unregister_block_device(DEVICE_ID);
+        std::fs::remove_file(&img_path).unwrap();
+        std::fs::remove_file(&sock_path).unwrap();
+    }
+}
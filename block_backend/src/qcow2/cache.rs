@@ -0,0 +1,94 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::HashMap;
+
+/// Cache of the metadata tables, indexed by the offset of the table in image.
+/// All the updates of tables are written through to the image, so the entries
+/// can be dropped at any time.
+pub struct Qcow2Cache<V> {
+    /// Max number of tables in cache.
+    capacity: usize,
+    /// The cached tables, with the last access time.
+    entries: HashMap<u64, (V, u64)>,
+    /// Increased on every access, used to find the least recently used table.
+    tick: u64,
+}
+
+impl<V> Qcow2Cache<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn get_mut(&mut self, offset: u64) -> Option<&mut V> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.get_mut(&offset).map(|(table, lru)| {
+            *lru = tick;
+            table
+        })
+    }
+
+    pub fn contains(&self, offset: u64) -> bool {
+        self.entries.contains_key(&offset)
+    }
+
+    pub fn insert(&mut self, offset: u64, table: V) {
+        if !self.entries.contains_key(&offset) && self.entries.len() >= self.capacity {
+            let lru_offset = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, lru))| *lru)
+                .map(|(offset, _)| *offset);
+            if let Some(lru_offset) = lru_offset {
+                self.entries.remove(&lru_offset);
+            }
+        }
+        self.tick += 1;
+        self.entries.insert(offset, (table, self.tick));
+    }
+
+    pub fn remove(&mut self, offset: u64) {
+        self.entries.remove(&offset);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_evict_lru() {
+        let mut cache = Qcow2Cache::new(2);
+        cache.insert(0, vec![0_u64]);
+        cache.insert(1, vec![1_u64]);
+        // Access table 0, so table 1 is the least recently used one.
+        assert_eq!(cache.get_mut(0).unwrap()[0], 0);
+        cache.insert(2, vec![2_u64]);
+        assert!(cache.contains(0));
+        assert!(!cache.contains(1));
+        assert!(cache.contains(2));
+
+        cache.remove(0);
+        assert!(cache.get_mut(0).is_none());
+        cache.clear();
+        assert!(!cache.contains(2));
+    }
+}
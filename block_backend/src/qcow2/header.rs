@@ -0,0 +1,227 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{bail, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+pub const QCOW_MAGIC: u32 = 0x5146_49fb;
+pub const QCOW_VERSION_2: u32 = 2;
+pub const QCOW_VERSION_3: u32 = 3;
+/// Size of the header of qcow2 version 2.
+pub const QCOW_V2_HEADER_SIZE: usize = 72;
+/// Size of the header of qcow2 version 3, without the optional fields.
+pub const QCOW_V3_HEADER_SIZE: usize = 104;
+pub const MIN_CLUSTER_BITS: u32 = 9;
+pub const MAX_CLUSTER_BITS: u32 = 21;
+pub const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+pub const MAX_REFCOUNT_ORDER: u32 = 6;
+/// Offset of `size` field in header.
+pub const SIZE_POS: u64 = 24;
+/// Offset of `l1_size` field in header, followed by `l1_table_offset`.
+pub const L1_SIZE_POS: u64 = 36;
+/// Offset of `refcount_table_offset` field in header.
+pub const REFCOUNT_TABLE_OFFSET_POS: u64 = 48;
+/// Offset of `incompatible_features` field in header.
+pub const INCOMPATIBLE_FEATURES_POS: u64 = 72;
+
+/// The image is marked dirty, the refcounts may be inconsistent.
+pub const QCOW2_INCOMPAT_DIRTY: u64 = 1 << 0;
+/// The image is marked corrupt, it must not be written.
+pub const QCOW2_INCOMPAT_CORRUPT: u64 = 1 << 1;
+
+/// End of the header extensions.
+pub const QCOW2_EXT_MAGIC_END: u32 = 0;
+/// Header extension containing the format name of the backing file.
+pub const QCOW2_EXT_MAGIC_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+/// The fixed part of qcow2 header, all fields are stored in big-endian.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QcowHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub backing_file_offset: u64,
+    pub backing_file_size: u32,
+    pub cluster_bits: u32,
+    pub size: u64,
+    pub crypt_method: u32,
+    pub l1_size: u32,
+    pub l1_table_offset: u64,
+    pub refcount_table_offset: u64,
+    pub refcount_table_clusters: u32,
+    pub nb_snapshots: u32,
+    pub snapshots_offset: u64,
+    // Following fields are only valid for version 3.
+    pub incompatible_features: u64,
+    pub compatible_features: u64,
+    pub autoclear_features: u64,
+    pub refcount_order: u32,
+    pub header_length: u32,
+}
+
+impl QcowHeader {
+    pub fn from_vec(buf: &[u8]) -> Result<Self> {
+        if buf.len() < QCOW_V2_HEADER_SIZE {
+            bail!("Invalid header len {}", buf.len());
+        }
+        let mut header = QcowHeader {
+            magic: BigEndian::read_u32(&buf[0..4]),
+            version: BigEndian::read_u32(&buf[4..8]),
+            backing_file_offset: BigEndian::read_u64(&buf[8..16]),
+            backing_file_size: BigEndian::read_u32(&buf[16..20]),
+            cluster_bits: BigEndian::read_u32(&buf[20..24]),
+            size: BigEndian::read_u64(&buf[24..32]),
+            crypt_method: BigEndian::read_u32(&buf[32..36]),
+            l1_size: BigEndian::read_u32(&buf[36..40]),
+            l1_table_offset: BigEndian::read_u64(&buf[40..48]),
+            refcount_table_offset: BigEndian::read_u64(&buf[48..56]),
+            refcount_table_clusters: BigEndian::read_u32(&buf[56..60]),
+            nb_snapshots: BigEndian::read_u32(&buf[60..64]),
+            snapshots_offset: BigEndian::read_u64(&buf[64..72]),
+            incompatible_features: 0,
+            compatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: DEFAULT_REFCOUNT_ORDER,
+            header_length: QCOW_V2_HEADER_SIZE as u32,
+        };
+        if header.magic != QCOW_MAGIC {
+            bail!("Invalid magic 0x{:x}", header.magic);
+        }
+        if header.version == QCOW_VERSION_3 {
+            if buf.len() < QCOW_V3_HEADER_SIZE {
+                bail!("Invalid header len {} for version 3", buf.len());
+            }
+            header.incompatible_features = BigEndian::read_u64(&buf[72..80]);
+            header.compatible_features = BigEndian::read_u64(&buf[80..88]);
+            header.autoclear_features = BigEndian::read_u64(&buf[88..96]);
+            header.refcount_order = BigEndian::read_u32(&buf[96..100]);
+            header.header_length = BigEndian::read_u32(&buf[100..104]);
+        }
+        header.check()?;
+        Ok(header)
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let len = if self.version == QCOW_VERSION_3 {
+            QCOW_V3_HEADER_SIZE
+        } else {
+            QCOW_V2_HEADER_SIZE
+        };
+        let mut buf = vec![0_u8; len];
+        BigEndian::write_u32(&mut buf[0..4], self.magic);
+        BigEndian::write_u32(&mut buf[4..8], self.version);
+        BigEndian::write_u64(&mut buf[8..16], self.backing_file_offset);
+        BigEndian::write_u32(&mut buf[16..20], self.backing_file_size);
+        BigEndian::write_u32(&mut buf[20..24], self.cluster_bits);
+        BigEndian::write_u64(&mut buf[24..32], self.size);
+        BigEndian::write_u32(&mut buf[32..36], self.crypt_method);
+        BigEndian::write_u32(&mut buf[36..40], self.l1_size);
+        BigEndian::write_u64(&mut buf[40..48], self.l1_table_offset);
+        BigEndian::write_u64(&mut buf[48..56], self.refcount_table_offset);
+        BigEndian::write_u32(&mut buf[56..60], self.refcount_table_clusters);
+        BigEndian::write_u32(&mut buf[60..64], self.nb_snapshots);
+        BigEndian::write_u64(&mut buf[64..72], self.snapshots_offset);
+        if self.version == QCOW_VERSION_3 {
+            BigEndian::write_u64(&mut buf[72..80], self.incompatible_features);
+            BigEndian::write_u64(&mut buf[80..88], self.compatible_features);
+            BigEndian::write_u64(&mut buf[88..96], self.autoclear_features);
+            BigEndian::write_u32(&mut buf[96..100], self.refcount_order);
+            BigEndian::write_u32(&mut buf[100..104], self.header_length);
+        }
+        buf
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.version != QCOW_VERSION_2 && self.version != QCOW_VERSION_3 {
+            bail!("Unsupported qcow2 version {}", self.version);
+        }
+        if self.cluster_bits < MIN_CLUSTER_BITS || self.cluster_bits > MAX_CLUSTER_BITS {
+            bail!("Invalid cluster bits {}", self.cluster_bits);
+        }
+        if self.crypt_method != 0 {
+            bail!("Encrypted qcow2 image is not supported");
+        }
+        if self.refcount_order > MAX_REFCOUNT_ORDER {
+            bail!("Invalid refcount order {}", self.refcount_order);
+        }
+        if (self.header_length as u64) > self.cluster_size() {
+            bail!("Header length {} exceeds cluster size", self.header_length);
+        }
+        if self.version == QCOW_VERSION_3 && (self.header_length as usize) < QCOW_V3_HEADER_SIZE {
+            bail!("Invalid header length {}", self.header_length);
+        }
+        let cluster_mask = self.cluster_size() - 1;
+        if self.l1_table_offset & cluster_mask != 0
+            || self.refcount_table_offset & cluster_mask != 0
+            || self.snapshots_offset & cluster_mask != 0
+        {
+            bail!("Metadata tables are not aligned to cluster size");
+        }
+        if self.refcount_table_offset == 0 || self.refcount_table_clusters == 0 {
+            bail!("Invalid refcount table");
+        }
+        if self.backing_file_offset != 0
+            && self.backing_file_offset + self.backing_file_size as u64 > self.cluster_size()
+        {
+            bail!("Backing file name is not in the first cluster");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_transform() {
+        let header = QcowHeader {
+            magic: QCOW_MAGIC,
+            version: QCOW_VERSION_3,
+            backing_file_offset: 0,
+            backing_file_size: 0,
+            cluster_bits: 16,
+            size: 1 << 30,
+            crypt_method: 0,
+            l1_size: 2,
+            l1_table_offset: 3 << 16,
+            refcount_table_offset: 1 << 16,
+            refcount_table_clusters: 1,
+            nb_snapshots: 0,
+            snapshots_offset: 0,
+            incompatible_features: 0,
+            compatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: DEFAULT_REFCOUNT_ORDER,
+            header_length: QCOW_V3_HEADER_SIZE as u32,
+        };
+        let buf = header.to_vec();
+        assert_eq!(buf.len(), QCOW_V3_HEADER_SIZE);
+        assert_eq!(QcowHeader::from_vec(&buf).unwrap(), header);
+
+        // Invalid magic.
+        let mut bad = buf.clone();
+        bad[0] = 0;
+        assert!(QcowHeader::from_vec(&bad).is_err());
+        // Invalid cluster bits.
+        let mut bad = buf.clone();
+        BigEndian::write_u32(&mut bad[20..24], MAX_CLUSTER_BITS + 1);
+        assert!(QcowHeader::from_vec(&bad).is_err());
+        // Encryption is not supported.
+        let mut bad = buf;
+        BigEndian::write_u32(&mut bad[32..36], 1);
+        assert!(QcowHeader::from_vec(&bad).is_err());
+    }
+}
@@ -0,0 +1,1427 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+pub mod cache;
+pub mod header;
+pub mod refcount;
+pub mod table;
+
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use log::error;
+
+use self::header::*;
+use self::refcount::{RefCount, REFCOUNT_TABLE_OFFSET_MASK};
+use self::table::Qcow2Table;
+use crate::file::{CombineRequest, FileDriver, SyncFile};
+use crate::{BlockDriverOps, BlockIoErrorCallback, BlockProperty};
+use machine_manager::config::DiskFormat;
+use util::aio::{
+    get_iov_size, iov_from_buf_direct, iov_slice, iov_to_buf_direct, Aio, Iovec, OpCode,
+};
+
+pub const L1_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+pub const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The cluster is referenced only once, it can be written in place.
+pub const QCOW2_OFLAG_COPIED: u64 = 1 << 63;
+/// The cluster is compressed.
+pub const QCOW2_OFLAG_COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as all zeros, only valid for version 3.
+pub const QCOW2_OFLAG_ZERO: u64 = 1 << 0;
+/// Max depth of the backing file chain.
+const MAX_BACKING_DEPTH: u32 = 16;
+pub const DEFAULT_CLUSTER_BITS: u32 = 16;
+
+/// Where the data of a guest cluster is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClusterMap {
+    /// Allocated at the host offset, the flag shows whether it can be written in place.
+    Normal(u64, bool),
+    /// Reads as zeros, the host cluster may still be allocated if the offset is not 0.
+    Zero(u64),
+    /// Not allocated, reads from the backing file.
+    Unallocated,
+    Compressed,
+}
+
+/// Allocation status of the guest range, which is used to map the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStatus {
+    /// Stored in the image file at the host offset.
+    Data(u64),
+    /// Reads as zeros.
+    Zero,
+    /// Not allocated, reads from the backing file.
+    Backing,
+}
+
+/// The backing file of qcow2 image, which is opened read-only.
+pub enum BackingImage {
+    Raw { file: SyncFile, size: u64 },
+    Qcow2(Box<Qcow2Image>),
+}
+
+impl BackingImage {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let size = match self {
+            BackingImage::Raw { size, .. } => *size,
+            BackingImage::Qcow2(image) => image.virtual_size(),
+        };
+        buf.fill(0);
+        if offset >= size {
+            return Ok(());
+        }
+        let len = ((size - offset) as usize).min(buf.len());
+        match self {
+            BackingImage::Raw { file, .. } => file.read_at(&mut buf[..len], offset),
+            BackingImage::Qcow2(image) => image.read_at(&mut buf[..len], offset),
+        }
+    }
+}
+
+/// Options to create a new qcow2 image.
+#[derive(Debug, Clone)]
+pub struct Qcow2CreateOptions {
+    /// Virtual size of the disk in bytes.
+    pub size: u64,
+    pub cluster_bits: u32,
+    pub backing_file: Option<String>,
+    pub backing_format: Option<DiskFormat>,
+}
+
+impl Default for Qcow2CreateOptions {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            cluster_bits: DEFAULT_CLUSTER_BITS,
+            backing_file: None,
+            backing_format: None,
+        }
+    }
+}
+
+/// Result of the consistency check of qcow2 image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Qcow2CheckResult {
+    /// Clusters whose refcount is bigger than the number of references.
+    pub leaks: u64,
+    /// Leaked clusters which are freed by the repair.
+    pub leaks_fixed: u64,
+    /// Clusters whose refcount is smaller than the number of references, and the
+    /// references which are misaligned or beyond the end of the image file.
+    pub corruptions: u64,
+    /// Guest clusters which are allocated in the image file.
+    pub allocated_clusters: u64,
+    /// All guest clusters of the virtual disk.
+    pub total_clusters: u64,
+}
+
+/// The metadata of qcow2 image, which is accessed synchronously.
+pub struct Qcow2Image {
+    file: Arc<SyncFile>,
+    pub header: QcowHeader,
+    cluster_bits: u64,
+    cluster_size: u64,
+    table: Qcow2Table,
+    refcount: RefCount,
+    backing: Option<BackingImage>,
+    /// Backing file name recorded in the header.
+    backing_file: Option<String>,
+    /// The image is accessed by the async requests of the guest, see `set_live`.
+    live: bool,
+    /// Host clusters which are no longer referenced, but not freed yet.
+    released_clusters: Vec<u64>,
+}
+
+impl Qcow2Image {
+    /// Open the qcow2 image, `path` is used to find the backing file with relative path.
+    pub fn open(file: File, path: &str, req_align: u32, buf_align: u32) -> Result<Self> {
+        Self::open_with_depth(file, path, req_align, buf_align, 0)
+    }
+
+    fn open_with_depth(
+        file: File,
+        path: &str,
+        req_align: u32,
+        buf_align: u32,
+        depth: u32,
+    ) -> Result<Self> {
+        let file = Arc::new(SyncFile::new(file, req_align, buf_align));
+        let mut buf = vec![0_u8; QCOW_V3_HEADER_SIZE];
+        file.read_at(&mut buf, 0)
+            .with_context(|| "Failed to read qcow2 header")?;
+        let header = QcowHeader::from_vec(&buf)?;
+        if header.incompatible_features & QCOW2_INCOMPAT_DIRTY != 0 {
+            bail!("The image is dirty, it needs to be repaired first");
+        }
+        if header.incompatible_features & QCOW2_INCOMPAT_CORRUPT != 0 {
+            bail!("The image is corrupt");
+        }
+        if header.incompatible_features != 0 {
+            bail!(
+                "Unsupported incompatible features 0x{:x}",
+                header.incompatible_features
+            );
+        }
+        let cluster_size = header.cluster_size();
+        if cluster_size < u64::from(req_align) {
+            bail!(
+                "Cluster size {} is smaller than the request alignment {} of direct io",
+                cluster_size,
+                req_align
+            );
+        }
+
+        let file_size = file.file_size()?;
+        let eof_cluster = file_size.div_ceil(cluster_size);
+        let table = Qcow2Table::new(file.clone(), &header)?;
+        let refcount = RefCount::new(file.clone(), &header, eof_cluster)?;
+        let mut image = Self {
+            file,
+            cluster_bits: u64::from(header.cluster_bits),
+            cluster_size,
+            header,
+            table,
+            refcount,
+            backing: None,
+            backing_file: None,
+            live: false,
+            released_clusters: Vec::new(),
+        };
+        image.open_backing_file(path, depth)?;
+        Ok(image)
+    }
+
+    fn open_backing_file(&mut self, path: &str, depth: u32) -> Result<()> {
+        if self.header.backing_file_offset == 0 {
+            return Ok(());
+        }
+        if depth >= MAX_BACKING_DEPTH {
+            bail!("Backing file chain is too long");
+        }
+
+        // The header extensions and backing file name are all in the first cluster.
+        let mut buf = vec![0_u8; self.cluster_size as usize];
+        self.file.read_at(&mut buf, 0)?;
+        let start = self.header.backing_file_offset as usize;
+        let end = start + self.header.backing_file_size as usize;
+        let name = String::from_utf8(buf[start..end].to_vec())
+            .with_context(|| "Invalid backing file name")?;
+        let mut format = None;
+        let mut pos = self.header.header_length as usize;
+        while pos + 8 <= buf.len() {
+            let magic = BigEndian::read_u32(&buf[pos..pos + 4]);
+            let len = BigEndian::read_u32(&buf[pos + 4..pos + 8]) as usize;
+            if magic == QCOW2_EXT_MAGIC_END || pos + 8 + len > buf.len() {
+                break;
+            }
+            if magic == QCOW2_EXT_MAGIC_BACKING_FORMAT {
+                let fmt = String::from_utf8_lossy(&buf[pos + 8..pos + 8 + len]).to_string();
+                format = Some(
+                    DiskFormat::from_str(&fmt)
+                        .map_err(|_| anyhow::anyhow!("Unsupported backing format {}", fmt))?,
+                );
+            }
+            pos += 8 + ((len + 7) & !7);
+        }
+
+        let backing_path = if Path::new(&name).is_absolute() {
+            name.clone()
+        } else {
+            let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+            dir.join(&name).to_string_lossy().to_string()
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .open(&backing_path)
+            .with_context(|| format!("Failed to open backing file {}", backing_path))?;
+        let format = match format {
+            Some(format) => format,
+            None => probe_format(&file)?,
+        };
+        let backing = match format {
+            DiskFormat::Raw => {
+                let file = SyncFile::new(file, 1, 1);
+                let size = file.file_size()?;
+                BackingImage::Raw { file, size }
+            }
+            DiskFormat::Qcow2 => {
+                let image = Self::open_with_depth(file, &backing_path, 1, 1, depth + 1)
+                    .with_context(|| format!("Failed to open backing file {}", backing_path))?;
+                BackingImage::Qcow2(Box::new(image))
+            }
+        };
+        self.backing = Some(backing);
+        self.backing_file = Some(name);
+        Ok(())
+    }
+
+    /// Create an empty qcow2 image of version 3 in `file`.
+    pub fn create(file: File, options: &Qcow2CreateOptions) -> Result<()> {
+        let cluster_bits = options.cluster_bits;
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            bail!("Invalid cluster bits {}", cluster_bits);
+        }
+        let cluster_size = 1_u64 << cluster_bits;
+        let mut header = QcowHeader {
+            magic: QCOW_MAGIC,
+            version: QCOW_VERSION_3,
+            cluster_bits,
+            size: options.size,
+            refcount_table_offset: cluster_size,
+            refcount_table_clusters: 1,
+            refcount_order: DEFAULT_REFCOUNT_ORDER,
+            header_length: QCOW_V3_HEADER_SIZE as u32,
+            ..Default::default()
+        };
+
+        // Header extensions and backing file name follow the header.
+        let mut extensions = Vec::new();
+        if let Some(format) = options.backing_format {
+            let name = format.to_string();
+            let mut ext = vec![0_u8; 8 + ((name.len() + 7) & !7)];
+            BigEndian::write_u32(&mut ext[0..4], QCOW2_EXT_MAGIC_BACKING_FORMAT);
+            BigEndian::write_u32(&mut ext[4..8], name.len() as u32);
+            ext[8..8 + name.len()].copy_from_slice(name.as_bytes());
+            extensions.extend(ext);
+        }
+        extensions.extend([0_u8; 8]);
+        if let Some(backing_file) = options.backing_file.as_ref() {
+            header.backing_file_offset = (QCOW_V3_HEADER_SIZE + extensions.len()) as u64;
+            header.backing_file_size = backing_file.len() as u32;
+            extensions.extend(backing_file.as_bytes());
+        }
+        if (QCOW_V3_HEADER_SIZE + extensions.len()) as u64 > cluster_size {
+            bail!("Backing file name is too long");
+        }
+
+        // Cluster 0 is header, cluster 1 is refcount table, cluster 2 is refcount block.
+        let mut buf = vec![0_u8; 3 * cluster_size as usize];
+        let header_buf = header.to_vec();
+        buf[..QCOW_V3_HEADER_SIZE].copy_from_slice(&header_buf);
+        buf[QCOW_V3_HEADER_SIZE..QCOW_V3_HEADER_SIZE + extensions.len()]
+            .copy_from_slice(&extensions);
+        let table_pos = cluster_size as usize;
+        BigEndian::write_u64(&mut buf[table_pos..table_pos + 8], 2 * cluster_size);
+        let block_pos = 2 * cluster_size as usize;
+        for i in 0..3 {
+            BigEndian::write_u16(&mut buf[block_pos + i * 2..block_pos + i * 2 + 2], 1);
+        }
+        let sync_file = SyncFile::new(file.try_clone()?, 1, 1);
+        sync_file.set_len(0)?;
+        sync_file.write_at(&buf, 0)?;
+
+        // Allocate the L1 table.
+        let l2_size = cluster_size * cluster_size / 8;
+        let l1_size = options.size.div_ceil(l2_size);
+        if l1_size > u64::from(u32::MAX) {
+            bail!("Image size {} is too big", options.size);
+        }
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size);
+        let mut image = Self::open(file, "", 1, 1)?;
+        let l1_offset = image.refcount.alloc_clusters_contiguous(l1_clusters)?;
+        image.file.write_at(
+            &vec![0_u8; (l1_clusters * cluster_size) as usize],
+            l1_offset,
+        )?;
+        let mut l1_buf = [0_u8; 12];
+        BigEndian::write_u32(&mut l1_buf[0..4], l1_size as u32);
+        BigEndian::write_u64(&mut l1_buf[4..12], l1_offset);
+        image.file.write_at(&l1_buf, L1_SIZE_POS)?;
+        image.file.sync()
+    }
+
+    pub fn virtual_size(&self) -> u64 {
+        self.header.size
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    pub fn file(&self) -> &SyncFile {
+        &self.file
+    }
+
+    pub fn backing_file(&self) -> Option<&str> {
+        self.backing_file.as_deref()
+    }
+
+    /// Grow the virtual size of the image to `new_size`, the L1 table is moved to
+    /// new clusters if it can't cover the new size.
+    pub fn resize(&mut self, new_size: u64) -> Result<()> {
+        if new_size < self.header.size {
+            bail!(
+                "Shrinking qcow2 image from {} to {} is not supported",
+                self.header.size,
+                new_size
+            );
+        }
+        let l1_size = new_size.div_ceil(self.cluster_size * self.l2_entries());
+        if l1_size > u64::from(u32::MAX) {
+            bail!("Image size {} is too big", new_size);
+        }
+        if l1_size > self.table.l1_table.len() as u64 {
+            self.grow_l1_table(l1_size)?;
+        }
+
+        let mut size_buf = [0_u8; 8];
+        BigEndian::write_u64(&mut size_buf, new_size);
+        self.file
+            .write_at(&size_buf, SIZE_POS)
+            .with_context(|| "Failed to update size in header")?;
+        self.file.sync()?;
+        self.header.size = new_size;
+        self.free_released_clusters_offline()
+    }
+
+    fn grow_l1_table(&mut self, l1_size: u64) -> Result<()> {
+        let old_offset = self.table.l1_table_offset;
+        let old_clusters = (self.table.l1_table.len() as u64 * 8).div_ceil(self.cluster_size);
+        let new_clusters = (l1_size * 8).div_ceil(self.cluster_size);
+        let new_offset = self.refcount.alloc_clusters_contiguous(new_clusters)?;
+
+        let mut new_table = self.table.l1_table.clone();
+        new_table.resize(l1_size as usize, 0);
+        let mut buf = vec![0_u8; (new_clusters * self.cluster_size) as usize];
+        for (i, entry) in new_table.iter().enumerate() {
+            BigEndian::write_u64(&mut buf[i * 8..i * 8 + 8], *entry);
+        }
+        self.file
+            .write_at(&buf, new_offset)
+            .with_context(|| "Failed to write new L1 table")?;
+        self.file.sync()?;
+
+        let mut header_buf = [0_u8; 12];
+        BigEndian::write_u32(&mut header_buf[0..4], l1_size as u32);
+        BigEndian::write_u64(&mut header_buf[4..12], new_offset);
+        self.file
+            .write_at(&header_buf, L1_SIZE_POS)
+            .with_context(|| "Failed to update L1 table in header")?;
+        self.table.l1_table = new_table;
+        self.table.l1_table_offset = new_offset;
+        self.header.l1_size = l1_size as u32;
+        self.header.l1_table_offset = new_offset;
+
+        if old_offset != 0 {
+            for i in 0..old_clusters {
+                self.released_clusters
+                    .push(old_offset + (i << self.cluster_bits));
+            }
+        }
+        Ok(())
+    }
+
+    /// Free the space of clusters in host file when they are released.
+    pub fn set_discard(&mut self, discard: bool) {
+        self.refcount.discard = discard;
+    }
+
+    /// Mark the image as accessed by the async requests of the guest. The refcounts
+    /// of the allocated clusters are flushed before the tables referencing them are
+    /// written, and the released clusters are kept until `free_released_clusters` is
+    /// called after the requests in flight, which may still access them, complete.
+    /// Otherwise the released clusters are freed as soon as the update is done, and
+    /// the refcounts are not flushed in advance, which is faster for the offline
+    /// tools but not crash consistent.
+    pub fn set_live(&mut self, live: bool) {
+        self.live = live;
+    }
+
+    /// Free the released clusters. The tables which no longer reference them are
+    /// flushed first, so that the clusters are never reused while they are still
+    /// referenced on the disk.
+    pub fn free_released_clusters(&mut self) -> Result<()> {
+        if self.released_clusters.is_empty() {
+            return Ok(());
+        }
+        self.file.sync()?;
+        while let Some(host_offset) = self.released_clusters.last() {
+            self.refcount.update_refcount(*host_offset, -1)?;
+            self.released_clusters.pop();
+        }
+        Ok(())
+    }
+
+    fn free_released_clusters_offline(&mut self) -> Result<()> {
+        if self.live {
+            return Ok(());
+        }
+        self.free_released_clusters()
+    }
+
+    /// Flush the refcounts of the allocated clusters before they are referenced.
+    fn flush_refcounts(&mut self) -> Result<()> {
+        if self.live {
+            return self.refcount.flush();
+        }
+        Ok(())
+    }
+
+    /// Get the status of the guest range at `offset`, return the status and the length of
+    /// the leading part of the range which has the same status.
+    pub fn block_status(&mut self, offset: u64, nbytes: u64) -> Result<(BlockStatus, u64)> {
+        let end = offset + nbytes;
+        let mut pos = offset;
+        let mut first = None;
+        while pos < end {
+            let in_cluster = pos & (self.cluster_size - 1);
+            let status = match self.get_cluster_map(pos)? {
+                ClusterMap::Normal(host_offset, _) => BlockStatus::Data(host_offset + in_cluster),
+                ClusterMap::Zero(_) => BlockStatus::Zero,
+                ClusterMap::Unallocated if self.backing.is_some() => BlockStatus::Backing,
+                ClusterMap::Unallocated => BlockStatus::Zero,
+                ClusterMap::Compressed => bail!("Compressed cluster is not supported"),
+            };
+            let same = match (first, status) {
+                (None, _) => true,
+                (Some(BlockStatus::Data(start)), BlockStatus::Data(host_offset)) => {
+                    host_offset == start + pos - offset
+                }
+                (Some(first), _) => first == status,
+            };
+            if !same {
+                break;
+            }
+            first = Some(status);
+            pos += (self.cluster_size - in_cluster).min(end - pos);
+        }
+        Ok((first.unwrap_or(BlockStatus::Zero), pos - offset))
+    }
+
+    /// Count one reference to each cluster of the host range.
+    fn check_refs(&self, refs: &mut [u64], offset: u64, size: u64, res: &mut Qcow2CheckResult) {
+        if offset & (self.cluster_size - 1) != 0 {
+            error!("Reference to host offset 0x{:x} is misaligned", offset);
+            res.corruptions += 1;
+            return;
+        }
+        let start = offset >> self.cluster_bits;
+        let end = (offset + size).div_ceil(self.cluster_size);
+        for index in start..end {
+            match refs.get_mut(index as usize) {
+                Some(refcount) => *refcount += 1,
+                None => {
+                    error!("Cluster {} is beyond the end of the image file", index);
+                    res.corruptions += 1;
+                }
+            }
+        }
+    }
+
+    /// Check the refcounts of all the clusters against the references in the metadata,
+    /// the leaked clusters are freed if `repair` is true.
+    pub fn check(&mut self, repair: bool) -> Result<Qcow2CheckResult> {
+        if self.header.nb_snapshots != 0 {
+            bail!("Checking image with internal snapshots is not supported");
+        }
+        let mut res = Qcow2CheckResult {
+            total_clusters: self.header.size.div_ceil(self.cluster_size),
+            ..Default::default()
+        };
+        let nb_clusters = self.file.file_size()?.div_ceil(self.cluster_size);
+        let mut refs = vec![0_u64; nb_clusters as usize];
+
+        // The header, the refcount table and the refcount blocks.
+        self.check_refs(&mut refs, 0, self.cluster_size, &mut res);
+        self.check_refs(
+            &mut refs,
+            self.refcount.refcount_table_offset,
+            u64::from(self.refcount.refcount_table_clusters) * self.cluster_size,
+            &mut res,
+        );
+        for entry in self.refcount.refcount_table.clone() {
+            let block_offset = entry & REFCOUNT_TABLE_OFFSET_MASK;
+            if block_offset != 0 {
+                self.check_refs(&mut refs, block_offset, self.cluster_size, &mut res);
+            }
+        }
+
+        // The L1 table, the L2 tables and the data clusters.
+        let l1_len = self.table.l1_table.len() as u64;
+        self.check_refs(&mut refs, self.table.l1_table_offset, l1_len * 8, &mut res);
+        for l1_entry in self.table.l1_table.clone() {
+            let l2_offset = l1_entry & L1_TABLE_OFFSET_MASK;
+            if l2_offset == 0 {
+                continue;
+            }
+            self.check_refs(&mut refs, l2_offset, self.cluster_size, &mut res);
+            if l2_offset & (self.cluster_size - 1) != 0 {
+                continue;
+            }
+            for l2_entry in self.table.load_l2_table(l2_offset)?.clone() {
+                if l2_entry & QCOW2_OFLAG_COMPRESSED != 0 {
+                    bail!("Compressed cluster is not supported");
+                }
+                let host_offset = l2_entry & L2_TABLE_OFFSET_MASK;
+                if host_offset != 0 {
+                    res.allocated_clusters += 1;
+                    self.check_refs(&mut refs, host_offset, self.cluster_size, &mut res);
+                }
+            }
+        }
+
+        for (index, expected) in refs.iter().enumerate() {
+            let refcount = self.refcount.get_refcount(index as u64)?;
+            if refcount == *expected {
+                continue;
+            }
+            if refcount < *expected {
+                error!(
+                    "Cluster {} has refcount {} but {} references",
+                    index, refcount, expected
+                );
+                res.corruptions += 1;
+                continue;
+            }
+            res.leaks += 1;
+            if repair {
+                self.refcount.set_refcount(index as u64, *expected)?;
+                res.leaks_fixed += 1;
+            }
+        }
+        if res.leaks_fixed != 0 {
+            self.file.sync()?;
+        }
+        Ok(res)
+    }
+
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size / 8
+    }
+
+    fn l1_index(&self, guest_offset: u64) -> u64 {
+        guest_offset >> (self.cluster_bits * 2 - 3)
+    }
+
+    fn l2_index(&self, guest_offset: u64) -> u64 {
+        (guest_offset >> self.cluster_bits) & (self.l2_entries() - 1)
+    }
+
+    fn get_cluster_map(&mut self, guest_offset: u64) -> Result<ClusterMap> {
+        let l1_entry = self.table.get_l1_entry(self.l1_index(guest_offset));
+        let l2_offset = l1_entry & L1_TABLE_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(ClusterMap::Unallocated);
+        }
+        let l2_index = self.l2_index(guest_offset);
+        let l2_entry = self.table.get_l2_entry(l2_offset, l2_index)?;
+        if l2_entry & QCOW2_OFLAG_COMPRESSED != 0 {
+            return Ok(ClusterMap::Compressed);
+        }
+        let host_offset = l2_entry & L2_TABLE_OFFSET_MASK;
+        if host_offset & (self.cluster_size - 1) != 0 {
+            bail!(
+                "Cluster offset 0x{:x} is not aligned, the image is corrupt",
+                host_offset
+            );
+        }
+        if self.header.version == QCOW_VERSION_3 && l2_entry & QCOW2_OFLAG_ZERO != 0 {
+            return Ok(ClusterMap::Zero(host_offset));
+        }
+        if host_offset == 0 {
+            return Ok(ClusterMap::Unallocated);
+        }
+        let copied = l1_entry & QCOW2_OFLAG_COPIED != 0 && l2_entry & QCOW2_OFLAG_COPIED != 0;
+        Ok(ClusterMap::Normal(host_offset, copied))
+    }
+
+    fn read_backing(&mut self, buf: &mut [u8], guest_offset: u64) -> Result<()> {
+        match self.backing.as_mut() {
+            Some(backing) => backing.read_at(buf, guest_offset),
+            None => {
+                buf.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    /// Split the guest range into pieces which do not cross the cluster boundary,
+    /// return the list of (offset, length).
+    fn split_range(&self, offset: u64, nbytes: u64) -> Vec<(u64, u64)> {
+        let mut ranges = Vec::new();
+        let mut pos = offset;
+        let end = offset + nbytes;
+        while pos < end {
+            let len = (self.cluster_size - (pos & (self.cluster_size - 1))).min(end - pos);
+            ranges.push((pos, len));
+            pos += len;
+        }
+        ranges
+    }
+
+    /// Read the guest data at `offset` synchronously.
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let mut pos = 0;
+        for (guest_offset, len) in self.split_range(offset, buf.len() as u64) {
+            let data = &mut buf[pos..pos + len as usize];
+            match self.get_cluster_map(guest_offset)? {
+                ClusterMap::Normal(host_offset, _) => {
+                    let in_cluster = guest_offset & (self.cluster_size - 1);
+                    self.file.read_at(data, host_offset + in_cluster)?;
+                }
+                ClusterMap::Zero(_) => data.fill(0),
+                ClusterMap::Unallocated => self.read_backing(data, guest_offset)?,
+                ClusterMap::Compressed => bail!("Compressed cluster is not supported"),
+            }
+            pos += len as usize;
+        }
+        Ok(())
+    }
+
+    /// Write the guest data at `offset` synchronously.
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+        let mut pos = 0;
+        for (guest_offset, len) in self.split_range(offset, buf.len() as u64) {
+            self.write_cluster(guest_offset, &buf[pos..pos + len as usize])?;
+            pos += len as usize;
+        }
+        self.free_released_clusters_offline()
+    }
+
+    /// Write the data within one cluster, allocate the cluster if it can not be written in place.
+    fn write_cluster(&mut self, guest_offset: u64, buf: &[u8]) -> Result<()> {
+        let in_cluster = guest_offset & (self.cluster_size - 1);
+        let map = self.get_cluster_map(guest_offset)?;
+        if let ClusterMap::Normal(host_offset, true) = map {
+            return self.file.write_at(buf, host_offset + in_cluster);
+        }
+
+        // Merge the old data of the cluster with the new data.
+        let cluster_start = guest_offset - in_cluster;
+        let mut data = vec![0_u8; self.cluster_size as usize];
+        if buf.len() as u64 != self.cluster_size {
+            match map {
+                ClusterMap::Normal(host_offset, _) => self.file.read_at(&mut data, host_offset)?,
+                ClusterMap::Unallocated => self.read_backing(&mut data, cluster_start)?,
+                ClusterMap::Zero(_) => {}
+                ClusterMap::Compressed => bail!("Compressed cluster is not supported"),
+            }
+        }
+        data[in_cluster as usize..in_cluster as usize + buf.len()].copy_from_slice(buf);
+
+        let l2_offset = self.get_writable_l2_table(guest_offset)?;
+        let host_offset = self.refcount.alloc_cluster()?;
+        self.file.write_at(&data, host_offset)?;
+        self.flush_refcounts()?;
+        self.table.set_l2_entry(
+            l2_offset,
+            self.l2_index(guest_offset),
+            host_offset | QCOW2_OFLAG_COPIED,
+        )?;
+        self.release_cluster(map);
+        Ok(())
+    }
+
+    /// Release the host cluster which is no longer referenced by the L2 entry, it
+    /// is freed later by `free_released_clusters`.
+    fn release_cluster(&mut self, map: ClusterMap) {
+        match map {
+            ClusterMap::Normal(host_offset, _) | ClusterMap::Zero(host_offset)
+                if host_offset != 0 =>
+            {
+                self.released_clusters.push(host_offset);
+            }
+            _ => {}
+        }
+    }
+
+    /// Get the L2 table of the guest offset which can be modified in place, allocate
+    /// a new one or copy the shared one if needed. Return the offset of the L2 table.
+    fn get_writable_l2_table(&mut self, guest_offset: u64) -> Result<u64> {
+        let l1_index = self.l1_index(guest_offset);
+        if l1_index >= self.table.l1_table.len() as u64 {
+            bail!("Guest offset {} is beyond the L1 table", guest_offset);
+        }
+        let l1_entry = self.table.get_l1_entry(l1_index);
+        let old_offset = l1_entry & L1_TABLE_OFFSET_MASK;
+        if old_offset != 0 && l1_entry & QCOW2_OFLAG_COPIED != 0 {
+            return Ok(old_offset);
+        }
+
+        let new_table = if old_offset != 0 {
+            self.table.load_l2_table(old_offset)?.clone()
+        } else {
+            vec![0_u64; self.l2_entries() as usize]
+        };
+        let new_offset = self.refcount.alloc_cluster()?;
+        self.table.write_l2_table(new_offset, new_table)?;
+        self.flush_refcounts()?;
+        self.table
+            .set_l1_entry(l1_index, new_offset | QCOW2_OFLAG_COPIED)?;
+        if old_offset != 0 {
+            self.table.drop_l2_table(old_offset);
+            self.released_clusters.push(old_offset);
+        }
+        Ok(new_offset)
+    }
+
+    /// Set the L2 entry of the guest cluster, and release the host cluster referenced before.
+    fn update_cluster_entry(
+        &mut self,
+        guest_offset: u64,
+        entry: u64,
+        map: ClusterMap,
+    ) -> Result<()> {
+        let l2_offset = self.get_writable_l2_table(guest_offset)?;
+        self.table
+            .set_l2_entry(l2_offset, self.l2_index(guest_offset), entry)?;
+        self.release_cluster(map);
+        Ok(())
+    }
+
+    /// Discard the whole clusters in the range, the partial clusters are ignored.
+    pub fn discard(&mut self, offset: u64, nbytes: u64) -> Result<()> {
+        // The discarded clusters must not read from backing file again.
+        let entry = if self.header.version == QCOW_VERSION_3 && self.backing.is_some() {
+            QCOW2_OFLAG_ZERO
+        } else {
+            0
+        };
+        for (guest_offset, len) in self.split_range(offset, nbytes) {
+            if len != self.cluster_size {
+                continue;
+            }
+            match self.get_cluster_map(guest_offset)? {
+                ClusterMap::Unallocated if entry == 0 => {}
+                ClusterMap::Zero(0) => {}
+                map => self.update_cluster_entry(guest_offset, entry, map)?,
+            }
+        }
+        self.free_released_clusters_offline()
+    }
+
+    /// Write zeros in the range, the whole clusters are marked as zero if possible.
+    pub fn write_zeroes(&mut self, offset: u64, nbytes: u64, unmap: bool) -> Result<()> {
+        let zero_flag = self.header.version == QCOW_VERSION_3;
+        for (guest_offset, len) in self.split_range(offset, nbytes) {
+            let map = self.get_cluster_map(guest_offset)?;
+            if map == ClusterMap::Unallocated && self.backing.is_none() {
+                continue;
+            }
+            if len != self.cluster_size || !zero_flag {
+                self.write_cluster(guest_offset, &vec![0_u8; len as usize])?;
+                continue;
+            }
+            match map {
+                ClusterMap::Zero(host_offset) if host_offset == 0 || !unmap => {}
+                ClusterMap::Normal(host_offset, true) if !unmap => {
+                    let entry = host_offset | QCOW2_OFLAG_COPIED | QCOW2_OFLAG_ZERO;
+                    self.update_cluster_entry(guest_offset, entry, ClusterMap::Unallocated)?;
+                }
+                ClusterMap::Compressed => bail!("Compressed cluster is not supported"),
+                map => self.update_cluster_entry(guest_offset, QCOW2_OFLAG_ZERO, map)?,
+            }
+        }
+        self.free_released_clusters_offline()
+    }
+
+    /// Translate the guest read request into the requests of the host file. The data
+    /// which is not in the image file is filled into `iovec` directly.
+    fn build_read_requests(
+        &mut self,
+        iovec: &[Iovec],
+        offset: u64,
+        nbytes: u64,
+    ) -> Result<Vec<CombineRequest>> {
+        let mut req_list: Vec<CombineRequest> = Vec::new();
+        let mut pos = 0;
+        for (guest_offset, len) in self.split_range(offset, nbytes) {
+            let iov = iov_slice(iovec, pos, len);
+            pos += len;
+            match self.get_cluster_map(guest_offset)? {
+                ClusterMap::Normal(host_offset, _) => {
+                    let host_offset = host_offset + (guest_offset & (self.cluster_size - 1));
+                    push_request(&mut req_list, iov, host_offset, len);
+                }
+                ClusterMap::Zero(_) => {
+                    iov_from_buf_direct(&iov, &vec![0_u8; len as usize])?;
+                }
+                ClusterMap::Unallocated => {
+                    let mut buf = vec![0_u8; len as usize];
+                    self.read_backing(&mut buf, guest_offset)?;
+                    iov_from_buf_direct(&iov, &buf)?;
+                }
+                ClusterMap::Compressed => bail!("Compressed cluster is not supported"),
+            }
+        }
+        Ok(req_list)
+    }
+
+    /// Translate the guest write request into the requests of the host file. The
+    /// clusters which need to be allocated are written synchronously.
+    fn build_write_requests(
+        &mut self,
+        iovec: &[Iovec],
+        offset: u64,
+        nbytes: u64,
+    ) -> Result<Vec<CombineRequest>> {
+        let mut req_list: Vec<CombineRequest> = Vec::new();
+        let mut pos = 0;
+        for (guest_offset, len) in self.split_range(offset, nbytes) {
+            let iov = iov_slice(iovec, pos, len);
+            pos += len;
+            match self.get_cluster_map(guest_offset)? {
+                ClusterMap::Normal(host_offset, true) => {
+                    let host_offset = host_offset + (guest_offset & (self.cluster_size - 1));
+                    push_request(&mut req_list, iov, host_offset, len);
+                }
+                _ => {
+                    let mut buf = vec![0_u8; len as usize];
+                    iov_to_buf_direct(&iov, &mut buf)?;
+                    self.write_cluster(guest_offset, &buf)?;
+                }
+            }
+        }
+        Ok(req_list)
+    }
+}
+
+/// Probe the format of image by the magic.
+pub fn probe_format(file: &File) -> Result<DiskFormat> {
+    let mut buf = [0_u8; 4];
+    SyncFile::new(file.try_clone()?, 1, 1).read_at(&mut buf, 0)?;
+    if BigEndian::read_u32(&buf) == QCOW_MAGIC {
+        Ok(DiskFormat::Qcow2)
+    } else {
+        Ok(DiskFormat::Raw)
+    }
+}
+
+/// Append the request to the list, merge it into the last one if they are contiguous.
+fn push_request(req_list: &mut Vec<CombineRequest>, iov: Vec<Iovec>, offset: u64, nbytes: u64) {
+    if let Some(last) = req_list.last_mut() {
+        if last.offset + last.nbytes == offset {
+            last.iov.extend(iov);
+            last.nbytes += nbytes;
+            return;
+        }
+    }
+    req_list.push(CombineRequest::new(iov, offset, nbytes));
+}
+
+/// Driver of qcow2 image.
+pub struct Qcow2Driver<T: Clone + 'static> {
+    driver: FileDriver<T>,
+    image: Qcow2Image,
+}
+
+// SAFETY: Send and Sync is not auto-implemented for raw pointer type in Aio.
+// The driver is always accessed with the lock held, and the aio context is
+// only used in the iothread of the block device.
+unsafe impl<T: Clone + 'static> Send for Qcow2Driver<T> {}
+
+impl<T: Clone + 'static> Qcow2Driver<T> {
+    pub fn new(file: File, aio: Aio<T>, prop: BlockProperty) -> Result<Self> {
+        let (req_align, buf_align) = if prop.direct {
+            (prop.req_align, prop.buf_align)
+        } else {
+            (1, 1)
+        };
+        let mut image = Qcow2Image::open(file.try_clone()?, &prop.path, req_align, buf_align)?;
+        image.set_discard(prop.discard);
+        image.set_live(true);
+        Ok(Self {
+            driver: FileDriver::new(file, aio, prop),
+            image,
+        })
+    }
+
+    fn complete_with_result(
+        &mut self,
+        opcode: OpCode,
+        res: Result<()>,
+        completecb: T,
+    ) -> Result<()> {
+        let res = match res {
+            Ok(()) => 0,
+            Err(e) => {
+                error!("Failed to handle qcow2 request: {:?}", e);
+                // Report the errno of the host if the request fails in accessing the image file.
+                let errno = e
+                    .root_cause()
+                    .downcast_ref::<std::io::Error>()
+                    .and_then(|e| e.raw_os_error())
+                    .unwrap_or(libc::EIO);
+                -i64::from(errno)
+            }
+        };
+        self.driver.complete_request(opcode, res, completecb)
+    }
+
+    /// Free the released clusters once no request is in flight, as the requests
+    /// submitted before the clusters are released may still access them.
+    fn free_released_clusters(&mut self) {
+        if !self.driver.is_idle() {
+            return;
+        }
+        if let Err(e) = self.image.free_released_clusters() {
+            error!("Failed to free the released qcow2 clusters: {:?}", e);
+        }
+    }
+}
+
+impl<T: Clone + 'static> Drop for Qcow2Driver<T> {
+    fn drop(&mut self) {
+        // The clusters are leaked if some requests are still in flight.
+        self.free_released_clusters();
+    }
+}
+
+impl<T: Clone + 'static> BlockDriverOps<T> for Qcow2Driver<T> {
+    fn disk_size(&mut self) -> Result<u64> {
+        Ok(self.image.virtual_size())
+    }
+
+    fn resize(&mut self, new_size: u64) -> Result<()> {
+        self.image.resize(new_size)
+    }
+
+    fn read_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()> {
+        self.free_released_clusters();
+        let nbytes = get_iov_size(&iovec);
+        match self
+            .image
+            .build_read_requests(&iovec, offset as u64, nbytes)
+        {
+            Ok(req_list) => self.driver.read_vectored(req_list, completecb),
+            Err(e) => self.complete_with_result(OpCode::Preadv, Err(e), completecb),
+        }
+    }
+
+    fn write_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()> {
+        self.free_released_clusters();
+        let nbytes = get_iov_size(&iovec);
+        match self
+            .image
+            .build_write_requests(&iovec, offset as u64, nbytes)
+        {
+            Ok(req_list) => self.driver.write_vectored(req_list, completecb),
+            Err(e) => self.complete_with_result(OpCode::Pwritev, Err(e), completecb),
+        }
+    }
+
+    fn datasync(&mut self, completecb: T) -> Result<()> {
+        self.free_released_clusters();
+        self.driver.datasync(completecb)
+    }
+
+    fn discard(&mut self, offset: usize, nbytes: u64, completecb: T) -> Result<()> {
+        self.free_released_clusters();
+        let res = self.image.discard(offset as u64, nbytes);
+        self.complete_with_result(OpCode::Discard, res, completecb)
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: usize,
+        nbytes: u64,
+        completecb: T,
+        unmap: bool,
+    ) -> Result<()> {
+        self.free_released_clusters();
+        let res = self.image.write_zeroes(offset as u64, nbytes, unmap);
+        self.complete_with_result(OpCode::WriteZeroes, res, completecb)
+    }
+
+    fn flush_request(&mut self) -> Result<()> {
+        self.free_released_clusters();
+        self.driver.flush_request()
+    }
+
+    fn register_io_event(
+        &mut self,
+        broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Result<()> {
+        self.driver.register_io_event(broken, error_cb)
+    }
+
+    fn unregister_io_event(&mut self) -> Result<()> {
+        self.driver.unregister_io_event()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+    use util::aio::{AioCb, AioEngine, WriteZeroesState};
+
+    fn create_image(size: u64, cluster_bits: u32, backing: Option<(&str, DiskFormat)>) -> TempFile {
+        let temp = TempFile::new().unwrap();
+        let options = Qcow2CreateOptions {
+            size,
+            cluster_bits,
+            backing_file: backing.map(|(path, _)| path.to_string()),
+            backing_format: backing.map(|(_, format)| format),
+        };
+        Qcow2Image::create(temp.as_file().try_clone().unwrap(), &options).unwrap();
+        temp
+    }
+
+    fn open_image(temp: &TempFile) -> Qcow2Image {
+        let path = temp.as_path().to_str().unwrap();
+        Qcow2Image::open(temp.as_file().try_clone().unwrap(), path, 1, 1).unwrap()
+    }
+
+    /// Check the refcounts of all the clusters referenced by the metadata.
+    fn check_refcounts(image: &mut Qcow2Image) {
+        let mut expected = vec![0_u64; image.refcount.eof_cluster as usize];
+        let bits = image.cluster_bits;
+        expected[0] = 1;
+        let table_start = image.refcount.refcount_table_offset >> bits;
+        for i in 0..u64::from(image.refcount.refcount_table_clusters) {
+            expected[(table_start + i) as usize] += 1;
+        }
+        for entry in image.refcount.refcount_table.clone() {
+            if entry != 0 {
+                expected[(entry >> bits) as usize] += 1;
+            }
+        }
+        let l1_start = image.table.l1_table_offset >> bits;
+        let l1_clusters = (image.table.l1_table.len() as u64 * 8 + image.cluster_size - 1) >> bits;
+        for i in 0..l1_clusters {
+            expected[(l1_start + i) as usize] += 1;
+        }
+        for l1_entry in image.table.l1_table.clone() {
+            let l2_offset = l1_entry & L1_TABLE_OFFSET_MASK;
+            if l2_offset == 0 {
+                continue;
+            }
+            expected[(l2_offset >> bits) as usize] += 1;
+            for l2_entry in image.table.load_l2_table(l2_offset).unwrap().clone() {
+                let host_offset = l2_entry & L2_TABLE_OFFSET_MASK;
+                if host_offset != 0 {
+                    expected[(host_offset >> bits) as usize] += 1;
+                }
+            }
+        }
+        for (index, refcount) in expected.iter().enumerate() {
+            assert_eq!(
+                image.refcount.get_refcount(index as u64).unwrap(),
+                *refcount
+            );
+        }
+    }
+
+    #[test]
+    fn test_qcow2_create_and_rw() {
+        let temp = create_image(16 << 20, 12, None);
+        let mut image = open_image(&temp);
+        assert_eq!(image.virtual_size(), 16 << 20);
+        assert_eq!(image.cluster_size(), 4096);
+
+        let mut buf = vec![0xff_u8; 8192];
+        image.read_at(&mut buf, 4000).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+
+        // Write across the cluster boundary and the L2 table boundary.
+        let data: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        for offset in [1000_u64, 2 << 20, (16 << 20) - 10000] {
+            image.write_at(&data, offset).unwrap();
+        }
+        // Overwrite in place.
+        image.write_at(&data[..512], 1500).unwrap();
+        drop(image);
+
+        let mut image = open_image(&temp);
+        let mut buf = vec![0_u8; 10000];
+        for offset in [1000_u64, 2 << 20, (16 << 20) - 10000] {
+            image.read_at(&mut buf, offset).unwrap();
+            if offset == 1000 {
+                assert_eq!(&buf[500..1012], &data[..512]);
+                assert_eq!(&buf[..500], &data[..500]);
+                assert_eq!(&buf[1012..], &data[1012..]);
+            } else {
+                assert_eq!(buf, data);
+            }
+        }
+        let mut buf = vec![0xff_u8; 100];
+        image.read_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+        check_refcounts(&mut image);
+    }
+
+    #[test]
+    fn test_qcow2_grow_refcount_table() {
+        // One refcount block covers 256 clusters and one refcount table cluster
+        // covers 64 blocks with cluster size 512.
+        let temp = create_image(32 << 20, 9, None);
+        let mut image = open_image(&temp);
+        let data = vec![0x5a_u8; 512];
+        for i in 0..20000 {
+            image.write_at(&data, i * 1024).unwrap();
+        }
+        assert!(image.refcount.refcount_table_clusters > 1);
+        drop(image);
+
+        let mut image = open_image(&temp);
+        let mut buf = vec![0_u8; 1024];
+        image.read_at(&mut buf, 19999 * 1024).unwrap();
+        assert_eq!(&buf[..512], &data[..]);
+        assert!(buf[512..].iter().all(|b| *b == 0));
+        check_refcounts(&mut image);
+    }
+
+    #[test]
+    fn test_qcow2_resize() {
+        // One L2 table covers 32K and one L1 table cluster covers 2M with cluster size 512.
+        let temp = create_image(1 << 20, 9, None);
+        let mut image = open_image(&temp);
+        let data = vec![0x5a_u8; 512];
+        image.write_at(&data, (1 << 20) - 512).unwrap();
+        assert!(image.resize(512 << 10).is_err());
+
+        let old_l1_offset = image.table.l1_table_offset;
+        image.resize(8 << 20).unwrap();
+        assert_ne!(image.table.l1_table_offset, old_l1_offset);
+        image.write_at(&data, (8 << 20) - 512).unwrap();
+        check_refcounts(&mut image);
+        drop(image);
+
+        let mut image = open_image(&temp);
+        assert_eq!(image.virtual_size(), 8 << 20);
+        assert_eq!(image.header.l1_size, 256);
+        let mut buf = vec![0_u8; 1024];
+        image.read_at(&mut buf, (1 << 20) - 512).unwrap();
+        assert_eq!(&buf[..512], &data[..]);
+        assert!(buf[512..].iter().all(|b| *b == 0));
+        image.read_at(&mut buf[..512], (8 << 20) - 512).unwrap();
+        assert_eq!(&buf[..512], &data[..]);
+        check_refcounts(&mut image);
+    }
+
+    #[test]
+    fn test_qcow2_backing_file() {
+        let backing = TempFile::new().unwrap();
+        let pattern: Vec<u8> = (0..(1_u32 << 20)).map(|i| (i % 253) as u8).collect();
+        backing.as_file().set_len(1 << 20).unwrap();
+        SyncFile::new(backing.as_file().try_clone().unwrap(), 1, 1)
+            .write_at(&pattern, 0)
+            .unwrap();
+
+        let backing_path = backing.as_path().to_str().unwrap();
+        for format in [Some(DiskFormat::Raw), None] {
+            let temp = TempFile::new().unwrap();
+            let options = Qcow2CreateOptions {
+                size: 2 << 20,
+                cluster_bits: 16,
+                backing_file: Some(backing_path.to_string()),
+                backing_format: format,
+            };
+            Qcow2Image::create(temp.as_file().try_clone().unwrap(), &options).unwrap();
+            let mut image = open_image(&temp);
+
+            let mut buf = vec![0_u8; 4096];
+            image.read_at(&mut buf, 100).unwrap();
+            assert_eq!(buf, pattern[100..4196]);
+            // Beyond the size of backing file.
+            image.read_at(&mut buf, (1 << 20) - 100).unwrap();
+            assert_eq!(&buf[..100], &pattern[(1 << 20) - 100..]);
+            assert!(buf[100..].iter().all(|b| *b == 0));
+
+            // Partial write merges the data of backing file.
+            image.write_at(&[0_u8; 10], 70000).unwrap();
+            let mut buf = vec![0_u8; 65536];
+            image.read_at(&mut buf, 65536).unwrap();
+            assert_eq!(&buf[..4464], &pattern[65536..70000]);
+            assert_eq!(&buf[4464..4474], &[0_u8; 10]);
+            assert_eq!(&buf[4474..], &pattern[70010..131072]);
+
+            // Discarded cluster does not read from backing file again.
+            image.discard(65536, 65536).unwrap();
+            image.discard(0, 65536).unwrap();
+            let mut buf = vec![0xff_u8; 131072];
+            image.read_at(&mut buf, 0).unwrap();
+            assert!(buf.iter().all(|b| *b == 0));
+            check_refcounts(&mut image);
+        }
+    }
+
+    #[test]
+    fn test_qcow2_write_zeroes() {
+        let temp = create_image(1 << 20, 12, None);
+        let mut image = open_image(&temp);
+        let data = vec![0x11_u8; 4 * 4096];
+        image.write_at(&data, 0).unwrap();
+
+        // Partial cluster, whole cluster in place and whole cluster with unmap.
+        image.write_zeroes(100, 100, false).unwrap();
+        image.write_zeroes(4096, 4096, false).unwrap();
+        image.write_zeroes(8192, 4096, true).unwrap();
+        assert!(matches!(
+            image.get_cluster_map(4096).unwrap(),
+            ClusterMap::Zero(offset) if offset != 0
+        ));
+        assert_eq!(image.get_cluster_map(8192).unwrap(), ClusterMap::Zero(0));
+
+        let mut buf = vec![0xff_u8; 4 * 4096];
+        image.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf[..100], &data[..100]);
+        assert!(buf[100..200].iter().all(|b| *b == 0));
+        assert_eq!(&buf[200..4096], &data[200..4096]);
+        assert!(buf[4096..3 * 4096].iter().all(|b| *b == 0));
+        assert_eq!(&buf[3 * 4096..], &data[3 * 4096..]);
+
+        // Zero cluster is allocated again by writing.
+        image.write_at(&[0x22_u8; 10], 4096 + 10).unwrap();
+        let mut buf = vec![0xff_u8; 4096];
+        image.read_at(&mut buf, 4096).unwrap();
+        assert!(buf[..10].iter().all(|b| *b == 0));
+        assert!(buf[10..20].iter().all(|b| *b == 0x22));
+        assert!(buf[20..].iter().all(|b| *b == 0));
+        check_refcounts(&mut image);
+    }
+
+    #[test]
+    fn test_qcow2_release_clusters() {
+        let temp = create_image(1 << 20, 12, None);
+        let mut image = open_image(&temp);
+        image.set_live(true);
+        image.write_at(&[0x44_u8; 4096], 0).unwrap();
+        let host_offset = match image.get_cluster_map(0).unwrap() {
+            ClusterMap::Normal(offset, true) => offset,
+            map => panic!("Unexpected cluster map {:?}", map),
+        };
+
+        // The released cluster is not reused until it is freed.
+        image.discard(0, 4096).unwrap();
+        assert_eq!(image.get_cluster_map(0).unwrap(), ClusterMap::Unallocated);
+        image.write_at(&[0x55_u8; 4096], 4096).unwrap();
+        assert_ne!(
+            image.get_cluster_map(4096).unwrap(),
+            ClusterMap::Normal(host_offset, true)
+        );
+        let index = host_offset >> image.cluster_bits;
+        assert_eq!(image.refcount.get_refcount(index).unwrap(), 1);
+
+        image.free_released_clusters().unwrap();
+        assert_eq!(image.refcount.get_refcount(index).unwrap(), 0);
+        image.write_at(&[0x66_u8; 4096], 8192).unwrap();
+        assert_eq!(
+            image.get_cluster_map(8192).unwrap(),
+            ClusterMap::Normal(host_offset, true)
+        );
+        check_refcounts(&mut image);
+    }
+
+    #[test]
+    fn test_qcow2_block_status() {
+        let temp = create_image(1 << 20, 12, None);
+        let mut image = open_image(&temp);
+        image.write_at(&vec![0x22_u8; 3 * 4096], 8192).unwrap();
+        image.write_zeroes(12288, 4096, true).unwrap();
+
+        assert_eq!(
+            image.block_status(0, 1 << 20).unwrap(),
+            (BlockStatus::Zero, 8192)
+        );
+        let (status, len) = image.block_status(8192 + 100, 1 << 20).unwrap();
+        assert!(matches!(status, BlockStatus::Data(offset) if offset & 4095 == 100));
+        assert_eq!(len, 4096 - 100);
+        assert_eq!(
+            image.block_status(12288, 4096).unwrap(),
+            (BlockStatus::Zero, 4096)
+        );
+        let (status, len) = image.block_status(16384, (1 << 20) - 16384).unwrap();
+        assert!(matches!(status, BlockStatus::Data(_)));
+        assert_eq!(len, 4096);
+    }
+
+    #[test]
+    fn test_qcow2_check() {
+        let temp = create_image(4 << 20, 12, None);
+        let mut image = open_image(&temp);
+        image.write_at(&vec![0x33_u8; 5 * 4096], 4096).unwrap();
+        let res = image.check(false).unwrap();
+        assert_eq!(res.leaks, 0);
+        assert_eq!(res.corruptions, 0);
+        assert_eq!(res.allocated_clusters, 5);
+        assert_eq!(res.total_clusters, 1024);
+
+        // Leak one data cluster, then repair it.
+        let host_offset = match image.get_cluster_map(4096).unwrap() {
+            ClusterMap::Normal(offset, _) => offset,
+            map => panic!("Unexpected cluster map {:?}", map),
+        };
+        image.refcount.update_refcount(host_offset, 1).unwrap();
+        let res = image.check(false).unwrap();
+        assert_eq!((res.leaks, res.leaks_fixed, res.corruptions), (1, 0, 0));
+        let res = image.check(true).unwrap();
+        assert_eq!((res.leaks, res.leaks_fixed, res.corruptions), (1, 1, 0));
+        assert_eq!(image.check(false).unwrap().leaks, 0);
+        check_refcounts(&mut image);
+
+        // Refcount of the referenced cluster is too small.
+        image.refcount.update_refcount(host_offset, -1).unwrap();
+        let res = image.check(true).unwrap();
+        assert_eq!((res.leaks, res.corruptions), (0, 1));
+    }
+
+    #[test]
+    fn test_qcow2_driver_rw() {
+        let temp = create_image(1 << 20, 12, None);
+        let prop = BlockProperty {
+            id: "drive0".to_string(),
+            path: temp.as_path().to_str().unwrap().to_string(),
+            format: DiskFormat::Qcow2,
+            iothread: None,
+            direct: false,
+            req_align: 1,
+            buf_align: 1,
+            discard: false,
+            write_zeroes: WriteZeroesState::Off,
+        };
+        let complete_func = |cb: &AioCb<Arc<AtomicI64>>, res: i64| -> Result<()> {
+            cb.iocompletecb.store(res, Ordering::SeqCst);
+            Ok(())
+        };
+        let aio = Aio::new(Arc::new(complete_func), AioEngine::Off).unwrap();
+        let file = temp.as_file().try_clone().unwrap();
+        let mut driver = Qcow2Driver::new(file, aio, prop).unwrap();
+        assert_eq!(driver.disk_size().unwrap(), 1 << 20);
+
+        let wbuf: Vec<u8> = (0..3 * 4096).map(|i| (i % 199) as u8).collect();
+        let iovec = vec![
+            Iovec::new(wbuf.as_ptr() as u64, 5000),
+            Iovec::new(wbuf.as_ptr() as u64 + 5000, 3 * 4096 - 5000),
+        ];
+        let res = Arc::new(AtomicI64::new(-1));
+        driver
+            .write_vectored(iovec.clone(), 1000, res.clone())
+            .unwrap();
+        assert_eq!(res.load(Ordering::SeqCst), 0);
+        // Write in place, the clusters are combined into one request.
+        res.store(-1, Ordering::SeqCst);
+        driver.write_vectored(iovec, 1000, res.clone()).unwrap();
+        assert!(res.load(Ordering::SeqCst) >= 0);
+
+        let mut rbuf = vec![0_u8; 4 * 4096];
+        let iovec = vec![Iovec::new(rbuf.as_mut_ptr() as u64, 4 * 4096)];
+        res.store(-1, Ordering::SeqCst);
+        driver.read_vectored(iovec, 0, res.clone()).unwrap();
+        assert!(res.load(Ordering::SeqCst) >= 0);
+        assert!(rbuf[..1000].iter().all(|b| *b == 0));
+        assert_eq!(&rbuf[1000..1000 + 3 * 4096], &wbuf[..]);
+        assert!(rbuf[1000 + 3 * 4096..].iter().all(|b| *b == 0));
+    }
+}
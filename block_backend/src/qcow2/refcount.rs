@@ -0,0 +1,349 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use log::warn;
+
+use super::cache::Qcow2Cache;
+use super::header::{QcowHeader, REFCOUNT_TABLE_OFFSET_POS};
+use crate::file::SyncFile;
+
+pub const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+const REFCOUNT_BLOCK_CACHE_SIZE: usize = 8;
+
+/// Read the refcount entry `index` of the refcount block, whose width is `1 << order` bits.
+pub fn get_refcount_entry(block: &[u8], order: u32, index: u64) -> u64 {
+    let bits = 1_u64 << order;
+    if bits < 8 {
+        let bit_pos = index * bits;
+        let mask = (1_u64 << bits) - 1;
+        (u64::from(block[(bit_pos / 8) as usize]) >> (bit_pos % 8)) & mask
+    } else {
+        let bytes = (bits / 8) as usize;
+        let pos = index as usize * bytes;
+        BigEndian::read_uint(&block[pos..pos + bytes], bytes)
+    }
+}
+
+/// Set the refcount entry `index` of the refcount block, return the range of the changed bytes.
+pub fn set_refcount_entry(block: &mut [u8], order: u32, index: u64, value: u64) -> (usize, usize) {
+    let bits = 1_u64 << order;
+    if bits < 8 {
+        let bit_pos = index * bits;
+        let mask = ((1_u64 << bits) - 1) as u8;
+        let shift = bit_pos % 8;
+        let pos = (bit_pos / 8) as usize;
+        block[pos] = (block[pos] & !(mask << shift)) | ((value as u8 & mask) << shift);
+        (pos, pos + 1)
+    } else {
+        let bytes = (bits / 8) as usize;
+        let pos = index as usize * bytes;
+        BigEndian::write_uint(&mut block[pos..pos + bytes], value, bytes);
+        (pos, pos + bytes)
+    }
+}
+
+/// The refcounts of all clusters in the image file.
+pub struct RefCount {
+    file: Arc<SyncFile>,
+    pub refcount_table: Vec<u64>,
+    pub refcount_table_offset: u64,
+    pub refcount_table_clusters: u32,
+    cluster_bits: u64,
+    cluster_size: u64,
+    refcount_order: u32,
+    /// Max value of refcount.
+    refcount_max: u64,
+    /// Number of refcount entries in one refcount block.
+    entries_per_block: u64,
+    block_cache: Qcow2Cache<Vec<u8>>,
+    /// The clusters before this index are all in use.
+    free_cluster_index: u64,
+    /// The index of the cluster next to the end of the image file.
+    pub eof_cluster: u64,
+    /// Free the space of clusters in host file when their refcounts drop to zero.
+    pub discard: bool,
+    /// The refcounts are updated since the last flush.
+    dirty: bool,
+}
+
+impl RefCount {
+    pub fn new(file: Arc<SyncFile>, header: &QcowHeader, eof_cluster: u64) -> Result<Self> {
+        let cluster_bits = u64::from(header.cluster_bits);
+        let cluster_size = header.cluster_size();
+        let table_len = u64::from(header.refcount_table_clusters) * cluster_size / 8;
+        let mut buf = vec![0_u8; (table_len * 8) as usize];
+        file.read_at(&mut buf, header.refcount_table_offset)
+            .with_context(|| "Failed to read refcount table")?;
+        let refcount_table = buf.chunks(8).map(BigEndian::read_u64).collect();
+        let refcount_bits = 1_u64 << header.refcount_order;
+
+        Ok(Self {
+            file,
+            refcount_table,
+            refcount_table_offset: header.refcount_table_offset,
+            refcount_table_clusters: header.refcount_table_clusters,
+            cluster_bits,
+            cluster_size,
+            refcount_order: header.refcount_order,
+            refcount_max: if refcount_bits == 64 {
+                u64::MAX
+            } else {
+                (1 << refcount_bits) - 1
+            },
+            entries_per_block: cluster_size * 8 / refcount_bits,
+            block_cache: Qcow2Cache::new(REFCOUNT_BLOCK_CACHE_SIZE),
+            free_cluster_index: 0,
+            eof_cluster,
+            discard: false,
+            dirty: false,
+        })
+    }
+
+    fn load_refcount_block(&mut self, block_offset: u64) -> Result<&mut Vec<u8>> {
+        if !self.block_cache.contains(block_offset) {
+            let mut block = vec![0_u8; self.cluster_size as usize];
+            self.file
+                .read_at(&mut block, block_offset)
+                .with_context(|| format!("Failed to read refcount block at {}", block_offset))?;
+            self.block_cache.insert(block_offset, block);
+        }
+        Ok(self.block_cache.get_mut(block_offset).unwrap())
+    }
+
+    pub fn get_refcount(&mut self, cluster_index: u64) -> Result<u64> {
+        let table_index = (cluster_index / self.entries_per_block) as usize;
+        let block_offset = match self.refcount_table.get(table_index) {
+            Some(entry) => entry & REFCOUNT_TABLE_OFFSET_MASK,
+            None => return Ok(0),
+        };
+        if block_offset == 0 {
+            return Ok(0);
+        }
+        let order = self.refcount_order;
+        let index = cluster_index % self.entries_per_block;
+        let block = self.load_refcount_block(block_offset)?;
+        Ok(get_refcount_entry(block, order, index))
+    }
+
+    pub fn set_refcount(&mut self, cluster_index: u64, value: u64) -> Result<()> {
+        if value > self.refcount_max {
+            bail!(
+                "Refcount {} of cluster {} exceeds the max value",
+                value,
+                cluster_index
+            );
+        }
+        let table_index = cluster_index / self.entries_per_block;
+        if table_index >= self.refcount_table.len() as u64 {
+            self.grow_refcount_table(table_index)?;
+        }
+        if self.refcount_table[table_index as usize] & REFCOUNT_TABLE_OFFSET_MASK == 0 {
+            self.alloc_refcount_block(table_index)?;
+        }
+        let block_offset = self.refcount_table[table_index as usize] & REFCOUNT_TABLE_OFFSET_MASK;
+        let order = self.refcount_order;
+        let index = cluster_index % self.entries_per_block;
+        let block = self.load_refcount_block(block_offset)?;
+        let (start, end) = set_refcount_entry(block, order, index, value);
+        let changed = block[start..end].to_vec();
+        self.file
+            .write_at(&changed, block_offset + start as u64)
+            .with_context(|| format!("Failed to update refcount of cluster {}", cluster_index))?;
+        self.dirty = true;
+
+        if value == 0 {
+            self.free_cluster_index = self.free_cluster_index.min(cluster_index);
+            if self.discard {
+                // Free the space in host file, it is fine to fail.
+                if let Err(e) = self
+                    .file
+                    .discard(cluster_index << self.cluster_bits, self.cluster_size)
+                {
+                    warn!("{:?}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush the updated refcounts to the disk.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.dirty {
+            self.file.sync()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Add `delta` to the refcount of the cluster at `offset` in image file.
+    pub fn update_refcount(&mut self, offset: u64, delta: i64) -> Result<()> {
+        let cluster_index = offset >> self.cluster_bits;
+        let refcount = self.get_refcount(cluster_index)?;
+        let value = if delta < 0 {
+            refcount.checked_sub(delta.unsigned_abs())
+        } else {
+            refcount.checked_add(delta as u64)
+        };
+        match value {
+            Some(value) => self.set_refcount(cluster_index, value),
+            None => bail!(
+                "Invalid refcount update {} for cluster {} with refcount {}",
+                delta,
+                cluster_index,
+                refcount
+            ),
+        }
+    }
+
+    /// Allocate one free cluster, return its offset in image file.
+    pub fn alloc_cluster(&mut self) -> Result<u64> {
+        while self.free_cluster_index < self.eof_cluster {
+            let index = self.free_cluster_index;
+            self.free_cluster_index += 1;
+            if self.get_refcount(index)? == 0 {
+                self.set_refcount(index, 1)?;
+                return Ok(index << self.cluster_bits);
+            }
+        }
+        let index = self.eof_cluster;
+        self.eof_cluster += 1;
+        self.set_refcount(index, 1)?;
+        Ok(index << self.cluster_bits)
+    }
+
+    /// Allocate `count` contiguous clusters at the end of image file, return the offset.
+    pub fn alloc_clusters_contiguous(&mut self, count: u64) -> Result<u64> {
+        let start = self.eof_cluster;
+        self.eof_cluster += count;
+        for index in start..start + count {
+            self.set_refcount(index, 1)?;
+        }
+        Ok(start << self.cluster_bits)
+    }
+
+    fn alloc_refcount_block(&mut self, table_index: u64) -> Result<()> {
+        let block_cluster = self.eof_cluster;
+        self.eof_cluster += 1;
+        let block_offset = block_cluster << self.cluster_bits;
+        let block = vec![0_u8; self.cluster_size as usize];
+        self.file
+            .write_at(&block, block_offset)
+            .with_context(|| "Failed to write new refcount block")?;
+        self.block_cache.insert(block_offset, block);
+
+        let mut entry = [0_u8; 8];
+        BigEndian::write_u64(&mut entry, block_offset);
+        self.file
+            .write_at(&entry, self.refcount_table_offset + table_index * 8)
+            .with_context(|| "Failed to update refcount table")?;
+        self.refcount_table[table_index as usize] = block_offset;
+
+        self.set_refcount(block_cluster, 1)
+    }
+
+    /// Move the refcount table to the end of image file with a bigger size, so that
+    /// the entry `min_index` is valid.
+    fn grow_refcount_table(&mut self, min_index: u64) -> Result<()> {
+        let entries_per_cluster = self.cluster_size / 8;
+        let old_len = self.refcount_table.len() as u64;
+        let mut new_len = (min_index + 1).max(old_len * 2);
+        let mut new_clusters;
+        loop {
+            new_clusters = new_len.div_ceil(entries_per_cluster);
+            new_len = new_clusters * entries_per_cluster;
+            // The refcount blocks for the new table itself should be covered too.
+            if (self.eof_cluster + new_clusters) / self.entries_per_block < new_len {
+                break;
+            }
+            new_len += entries_per_cluster;
+        }
+        if new_clusters > u64::from(u32::MAX) {
+            bail!("Refcount table is too big");
+        }
+
+        let new_offset = self.eof_cluster << self.cluster_bits;
+        self.eof_cluster += new_clusters;
+        let mut new_table = self.refcount_table.clone();
+        new_table.resize(new_len as usize, 0);
+        let mut buf = vec![0_u8; (new_len * 8) as usize];
+        for (i, entry) in new_table.iter().enumerate() {
+            BigEndian::write_u64(&mut buf[i * 8..i * 8 + 8], *entry);
+        }
+        self.file
+            .write_at(&buf, new_offset)
+            .with_context(|| "Failed to write new refcount table")?;
+        self.file.sync()?;
+
+        let mut header_buf = [0_u8; 12];
+        BigEndian::write_u64(&mut header_buf[0..8], new_offset);
+        BigEndian::write_u32(&mut header_buf[8..12], new_clusters as u32);
+        self.file
+            .write_at(&header_buf, REFCOUNT_TABLE_OFFSET_POS)
+            .with_context(|| "Failed to update refcount table in header")?;
+
+        let old_offset = self.refcount_table_offset;
+        let old_clusters = u64::from(self.refcount_table_clusters);
+        self.refcount_table = new_table;
+        self.refcount_table_offset = new_offset;
+        self.refcount_table_clusters = new_clusters as u32;
+
+        let new_start = new_offset >> self.cluster_bits;
+        for index in new_start..new_start + new_clusters {
+            self.set_refcount(index, 1)?;
+        }
+        // The old table is freed only after the header is switched to the new one.
+        self.file.sync()?;
+        let old_start = old_offset >> self.cluster_bits;
+        for index in old_start..old_start + old_clusters {
+            self.update_refcount(index << self.cluster_bits, -1)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refcount_entry() {
+        let mut block = vec![0_u8; 64];
+        for order in 0..=6 {
+            block.fill(0);
+            let max = if order == 6 {
+                u64::MAX
+            } else {
+                (1_u64 << (1 << order)) - 1
+            };
+            set_refcount_entry(&mut block, order, 3, max);
+            set_refcount_entry(&mut block, order, 4, 1);
+            assert_eq!(get_refcount_entry(&block, order, 2), 0);
+            assert_eq!(get_refcount_entry(&block, order, 3), max);
+            assert_eq!(get_refcount_entry(&block, order, 4), 1);
+            assert_eq!(get_refcount_entry(&block, order, 5), 0);
+        }
+
+        // Sub-byte entries are stored from the least significant bit.
+        block.fill(0);
+        set_refcount_entry(&mut block, 1, 1, 3);
+        assert_eq!(block[0], 0b1100);
+        // Multi-byte entries are stored in big-endian.
+        block.fill(0);
+        let range = set_refcount_entry(&mut block, 4, 1, 0x102);
+        assert_eq!(range, (2, 4));
+        assert_eq!(&block[2..4], &[1, 2]);
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+use super::cache::Qcow2Cache;
+use super::header::QcowHeader;
+use crate::file::SyncFile;
+
+const L2_TABLE_CACHE_SIZE: usize = 16;
+
+/// The two-level tables mapping the guest clusters to the host clusters.
+pub struct Qcow2Table {
+    file: Arc<SyncFile>,
+    cluster_size: u64,
+    pub l1_table: Vec<u64>,
+    pub l1_table_offset: u64,
+    l2_cache: Qcow2Cache<Vec<u64>>,
+}
+
+impl Qcow2Table {
+    pub fn new(file: Arc<SyncFile>, header: &QcowHeader) -> Result<Self> {
+        let l1_table = if header.l1_size == 0 {
+            Vec::new()
+        } else {
+            let mut buf = vec![0_u8; header.l1_size as usize * 8];
+            file.read_at(&mut buf, header.l1_table_offset)
+                .with_context(|| "Failed to read L1 table")?;
+            buf.chunks(8).map(BigEndian::read_u64).collect()
+        };
+
+        Ok(Self {
+            file,
+            cluster_size: header.cluster_size(),
+            l1_table,
+            l1_table_offset: header.l1_table_offset,
+            l2_cache: Qcow2Cache::new(L2_TABLE_CACHE_SIZE),
+        })
+    }
+
+    /// Get the L1 entry, the entries beyond the L1 table are treated as unallocated.
+    pub fn get_l1_entry(&self, l1_index: u64) -> u64 {
+        self.l1_table.get(l1_index as usize).copied().unwrap_or(0)
+    }
+
+    pub fn set_l1_entry(&mut self, l1_index: u64, entry: u64) -> Result<()> {
+        let mut buf = [0_u8; 8];
+        BigEndian::write_u64(&mut buf, entry);
+        self.file
+            .write_at(&buf, self.l1_table_offset + l1_index * 8)
+            .with_context(|| format!("Failed to update L1 entry {}", l1_index))?;
+        self.l1_table[l1_index as usize] = entry;
+        Ok(())
+    }
+
+    pub fn load_l2_table(&mut self, l2_offset: u64) -> Result<&mut Vec<u64>> {
+        if !self.l2_cache.contains(l2_offset) {
+            let mut buf = vec![0_u8; self.cluster_size as usize];
+            self.file
+                .read_at(&mut buf, l2_offset)
+                .with_context(|| format!("Failed to read L2 table at {}", l2_offset))?;
+            let table = buf.chunks(8).map(BigEndian::read_u64).collect();
+            self.l2_cache.insert(l2_offset, table);
+        }
+        Ok(self.l2_cache.get_mut(l2_offset).unwrap())
+    }
+
+    pub fn get_l2_entry(&mut self, l2_offset: u64, l2_index: u64) -> Result<u64> {
+        let table = self.load_l2_table(l2_offset)?;
+        Ok(table[l2_index as usize])
+    }
+
+    pub fn set_l2_entry(&mut self, l2_offset: u64, l2_index: u64, entry: u64) -> Result<()> {
+        let mut buf = [0_u8; 8];
+        BigEndian::write_u64(&mut buf, entry);
+        self.file
+            .write_at(&buf, l2_offset + l2_index * 8)
+            .with_context(|| format!("Failed to update L2 entry {}", l2_index))?;
+        let table = self.load_l2_table(l2_offset)?;
+        table[l2_index as usize] = entry;
+        Ok(())
+    }
+
+    /// Write the whole L2 table at `l2_offset` of the image file.
+    pub fn write_l2_table(&mut self, l2_offset: u64, table: Vec<u64>) -> Result<()> {
+        let mut buf = vec![0_u8; self.cluster_size as usize];
+        for (i, entry) in table.iter().enumerate() {
+            BigEndian::write_u64(&mut buf[i * 8..i * 8 + 8], *entry);
+        }
+        self.file
+            .write_at(&buf, l2_offset)
+            .with_context(|| format!("Failed to write L2 table at {}", l2_offset))?;
+        self.l2_cache.insert(l2_offset, table);
+        Ok(())
+    }
+
+    /// Drop the L2 table from cache, called after the table is freed.
+    pub fn drop_l2_table(&mut self, l2_offset: u64) {
+        self.l2_cache.remove(l2_offset);
+    }
+}
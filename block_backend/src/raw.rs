@@ -0,0 +1,110 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+
+use crate::file::{CombineRequest, FileDriver};
+use crate::{BlockDriverOps, BlockIoErrorCallback, BlockProperty};
+use util::aio::{get_iov_size, Aio, Iovec};
+
+/// Driver of raw image, the guest disk is mapped to the host file directly.
+pub struct RawDriver<T: Clone + 'static> {
+    driver: FileDriver<T>,
+}
+
+// SAFETY: Send and Sync is not auto-implemented for raw pointer type in Aio.
+// The driver is always accessed with the lock held, and the aio context is
+// only used in the iothread of the block device.
+unsafe impl<T: Clone + 'static> Send for RawDriver<T> {}
+
+impl<T: Clone + 'static> RawDriver<T> {
+    pub fn new(file: File, aio: Aio<T>, prop: BlockProperty) -> Self {
+        Self {
+            driver: FileDriver::new(file, aio, prop),
+        }
+    }
+}
+
+impl<T: Clone + 'static> BlockDriverOps<T> for RawDriver<T> {
+    fn disk_size(&mut self) -> Result<u64> {
+        self.driver.disk_size()
+    }
+
+    fn resize(&mut self, new_size: u64) -> Result<()> {
+        let old_size = self.driver.disk_size()?;
+        if new_size < old_size {
+            bail!(
+                "Shrinking raw image from {} to {} is not supported",
+                old_size,
+                new_size
+            );
+        }
+        self.driver
+            .file
+            .set_len(new_size)
+            .with_context(|| format!("Failed to resize raw image to {}", new_size))
+    }
+
+    fn read_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()> {
+        let nbytes = get_iov_size(&iovec);
+        self.driver.read_vectored(
+            vec![CombineRequest::new(iovec, offset as u64, nbytes)],
+            completecb,
+        )
+    }
+
+    fn write_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()> {
+        let nbytes = get_iov_size(&iovec);
+        self.driver.write_vectored(
+            vec![CombineRequest::new(iovec, offset as u64, nbytes)],
+            completecb,
+        )
+    }
+
+    fn datasync(&mut self, completecb: T) -> Result<()> {
+        self.driver.datasync(completecb)
+    }
+
+    fn discard(&mut self, offset: usize, nbytes: u64, completecb: T) -> Result<()> {
+        self.driver.discard(offset, nbytes, completecb)
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: usize,
+        nbytes: u64,
+        completecb: T,
+        unmap: bool,
+    ) -> Result<()> {
+        self.driver.write_zeroes(offset, nbytes, completecb, unmap)
+    }
+
+    fn flush_request(&mut self) -> Result<()> {
+        self.driver.flush_request()
+    }
+
+    fn register_io_event(
+        &mut self,
+        broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Result<()> {
+        self.driver.register_io_event(broken, error_cb)
+    }
+
+    fn unregister_io_event(&mut self) -> Result<()> {
+        self.driver.unregister_io_event()
+    }
+}
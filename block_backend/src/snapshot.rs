@@ -0,0 +1,183 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Temporary snapshot of drives with `snapshot=on`.
+//!
+//! The writes of the guest are redirected to a temporary qcow2 overlay whose
+//! backing file is the image of the drive, so the image is only opened
+//! read-only and can be shared by several VMs. The overlay is deleted when
+//! the VM exits.
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::os::unix::io::FromRawFd;
+
+use anyhow::{Context, Result};
+use log::info;
+
+use crate::qcow2::{Qcow2CreateOptions, Qcow2Image};
+use machine_manager::config::{DiskFormat, DriveConfig, VmConfig};
+use machine_manager::temp_cleaner::TempCleaner;
+
+/// The directory of the overlays if `TMPDIR` is not set.
+const DEFAULT_SNAPSHOT_DIR: &str = "/var/tmp";
+
+fn snapshot_dir() -> String {
+    std::env::var("TMPDIR")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| DEFAULT_SNAPSHOT_DIR.to_string())
+}
+
+/// Create an empty file with unique name in `dir`, return the file and its path.
+fn create_temp_file(dir: &str) -> Result<(File, String)> {
+    // The last six characters of template file must be "XXXXXX" for `mkstemp`
+    // function to create unique temporary file.
+    let template = CString::new(format!("{}/stratovirt_snapshot_XXXXXX", dir))
+        .with_context(|| format!("Invalid snapshot directory {}", dir))?;
+    let raw = template.into_raw();
+    // SAFETY: raw is a valid C string obtained by calling CString::into_raw.
+    let fd = unsafe { libc::mkstemp(raw) };
+    // SAFETY: raw is obtained by calling CString::into_raw and filled by mkstemp.
+    let path = unsafe { CString::from_raw(raw) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to create snapshot file in directory {}", dir));
+    }
+    // SAFETY: fd is created above and owned by the file from now on.
+    let file = unsafe { File::from_raw_fd(fd) };
+    Ok((file, path.to_string_lossy().to_string()))
+}
+
+/// Get the virtual size of the image in bytes.
+fn image_size(path: &str, format: DiskFormat) -> Result<u64> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .with_context(|| format!("Failed to open image {}", path))?;
+    match format {
+        DiskFormat::Raw => Ok(file.seek(SeekFrom::End(0))?),
+        DiskFormat::Qcow2 => Ok(Qcow2Image::open(file, path, 1, 1)?.virtual_size()),
+    }
+}
+
+/// Create a temporary qcow2 overlay in `dir` backed by the image of the drive, and
+/// switch the drive to it. The overlay is deleted by `TempCleaner` when the VM exits.
+///
+/// # Arguments
+///
+/// * `drive` - The drive with `snapshot=on`.
+/// * `dir` - The directory in which the overlay is created.
+pub fn create_snapshot_overlay(drive: &mut DriveConfig, dir: &str) -> Result<()> {
+    // The overlay may be in another directory, so the backing file must be absolute.
+    let base = std::fs::canonicalize(&drive.path_on_host)
+        .with_context(|| format!("Failed to find the image {}", drive.path_on_host))?
+        .to_string_lossy()
+        .to_string();
+    let options = Qcow2CreateOptions {
+        size: image_size(&base, drive.format)?,
+        backing_file: Some(base.clone()),
+        backing_format: Some(drive.format),
+        ..Default::default()
+    };
+
+    let (file, path) = create_temp_file(dir)?;
+    TempCleaner::add_path(path.clone());
+    Qcow2Image::create(file, &options)
+        .with_context(|| format!("Failed to create snapshot overlay {}", path))?;
+    info!(
+        "Drive {} writes to the temporary overlay {} of {}",
+        drive.id, path, base
+    );
+
+    drive.path_on_host = path;
+    drive.format = DiskFormat::Qcow2;
+    drive.snapshot = false;
+    Ok(())
+}
+
+/// Create the temporary overlays for all the drives with `snapshot=on`, it must be
+/// called before the drive files are opened.
+pub fn create_drive_snapshots(vm_config: &mut VmConfig) -> Result<()> {
+    let dir = snapshot_dir();
+    for drive in vm_config.drives.values_mut() {
+        if drive.snapshot {
+            create_snapshot_overlay(drive, &dir)
+                .with_context(|| format!("Failed to create snapshot for drive {}", drive.id))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+    use crate::file::SyncFile;
+
+    #[test]
+    fn test_snapshot_overlay() {
+        TempCleaner::object_init();
+        let base = TempFile::new().unwrap();
+        let pattern: Vec<u8> = (0..(1_u32 << 20)).map(|i| (i % 251) as u8).collect();
+        base.as_file().set_len(1 << 20).unwrap();
+        SyncFile::new(base.as_file().try_clone().unwrap(), 1, 1)
+            .write_at(&pattern, 0)
+            .unwrap();
+        let base_path = base.as_path().to_str().unwrap().to_string();
+
+        let dir = std::env::temp_dir().to_string_lossy().to_string();
+        let mut overlays = Vec::new();
+        for _ in 0..2 {
+            let mut drive = DriveConfig {
+                id: "snapshot0".to_string(),
+                path_on_host: base_path.clone(),
+                snapshot: true,
+                ..Default::default()
+            };
+            create_snapshot_overlay(&mut drive, &dir).unwrap();
+            assert_ne!(drive.path_on_host, base_path);
+            assert_eq!(drive.format, DiskFormat::Qcow2);
+            overlays.push(drive.path_on_host);
+        }
+        assert_ne!(overlays[0], overlays[1]);
+
+        // Reads fall through to the base image, writes stay in the overlay.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&overlays[0])
+            .unwrap();
+        let mut image = Qcow2Image::open(file, &overlays[0], 1, 1).unwrap();
+        assert_eq!(image.virtual_size(), 1 << 20);
+        let mut buf = vec![0_u8; 4096];
+        image.read_at(&mut buf, 4096).unwrap();
+        assert_eq!(buf, pattern[4096..8192]);
+        image.write_at(&[0xa5_u8; 4096], 4096).unwrap();
+        image.read_at(&mut buf, 4096).unwrap();
+        assert!(buf.iter().all(|b| *b == 0xa5));
+        drop(image);
+
+        let mut buf = vec![0_u8; 1 << 20];
+        SyncFile::new(base.as_file().try_clone().unwrap(), 1, 1)
+            .read_at(&mut buf, 0)
+            .unwrap();
+        assert_eq!(buf, pattern);
+
+        TempCleaner::clean();
+        for overlay in overlays {
+            assert!(!std::path::Path::new(&overlay).exists());
+        }
+    }
+}
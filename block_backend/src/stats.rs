@@ -0,0 +1,186 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::Mutex;
+use std::time::Instant;
+
+use machine_manager::qmp::qmp_schema::{BlockDeviceStats, BlockLatencyHistogramInfo};
+
+/// Boundaries(in nanoseconds) of the latency histogram, 10us/100us/1ms/10ms/100ms/1s.
+const LATENCY_HISTOGRAM_BOUNDARIES: [u64; 6] = [
+    10_000,
+    100_000,
+    1_000_000,
+    10_000_000,
+    100_000_000,
+    1_000_000_000,
+];
+const LATENCY_HISTOGRAM_BINS: usize = LATENCY_HISTOGRAM_BOUNDARIES.len() + 1;
+
+/// Type of the accounted requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockAcctType {
+    Read = 0,
+    Write = 1,
+    Flush = 2,
+    Unmap = 3,
+}
+
+const BLOCK_ACCT_TYPE_NUM: usize = 4;
+
+/// Accounting information of one request, created when the request is submitted.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockAcctCookie {
+    acct_type: BlockAcctType,
+    bytes: u64,
+    start: Instant,
+}
+
+impl BlockAcctCookie {
+    pub fn new(acct_type: BlockAcctType, bytes: u64) -> Self {
+        Self {
+            acct_type,
+            bytes,
+            start: Instant::now(),
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct OpStats {
+    ops: u64,
+    bytes: u64,
+    failed: u64,
+    merged: u64,
+    total_time_ns: u64,
+    histogram: [u64; LATENCY_HISTOGRAM_BINS],
+}
+
+impl OpStats {
+    fn histogram_info(&self) -> BlockLatencyHistogramInfo {
+        BlockLatencyHistogramInfo {
+            boundaries: LATENCY_HISTOGRAM_BOUNDARIES.to_vec(),
+            bins: self.histogram.to_vec(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct BlockStatsInner {
+    ops: [OpStats; BLOCK_ACCT_TYPE_NUM],
+    /// Time of the last completed request.
+    last_access: Option<Instant>,
+}
+
+/// I/O statistics of one block device.
+#[derive(Default)]
+pub struct BlockStats {
+    inner: Mutex<BlockStatsInner>,
+}
+
+impl BlockStats {
+    /// Account the completion of the request described by `cookie`.
+    pub fn account_done(&self, cookie: &BlockAcctCookie, failed: bool) {
+        let now = Instant::now();
+        let latency = now.duration_since(cookie.start).as_nanos() as u64;
+        let mut locked_inner = self.inner.lock().unwrap();
+        let stats = &mut locked_inner.ops[cookie.acct_type as usize];
+        if failed {
+            stats.failed += 1;
+        } else {
+            stats.ops += 1;
+            stats.bytes += cookie.bytes;
+            stats.total_time_ns += latency;
+            let bin = LATENCY_HISTOGRAM_BOUNDARIES
+                .iter()
+                .position(|&b| latency < b)
+                .unwrap_or(LATENCY_HISTOGRAM_BOUNDARIES.len());
+            stats.histogram[bin] += 1;
+        }
+        locked_inner.last_access = Some(now);
+    }
+
+    /// Account `num` requests which are merged into other requests.
+    pub fn account_merged(&self, acct_type: BlockAcctType, num: u64) {
+        self.inner.lock().unwrap().ops[acct_type as usize].merged += num;
+    }
+
+    /// Get the statistics in the format of qmp.
+    pub fn query(&self) -> BlockDeviceStats {
+        let locked_inner = self.inner.lock().unwrap();
+        let rd = &locked_inner.ops[BlockAcctType::Read as usize];
+        let wr = &locked_inner.ops[BlockAcctType::Write as usize];
+        let flush = &locked_inner.ops[BlockAcctType::Flush as usize];
+        let unmap = &locked_inner.ops[BlockAcctType::Unmap as usize];
+        BlockDeviceStats {
+            rd_bytes: rd.bytes,
+            wr_bytes: wr.bytes,
+            unmap_bytes: unmap.bytes,
+            rd_operations: rd.ops,
+            wr_operations: wr.ops,
+            flush_operations: flush.ops,
+            unmap_operations: unmap.ops,
+            rd_merged: rd.merged,
+            wr_merged: wr.merged,
+            unmap_merged: unmap.merged,
+            rd_total_time_ns: rd.total_time_ns,
+            wr_total_time_ns: wr.total_time_ns,
+            flush_total_time_ns: flush.total_time_ns,
+            unmap_total_time_ns: unmap.total_time_ns,
+            failed_rd_operations: rd.failed,
+            failed_wr_operations: wr.failed,
+            failed_flush_operations: flush.failed,
+            failed_unmap_operations: unmap.failed,
+            idle_time_ns: locked_inner
+                .last_access
+                .map(|t| t.elapsed().as_nanos() as u64),
+            account_failed: true,
+            rd_latency_histogram: rd.histogram_info(),
+            wr_latency_histogram: wr.histogram_info(),
+            flush_latency_histogram: flush.histogram_info(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_stats_account() {
+        let stats = BlockStats::default();
+        let cookie = BlockAcctCookie::new(BlockAcctType::Read, 4096);
+        stats.account_done(&cookie, false);
+        let cookie = BlockAcctCookie::new(BlockAcctType::Read, 512);
+        stats.account_done(&cookie, false);
+        stats.account_merged(BlockAcctType::Read, 1);
+        let cookie = BlockAcctCookie::new(BlockAcctType::Write, 512);
+        stats.account_done(&cookie, true);
+        let cookie = BlockAcctCookie::new(BlockAcctType::Flush, 0);
+        stats.account_done(&cookie, false);
+
+        let info = stats.query();
+        assert_eq!(info.rd_operations, 2);
+        assert_eq!(info.rd_bytes, 4608);
+        assert_eq!(info.rd_merged, 1);
+        assert_eq!(info.wr_operations, 0);
+        assert_eq!(info.wr_bytes, 0);
+        assert_eq!(info.failed_wr_operations, 1);
+        assert_eq!(info.flush_operations, 1);
+        assert_eq!(info.rd_latency_histogram.bins.iter().sum::<u64>(), 2);
+        assert_eq!(
+            info.rd_latency_histogram.bins.len(),
+            info.rd_latency_histogram.boundaries.len() + 1
+        );
+        assert!(info.idle_time_ns.is_some());
+    }
+}
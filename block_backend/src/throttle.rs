@@ -0,0 +1,493 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! I/O throttling of the block devices.
+//!
+//! Every throttled resource (bytes or operations of read, write and total) is
+//! limited by a leaky bucket. A request can be submitted only if none of the
+//! buckets it touches is full, otherwise the submitter waits for the wakeup
+//! event which is written when the buckets have leaked enough.
+//!
+//! A throttle is shared by all the submitters (queues of a block device, or
+//! all the block devices of a throttle group). The throttled submitters wait
+//! in a FIFO queue and take turns to submit one request, so the budget is
+//! shared fairly no matter which iothread serves them.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::error;
+use once_cell::sync::Lazy;
+use vmm_sys_util::eventfd::EventFd;
+
+use machine_manager::config::{ThrottleConfig, ThrottleGroupConfig, ThrottleLimit};
+use util::leak_bucket::Bucket;
+use util::loop_context::EventLoopContext;
+
+/// State of the timer used to wake up the throttled submitters.
+#[derive(Default)]
+struct ThrottleTimer {
+    /// Indicate whether the timer started.
+    started: bool,
+    /// Increased when the timer is cancelled, so the stale timer does nothing.
+    generation: u64,
+    /// Wakeup events of the throttled submitters, the head one is the next to submit.
+    waiters: VecDeque<Arc<EventFd>>,
+}
+
+fn wake_up(waiter: &EventFd) {
+    waiter
+        .write(1)
+        .unwrap_or_else(|e| error!("Throttle send event to device failed {:?}", e));
+}
+
+impl ThrottleTimer {
+    fn is_head(&self, submitter: &Arc<EventFd>) -> bool {
+        self.waiters
+            .front()
+            .map_or(true, |waiter| Arc::ptr_eq(waiter, submitter))
+    }
+
+    fn enqueue(&mut self, submitter: &Arc<EventFd>) {
+        if !self.is_waiting(submitter) {
+            self.waiters.push_back(submitter.clone());
+        }
+    }
+
+    fn is_waiting(&self, submitter: &Arc<EventFd>) -> bool {
+        self.waiters
+            .iter()
+            .any(|waiter| Arc::ptr_eq(waiter, submitter))
+    }
+
+    fn wake_head(&mut self) {
+        self.started = false;
+        if let Some(waiter) = self.waiters.front() {
+            wake_up(waiter);
+        }
+    }
+
+    fn wake_all(&mut self) {
+        self.started = false;
+        for waiter in self.waiters.drain(..) {
+            wake_up(&waiter);
+        }
+    }
+}
+
+fn new_bucket(limit: &ThrottleLimit) -> Bucket {
+    Bucket::new(limit.avg, limit.max, limit.max_length)
+}
+
+fn set_bucket(bucket: &mut Bucket, limit: &ThrottleLimit) {
+    bucket.set_limit(limit.avg, limit.max, limit.max_length);
+}
+
+/// I/O throttling state, shared by all the queues of a block device or all the
+/// block devices of a throttle group.
+pub struct Throttle {
+    config: ThrottleConfig,
+    bps_total: Bucket,
+    bps_read: Bucket,
+    bps_write: Bucket,
+    iops_total: Bucket,
+    iops_read: Bucket,
+    iops_write: Bucket,
+    timer: Arc<Mutex<ThrottleTimer>>,
+}
+
+impl Throttle {
+    pub fn new(config: &ThrottleConfig) -> Self {
+        Throttle {
+            config: *config,
+            bps_total: new_bucket(&config.bps_total),
+            bps_read: new_bucket(&config.bps_read),
+            bps_write: new_bucket(&config.bps_write),
+            iops_total: new_bucket(&config.iops_total),
+            iops_read: new_bucket(&config.iops_read),
+            iops_write: new_bucket(&config.iops_write),
+            timer: Arc::new(Mutex::new(ThrottleTimer::default())),
+        }
+    }
+
+    pub fn config(&self) -> ThrottleConfig {
+        self.config
+    }
+
+    /// Change the limits at runtime, the throttled submitters are woken up to
+    /// check the new limits.
+    pub fn set_config(&mut self, config: &ThrottleConfig) {
+        self.config = *config;
+        set_bucket(&mut self.bps_total, &config.bps_total);
+        set_bucket(&mut self.bps_read, &config.bps_read);
+        set_bucket(&mut self.bps_write, &config.bps_write);
+        set_bucket(&mut self.iops_total, &config.iops_total);
+        set_bucket(&mut self.iops_read, &config.iops_read);
+        set_bucket(&mut self.iops_write, &config.iops_write);
+
+        let mut locked_timer = self.timer.lock().unwrap();
+        locked_timer.generation += 1;
+        locked_timer.wake_all();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.is_enabled()
+    }
+
+    /// Return true if the submitter is waiting for its turn.
+    pub fn is_waiting(&self, wakeup: &Arc<EventFd>) -> bool {
+        self.timer.lock().unwrap().is_waiting(wakeup)
+    }
+
+    /// Remove the submitter which will not submit any more requests, and pass
+    /// its turn to the next one.
+    pub fn remove_waiter(&mut self, wakeup: &Arc<EventFd>) {
+        let mut locked_timer = self.timer.lock().unwrap();
+        let was_head = !locked_timer.waiters.is_empty() && locked_timer.is_head(wakeup);
+        locked_timer
+            .waiters
+            .retain(|waiter| !Arc::ptr_eq(waiter, wakeup));
+        if was_head && !locked_timer.started {
+            locked_timer.wake_head();
+        }
+    }
+
+    /// Return true if the request must not be submitted now, `wakeup` will be
+    /// written when the submitter should try again. Otherwise the caller can
+    /// submit the request after accounting it by `account()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - used for delay function call.
+    /// * `is_write` - the request is a write request or a read request.
+    /// * `wakeup` - the wakeup event of the submitter.
+    pub fn throttled(
+        &mut self,
+        ctx: &mut EventLoopContext,
+        is_write: bool,
+        wakeup: &Arc<EventFd>,
+    ) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+
+        let timer = self.timer.clone();
+        let mut locked_timer = timer.lock().unwrap();
+        // Wait for the turn, or for the timer if it's our turn.
+        if !locked_timer.is_head(wakeup) {
+            locked_timer.enqueue(wakeup);
+            return true;
+        }
+        if locked_timer.started {
+            return true;
+        }
+
+        if let Some(delay) = self.wait_time(is_write) {
+            locked_timer.enqueue(wakeup);
+            locked_timer.started = true;
+            let generation = locked_timer.generation;
+            let timer = self.timer.clone();
+            let func = Box::new(move || {
+                let mut locked_timer = timer.lock().unwrap();
+                if locked_timer.generation == generation {
+                    locked_timer.wake_head();
+                }
+            });
+            ctx.delay_call(func, delay);
+            return true;
+        }
+        false
+    }
+
+    /// Account the request which is not throttled, and pass the turn to the
+    /// next waiting submitter.
+    pub fn account(&mut self, is_write: bool, bytes: u64, wakeup: &Arc<EventFd>) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut locked_timer = self.timer.lock().unwrap();
+        if locked_timer
+            .waiters
+            .front()
+            .map_or(false, |waiter| Arc::ptr_eq(waiter, wakeup))
+        {
+            locked_timer.waiters.pop_front();
+            if !locked_timer.started {
+                locked_timer.wake_head();
+            }
+        }
+        drop(locked_timer);
+
+        self.bps_total.consume(bytes);
+        self.iops_total.consume(1);
+        if is_write {
+            self.bps_write.consume(bytes);
+            self.iops_write.consume(1);
+        } else {
+            self.bps_read.consume(bytes);
+            self.iops_read.consume(1);
+        }
+    }
+
+    /// Get the time to wait before the request can be submitted, None if all
+    /// the buckets it touches are not full.
+    fn wait_time(&mut self, is_write: bool) -> Option<Duration> {
+        let (bps, iops) = if is_write {
+            (&mut self.bps_write, &mut self.iops_write)
+        } else {
+            (&mut self.bps_read, &mut self.iops_read)
+        };
+        [&mut self.bps_total, bps, &mut self.iops_total, iops]
+            .into_iter()
+            .filter_map(|bucket| bucket.wait_time())
+            .max()
+    }
+}
+
+/// Return true if the request must wait for the throttle of the block device or
+/// the throttle group, otherwise the request is accounted by both of them.
+///
+/// The submitter leaves the queue of the group when it's stopped by the throttle
+/// of the block device, so that it doesn't hold the turn of the other block
+/// devices of the group.
+pub fn throttle_request(
+    throttle: &Mutex<Throttle>,
+    group: Option<&Mutex<Throttle>>,
+    ctx: &mut EventLoopContext,
+    is_write: bool,
+    bytes: u64,
+    wakeup: &Arc<EventFd>,
+) -> bool {
+    let mut locked_throttle = throttle.lock().unwrap();
+    if locked_throttle.throttled(ctx, is_write, wakeup) {
+        if let Some(group) = group {
+            group.lock().unwrap().remove_waiter(wakeup);
+        }
+        return true;
+    }
+    if let Some(group) = group {
+        let mut locked_group = group.lock().unwrap();
+        if locked_group.throttled(ctx, is_write, wakeup) {
+            return true;
+        }
+        locked_group.account(is_write, bytes, wakeup);
+    }
+    locked_throttle.account(is_write, bytes, wakeup);
+    false
+}
+
+/// All the throttle groups, indexed by the group id.
+static THROTTLE_GROUPS: Lazy<Mutex<HashMap<String, Arc<Mutex<Throttle>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Get the throttle group shared by the block devices, the group is created
+/// with `config` when it's used for the first time.
+pub fn throttle_group(config: &ThrottleGroupConfig) -> Arc<Mutex<Throttle>> {
+    THROTTLE_GROUPS
+        .lock()
+        .unwrap()
+        .entry(config.id.clone())
+        .or_insert_with(|| Arc::new(Mutex::new(Throttle::new(&config.throttle))))
+        .clone()
+}
+
+/// Find the throttle group which has been used by the block devices.
+pub fn find_throttle_group(id: &str) -> Option<Arc<Mutex<Throttle>>> {
+    THROTTLE_GROUPS.lock().unwrap().get(id).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    fn new_submitter() -> Arc<EventFd> {
+        Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap())
+    }
+
+    fn submit(
+        throttle: &mut Throttle,
+        ctx: &mut EventLoopContext,
+        is_write: bool,
+        bytes: u64,
+        wakeup: &Arc<EventFd>,
+    ) -> bool {
+        if throttle.throttled(ctx, is_write, wakeup) {
+            return false;
+        }
+        throttle.account(is_write, bytes, wakeup);
+        true
+    }
+
+    #[test]
+    fn test_throttle_iops() {
+        let mut ctx = EventLoopContext::new();
+        let wakeup = new_submitter();
+        let mut config = ThrottleConfig::default();
+        let mut throttle = Throttle::new(&config);
+        assert!(!throttle.is_enabled());
+        assert!(submit(&mut throttle, &mut ctx, false, 4096, &wakeup));
+
+        // The bucket holds the operations of one second.
+        config.iops_read = ThrottleLimit::new(2);
+        throttle.set_config(&config);
+        assert!(submit(&mut throttle, &mut ctx, false, 4096, &wakeup));
+        assert!(submit(&mut throttle, &mut ctx, false, 4096, &wakeup));
+        assert!(submit(&mut throttle, &mut ctx, false, 4096, &wakeup));
+        assert!(!submit(&mut throttle, &mut ctx, false, 4096, &wakeup));
+        assert!(throttle.is_waiting(&wakeup));
+        // Write requests are blocked by the started timer too.
+        assert!(!submit(&mut throttle, &mut ctx, true, 4096, &wakeup));
+        assert!(wakeup.read().is_err());
+
+        // Removing the limits wakes up the waiters.
+        throttle.set_config(&ThrottleConfig::default());
+        assert!(!throttle.is_waiting(&wakeup));
+        assert_eq!(wakeup.read().unwrap(), 1);
+        assert!(submit(&mut throttle, &mut ctx, true, 4096, &wakeup));
+    }
+
+    #[test]
+    fn test_throttle_bps() {
+        let mut ctx = EventLoopContext::new();
+        let wakeup = new_submitter();
+        let config = ThrottleConfig {
+            bps_write: ThrottleLimit {
+                avg: 4096,
+                max: 8192,
+                max_length: 2,
+            },
+            ..Default::default()
+        };
+        let mut throttle = Throttle::new(&config);
+        // Read requests are not limited.
+        for _ in 0..10 {
+            assert!(submit(&mut throttle, &mut ctx, false, 1 << 20, &wakeup));
+        }
+        // The burst bucket holds 819 bytes.
+        assert!(submit(&mut throttle, &mut ctx, true, 512, &wakeup));
+        assert!(submit(&mut throttle, &mut ctx, true, 512, &wakeup));
+        assert!(throttle.wait_time(true).is_some());
+        assert!(!submit(&mut throttle, &mut ctx, true, 512, &wakeup));
+        assert_eq!(throttle.config(), config);
+    }
+
+    #[test]
+    fn test_throttle_fairness() {
+        let mut ctx = EventLoopContext::new();
+        let submitter1 = new_submitter();
+        let submitter2 = new_submitter();
+        let config = ThrottleConfig {
+            iops_total: ThrottleLimit::new(100),
+            ..Default::default()
+        };
+        let mut throttle = Throttle::new(&config);
+
+        for _ in 0..101 {
+            assert!(submit(&mut throttle, &mut ctx, false, 512, &submitter1));
+        }
+        // Submitter1 waits for the timer, submitter2 waits for its turn.
+        assert!(!submit(&mut throttle, &mut ctx, false, 512, &submitter1));
+        assert!(!submit(&mut throttle, &mut ctx, true, 512, &submitter2));
+        assert!(throttle.is_waiting(&submitter1));
+        assert!(throttle.is_waiting(&submitter2));
+
+        // The timer wakes up the head one only.
+        sleep(Duration::from_millis(20));
+        ctx.run_timers();
+        assert_eq!(submitter1.read().unwrap(), 1);
+        assert!(submitter2.read().is_err());
+
+        // The submitters take turns.
+        assert!(submit(&mut throttle, &mut ctx, false, 512, &submitter1));
+        assert_eq!(submitter2.read().unwrap(), 1);
+        assert!(!submit(&mut throttle, &mut ctx, false, 512, &submitter1));
+        assert!(submit(&mut throttle, &mut ctx, true, 512, &submitter2));
+        assert_eq!(submitter1.read().unwrap(), 1);
+
+        // Removing the head passes the turn to the next one.
+        assert!(!submit(&mut throttle, &mut ctx, true, 512, &submitter2));
+        throttle.remove_waiter(&submitter1);
+        assert!(!throttle.is_waiting(&submitter1));
+        assert_eq!(submitter2.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_throttle_group_device_limit() {
+        let mut ctx = EventLoopContext::new();
+        let group_config = ThrottleConfig {
+            iops_total: ThrottleLimit::new(100),
+            ..Default::default()
+        };
+        let group = Mutex::new(Throttle::new(&group_config));
+        // Block device 1 is limited by its own write limit too.
+        let config1 = ThrottleConfig {
+            iops_write: ThrottleLimit::new(2),
+            ..Default::default()
+        };
+        let throttle1 = Mutex::new(Throttle::new(&config1));
+        let throttle2 = Mutex::new(Throttle::new(&ThrottleConfig::default()));
+        let submitter1 = new_submitter();
+        let submitter2 = new_submitter();
+        let submit = |ctx: &mut EventLoopContext,
+                      throttle: &Mutex<Throttle>,
+                      is_write: bool,
+                      wakeup: &Arc<EventFd>| {
+            !throttle_request(throttle, Some(&group), ctx, is_write, 512, wakeup)
+        };
+
+        for _ in 0..3 {
+            assert!(submit(&mut ctx, &throttle1, true, &submitter1));
+        }
+        for _ in 0..98 {
+            assert!(submit(&mut ctx, &throttle1, false, &submitter1));
+        }
+        // Block device 1 waits for the group timer, block device 2 waits for its turn.
+        assert!(!submit(&mut ctx, &throttle1, false, &submitter1));
+        assert!(!submit(&mut ctx, &throttle2, false, &submitter2));
+        sleep(Duration::from_millis(20));
+        ctx.run_timers();
+        assert_eq!(submitter1.read().unwrap(), 1);
+
+        // Block device 1 is stopped by its own limit, and passes the turn of the group.
+        assert!(!submit(&mut ctx, &throttle1, true, &submitter1));
+        assert!(!group.lock().unwrap().is_waiting(&submitter1));
+        assert!(throttle1.lock().unwrap().is_waiting(&submitter1));
+        assert_eq!(submitter2.read().unwrap(), 1);
+        assert!(submit(&mut ctx, &throttle2, false, &submitter2));
+    }
+
+    #[test]
+    fn test_throttle_group() {
+        let mut config = ThrottleGroupConfig {
+            id: "group0".to_string(),
+            ..Default::default()
+        };
+        config.throttle.bps_total = ThrottleLimit::new(1 << 20);
+        let group1 = throttle_group(&config);
+        config.throttle.bps_total = ThrottleLimit::new(1 << 10);
+        let group2 = throttle_group(&config);
+        assert!(Arc::ptr_eq(&group1, &group2));
+        assert!(Arc::ptr_eq(
+            &group1,
+            &find_throttle_group("group0").unwrap()
+        ));
+        assert!(find_throttle_group("group1").is_none());
+        assert_eq!(
+            group2.lock().unwrap().config().bps_total,
+            ThrottleLimit::new(1 << 20)
+        );
+    }
+}
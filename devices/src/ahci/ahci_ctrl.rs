@@ -0,0 +1,922 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use log::{error, warn};
+
+use super::ide::IdeDrive;
+use super::*;
+use address_space::{AddressSpace, GuestAddress, RegionOps};
+use block_backend::BlockIoErrorCallback;
+use machine_manager::config::{AhciConfig, DriveFile, IdeDevConfig, AHCI_MAX_PORTS};
+use pci::intx::Intx;
+use pci::msix::Msix;
+use util::aio::Iovec;
+use util::num_ops::{read_data_u32, write_data_u32, write_u64_high, write_u64_low};
+
+/// The guest memory is mapped in pages, and each page is contiguous in the host.
+const AHCI_PAGE_SIZE: u64 = 0x1000;
+/// Data Byte Count of the physical region, the max size of a region is 4MiB.
+const AHCI_PRD_DBC_MASK: u32 = 0x3f_ffff;
+/// Writable bits of the Port Interrupt Enable register.
+const AHCI_PORT_IE_MASK: u32 = 0xfdc0_00ff;
+/// Writable bits of the Port Command and Status register.
+const AHCI_PORT_CMD_MASK: u32 = AHCI_PORT_CMD_ST
+    | AHCI_PORT_CMD_SUD
+    | AHCI_PORT_CMD_POD
+    | AHCI_PORT_CMD_FRE
+    | AHCI_PORT_CMD_ATAPI;
+/// Status of the device which is ready to accept commands.
+const ATA_STATUS_READY: u8 = ATA_STATUS_DRDY | ATA_STATUS_DSC;
+/// Task file data of the port without device attached.
+const AHCI_PORT_TFD_NO_DEVICE: u32 = 0x7f;
+
+/// Interrupt of the controller. The ports with pending interrupts are reported
+/// by the Interrupt Status register of the HBA, MSI-X is preferred and the
+/// pin-based interrupt is asserted as long as any port is pending.
+pub struct AhciIrq {
+    msix: Arc<Mutex<Msix>>,
+    intx: Arc<Mutex<Intx>>,
+    dev_id: Arc<AtomicU16>,
+    /// Ports whose PxIS and PxIE have common bits set.
+    pending: Mutex<u32>,
+    /// Interrupt Enable of the Global HBA Control register.
+    enabled: AtomicBool,
+}
+
+impl AhciIrq {
+    pub fn new(msix: Arc<Mutex<Msix>>, intx: Arc<Mutex<Intx>>, dev_id: Arc<AtomicU16>) -> Self {
+        Self {
+            msix,
+            intx,
+            dev_id,
+            pending: Mutex::new(0),
+            enabled: AtomicBool::new(false),
+        }
+    }
+
+    fn pending(&self) -> u32 {
+        *self.pending.lock().unwrap()
+    }
+
+    /// Update the interrupt state of the port, `notify` means there are new events
+    /// which need a message of MSI-X.
+    fn update_port(&self, port: usize, asserted: bool, notify: bool) {
+        let mut pending = self.pending.lock().unwrap();
+        if asserted {
+            *pending |= 1 << port;
+        } else {
+            *pending &= !(1 << port);
+        }
+        self.update(*pending, asserted && notify);
+    }
+
+    fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+        let pending = self.pending.lock().unwrap();
+        self.update(*pending, *pending != 0);
+    }
+
+    fn reset(&self) {
+        self.enabled.store(false, Ordering::SeqCst);
+        let mut pending = self.pending.lock().unwrap();
+        *pending = 0;
+        self.update(*pending, false);
+    }
+
+    fn update(&self, pending: u32, notify: bool) {
+        let enabled = self.enabled.load(Ordering::SeqCst);
+        let mut locked_msix = self.msix.lock().unwrap();
+        if locked_msix.enabled {
+            if enabled && notify {
+                locked_msix.notify(0, self.dev_id.load(Ordering::Acquire));
+            }
+            return;
+        }
+        drop(locked_msix);
+
+        let level = enabled && pending != 0;
+        self.intx.lock().unwrap().notify(level as u8);
+    }
+}
+
+/// The failed NCQ command, reported by the NCQ Command Error log.
+#[derive(Clone, Copy, Default)]
+pub struct NcqError {
+    pub tag: u8,
+    pub status: u8,
+    pub error: u8,
+}
+
+/// Registers and the command state of a port, it's shared with the in-flight requests.
+pub struct AhciPortState {
+    port: usize,
+    clb: u64,
+    fb: u64,
+    is: u32,
+    ie: u32,
+    cmd: u32,
+    tfd: u32,
+    sig: u32,
+    ssts: u32,
+    sctl: u32,
+    serr: u32,
+    sact: u32,
+    ci: u32,
+    /// Command slots which are being processed.
+    issued: u32,
+    /// A task file error occurred, no command is processed until the port is restarted.
+    stalled: bool,
+    /// Software Reset bit written by the last control FIS.
+    srst: bool,
+    /// The D2H FIS with the signature has been posted after reset.
+    signature_posted: bool,
+    /// Bumped when the port is stopped or reset, the late completions are dropped.
+    generation: u64,
+    ncq_error: Option<NcqError>,
+    /// Attached device, true if it's an ATAPI device.
+    atapi: Option<bool>,
+    /// The io handler of the attached drive fails, reported as device fault.
+    fault: Arc<AtomicBool>,
+    mem_space: Arc<AddressSpace>,
+    irq: Arc<AhciIrq>,
+}
+
+impl AhciPortState {
+    fn new(port: usize, mem_space: Arc<AddressSpace>, irq: Arc<AhciIrq>) -> Self {
+        let mut state = Self {
+            port,
+            clb: 0,
+            fb: 0,
+            is: 0,
+            ie: 0,
+            cmd: 0,
+            tfd: 0,
+            sig: 0,
+            ssts: 0,
+            sctl: 0,
+            serr: 0,
+            sact: 0,
+            ci: 0,
+            issued: 0,
+            stalled: false,
+            srst: false,
+            signature_posted: false,
+            generation: 0,
+            ncq_error: None,
+            atapi: None,
+            fault: Arc::new(AtomicBool::new(false)),
+            mem_space,
+            irq,
+        };
+        state.reset();
+        state
+    }
+
+    /// Reset the port registers, used by the reset of the HBA.
+    fn reset(&mut self) {
+        self.clb = 0;
+        self.fb = 0;
+        self.is = 0;
+        self.ie = 0;
+        // Staggered spin-up is not supported, so the device is always spun up and powered on.
+        self.cmd = AHCI_PORT_CMD_SUD | AHCI_PORT_CMD_POD;
+        self.sctl = 0;
+        self.serr = 0;
+        self.stop();
+        self.reset_device();
+        self.update_irq(false);
+    }
+
+    /// Stop the command list processing, the outstanding commands are discarded.
+    fn stop(&mut self) {
+        self.ci = 0;
+        self.sact = 0;
+        self.issued = 0;
+        self.stalled = false;
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    /// Reset the device to the state after power on, the signature is reported again.
+    fn reset_device(&mut self) {
+        match self.atapi {
+            Some(atapi) => {
+                self.sig = if atapi { AHCI_SIG_ATAPI } else { AHCI_SIG_ATA };
+                // Diagnostic code 0x01 means the device passed.
+                self.tfd = 0x1 << 8 | ATA_STATUS_READY as u32;
+                self.ssts = AHCI_PORT_SSTS_ONLINE;
+            }
+            None => {
+                self.sig = u32::MAX;
+                self.tfd = AHCI_PORT_TFD_NO_DEVICE;
+                self.ssts = 0;
+            }
+        }
+        self.srst = false;
+        self.signature_posted = false;
+        self.ncq_error = None;
+        self.fault.store(false, Ordering::SeqCst);
+    }
+
+    fn attach(&mut self, atapi: bool) {
+        self.atapi = Some(atapi);
+        self.reset_device();
+    }
+
+    fn update_irq(&self, notify: bool) {
+        self.irq
+            .update_port(self.port, self.is & self.ie != 0, notify);
+    }
+
+    fn set_irq(&mut self, bits: u32) {
+        self.is |= bits;
+        self.update_irq(true);
+    }
+
+    fn write_fis(&self, offset: u64, fis: &[u8]) {
+        if self.cmd & AHCI_PORT_CMD_FRE == 0 || self.fb == 0 {
+            return;
+        }
+        let mut src = fis;
+        if let Err(e) =
+            self.mem_space
+                .write(&mut src, GuestAddress(self.fb + offset), fis.len() as u64)
+        {
+            error!("Failed to write fis of ahci port {}: {:?}", self.port, e);
+        }
+    }
+
+    fn post_d2h(&mut self, status: u8, error: u8, count: u16) {
+        let mut fis = [0_u8; AHCI_CMD_FIS_SIZE];
+        fis[0] = SATA_FIS_TYPE_REG_D2H;
+        fis[1] = SATA_FIS_I;
+        fis[2] = status;
+        fis[3] = error;
+        LittleEndian::write_u16(&mut fis[12..14], count);
+        self.tfd = (error as u32) << 8 | status as u32;
+        self.write_fis(AHCI_RX_FIS_D2H, &fis);
+    }
+
+    /// Post the D2H FIS with the signature in the Sector Count and LBA fields.
+    fn post_signature(&mut self) {
+        if self.atapi.is_none() || self.cmd & AHCI_PORT_CMD_FRE == 0 {
+            return;
+        }
+        let mut fis = [0_u8; AHCI_CMD_FIS_SIZE];
+        fis[0] = SATA_FIS_TYPE_REG_D2H;
+        fis[2] = self.tfd as u8;
+        fis[3] = (self.tfd >> 8) as u8;
+        fis[4] = (self.sig >> 8) as u8;
+        fis[5] = (self.sig >> 16) as u8;
+        fis[6] = (self.sig >> 24) as u8;
+        fis[12] = self.sig as u8;
+        self.write_fis(AHCI_RX_FIS_D2H, &fis);
+        self.signature_posted = true;
+    }
+
+    /// Complete the command slot without any FIS, used by the control FISes.
+    fn clear_slot(&mut self, slot: u8) {
+        self.ci &= !(1 << slot);
+        self.issued &= !(1 << slot);
+    }
+
+    /// The device accepts the NCQ command, which is completed by a Set Device Bits FIS.
+    fn accept_ncq(&mut self, slot: u8) {
+        self.clear_slot(slot);
+        self.tfd &= !(ATA_STATUS_BSY | ATA_STATUS_DRQ) as u32;
+    }
+
+    fn complete(&mut self, req: &AhciRequest, status: u8, error: u8) {
+        let failed = status & ATA_STATUS_ERR != 0;
+        let mut irq = 0;
+        match req.tag {
+            Some(tag) => {
+                if self.sact & (1 << tag) == 0 {
+                    return;
+                }
+                self.sact &= !(1 << tag);
+                if failed {
+                    self.ncq_error = Some(NcqError { tag, status, error });
+                    self.post_d2h(status, error, 0);
+                    irq |= AHCI_PORT_IS_DHRS | AHCI_PORT_IS_TFES;
+                } else {
+                    let mut fis = [0_u8; 8];
+                    fis[0] = SATA_FIS_TYPE_SDB;
+                    fis[1] = SATA_FIS_I;
+                    // Bits 6:4 and 2:0 of the Status register.
+                    fis[2] = status & 0x77;
+                    fis[3] = error;
+                    LittleEndian::write_u32(&mut fis[4..8], 1 << tag);
+                    self.tfd = (error as u32) << 8 | status as u32;
+                    self.write_fis(AHCI_RX_FIS_SDB, &fis);
+                    irq |= AHCI_PORT_IS_SDBS;
+                }
+            }
+            None => {
+                if self.issued & (1 << req.slot) == 0 {
+                    return;
+                }
+                let bytes = req.bytes.load(Ordering::SeqCst);
+                // Physical Region Descriptor Byte Count of the command header.
+                if let Err(e) = self
+                    .mem_space
+                    .write_object(&bytes, GuestAddress(req.header_addr + 4))
+                {
+                    error!("Failed to update ahci command header: {:?}", e);
+                }
+                if req.pio_in {
+                    // The status of the successful PIO data-in command is reported by
+                    // the E_Status field of the PIO Setup FIS.
+                    let mut fis = [0_u8; AHCI_CMD_FIS_SIZE];
+                    fis[0] = SATA_FIS_TYPE_PIO_SETUP;
+                    fis[1] = SATA_FIS_I | (1 << 5);
+                    fis[2] = status;
+                    fis[3] = error;
+                    fis[15] = status;
+                    LittleEndian::write_u16(&mut fis[16..18], bytes as u16);
+                    self.write_fis(AHCI_RX_FIS_PIO_SETUP, &fis);
+                    irq |= AHCI_PORT_IS_PSS;
+                }
+                self.clear_slot(req.slot);
+                self.post_d2h(status, error, req.count.load(Ordering::SeqCst));
+                irq |= AHCI_PORT_IS_DHRS;
+                if failed {
+                    irq |= AHCI_PORT_IS_TFES;
+                }
+            }
+        }
+        if failed {
+            self.stalled = true;
+        }
+        self.set_irq(irq);
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        match offset {
+            AHCI_PORT_CLB => self.clb as u32,
+            AHCI_PORT_CLBU => (self.clb >> 32) as u32,
+            AHCI_PORT_FB => self.fb as u32,
+            AHCI_PORT_FBU => (self.fb >> 32) as u32,
+            AHCI_PORT_IS => self.is,
+            AHCI_PORT_IE => self.ie,
+            AHCI_PORT_CMD => self.cmd,
+            AHCI_PORT_TFD => {
+                if self.fault.load(Ordering::SeqCst) {
+                    self.tfd | (ATA_STATUS_DF | ATA_STATUS_ERR) as u32
+                } else {
+                    self.tfd
+                }
+            }
+            AHCI_PORT_SIG => self.sig,
+            AHCI_PORT_SSTS => self.ssts,
+            AHCI_PORT_SCTL => self.sctl,
+            AHCI_PORT_SERR => self.serr,
+            AHCI_PORT_SACT => self.sact,
+            AHCI_PORT_CI => self.ci,
+            _ => 0,
+        }
+    }
+
+    /// Write the port register, returns true if there are new commands to process.
+    fn write(&mut self, offset: u64, value: u32) -> bool {
+        match offset {
+            AHCI_PORT_CLB => self.clb = write_u64_low(self.clb, value & !0x3ff),
+            AHCI_PORT_CLBU => self.clb = write_u64_high(self.clb, value),
+            AHCI_PORT_FB => self.fb = write_u64_low(self.fb, value & !0xff),
+            AHCI_PORT_FBU => self.fb = write_u64_high(self.fb, value),
+            AHCI_PORT_IS => {
+                self.is &= !value;
+                self.update_irq(false);
+            }
+            AHCI_PORT_IE => {
+                self.ie = value & AHCI_PORT_IE_MASK;
+                self.update_irq(true);
+            }
+            AHCI_PORT_CMD => self.write_cmd(value),
+            AHCI_PORT_SCTL => self.sctl = value,
+            AHCI_PORT_SERR => self.serr &= !value,
+            AHCI_PORT_SACT => {
+                if self.cmd & AHCI_PORT_CMD_ST != 0 {
+                    self.sact |= value;
+                }
+            }
+            AHCI_PORT_CI => {
+                if self.cmd & AHCI_PORT_CMD_ST != 0 {
+                    self.ci |= value;
+                    return true;
+                }
+            }
+            _ => warn!(
+                "Write to read-only or reserved register 0x{:x} of ahci port {}",
+                offset, self.port
+            ),
+        }
+        false
+    }
+
+    fn write_cmd(&mut self, value: u32) {
+        let old = self.cmd;
+        self.cmd = (old & !AHCI_PORT_CMD_MASK) | (value & AHCI_PORT_CMD_MASK);
+        // Command List Override clears BSY and DRQ, and it's cleared once done.
+        if value & AHCI_PORT_CMD_CLO != 0 {
+            self.tfd &= !(ATA_STATUS_BSY | ATA_STATUS_DRQ) as u32;
+        }
+
+        if value & AHCI_PORT_CMD_FRE != 0 {
+            self.cmd |= AHCI_PORT_CMD_FR;
+            if !self.signature_posted {
+                self.post_signature();
+            }
+        } else {
+            self.cmd &= !AHCI_PORT_CMD_FR;
+        }
+
+        if value & AHCI_PORT_CMD_ST != 0 {
+            self.cmd |= AHCI_PORT_CMD_CR;
+        } else {
+            if old & AHCI_PORT_CMD_ST != 0 {
+                self.stop();
+            }
+            self.cmd &= !AHCI_PORT_CMD_CR;
+        }
+    }
+}
+
+/// The request of a command slot.
+pub struct AhciRequest {
+    port: Arc<Mutex<AhciPortState>>,
+    mem_space: Arc<AddressSpace>,
+    slot: u8,
+    /// Tag of the NCQ command, None for the non-queued commands.
+    tag: Option<u8>,
+    /// The command transfers data from the device by PIO protocol.
+    pio_in: bool,
+    header_addr: u64,
+    generation: u64,
+    /// Guest memory regions described by the Physical Region Descriptor Table.
+    sgl: Vec<(u64, u64)>,
+    /// Bytes transferred, reported by the command header.
+    bytes: AtomicU32,
+    /// Sector Count register reported by the D2H FIS.
+    count: AtomicU16,
+}
+
+impl AhciRequest {
+    pub fn sgl_size(&self) -> u64 {
+        self.sgl.iter().map(|(_, len)| len).sum()
+    }
+
+    pub fn set_bytes(&self, bytes: u32) {
+        self.bytes.store(bytes, Ordering::SeqCst);
+    }
+
+    pub fn set_count(&self, count: u16) {
+        self.count.store(count, Ordering::SeqCst);
+    }
+
+    /// Map the first `len` bytes of the regions to the host, returns None if the
+    /// regions are too small or not in the guest memory.
+    pub fn map_iovecs(&self, len: u64) -> Option<Vec<Iovec>> {
+        let mut iovecs: Vec<Iovec> = Vec::new();
+        let mut remaining = len;
+        for &(addr, seg_len) in self.sgl.iter() {
+            let mut addr = addr;
+            let mut seg_len = min(seg_len, remaining);
+            remaining -= seg_len;
+            while seg_len > 0 {
+                let len = min(seg_len, AHCI_PAGE_SIZE - (addr & (AHCI_PAGE_SIZE - 1)));
+                let hva = match self.mem_space.get_host_address(GuestAddress(addr)) {
+                    Some(hva) => hva,
+                    None => {
+                        error!("Failed to map address 0x{:x} of ahci request", addr);
+                        return None;
+                    }
+                };
+                match iovecs.last_mut() {
+                    Some(last) if last.iov_base + last.iov_len == hva => last.iov_len += len,
+                    _ => iovecs.push(Iovec::new(hva, len)),
+                }
+                addr += len;
+                seg_len -= len;
+            }
+            if remaining == 0 {
+                break;
+            }
+        }
+        if remaining != 0 {
+            return None;
+        }
+        Some(iovecs)
+    }
+
+    /// Copy the data to the regions, the data out of the regions is dropped.
+    pub fn dma_write(&self, data: &[u8]) -> Result<()> {
+        let mut pos = 0;
+        for &(addr, len) in self.sgl.iter() {
+            if pos >= data.len() {
+                break;
+            }
+            let len = min(len as usize, data.len() - pos);
+            let mut src = &data[pos..pos + len];
+            self.mem_space
+                .write(&mut src, GuestAddress(addr), len as u64)
+                .with_context(|| "Failed to write data of ahci request")?;
+            pos += len;
+        }
+        self.set_bytes(pos as u32);
+        Ok(())
+    }
+
+    /// Read and clear the error of the NCQ commands.
+    pub fn take_ncq_error(&self) -> Option<NcqError> {
+        self.port.lock().unwrap().ncq_error.take()
+    }
+
+    pub fn done(&self, status: u8, error: u8) {
+        let mut locked_port = self.port.lock().unwrap();
+        if locked_port.generation != self.generation {
+            return;
+        }
+        locked_port.complete(self, status, error);
+    }
+}
+
+/// The command FIS, ATAPI command and PRDT of the command table.
+struct AhciCmdTable {
+    fis: [u8; AHCI_CMD_FIS_SIZE],
+    acmd: [u8; AHCI_ATAPI_CMD_SIZE],
+    sgl: Vec<(u64, u64)>,
+}
+
+struct AhciPort {
+    state: Arc<Mutex<AhciPortState>>,
+    drive: Option<IdeDrive>,
+}
+
+pub struct AhciCtrl {
+    id: String,
+    iothread: Option<String>,
+    mem_space: Arc<AddressSpace>,
+    drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    irq: Option<Arc<AhciIrq>>,
+    ghc: u32,
+    /// Ports are initialized with the interrupt.
+    ports: Vec<AhciPort>,
+    /// The io handlers of the drives stop working if it's set.
+    broken: Arc<AtomicBool>,
+}
+
+impl AhciCtrl {
+    pub fn new(
+        config: &AhciConfig,
+        mem_space: &Arc<AddressSpace>,
+        drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    ) -> Self {
+        Self {
+            id: config.id.clone(),
+            iothread: config.iothread.clone(),
+            mem_space: mem_space.clone(),
+            drive_files,
+            irq: None,
+            ghc: AHCI_GHC_AE,
+            ports: Vec::new(),
+            broken: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn init_ports(&mut self, irq: Arc<AhciIrq>) {
+        self.ports = (0..AHCI_MAX_PORTS as usize)
+            .map(|port| AhciPort {
+                state: Arc::new(Mutex::new(AhciPortState::new(
+                    port,
+                    self.mem_space.clone(),
+                    irq.clone(),
+                ))),
+                drive: None,
+            })
+            .collect();
+        self.irq = Some(irq);
+    }
+
+    /// Attach an ATA disk or an ATAPI CD-ROM to the port of the config.
+    pub fn attach_drive(&mut self, config: IdeDevConfig, is_cd: bool) -> Result<()> {
+        let port_id = config.port;
+        let port = self
+            .ports
+            .get_mut(port_id as usize)
+            .with_context(|| format!("Invalid port {} of ahci {}", port_id, self.id))?;
+        if port.drive.is_some() {
+            bail!("Port {} of ahci {} has been used", port_id, self.id);
+        }
+
+        let fault = port.state.lock().unwrap().fault.clone();
+        let error_cb: BlockIoErrorCallback = Arc::new(move || {
+            fault.store(true, Ordering::SeqCst);
+        });
+        let drive = IdeDrive::new(
+            config,
+            is_cd,
+            self.drive_files.clone(),
+            self.iothread.clone(),
+            self.broken.clone(),
+            error_cb,
+        )?;
+        port.drive = Some(drive);
+        port.state.lock().unwrap().attach(is_cd);
+        Ok(())
+    }
+
+    pub fn unrealize(&mut self) -> Result<()> {
+        self.reset();
+        for port in self.ports.iter_mut() {
+            if let Some(mut drive) = port.drive.take() {
+                drive.unrealize()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reset the HBA and all the ports.
+    pub fn reset(&mut self) {
+        self.ghc = AHCI_GHC_AE;
+        if let Some(irq) = self.irq.as_ref() {
+            irq.reset();
+        }
+        for port in self.ports.iter_mut() {
+            port.state.lock().unwrap().reset();
+            if let Some(drive) = port.drive.as_mut() {
+                drive.reset();
+            }
+        }
+    }
+
+    fn reset_device(&mut self, port_id: usize) {
+        let port = &mut self.ports[port_id];
+        let mut locked_state = port.state.lock().unwrap();
+        locked_state.stop();
+        locked_state.reset_device();
+        locked_state.post_signature();
+        drop(locked_state);
+        if let Some(drive) = port.drive.as_mut() {
+            drive.reset();
+        }
+    }
+
+    fn cap(&self) -> u32 {
+        (AHCI_MAX_PORTS as u32 - 1)
+            | (AHCI_MAX_CMDS as u32 - 1) << 8
+            // Gen 2 (3 Gbps) interface speed.
+            | 2 << 20
+            // Supports AHCI mode only.
+            | 1 << 18
+            // Supports Command List Override.
+            | 1 << 24
+            // Supports Native Command Queuing.
+            | 1 << 30
+            // Supports 64-bit addressing.
+            | 1 << 31
+    }
+
+    fn read_reg(&self, offset: u64) -> u32 {
+        if offset >= AHCI_PORT_REG_BASE {
+            let port_id = ((offset - AHCI_PORT_REG_BASE) / AHCI_PORT_REG_SIZE) as usize;
+            return match self.ports.get(port_id) {
+                Some(port) => port.state.lock().unwrap().read(offset % AHCI_PORT_REG_SIZE),
+                None => 0,
+            };
+        }
+        match offset {
+            AHCI_REG_CAP => self.cap(),
+            AHCI_REG_GHC => self.ghc,
+            AHCI_REG_IS => self.irq.as_ref().map_or(0, |irq| irq.pending()),
+            AHCI_REG_PI => (1 << AHCI_MAX_PORTS) - 1,
+            AHCI_REG_VS => AHCI_VERSION,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, value: u32) {
+        if offset >= AHCI_PORT_REG_BASE {
+            let port_id = ((offset - AHCI_PORT_REG_BASE) / AHCI_PORT_REG_SIZE) as usize;
+            let reg = offset % AHCI_PORT_REG_SIZE;
+            let process = match self.ports.get(port_id) {
+                Some(port) => port.state.lock().unwrap().write(reg, value),
+                None => return,
+            };
+            if reg == AHCI_PORT_SCTL && value & AHCI_PORT_SCTL_DET_MASK == AHCI_PORT_SCTL_DET_INIT {
+                // COMRESET, the device is reset and sends the signature again.
+                self.reset_device(port_id);
+            }
+            if process {
+                self.process_port(port_id);
+            }
+            return;
+        }
+
+        match offset {
+            AHCI_REG_GHC => {
+                if value & AHCI_GHC_HR != 0 {
+                    self.reset();
+                    return;
+                }
+                self.ghc = AHCI_GHC_AE | (value & AHCI_GHC_IE);
+                if let Some(irq) = self.irq.as_ref() {
+                    irq.set_enabled(value & AHCI_GHC_IE != 0);
+                }
+            }
+            // The bits are cleared when the interrupts of the ports are cleared.
+            AHCI_REG_IS => {}
+            _ => warn!(
+                "Write to read-only or reserved register 0x{:x} of ahci {}",
+                offset, self.id
+            ),
+        }
+    }
+
+    fn process_port(&mut self, port_id: usize) {
+        let state = self.ports[port_id].state.clone();
+        let mut locked_state = state.lock().unwrap();
+        if locked_state.cmd & AHCI_PORT_CMD_ST == 0 || locked_state.stalled {
+            return;
+        }
+        let slots = locked_state.ci & !locked_state.issued;
+        locked_state.issued |= slots;
+        let clb = locked_state.clb;
+        let generation = locked_state.generation;
+        drop(locked_state);
+
+        for slot in 0..AHCI_MAX_CMDS as u8 {
+            if slots & (1 << slot) != 0 {
+                self.process_cmd(port_id, slot, clb, generation);
+            }
+        }
+        if let Some(drive) = self.ports[port_id].drive.as_ref() {
+            drive.flush_request();
+        }
+    }
+
+    fn read_cmd(&self, header_addr: u64) -> Result<AhciCmdTable> {
+        let header = self
+            .mem_space
+            .read_object::<AhciCmdHeader>(GuestAddress(header_addr))
+            .with_context(|| "Failed to read command header")?;
+        let mut fis = [0_u8; AHCI_CMD_FIS_SIZE];
+        self.mem_space
+            .read(
+                &mut fis.as_mut_slice(),
+                GuestAddress(header.ctba + AHCI_CMD_TBL_CFIS),
+                AHCI_CMD_FIS_SIZE as u64,
+            )
+            .with_context(|| "Failed to read command fis")?;
+        let mut acmd = [0_u8; AHCI_ATAPI_CMD_SIZE];
+        if header.flags & AHCI_CMD_HDR_ATAPI != 0 {
+            self.mem_space
+                .read(
+                    &mut acmd.as_mut_slice(),
+                    GuestAddress(header.ctba + AHCI_CMD_TBL_ACMD),
+                    AHCI_ATAPI_CMD_SIZE as u64,
+                )
+                .with_context(|| "Failed to read atapi command")?;
+        }
+
+        let mut sgl = Vec::with_capacity(header.prdtl as usize);
+        for i in 0..header.prdtl as u64 {
+            let prd_addr =
+                header.ctba + AHCI_CMD_TBL_PRDT + i * std::mem::size_of::<AhciPrd>() as u64;
+            let prd = self
+                .mem_space
+                .read_object::<AhciPrd>(GuestAddress(prd_addr))
+                .with_context(|| "Failed to read physical region descriptor")?;
+            sgl.push((
+                prd.dba & !0x1,
+                ((prd.dbc & AHCI_PRD_DBC_MASK) | 0x1) as u64 + 1,
+            ));
+        }
+        Ok(AhciCmdTable { fis, acmd, sgl })
+    }
+
+    fn process_cmd(&mut self, port_id: usize, slot: u8, clb: u64, generation: u64) {
+        let state = self.ports[port_id].state.clone();
+        let header_addr = clb + slot as u64 * AHCI_CMD_HDR_SIZE;
+        let new_req = |tag: Option<u8>, pio_in: bool, sgl: Vec<(u64, u64)>| {
+            Arc::new(AhciRequest {
+                port: state.clone(),
+                mem_space: self.mem_space.clone(),
+                slot,
+                tag,
+                pio_in,
+                header_addr,
+                generation,
+                sgl,
+                bytes: AtomicU32::new(0),
+                count: AtomicU16::new(0),
+            })
+        };
+
+        let AhciCmdTable { fis, acmd, sgl } = match self.read_cmd(header_addr) {
+            Ok(cmd) if cmd.fis[0] != SATA_FIS_TYPE_REG_H2D => {
+                warn!(
+                    "Unsupported fis type 0x{:x} of ahci {} port {}",
+                    cmd.fis[0], self.id, port_id
+                );
+                new_req(None, false, Vec::new())
+                    .done(ATA_STATUS_READY | ATA_STATUS_ERR, ATA_ERROR_ABRT);
+                return;
+            }
+            Ok(cmd) => cmd,
+            Err(e) => {
+                error!(
+                    "Failed to read command slot {} of ahci {} port {}: {:?}",
+                    slot, self.id, port_id, e
+                );
+                new_req(None, false, Vec::new())
+                    .done(ATA_STATUS_READY | ATA_STATUS_ERR, ATA_ERROR_ABRT);
+                return;
+            }
+        };
+
+        if fis[1] & SATA_FIS_REG_H2D_C == 0 {
+            // The Device Control register is updated, the device is reset when the
+            // Software Reset bit is cleared after it's set.
+            let srst = fis[15] & ATA_DEVCTL_SRST != 0;
+            let mut locked_state = state.lock().unwrap();
+            locked_state.clear_slot(slot);
+            let reset = locked_state.srst && !srst;
+            locked_state.srst = srst;
+            drop(locked_state);
+            if reset {
+                self.reset_device(port_id);
+            }
+            return;
+        }
+
+        let tf = AtaTaskFile::from_fis(&fis);
+        let tag = match tf.command {
+            ATA_CMD_READ_FPDMA_QUEUED | ATA_CMD_WRITE_FPDMA_QUEUED => Some(tf.ncq_tag()),
+            _ => None,
+        };
+        let pio_in = matches!(
+            tf.command,
+            ATA_CMD_READ_SECTORS
+                | ATA_CMD_READ_SECTORS_EXT
+                | ATA_CMD_READ_LOG_EXT
+                | ATA_CMD_IDENTIFY_DEVICE
+                | ATA_CMD_IDENTIFY_PACKET_DEVICE
+        );
+        let req = new_req(tag, pio_in, sgl);
+        if tag.is_some() {
+            state.lock().unwrap().accept_ncq(slot);
+        }
+        match self.ports[port_id].drive.as_mut() {
+            Some(drive) => drive.execute(&req, &tf, &acmd),
+            None => req.done(ATA_STATUS_READY | ATA_STATUS_ERR, ATA_ERROR_ABRT),
+        }
+    }
+}
+
+/// Build the ops of the HBA registers, the accesses are split into 4 bytes ones.
+pub fn build_ahci_ops(ctrl: &Arc<Mutex<AhciCtrl>>) -> RegionOps {
+    let cloned_ctrl = ctrl.clone();
+    let ahci_read = move |data: &mut [u8], _addr: GuestAddress, offset: u64| -> bool {
+        let value = cloned_ctrl.lock().unwrap().read_reg(offset & !0x3);
+        write_data_u32(data, value >> ((offset & 0x3) * 8))
+    };
+
+    let cloned_ctrl = ctrl.clone();
+    let ahci_write = move |data: &[u8], _addr: GuestAddress, offset: u64| -> bool {
+        let mut value = 0;
+        if !read_data_u32(data, &mut value) {
+            return false;
+        }
+        if data.len() != 4 || offset & 0x3 != 0 {
+            warn!(
+                "Unaligned write of ahci register, offset 0x{:x} len {}",
+                offset,
+                data.len()
+            );
+            return true;
+        }
+        cloned_ctrl.lock().unwrap().write_reg(offset, value);
+        true
+    };
+
+    RegionOps {
+        read: Arc::new(ahci_read),
+        write: Arc::new(ahci_write),
+    }
+}
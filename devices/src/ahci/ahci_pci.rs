@@ -0,0 +1,239 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{bail, Context, Result};
+
+use super::ahci_ctrl::{build_ahci_ops, AhciCtrl, AhciIrq};
+use address_space::{AddressSpace, Region};
+use machine_manager::config::{AhciConfig, DriveFile, IdeDevConfig};
+use machine_manager::event_loop::EventLoop;
+use pci::config::{
+    PciConfig, RegionType, DEVICE_ID, PCI_CLASS_STORAGE_SATA, PCI_CONFIG_SPACE_SIZE,
+    PCI_DEVICE_ID_INTEL_ICH9_AHCI, PCI_VENDOR_ID_INTEL, REVISION_ID, SUB_CLASS_CODE, VENDOR_ID,
+};
+use pci::msix::update_dev_id;
+use pci::{init_intx, init_msix, init_multifunction, le_write_u16, PciBus, PciDevOps};
+
+/// Programming interface of AHCI 1.0.
+const PCI_CLASS_PI: usize = 0x09;
+const AHCI_PROG_IF: u8 = 0x01;
+/// The HBA memory registers are in BAR5 (ABAR).
+const AHCI_ABAR_INDEX: usize = 5;
+/// ABAR layout.
+/// 0x0         0x1000       0x1800      0x2000
+/// | registers | MSIX table | MSIX PBA  |
+const AHCI_ABAR_SIZE: u64 = 0x2000;
+const AHCI_REG_SIZE: u64 = 0x1000;
+const AHCI_MSIX_TABLE_OFFSET: u32 = 0x1000;
+const AHCI_MSIX_PBA_OFFSET: u32 = 0x1800;
+
+/// ICH9 AHCI controller which can be attached to PCI bus.
+pub struct AhciPciDevice {
+    pci_config: PciConfig,
+    devfn: u8,
+    dev_id: Arc<AtomicU16>,
+    name: String,
+    parent_bus: Weak<Mutex<PciBus>>,
+    mem_region: Region,
+    config: AhciConfig,
+    multi_func: bool,
+    pub ctrl: Arc<Mutex<AhciCtrl>>,
+}
+
+impl AhciPciDevice {
+    pub fn new(
+        config: &AhciConfig,
+        devfn: u8,
+        parent_bus: Weak<Mutex<PciBus>>,
+        mem_space: &Arc<AddressSpace>,
+        drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+        multi_func: bool,
+    ) -> Self {
+        Self {
+            pci_config: PciConfig::new(PCI_CONFIG_SPACE_SIZE, AHCI_ABAR_INDEX as u8 + 1),
+            devfn,
+            dev_id: Arc::new(AtomicU16::new(0)),
+            name: config.id.clone(),
+            parent_bus,
+            mem_region: Region::init_container_region(AHCI_ABAR_SIZE),
+            config: config.clone(),
+            multi_func,
+            ctrl: Arc::new(Mutex::new(AhciCtrl::new(config, mem_space, drive_files))),
+        }
+    }
+
+    /// Attach a device described by `-device ide-hd` or `-device ide-cd` to the port.
+    pub fn add_drive(&self, config: IdeDevConfig, is_cd: bool) -> Result<()> {
+        self.ctrl
+            .lock()
+            .unwrap()
+            .attach_drive(config, is_cd)
+            .with_context(|| format!("Failed to add drive to ahci {}", self.name))
+    }
+
+    fn mem_region_init(&mut self) -> pci::Result<()> {
+        let mut reg_region = Region::init_io_region(AHCI_REG_SIZE, build_ahci_ops(&self.ctrl));
+        reg_region.set_access_size(4);
+        pci::Result::with_context(self.mem_region.add_subregion(reg_region, 0), || {
+            "Failed to register ahci register region."
+        })?;
+        Ok(())
+    }
+}
+
+impl PciDevOps for AhciPciDevice {
+    fn init_write_mask(&mut self) -> pci::Result<()> {
+        self.pci_config.init_common_write_mask()
+    }
+
+    fn init_write_clear_mask(&mut self) -> pci::Result<()> {
+        self.pci_config.init_common_write_clear_mask()
+    }
+
+    fn realize(mut self) -> pci::Result<()> {
+        if self.config.iothread.is_some()
+            && EventLoop::get_ctx(self.config.iothread.as_ref()).is_none()
+        {
+            bail!(
+                "IOThread {:?} of ahci is not configured in params.",
+                self.config.iothread
+            );
+        }
+
+        self.init_write_mask()?;
+        self.init_write_clear_mask()?;
+        le_write_u16(
+            &mut self.pci_config.config,
+            VENDOR_ID as usize,
+            PCI_VENDOR_ID_INTEL,
+        )?;
+        le_write_u16(
+            &mut self.pci_config.config,
+            DEVICE_ID as usize,
+            PCI_DEVICE_ID_INTEL_ICH9_AHCI,
+        )?;
+        le_write_u16(&mut self.pci_config.config, REVISION_ID, 0x2_u16)?;
+        le_write_u16(
+            &mut self.pci_config.config,
+            SUB_CLASS_CODE as usize,
+            PCI_CLASS_STORAGE_SATA,
+        )?;
+        self.pci_config.config[PCI_CLASS_PI] = AHCI_PROG_IF;
+        init_multifunction(
+            self.multi_func,
+            &mut self.pci_config.config,
+            self.devfn,
+            self.parent_bus.clone(),
+        )?;
+
+        #[cfg(target_arch = "aarch64")]
+        self.pci_config.set_interrupt_pin();
+
+        self.dev_id.store(self.devfn as u16, Ordering::SeqCst);
+        self.mem_region_init()?;
+
+        // All the ports share one vector.
+        init_msix(
+            AHCI_ABAR_INDEX,
+            1,
+            &mut self.pci_config,
+            self.dev_id.clone(),
+            &self.name,
+            Some(&self.mem_region),
+            Some((AHCI_MSIX_TABLE_OFFSET, AHCI_MSIX_PBA_OFFSET)),
+        )?;
+
+        init_intx(
+            self.name.clone(),
+            &mut self.pci_config,
+            self.parent_bus.clone(),
+            self.devfn,
+        )?;
+
+        self.pci_config.register_bar(
+            AHCI_ABAR_INDEX,
+            self.mem_region.clone(),
+            RegionType::Mem32Bit,
+            false,
+            AHCI_ABAR_SIZE,
+        )?;
+
+        // It is safe to unwrap, because they are initialized in init_msix and init_intx.
+        let msix = self.pci_config.msix.as_ref().unwrap().clone();
+        let intx = self.pci_config.intx.as_ref().unwrap().clone();
+        let irq = Arc::new(AhciIrq::new(msix, intx, self.dev_id.clone()));
+        self.ctrl.lock().unwrap().init_ports(irq);
+
+        let devfn = self.devfn;
+        let dev = Arc::new(Mutex::new(self));
+        // Attach to the PCI bus.
+        let pci_bus = dev.lock().unwrap().parent_bus.upgrade().unwrap();
+        let mut locked_pci_bus = pci_bus.lock().unwrap();
+        if let Some(pci_device) = locked_pci_bus.devices.get(&devfn) {
+            let used_by = pci_device.lock().unwrap().name();
+            dev.lock().unwrap().unrealize()?;
+            bail!("Devfn {:?} has been used by {:?}", &devfn, used_by);
+        }
+        locked_pci_bus.devices.insert(devfn, dev);
+        Ok(())
+    }
+
+    fn unrealize(&mut self) -> pci::Result<()> {
+        self.ctrl.lock().unwrap().unrealize()
+    }
+
+    fn devfn(&self) -> Option<u8> {
+        Some(self.devfn)
+    }
+
+    fn read_config(&mut self, offset: usize, data: &mut [u8]) {
+        self.pci_config.read(offset, data);
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        update_dev_id(&self.parent_bus, self.devfn, &self.dev_id);
+        let parent_bus = self.parent_bus.upgrade().unwrap();
+        let locked_parent_bus = parent_bus.lock().unwrap();
+
+        self.pci_config.write(
+            offset,
+            data,
+            self.dev_id.clone().load(Ordering::Acquire),
+            #[cfg(target_arch = "x86_64")]
+            Some(&locked_parent_bus.io_region),
+            Some(&locked_parent_bus.mem_region),
+        );
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_dev_path(&self) -> Option<String> {
+        // The drives of the controller have their own boot path("/drive@$port/disk@0").
+        let parent_bus = self.parent_bus.upgrade().unwrap();
+        let parent_dev_path = self.get_parent_dev_path(parent_bus);
+        Some(self.populate_dev_path(parent_dev_path, self.devfn, "/ide@"))
+    }
+
+    fn reset(&mut self, _reset_child_device: bool) -> pci::Result<()> {
+        self.ctrl.lock().unwrap().reset();
+
+        self.pci_config.reset()?;
+
+        Ok(())
+    }
+}
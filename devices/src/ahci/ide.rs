@@ -0,0 +1,760 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use log::{error, warn};
+
+use super::ahci_ctrl::AhciRequest;
+use super::*;
+use crate::ScsiBus::{
+    ScsiBus, ScsiRequest, ScsiRequestOps, ScsiSense, ScsiXferMode, EMULATE_SCSI_OPS, GOOD,
+    REQUEST_SENSE, SCSI_CMD_BUF_SIZE, SCSI_SENSE_INVALID_OPCODE, SCSI_SENSE_IO_ERROR,
+    SCSI_SENSE_LBA_OUT_OF_RANGE,
+};
+use crate::ScsiDisk::{ScsiDevice, SCSI_TYPE_ROM};
+use block_backend::stats::{BlockAcctCookie, BlockAcctType, BlockStats};
+use block_backend::{
+    create_block_backend, create_nbd_backend, register_block_device, unregister_block_device,
+    BlockDevInfo, BlockDriverOps, BlockIoErrorCallback, BlockProperty,
+};
+use machine_manager::config::{is_nbd_path, DriveFile, IdeDevConfig, ScsiDevConfig, VmConfig};
+use util::aio::{get_iov_size, Aio, AioCb, AioEngine};
+
+/// Status of the successful commands.
+const IDE_STATUS_OK: u8 = ATA_STATUS_DRDY | ATA_STATUS_DSC;
+/// Status of the failed commands.
+const IDE_STATUS_ERR: u8 = ATA_STATUS_DRDY | ATA_STATUS_ERR;
+/// Default CHS geometry reported by IDENTIFY DEVICE.
+const IDE_HEADS: u64 = 16;
+const IDE_SECTORS_PER_TRACK: u64 = 63;
+const IDE_MAX_CYLINDERS: u64 = 16383;
+/// Max number of sectors of READ/WRITE MULTIPLE.
+const IDE_MAX_MULTIPLE_SECTORS: u16 = 16;
+/// Max LBA of the 28-bit commands.
+const IDE_MAX_LBA28: u64 = 0x0fff_ffff;
+const IDE_FIRMWARE_REVISION: &str = "1.0";
+const IDE_DISK_MODEL: &str = "STRA HARDDISK";
+const IDE_CD_MODEL: &str = "STRA CDROM";
+
+/// Subcommands of SET FEATURES.
+const ATA_FEATURE_ENABLE_WRITE_CACHE: u16 = 0x02;
+const ATA_FEATURE_SET_TRANSFER_MODE: u16 = 0x03;
+const ATA_FEATURE_DISABLE_READ_LOOK_AHEAD: u16 = 0x55;
+const ATA_FEATURE_DISABLE_WRITE_CACHE: u16 = 0x82;
+const ATA_FEATURE_ENABLE_READ_LOOK_AHEAD: u16 = 0xaa;
+
+/// Log addresses of READ LOG EXT.
+const ATA_LOG_DIRECTORY: u64 = 0x00;
+const ATA_LOG_NCQ_COMMAND_ERROR: u64 = 0x10;
+const ATA_LOG_PAGE_SIZE: usize = 512;
+
+/// The ATAPI command packet is 12 bytes.
+const ATAPI_PACKET_SIZE: usize = 12;
+/// Fixed format sense data returned by REQUEST SENSE.
+const ATAPI_SENSE_DATA_SIZE: usize = 18;
+
+fn put_word(data: &mut [u8], word: usize, value: u16) {
+    LittleEndian::write_u16(&mut data[word * 2..word * 2 + 2], value);
+}
+
+/// Fill the ATA string `buf` with `s` padded with spaces, the first character
+/// of each word is in the high byte.
+fn put_ata_string(buf: &mut [u8], s: &str) {
+    buf.fill(b' ');
+    let len = min(buf.len(), s.len());
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+    for pair in buf.chunks_exact_mut(2) {
+        pair.swap(0, 1);
+    }
+}
+
+/// Integrity word of the IDENTIFY data, the sum of all the bytes is zero.
+fn put_checksum(data: &mut [u8]) {
+    data[ATA_IDENTIFY_DATA_SIZE - 2] = 0xa5;
+    let sum = data[..ATA_IDENTIFY_DATA_SIZE - 1]
+        .iter()
+        .fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+    data[ATA_IDENTIFY_DATA_SIZE - 1] = 0_u8.wrapping_sub(sum);
+}
+
+fn abort(req: &AhciRequest) {
+    req.done(IDE_STATUS_ERR, ATA_ERROR_ABRT);
+}
+
+/// Write the data to the regions of the request, and complete it.
+fn transfer_data_in(req: &AhciRequest, data: &[u8]) {
+    match req.dma_write(data) {
+        Ok(()) => req.done(IDE_STATUS_OK, 0),
+        Err(e) => {
+            error!("{:?}", e);
+            abort(req);
+        }
+    }
+}
+
+/// Device attached to the port of the AHCI controller.
+pub enum IdeDrive {
+    Disk(IdeDisk),
+    Cd(IdeCd),
+}
+
+impl IdeDrive {
+    pub fn new(
+        config: IdeDevConfig,
+        is_cd: bool,
+        drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+        iothread: Option<String>,
+        broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Result<Self> {
+        if is_cd {
+            let mut cd = IdeCd::new(config, drive_files);
+            cd.realize()?;
+            Ok(IdeDrive::Cd(cd))
+        } else {
+            let mut disk = IdeDisk::new(config, drive_files);
+            disk.realize(iothread, broken, error_cb)?;
+            Ok(IdeDrive::Disk(disk))
+        }
+    }
+
+    pub fn execute(
+        &mut self,
+        req: &Arc<AhciRequest>,
+        tf: &AtaTaskFile,
+        acmd: &[u8; AHCI_ATAPI_CMD_SIZE],
+    ) {
+        match tf.command {
+            // The power management commands have no effect.
+            ATA_CMD_STANDBY_IMMEDIATE
+            | ATA_CMD_IDLE_IMMEDIATE
+            | ATA_CMD_STANDBY
+            | ATA_CMD_IDLE
+            | ATA_CMD_SLEEP => req.done(IDE_STATUS_OK, 0),
+            ATA_CMD_CHECK_POWER_MODE => {
+                // The device is in active or idle mode.
+                req.set_count(0xff);
+                req.done(IDE_STATUS_OK, 0);
+            }
+            // The device passed the diagnostic.
+            ATA_CMD_EXECUTE_DEVICE_DIAGNOSTIC => req.done(IDE_STATUS_OK, 0x01),
+            ATA_CMD_SET_FEATURES => {
+                let features = tf.features & 0xff;
+                match (features, self) {
+                    (ATA_FEATURE_ENABLE_WRITE_CACHE, IdeDrive::Disk(disk)) => {
+                        disk.write_cache = true
+                    }
+                    (ATA_FEATURE_DISABLE_WRITE_CACHE, IdeDrive::Disk(disk)) => {
+                        disk.write_cache = false
+                    }
+                    (
+                        ATA_FEATURE_SET_TRANSFER_MODE
+                        | ATA_FEATURE_ENABLE_READ_LOOK_AHEAD
+                        | ATA_FEATURE_DISABLE_READ_LOOK_AHEAD,
+                        _,
+                    ) => {}
+                    _ => {
+                        warn!("Unsupported feature 0x{:x} of ide device", features);
+                        abort(req);
+                        return;
+                    }
+                }
+                req.done(IDE_STATUS_OK, 0);
+            }
+            _ => match self {
+                IdeDrive::Disk(disk) => disk.execute(req, tf),
+                IdeDrive::Cd(cd) => cd.execute(req, tf, acmd),
+            },
+        }
+    }
+
+    /// Submit the batched requests of the backend.
+    pub fn flush_request(&self) {
+        if let IdeDrive::Disk(disk) = self {
+            disk.flush_request();
+        }
+    }
+
+    pub fn reset(&mut self) {
+        match self {
+            IdeDrive::Disk(disk) => disk.write_cache = true,
+            IdeDrive::Cd(cd) => *cd.sense.lock().unwrap() = ScsiSense::default(),
+        }
+    }
+
+    pub fn unrealize(&mut self) -> Result<()> {
+        match self {
+            IdeDrive::Disk(disk) => disk.unrealize(),
+            IdeDrive::Cd(cd) => {
+                cd.scsi_dev.lock().unwrap().unrealize();
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct IdeCompleteCb {
+    req: Arc<AhciRequest>,
+    /// Error register of the request if the aio request fails.
+    error: u8,
+    stats: Arc<BlockStats>,
+    acct: BlockAcctCookie,
+}
+
+fn ide_aio_complete(aiocb: &AioCb<IdeCompleteCb>, ret: i64) -> Result<()> {
+    let complete_cb = &aiocb.iocompletecb;
+    complete_cb.stats.account_done(&complete_cb.acct, ret < 0);
+    if ret < 0 {
+        error!("Failed to handle ide request, ret {}", ret);
+        complete_cb.req.done(IDE_STATUS_ERR, complete_cb.error);
+    } else {
+        complete_cb.req.done(IDE_STATUS_OK, 0);
+    }
+    Ok(())
+}
+
+/// ATA hard disk backed by a drive, the logical sector size is 512 bytes.
+pub struct IdeDisk {
+    config: IdeDevConfig,
+    block_backend: Option<Arc<Mutex<dyn BlockDriverOps<IdeCompleteCb>>>>,
+    /// Size of the disk in sectors.
+    sectors: u64,
+    /// The volatile write cache is enabled, it's reported by IDENTIFY DEVICE.
+    write_cache: bool,
+    stats: Arc<BlockStats>,
+    drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+}
+
+impl IdeDisk {
+    pub fn new(config: IdeDevConfig, drive_files: Arc<Mutex<HashMap<String, DriveFile>>>) -> Self {
+        Self {
+            config,
+            block_backend: None,
+            sectors: 0,
+            write_cache: true,
+            stats: Arc::new(BlockStats::default()),
+            drive_files,
+        }
+    }
+
+    pub fn realize(
+        &mut self,
+        iothread: Option<String>,
+        broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Result<()> {
+        let drive = &self.config.drive;
+        let is_nbd = is_nbd_path(&drive.path_on_host);
+        // The disk exported by an NBD server has no host file nor alignment requirement.
+        let (file, alignments) = if is_nbd {
+            (None, (1, 1))
+        } else {
+            let drive_files = self.drive_files.lock().unwrap();
+            (
+                Some(VmConfig::fetch_drive_file(
+                    &drive_files,
+                    &drive.path_on_host,
+                )?),
+                VmConfig::fetch_drive_align(&drive_files, &drive.path_on_host)?,
+            )
+        };
+        let aio = Aio::new_with_uring_config(Arc::new(ide_aio_complete), drive.aio, drive.uring)?;
+        let prop = BlockProperty {
+            id: self.config.id.clone(),
+            path: drive.path_on_host.clone(),
+            format: drive.format,
+            iothread,
+            direct: drive.direct,
+            req_align: alignments.0,
+            buf_align: alignments.1,
+            discard: drive.discard,
+            write_zeroes: drive.write_zeroes,
+        };
+        let block_backend = match file {
+            Some(file) => create_block_backend(file, aio, prop.clone())?,
+            None => create_nbd_backend(aio, prop.clone())?,
+        };
+        let mut locked_backend = block_backend.lock().unwrap();
+        self.sectors = locked_backend.disk_size()? >> ATA_SECTOR_SHIFT;
+        locked_backend.register_io_event(broken, error_cb)?;
+        drop(locked_backend);
+        self.block_backend = Some(block_backend);
+
+        register_block_device(BlockDevInfo {
+            prop,
+            read_only: drive.read_only,
+            aio: drive.aio,
+            throttle: None,
+            throttle_group: None,
+            removable: false,
+            stats: self.stats.clone(),
+            resize: None,
+            backup: None,
+            dirty_bitmaps: None,
+            medium: None,
+        });
+        Ok(())
+    }
+
+    pub fn unrealize(&mut self) -> Result<()> {
+        if let Some(block_backend) = self.block_backend.take() {
+            block_backend.lock().unwrap().unregister_io_event()?;
+        }
+        unregister_block_device(&self.config.id);
+        Ok(())
+    }
+
+    fn execute(&mut self, req: &Arc<AhciRequest>, tf: &AtaTaskFile) {
+        match tf.command {
+            ATA_CMD_IDENTIFY_DEVICE => transfer_data_in(req, &self.identify()),
+            ATA_CMD_READ_DMA | ATA_CMD_READ_SECTORS => {
+                self.rw(req, false, tf.lba28(), tf.count28())
+            }
+            ATA_CMD_WRITE_DMA | ATA_CMD_WRITE_SECTORS => {
+                self.rw(req, true, tf.lba28(), tf.count28())
+            }
+            ATA_CMD_READ_DMA_EXT | ATA_CMD_READ_SECTORS_EXT => {
+                self.rw(req, false, tf.lba, tf.count48())
+            }
+            ATA_CMD_WRITE_DMA_EXT | ATA_CMD_WRITE_SECTORS_EXT => {
+                self.rw(req, true, tf.lba, tf.count48())
+            }
+            ATA_CMD_READ_FPDMA_QUEUED => self.rw(req, false, tf.lba, tf.ncq_count()),
+            ATA_CMD_WRITE_FPDMA_QUEUED => self.rw(req, true, tf.lba, tf.ncq_count()),
+            ATA_CMD_READ_VERIFY => self.verify(req, tf.lba28(), tf.count28()),
+            ATA_CMD_READ_VERIFY_EXT => self.verify(req, tf.lba, tf.count48()),
+            ATA_CMD_FLUSH_CACHE | ATA_CMD_FLUSH_CACHE_EXT => self.flush(req),
+            ATA_CMD_READ_LOG_EXT | ATA_CMD_READ_LOG_DMA_EXT => self.read_log(req, tf),
+            // The obsolete commands of CHS addressing have no effect.
+            ATA_CMD_RECALIBRATE
+            | ATA_CMD_INITIALIZE_DEVICE_PARAMETERS
+            | ATA_CMD_SET_MULTIPLE_MODE => req.done(IDE_STATUS_OK, 0),
+            _ => {
+                warn!(
+                    "Unsupported command 0x{:x} of ide disk {}",
+                    tf.command, self.config.id
+                );
+                abort(req);
+            }
+        }
+    }
+
+    fn check_range(&self, lba: u64, count: u64) -> bool {
+        matches!(lba.checked_add(count), Some(end) if end <= self.sectors)
+    }
+
+    /// IDENTIFY DEVICE data, refer to 7.16.7 of ATA8-ACS.
+    fn identify(&self) -> Vec<u8> {
+        let mut data = vec![0_u8; ATA_IDENTIFY_DATA_SIZE];
+        let cylinders = min(
+            self.sectors / (IDE_HEADS * IDE_SECTORS_PER_TRACK),
+            IDE_MAX_CYLINDERS,
+        );
+        // Fixed device.
+        put_word(&mut data, 0, 0x0040);
+        put_word(&mut data, 1, cylinders as u16);
+        put_word(&mut data, 3, IDE_HEADS as u16);
+        put_word(&mut data, 6, IDE_SECTORS_PER_TRACK as u16);
+        let serial = self.config.serial.as_ref().unwrap_or(&self.config.id);
+        put_ata_string(&mut data[20..40], serial);
+        put_ata_string(&mut data[46..54], IDE_FIRMWARE_REVISION);
+        put_ata_string(&mut data[54..94], IDE_DISK_MODEL);
+        put_word(&mut data, 47, 0x8000 | IDE_MAX_MULTIPLE_SECTORS);
+        // LBA and DMA are supported.
+        put_word(&mut data, 49, 0x0300);
+        // Words 54-58, 64-70 and 88 are valid.
+        put_word(&mut data, 53, 0x0006);
+        put_word(&mut data, 54, cylinders as u16);
+        put_word(&mut data, 55, IDE_HEADS as u16);
+        put_word(&mut data, 56, IDE_SECTORS_PER_TRACK as u16);
+        let chs_sectors = cylinders * IDE_HEADS * IDE_SECTORS_PER_TRACK;
+        LittleEndian::write_u32(&mut data[114..118], chs_sectors as u32);
+        LittleEndian::write_u32(&mut data[120..124], min(self.sectors, IDE_MAX_LBA28) as u32);
+        // Multiword DMA mode 0-2 and PIO mode 3-4 are supported.
+        put_word(&mut data, 63, 0x0007);
+        put_word(&mut data, 64, 0x0003);
+        for word in 65..=68 {
+            put_word(&mut data, word, 120);
+        }
+        // Queue depth minus one of NCQ.
+        put_word(&mut data, 75, AHCI_MAX_CMDS as u16 - 1);
+        // NCQ, SATA Gen1 and Gen2 speed are supported.
+        put_word(&mut data, 76, 0x0106);
+        // ATA/ATAPI-4 to ATA8-ACS.
+        put_word(&mut data, 80, 0x01f0);
+        // Power management, write cache, look-ahead and NOP are supported.
+        put_word(&mut data, 82, 0x4068);
+        // 48-bit address, FLUSH CACHE and FLUSH CACHE EXT are supported.
+        put_word(&mut data, 83, 0x7400);
+        // General Purpose Logging is supported.
+        put_word(&mut data, 84, 0x4020);
+        let write_cache = if self.write_cache { 1 << 5 } else { 0 };
+        put_word(&mut data, 85, 0x4048 | write_cache);
+        put_word(&mut data, 86, 0x3400);
+        put_word(&mut data, 87, 0x4020);
+        // Ultra DMA mode 0-5 are supported and mode 5 is selected.
+        put_word(&mut data, 88, 0x203f);
+        LittleEndian::write_u64(&mut data[200..208], self.sectors);
+        put_checksum(&mut data);
+        data
+    }
+
+    fn submit<F>(&self, req: &Arc<AhciRequest>, acct: BlockAcctType, bytes: u64, error: u8, f: F)
+    where
+        F: FnOnce(&mut dyn BlockDriverOps<IdeCompleteCb>, IdeCompleteCb) -> Result<()>,
+    {
+        let block_backend = match self.block_backend.as_ref() {
+            Some(block_backend) => block_backend,
+            None => {
+                abort(req);
+                return;
+            }
+        };
+        let complete_cb = IdeCompleteCb {
+            req: req.clone(),
+            error,
+            stats: self.stats.clone(),
+            acct: BlockAcctCookie::new(acct, bytes),
+        };
+        let mut locked_backend = block_backend.lock().unwrap();
+        if let Err(e) = f(&mut *locked_backend, complete_cb) {
+            error!(
+                "Failed to submit request of ide disk {}: {:?}",
+                self.config.id, e
+            );
+            req.done(IDE_STATUS_ERR, error);
+        }
+    }
+
+    fn rw(&self, req: &Arc<AhciRequest>, write: bool, lba: u64, count: u64) {
+        if !self.check_range(lba, count) {
+            req.done(IDE_STATUS_ERR, ATA_ERROR_IDNF);
+            return;
+        }
+        if write && self.config.drive.read_only {
+            abort(req);
+            return;
+        }
+        let iovecs = match req.map_iovecs(count << ATA_SECTOR_SHIFT) {
+            Some(iovecs) => iovecs,
+            None => {
+                abort(req);
+                return;
+            }
+        };
+        let offset = (lba << ATA_SECTOR_SHIFT) as usize;
+        let bytes = get_iov_size(&iovecs);
+        req.set_bytes(bytes as u32);
+        if write {
+            self.submit(
+                req,
+                BlockAcctType::Write,
+                bytes,
+                ATA_ERROR_ABRT,
+                |backend, cb| backend.write_vectored(iovecs, offset, cb),
+            );
+        } else {
+            self.submit(
+                req,
+                BlockAcctType::Read,
+                bytes,
+                ATA_ERROR_UNC,
+                |backend, cb| backend.read_vectored(iovecs, offset, cb),
+            );
+        }
+    }
+
+    fn verify(&self, req: &Arc<AhciRequest>, lba: u64, count: u64) {
+        if !self.check_range(lba, count) {
+            req.done(IDE_STATUS_ERR, ATA_ERROR_IDNF);
+            return;
+        }
+        req.done(IDE_STATUS_OK, 0);
+    }
+
+    fn flush(&self, req: &Arc<AhciRequest>) {
+        self.submit(
+            req,
+            BlockAcctType::Flush,
+            0,
+            ATA_ERROR_ABRT,
+            |backend, cb| backend.datasync(cb),
+        );
+    }
+
+    /// Log Directory and NCQ Command Error log are supported, refer to A.2 and A.14
+    /// of ATA8-ACS.
+    fn read_log(&self, req: &Arc<AhciRequest>, tf: &AtaTaskFile) {
+        let log = tf.lba & 0xff;
+        let page = (tf.lba >> 8) & 0xffff;
+        let pages = tf.count as usize;
+        if pages == 0 || page != 0 {
+            abort(req);
+            return;
+        }
+        let mut data = vec![0_u8; pages * ATA_LOG_PAGE_SIZE];
+        match log {
+            ATA_LOG_DIRECTORY => {
+                put_word(&mut data, 0, 0x0001);
+                put_word(&mut data, ATA_LOG_NCQ_COMMAND_ERROR as usize, 1);
+            }
+            ATA_LOG_NCQ_COMMAND_ERROR => {
+                match req.take_ncq_error() {
+                    Some(ncq_error) => {
+                        data[0] = ncq_error.tag & 0x1f;
+                        data[2] = ncq_error.status;
+                        data[3] = ncq_error.error;
+                    }
+                    // The last error is not for an NCQ command.
+                    None => data[0] = 0x80,
+                }
+                let sum = data[..ATA_LOG_PAGE_SIZE - 1]
+                    .iter()
+                    .fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+                data[ATA_LOG_PAGE_SIZE - 1] = 0_u8.wrapping_sub(sum);
+            }
+            _ => {
+                abort(req);
+                return;
+            }
+        }
+        transfer_data_in(req, &data);
+    }
+
+    fn flush_request(&self) {
+        if let Some(block_backend) = self.block_backend.as_ref() {
+            if let Err(e) = block_backend.lock().unwrap().flush_request() {
+                error!(
+                    "Failed to flush requests of ide disk {}: {:?}",
+                    self.config.id, e
+                );
+            }
+        }
+    }
+}
+
+/// The upper request of the packet command.
+struct AtapiRequest {
+    req: Arc<AhciRequest>,
+    sense: Arc<Mutex<ScsiSense>>,
+}
+
+impl ScsiRequestOps for AtapiRequest {
+    fn scsi_request_complete_cb(&mut self, status: u8, scsisense: Option<ScsiSense>) -> Result<()> {
+        if status == GOOD {
+            *self.sense.lock().unwrap() = ScsiSense::default();
+            self.req.done(IDE_STATUS_OK, 0);
+            return Ok(());
+        }
+        // The sense key is reported in the Error register.
+        let sense = scsisense.unwrap_or(SCSI_SENSE_IO_ERROR);
+        let error = sense.key << 4;
+        *self.sense.lock().unwrap() = sense;
+        self.req.done(IDE_STATUS_ERR, error);
+        Ok(())
+    }
+}
+
+/// ATAPI CD-ROM, the packet commands are emulated by the scsi CD-ROM.
+pub struct IdeCd {
+    config: IdeDevConfig,
+    scsi_bus: Arc<Mutex<ScsiBus>>,
+    scsi_dev: Arc<Mutex<ScsiDevice>>,
+    /// Sense data of the last packet command, reported by REQUEST SENSE.
+    sense: Arc<Mutex<ScsiSense>>,
+}
+
+impl IdeCd {
+    pub fn new(config: IdeDevConfig, drive_files: Arc<Mutex<HashMap<String, DriveFile>>>) -> Self {
+        let drive = &config.drive;
+        // The requests of the CD-ROM are handled synchronously like usb-storage.
+        let scsi_cfg = ScsiDevConfig {
+            id: config.id.clone(),
+            path_on_host: drive.path_on_host.clone(),
+            serial: config.serial.clone(),
+            cntlr: config.cntlr.clone(),
+            read_only: true,
+            direct: drive.direct,
+            aio_type: AioEngine::Off,
+            format: drive.format,
+            boot_index: config.boot_index,
+            ..Default::default()
+        };
+        Self {
+            config,
+            scsi_bus: Arc::new(Mutex::new(ScsiBus::new("".to_string()))),
+            scsi_dev: Arc::new(Mutex::new(ScsiDevice::new(
+                scsi_cfg,
+                SCSI_TYPE_ROM,
+                drive_files,
+            ))),
+            sense: Arc::new(Mutex::new(ScsiSense::default())),
+        }
+    }
+
+    pub fn realize(&mut self) -> Result<()> {
+        let mut locked_scsi_dev = self.scsi_dev.lock().unwrap();
+        locked_scsi_dev
+            .realize(None)
+            .with_context(|| format!("Ide-cd {}: scsi device realize error!", self.config.id))?;
+        locked_scsi_dev.parent_bus = Arc::downgrade(&self.scsi_bus);
+        drop(locked_scsi_dev);
+        self.scsi_bus
+            .lock()
+            .unwrap()
+            .devices
+            .insert((0, 0), self.scsi_dev.clone());
+        Ok(())
+    }
+
+    fn execute(
+        &mut self,
+        req: &Arc<AhciRequest>,
+        tf: &AtaTaskFile,
+        acmd: &[u8; AHCI_ATAPI_CMD_SIZE],
+    ) {
+        match tf.command {
+            ATA_CMD_PACKET => self.packet(req, acmd),
+            ATA_CMD_IDENTIFY_PACKET_DEVICE => transfer_data_in(req, &self.identify()),
+            ATA_CMD_DEVICE_RESET => {
+                *self.sense.lock().unwrap() = ScsiSense::default();
+                req.done(IDE_STATUS_OK, 0x01);
+            }
+            ATA_CMD_NOP | ATA_CMD_IDENTIFY_DEVICE => abort(req),
+            _ => {
+                warn!(
+                    "Unsupported command 0x{:x} of ide cd {}",
+                    tf.command, self.config.id
+                );
+                abort(req);
+            }
+        }
+    }
+
+    /// IDENTIFY PACKET DEVICE data, refer to 7.17.7 of ATA8-ACS.
+    fn identify(&self) -> Vec<u8> {
+        let mut data = vec![0_u8; ATA_IDENTIFY_DATA_SIZE];
+        // ATAPI removable CD-ROM device, 12 bytes command packet.
+        put_word(&mut data, 0, 0x85c0);
+        let serial = self.config.serial.as_ref().unwrap_or(&self.config.id);
+        put_ata_string(&mut data[20..40], serial);
+        put_ata_string(&mut data[46..54], IDE_FIRMWARE_REVISION);
+        put_ata_string(&mut data[54..94], IDE_CD_MODEL);
+        put_word(&mut data, 49, 0x0300);
+        put_word(&mut data, 53, 0x0006);
+        put_word(&mut data, 63, 0x0007);
+        put_word(&mut data, 64, 0x0003);
+        for word in 65..=68 {
+            put_word(&mut data, word, 120);
+        }
+        // SATA Gen1 and Gen2 speed are supported.
+        put_word(&mut data, 76, 0x0006);
+        put_word(&mut data, 80, 0x01f0);
+        // PACKET and NOP are supported.
+        put_word(&mut data, 82, 0x4010);
+        put_word(&mut data, 83, 0x4000);
+        put_word(&mut data, 84, 0x4000);
+        put_word(&mut data, 85, 0x4010);
+        put_word(&mut data, 87, 0x4000);
+        put_word(&mut data, 88, 0x203f);
+        put_checksum(&mut data);
+        data
+    }
+
+    fn request_sense(&self, req: &Arc<AhciRequest>, alloc_len: u8) {
+        let sense = std::mem::take(&mut *self.sense.lock().unwrap());
+        let mut data = [0_u8; ATAPI_SENSE_DATA_SIZE];
+        // Current error, fixed format sense data.
+        data[0] = 0x70;
+        data[2] = sense.key;
+        data[7] = (ATAPI_SENSE_DATA_SIZE - 8) as u8;
+        data[12] = sense.asc;
+        data[13] = sense.ascq;
+        let len = min(alloc_len as usize, ATAPI_SENSE_DATA_SIZE);
+        transfer_data_in(req, &data[..len]);
+    }
+
+    fn packet(&mut self, req: &Arc<AhciRequest>, acmd: &[u8; AHCI_ATAPI_CMD_SIZE]) {
+        let mut cdb = [0_u8; SCSI_CMD_BUF_SIZE];
+        cdb[..ATAPI_PACKET_SIZE].copy_from_slice(&acmd[..ATAPI_PACKET_SIZE]);
+        // The scsi device doesn't keep the sense data, so it's reported here.
+        if cdb[0] == REQUEST_SENSE {
+            self.request_sense(req, cdb[4]);
+            return;
+        }
+
+        let sgl_size = min(req.sgl_size(), u32::MAX as u64) as u32;
+        let upper_req = Box::new(AtapiRequest {
+            req: req.clone(),
+            sense: self.sense.clone(),
+        });
+        let mut sreq = match ScsiRequest::new(
+            cdb,
+            0,
+            Vec::new(),
+            sgl_size,
+            self.scsi_dev.clone(),
+            upper_req,
+        ) {
+            Ok(sreq) => sreq,
+            Err(e) => {
+                warn!(
+                    "Invalid packet command 0x{:x} of ide cd {}: {:?}",
+                    cdb[0], self.config.id, e
+                );
+                // Group 3, 6 and 7 commands are reserved or vendor specific.
+                let sense = if matches!(cdb[0] >> 5, 3 | 6 | 7) {
+                    SCSI_SENSE_INVALID_OPCODE
+                } else {
+                    SCSI_SENSE_LBA_OUT_OF_RANGE
+                };
+                let error = sense.key << 4;
+                *self.sense.lock().unwrap() = sense;
+                req.done(IDE_STATUS_ERR, error);
+                return;
+            }
+        };
+
+        let len = match sreq.cmd.mode {
+            ScsiXferMode::ScsiXferNone => 0,
+            _ => min(sreq.cmd.xfer, sgl_size),
+        };
+        sreq.iovec = match req.map_iovecs(len as u64) {
+            Some(iovecs) => iovecs,
+            None => {
+                abort(req);
+                return;
+            }
+        };
+        sreq.datalen = len;
+        req.set_bytes(len);
+
+        let ret = match sreq.opstype {
+            EMULATE_SCSI_OPS => sreq.emulate_execute(),
+            _ => sreq.execute(),
+        };
+        if let Err(e) = ret {
+            error!(
+                "Failed to execute packet command 0x{:x} of ide cd {}: {:?}",
+                cdb[0], self.config.id, e
+            );
+            abort(req);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Emulated ICH9 AHCI controller, refer to Serial ATA AHCI 1.3.1 and ATA8-ACS.
+
+pub mod ahci_ctrl;
+pub mod ahci_pci;
+pub mod ide;
+
+pub use ahci_pci::AhciPciDevice;
+
+use util::byte_code::ByteCode;
+
+/// 3.1 Generic Host Control.
+pub const AHCI_REG_CAP: u64 = 0x00;
+pub const AHCI_REG_GHC: u64 = 0x04;
+pub const AHCI_REG_IS: u64 = 0x08;
+pub const AHCI_REG_PI: u64 = 0x0c;
+pub const AHCI_REG_VS: u64 = 0x10;
+pub const AHCI_REG_CAP2: u64 = 0x24;
+/// The port registers start at 0x100, and each port has 0x80 bytes.
+pub const AHCI_PORT_REG_BASE: u64 = 0x100;
+pub const AHCI_PORT_REG_SIZE: u64 = 0x80;
+
+/// 3.3 Port Registers.
+pub const AHCI_PORT_CLB: u64 = 0x00;
+pub const AHCI_PORT_CLBU: u64 = 0x04;
+pub const AHCI_PORT_FB: u64 = 0x08;
+pub const AHCI_PORT_FBU: u64 = 0x0c;
+pub const AHCI_PORT_IS: u64 = 0x10;
+pub const AHCI_PORT_IE: u64 = 0x14;
+pub const AHCI_PORT_CMD: u64 = 0x18;
+pub const AHCI_PORT_TFD: u64 = 0x20;
+pub const AHCI_PORT_SIG: u64 = 0x24;
+pub const AHCI_PORT_SSTS: u64 = 0x28;
+pub const AHCI_PORT_SCTL: u64 = 0x2c;
+pub const AHCI_PORT_SERR: u64 = 0x30;
+pub const AHCI_PORT_SACT: u64 = 0x34;
+pub const AHCI_PORT_CI: u64 = 0x38;
+
+/// Global HBA Control.
+pub const AHCI_GHC_HR: u32 = 1 << 0;
+pub const AHCI_GHC_IE: u32 = 1 << 1;
+pub const AHCI_GHC_AE: u32 = 1 << 31;
+
+/// Port Interrupt Status.
+pub const AHCI_PORT_IS_DHRS: u32 = 1 << 0;
+pub const AHCI_PORT_IS_PSS: u32 = 1 << 1;
+pub const AHCI_PORT_IS_SDBS: u32 = 1 << 3;
+pub const AHCI_PORT_IS_TFES: u32 = 1 << 30;
+
+/// Port Command and Status.
+pub const AHCI_PORT_CMD_ST: u32 = 1 << 0;
+pub const AHCI_PORT_CMD_SUD: u32 = 1 << 1;
+pub const AHCI_PORT_CMD_POD: u32 = 1 << 2;
+pub const AHCI_PORT_CMD_CLO: u32 = 1 << 3;
+pub const AHCI_PORT_CMD_FRE: u32 = 1 << 4;
+pub const AHCI_PORT_CMD_FR: u32 = 1 << 14;
+pub const AHCI_PORT_CMD_CR: u32 = 1 << 15;
+pub const AHCI_PORT_CMD_ATAPI: u32 = 1 << 24;
+
+/// Device detected and phy communication established, Gen1 speed, interface active.
+pub const AHCI_PORT_SSTS_ONLINE: u32 = 0x113;
+/// Device Detection Initialization of Serial ATA Control.
+pub const AHCI_PORT_SCTL_DET_MASK: u32 = 0xf;
+pub const AHCI_PORT_SCTL_DET_INIT: u32 = 0x1;
+
+/// Signatures reported by the device after reset.
+pub const AHCI_SIG_ATA: u32 = 0x0000_0101;
+pub const AHCI_SIG_ATAPI: u32 = 0xeb14_0101;
+
+/// Number of command slots of each port, which is also the NCQ queue depth.
+pub const AHCI_MAX_CMDS: usize = 32;
+/// AHCI 1.3.
+pub const AHCI_VERSION: u32 = 0x0001_0300;
+
+/// 4.2.1 Received FIS Structure.
+pub const AHCI_RX_FIS_PIO_SETUP: u64 = 0x20;
+pub const AHCI_RX_FIS_D2H: u64 = 0x40;
+pub const AHCI_RX_FIS_SDB: u64 = 0x58;
+
+/// 4.2.3 Command Table.
+pub const AHCI_CMD_TBL_CFIS: u64 = 0x00;
+pub const AHCI_CMD_TBL_ACMD: u64 = 0x40;
+pub const AHCI_CMD_TBL_PRDT: u64 = 0x80;
+pub const AHCI_CMD_HDR_SIZE: u64 = 32;
+pub const AHCI_CMD_FIS_SIZE: usize = 20;
+pub const AHCI_ATAPI_CMD_SIZE: usize = 16;
+
+/// Flags of Command Header.
+pub const AHCI_CMD_HDR_ATAPI: u16 = 1 << 5;
+
+/// 10.3 FIS Types of Serial ATA Revision 3.0.
+pub const SATA_FIS_TYPE_REG_H2D: u8 = 0x27;
+pub const SATA_FIS_TYPE_REG_D2H: u8 = 0x34;
+pub const SATA_FIS_TYPE_SDB: u8 = 0xa1;
+pub const SATA_FIS_TYPE_PIO_SETUP: u8 = 0x5f;
+/// The Register Host to Device FIS updates the Command register.
+pub const SATA_FIS_REG_H2D_C: u8 = 1 << 7;
+/// The Interrupt bit of the Device to Host FISes.
+pub const SATA_FIS_I: u8 = 1 << 6;
+/// Software Reset bit of the Device Control register.
+pub const ATA_DEVCTL_SRST: u8 = 1 << 2;
+
+/// Status register.
+pub const ATA_STATUS_ERR: u8 = 1 << 0;
+pub const ATA_STATUS_DRQ: u8 = 1 << 3;
+pub const ATA_STATUS_DSC: u8 = 1 << 4;
+pub const ATA_STATUS_DF: u8 = 1 << 5;
+pub const ATA_STATUS_DRDY: u8 = 1 << 6;
+pub const ATA_STATUS_BSY: u8 = 1 << 7;
+/// Error register.
+pub const ATA_ERROR_ABRT: u8 = 1 << 2;
+pub const ATA_ERROR_IDNF: u8 = 1 << 4;
+pub const ATA_ERROR_UNC: u8 = 1 << 6;
+
+/// 7 Command Descriptions of ATA8-ACS.
+pub const ATA_CMD_NOP: u8 = 0x00;
+pub const ATA_CMD_DEVICE_RESET: u8 = 0x08;
+pub const ATA_CMD_RECALIBRATE: u8 = 0x10;
+pub const ATA_CMD_READ_SECTORS: u8 = 0x20;
+pub const ATA_CMD_READ_SECTORS_EXT: u8 = 0x24;
+pub const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+pub const ATA_CMD_READ_LOG_EXT: u8 = 0x2f;
+pub const ATA_CMD_WRITE_SECTORS: u8 = 0x30;
+pub const ATA_CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+pub const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+pub const ATA_CMD_READ_VERIFY: u8 = 0x40;
+pub const ATA_CMD_READ_VERIFY_EXT: u8 = 0x42;
+pub const ATA_CMD_READ_LOG_DMA_EXT: u8 = 0x47;
+pub const ATA_CMD_READ_FPDMA_QUEUED: u8 = 0x60;
+pub const ATA_CMD_WRITE_FPDMA_QUEUED: u8 = 0x61;
+pub const ATA_CMD_EXECUTE_DEVICE_DIAGNOSTIC: u8 = 0x90;
+pub const ATA_CMD_INITIALIZE_DEVICE_PARAMETERS: u8 = 0x91;
+pub const ATA_CMD_PACKET: u8 = 0xa0;
+pub const ATA_CMD_IDENTIFY_PACKET_DEVICE: u8 = 0xa1;
+pub const ATA_CMD_SET_MULTIPLE_MODE: u8 = 0xc6;
+pub const ATA_CMD_READ_DMA: u8 = 0xc8;
+pub const ATA_CMD_WRITE_DMA: u8 = 0xca;
+pub const ATA_CMD_STANDBY_IMMEDIATE: u8 = 0xe0;
+pub const ATA_CMD_IDLE_IMMEDIATE: u8 = 0xe1;
+pub const ATA_CMD_STANDBY: u8 = 0xe2;
+pub const ATA_CMD_IDLE: u8 = 0xe3;
+pub const ATA_CMD_CHECK_POWER_MODE: u8 = 0xe5;
+pub const ATA_CMD_SLEEP: u8 = 0xe6;
+pub const ATA_CMD_FLUSH_CACHE: u8 = 0xe7;
+pub const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xea;
+pub const ATA_CMD_IDENTIFY_DEVICE: u8 = 0xec;
+pub const ATA_CMD_SET_FEATURES: u8 = 0xef;
+
+/// The logical sector size is 512 bytes.
+pub const ATA_SECTOR_SHIFT: u64 = 9;
+/// Size of the data returned by IDENTIFY DEVICE and IDENTIFY PACKET DEVICE.
+pub const ATA_IDENTIFY_DATA_SIZE: usize = 512;
+
+/// 4.2.2 Command Header.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AhciCmdHeader {
+    /// Command FIS length in dwords(bits 4:0) and the flags.
+    pub flags: u16,
+    /// Physical Region Descriptor Table Length.
+    pub prdtl: u16,
+    /// Physical Region Descriptor Byte Count.
+    pub prdbc: u32,
+    /// Command Table Base Address.
+    pub ctba: u64,
+    pub rsvd: [u32; 4],
+}
+
+impl ByteCode for AhciCmdHeader {}
+
+/// 4.2.3.3 Physical Region Descriptor Table.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AhciPrd {
+    /// Data Base Address.
+    pub dba: u64,
+    pub rsvd: u32,
+    /// Data Byte Count(bits 21:0, zero based) and Interrupt on Completion(bit 31).
+    pub dbc: u32,
+}
+
+impl ByteCode for AhciPrd {}
+
+/// The registers of the ATA device written by the Register Host to Device FIS.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AtaTaskFile {
+    pub command: u8,
+    pub features: u16,
+    pub lba: u64,
+    pub device: u8,
+    pub count: u16,
+    pub control: u8,
+}
+
+impl AtaTaskFile {
+    /// Parse the Register Host to Device FIS.
+    pub fn from_fis(fis: &[u8; AHCI_CMD_FIS_SIZE]) -> Self {
+        Self {
+            command: fis[2],
+            features: u16::from(fis[3]) | u16::from(fis[11]) << 8,
+            lba: u64::from(fis[4])
+                | u64::from(fis[5]) << 8
+                | u64::from(fis[6]) << 16
+                | u64::from(fis[8]) << 24
+                | u64::from(fis[9]) << 32
+                | u64::from(fis[10]) << 40,
+            device: fis[7],
+            count: u16::from(fis[12]) | u16::from(fis[13]) << 8,
+            control: fis[15],
+        }
+    }
+
+    /// LBA of the 28-bit commands, bits 27:24 are in the Device register.
+    pub fn lba28(&self) -> u64 {
+        (self.lba & 0xff_ffff) | u64::from(self.device & 0xf) << 24
+    }
+
+    /// Sector count of the 28-bit commands, 0 means 256 sectors.
+    pub fn count28(&self) -> u64 {
+        match self.count & 0xff {
+            0 => 256,
+            count => u64::from(count),
+        }
+    }
+
+    /// Sector count of the 48-bit commands, 0 means 65536 sectors.
+    pub fn count48(&self) -> u64 {
+        match self.count {
+            0 => 65536,
+            count => u64::from(count),
+        }
+    }
+
+    /// Sector count of the NCQ commands which is in the Features register.
+    pub fn ncq_count(&self) -> u64 {
+        match self.features {
+            0 => 65536,
+            count => u64::from(count),
+        }
+    }
+
+    /// Tag of the NCQ commands, which is in bits 7:3 of the Count register.
+    pub fn ncq_tag(&self) -> u8 {
+        ((self.count >> 3) & 0x1f) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use super::*;
+
+    #[test]
+    fn test_ahci_task_file() {
+        assert_eq!(size_of::<AhciCmdHeader>() as u64, AHCI_CMD_HDR_SIZE);
+        assert_eq!(size_of::<AhciPrd>(), 16);
+
+        // READ FPDMA QUEUED, tag 5, 8 sectors from LBA 0x0102030405.
+        let fis: [u8; AHCI_CMD_FIS_SIZE] = [
+            SATA_FIS_TYPE_REG_H2D,
+            SATA_FIS_REG_H2D_C,
+            ATA_CMD_READ_FPDMA_QUEUED,
+            8,
+            0x05,
+            0x04,
+            0x03,
+            0x40,
+            0x02,
+            0x01,
+            0,
+            0,
+            5 << 3,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        let tf = AtaTaskFile::from_fis(&fis);
+        assert_eq!(tf.command, ATA_CMD_READ_FPDMA_QUEUED);
+        assert_eq!(tf.lba, 0x01_0203_0405);
+        assert_eq!(tf.ncq_count(), 8);
+        assert_eq!(tf.ncq_tag(), 5);
+
+        // READ DMA, 0 sectors means 256 sectors, LBA bits 27:24 are in the Device register.
+        let mut fis = [0_u8; AHCI_CMD_FIS_SIZE];
+        fis[2] = ATA_CMD_READ_DMA;
+        fis[4] = 0x10;
+        fis[7] = 0x40 | 0x0a;
+        let tf = AtaTaskFile::from_fis(&fis);
+        assert_eq!(tf.lba28(), 0x0a00_0010);
+        assert_eq!(tf.count28(), 256);
+        assert_eq!(tf.count48(), 65536);
+    }
+}
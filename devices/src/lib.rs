@@ -17,10 +17,12 @@
 //! - legacy devices, such as serial devices
 
 pub mod acpi;
+pub mod ahci;
 pub mod camera_backend;
 mod interrupt_controller;
 pub mod legacy;
 pub mod misc;
+pub mod nvme;
 pub mod scsi;
 pub mod usb;
 
@@ -32,3 +34,4 @@ pub use interrupt_controller::{
 pub use legacy::error::LegacyError as LegacyErrs;
 pub use scsi::bus as ScsiBus;
 pub use scsi::disk as ScsiDisk;
+pub use scsi::generic as ScsiGeneric;
@@ -0,0 +1,175 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Emulated NVMe controller, refer to NVM Express Base Specification 1.4.
+
+pub mod nvme_ctrl;
+pub mod nvme_ns;
+pub mod nvme_pci;
+
+pub use nvme_pci::NvmePciDevice;
+
+use util::byte_code::ByteCode;
+
+/// 3.1 Controller Registers.
+pub const NVME_REG_CAP: u64 = 0x00;
+pub const NVME_REG_VS: u64 = 0x08;
+pub const NVME_REG_INTMS: u64 = 0x0c;
+pub const NVME_REG_INTMC: u64 = 0x10;
+pub const NVME_REG_CC: u64 = 0x14;
+pub const NVME_REG_CSTS: u64 = 0x1c;
+pub const NVME_REG_NSSR: u64 = 0x20;
+pub const NVME_REG_AQA: u64 = 0x24;
+pub const NVME_REG_ASQ: u64 = 0x28;
+pub const NVME_REG_ACQ: u64 = 0x30;
+/// The doorbells start at 0x1000, the stride is 4 bytes as CAP.DSTRD is 0.
+pub const NVME_REG_DBS: u64 = 0x1000;
+
+/// Controller Configuration.
+pub const NVME_CC_EN: u32 = 1 << 0;
+pub const NVME_CC_CSS_SHIFT: u32 = 4;
+pub const NVME_CC_CSS_MASK: u32 = 0x7;
+pub const NVME_CC_MPS_SHIFT: u32 = 7;
+pub const NVME_CC_MPS_MASK: u32 = 0xf;
+pub const NVME_CC_SHN_SHIFT: u32 = 14;
+pub const NVME_CC_SHN_MASK: u32 = 0x3;
+/// Controller Status.
+pub const NVME_CSTS_RDY: u32 = 1 << 0;
+pub const NVME_CSTS_CFS: u32 = 1 << 1;
+pub const NVME_CSTS_SHST_COMPLETE: u32 = 2 << 2;
+
+/// Version 1.4.0.
+pub const NVME_VERSION: u32 = 0x0001_0400;
+/// Memory page size, only 4KiB is supported.
+pub const NVME_PAGE_SIZE: u64 = 4096;
+/// Max entries of a queue, CAP.MQES is zero based.
+pub const NVME_MAX_QUEUE_ENTRIES: u32 = 2048;
+/// Size of the submission queue entry.
+pub const NVME_SQE_SIZE: u64 = 64;
+/// Size of the completion queue entry.
+pub const NVME_CQE_SIZE: u64 = 16;
+/// Max data transfer size in unit of the memory page size, 2^7 * 4KiB.
+pub const NVME_MDTS: u8 = 7;
+/// Max outstanding asynchronous event requests, zero based.
+pub const NVME_AERL: u8 = 3;
+/// The logical block size is 512 bytes.
+pub const NVME_LBA_SHIFT: u64 = 9;
+/// The NSID which applies to all namespaces.
+pub const NVME_NSID_BROADCAST: u32 = 0xffff_ffff;
+/// Size of the data structure returned by Identify.
+pub const NVME_IDENTIFY_DATA_SIZE: usize = 4096;
+
+/// 5 Admin Command Set.
+pub const NVME_ADM_CMD_DELETE_SQ: u8 = 0x00;
+pub const NVME_ADM_CMD_CREATE_SQ: u8 = 0x01;
+pub const NVME_ADM_CMD_GET_LOG_PAGE: u8 = 0x02;
+pub const NVME_ADM_CMD_DELETE_CQ: u8 = 0x04;
+pub const NVME_ADM_CMD_CREATE_CQ: u8 = 0x05;
+pub const NVME_ADM_CMD_IDENTIFY: u8 = 0x06;
+pub const NVME_ADM_CMD_ABORT: u8 = 0x08;
+pub const NVME_ADM_CMD_SET_FEATURES: u8 = 0x09;
+pub const NVME_ADM_CMD_GET_FEATURES: u8 = 0x0a;
+pub const NVME_ADM_CMD_ASYNC_EV_REQ: u8 = 0x0c;
+
+/// 6 NVM Command Set.
+pub const NVME_CMD_FLUSH: u8 = 0x00;
+pub const NVME_CMD_WRITE: u8 = 0x01;
+pub const NVME_CMD_READ: u8 = 0x02;
+pub const NVME_CMD_WRITE_ZEROES: u8 = 0x08;
+pub const NVME_CMD_DSM: u8 = 0x09;
+
+/// 4.6.1 Status Field, which is composed of SCT(bits 10:8) and SC(bits 7:0).
+/// Generic Command Status.
+pub const NVME_SUCCESS: u16 = 0x0000;
+pub const NVME_INVALID_OPCODE: u16 = 0x0001;
+pub const NVME_INVALID_FIELD: u16 = 0x0002;
+pub const NVME_DATA_TRANSFER_ERROR: u16 = 0x0004;
+pub const NVME_INTERNAL_DEV_ERROR: u16 = 0x0006;
+pub const NVME_INVALID_NSID: u16 = 0x000b;
+pub const NVME_LBA_RANGE: u16 = 0x0080;
+/// Command Specific Status.
+pub const NVME_INVALID_CQID: u16 = 0x0100;
+pub const NVME_INVALID_QID: u16 = 0x0101;
+pub const NVME_MAX_QSIZE_EXCEEDED: u16 = 0x0102;
+pub const NVME_AER_LIMIT_EXCEEDED: u16 = 0x0105;
+pub const NVME_INVALID_IRQ_VECTOR: u16 = 0x0108;
+pub const NVME_INVALID_LOG_ID: u16 = 0x0109;
+pub const NVME_INVALID_QUEUE_DEL: u16 = 0x010c;
+pub const NVME_FEAT_NOT_SAVEABLE: u16 = 0x010d;
+pub const NVME_WRITE_TO_RO: u16 = 0x0182;
+/// Media and Data Integrity Errors.
+pub const NVME_WRITE_FAULT: u16 = 0x0280;
+pub const NVME_UNRECOVERED_READ: u16 = 0x0281;
+/// Do Not Retry.
+pub const NVME_DNR: u16 = 0x4000;
+
+/// Submission Queue Entry.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NvmeSqe {
+    pub opcode: u8,
+    pub flags: u8,
+    pub cid: u16,
+    pub nsid: u32,
+    pub res: u64,
+    pub mptr: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+impl ByteCode for NvmeSqe {}
+
+/// Completion Queue Entry.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NvmeCqe {
+    pub result: u32,
+    pub rsvd: u32,
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub cid: u16,
+    /// Status field(bits 15:1) and phase tag(bit 0).
+    pub status: u16,
+}
+
+impl ByteCode for NvmeCqe {}
+
+/// Range of the Dataset Management command.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NvmeDsmRange {
+    pub attributes: u32,
+    pub nlb: u32,
+    pub slba: u64,
+}
+
+impl ByteCode for NvmeDsmRange {}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use super::*;
+
+    #[test]
+    fn test_nvme_entry_size() {
+        assert_eq!(size_of::<NvmeSqe>() as u64, NVME_SQE_SIZE);
+        assert_eq!(size_of::<NvmeCqe>() as u64, NVME_CQE_SIZE);
+        assert_eq!(size_of::<NvmeDsmRange>(), 16);
+    }
+}
@@ -0,0 +1,1215 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp::min;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use log::{error, warn};
+
+use super::nvme_ns::NvmeNamespace;
+use super::*;
+use address_space::{AddressSpace, GuestAddress, RegionOps};
+use block_backend::BlockIoErrorCallback;
+use machine_manager::config::{DriveFile, NvmeConfig, NvmeNsConfig, NVME_MAX_NAMESPACES};
+use pci::config::PCI_VENDOR_ID_REDHAT;
+use pci::intx::Intx;
+use pci::msix::Msix;
+use util::aio::Iovec;
+use util::byte_code::ByteCode;
+use util::num_ops::{read_data_u32, read_u32, write_data_u32, write_u64_high, write_u64_low};
+
+/// 5.21.1 Feature Identifiers.
+const NVME_FEAT_ARBITRATION: u32 = 0x01;
+const NVME_FEAT_POWER_MGMT: u32 = 0x02;
+const NVME_FEAT_TEMP_THRESH: u32 = 0x04;
+const NVME_FEAT_ERR_RECOVERY: u32 = 0x05;
+const NVME_FEAT_VOLATILE_WC: u32 = 0x06;
+const NVME_FEAT_NUM_QUEUES: u32 = 0x07;
+const NVME_FEAT_INT_COALESCING: u32 = 0x08;
+const NVME_FEAT_INT_VECTOR_CONFIG: u32 = 0x09;
+const NVME_FEAT_WRITE_ATOMICITY: u32 = 0x0a;
+const NVME_FEAT_ASYNC_EVENT_CONFIG: u32 = 0x0b;
+/// The features are changeable but not saveable, reported by Get Features with select 3.
+const NVME_FEAT_CAP_CHANGEABLE: u32 = 1 << 2;
+
+/// 5.14.1 Log Page Identifiers.
+const NVME_LOG_ERROR_INFO: u32 = 0x01;
+const NVME_LOG_SMART_INFO: u32 = 0x02;
+const NVME_LOG_FW_SLOT_INFO: u32 = 0x03;
+const NVME_LOG_ERROR_INFO_SIZE: usize = 64;
+const NVME_LOG_SMART_INFO_SIZE: usize = 512;
+const NVME_LOG_FW_SLOT_INFO_SIZE: usize = 512;
+
+/// 5.15.1 Controller or Namespace Structure.
+const NVME_ID_CNS_NS: u32 = 0x00;
+const NVME_ID_CNS_CTRL: u32 = 0x01;
+const NVME_ID_CNS_NS_ACTIVE_LIST: u32 = 0x02;
+const NVME_ID_CNS_NS_DESC_LIST: u32 = 0x03;
+
+/// Composite temperature reported by SMART log in Kelvin, 50 degrees Celsius.
+const NVME_TEMPERATURE: u16 = 0x0143;
+/// Default over temperature threshold in Kelvin, 70 degrees Celsius.
+const NVME_TEMPERATURE_WARNING: u16 = 0x0157;
+/// Critical temperature threshold in Kelvin, 100 degrees Celsius.
+const NVME_TEMPERATURE_CRITICAL: u16 = 0x0175;
+/// PCI subsystem vendor id reported by Identify Controller.
+const NVME_SUBSYSTEM_VENDOR_ID: u16 = 0x1af4;
+const NVME_MODEL_NUMBER: &str = "StratoVirt NVMe Ctrl";
+const NVME_FIRMWARE_REVISION: &str = "1.0";
+
+type NvmeResult<T> = std::result::Result<T, u16>;
+
+/// Interrupts of the completion queues, MSI-X is preferred and the pin-based
+/// interrupt is asserted as long as any completion queue has unconsumed entries.
+pub struct NvmeIrq {
+    msix: Arc<Mutex<Msix>>,
+    intx: Arc<Mutex<Intx>>,
+    dev_id: Arc<AtomicU16>,
+    /// Completion queues which keep the pin-based interrupt asserted.
+    intx_pending: Mutex<HashSet<u16>>,
+    /// The pin-based interrupt is masked by INTMS.
+    intx_masked: AtomicBool,
+}
+
+impl NvmeIrq {
+    pub fn new(msix: Arc<Mutex<Msix>>, intx: Arc<Mutex<Intx>>, dev_id: Arc<AtomicU16>) -> Self {
+        Self {
+            msix,
+            intx,
+            dev_id,
+            intx_pending: Mutex::new(HashSet::new()),
+            intx_masked: AtomicBool::new(false),
+        }
+    }
+
+    fn assert(&self, cqid: u16, vector: u16) {
+        let mut locked_msix = self.msix.lock().unwrap();
+        if locked_msix.enabled {
+            locked_msix.notify(vector, self.dev_id.load(Ordering::Acquire));
+            return;
+        }
+        drop(locked_msix);
+
+        let mut pending = self.intx_pending.lock().unwrap();
+        pending.insert(cqid);
+        self.update_intx(&pending);
+    }
+
+    fn deassert(&self, cqid: u16) {
+        let mut pending = self.intx_pending.lock().unwrap();
+        if pending.remove(&cqid) {
+            self.update_intx(&pending);
+        }
+    }
+
+    fn set_intx_mask(&self, masked: bool) {
+        self.intx_masked.store(masked, Ordering::SeqCst);
+        let pending = self.intx_pending.lock().unwrap();
+        self.update_intx(&pending);
+    }
+
+    fn update_intx(&self, pending: &HashSet<u16>) {
+        let level = !pending.is_empty() && !self.intx_masked.load(Ordering::SeqCst);
+        self.intx.lock().unwrap().notify(level as u8);
+    }
+}
+
+/// Completion queue, it's shared with the in-flight requests.
+pub struct NvmeCq {
+    cqid: u16,
+    dma_addr: u64,
+    size: u32,
+    head: u32,
+    tail: u32,
+    phase: bool,
+    vector: u16,
+    irq_enabled: bool,
+    /// Completions waiting for the free entries of the queue.
+    pending: VecDeque<NvmeCqe>,
+    /// The queue is deleted or the controller is reset, the late completions are dropped.
+    disabled: bool,
+    mem_space: Arc<AddressSpace>,
+    irq: Arc<NvmeIrq>,
+}
+
+impl NvmeCq {
+    fn new(
+        cqid: u16,
+        dma_addr: u64,
+        size: u32,
+        vector: u16,
+        irq_enabled: bool,
+        mem_space: Arc<AddressSpace>,
+        irq: Arc<NvmeIrq>,
+    ) -> Self {
+        Self {
+            cqid,
+            dma_addr,
+            size,
+            head: 0,
+            tail: 0,
+            // The phase tag of the first pass is 1.
+            phase: true,
+            vector,
+            irq_enabled,
+            pending: VecDeque::new(),
+            disabled: false,
+            mem_space,
+            irq,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        (self.tail + 1) % self.size == self.head
+    }
+
+    fn post(&mut self, cqe: NvmeCqe) {
+        if self.disabled {
+            return;
+        }
+        if !self.pending.is_empty() || self.is_full() {
+            self.pending.push_back(cqe);
+            return;
+        }
+        self.write_cqe(cqe);
+        self.notify();
+    }
+
+    fn write_cqe(&mut self, mut cqe: NvmeCqe) {
+        cqe.status = (cqe.status << 1) | self.phase as u16;
+        let addr = self.dma_addr + self.tail as u64 * NVME_CQE_SIZE;
+        if let Err(e) = self.mem_space.write_object(&cqe, GuestAddress(addr)) {
+            error!("Failed to write entry of nvme cq {}: {:?}", self.cqid, e);
+            return;
+        }
+        self.tail += 1;
+        if self.tail == self.size {
+            self.tail = 0;
+            self.phase = !self.phase;
+        }
+    }
+
+    fn notify(&self) {
+        if self.irq_enabled {
+            self.irq.assert(self.cqid, self.vector);
+        }
+    }
+
+    /// Update the head written by the guest, returns false if it's out of the queue.
+    fn update_head(&mut self, head: u32) -> bool {
+        if head >= self.size {
+            return false;
+        }
+        self.head = head;
+
+        let mut posted = false;
+        while !self.is_full() {
+            match self.pending.pop_front() {
+                Some(cqe) => self.write_cqe(cqe),
+                None => break,
+            }
+            posted = true;
+        }
+        if posted {
+            self.notify();
+        } else if self.head == self.tail {
+            self.irq.deassert(self.cqid);
+        }
+        true
+    }
+
+    fn disable(&mut self) {
+        self.disabled = true;
+        self.pending.clear();
+        self.irq.deassert(self.cqid);
+    }
+}
+
+/// Submission queue.
+struct NvmeSq {
+    cqid: u16,
+    dma_addr: u64,
+    size: u32,
+    /// The head is reported in the completions of the requests.
+    head: Arc<AtomicU32>,
+    tail: u32,
+}
+
+impl NvmeSq {
+    fn new(cqid: u16, dma_addr: u64, size: u32) -> Self {
+        Self {
+            cqid,
+            dma_addr,
+            size,
+            head: Arc::new(AtomicU32::new(0)),
+            tail: 0,
+        }
+    }
+}
+
+/// The request of a submission queue entry. It may be split into several aio
+/// requests, and is completed when all of them are done.
+pub struct NvmeRequest {
+    cq: Arc<Mutex<NvmeCq>>,
+    sq_head: Arc<AtomicU32>,
+    sqid: u16,
+    cid: u16,
+    /// Outstanding aio requests, plus one held by the submitter until all the
+    /// aio requests are submitted.
+    remaining: AtomicU32,
+    /// The first error status of the aio requests.
+    status: AtomicU16,
+    /// Command specific result, which is DW0 of the completion.
+    result: AtomicU32,
+}
+
+impl NvmeRequest {
+    fn new(cq: Arc<Mutex<NvmeCq>>, sq_head: Arc<AtomicU32>, sqid: u16, cid: u16) -> Self {
+        Self {
+            cq,
+            sq_head,
+            sqid,
+            cid,
+            remaining: AtomicU32::new(1),
+            status: AtomicU16::new(NVME_SUCCESS),
+            result: AtomicU32::new(0),
+        }
+    }
+
+    /// Called before submitting an aio request of the request.
+    pub fn start_aio(&self) {
+        self.remaining.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Called when an aio request is done, the last one posts the completion.
+    pub fn done(&self, status: u16) {
+        if status != NVME_SUCCESS {
+            let _ = self.status.compare_exchange(
+                NVME_SUCCESS,
+                status,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+        }
+        if self.remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let cqe = NvmeCqe {
+                result: self.result.load(Ordering::SeqCst),
+                rsvd: 0,
+                sq_head: self.sq_head.load(Ordering::Acquire) as u16,
+                sq_id: self.sqid,
+                cid: self.cid,
+                status: self.status.load(Ordering::SeqCst),
+            };
+            self.cq.lock().unwrap().post(cqe);
+        }
+    }
+}
+
+/// Current value of the features which can be set by Set Features.
+#[derive(Clone, Copy)]
+struct NvmeFeatures {
+    arbitration: u32,
+    power_mgmt: u32,
+    temp_thresh_over: u16,
+    temp_thresh_under: u16,
+    err_recovery: u32,
+    volatile_wc: u32,
+    num_queues: u32,
+    int_coalescing: u32,
+    write_atomicity: u32,
+    async_event_config: u32,
+}
+
+impl NvmeFeatures {
+    fn new(max_ioqpairs: u16) -> Self {
+        let queues = (max_ioqpairs - 1) as u32;
+        Self {
+            arbitration: 0,
+            power_mgmt: 0,
+            temp_thresh_over: NVME_TEMPERATURE_WARNING,
+            temp_thresh_under: 0,
+            err_recovery: 0,
+            volatile_wc: 1,
+            num_queues: queues | (queues << 16),
+            int_coalescing: 0,
+            write_atomicity: 0,
+            async_event_config: 0,
+        }
+    }
+}
+
+/// Fill `buf` with `s`, padded with spaces.
+fn copy_padded(buf: &mut [u8], s: &str) {
+    buf.fill(b' ');
+    let len = min(buf.len(), s.len());
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+pub struct NvmeCtrl {
+    id: String,
+    serial: String,
+    /// Max number of I/O queue pairs, the number of interrupt vectors is one more.
+    max_ioqpairs: u16,
+    iothread: Option<String>,
+    mem_space: Arc<AddressSpace>,
+    drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    irq: Option<Arc<NvmeIrq>>,
+    cap: u64,
+    cc: u32,
+    csts: u32,
+    intms: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+    /// Queues indexed by queue id, the admin queues are at index 0.
+    sqs: Vec<Option<NvmeSq>>,
+    cqs: Vec<Option<Arc<Mutex<NvmeCq>>>>,
+    namespaces: BTreeMap<u32, NvmeNamespace>,
+    features: NvmeFeatures,
+    /// Outstanding asynchronous event requests, no event is reported now.
+    aer_reqs: Vec<Arc<NvmeRequest>>,
+    /// The io handlers of the namespaces stop working if it's set.
+    broken: Arc<AtomicBool>,
+    /// Controller Fatal Status, set if the io handlers of the namespaces fail.
+    fatal: Arc<AtomicBool>,
+}
+
+impl NvmeCtrl {
+    pub fn new(
+        config: &NvmeConfig,
+        mem_space: &Arc<AddressSpace>,
+        drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    ) -> Self {
+        // MQES, CQR, TO(7.5s), and the NVM command set in CSS. DSTRD, MPSMIN and
+        // MPSMAX are all 0, so the doorbell stride is 4 bytes and the page size is 4KiB.
+        let cap = (NVME_MAX_QUEUE_ENTRIES - 1) as u64 | (1 << 16) | (0x0f << 24) | (1 << 37);
+        let queues = config.queues as usize + 1;
+        Self {
+            id: config.id.clone(),
+            serial: config.serial.clone(),
+            max_ioqpairs: config.queues,
+            iothread: config.iothread.clone(),
+            mem_space: mem_space.clone(),
+            drive_files,
+            irq: None,
+            cap,
+            cc: 0,
+            csts: 0,
+            intms: 0,
+            aqa: 0,
+            asq: 0,
+            acq: 0,
+            sqs: (0..queues).map(|_| None).collect(),
+            cqs: vec![None; queues],
+            namespaces: BTreeMap::new(),
+            features: NvmeFeatures::new(config.queues),
+            aer_reqs: Vec::new(),
+            broken: Arc::new(AtomicBool::new(false)),
+            fatal: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn set_irq(&mut self, irq: Arc<NvmeIrq>) {
+        self.irq = Some(irq);
+    }
+
+    /// Attach a namespace to the controller, nsid 0 in the config means the first free one.
+    pub fn add_namespace(&mut self, config: NvmeNsConfig) -> Result<()> {
+        let nsid = if config.nsid == 0 {
+            (1..=NVME_MAX_NAMESPACES)
+                .find(|nsid| !self.namespaces.contains_key(nsid))
+                .with_context(|| format!("No free namespace of nvme controller {}", self.id))?
+        } else {
+            config.nsid
+        };
+        if self.namespaces.contains_key(&nsid) {
+            bail!(
+                "Namespace {} of nvme controller {} has been used",
+                nsid,
+                self.id
+            );
+        }
+
+        let mut ns = NvmeNamespace::new(config, nsid, self.drive_files.clone());
+        let fatal = self.fatal.clone();
+        let error_cb: BlockIoErrorCallback = Arc::new(move || {
+            fatal.store(true, Ordering::SeqCst);
+        });
+        ns.realize(self.iothread.clone(), self.broken.clone(), error_cb)?;
+        self.namespaces.insert(nsid, ns);
+        Ok(())
+    }
+
+    pub fn unrealize(&mut self) -> Result<()> {
+        self.hard_reset();
+        for ns in self.namespaces.values_mut() {
+            ns.unrealize()?;
+        }
+        self.namespaces.clear();
+        Ok(())
+    }
+
+    /// Controller Level Reset, the admin queue attributes are retained.
+    fn reset(&mut self) {
+        for cq in self.cqs.iter_mut() {
+            if let Some(cq) = cq.take() {
+                cq.lock().unwrap().disable();
+            }
+        }
+        for sq in self.sqs.iter_mut() {
+            *sq = None;
+        }
+        self.aer_reqs.clear();
+        self.features = NvmeFeatures::new(self.max_ioqpairs);
+        self.intms = 0;
+        if let Some(irq) = self.irq.as_ref() {
+            irq.set_intx_mask(false);
+        }
+        self.csts = 0;
+        self.fatal.store(false, Ordering::SeqCst);
+    }
+
+    /// Reset all the registers, used by the reset of the PCI device.
+    pub fn hard_reset(&mut self) {
+        self.reset();
+        self.cc = 0;
+        self.aqa = 0;
+        self.asq = 0;
+        self.acq = 0;
+    }
+
+    fn start(&mut self) -> Result<()> {
+        let irq = self
+            .irq
+            .clone()
+            .with_context(|| "The interrupt is not initialized")?;
+        if (self.cc >> NVME_CC_MPS_SHIFT) & NVME_CC_MPS_MASK != 0 {
+            bail!("Only 4KiB memory page size is supported");
+        }
+        if (self.cc >> NVME_CC_CSS_SHIFT) & NVME_CC_CSS_MASK != 0 {
+            bail!("Only NVM command set is supported");
+        }
+        if self.asq == 0 || self.acq == 0 {
+            bail!("The admin queues are not configured");
+        }
+        let sq_size = (self.aqa & 0xfff) + 1;
+        let cq_size = ((self.aqa >> 16) & 0xfff) + 1;
+        if sq_size < 2 || cq_size < 2 {
+            bail!("Invalid admin queue size, sq {} cq {}", sq_size, cq_size);
+        }
+
+        self.cqs[0] = Some(Arc::new(Mutex::new(NvmeCq::new(
+            0,
+            self.acq,
+            cq_size,
+            0,
+            true,
+            self.mem_space.clone(),
+            irq,
+        ))));
+        self.sqs[0] = Some(NvmeSq::new(0, self.asq, sq_size));
+        self.csts = NVME_CSTS_RDY;
+        Ok(())
+    }
+
+    fn read_reg(&self, offset: u64) -> u32 {
+        match offset {
+            NVME_REG_VS => NVME_VERSION,
+            NVME_REG_INTMS | NVME_REG_INTMC => self.intms,
+            NVME_REG_CC => self.cc,
+            NVME_REG_CSTS => {
+                if self.fatal.load(Ordering::SeqCst) {
+                    self.csts | NVME_CSTS_CFS
+                } else {
+                    self.csts
+                }
+            }
+            NVME_REG_AQA => self.aqa,
+            _ => {
+                let value = match offset & !0x7 {
+                    NVME_REG_CAP => self.cap,
+                    NVME_REG_ASQ => self.asq,
+                    NVME_REG_ACQ => self.acq,
+                    _ => 0,
+                };
+                read_u32(value, ((offset & 0x4) >> 2) as u32)
+            }
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, value: u32) {
+        match offset {
+            NVME_REG_INTMS | NVME_REG_INTMC => {
+                if offset == NVME_REG_INTMS {
+                    self.intms |= value;
+                } else {
+                    self.intms &= !value;
+                }
+                // Only vector 0 is used by the pin-based interrupt.
+                if let Some(irq) = self.irq.as_ref() {
+                    irq.set_intx_mask(self.intms & 0x1 != 0);
+                }
+            }
+            NVME_REG_CC => self.write_cc(value),
+            NVME_REG_AQA => self.aqa = value & 0x0fff_0fff,
+            _ => {
+                let high = offset & 0x4 != 0;
+                let update = |origin: u64, value: u32| {
+                    if high {
+                        write_u64_high(origin, value)
+                    } else {
+                        // The queues are aligned to the memory page.
+                        write_u64_low(origin, value & !(NVME_PAGE_SIZE as u32 - 1))
+                    }
+                };
+                match offset & !0x7 {
+                    NVME_REG_ASQ => self.asq = update(self.asq, value),
+                    NVME_REG_ACQ => self.acq = update(self.acq, value),
+                    _ => warn!(
+                        "Write to read-only or reserved register 0x{:x} of nvme {}",
+                        offset, self.id
+                    ),
+                }
+            }
+        }
+    }
+
+    fn write_cc(&mut self, value: u32) {
+        let old = self.cc;
+        self.cc = value;
+        if value & NVME_CC_EN != 0 && old & NVME_CC_EN == 0 {
+            if let Err(e) = self.start() {
+                error!("Failed to enable nvme controller {}: {:?}", self.id, e);
+            }
+        } else if value & NVME_CC_EN == 0 && old & NVME_CC_EN != 0 {
+            self.reset();
+        }
+
+        // The requests are submitted to the backends once they are fetched, so
+        // the shutdown processing is completed immediately.
+        if (value >> NVME_CC_SHN_SHIFT) & NVME_CC_SHN_MASK != 0 {
+            self.csts |= NVME_CSTS_SHST_COMPLETE;
+        } else {
+            self.csts &= !NVME_CSTS_SHST_COMPLETE;
+        }
+    }
+
+    fn write_doorbell(&mut self, offset: u64, value: u32) {
+        if self.csts & NVME_CSTS_RDY == 0 {
+            warn!("Doorbell is written when nvme {} is not ready", self.id);
+            return;
+        }
+        let qid = (offset >> 3) as usize;
+        if offset & 0x4 == 0 {
+            match self.sqs.get_mut(qid).and_then(|sq| sq.as_mut()) {
+                Some(sq) if value < sq.size => sq.tail = value,
+                _ => {
+                    error!("Invalid tail {} of nvme {} sq {}", value, self.id, qid);
+                    return;
+                }
+            }
+            self.process_sq(qid);
+        } else {
+            let valid = match self.cqs.get(qid).and_then(|cq| cq.as_ref()) {
+                Some(cq) => cq.lock().unwrap().update_head(value),
+                None => false,
+            };
+            if !valid {
+                error!("Invalid head {} of nvme {} cq {}", value, self.id, qid);
+            }
+        }
+    }
+
+    fn process_sq(&mut self, sqid: usize) {
+        let mut flush_ns = BTreeSet::new();
+        while let Some(sq) = self.sqs[sqid].as_ref() {
+            let head = sq.head.load(Ordering::Acquire);
+            if head == sq.tail {
+                break;
+            }
+            let addr = sq.dma_addr + head as u64 * NVME_SQE_SIZE;
+            let sq_head = sq.head.clone();
+            // The completion queue can't be deleted when any submission queue is attached.
+            let cq = self.cqs[sq.cqid as usize].clone().unwrap();
+            sq_head.store((head + 1) % sq.size, Ordering::Release);
+
+            let sqe = match self.mem_space.read_object::<NvmeSqe>(GuestAddress(addr)) {
+                Ok(sqe) => sqe,
+                Err(e) => {
+                    error!(
+                        "Failed to read entry of nvme {} sq {}: {:?}",
+                        self.id, sqid, e
+                    );
+                    break;
+                }
+            };
+            let req = Arc::new(NvmeRequest::new(cq, sq_head, sqid as u16, sqe.cid));
+            if sqid == 0 && sqe.opcode == NVME_ADM_CMD_ASYNC_EV_REQ {
+                // The request is completed when an event is reported.
+                if self.aer_reqs.len() > NVME_AERL as usize {
+                    req.done(NVME_AER_LIMIT_EXCEEDED | NVME_DNR);
+                } else {
+                    self.aer_reqs.push(req);
+                }
+                continue;
+            }
+
+            let ret = if sqid == 0 {
+                self.handle_admin_cmd(&sqe)
+            } else {
+                self.handle_io_cmd(&sqe, &req, &mut flush_ns).map(|_| 0)
+            };
+            match ret {
+                Ok(result) => {
+                    req.result.store(result, Ordering::SeqCst);
+                    req.done(NVME_SUCCESS);
+                }
+                Err(status) => req.done(status | NVME_DNR),
+            }
+        }
+
+        for nsid in flush_ns {
+            if let Some(ns) = self.namespaces.get(&nsid) {
+                ns.flush_request();
+            }
+        }
+    }
+
+    fn handle_admin_cmd(&mut self, sqe: &NvmeSqe) -> NvmeResult<u32> {
+        match sqe.opcode {
+            NVME_ADM_CMD_DELETE_SQ => self.delete_sq(sqe),
+            NVME_ADM_CMD_CREATE_SQ => self.create_sq(sqe),
+            NVME_ADM_CMD_GET_LOG_PAGE => self.get_log_page(sqe),
+            NVME_ADM_CMD_DELETE_CQ => self.delete_cq(sqe),
+            NVME_ADM_CMD_CREATE_CQ => self.create_cq(sqe),
+            NVME_ADM_CMD_IDENTIFY => self.identify(sqe),
+            // The command is not aborted.
+            NVME_ADM_CMD_ABORT => Ok(1),
+            NVME_ADM_CMD_SET_FEATURES => self.set_features(sqe),
+            NVME_ADM_CMD_GET_FEATURES => self.get_features(sqe),
+            _ => {
+                warn!(
+                    "Unsupported admin command 0x{:x} of nvme {}",
+                    sqe.opcode, self.id
+                );
+                Err(NVME_INVALID_OPCODE)
+            }
+        }
+    }
+
+    fn check_queue_size(&self, sqe: &NvmeSqe) -> NvmeResult<u32> {
+        let size = (sqe.cdw10 >> 16) + 1;
+        if !(2..=NVME_MAX_QUEUE_ENTRIES).contains(&size) {
+            return Err(NVME_MAX_QSIZE_EXCEEDED);
+        }
+        // Physically contiguous queue is required, and it must be aligned to the memory page.
+        if sqe.cdw11 & 0x1 == 0 || sqe.prp1 == 0 || sqe.prp1 & (NVME_PAGE_SIZE - 1) != 0 {
+            return Err(NVME_INVALID_FIELD);
+        }
+        Ok(size)
+    }
+
+    fn create_cq(&mut self, sqe: &NvmeSqe) -> NvmeResult<u32> {
+        let cqid = (sqe.cdw10 & 0xffff) as usize;
+        if cqid == 0 || !matches!(self.cqs.get(cqid), Some(None)) {
+            return Err(NVME_INVALID_QID);
+        }
+        let size = self.check_queue_size(sqe)?;
+        let vector = (sqe.cdw11 >> 16) as u16;
+        if vector > self.max_ioqpairs {
+            return Err(NVME_INVALID_IRQ_VECTOR);
+        }
+        let irq = self.irq.clone().ok_or(NVME_INTERNAL_DEV_ERROR)?;
+
+        self.cqs[cqid] = Some(Arc::new(Mutex::new(NvmeCq::new(
+            cqid as u16,
+            sqe.prp1,
+            size,
+            vector,
+            sqe.cdw11 & 0x2 != 0,
+            self.mem_space.clone(),
+            irq,
+        ))));
+        Ok(0)
+    }
+
+    fn create_sq(&mut self, sqe: &NvmeSqe) -> NvmeResult<u32> {
+        let sqid = (sqe.cdw10 & 0xffff) as usize;
+        if sqid == 0 || !matches!(self.sqs.get(sqid), Some(None)) {
+            return Err(NVME_INVALID_QID);
+        }
+        let cqid = (sqe.cdw11 >> 16) as usize;
+        if cqid == 0 || !matches!(self.cqs.get(cqid), Some(Some(_))) {
+            return Err(NVME_INVALID_CQID);
+        }
+        let size = self.check_queue_size(sqe)?;
+
+        self.sqs[sqid] = Some(NvmeSq::new(cqid as u16, sqe.prp1, size));
+        Ok(0)
+    }
+
+    fn delete_sq(&mut self, sqe: &NvmeSqe) -> NvmeResult<u32> {
+        let sqid = (sqe.cdw10 & 0xffff) as usize;
+        if sqid == 0 || !matches!(self.sqs.get(sqid), Some(Some(_))) {
+            return Err(NVME_INVALID_QID);
+        }
+        // The in-flight requests are still completed to the completion queue.
+        self.sqs[sqid] = None;
+        Ok(0)
+    }
+
+    fn delete_cq(&mut self, sqe: &NvmeSqe) -> NvmeResult<u32> {
+        let cqid = (sqe.cdw10 & 0xffff) as usize;
+        if cqid == 0 || !matches!(self.cqs.get(cqid), Some(Some(_))) {
+            return Err(NVME_INVALID_QID);
+        }
+        if self.sqs.iter().flatten().any(|sq| sq.cqid as usize == cqid) {
+            return Err(NVME_INVALID_QUEUE_DEL);
+        }
+        if let Some(cq) = self.cqs[cqid].take() {
+            cq.lock().unwrap().disable();
+        }
+        Ok(0)
+    }
+
+    fn get_log_page(&self, sqe: &NvmeSqe) -> NvmeResult<u32> {
+        let lid = sqe.cdw10 & 0xff;
+        let numd = (((sqe.cdw11 & 0xffff) << 16) | (sqe.cdw10 >> 16)) as u64 + 1;
+        let offset = ((sqe.cdw13 as u64) << 32) | sqe.cdw12 as u64;
+
+        let log = match lid {
+            // The error log is always empty.
+            NVME_LOG_ERROR_INFO => vec![0_u8; NVME_LOG_ERROR_INFO_SIZE],
+            NVME_LOG_SMART_INFO => {
+                let mut log = vec![0_u8; NVME_LOG_SMART_INFO_SIZE];
+                LittleEndian::write_u16(&mut log[1..3], NVME_TEMPERATURE);
+                // Available spare and its threshold in percentage.
+                log[3] = 100;
+                log[4] = 10;
+                log
+            }
+            NVME_LOG_FW_SLOT_INFO => {
+                let mut log = vec![0_u8; NVME_LOG_FW_SLOT_INFO_SIZE];
+                // The firmware in slot 1 is active.
+                log[0] = 1;
+                copy_padded(&mut log[8..16], NVME_FIRMWARE_REVISION);
+                log
+            }
+            _ => return Err(NVME_INVALID_LOG_ID),
+        };
+        if offset & 0x3 != 0 || offset >= log.len() as u64 {
+            return Err(NVME_INVALID_FIELD);
+        }
+        let start = offset as usize;
+        let end = min(offset + numd * 4, log.len() as u64) as usize;
+        self.dma_write(sqe.prp1, sqe.prp2, &log[start..end])?;
+        Ok(0)
+    }
+
+    fn identify(&self, sqe: &NvmeSqe) -> NvmeResult<u32> {
+        let data = match sqe.cdw10 & 0xff {
+            NVME_ID_CNS_NS => self.identify_ns(sqe.nsid)?,
+            NVME_ID_CNS_CTRL => self.identify_ctrl(),
+            NVME_ID_CNS_NS_ACTIVE_LIST => self.identify_active_ns_list(sqe.nsid)?,
+            NVME_ID_CNS_NS_DESC_LIST => {
+                // No namespace identifier is reported.
+                if !self.namespaces.contains_key(&sqe.nsid) {
+                    return Err(NVME_INVALID_NSID);
+                }
+                vec![0_u8; NVME_IDENTIFY_DATA_SIZE]
+            }
+            _ => return Err(NVME_INVALID_FIELD),
+        };
+        self.dma_write(sqe.prp1, sqe.prp2, &data)?;
+        Ok(0)
+    }
+
+    fn identify_ns(&self, nsid: u32) -> NvmeResult<Vec<u8>> {
+        if nsid == 0 || nsid > NVME_MAX_NAMESPACES {
+            return Err(NVME_INVALID_NSID);
+        }
+        // The data of the inactive namespace is zero filled.
+        Ok(match self.namespaces.get(&nsid) {
+            Some(ns) => ns.identify(),
+            None => vec![0_u8; NVME_IDENTIFY_DATA_SIZE],
+        })
+    }
+
+    fn identify_active_ns_list(&self, nsid: u32) -> NvmeResult<Vec<u8>> {
+        if nsid >= NVME_NSID_BROADCAST - 1 {
+            return Err(NVME_INVALID_NSID);
+        }
+        let mut data = vec![0_u8; NVME_IDENTIFY_DATA_SIZE];
+        for (i, id) in self
+            .namespaces
+            .range(nsid + 1..)
+            .map(|ns| *ns.0)
+            .enumerate()
+        {
+            if i >= NVME_IDENTIFY_DATA_SIZE / 4 {
+                break;
+            }
+            LittleEndian::write_u32(&mut data[i * 4..(i + 1) * 4], id);
+        }
+        Ok(data)
+    }
+
+    fn identify_ctrl(&self) -> Vec<u8> {
+        let mut data = vec![0_u8; NVME_IDENTIFY_DATA_SIZE];
+        LittleEndian::write_u16(&mut data[0..2], PCI_VENDOR_ID_REDHAT);
+        LittleEndian::write_u16(&mut data[2..4], NVME_SUBSYSTEM_VENDOR_ID);
+        copy_padded(&mut data[4..24], &self.serial);
+        copy_padded(&mut data[24..64], NVME_MODEL_NUMBER);
+        copy_padded(&mut data[64..72], NVME_FIRMWARE_REVISION);
+        // Recommended Arbitration Burst.
+        data[72] = 6;
+        data[77] = NVME_MDTS;
+        LittleEndian::write_u32(&mut data[80..84], NVME_VERSION);
+        // I/O controller.
+        data[111] = 1;
+        // Abort Command Limit and Asynchronous Event Request Limit.
+        data[258] = 3;
+        data[259] = NVME_AERL;
+        // The firmware slot 1 is read only, and there is only one slot.
+        data[260] = 0x3;
+        LittleEndian::write_u16(&mut data[266..268], NVME_TEMPERATURE_WARNING);
+        LittleEndian::write_u16(&mut data[268..270], NVME_TEMPERATURE_CRITICAL);
+        // Submission and Completion Queue Entry Size.
+        data[512] = 0x66;
+        data[513] = 0x44;
+        LittleEndian::write_u32(&mut data[516..520], NVME_MAX_NAMESPACES);
+        // Optional NVM Command Support: Dataset Management and Write Zeroes.
+        LittleEndian::write_u16(&mut data[520..522], (1 << 2) | (1 << 3));
+        // Volatile write cache is present, and the Flush command supports the broadcast NSID.
+        data[525] = 0x7;
+        // NVM Subsystem NVMe Qualified Name, which is null terminated.
+        let subnqn = format!("nqn.2023-01.org.openeuler.stratovirt:nvme:{}", self.serial);
+        let len = min(subnqn.len(), 255);
+        data[768..768 + len].copy_from_slice(&subnqn.as_bytes()[..len]);
+        // Power State 0: max power 25W, entry and exit latency 16us.
+        LittleEndian::write_u16(&mut data[2048..2050], 0x9c4);
+        LittleEndian::write_u32(&mut data[2052..2056], 0x10);
+        LittleEndian::write_u32(&mut data[2056..2060], 0x10);
+        data
+    }
+
+    fn set_features(&mut self, sqe: &NvmeSqe) -> NvmeResult<u32> {
+        if sqe.cdw10 & (1 << 31) != 0 {
+            return Err(NVME_FEAT_NOT_SAVEABLE);
+        }
+        let value = sqe.cdw11;
+        match sqe.cdw10 & 0xff {
+            NVME_FEAT_ARBITRATION => self.features.arbitration = value,
+            NVME_FEAT_POWER_MGMT => {
+                // Only power state 0 is supported.
+                if value & 0x1f != 0 {
+                    return Err(NVME_INVALID_FIELD);
+                }
+                self.features.power_mgmt = value;
+            }
+            NVME_FEAT_TEMP_THRESH => {
+                // Only the composite temperature is supported.
+                if (value >> 16) & 0xf != 0 {
+                    return Err(NVME_INVALID_FIELD);
+                }
+                match (value >> 20) & 0x3 {
+                    0 => self.features.temp_thresh_over = value as u16,
+                    1 => self.features.temp_thresh_under = value as u16,
+                    _ => return Err(NVME_INVALID_FIELD),
+                }
+            }
+            NVME_FEAT_ERR_RECOVERY => self.features.err_recovery = value,
+            NVME_FEAT_VOLATILE_WC => self.features.volatile_wc = value & 0x1,
+            NVME_FEAT_NUM_QUEUES => {
+                if value & 0xffff == 0xffff || value >> 16 == 0xffff {
+                    return Err(NVME_INVALID_FIELD);
+                }
+                // The allocated queues are always the max ones.
+                return Ok(self.features.num_queues);
+            }
+            NVME_FEAT_INT_COALESCING => self.features.int_coalescing = value,
+            NVME_FEAT_INT_VECTOR_CONFIG => {
+                if value & 0xffff > self.max_ioqpairs as u32 {
+                    return Err(NVME_INVALID_FIELD);
+                }
+            }
+            NVME_FEAT_WRITE_ATOMICITY => self.features.write_atomicity = value,
+            NVME_FEAT_ASYNC_EVENT_CONFIG => self.features.async_event_config = value,
+            fid => {
+                warn!("Unsupported feature 0x{:x} of nvme {}", fid, self.id);
+                return Err(NVME_INVALID_FIELD);
+            }
+        }
+        Ok(0)
+    }
+
+    fn get_features(&self, sqe: &NvmeSqe) -> NvmeResult<u32> {
+        let fid = sqe.cdw10 & 0xff;
+        let features = match (sqe.cdw10 >> 8) & 0x7 {
+            0 => self.features,
+            // The saved value is the default one as no feature is saveable.
+            1 | 2 => NvmeFeatures::new(self.max_ioqpairs),
+            3 => {
+                if !(NVME_FEAT_ARBITRATION..=NVME_FEAT_ASYNC_EVENT_CONFIG).contains(&fid)
+                    || fid == 0x03
+                {
+                    return Err(NVME_INVALID_FIELD);
+                }
+                return Ok(NVME_FEAT_CAP_CHANGEABLE);
+            }
+            _ => return Err(NVME_INVALID_FIELD),
+        };
+
+        let value = match fid {
+            NVME_FEAT_ARBITRATION => features.arbitration,
+            NVME_FEAT_POWER_MGMT => features.power_mgmt,
+            NVME_FEAT_TEMP_THRESH => {
+                if (sqe.cdw11 >> 16) & 0xf != 0 {
+                    return Err(NVME_INVALID_FIELD);
+                }
+                match (sqe.cdw11 >> 20) & 0x3 {
+                    0 => features.temp_thresh_over as u32,
+                    1 => features.temp_thresh_under as u32,
+                    _ => return Err(NVME_INVALID_FIELD),
+                }
+            }
+            NVME_FEAT_ERR_RECOVERY => features.err_recovery,
+            NVME_FEAT_VOLATILE_WC => features.volatile_wc,
+            NVME_FEAT_NUM_QUEUES => features.num_queues,
+            NVME_FEAT_INT_COALESCING => features.int_coalescing,
+            NVME_FEAT_INT_VECTOR_CONFIG => {
+                if sqe.cdw11 & 0xffff > self.max_ioqpairs as u32 {
+                    return Err(NVME_INVALID_FIELD);
+                }
+                sqe.cdw11 & 0xffff
+            }
+            NVME_FEAT_WRITE_ATOMICITY => features.write_atomicity,
+            NVME_FEAT_ASYNC_EVENT_CONFIG => features.async_event_config,
+            _ => return Err(NVME_INVALID_FIELD),
+        };
+        Ok(value)
+    }
+
+    fn handle_io_cmd(
+        &self,
+        sqe: &NvmeSqe,
+        req: &Arc<NvmeRequest>,
+        flush_ns: &mut BTreeSet<u32>,
+    ) -> NvmeResult<()> {
+        if sqe.opcode == NVME_CMD_FLUSH && sqe.nsid == NVME_NSID_BROADCAST {
+            for ns in self.namespaces.values() {
+                ns.flush(req);
+                flush_ns.insert(ns.nsid);
+            }
+            return Ok(());
+        }
+
+        let ns = self.namespaces.get(&sqe.nsid).ok_or(NVME_INVALID_NSID)?;
+        let slba = ((sqe.cdw11 as u64) << 32) | sqe.cdw10 as u64;
+        let nlb = (sqe.cdw12 & 0xffff) as u64 + 1;
+        match sqe.opcode {
+            NVME_CMD_FLUSH => ns.flush(req),
+            NVME_CMD_READ | NVME_CMD_WRITE => {
+                let write = sqe.opcode == NVME_CMD_WRITE;
+                let len = nlb << NVME_LBA_SHIFT;
+                if len > NVME_PAGE_SIZE << NVME_MDTS {
+                    return Err(NVME_INVALID_FIELD);
+                }
+                ns.check_range(slba, nlb)?;
+                if write && ns.read_only() {
+                    return Err(NVME_WRITE_TO_RO);
+                }
+                let iovecs = self.map_host_iovecs(sqe.prp1, sqe.prp2, len)?;
+                ns.rw(req, write, slba, iovecs);
+            }
+            NVME_CMD_WRITE_ZEROES => {
+                ns.check_range(slba, nlb)?;
+                if ns.read_only() {
+                    return Err(NVME_WRITE_TO_RO);
+                }
+                // Deallocate the blocks if DEAC is set.
+                ns.write_zeroes(req, slba, nlb, sqe.cdw12 & (1 << 25) != 0);
+            }
+            NVME_CMD_DSM => {
+                // Only the deallocate attribute is handled, the others are hints.
+                if sqe.cdw11 & (1 << 2) == 0 {
+                    return Ok(());
+                }
+                if ns.read_only() {
+                    return Err(NVME_WRITE_TO_RO);
+                }
+                let nr = (sqe.cdw10 & 0xff) as usize + 1;
+                let range_size = std::mem::size_of::<NvmeDsmRange>();
+                let data = self.dma_read(sqe.prp1, sqe.prp2, (nr * range_size) as u64)?;
+                let ranges = data
+                    .chunks_exact(range_size)
+                    .map(|buf| *NvmeDsmRange::from_bytes(buf).unwrap())
+                    .filter(|range| range.nlb != 0)
+                    .collect::<Vec<NvmeDsmRange>>();
+                for range in ranges.iter() {
+                    ns.check_range(range.slba, range.nlb as u64)?;
+                }
+                ns.discard(req, &ranges);
+            }
+            _ => {
+                warn!(
+                    "Unsupported io command 0x{:x} of nvme {}",
+                    sqe.opcode, self.id
+                );
+                return Err(NVME_INVALID_OPCODE);
+            }
+        }
+        flush_ns.insert(ns.nsid);
+        Ok(())
+    }
+
+    /// Map the PRP entries to the guest memory segments of `len` bytes.
+    fn map_prp(&self, prp1: u64, prp2: u64, len: u64) -> NvmeResult<Vec<(u64, u64)>> {
+        let mut segs = Vec::new();
+        let first_len = min(len, NVME_PAGE_SIZE - (prp1 & (NVME_PAGE_SIZE - 1)));
+        segs.push((prp1, first_len));
+        let mut remaining = len - first_len;
+        if remaining == 0 {
+            return Ok(segs);
+        }
+        if remaining <= NVME_PAGE_SIZE {
+            segs.push((prp2, remaining));
+            return Ok(segs);
+        }
+
+        // PRP2 is a pointer to the PRP list, the last entry of a list page points to
+        // the next list page if more entries are needed.
+        let mut list_addr = prp2;
+        while remaining > 0 {
+            if list_addr & 0x7 != 0 {
+                return Err(NVME_INVALID_FIELD);
+            }
+            let entries = (NVME_PAGE_SIZE - (list_addr & (NVME_PAGE_SIZE - 1))) / 8;
+            for i in 0..entries {
+                let entry = self
+                    .mem_space
+                    .read_object::<u64>(GuestAddress(list_addr + i * 8))
+                    .map_err(|e| {
+                        error!("Failed to read prp list of nvme {}: {:?}", self.id, e);
+                        NVME_DATA_TRANSFER_ERROR
+                    })?;
+                if i == entries - 1 && remaining > NVME_PAGE_SIZE {
+                    list_addr = entry;
+                    break;
+                }
+                let seg_len = min(remaining, NVME_PAGE_SIZE);
+                segs.push((entry, seg_len));
+                remaining -= seg_len;
+                if remaining == 0 {
+                    break;
+                }
+            }
+        }
+        Ok(segs)
+    }
+
+    fn map_host_iovecs(&self, prp1: u64, prp2: u64, len: u64) -> NvmeResult<Vec<Iovec>> {
+        let mut iovecs: Vec<Iovec> = Vec::new();
+        for (addr, len) in self.map_prp(prp1, prp2, len)? {
+            // The segment doesn't cross the memory page, so it's contiguous in the host.
+            let hva = self
+                .mem_space
+                .get_host_address(GuestAddress(addr))
+                .ok_or_else(|| {
+                    error!("Failed to map address 0x{:x} of nvme {}", addr, self.id);
+                    NVME_DATA_TRANSFER_ERROR
+                })?;
+            match iovecs.last_mut() {
+                Some(last) if last.iov_base + last.iov_len == hva => last.iov_len += len,
+                _ => iovecs.push(Iovec::new(hva, len)),
+            }
+        }
+        Ok(iovecs)
+    }
+
+    fn dma_write(&self, prp1: u64, prp2: u64, data: &[u8]) -> NvmeResult<()> {
+        let mut pos = 0;
+        for (addr, len) in self.map_prp(prp1, prp2, data.len() as u64)? {
+            let mut src = &data[pos..pos + len as usize];
+            self.mem_space
+                .write(&mut src, GuestAddress(addr), len)
+                .map_err(|e| {
+                    error!("Failed to write data of nvme {}: {:?}", self.id, e);
+                    NVME_DATA_TRANSFER_ERROR
+                })?;
+            pos += len as usize;
+        }
+        Ok(())
+    }
+
+    fn dma_read(&self, prp1: u64, prp2: u64, len: u64) -> NvmeResult<Vec<u8>> {
+        let mut data = vec![0_u8; len as usize];
+        let mut pos = 0;
+        for (addr, len) in self.map_prp(prp1, prp2, len)? {
+            let mut dst = &mut data[pos..pos + len as usize];
+            self.mem_space
+                .read(&mut dst, GuestAddress(addr), len)
+                .map_err(|e| {
+                    error!("Failed to read data of nvme {}: {:?}", self.id, e);
+                    NVME_DATA_TRANSFER_ERROR
+                })?;
+            pos += len as usize;
+        }
+        Ok(data)
+    }
+}
+
+/// Build the ops of the controller registers and the doorbells, the accesses
+/// are split into 4 bytes ones.
+pub fn build_nvme_ops(ctrl: &Arc<Mutex<NvmeCtrl>>) -> RegionOps {
+    let cloned_ctrl = ctrl.clone();
+    let nvme_read = move |data: &mut [u8], _addr: GuestAddress, offset: u64| -> bool {
+        let value = if offset >= NVME_REG_DBS {
+            0
+        } else {
+            cloned_ctrl.lock().unwrap().read_reg(offset & !0x3)
+        };
+        write_data_u32(data, value >> ((offset & 0x3) * 8))
+    };
+
+    let cloned_ctrl = ctrl.clone();
+    let nvme_write = move |data: &[u8], _addr: GuestAddress, offset: u64| -> bool {
+        let mut value = 0;
+        if !read_data_u32(data, &mut value) {
+            return false;
+        }
+        if data.len() != 4 || offset & 0x3 != 0 {
+            warn!(
+                "Unaligned write of nvme register, offset 0x{:x} len {}",
+                offset,
+                data.len()
+            );
+            return true;
+        }
+        let mut locked_ctrl = cloned_ctrl.lock().unwrap();
+        if offset >= NVME_REG_DBS {
+            locked_ctrl.write_doorbell(offset - NVME_REG_DBS, value);
+        } else {
+            locked_ctrl.write_reg(offset, value);
+        }
+        true
+    };
+
+    RegionOps {
+        read: Arc::new(nvme_read),
+        write: Arc::new(nvme_write),
+    }
+}
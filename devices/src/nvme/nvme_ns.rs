@@ -0,0 +1,287 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use byteorder::{ByteOrder, LittleEndian};
+use log::error;
+
+use super::nvme_ctrl::NvmeRequest;
+use super::{
+    NvmeDsmRange, NVME_IDENTIFY_DATA_SIZE, NVME_INTERNAL_DEV_ERROR, NVME_LBA_RANGE, NVME_LBA_SHIFT,
+    NVME_SUCCESS, NVME_UNRECOVERED_READ, NVME_WRITE_FAULT,
+};
+use block_backend::stats::{BlockAcctCookie, BlockAcctType, BlockStats};
+use block_backend::{
+    create_block_backend, create_nbd_backend, register_block_device, unregister_block_device,
+    BlockDevInfo, BlockDriverOps, BlockIoErrorCallback, BlockProperty,
+};
+use machine_manager::config::{is_nbd_path, DriveFile, NvmeNsConfig, VmConfig};
+use util::aio::{get_iov_size, Aio, AioCb, Iovec};
+
+#[derive(Clone)]
+pub struct NvmeCompleteCb {
+    req: Arc<NvmeRequest>,
+    /// Status of the request if the aio request fails.
+    err_status: u16,
+    stats: Arc<BlockStats>,
+    acct: BlockAcctCookie,
+}
+
+fn nvme_aio_complete(aiocb: &AioCb<NvmeCompleteCb>, ret: i64) -> Result<()> {
+    let complete_cb = &aiocb.iocompletecb;
+    complete_cb.stats.account_done(&complete_cb.acct, ret < 0);
+    let status = if ret < 0 {
+        error!("Failed to handle nvme request, ret {}", ret);
+        complete_cb.err_status
+    } else {
+        NVME_SUCCESS
+    };
+    complete_cb.req.done(status);
+    Ok(())
+}
+
+/// NVMe namespace backed by a drive, the logical block size is 512 bytes.
+pub struct NvmeNamespace {
+    pub nsid: u32,
+    config: NvmeNsConfig,
+    block_backend: Option<Arc<Mutex<dyn BlockDriverOps<NvmeCompleteCb>>>>,
+    /// Size of the namespace in logical blocks.
+    nsze: u64,
+    stats: Arc<BlockStats>,
+    drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+}
+
+impl NvmeNamespace {
+    pub fn new(
+        config: NvmeNsConfig,
+        nsid: u32,
+        drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    ) -> Self {
+        Self {
+            nsid,
+            config,
+            block_backend: None,
+            nsze: 0,
+            stats: Arc::new(BlockStats::default()),
+            drive_files,
+        }
+    }
+
+    pub fn realize(
+        &mut self,
+        iothread: Option<String>,
+        broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Result<()> {
+        let drive = &self.config.drive;
+        let is_nbd = is_nbd_path(&drive.path_on_host);
+        // The disk exported by an NBD server has no host file nor alignment requirement.
+        let (file, alignments) = if is_nbd {
+            (None, (1, 1))
+        } else {
+            let drive_files = self.drive_files.lock().unwrap();
+            (
+                Some(VmConfig::fetch_drive_file(
+                    &drive_files,
+                    &drive.path_on_host,
+                )?),
+                VmConfig::fetch_drive_align(&drive_files, &drive.path_on_host)?,
+            )
+        };
+        let aio = Aio::new_with_uring_config(Arc::new(nvme_aio_complete), drive.aio, drive.uring)?;
+        let prop = BlockProperty {
+            id: self.config.id.clone(),
+            path: drive.path_on_host.clone(),
+            format: drive.format,
+            iothread,
+            direct: drive.direct,
+            req_align: alignments.0,
+            buf_align: alignments.1,
+            discard: drive.discard,
+            write_zeroes: drive.write_zeroes,
+        };
+        let block_backend = match file {
+            Some(file) => create_block_backend(file, aio, prop.clone())?,
+            None => create_nbd_backend(aio, prop.clone())?,
+        };
+        let mut locked_backend = block_backend.lock().unwrap();
+        self.nsze = locked_backend.disk_size()? >> NVME_LBA_SHIFT;
+        locked_backend.register_io_event(broken, error_cb)?;
+        drop(locked_backend);
+        self.block_backend = Some(block_backend);
+
+        register_block_device(BlockDevInfo {
+            prop,
+            read_only: drive.read_only,
+            aio: drive.aio,
+            throttle: None,
+            throttle_group: None,
+            removable: false,
+            stats: self.stats.clone(),
+            resize: None,
+            backup: None,
+            dirty_bitmaps: None,
+            medium: None,
+        });
+        Ok(())
+    }
+
+    pub fn unrealize(&mut self) -> Result<()> {
+        if let Some(block_backend) = self.block_backend.take() {
+            block_backend.lock().unwrap().unregister_io_event()?;
+        }
+        unregister_block_device(&self.config.id);
+        Ok(())
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.config.drive.read_only
+    }
+
+    pub fn check_range(&self, slba: u64, nlb: u64) -> Result<(), u16> {
+        match slba.checked_add(nlb) {
+            Some(end) if end <= self.nsze => Ok(()),
+            _ => Err(NVME_LBA_RANGE),
+        }
+    }
+
+    /// Identify Namespace data structure.
+    pub fn identify(&self) -> Vec<u8> {
+        let mut data = vec![0_u8; NVME_IDENTIFY_DATA_SIZE];
+        // Namespace Size, Capacity and Utilization.
+        LittleEndian::write_u64(&mut data[0..8], self.nsze);
+        LittleEndian::write_u64(&mut data[8..16], self.nsze);
+        LittleEndian::write_u64(&mut data[16..24], self.nsze);
+        // The deallocated blocks are read as zeroes, and Write Zeroes supports
+        // deallocating blocks.
+        if self.config.drive.discard {
+            data[33] = 0x09;
+        }
+        // LBA Format 0: no metadata, 512 bytes data.
+        data[130] = NVME_LBA_SHIFT as u8;
+        data
+    }
+
+    fn submit<F>(
+        &self,
+        req: &Arc<NvmeRequest>,
+        acct: BlockAcctType,
+        bytes: u64,
+        err_status: u16,
+        f: F,
+    ) where
+        F: FnOnce(&mut dyn BlockDriverOps<NvmeCompleteCb>, NvmeCompleteCb) -> Result<()>,
+    {
+        let block_backend = match self.block_backend.as_ref() {
+            Some(block_backend) => block_backend,
+            None => {
+                req.start_aio();
+                req.done(NVME_INTERNAL_DEV_ERROR);
+                return;
+            }
+        };
+        let complete_cb = NvmeCompleteCb {
+            req: req.clone(),
+            err_status,
+            stats: self.stats.clone(),
+            acct: BlockAcctCookie::new(acct, bytes),
+        };
+        req.start_aio();
+        let mut locked_backend = block_backend.lock().unwrap();
+        if let Err(e) = f(&mut *locked_backend, complete_cb) {
+            error!(
+                "Failed to submit request of nvme namespace {}: {:?}",
+                self.config.id, e
+            );
+            req.done(err_status);
+        }
+    }
+
+    pub fn rw(&self, req: &Arc<NvmeRequest>, write: bool, slba: u64, iovecs: Vec<Iovec>) {
+        let offset = (slba << NVME_LBA_SHIFT) as usize;
+        let bytes = get_iov_size(&iovecs);
+        if write {
+            self.submit(
+                req,
+                BlockAcctType::Write,
+                bytes,
+                NVME_WRITE_FAULT,
+                |backend, cb| backend.write_vectored(iovecs, offset, cb),
+            );
+        } else {
+            self.submit(
+                req,
+                BlockAcctType::Read,
+                bytes,
+                NVME_UNRECOVERED_READ,
+                |backend, cb| backend.read_vectored(iovecs, offset, cb),
+            );
+        }
+    }
+
+    pub fn flush(&self, req: &Arc<NvmeRequest>) {
+        self.submit(
+            req,
+            BlockAcctType::Flush,
+            0,
+            NVME_WRITE_FAULT,
+            |backend, cb| backend.datasync(cb),
+        );
+    }
+
+    pub fn write_zeroes(&self, req: &Arc<NvmeRequest>, slba: u64, nlb: u64, deallocate: bool) {
+        let offset = (slba << NVME_LBA_SHIFT) as usize;
+        let bytes = nlb << NVME_LBA_SHIFT;
+        let unmap = deallocate && self.config.drive.discard;
+        self.submit(
+            req,
+            BlockAcctType::Write,
+            bytes,
+            NVME_WRITE_FAULT,
+            |backend, cb| backend.write_zeroes(offset, bytes, cb, unmap),
+        );
+    }
+
+    /// Deallocate the ranges, it's a hint and nothing is done if discard is disabled.
+    pub fn discard(&self, req: &Arc<NvmeRequest>, ranges: &[NvmeDsmRange]) {
+        if !self.config.drive.discard {
+            return;
+        }
+        for range in ranges {
+            let offset = (range.slba << NVME_LBA_SHIFT) as usize;
+            let bytes = (range.nlb as u64) << NVME_LBA_SHIFT;
+            self.submit(
+                req,
+                BlockAcctType::Unmap,
+                bytes,
+                NVME_WRITE_FAULT,
+                |backend, cb| backend.discard(offset, bytes, cb),
+            );
+        }
+    }
+
+    /// Submit the batched requests of the backend.
+    pub fn flush_request(&self) {
+        if let Some(block_backend) = self.block_backend.as_ref() {
+            if let Err(e) = block_backend.lock().unwrap().flush_request() {
+                error!(
+                    "Failed to flush requests of nvme namespace {}: {:?}",
+                    self.config.id, e
+                );
+            }
+        }
+    }
+}
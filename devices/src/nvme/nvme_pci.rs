@@ -0,0 +1,230 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp::max;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{bail, Context, Result};
+
+use super::nvme_ctrl::{build_nvme_ops, NvmeCtrl, NvmeIrq};
+use address_space::{AddressSpace, Region};
+use machine_manager::config::{DriveFile, NvmeConfig, NvmeNsConfig};
+use machine_manager::event_loop::EventLoop;
+use pci::config::{
+    PciConfig, RegionType, DEVICE_ID, MINMUM_BAR_SIZE_FOR_MMIO, PCI_CLASS_STORAGE_EXPRESS,
+    PCI_CONFIG_SPACE_SIZE, PCI_DEVICE_ID_REDHAT_NVME, PCI_VENDOR_ID_REDHAT, REVISION_ID,
+    SUB_CLASS_CODE, VENDOR_ID,
+};
+use pci::msix::update_dev_id;
+use pci::{init_intx, init_msix, le_write_u16, PciBus, PciDevOps};
+
+/// Programming interface of NVM Express.
+const PCI_CLASS_PI: usize = 0x09;
+const NVME_PROG_IF: u8 = 0x02;
+/// BAR0 layout.
+/// 0x0         0x1000      0x2000       0x3000      0x4000
+/// | registers | doorbells | MSIX table | MSIX PBA  |
+const NVME_BAR_SIZE: u64 = 0x4000;
+const NVME_REG_SIZE: u64 = 0x2000;
+const NVME_MSIX_TABLE_OFFSET: u32 = 0x2000;
+const NVME_MSIX_PBA_OFFSET: u32 = 0x3000;
+
+/// NVMe controller which can be attached to PCI bus.
+pub struct NvmePciDevice {
+    pci_config: PciConfig,
+    devfn: u8,
+    dev_id: Arc<AtomicU16>,
+    name: String,
+    parent_bus: Weak<Mutex<PciBus>>,
+    mem_region: Region,
+    config: NvmeConfig,
+    pub ctrl: Arc<Mutex<NvmeCtrl>>,
+}
+
+impl NvmePciDevice {
+    pub fn new(
+        config: &NvmeConfig,
+        devfn: u8,
+        parent_bus: Weak<Mutex<PciBus>>,
+        mem_space: &Arc<AddressSpace>,
+        drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    ) -> Self {
+        Self {
+            pci_config: PciConfig::new(PCI_CONFIG_SPACE_SIZE, 1),
+            devfn,
+            dev_id: Arc::new(AtomicU16::new(0)),
+            name: config.id.clone(),
+            parent_bus,
+            mem_region: Region::init_container_region(NVME_BAR_SIZE),
+            config: config.clone(),
+            ctrl: Arc::new(Mutex::new(NvmeCtrl::new(config, mem_space, drive_files))),
+        }
+    }
+
+    /// Attach a namespace described by `-device nvme-ns` to the controller.
+    pub fn add_namespace(&self, config: NvmeNsConfig) -> Result<()> {
+        self.ctrl
+            .lock()
+            .unwrap()
+            .add_namespace(config)
+            .with_context(|| format!("Failed to add namespace to nvme {}", self.name))
+    }
+
+    fn mem_region_init(&mut self) -> pci::Result<()> {
+        let mut reg_region = Region::init_io_region(NVME_REG_SIZE, build_nvme_ops(&self.ctrl));
+        reg_region.set_access_size(4);
+        pci::Result::with_context(self.mem_region.add_subregion(reg_region, 0), || {
+            "Failed to register nvme register region."
+        })?;
+        Ok(())
+    }
+}
+
+impl PciDevOps for NvmePciDevice {
+    fn init_write_mask(&mut self) -> pci::Result<()> {
+        self.pci_config.init_common_write_mask()
+    }
+
+    fn init_write_clear_mask(&mut self) -> pci::Result<()> {
+        self.pci_config.init_common_write_clear_mask()
+    }
+
+    fn realize(mut self) -> pci::Result<()> {
+        if self.config.iothread.is_some()
+            && EventLoop::get_ctx(self.config.iothread.as_ref()).is_none()
+        {
+            bail!(
+                "IOThread {:?} of nvme is not configured in params.",
+                self.config.iothread
+            );
+        }
+
+        self.init_write_mask()?;
+        self.init_write_clear_mask()?;
+        le_write_u16(
+            &mut self.pci_config.config,
+            VENDOR_ID as usize,
+            PCI_VENDOR_ID_REDHAT,
+        )?;
+        le_write_u16(
+            &mut self.pci_config.config,
+            DEVICE_ID as usize,
+            PCI_DEVICE_ID_REDHAT_NVME,
+        )?;
+        le_write_u16(&mut self.pci_config.config, REVISION_ID, 0x2_u16)?;
+        le_write_u16(
+            &mut self.pci_config.config,
+            SUB_CLASS_CODE as usize,
+            PCI_CLASS_STORAGE_EXPRESS,
+        )?;
+        self.pci_config.config[PCI_CLASS_PI] = NVME_PROG_IF;
+
+        #[cfg(target_arch = "aarch64")]
+        self.pci_config.set_interrupt_pin();
+
+        self.dev_id.store(self.devfn as u16, Ordering::SeqCst);
+        self.mem_region_init()?;
+
+        // One vector for the admin queue and one for each I/O queue.
+        init_msix(
+            0_usize,
+            self.config.queues as u32 + 1,
+            &mut self.pci_config,
+            self.dev_id.clone(),
+            &self.name,
+            Some(&self.mem_region),
+            Some((NVME_MSIX_TABLE_OFFSET, NVME_MSIX_PBA_OFFSET)),
+        )?;
+
+        init_intx(
+            self.name.clone(),
+            &mut self.pci_config,
+            self.parent_bus.clone(),
+            self.devfn,
+        )?;
+
+        let mem_region_size = max(
+            NVME_BAR_SIZE.next_power_of_two(),
+            MINMUM_BAR_SIZE_FOR_MMIO as u64,
+        );
+        self.pci_config.register_bar(
+            0_usize,
+            self.mem_region.clone(),
+            RegionType::Mem64Bit,
+            false,
+            mem_region_size,
+        )?;
+
+        // It is safe to unwrap, because they are initialized in init_msix and init_intx.
+        let msix = self.pci_config.msix.as_ref().unwrap().clone();
+        let intx = self.pci_config.intx.as_ref().unwrap().clone();
+        let irq = Arc::new(NvmeIrq::new(msix, intx, self.dev_id.clone()));
+        self.ctrl.lock().unwrap().set_irq(irq);
+        if let Some(ns_config) = self.config.namespace.clone() {
+            self.add_namespace(ns_config)?;
+        }
+
+        let devfn = self.devfn;
+        let dev = Arc::new(Mutex::new(self));
+        // Attach to the PCI bus.
+        let pci_bus = dev.lock().unwrap().parent_bus.upgrade().unwrap();
+        let mut locked_pci_bus = pci_bus.lock().unwrap();
+        if let Some(pci_device) = locked_pci_bus.devices.get(&devfn) {
+            let used_by = pci_device.lock().unwrap().name();
+            dev.lock().unwrap().unrealize()?;
+            bail!("Devfn {:?} has been used by {:?}", &devfn, used_by);
+        }
+        locked_pci_bus.devices.insert(devfn, dev);
+        Ok(())
+    }
+
+    fn unrealize(&mut self) -> pci::Result<()> {
+        self.ctrl.lock().unwrap().unrealize()
+    }
+
+    fn devfn(&self) -> Option<u8> {
+        Some(self.devfn)
+    }
+
+    fn read_config(&mut self, offset: usize, data: &mut [u8]) {
+        self.pci_config.read(offset, data);
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        update_dev_id(&self.parent_bus, self.devfn, &self.dev_id);
+        let parent_bus = self.parent_bus.upgrade().unwrap();
+        let locked_parent_bus = parent_bus.lock().unwrap();
+
+        self.pci_config.write(
+            offset,
+            data,
+            self.dev_id.clone().load(Ordering::Acquire),
+            #[cfg(target_arch = "x86_64")]
+            Some(&locked_parent_bus.io_region),
+            Some(&locked_parent_bus.mem_region),
+        );
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn reset(&mut self, _reset_child_device: bool) -> pci::Result<()> {
+        self.ctrl.lock().unwrap().hard_reset();
+
+        self.pci_config.reset()?;
+
+        Ok(())
+    }
+}
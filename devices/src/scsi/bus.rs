@@ -13,7 +13,6 @@
 use std::cmp;
 use std::collections::HashMap;
 use std::io::Write;
-use std::os::unix::io::AsRawFd;
 use std::sync::{Arc, Mutex};
 
 use anyhow::{anyhow, bail, Context, Result};
@@ -21,11 +20,12 @@ use byteorder::{BigEndian, ByteOrder};
 use log::{debug, error, info};
 
 use crate::ScsiDisk::{
-    ScsiDevice, DEFAULT_SECTOR_SIZE, SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT,
-    SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT, SCSI_DISK_F_DPOFUA, SCSI_DISK_F_REMOVABLE, SCSI_TYPE_DISK,
+    ScsiDevice, DEFAULT_SECTOR_SIZE, SCSI_DISK_F_DPOFUA, SCSI_DISK_F_REMOVABLE, SCSI_TYPE_DISK,
     SCSI_TYPE_ROM, SECTOR_SHIFT,
 };
-use util::aio::{AioCb, Iovec, OpCode, WriteZeroesState};
+use block_backend::stats::{BlockAcctCookie, BlockAcctType, BlockStats};
+use machine_manager::config::ScsiPassthrough;
+use util::aio::{iov_to_buf_direct, AioCb, Iovec};
 use util::AsAny;
 
 /// Scsi Operation code.
@@ -175,6 +175,14 @@ pub const SCSI_SENSE_BUF_SIZE: usize = 252;
 /// SERVICE ACTION IN subcodes.
 pub const SUBCODE_READ_CAPACITY_16: u8 = 0x10;
 
+/// The UNMAP bit in byte 1 of WRITE SAME, the blocks can be unmapped.
+const WRITE_SAME_UNMAP: u8 = 0x08;
+/// The NDOB(No Data-Out Buffer) bit in byte 1 of WRITE SAME(16), the blocks are zeroed.
+const WRITE_SAME_NDOB: u8 = 0x01;
+/// UNMAP parameter list: 8 bytes header followed by the block descriptors of 16 bytes.
+const UNMAP_PARAM_HEADER_LEN: usize = 8;
+const UNMAP_BLOCK_DESC_LEN: usize = 16;
+
 /// Sense Keys.
 pub const NO_SENSE: u8 = 0x00;
 pub const RECOVERED_ERROR: u8 = 0x01;
@@ -249,6 +257,20 @@ pub struct ScsiSense {
     pub ascq: u8,
 }
 
+impl ScsiSense {
+    /// Parse the raw sense data returned by the host device, both the fixed format
+    /// and the descriptor format are supported.
+    pub fn from_sense_data(data: &[u8]) -> Option<ScsiSense> {
+        match data.first()? & 0x7f {
+            // Fixed format: current(0x70) or deferred(0x71) errors.
+            0x70 | 0x71 if data.len() >= 14 => Some(scsisense!(data[2] & 0xf, data[12], data[13])),
+            // Descriptor format: current(0x72) or deferred(0x73) errors.
+            0x72 | 0x73 if data.len() >= 4 => Some(scsisense!(data[1] & 0xf, data[2], data[3])),
+            _ => None,
+        }
+    }
+}
+
 /// Mode page codes for mode sense/set.
 pub const MODE_PAGE_R_W_ERROR: u8 = 0x01;
 pub const MODE_PAGE_HD_GEOMETRY: u8 = 0x04;
@@ -456,6 +478,10 @@ pub struct ScsiCommand {
 #[derive(Clone)]
 pub struct ScsiCompleteCb {
     pub req: Arc<Mutex<ScsiRequest>>,
+    /// I/O statistics of the scsi device.
+    pub stats: Arc<BlockStats>,
+    /// Accounting information of the request.
+    pub acct: BlockAcctCookie,
 }
 
 pub fn aio_complete_cb(aiocb: &AioCb<ScsiCompleteCb>, ret: i64) -> Result<()> {
@@ -465,7 +491,10 @@ pub fn aio_complete_cb(aiocb: &AioCb<ScsiCompleteCb>, ret: i64) -> Result<()> {
         (GOOD, None)
     };
 
-    let sreq = &mut aiocb.iocompletecb.req.lock().unwrap();
+    let complete_cb = &aiocb.iocompletecb;
+    complete_cb.stats.account_done(&complete_cb.acct, ret < 0);
+
+    let sreq = &mut complete_cb.req.lock().unwrap();
     sreq.upper_req
         .as_mut()
         .scsi_request_complete_cb(status, sense)?;
@@ -475,6 +504,17 @@ pub fn aio_complete_cb(aiocb: &AioCb<ScsiCompleteCb>, ret: i64) -> Result<()> {
 pub trait ScsiRequestOps: AsAny {
     // Will be called in the end of this scsi instruction execution.
     fn scsi_request_complete_cb(&mut self, status: u8, scsisense: Option<ScsiSense>) -> Result<()>;
+
+    // Will be called in the end of the scsi instruction passed through to the host device,
+    // with the raw sense data and the residual bytes returned by the host device.
+    fn scsi_request_complete_passthrough(
+        &mut self,
+        status: u8,
+        sense: &[u8],
+        _resid: u32,
+    ) -> Result<()> {
+        self.scsi_request_complete_cb(status, ScsiSense::from_sense_data(sense))
+    }
 }
 
 pub struct ScsiRequest {
@@ -500,19 +540,37 @@ impl ScsiRequest {
         scsidevice: Arc<Mutex<ScsiDevice>>,
         upper_req: Box<dyn ScsiRequestOps>,
     ) -> Result<Self> {
+        let mut dev_lock = scsidevice.lock().unwrap();
+        dev_lock.update_capacity()?;
+        dev_lock.update_medium()?;
+        drop(dev_lock);
         let cmd = scsi_bus_parse_req_cdb(cdb, scsidevice.clone()).with_context(|| "Error cdb!")?;
         let op = cmd.op;
-        let opstype = scsi_operation_type(op);
+        let mut opstype = scsi_operation_type(op);
+
+        // The commands of the lun are passed through to the host device, except that the
+        // read and write commands of scsi-block are handled by the block backend.
+        let dev_lock = scsidevice.lock().unwrap();
+        let passthrough = match dev_lock.config.passthrough {
+            Some(ScsiPassthrough::Generic) => true,
+            // The host device knows how to release its own space.
+            Some(ScsiPassthrough::Block) => {
+                opstype == EMULATE_SCSI_OPS || matches!(op, UNMAP | WRITE_SAME_10 | WRITE_SAME_16)
+            }
+            None => false,
+        };
+        if passthrough && req_lun == dev_lock.config.lun && op != REPORT_LUNS {
+            opstype = PASSTHROUGH_SCSI_OPS;
+        }
+        drop(dev_lock);
 
-        if op == WRITE_10 || op == READ_10 {
+        // The request without medium is completed with NO MEDIUM sense when executed.
+        let has_medium = scsidevice.lock().unwrap().block_backend.is_some();
+        if (op == WRITE_10 || op == READ_10) && has_medium {
             let dev_lock = scsidevice.lock().unwrap();
             let disk_size = dev_lock.disk_sectors << SECTOR_SHIFT;
-            let disk_type = dev_lock.scsi_type;
+            let offset_shift = dev_lock.block_size.trailing_zeros();
             drop(dev_lock);
-            let offset_shift = match disk_type {
-                SCSI_TYPE_DISK => SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT,
-                _ => SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT,
-            };
             let offset = cmd
                 .lba
                 .checked_shl(offset_shift)
@@ -540,54 +598,134 @@ impl ScsiRequest {
         })
     }
 
-    pub fn execute(self) -> Result<Arc<Mutex<ScsiRequest>>> {
+    /// Complete the request with the pending unit attention condition of the device.
+    /// INQUIRY, REPORT LUNS, REQUEST SENSE, GET CONFIGURATION and GET EVENT STATUS
+    /// NOTIFICATION don't report and clear the condition.
+    fn report_unit_attention(&mut self) -> Result<bool> {
+        if matches!(
+            self.cmd.op,
+            INQUIRY
+                | REPORT_LUNS
+                | REQUEST_SENSE
+                | GET_CONFIGURATION
+                | GET_EVENT_STATUS_NOTIFICATION
+        ) {
+            return Ok(false);
+        }
+        let mut locked_dev = self.dev.lock().unwrap();
+        if self.req_lun != locked_dev.config.lun {
+            return Ok(false);
+        }
+        let sense = locked_dev.unit_attention.take();
+        if sense.is_some() {
+            locked_dev.unit_attention_reported();
+        }
+        drop(locked_dev);
+        match sense {
+            Some(sense) => {
+                self.upper_req
+                    .as_mut()
+                    .scsi_request_complete_cb(CHECK_CONDITION, Some(sense))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn execute(mut self) -> Result<Arc<Mutex<ScsiRequest>>> {
+        if self.report_unit_attention()? {
+            return Ok(Arc::new(Mutex::new(self)));
+        }
+        if self.opstype == PASSTHROUGH_SCSI_OPS {
+            return self.passthrough_execute();
+        }
         let mode = self.cmd.mode.clone();
         let op = self.cmd.op;
         let dev = self.dev.clone();
         let locked_dev = dev.lock().unwrap();
-        let mut aio = locked_dev.aio.as_ref().unwrap().lock().unwrap();
+        if !locked_dev.medium_available() {
+            drop(locked_dev);
+            self.upper_req
+                .as_mut()
+                .scsi_request_complete_cb(CHECK_CONDITION, Some(SCSI_SENSE_NO_MEDIUM))?;
+            return Ok(Arc::new(Mutex::new(self)));
+        }
+        let discard_range = if matches!(op, UNMAP | WRITE_SAME_10 | WRITE_SAME_16) {
+            match self.discard_range(&locked_dev) {
+                Ok(Some(range)) => Some(range),
+                res => {
+                    drop(locked_dev);
+                    let (status, sense) = match res {
+                        Err(sense) => (CHECK_CONDITION, Some(sense)),
+                        _ => (GOOD, None),
+                    };
+                    self.upper_req
+                        .as_mut()
+                        .scsi_request_complete_cb(status, sense)?;
+                    return Ok(Arc::new(Mutex::new(self)));
+                }
+            }
+        } else {
+            None
+        };
+        let mut locked_backend = locked_dev.block_backend.as_ref().unwrap().lock().unwrap();
         let s_req = Arc::new(Mutex::new(self));
 
-        let offset = match locked_dev.scsi_type {
-            SCSI_TYPE_DISK => SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT,
-            _ => SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT,
-        };
+        let offset = locked_dev.block_size.trailing_zeros();
         let locked_req = s_req.lock().unwrap();
-
-        let mut aiocb = AioCb {
-            direct: locked_dev.config.direct,
-            req_align: locked_dev.req_align,
-            buf_align: locked_dev.buf_align,
-            file_fd: locked_dev.disk_image.as_ref().unwrap().as_raw_fd(),
-            opcode: OpCode::Noop,
-            iovec: locked_req.iovec.clone(),
-            offset: (locked_req.cmd.lba << offset) as usize,
-            nbytes: locked_req.cmd.xfer as u64,
-            user_data: 0,
-            iocompletecb: ScsiCompleteCb { req: s_req.clone() },
-            discard: false,
-            write_zeroes: WriteZeroesState::Off,
-            write_zeroes_unmap: false,
+        let iovecs = locked_req.iovec.clone();
+        let offset = (locked_req.cmd.lba << offset) as usize;
+        let datalen = match discard_range {
+            Some((_, nbytes, _)) => nbytes,
+            None => u64::from(locked_req.datalen),
         };
         drop(locked_req);
+        let acct_type = if op == SYNCHRONIZE_CACHE {
+            BlockAcctType::Flush
+        } else if op == UNMAP {
+            BlockAcctType::Unmap
+        } else if mode == ScsiXferMode::ScsiXferToDev {
+            BlockAcctType::Write
+        } else {
+            BlockAcctType::Read
+        };
+        let completecb = ScsiCompleteCb {
+            req: s_req.clone(),
+            stats: locked_dev.stats.clone(),
+            acct: BlockAcctCookie::new(acct_type, datalen),
+        };
 
         if op == SYNCHRONIZE_CACHE {
-            aiocb.opcode = OpCode::Fdsync;
-            aio.submit_request(aiocb)
+            locked_backend
+                .datasync(completecb)
                 .with_context(|| "Failed to process scsi request for flushing")?;
-            aio.flush_request()?;
+            locked_backend.flush_request()?;
+            return Ok(s_req);
+        }
+
+        if let Some((offset, nbytes, unmap)) = discard_range {
+            if op == UNMAP {
+                locked_backend
+                    .discard(offset, nbytes, completecb)
+                    .with_context(|| "Failed to process scsi request for unmap")?;
+            } else {
+                locked_backend
+                    .write_zeroes(offset, nbytes, completecb, unmap)
+                    .with_context(|| "Failed to process scsi request for write same")?;
+            }
+            locked_backend.flush_request()?;
             return Ok(s_req);
         }
 
         match mode {
             ScsiXferMode::ScsiXferFromDev => {
-                aiocb.opcode = OpCode::Preadv;
-                aio.submit_request(aiocb)
+                locked_backend
+                    .read_vectored(iovecs, offset, completecb)
                     .with_context(|| "Failed to process scsi request for reading")?;
             }
             ScsiXferMode::ScsiXferToDev => {
-                aiocb.opcode = OpCode::Pwritev;
-                aio.submit_request(aiocb)
+                locked_backend
+                    .write_vectored(iovecs, offset, completecb)
                     .with_context(|| "Failed to process block request for writing")?;
             }
             _ => {
@@ -595,10 +733,118 @@ impl ScsiRequest {
             }
         }
 
-        aio.flush_request()?;
+        locked_backend.flush_request()?;
         Ok(s_req)
     }
 
+    /// Get the byte range of UNMAP or WRITE SAME, and whether the range can be unmapped.
+    /// None if there is nothing to do, and the sense is returned if the command is invalid.
+    fn discard_range(
+        &self,
+        dev: &ScsiDevice,
+    ) -> std::result::Result<Option<(usize, u64, bool)>, ScsiSense> {
+        if dev.config.read_only {
+            return Err(SCSI_SENSE_WRITE_PROTECTED);
+        }
+        let (lba, nb_blocks, unmap) = if self.cmd.op == UNMAP {
+            if !dev.config.discard {
+                return Err(SCSI_SENSE_INVALID_OPCODE);
+            }
+            let len = cmp::min(self.cmd.xfer, self.datalen) as usize;
+            if len == 0 {
+                return Ok(None);
+            }
+            if len < UNMAP_PARAM_HEADER_LEN {
+                return Err(SCSI_SENSE_INVALID_PARAM_LEN);
+            }
+            let mut param = vec![0_u8; len];
+            match iov_to_buf_direct(&self.iovec, &mut param) {
+                Ok(size) if size == len => {}
+                _ => return Err(SCSI_SENSE_INVALID_PARAM_LEN),
+            }
+            // Bytes[2-3]: Block descriptor data length.
+            let desc_len = BigEndian::read_u16(&param[2..4]) as usize;
+            if desc_len == 0 {
+                return Ok(None);
+            }
+            // Only one block descriptor is supported, as reported in the Block Limits VPD page.
+            if desc_len != UNMAP_BLOCK_DESC_LEN
+                || len < UNMAP_PARAM_HEADER_LEN + UNMAP_BLOCK_DESC_LEN
+            {
+                return Err(SCSI_SENSE_INVALID_PARAM);
+            }
+            // Block descriptor: Bytes[0-7]: LBA, Bytes[8-11]: Number of logical blocks.
+            let desc = &param[UNMAP_PARAM_HEADER_LEN..];
+            let nb_blocks = u64::from(BigEndian::read_u32(&desc[8..12]));
+            if nb_blocks == 0 {
+                return Ok(None);
+            }
+            (BigEndian::read_u64(&desc[0..8]), nb_blocks, true)
+        } else {
+            let nb_blocks = match self.cmd.op {
+                WRITE_SAME_10 => u64::from(BigEndian::read_u16(&self.cmd.buf[7..9])),
+                _ => u64::from(BigEndian::read_u32(&self.cmd.buf[10..14])),
+            };
+            // WSNZ is set in the Block Limits VPD page, the number of blocks can't be zero.
+            if nb_blocks == 0 {
+                return Err(SCSI_SENSE_INVALID_FIELD);
+            }
+            let ndob = self.cmd.op == WRITE_SAME_16 && self.cmd.buf[1] & WRITE_SAME_NDOB != 0;
+            if !ndob {
+                let mut data = vec![0_u8; dev.block_size as usize];
+                match iov_to_buf_direct(&self.iovec, &mut data) {
+                    Ok(size) if size == data.len() => {}
+                    _ => return Err(SCSI_SENSE_INVALID_PARAM_LEN),
+                }
+                // Only zeroes can be written, which is the way the guests use WRITE SAME.
+                if data.iter().any(|b| *b != 0) {
+                    return Err(SCSI_SENSE_INVALID_FIELD);
+                }
+            }
+            let unmap = self.cmd.buf[1] & WRITE_SAME_UNMAP != 0 && dev.config.discard;
+            (self.cmd.lba, nb_blocks, unmap)
+        };
+
+        let shift = dev.block_size.trailing_zeros();
+        let nb_total = (dev.disk_sectors << SECTOR_SHIFT) >> shift;
+        if lba
+            .checked_add(nb_blocks)
+            .filter(|&end| end <= nb_total)
+            .is_none()
+        {
+            return Err(SCSI_SENSE_LBA_OUT_OF_RANGE);
+        }
+        Ok(Some(((lba << shift) as usize, nb_blocks << shift, unmap)))
+    }
+
+    /// Pass the command through to the host device synchronously.
+    fn passthrough_execute(mut self) -> Result<Arc<Mutex<ScsiRequest>>> {
+        debug!("passthrough scsi command is {:#x}", self.cmd.op);
+        let dev = self.dev.clone();
+        let locked_dev = dev.lock().unwrap();
+        // It's safe to unwrap, the passthrough request is only created for the device
+        // with the host device.
+        let res =
+            locked_dev
+                .generic
+                .as_ref()
+                .unwrap()
+                .execute(&self.cmd, &self.iovec, self.datalen);
+        drop(locked_dev);
+
+        if res.status == CHECK_CONDITION && res.sense.is_empty() {
+            // The host device failed without sense, such as the transport errors.
+            self.upper_req
+                .as_mut()
+                .scsi_request_complete_cb(CHECK_CONDITION, Some(SCSI_SENSE_IO_ERROR))?;
+        } else {
+            self.upper_req
+                .as_mut()
+                .scsi_request_complete_passthrough(res.status, &res.sense, res.resid)?;
+        }
+        Ok(Arc::new(Mutex::new(self)))
+    }
+
     fn emulate_target_execute(
         &self,
         not_supported_flag: &mut bool,
@@ -628,24 +874,30 @@ impl ScsiRequest {
         not_supported_flag: &mut bool,
         sense: &mut Option<ScsiSense>,
     ) -> Result<Vec<u8>> {
+        // These commands can be executed without medium.
+        if !matches!(
+            self.cmd.op,
+            INQUIRY
+                | MODE_SENSE_10
+                | START_STOP
+                | ALLOW_MEDIUM_REMOVAL
+                | GET_CONFIGURATION
+                | GET_EVENT_STATUS_NOTIFICATION
+                | REQUEST_SENSE
+        ) && !self.dev.lock().unwrap().medium_available()
+        {
+            *sense = Some(SCSI_SENSE_NO_MEDIUM);
+            bail!("No medium in scsi device");
+        }
+
         match self.cmd.op {
             REQUEST_SENSE => {
                 *sense = Some(SCSI_SENSE_NO_SENSE);
                 Ok(Vec::new())
             }
-            TEST_UNIT_READY => {
-                let dev_lock = self.dev.lock().unwrap();
-                if dev_lock.disk_image.is_none() {
-                    Err(anyhow!("No scsi backend!"))
-                } else {
-                    Ok(Vec::new())
-                }
-            }
-            // Do not support SCSI_DISK_F_REMOVABLE now.
-            // Return Ok is enough for START_STOP/ALLOW_MEDIUM_REMOVAL.
-            // TODO: implement SCSI_DISK_F_REMOVABLE.
-            START_STOP => Ok(Vec::new()),
-            ALLOW_MEDIUM_REMOVAL => Ok(Vec::new()),
+            TEST_UNIT_READY => Ok(Vec::new()),
+            START_STOP => scsi_command_emulate_start_stop(&self.cmd, &self.dev, sense),
+            ALLOW_MEDIUM_REMOVAL => scsi_command_emulate_allow_medium_removal(&self.cmd, &self.dev),
             INQUIRY => scsi_command_emulate_inquiry(&self.cmd, &self.dev),
             READ_CAPACITY_10 => scsi_command_emulate_read_capacity_10(&self.cmd, &self.dev),
             MODE_SENSE | MODE_SENSE_10 => scsi_command_emulate_mode_sense(&self.cmd, &self.dev),
@@ -667,6 +919,9 @@ impl ScsiRequest {
 
     pub fn emulate_execute(mut self) -> Result<Arc<Mutex<ScsiRequest>>> {
         debug!("emulate scsi command is {:#x}", self.cmd.op);
+        if self.report_unit_attention()? {
+            return Ok(Arc::new(Mutex::new(self)));
+        }
         let mut not_supported_flag = false;
         let mut sense = None;
         let mut status = GOOD;
@@ -690,6 +945,10 @@ impl ScsiRequest {
                     info!("emulation scsi command {:#x} is no supported", self.cmd.op);
                     status = CHECK_CONDITION;
                     sense = Some(SCSI_SENSE_INVALID_OPCODE);
+                } else if sense.is_some() {
+                    // The sense has been set by the command, such as NO MEDIUM.
+                    debug!("Scsi command {:#x} failed, err is {:?}", self.cmd.op, e);
+                    status = CHECK_CONDITION;
                 } else {
                     error!(
                         "Error in processing scsi command {:#x}, err is {:?}",
@@ -747,13 +1006,14 @@ fn outbuf_to_iov(command: u8, outbuf: &[u8], iovec: &[Iovec]) -> Result<()> {
 pub const EMULATE_SCSI_OPS: u32 = 0;
 // Scsi Commands which will do something(eg: read and write) to the backend.
 pub const NON_EMULATE_SCSI_OPS: u32 = 1;
+/// Passed through to the host scsi device.
+pub const PASSTHROUGH_SCSI_OPS: u32 = 2;
 
 fn scsi_operation_type(op: u8) -> u32 {
     match op {
         READ_6 | READ_10 | READ_12 | READ_16 | WRITE_6 | WRITE_10 | WRITE_12 | WRITE_16
-        | WRITE_VERIFY_10 | WRITE_VERIFY_12 | WRITE_VERIFY_16 | SYNCHRONIZE_CACHE => {
-            NON_EMULATE_SCSI_OPS
-        }
+        | WRITE_VERIFY_10 | WRITE_VERIFY_12 | WRITE_VERIFY_16 | SYNCHRONIZE_CACHE | UNMAP
+        | WRITE_SAME_10 | WRITE_SAME_16 => NON_EMULATE_SCSI_OPS,
         _ => EMULATE_SCSI_OPS,
     }
 }
@@ -813,6 +1073,14 @@ fn scsi_cdb_xfer(cdb: &[u8; SCSI_CMD_BUF_SIZE], dev: Arc<Mutex<ScsiDevice>>) ->
         WRITE_10 | WRITE_12 | WRITE_16 | READ_10 | READ_12 | READ_16 => {
             xfer *= block_size;
         }
+        WRITE_SAME_10 | WRITE_SAME_16 => {
+            // One logical block is transferred, and none if NDOB is set.
+            xfer = if cdb[0] == WRITE_SAME_16 && cdb[1] & WRITE_SAME_NDOB != 0 {
+                0
+            } else {
+                block_size
+            };
+        }
         INQUIRY => {
             xfer = i32::from(cdb[4]) | i32::from(cdb[3]) << 8;
         }
@@ -981,6 +1249,12 @@ fn scsi_command_emulate_vpd_page(
             outbuf[4] = 1;
             let max_xfer_length: u32 = u32::MAX / 512;
             BigEndian::write_u32(&mut outbuf[8..12], max_xfer_length);
+            if dev_lock.config.discard {
+                // One block descriptor in UNMAP, the same as virtio-blk discard.
+                BigEndian::write_u32(&mut outbuf[20..24], max_xfer_length);
+                BigEndian::write_u32(&mut outbuf[24..28], 1);
+                BigEndian::write_u32(&mut outbuf[28..32], 1);
+            }
             BigEndian::write_u64(&mut outbuf[36..44], max_xfer_length as u64);
             buflen = outbuf.len();
         }
@@ -996,10 +1270,15 @@ fn scsi_command_emulate_vpd_page(
         0xb2 => {
             // Logical Block Provisioning.
             // 0: Threshold exponent.
-            // 0xe0: LBPU(bit 7) | LBPWS | LBPWS10 | LBPRZ | ANC_SUP | DP.
-            // 0: Threshold percentage | Provisioning Type.
-            // 0: Threshold percentage.
-            outbuf.append(&mut [0_u8, 0x60_u8, 1_u8, 0_u8].to_vec());
+            // 0xe4: LBPU(bit 7) | LBPWS | LBPWS10 | Reserved(bit 4 - bit 3) | LBPRZ | ANC_SUP | DP.
+            // 1: Reserved(bit 7 - bit 3) | Provisioning Type(thin provisioned).
+            // 0: Reserved.
+            // The unmapped blocks of the sparse image are read as zeroes.
+            if dev_lock.config.discard {
+                outbuf.append(&mut [0_u8, 0xe4_u8, 1_u8, 0_u8].to_vec());
+            } else {
+                outbuf.append(&mut [0_u8, 0_u8, 0_u8, 0_u8].to_vec());
+            }
             buflen = 8;
         }
         _ => {
@@ -1093,10 +1372,10 @@ fn scsi_command_emulate_inquiry(
     let dev_lock = dev.lock().unwrap();
 
     outbuf[0] = (dev_lock.scsi_type & 0x1f) as u8;
-    outbuf[1] = match dev_lock.state.features & SCSI_DISK_F_REMOVABLE {
-        1 => 0x80,
-        _ => 0,
-    };
+    if dev_lock.state.features & (1 << SCSI_DISK_F_REMOVABLE) != 0 {
+        // Byte1: Bit7: RMB(Removable Medium).
+        outbuf[1] = 0x80;
+    }
 
     let product_bytes = dev_lock.state.product.as_bytes();
     let product_len = cmp::min(product_bytes.len(), SCSI_INQUIRY_PRODUCT_MAX_LEN);
@@ -1168,6 +1447,7 @@ fn scsi_command_emulate_mode_sense(
     let mut nb_sectors = dev_lock.disk_sectors as u32;
     let scsi_type = dev_lock.scsi_type;
     let block_size = dev_lock.block_size;
+    let locked = dev_lock.medium.lock().unwrap().locked;
     nb_sectors /= block_size / DEFAULT_SECTOR_SIZE;
 
     debug!(
@@ -1232,10 +1512,22 @@ fn scsi_command_emulate_mode_sense(
     if page_code == 0x3f {
         // 3Fh Return all pages not including subpages.
         for pg in 0..page_code {
-            let _ = scsi_command_emulate_mode_sense_page(pg, page_control, &mut outbuf, scsi_type);
+            let _ = scsi_command_emulate_mode_sense_page(
+                pg,
+                page_control,
+                &mut outbuf,
+                scsi_type,
+                locked,
+            );
         }
     } else {
-        scsi_command_emulate_mode_sense_page(page_code, page_control, &mut outbuf, scsi_type)?;
+        scsi_command_emulate_mode_sense_page(
+            page_code,
+            page_control,
+            &mut outbuf,
+            scsi_type,
+            locked,
+        )?;
     }
 
     // The Mode Data Length field indicates the length in bytes of the following data
@@ -1257,6 +1549,7 @@ fn scsi_command_emulate_mode_sense_page(
     page_control: u8,
     outbuf: &mut Vec<u8>,
     scsi_type: u32,
+    locked: bool,
 ) -> Result<Vec<u8>> {
     if scsi_type == SCSI_TYPE_DISK
         && ![
@@ -1345,8 +1638,9 @@ fn scsi_command_emulate_mode_sense_page(
             outbuf[buflen + 2] = 0x3b;
             outbuf[buflen + 4] = 0x7f;
             outbuf[buflen + 5] = 0xff;
-            // Stratovirt does not implement tray for CD, so set "Lock State" to 0.
-            outbuf[buflen + 6] = 0x2d;
+            // Byte[buflen + 6]: Bit1: Lock State, the medium is locked by PREVENT ALLOW
+            // MEDIUM REMOVAL command.
+            outbuf[buflen + 6] = 0x2d | (locked as u8) << 1;
             BigEndian::write_u16(&mut outbuf[(buflen + 10)..(buflen + 12)], 2);
             BigEndian::write_u16(&mut outbuf[(buflen + 12)..(buflen + 14)], 2048);
         }
@@ -1425,13 +1719,18 @@ fn scsi_command_emulate_service_action_in_16(
         let mut nb_sectors = dev_lock.disk_sectors;
         nb_sectors /= (block_size / DEFAULT_SECTOR_SIZE) as u64;
         nb_sectors -= 1;
+        let discard = dev_lock.config.discard;
 
         drop(dev_lock);
 
         // Byte[0-7]: Returned Logical BLock Address(the logical block address of the last logical block).
         // Byte[8-11]: Logical Block Length in Bytes.
+        // Byte[14]: bit 7: LBPME(Logical Block Provisioning Management Enabled), bit 6: LBPRZ.
         BigEndian::write_u64(&mut outbuf[0..8], nb_sectors);
         BigEndian::write_u32(&mut outbuf[8..12], block_size);
+        if discard {
+            outbuf[14] = 0xc0;
+        }
 
         return Ok(outbuf);
     }
@@ -1559,7 +1858,10 @@ fn scsi_command_emulate_get_configuration(
     // Bytes[4-5]: Reserved.
     // Bytes[6-7]: Current Profile.
     BigEndian::write_u32(&mut outbuf[0..4], 36);
-    let current = if dev_lock.disk_sectors > CD_MAX_SECTORS as u64 {
+    let current = if !dev_lock.medium_available() {
+        // No current profile without medium.
+        0
+    } else if dev_lock.disk_sectors > CD_MAX_SECTORS as u64 {
         GC_PROFILE_DVD_ROM
     } else {
         GC_PROFILE_CD_ROM
@@ -1616,6 +1918,43 @@ fn scsi_command_emulate_get_configuration(
     Ok(outbuf)
 }
 
+fn scsi_command_emulate_start_stop(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+    sense: &mut Option<ScsiSense>,
+) -> Result<Vec<u8>> {
+    // Byte4: Bits[4-7]: Power Condition. Bit1: LOEJ(Load Eject). Bit0: Start.
+    let start = cmd.buf[4] & 1 != 0;
+    let loej = cmd.buf[4] & 2 != 0;
+    let power_condition = cmd.buf[4] & 0xf0;
+
+    let mut dev_lock = dev.lock().unwrap();
+    if dev_lock.scsi_type != SCSI_TYPE_ROM || power_condition != 0 || !loej {
+        return Ok(Vec::new());
+    }
+
+    if !start && dev_lock.medium.lock().unwrap().locked {
+        *sense = Some(if dev_lock.block_backend.is_some() {
+            SCSI_SENSE_ILLEGAL_REQ_REMOVAL_PREVENTED
+        } else {
+            SCSI_SENSE_NOT_READY_REMOVAL_PREVENTED
+        });
+        bail!("The medium of scsi device {} is locked", dev_lock.config.id);
+    }
+    dev_lock.load_eject(start);
+
+    Ok(Vec::new())
+}
+
+fn scsi_command_emulate_allow_medium_removal(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+) -> Result<Vec<u8>> {
+    // Byte4: Bits[0-1]: Prevent. Bit0 = 1: prevent the medium from being removed.
+    dev.lock().unwrap().medium.lock().unwrap().locked = cmd.buf[4] & 1 != 0;
+    Ok(Vec::new())
+}
+
 fn scsi_command_emulate_get_event_status_notification(
     cmd: &ScsiCommand,
     dev: &Arc<Mutex<ScsiDevice>>,
@@ -1623,6 +1962,7 @@ fn scsi_command_emulate_get_event_status_notification(
     // Byte4: Notification Class Request.
     let notification_class_request = cmd.buf[4];
     let dev_lock = dev.lock().unwrap();
+    let present = dev_lock.block_backend.is_some();
 
     if dev_lock.scsi_type != SCSI_TYPE_ROM {
         bail!("Invalid scsi type {}", dev_lock.scsi_type);
@@ -1655,11 +1995,9 @@ fn scsi_command_emulate_get_event_status_notification(
         // Byte5: Media Status. Bits[2-7] reserved. Bit 1: Media Present. Bit 0: Door or Tray open.
         // Byte6: Start Slot.
         // Byte7: End Slot.
-
-        // Do not support hot-plug/hot-unplug scsi cd which will be present all the time once vm starts.
-        // To do: this outbuf event code and media status should be changed after allowing hot-plug.
-        outbuf[4] = GESN_EC_NOCHG;
-        outbuf[5] = 1 << GESN_MS_MEDIA_PRESENT_BIT;
+        let (event_code, media_status) = dev_lock.medium.lock().unwrap().take_media_event(present);
+        outbuf[4] = event_code;
+        outbuf[5] = media_status;
     } else {
         // NCE = 1.
         outbuf[2] = 0x80;
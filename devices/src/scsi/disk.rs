@@ -11,15 +11,29 @@
 // See the Mulan PSL v2 for more details.
 
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileTypeExt;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, Weak};
 
 use anyhow::{bail, Context, Result};
+use log::info;
 
-use crate::ScsiBus::{ScsiBus, ScsiCompleteCb};
-use machine_manager::config::{DriveFile, ScsiDevConfig, VmConfig};
-use util::aio::Aio;
+use crate::ScsiBus::{
+    aio_complete_cb, ScsiBus, ScsiCompleteCb, ScsiSense, GESN_EC_EJECTREQUEST, GESN_EC_NEWMEDIA,
+    GESN_EC_NOCHG, GESN_MS_DOOR_OR_TRAY_OPEN_BIT, GESN_MS_MEDIA_PRESENT_BIT,
+    SCSI_SENSE_CAPACITY_CHANGED, SCSI_SENSE_MEDIUM_CHANGED, SCSI_SENSE_UNIT_ATTENTION_NO_MEDIUM,
+};
+use crate::ScsiGeneric::ScsiGeneric;
+use block_backend::stats::BlockStats;
+use block_backend::{
+    create_block_backend, register_block_device, unregister_block_device, update_block_device,
+    BlockDevInfo, BlockDriverOps, BlockIoErrorCallback, BlockMediumOps, BlockProperty,
+    BlockResizeCallback,
+};
+use machine_manager::config::{DiskFormat, DriveFile, ScsiDevConfig, ScsiPassthrough, VmConfig};
+use machine_manager::event;
+use machine_manager::qmp::{qmp_schema, QmpChannel};
+use util::aio::{Aio, AioEngine, IoUringConfig};
 
 /// SCSI DEVICE TYPES.
 pub const SCSI_TYPE_DISK: u32 = 0x00;
@@ -85,17 +99,229 @@ impl ScsiDevState {
     }
 }
 
+/// The medium inserted or removed by qmp, which is not switched to by the device yet.
+struct NewMedium {
+    /// Block backend of the new medium, None if the medium is removed.
+    block_backend: Option<Arc<Mutex<dyn BlockDriverOps<ScsiCompleteCb>>>>,
+    /// Number of sectors of the new medium.
+    disk_sectors: u64,
+}
+
+/// State of the removable medium of the CD-ROM, it's shared by the device and the
+/// qmp commands which eject or change the medium.
+#[derive(Default)]
+pub struct ScsiMedium {
+    /// The tray is open, the medium can't be accessed by the guest.
+    pub tray_open: bool,
+    /// The guest prevents the medium from being removed.
+    pub locked: bool,
+    /// The host asks the guest to eject the locked medium.
+    pub eject_request: bool,
+    /// The medium is changed and the NEW MEDIA event is not reported yet.
+    pub media_event: bool,
+    /// The medium is loaded, MEDIUM CHANGED will be reported after NO MEDIUM unit attention.
+    media_changed: bool,
+    /// Path of the image of the medium, empty if there is no medium.
+    path: String,
+    /// The medium which is changed but not switched to by the device yet.
+    new_medium: Option<NewMedium>,
+    /// The unit attention condition caused by the change of the medium.
+    unit_attention: Option<ScsiSense>,
+}
+
+impl ScsiMedium {
+    /// Open or close the tray, and report the movement to qmp.
+    fn set_tray_open(&mut self, id: &str, open: bool) {
+        if self.tray_open == open {
+            return;
+        }
+        self.tray_open = open;
+        info!(
+            "The tray of scsi device {} is {}",
+            id,
+            if open { "open" } else { "closed" }
+        );
+        let tray_moved = qmp_schema::DeviceTrayMoved {
+            device: id.to_string(),
+            id: id.to_string(),
+            tray_open: open,
+        };
+        event!(DeviceTrayMoved; tray_moved);
+    }
+
+    /// The medium is loaded or unloaded by the host, the guest is notified by the unit
+    /// attention and the media event.
+    fn change_media(&mut self, id: &str, load: bool) {
+        self.set_tray_open(id, !load);
+        self.media_changed = load;
+        self.media_event = true;
+        self.eject_request = false;
+        self.unit_attention = Some(SCSI_SENSE_UNIT_ATTENTION_NO_MEDIUM);
+    }
+
+    /// Open the tray by the host. If the medium is locked by the guest, the guest is
+    /// asked to eject it, and the tray is opened anyway only if `force`.
+    fn open_tray(&mut self, id: &str, force: bool) -> Result<()> {
+        if self.tray_open {
+            return Ok(());
+        }
+        if self.locked {
+            self.eject_request = true;
+            if !force {
+                bail!(
+                    "Device {} is locked and force was not specified, wait for the tray to open and try again",
+                    id
+                );
+            }
+            self.locked = false;
+        }
+        self.change_media(id, false);
+        Ok(())
+    }
+
+    /// Get the event code and the media status reported by GET EVENT STATUS NOTIFICATION,
+    /// the reported event is cleared.
+    pub fn take_media_event(&mut self, present: bool) -> (u8, u8) {
+        let media_status = if self.tray_open {
+            1 << GESN_MS_DOOR_OR_TRAY_OPEN_BIT
+        } else if present {
+            1 << GESN_MS_MEDIA_PRESENT_BIT
+        } else {
+            0
+        };
+
+        let mut event_code = GESN_EC_NOCHG;
+        if !self.tray_open {
+            if self.media_event {
+                event_code = GESN_EC_NEWMEDIA;
+                self.media_event = false;
+            } else if self.eject_request {
+                event_code = GESN_EC_EJECTREQUEST;
+                self.eject_request = false;
+            }
+        }
+        (event_code, media_status)
+    }
+}
+
+/// Handler of the qmp commands which eject or change the medium of the CD-ROM.
+struct ScsiMediumHandler {
+    /// State of the medium shared with the device.
+    medium: Arc<Mutex<ScsiMedium>>,
+    /// Properties of the block backend, the path and format are replaced by the new medium.
+    prop: BlockProperty,
+    /// Async IO type of the block backend.
+    aio_type: AioEngine,
+    /// Tuning of the io_uring engine.
+    uring: IoUringConfig,
+    /// Drive backend files.
+    drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+}
+
+impl ScsiMediumHandler {
+    fn create_backend(
+        &self,
+        drive_files: &HashMap<String, DriveFile>,
+        path: &str,
+        format: DiskFormat,
+    ) -> Result<(NewMedium, BlockProperty)> {
+        let file = VmConfig::fetch_drive_file(drive_files, path)?;
+        let (req_align, buf_align) = VmConfig::fetch_drive_align(drive_files, path)?;
+        let aio = Aio::new_with_uring_config(Arc::new(aio_complete_cb), self.aio_type, self.uring)?;
+        let prop = BlockProperty {
+            path: path.to_string(),
+            format,
+            req_align,
+            buf_align,
+            ..self.prop.clone()
+        };
+        let block_backend = create_block_backend(file, aio, prop.clone())?;
+        let disk_size = block_backend.lock().unwrap().disk_size()?;
+        let new_medium = NewMedium {
+            block_backend: Some(block_backend),
+            disk_sectors: disk_size >> SECTOR_SHIFT,
+        };
+        Ok((new_medium, prop))
+    }
+
+    /// Release the drive file of the old medium, and update the block device info.
+    fn release_medium(&self, old_path: &str, prop: BlockProperty) -> Result<()> {
+        VmConfig::remove_drive_file(&mut self.drive_files.lock().unwrap(), old_path)?;
+        // The medium lock must not be held, it's taken by the qmp queries of the block devices.
+        update_block_device(&self.prop.id, prop);
+        Ok(())
+    }
+}
+
+impl BlockMediumOps for ScsiMediumHandler {
+    fn tray_open(&self) -> bool {
+        self.medium.lock().unwrap().tray_open
+    }
+
+    fn locked(&self) -> bool {
+        self.medium.lock().unwrap().locked
+    }
+
+    fn eject(&self, force: bool) -> Result<()> {
+        let mut medium = self.medium.lock().unwrap();
+        medium.open_tray(&self.prop.id, force)?;
+        if medium.path.is_empty() {
+            return Ok(());
+        }
+        let old_path = std::mem::take(&mut medium.path);
+        medium.new_medium = Some(NewMedium {
+            block_backend: None,
+            disk_sectors: 0,
+        });
+        drop(medium);
+
+        let prop = BlockProperty {
+            path: String::new(),
+            ..self.prop.clone()
+        };
+        self.release_medium(&old_path, prop)
+    }
+
+    fn change_medium(&self, path: &str, format: DiskFormat) -> Result<()> {
+        {
+            let mut medium = self.medium.lock().unwrap();
+            if !medium.tray_open && medium.locked {
+                medium.eject_request = true;
+                bail!(
+                    "Device {} is locked, wait for the guest to unlock it and try again",
+                    self.prop.id
+                );
+            }
+        }
+
+        // Open the new medium first, the old one is kept if it fails.
+        let mut drive_files = self.drive_files.lock().unwrap();
+        VmConfig::add_drive_file(&mut drive_files, path, true, self.prop.direct)?;
+        let res = self.create_backend(&drive_files, path, format);
+        if res.is_err() {
+            VmConfig::remove_drive_file(&mut drive_files, path)?;
+        }
+        let (new_medium, prop) = res?;
+        drop(drive_files);
+
+        let mut medium = self.medium.lock().unwrap();
+        medium.open_tray(&self.prop.id, false)?;
+        let old_path = std::mem::replace(&mut medium.path, path.to_string());
+        medium.new_medium = Some(new_medium);
+        medium.change_media(&self.prop.id, true);
+        drop(medium);
+
+        self.release_medium(&old_path, prop)
+    }
+}
+
 pub struct ScsiDevice {
     /// Configuration of the scsi device.
     pub config: ScsiDevConfig,
     /// State of the scsi device.
     pub state: ScsiDevState,
-    /// Image file opened.
-    pub disk_image: Option<Arc<File>>,
-    /// The align requirement of request(offset/len).
-    pub req_align: u32,
-    /// The align requirement of buffer(iova_base).
-    pub buf_align: u32,
+    /// Block backend opened by scsi device.
+    pub block_backend: Option<Arc<Mutex<dyn BlockDriverOps<ScsiCompleteCb>>>>,
     /// Number of sectors of the image file.
     pub disk_sectors: u64,
     /// Scsi Device block size.
@@ -106,8 +332,19 @@ pub struct ScsiDevice {
     pub parent_bus: Weak<Mutex<ScsiBus>>,
     /// Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
-    /// Aio context.
-    pub aio: Option<Arc<Mutex<Aio<ScsiCompleteCb>>>>,
+    /// I/O statistics of the scsi device.
+    pub stats: Arc<BlockStats>,
+    /// The disk has been resized, but the new capacity is not reported yet.
+    resized: Arc<AtomicBool>,
+    /// The unit attention condition which will be reported to the next command.
+    pub unit_attention: Option<ScsiSense>,
+    /// State of the removable medium.
+    pub medium: Arc<Mutex<ScsiMedium>>,
+    /// Arguments used to register the io completion events of the block backend,
+    /// None if the events are not registered.
+    io_event: Option<(Arc<AtomicBool>, BlockIoErrorCallback)>,
+    /// The host scsi device which the commands are passed through to.
+    pub generic: Option<ScsiGeneric>,
 }
 
 // SAFETY: the devices attached in one scsi controller will process IO in the same thread.
@@ -123,19 +360,26 @@ impl ScsiDevice {
         ScsiDevice {
             config,
             state: ScsiDevState::new(),
-            disk_image: None,
-            req_align: 1,
-            buf_align: 1,
+            block_backend: None,
             disk_sectors: 0,
             block_size: 0,
             scsi_type,
             parent_bus: Weak::new(),
             drive_files,
-            aio: None,
+            stats: Arc::new(BlockStats::default()),
+            resized: Arc::new(AtomicBool::new(false)),
+            unit_attention: None,
+            medium: Arc::new(Mutex::new(ScsiMedium::default())),
+            io_event: None,
+            generic: None,
         }
     }
 
-    pub fn realize(&mut self) -> Result<()> {
+    pub fn realize(&mut self, iothread: Option<String>) -> Result<()> {
+        if let Some(passthrough) = self.config.passthrough {
+            return self.realize_passthrough(passthrough, iothread);
+        }
+
         match self.scsi_type {
             SCSI_TYPE_DISK => {
                 self.block_size = SCSI_DISK_DEFAULT_BLOCK_SIZE;
@@ -144,6 +388,7 @@ impl ScsiDevice {
             SCSI_TYPE_ROM => {
                 self.block_size = SCSI_CDROM_DEFAULT_BLOCK_SIZE;
                 self.state.product = "STRA CDROM".to_string();
+                self.state.features |= 1 << SCSI_DISK_F_REMOVABLE;
             }
             _ => {
                 bail!("Scsi type {} does not support now", self.scsi_type);
@@ -154,20 +399,243 @@ impl ScsiDevice {
             self.state.serial = serial.clone();
         }
 
-        let drive_files = self.drive_files.lock().unwrap();
-        // File path can not be empty string. And it has also been checked in CmdParser::parse.
-        let mut file = VmConfig::fetch_drive_file(&drive_files, &self.config.path_on_host)?;
-        let disk_size = file
-            .seek(SeekFrom::End(0))
-            .with_context(|| "Failed to seek the end for scsi device")?;
-        self.disk_image = Some(Arc::new(file));
+        let removable = self.scsi_type == SCSI_TYPE_ROM;
+        let mut conf = BlockProperty {
+            id: self.config.id.clone(),
+            path: self.config.path_on_host.clone(),
+            format: self.config.format,
+            iothread,
+            direct: self.config.direct,
+            req_align: 1,
+            buf_align: 1,
+            discard: self.config.discard,
+            write_zeroes: self.config.write_zeroes,
+        };
+        let mut resize = None;
+        // Only the CD-ROM can start without medium.
+        if !conf.path.is_empty() {
+            let drive_files = self.drive_files.lock().unwrap();
+            let file = VmConfig::fetch_drive_file(&drive_files, &conf.path)?;
+            (conf.req_align, conf.buf_align) =
+                VmConfig::fetch_drive_align(&drive_files, &conf.path)?;
+            let aio = Aio::new_with_uring_config(
+                Arc::new(aio_complete_cb),
+                self.config.aio_type,
+                self.config.uring,
+            )?;
+            let block_backend = create_block_backend(file, aio, conf.clone())?;
+            let disk_size = block_backend.lock().unwrap().disk_size()?;
+            if !removable {
+                resize = Some(self.gen_resize_cb(block_backend.clone()));
+            }
+            self.block_backend = Some(block_backend);
+            self.disk_sectors = disk_size >> SECTOR_SHIFT;
+        } else if !removable {
+            bail!("Scsi disk {} has no image file", self.config.id);
+        }
+
+        let medium: Option<Arc<dyn BlockMediumOps>> = if removable {
+            self.medium.lock().unwrap().path = conf.path.clone();
+            Some(Arc::new(ScsiMediumHandler {
+                medium: self.medium.clone(),
+                prop: conf.clone(),
+                aio_type: self.config.aio_type,
+                uring: self.config.uring,
+                drive_files: self.drive_files.clone(),
+            }))
+        } else {
+            None
+        };
+        register_block_device(BlockDevInfo {
+            prop: conf,
+            read_only: self.config.read_only || removable,
+            aio: self.config.aio_type,
+            throttle: None,
+            throttle_group: None,
+            removable,
+            stats: self.stats.clone(),
+            resize,
+            backup: None,
+            dirty_bitmaps: None,
+            medium,
+        });
 
-        let alignments = VmConfig::fetch_drive_align(&drive_files, &self.config.path_on_host)?;
-        self.req_align = alignments.0;
-        self.buf_align = alignments.1;
+        Ok(())
+    }
 
+    /// The device type and the block size are the same as the host device. The block
+    /// backend is only used by the read and write commands of scsi-block.
+    fn realize_passthrough(
+        &mut self,
+        passthrough: ScsiPassthrough,
+        iothread: Option<String>,
+    ) -> Result<()> {
+        let path = self.config.path_on_host.clone();
+        let drive_files = self.drive_files.lock().unwrap();
+        let file = VmConfig::fetch_drive_file(&drive_files, &path)?;
+        let is_block = file.metadata()?.file_type().is_block_device();
+        if passthrough == ScsiPassthrough::Block && !is_block {
+            bail!(
+                "The host device {} of scsi-block is not a block device",
+                path
+            );
+        }
+        let generic = ScsiGeneric::new(file, &path)
+            .with_context(|| format!("Failed to realize scsi device {}", self.config.id))?;
+        self.scsi_type = generic.device_type()?;
+        self.block_size = generic.block_size(self.scsi_type);
+        self.generic = Some(generic);
+        if passthrough == ScsiPassthrough::Generic {
+            return Ok(());
+        }
+
+        let mut conf = BlockProperty {
+            id: self.config.id.clone(),
+            path: path.clone(),
+            format: self.config.format,
+            iothread,
+            direct: self.config.direct,
+            req_align: 1,
+            buf_align: 1,
+            discard: self.config.discard,
+            write_zeroes: self.config.write_zeroes,
+        };
+        let file = VmConfig::fetch_drive_file(&drive_files, &path)?;
+        (conf.req_align, conf.buf_align) = VmConfig::fetch_drive_align(&drive_files, &path)?;
+        drop(drive_files);
+        let aio = Aio::new_with_uring_config(
+            Arc::new(aio_complete_cb),
+            self.config.aio_type,
+            self.config.uring,
+        )?;
+        let block_backend = create_block_backend(file, aio, conf.clone())?;
+        let disk_size = block_backend.lock().unwrap().disk_size()?;
+        self.block_backend = Some(block_backend);
         self.disk_sectors = disk_size >> SECTOR_SHIFT;
 
+        register_block_device(BlockDevInfo {
+            prop: conf,
+            read_only: self.config.read_only,
+            aio: self.config.aio_type,
+            throttle: None,
+            throttle_group: None,
+            removable: false,
+            stats: self.stats.clone(),
+            resize: None,
+            backup: None,
+            dirty_bitmaps: None,
+            medium: None,
+        });
+
+        Ok(())
+    }
+
+    pub fn unrealize(&mut self) {
+        unregister_block_device(&self.config.id);
+    }
+
+    fn gen_resize_cb(
+        &self,
+        block_backend: Arc<Mutex<dyn BlockDriverOps<ScsiCompleteCb>>>,
+    ) -> BlockResizeCallback {
+        let resized = self.resized.clone();
+        Arc::new(move |size: u64| {
+            block_backend.lock().unwrap().resize(size)?;
+            resized.store(true, Ordering::SeqCst);
+            Ok(())
+        })
+    }
+
+    /// Register the io completion events of the block backend, the events of the
+    /// medium inserted later are registered with the same arguments.
+    pub fn register_io_event(
+        &mut self,
+        broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Result<()> {
+        if let Some(block_backend) = self.block_backend.as_ref() {
+            block_backend
+                .lock()
+                .unwrap()
+                .register_io_event(broken.clone(), error_cb.clone())?;
+        }
+        self.io_event = Some((broken, error_cb));
+        Ok(())
+    }
+
+    /// Unregister the io completion events of the block backend.
+    pub fn unregister_io_event(&mut self) -> Result<()> {
+        self.io_event = None;
+        if let Some(block_backend) = self.block_backend.as_ref() {
+            block_backend.lock().unwrap().unregister_io_event()?;
+        }
+        Ok(())
+    }
+
+    /// The medium can be accessed by the guest or not.
+    pub fn medium_available(&self) -> bool {
+        self.block_backend.is_some() && !self.medium.lock().unwrap().tray_open
+    }
+
+    /// Switch to the medium changed by qmp, and take the unit attention condition
+    /// caused by the change.
+    pub fn update_medium(&mut self) -> Result<()> {
+        let mut medium = self.medium.lock().unwrap();
+        if let Some(sense) = medium.unit_attention.take() {
+            self.unit_attention = Some(sense);
+        }
+        let new_medium = match medium.new_medium.take() {
+            Some(new_medium) => new_medium,
+            None => return Ok(()),
+        };
+        drop(medium);
+
+        if let Some((broken, error_cb)) = self.io_event.as_ref() {
+            if let Some(block_backend) = self.block_backend.as_ref() {
+                block_backend.lock().unwrap().unregister_io_event()?;
+            }
+            if let Some(block_backend) = new_medium.block_backend.as_ref() {
+                block_backend
+                    .lock()
+                    .unwrap()
+                    .register_io_event(broken.clone(), error_cb.clone())?;
+            }
+        }
+        self.block_backend = new_medium.block_backend;
+        self.disk_sectors = new_medium.disk_sectors;
+        Ok(())
+    }
+
+    /// The unit attention condition has been reported to the guest. If a new medium
+    /// is loaded, MEDIUM CHANGED is reported next.
+    pub fn unit_attention_reported(&mut self) {
+        let mut medium = self.medium.lock().unwrap();
+        if medium.media_changed {
+            medium.media_changed = false;
+            self.unit_attention = Some(SCSI_SENSE_MEDIUM_CHANGED);
+        }
+    }
+
+    /// Load or eject the medium by START STOP UNIT command of the guest, only the tray
+    /// is moved, the medium is kept.
+    pub fn load_eject(&mut self, load: bool) {
+        self.medium
+            .lock()
+            .unwrap()
+            .set_tray_open(&self.config.id, !load);
+    }
+
+    /// Update the number of sectors if the disk has been resized, and report the
+    /// change to the guest by unit attention.
+    pub fn update_capacity(&mut self) -> Result<()> {
+        if !self.resized.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        if let Some(block_backend) = self.block_backend.as_ref() {
+            let disk_size = block_backend.lock().unwrap().disk_size()?;
+            self.disk_sectors = disk_size >> SECTOR_SHIFT;
+            self.unit_attention = Some(SCSI_SENSE_CAPACITY_CHANGED);
+        }
         Ok(())
     }
 }
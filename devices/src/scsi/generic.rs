@@ -0,0 +1,259 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::os::raw::{c_int, c_uint, c_ulong, c_ushort, c_void};
+
+use anyhow::{bail, Result};
+use byteorder::{BigEndian, ByteOrder};
+use log::error;
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+
+use crate::ScsiBus::{
+    ScsiCommand, ScsiXferMode, CHECK_CONDITION, GOOD, INQUIRY, READ_CAPACITY_10,
+    SCSI_SENSE_BUF_SIZE, STATUS_MASK,
+};
+use crate::ScsiDisk::{
+    SCSI_CDROM_DEFAULT_BLOCK_SIZE, SCSI_DISK_DEFAULT_BLOCK_SIZE, SCSI_TYPE_DISK, SCSI_TYPE_ROM,
+};
+use util::aio::Iovec;
+
+/// Ioctls of the linux scsi generic driver. From <scsi/sg.h>
+const SG_GET_VERSION_NUM: c_ulong = 0x2282;
+const SG_IO: c_ulong = 0x2285;
+
+/// The SG_IO ioctl with sg_io_hdr is supported since sg driver version 3.
+const SG_MIN_VERSION: c_int = 30000;
+
+/// Data transfer direction of sg_io_hdr.
+const SG_DXFER_NONE: c_int = -1;
+const SG_DXFER_TO_DEV: c_int = -2;
+const SG_DXFER_FROM_DEV: c_int = -3;
+
+/// Timeout of one command in milliseconds.
+const SG_IO_TIMEOUT_MS: c_uint = 30_000;
+
+/// Mask of the driver status in sg_io_hdr, DRIVER_SENSE is not an error.
+const SG_ERR_DRIVER_MASK: c_ushort = 0x07;
+
+/// Length of the INQUIRY data used to get the peripheral device type.
+const SG_INQUIRY_LEN: usize = 36;
+/// Length of the READ CAPACITY(10) data.
+const SG_READ_CAPACITY_10_LEN: usize = 8;
+
+/// The sg_io_hdr structure in <scsi/sg.h>.
+#[repr(C)]
+struct SgIoHdr {
+    /// Always 'S' for sg_io_hdr.
+    interface_id: c_int,
+    dxfer_direction: c_int,
+    cmd_len: u8,
+    mx_sb_len: u8,
+    /// Number of the scatter gather elements, 0 means no scatter gather.
+    iovec_count: c_ushort,
+    dxfer_len: c_uint,
+    /// Data buffer or the scatter gather list.
+    dxferp: *mut c_void,
+    cmdp: *const u8,
+    sbp: *mut u8,
+    timeout: c_uint,
+    flags: c_uint,
+    pack_id: c_int,
+    usr_ptr: *mut c_void,
+    status: u8,
+    masked_status: u8,
+    msg_status: u8,
+    /// Number of the bytes written to the sense buffer.
+    sb_len_wr: u8,
+    host_status: c_ushort,
+    driver_status: c_ushort,
+    resid: c_int,
+    duration: c_uint,
+    info: c_uint,
+}
+
+/// Result of one command passed through to the host scsi device.
+pub struct SgIoResult {
+    /// SAM status of the command.
+    pub status: u8,
+    /// Raw sense data returned by the host device, empty if there is no sense.
+    pub sense: Vec<u8>,
+    /// Number of the bytes which are not transferred.
+    pub resid: u32,
+}
+
+/// The host scsi device which the commands are passed through to by SG_IO.
+pub struct ScsiGeneric {
+    /// The sg character device or the scsi block device.
+    file: File,
+}
+
+impl ScsiGeneric {
+    pub fn new(file: File, path: &str) -> Result<Self> {
+        let mut version: c_int = 0;
+        // SAFETY: file is a valid fd and version is a valid c_int.
+        let ret = unsafe { ioctl_with_mut_ref(&file, SG_GET_VERSION_NUM, &mut version) };
+        if ret < 0 {
+            bail!(
+                "{} is not a scsi generic device: {}",
+                path,
+                std::io::Error::last_os_error()
+            );
+        }
+        if version < SG_MIN_VERSION {
+            bail!(
+                "The scsi generic driver version {} of {} is too old",
+                version,
+                path
+            );
+        }
+        Ok(ScsiGeneric { file })
+    }
+
+    /// Pass the command through to the host device. The data is transferred by the
+    /// `iovec`, of which only `datalen` bytes are used.
+    pub fn execute(&self, cmd: &ScsiCommand, iovec: &[Iovec], datalen: u32) -> SgIoResult {
+        let len = if cmd.mode == ScsiXferMode::ScsiXferNone {
+            0
+        } else {
+            std::cmp::min(cmd.xfer, datalen)
+        };
+        let mut left = u64::from(len);
+        let mut sg_iovec = Vec::new();
+        for iov in iovec {
+            if left == 0 {
+                break;
+            }
+            let iov_len = std::cmp::min(iov.iov_len, left);
+            sg_iovec.push(libc::iovec {
+                iov_base: iov.iov_base as *mut c_void,
+                iov_len: iov_len as usize,
+            });
+            left -= iov_len;
+        }
+        let direction = match cmd.mode {
+            _ if len == 0 => SG_DXFER_NONE,
+            ScsiXferMode::ScsiXferToDev => SG_DXFER_TO_DEV,
+            _ => SG_DXFER_FROM_DEV,
+        };
+        self.sg_io(&cmd.buf[..cmd.len as usize], direction, &mut sg_iovec)
+    }
+
+    fn sg_io(&self, cdb: &[u8], direction: c_int, iovec: &mut [libc::iovec]) -> SgIoResult {
+        let mut sense = vec![0_u8; SCSI_SENSE_BUF_SIZE];
+        let dxfer_len: usize = iovec.iter().map(|iov| iov.iov_len).sum();
+        let mut hdr = SgIoHdr {
+            interface_id: 'S' as c_int,
+            dxfer_direction: direction,
+            cmd_len: cdb.len() as u8,
+            mx_sb_len: SCSI_SENSE_BUF_SIZE as u8,
+            iovec_count: iovec.len() as c_ushort,
+            dxfer_len: dxfer_len as c_uint,
+            dxferp: iovec.as_mut_ptr() as *mut c_void,
+            cmdp: cdb.as_ptr(),
+            sbp: sense.as_mut_ptr(),
+            timeout: SG_IO_TIMEOUT_MS,
+            flags: 0,
+            pack_id: 0,
+            usr_ptr: std::ptr::null_mut(),
+            status: 0,
+            masked_status: 0,
+            msg_status: 0,
+            sb_len_wr: 0,
+            host_status: 0,
+            driver_status: 0,
+            resid: 0,
+            duration: 0,
+            info: 0,
+        };
+        // SAFETY: the cdb, the sense buffer and the iovec are valid during the ioctl, and
+        // the buffers of the iovec are the guest memory or the local buffers.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, SG_IO, &mut hdr) };
+        if ret < 0 || hdr.host_status != 0 || hdr.driver_status & SG_ERR_DRIVER_MASK != 0 {
+            error!(
+                "Failed to pass through scsi command {:#x}, ret {}, host status {:#x}, driver status {:#x}, error {}",
+                cdb[0],
+                ret,
+                hdr.host_status,
+                hdr.driver_status,
+                std::io::Error::last_os_error()
+            );
+            return SgIoResult {
+                status: CHECK_CONDITION,
+                sense: Vec::new(),
+                resid: dxfer_len as u32,
+            };
+        }
+
+        let status = hdr.status & STATUS_MASK;
+        sense.truncate(if status == CHECK_CONDITION {
+            hdr.sb_len_wr as usize
+        } else {
+            0
+        });
+        SgIoResult {
+            status,
+            sense,
+            resid: hdr.resid.max(0) as u32,
+        }
+    }
+
+    /// Read the data of the command which has no parameters from the host device.
+    fn read_data(&self, cdb: &[u8], len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0_u8; len];
+        let mut iovec = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: len,
+        }];
+        let res = self.sg_io(cdb, SG_DXFER_FROM_DEV, &mut iovec);
+        if res.status != GOOD {
+            bail!(
+                "Scsi command {:#x} failed on the host device, status {:#x}",
+                cdb[0],
+                res.status
+            );
+        }
+        Ok(buf)
+    }
+
+    /// Get the peripheral device type of the host device by INQUIRY.
+    pub fn device_type(&self) -> Result<u32> {
+        let cdb = [INQUIRY, 0, 0, 0, SG_INQUIRY_LEN as u8, 0];
+        let data = self.read_data(&cdb, SG_INQUIRY_LEN)?;
+        Ok(u32::from(data[0] & 0x1f))
+    }
+
+    /// Get the logical block size of the host device, which is used to compute the
+    /// transfer length of the read and write commands.
+    pub fn block_size(&self, scsi_type: u32) -> u32 {
+        match scsi_type {
+            SCSI_TYPE_DISK => {
+                let cdb = [READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+                match self.read_data(&cdb, SG_READ_CAPACITY_10_LEN) {
+                    Ok(data) if BigEndian::read_u32(&data[4..]).is_power_of_two() => {
+                        BigEndian::read_u32(&data[4..])
+                    }
+                    res => {
+                        error!(
+                            "Failed to get block size, use the default one: {:?}",
+                            res.err()
+                        );
+                        SCSI_DISK_DEFAULT_BLOCK_SIZE
+                    }
+                }
+            }
+            // The medium of the CD-ROM may be absent, use the fixed block size.
+            SCSI_TYPE_ROM => SCSI_CDROM_DEFAULT_BLOCK_SIZE,
+            _ => SCSI_DISK_DEFAULT_BLOCK_SIZE,
+        }
+    }
+}
@@ -12,3 +12,4 @@
 
 pub mod bus;
 pub mod disk;
+pub mod generic;
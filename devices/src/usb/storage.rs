@@ -21,7 +21,7 @@ use log::{debug, error, info, warn};
 use once_cell::sync::Lazy;
 
 use machine_manager::config::{DriveFile, UsbStorageConfig};
-use util::aio::{Aio, AioEngine};
+use util::aio::AioEngine;
 
 use super::config::*;
 use super::descriptor::{
@@ -32,8 +32,8 @@ use super::xhci::xhci_controller::XhciDevice;
 use super::{UsbDevice, UsbDeviceOps, UsbDeviceRequest, UsbEndpoint, UsbPacket, UsbPacketStatus};
 use crate::{
     ScsiBus::{
-        aio_complete_cb, ScsiBus, ScsiRequest, ScsiRequestOps, ScsiSense, ScsiXferMode,
-        EMULATE_SCSI_OPS, GOOD, SCSI_CMD_BUF_SIZE,
+        ScsiBus, ScsiRequest, ScsiRequestOps, ScsiSense, ScsiXferMode, EMULATE_SCSI_OPS, GOOD,
+        SCSI_CMD_BUF_SIZE,
     },
     ScsiDisk::{ScsiDevice, SCSI_TYPE_DISK, SCSI_TYPE_ROM},
 };
@@ -520,11 +520,12 @@ impl UsbDeviceOps for UsbStorage {
         self.usb_device
             .init_descriptor(DESC_DEVICE_STORAGE.clone(), s)?;
 
-        let aio = Aio::new(Arc::new(aio_complete_cb), AioEngine::Off)
-            .with_context(|| format!("USB-storage {}: aio creation error!", self.id))?;
         let mut locked_scsi_dev = self.scsi_dev.lock().unwrap();
-        locked_scsi_dev.aio = Some(Arc::new(Mutex::new(aio)));
-        locked_scsi_dev.realize()?;
+        // The requests of usb storage are handled synchronously.
+        locked_scsi_dev.config.aio_type = AioEngine::Off;
+        locked_scsi_dev
+            .realize(None)
+            .with_context(|| format!("USB-storage {}: scsi device realize error!", self.id))?;
         drop(locked_scsi_dev);
         self.scsi_bus
             .lock()
@@ -536,6 +537,11 @@ impl UsbDeviceOps for UsbStorage {
         Ok(storage)
     }
 
+    fn unrealize(&mut self) -> Result<()> {
+        self.scsi_dev.lock().unwrap().unrealize();
+        Ok(())
+    }
+
     fn reset(&mut self) {
         info!("Storage device reset");
         self.usb_device.remote_wakeup = 0;
@@ -0,0 +1,249 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+
+use crate::img::PreallocMode;
+use machine_manager::config::DiskFormat;
+
+pub const USAGE: &str = "\
+Usage: stratovirt-img <command> [options]
+
+Commands:
+  create [-f fmt] [-o options] filename [size]
+  info [-f fmt] filename
+  resize [-f fmt] [--preallocation=mode] [--shrink] filename [--] [+|-]size
+  convert [-f fmt] [-O output_fmt] [-S sparse_size] [-t cache] [-T src_cache] [-o options] filename output_filename
+  check [-f fmt] [-r leaks] filename
+  map [-f fmt] filename
+
+Options:
+  -f fmt          format of the image, 'raw' or 'qcow2', probed if not set
+  -O output_fmt   format of the output image, 'raw' by default
+  -o options      comma separated options of the new image:
+                    cluster_size=size   cluster size of qcow2 image
+                    backing_file=path   backing file of qcow2 image
+                    backing_fmt=fmt     format of the backing file
+                    preallocation=mode  'off', 'falloc' or 'full', only for raw image
+  -S sparse_size  zeroed ranges of at least sparse_size bytes are left unallocated
+                  in the output image, 0 disables it, 4k by default
+  -t cache        cache mode of the output image, 'none' uses direct io, 'writeback' by default
+  -T src_cache    cache mode of the source image
+  -r leaks        free the leaked clusters found by the check
+  --shrink        allow shrinking the raw image, the data beyond the new size is lost
+  -h, --help      print this help
+  -V, --version   print the version
+
+The size accepts the suffixes K, M, G and T, which are powers of 1024.";
+
+/// Options and positional arguments of one command.
+#[derive(Debug, Default)]
+pub struct ArgList {
+    values: HashMap<String, String>,
+    flags: Vec<String>,
+    pub args: Vec<String>,
+}
+
+impl ArgList {
+    pub fn value_of(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    pub fn is_present(&self, name: &str) -> bool {
+        self.flags.iter().any(|flag| flag == name)
+    }
+
+    /// Get the image format set by the option `name`.
+    pub fn format_of(&self, name: &str) -> Result<Option<DiskFormat>> {
+        self.value_of(name).map(parse_format).transpose()
+    }
+}
+
+/// Parse the arguments of one command. Options are `-x value` or `--long[=value]`,
+/// `value_opts` take a value and `flag_opts` don't. The `-o` option can be given
+/// several times, the values are joined with comma.
+pub fn parse_args(args: &[String], value_opts: &[&str], flag_opts: &[&str]) -> Result<ArgList> {
+    let mut list = ArgList::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        // All the arguments after "--" are positional, e.g. the negative size.
+        if arg == "--" {
+            list.args.extend(iter.cloned());
+            break;
+        }
+        if !arg.starts_with('-') {
+            list.args.push(arg.clone());
+            continue;
+        }
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if arg.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        if flag_opts.contains(&name) && inline_value.is_none() {
+            list.flags.push(name.to_string());
+            continue;
+        }
+        if !value_opts.contains(&name) {
+            bail!("Invalid option {}", arg);
+        }
+        let value = match inline_value {
+            Some(value) => value,
+            None => iter
+                .next()
+                .with_context(|| format!("Option {} requires a value", name))?
+                .clone(),
+        };
+        match list.values.get_mut(name) {
+            Some(old) if name == "-o" => {
+                old.push(',');
+                old.push_str(&value);
+            }
+            _ => {
+                list.values.insert(name.to_string(), value);
+            }
+        }
+    }
+    Ok(list)
+}
+
+/// Parse the size with optional suffix K, M, G or T.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let (num, shift) = match size.char_indices().last() {
+        Some((pos, c)) if c.is_ascii_alphabetic() => {
+            let shift = match c.to_ascii_uppercase() {
+                'B' => 0,
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => bail!("Invalid size suffix in {}", size),
+            };
+            (&size[..pos], shift)
+        }
+        _ => (size, 0),
+    };
+    let num = num
+        .parse::<u64>()
+        .with_context(|| format!("Invalid size {}", size))?;
+    num.checked_mul(1 << shift)
+        .with_context(|| format!("Size {} is too big", size))
+}
+
+pub fn parse_format(format: &str) -> Result<DiskFormat> {
+    DiskFormat::from_str(format).map_err(|_| anyhow::anyhow!("Unsupported image format {}", format))
+}
+
+/// Options to create the image, which are set by `-o`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CreateOptions {
+    pub cluster_size: Option<u64>,
+    pub backing_file: Option<String>,
+    pub backing_format: Option<DiskFormat>,
+    pub preallocation: PreallocMode,
+}
+
+pub fn parse_create_options(opts: Option<&str>) -> Result<CreateOptions> {
+    let mut options = CreateOptions::default();
+    let opts = match opts {
+        Some(opts) => opts,
+        None => return Ok(options),
+    };
+    for opt in opts.split(',').filter(|opt| !opt.is_empty()) {
+        let (key, value) = opt
+            .split_once('=')
+            .with_context(|| format!("Invalid option {}, expect key=value", opt))?;
+        match key {
+            "cluster_size" => {
+                let size = parse_size(value)?;
+                if !size.is_power_of_two() {
+                    bail!("Cluster size {} is not power of 2", size);
+                }
+                options.cluster_size = Some(size);
+            }
+            "backing_file" => options.backing_file = Some(value.to_string()),
+            "backing_fmt" => options.backing_format = Some(parse_format(value)?),
+            "preallocation" => options.preallocation = PreallocMode::from_str(value)?,
+            _ => bail!("Unsupported option {}", key),
+        }
+    }
+    Ok(options)
+}
+
+/// Parse the cache mode, return whether direct io is used.
+pub fn parse_cache_mode(mode: Option<&str>) -> Result<bool> {
+    match mode {
+        None | Some("writeback") => Ok(false),
+        Some("none") => Ok(true),
+        Some(mode) => bail!("Unsupported cache mode {}", mode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("64k").unwrap(), 64 << 10);
+        assert_eq!(parse_size("10M").unwrap(), 10 << 20);
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        assert_eq!(parse_size("1T").unwrap(), 1 << 40);
+        assert_eq!(parse_size("512B").unwrap(), 512);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = to_args(&[
+            "-f",
+            "qcow2",
+            "-o",
+            "cluster_size=4k",
+            "--preallocation=full",
+            "--shrink",
+            "-o",
+            "preallocation=falloc",
+            "test.img",
+            "+1G",
+        ]);
+        let list = parse_args(&args, &["-f", "-o", "--preallocation"], &["--shrink"]).unwrap();
+        assert_eq!(list.format_of("-f").unwrap(), Some(DiskFormat::Qcow2));
+        assert_eq!(list.value_of("--preallocation"), Some("full"));
+        assert!(list.is_present("--shrink"));
+        assert_eq!(list.args, to_args(&["test.img", "+1G"]));
+
+        let options = parse_create_options(list.value_of("-o")).unwrap();
+        assert_eq!(options.cluster_size, Some(4096));
+        assert_eq!(options.preallocation, PreallocMode::Falloc);
+
+        let list = parse_args(&to_args(&["test.img", "--", "-1G"]), &[], &[]).unwrap();
+        assert_eq!(list.args, to_args(&["test.img", "-1G"]));
+
+        assert!(parse_args(&to_args(&["-x", "a"]), &["-f"], &[]).is_err());
+        assert!(parse_args(&to_args(&["a", "-f"]), &["-f"], &[]).is_err());
+        assert!(parse_create_options(Some("cluster_size=3000")).is_err());
+        assert!(parse_create_options(Some("backing_fmt=vmdk")).is_err());
+        assert!(parse_create_options(Some("compat")).is_err());
+    }
+}
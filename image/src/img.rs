@@ -0,0 +1,616 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+
+use crate::cmdline::{parse_size, CreateOptions};
+use block_backend::file::SyncFile;
+use block_backend::qcow2::header::QCOW_VERSION_2;
+use block_backend::qcow2::{
+    probe_format, BlockStatus, Qcow2CreateOptions, Qcow2Image, DEFAULT_CLUSTER_BITS,
+};
+use machine_manager::config::DiskFormat;
+use util::file::{get_file_alignment, lock_file, open_file};
+
+/// Size of the buffer used to copy and preallocate the data.
+const IO_BUF_SIZE: u64 = 2 << 20;
+/// Default sparse size of the output image of convert.
+pub const DEFAULT_SPARSE_SIZE: u64 = 4096;
+/// Exit code of check if the image has corruptions.
+const CHECK_EXIT_CORRUPT: i32 = 2;
+/// Exit code of check if the image only has leaked clusters.
+const CHECK_EXIT_LEAKED: i32 = 3;
+
+/// How the space of raw image is allocated when it is created or grown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PreallocMode {
+    /// The image is sparse.
+    #[default]
+    Off,
+    /// The space is allocated by fallocate, without writing the data.
+    Falloc,
+    /// The space is allocated by writing zeros.
+    Full,
+}
+
+impl FromStr for PreallocMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(PreallocMode::Off),
+            "falloc" => Ok(PreallocMode::Falloc),
+            "full" => Ok(PreallocMode::Full),
+            _ => bail!("Unsupported preallocation mode {}", s),
+        }
+    }
+}
+
+/// The image opened by the tool.
+pub enum Image {
+    Raw { file: SyncFile, size: u64 },
+    Qcow2(Box<Qcow2Image>),
+}
+
+impl Image {
+    /// Open the image, the format is probed if it is not given. The image is locked
+    /// so that it is not modified by the running VM at the same time.
+    pub fn open(
+        path: &str,
+        format: Option<DiskFormat>,
+        read_only: bool,
+        direct: bool,
+    ) -> Result<Self> {
+        let format = match format {
+            Some(format) => format,
+            // The magic can't be read with direct io, which needs aligned request.
+            None => probe_format(&open_file(path, true, false)?)?,
+        };
+        let mut file = open_file(path, read_only, direct)?;
+        lock_file(&file, path, read_only)?;
+        let (req_align, buf_align) = get_file_alignment(&file, direct);
+        match format {
+            DiskFormat::Raw => {
+                // The size of block device is not in the metadata.
+                let size = file
+                    .seek(SeekFrom::End(0))
+                    .with_context(|| format!("Failed to get the size of {}", path))?;
+                Ok(Image::Raw {
+                    file: SyncFile::new(file, req_align, buf_align),
+                    size,
+                })
+            }
+            DiskFormat::Qcow2 => {
+                let image = Qcow2Image::open(file, path, req_align, buf_align)
+                    .with_context(|| format!("Failed to open qcow2 image {}", path))?;
+                Ok(Image::Qcow2(Box::new(image)))
+            }
+        }
+    }
+
+    pub fn format(&self) -> DiskFormat {
+        match self {
+            Image::Raw { .. } => DiskFormat::Raw,
+            Image::Qcow2(_) => DiskFormat::Qcow2,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match self {
+            Image::Raw { size, .. } => *size,
+            Image::Qcow2(image) => image.virtual_size(),
+        }
+    }
+
+    fn file(&self) -> Result<File> {
+        let file = match self {
+            Image::Raw { file, .. } => file.file().try_clone(),
+            Image::Qcow2(image) => image.file().file().try_clone(),
+        };
+        file.with_context(|| "Failed to clone the image file")
+    }
+
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+        match self {
+            Image::Raw { file, .. } => file.read_at(buf, offset),
+            Image::Qcow2(image) => image.read_at(buf, offset),
+        }
+    }
+
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+        match self {
+            Image::Raw { file, .. } => file.write_at(buf, offset),
+            Image::Qcow2(image) => image.write_at(buf, offset),
+        }
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        match self {
+            Image::Raw { file, .. } => file.sync(),
+            Image::Qcow2(image) => image.file().sync(),
+        }
+    }
+
+    /// Get the status of the range at `offset`, return the status and the length of
+    /// the leading part of the range which has the same status.
+    pub fn block_status(&mut self, offset: u64, nbytes: u64) -> Result<(BlockStatus, u64)> {
+        match self {
+            Image::Raw { file, .. } => raw_block_status(file.file(), offset, nbytes),
+            Image::Qcow2(image) => image.block_status(offset, nbytes),
+        }
+    }
+}
+
+/// Find the data and holes of raw file by SEEK_DATA and SEEK_HOLE.
+fn raw_block_status(file: &File, offset: u64, nbytes: u64) -> Result<(BlockStatus, u64)> {
+    let fd = file.as_raw_fd();
+    // SAFETY: fd is valid, lseek doesn't touch the memory.
+    let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+    if data < 0 {
+        let err = std::io::Error::last_os_error();
+        return match err.raw_os_error() {
+            // There is no data after the offset.
+            Some(libc::ENXIO) => Ok((BlockStatus::Zero, nbytes)),
+            // The file system doesn't support it, treat all as data.
+            Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => Ok((BlockStatus::Data(offset), nbytes)),
+            _ => Err(err).with_context(|| format!("Failed to seek data at {}", offset)),
+        };
+    }
+    let data = data as u64;
+    if data > offset {
+        return Ok((BlockStatus::Zero, (data - offset).min(nbytes)));
+    }
+    // SAFETY: fd is valid, lseek doesn't touch the memory.
+    let hole = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_HOLE) };
+    if hole < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to seek hole at {}", offset));
+    }
+    let len = (hole as u64).saturating_sub(offset).clamp(1, nbytes);
+    Ok((BlockStatus::Data(offset), len))
+}
+
+/// Allocate the space of raw file in the range.
+fn preallocate(file: &File, offset: u64, len: u64, mode: PreallocMode) -> Result<()> {
+    match mode {
+        PreallocMode::Off => Ok(()),
+        PreallocMode::Falloc => {
+            // SAFETY: fd is valid, fallocate doesn't touch the memory.
+            let ret = unsafe {
+                libc::fallocate(
+                    file.as_raw_fd(),
+                    0,
+                    offset as libc::off_t,
+                    len as libc::off_t,
+                )
+            };
+            if ret < 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| "Failed to preallocate by fallocate");
+            }
+            Ok(())
+        }
+        PreallocMode::Full => {
+            let file = SyncFile::new(file.try_clone()?, 1, 1);
+            let zeros = vec![0_u8; IO_BUF_SIZE as usize];
+            let mut pos = offset;
+            while pos < offset + len {
+                let size = IO_BUF_SIZE.min(offset + len - pos);
+                file.write_at(&zeros[..size as usize], pos)?;
+                pos += size;
+            }
+            file.sync()
+        }
+    }
+}
+
+/// Resolve the path of backing file, which is relative to the directory of the image.
+fn backing_path(path: &str, backing_file: &str) -> String {
+    if Path::new(backing_file).is_absolute() {
+        return backing_file.to_string();
+    }
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    dir.join(backing_file).to_string_lossy().to_string()
+}
+
+/// Create the image, the size can be omitted if the qcow2 image has backing file.
+pub fn create(
+    path: &str,
+    format: DiskFormat,
+    size: Option<u64>,
+    options: &CreateOptions,
+) -> Result<()> {
+    if format == DiskFormat::Raw
+        && (options.cluster_size.is_some() || options.backing_file.is_some())
+    {
+        bail!("Cluster size and backing file are only supported by qcow2 image");
+    }
+    if format == DiskFormat::Qcow2 && options.preallocation != PreallocMode::Off {
+        bail!("Preallocation is only supported by raw image");
+    }
+    let size = match (size, options.backing_file.as_ref()) {
+        (Some(size), _) => size,
+        (None, Some(backing_file)) => {
+            let backing = backing_path(path, backing_file);
+            Image::open(&backing, options.backing_format, true, false)
+                .with_context(|| format!("Failed to open backing file {}", backing))?
+                .size()
+        }
+        (None, None) => bail!("Image size must be specified"),
+    };
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("Failed to create image {}", path))?;
+    lock_file(&file, path, false)?;
+    match format {
+        DiskFormat::Raw => {
+            file.set_len(size)
+                .with_context(|| format!("Failed to set the size of {}", path))?;
+            preallocate(&file, 0, size, options.preallocation)
+        }
+        DiskFormat::Qcow2 => {
+            let cluster_bits = match options.cluster_size {
+                Some(cluster_size) => cluster_size.trailing_zeros(),
+                None => DEFAULT_CLUSTER_BITS,
+            };
+            let qcow2_options = Qcow2CreateOptions {
+                size,
+                cluster_bits,
+                backing_file: options.backing_file.clone(),
+                backing_format: options.backing_format,
+            };
+            Qcow2Image::create(file, &qcow2_options)
+                .with_context(|| format!("Failed to create qcow2 image {}", path))
+        }
+    }
+}
+
+/// Format the size in bytes with binary unit, e.g. "1.5 GiB".
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, UNITS[0])
+    } else {
+        let value = format!("{:.3}", value);
+        let value = value.trim_end_matches('0').trim_end_matches('.');
+        format!("{} {}", value, UNITS[unit])
+    }
+}
+
+/// Describe the image, the output is similar to `qemu-img info`.
+pub fn info(path: &str, format: Option<DiskFormat>) -> Result<String> {
+    let image = Image::open(path, format, true, false)?;
+    let disk_size = image.file()?.metadata()?.blocks() * 512;
+    let mut info = format!(
+        "image: {}\nfile format: {}\nvirtual size: {} ({} bytes)\ndisk size: {}\n",
+        path,
+        image.format(),
+        human_size(image.size()),
+        image.size(),
+        human_size(disk_size)
+    );
+    if let Image::Qcow2(qcow2) = &image {
+        info += &format!("cluster_size: {}\n", qcow2.cluster_size());
+        if let Some(backing_file) = qcow2.backing_file() {
+            info += &format!("backing file: {}\n", backing_file);
+        }
+        info += &format!(
+            "Format specific information:\n    compat: {}\n    refcount bits: {}\n",
+            if qcow2.header.version == QCOW_VERSION_2 {
+                "0.10"
+            } else {
+                "1.1"
+            },
+            1 << qcow2.header.refcount_order
+        );
+    }
+    Ok(info)
+}
+
+/// Resize the image, `size` is the new size, or the delta if it starts with '+' or '-'.
+pub fn resize(
+    path: &str,
+    format: Option<DiskFormat>,
+    size: &str,
+    preallocation: PreallocMode,
+    shrink: bool,
+) -> Result<()> {
+    let mut image = Image::open(path, format, false, false)?;
+    let old_size = image.size();
+    let new_size = if let Some(delta) = size.strip_prefix('+') {
+        old_size.checked_add(parse_size(delta)?)
+    } else if let Some(delta) = size.strip_prefix('-') {
+        old_size.checked_sub(parse_size(delta)?)
+    } else {
+        Some(parse_size(size)?)
+    }
+    .with_context(|| format!("Invalid new size {}", size))?;
+
+    match &mut image {
+        Image::Raw { file, .. } => {
+            if new_size < old_size && !shrink {
+                bail!("Use the --shrink option to shrink the image, the data beyond the new size will be lost");
+            }
+            file.set_len(new_size)?;
+            if new_size > old_size {
+                preallocate(file.file(), old_size, new_size - old_size, preallocation)?;
+            }
+            file.sync()
+        }
+        Image::Qcow2(qcow2) => {
+            if preallocation != PreallocMode::Off {
+                bail!("Preallocation is only supported by raw image");
+            }
+            qcow2.resize(new_size)
+        }
+    }
+}
+
+/// Copy the source image to the new output image. The zeroed ranges of at least
+/// `sparse_size` bytes are skipped, so they stay unallocated in the output image.
+#[allow(clippy::too_many_arguments)]
+pub fn convert(
+    src_path: &str,
+    src_format: Option<DiskFormat>,
+    src_direct: bool,
+    dst_path: &str,
+    dst_format: DiskFormat,
+    dst_direct: bool,
+    sparse_size: u64,
+    options: &CreateOptions,
+) -> Result<()> {
+    if sparse_size % 512 != 0 {
+        bail!("Sparse size {} is not a multiple of 512", sparse_size);
+    }
+    if options.backing_file.is_some() {
+        bail!("Backing file of the output image is not supported by convert");
+    }
+    let mut src = Image::open(src_path, src_format, true, src_direct)?;
+    let size = src.size();
+    create(dst_path, dst_format, Some(size), options)?;
+    let mut dst = Image::open(dst_path, Some(dst_format), false, dst_direct)?;
+    // Partial cluster of qcow2 image is allocated, so check the zeros by clusters.
+    let granularity = match &dst {
+        Image::Qcow2(qcow2) => qcow2.cluster_size().max(sparse_size),
+        Image::Raw { .. } => sparse_size,
+    };
+
+    let mut buf = vec![0_u8; IO_BUF_SIZE as usize];
+    let mut offset = 0;
+    while offset < size {
+        let (status, len) = src.block_status(offset, IO_BUF_SIZE.min(size - offset))?;
+        // The new output image reads as zeros already.
+        if status == BlockStatus::Zero && sparse_size != 0 {
+            offset += len;
+            continue;
+        }
+        let data = &mut buf[..len as usize];
+        src.read_at(data, offset)?;
+        if sparse_size == 0 {
+            dst.write_at(data, offset)?;
+            offset += len;
+            continue;
+        }
+        let mut pos = offset;
+        while pos < offset + len {
+            let next = ((pos / granularity + 1) * granularity).min(offset + len);
+            let chunk = &data[(pos - offset) as usize..(next - offset) as usize];
+            if chunk.iter().any(|b| *b != 0) {
+                dst.write_at(chunk, pos)?;
+            }
+            pos = next;
+        }
+        offset += len;
+    }
+    dst.sync()
+}
+
+/// Check the consistency of qcow2 image, return the exit code like `qemu-img check`.
+pub fn check(path: &str, format: Option<DiskFormat>, repair: bool) -> Result<i32> {
+    let mut image = Image::open(path, format, !repair, false)?;
+    let qcow2 = match &mut image {
+        Image::Qcow2(qcow2) => qcow2,
+        Image::Raw { .. } => bail!("Image format raw does not support checks"),
+    };
+    let res = qcow2.check(repair)?;
+    if res.corruptions == 0 && res.leaks == 0 {
+        println!("No errors were found on the image.");
+    }
+    if res.corruptions != 0 {
+        println!(
+            "{} errors were found on the image.\nData may be corrupted, or further writes to the image may corrupt it.",
+            res.corruptions
+        );
+    }
+    if res.leaks_fixed != 0 {
+        println!(
+            "The following inconsistencies were found and repaired:\n    {} leaked clusters",
+            res.leaks_fixed
+        );
+    } else if res.leaks != 0 {
+        println!(
+            "{} leaked clusters were found on the image.\nThis means waste of disk space, but no harm to data.",
+            res.leaks
+        );
+    }
+    if res.total_clusters != 0 {
+        println!(
+            "{}/{} = {:.2}% allocated",
+            res.allocated_clusters,
+            res.total_clusters,
+            res.allocated_clusters as f64 * 100.0 / res.total_clusters as f64
+        );
+    }
+
+    if res.corruptions != 0 {
+        Ok(CHECK_EXIT_CORRUPT)
+    } else if res.leaks > res.leaks_fixed {
+        Ok(CHECK_EXIT_LEAKED)
+    } else {
+        Ok(0)
+    }
+}
+
+/// Get the extents of the image, each of them is (offset, length, status).
+pub fn map(path: &str, format: Option<DiskFormat>) -> Result<Vec<(u64, u64, BlockStatus)>> {
+    let mut image = Image::open(path, format, true, false)?;
+    let size = image.size();
+    let mut extents: Vec<(u64, u64, BlockStatus)> = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let (status, len) = image.block_status(offset, size - offset)?;
+        match extents.last_mut() {
+            Some((_, last_len, BlockStatus::Data(host)))
+                if status == BlockStatus::Data(*host + *last_len) =>
+            {
+                *last_len += len;
+            }
+            Some((_, last_len, last_status))
+                if *last_status == status && !matches!(status, BlockStatus::Data(_)) =>
+            {
+                *last_len += len;
+            }
+            _ => extents.push((offset, len, status)),
+        }
+        offset += len;
+    }
+    Ok(extents)
+}
+
+#[cfg(test)]
+mod tests {
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    fn temp_path(temp: &TempFile) -> String {
+        temp.as_path().to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_create_and_resize_raw() {
+        let temp = TempFile::new().unwrap();
+        let path = temp_path(&temp);
+        for mode in [PreallocMode::Off, PreallocMode::Falloc, PreallocMode::Full] {
+            let options = CreateOptions {
+                preallocation: mode,
+                ..Default::default()
+            };
+            create(&path, DiskFormat::Raw, Some(1 << 20), &options).unwrap();
+            let metadata = std::fs::metadata(&path).unwrap();
+            assert_eq!(metadata.len(), 1 << 20);
+            if mode == PreallocMode::Full {
+                assert!(metadata.blocks() * 512 >= 1 << 20);
+            }
+        }
+
+        resize(&path, None, "+1M", PreallocMode::Off, false).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 2 << 20);
+        assert!(resize(&path, None, "1M", PreallocMode::Off, false).is_err());
+        resize(&path, None, "-512K", PreallocMode::Off, true).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 1536 << 10);
+        assert!(resize(&path, None, "-2M", PreallocMode::Off, true).is_err());
+    }
+
+    #[test]
+    fn test_convert_and_map() {
+        let src = TempFile::new().unwrap();
+        let src_path = temp_path(&src);
+        create(
+            &src_path,
+            DiskFormat::Raw,
+            Some(4 << 20),
+            &CreateOptions::default(),
+        )
+        .unwrap();
+        let data = vec![0x5a_u8; 8192];
+        let mut image = Image::open(&src_path, None, false, false).unwrap();
+        image.write_at(&data, 1 << 20).unwrap();
+        image.write_at(&data, 3 << 20).unwrap();
+        drop(image);
+
+        let qcow2 = TempFile::new().unwrap();
+        let qcow2_path = temp_path(&qcow2);
+        let options = CreateOptions {
+            cluster_size: Some(4096),
+            ..Default::default()
+        };
+        convert(
+            &src_path,
+            None,
+            false,
+            &qcow2_path,
+            DiskFormat::Qcow2,
+            false,
+            DEFAULT_SPARSE_SIZE,
+            &options,
+        )
+        .unwrap();
+        let extents = map(&qcow2_path, None).unwrap();
+        let data_len: u64 = extents
+            .iter()
+            .filter(|(_, _, status)| matches!(status, BlockStatus::Data(_)))
+            .map(|(_, len, _)| len)
+            .sum();
+        assert_eq!(data_len, 16384);
+        assert!(extents.iter().any(|e| e.0 == 1 << 20 && e.1 == 8192));
+        assert_eq!(check(&qcow2_path, None, false).unwrap(), 0);
+
+        // Convert back to the sparse raw image.
+        let raw = TempFile::new().unwrap();
+        let raw_path = temp_path(&raw);
+        convert(
+            &qcow2_path,
+            Some(DiskFormat::Qcow2),
+            false,
+            &raw_path,
+            DiskFormat::Raw,
+            false,
+            DEFAULT_SPARSE_SIZE,
+            &CreateOptions::default(),
+        )
+        .unwrap();
+        let mut image = Image::open(&raw_path, Some(DiskFormat::Raw), true, false).unwrap();
+        assert_eq!(image.size(), 4 << 20);
+        let mut buf = vec![0_u8; 8192];
+        image.read_at(&mut buf, 3 << 20).unwrap();
+        assert_eq!(buf, data);
+        let (status, len) = image.block_status(0, 4 << 20).unwrap();
+        assert_eq!(status, BlockStatus::Zero);
+        assert!(len > 0);
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(10 << 30), "10 GiB");
+        assert_eq!(human_size(1536 << 10), "1.5 MiB");
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+pub mod cmdline;
+pub mod img;
+
+use std::io::Write;
+
+use anyhow::{bail, Result};
+
+use crate::cmdline::{
+    parse_args, parse_cache_mode, parse_create_options, parse_size, ArgList, USAGE,
+};
+use crate::img::{PreallocMode, DEFAULT_SPARSE_SIZE};
+use block_backend::qcow2::BlockStatus;
+use machine_manager::config::DiskFormat;
+
+const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+
+pub trait ExitCode {
+    /// Returns the value to use as the exit status.
+    fn code(self) -> i32;
+}
+
+impl ExitCode for i32 {
+    fn code(self) -> i32 {
+        self
+    }
+}
+
+impl ExitCode for () {
+    fn code(self) -> i32 {
+        0
+    }
+}
+
+fn main() {
+    ::std::process::exit(match run() {
+        Ok(ret) => ExitCode::code(ret),
+        Err(ref e) => {
+            write!(&mut ::std::io::stderr(), "{}", format_args!("{:?}\r\n", e))
+                .expect("Error writing to stderr");
+
+            1
+        }
+    });
+}
+
+fn run() -> Result<i32> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => bail!("No command is given, see 'stratovirt-img --help'"),
+    };
+    match command {
+        "create" => create(args),
+        "info" => info(args),
+        "resize" => resize(args),
+        "convert" => convert(args),
+        "check" => check(args),
+        "map" => map(args),
+        "-h" | "--help" | "help" => {
+            println!("{}", USAGE);
+            Ok(0)
+        }
+        "-V" | "--version" => {
+            println!("stratovirt-img version {}", VERSION.unwrap_or("unknown"));
+            Ok(0)
+        }
+        _ => bail!("Unknown command {}, see 'stratovirt-img --help'", command),
+    }
+}
+
+/// Get the positional arguments, of which the last `optional` ones can be omitted.
+fn positional(list: &ArgList, required: usize, optional: usize) -> Result<Vec<Option<&str>>> {
+    let count = list.args.len();
+    if count < required || count > required + optional {
+        bail!("Invalid number of arguments, see 'stratovirt-img --help'");
+    }
+    Ok((0..required + optional)
+        .map(|i| list.args.get(i).map(String::as_str))
+        .collect())
+}
+
+fn create(args: &[String]) -> Result<i32> {
+    let list = parse_args(args, &["-f", "-o"], &[])?;
+    let pos = positional(&list, 1, 1)?;
+    let format = list.format_of("-f")?.unwrap_or(DiskFormat::Raw);
+    let options = parse_create_options(list.value_of("-o"))?;
+    let size = pos[1].map(parse_size).transpose()?;
+    img::create(pos[0].unwrap(), format, size, &options)?;
+    Ok(0)
+}
+
+fn info(args: &[String]) -> Result<i32> {
+    let list = parse_args(args, &["-f"], &[])?;
+    let pos = positional(&list, 1, 0)?;
+    print!("{}", img::info(pos[0].unwrap(), list.format_of("-f")?)?);
+    Ok(0)
+}
+
+fn resize(args: &[String]) -> Result<i32> {
+    let list = parse_args(args, &["-f", "--preallocation"], &["--shrink"])?;
+    let pos = positional(&list, 2, 0)?;
+    let preallocation = match list.value_of("--preallocation") {
+        Some(mode) => mode.parse::<PreallocMode>()?,
+        None => PreallocMode::Off,
+    };
+    img::resize(
+        pos[0].unwrap(),
+        list.format_of("-f")?,
+        pos[1].unwrap(),
+        preallocation,
+        list.is_present("--shrink"),
+    )?;
+    Ok(0)
+}
+
+fn convert(args: &[String]) -> Result<i32> {
+    let list = parse_args(args, &["-f", "-O", "-S", "-t", "-T", "-o"], &[])?;
+    let pos = positional(&list, 2, 0)?;
+    let sparse_size = match list.value_of("-S") {
+        Some(size) => parse_size(size)?,
+        None => DEFAULT_SPARSE_SIZE,
+    };
+    img::convert(
+        pos[0].unwrap(),
+        list.format_of("-f")?,
+        parse_cache_mode(list.value_of("-T"))?,
+        pos[1].unwrap(),
+        list.format_of("-O")?.unwrap_or(DiskFormat::Raw),
+        parse_cache_mode(list.value_of("-t"))?,
+        sparse_size,
+        &parse_create_options(list.value_of("-o"))?,
+    )?;
+    Ok(0)
+}
+
+fn check(args: &[String]) -> Result<i32> {
+    let list = parse_args(args, &["-f", "-r"], &[])?;
+    let pos = positional(&list, 1, 0)?;
+    let repair = match list.value_of("-r") {
+        Some("leaks") => true,
+        Some(mode) => bail!(
+            "Unsupported repair mode {}, only 'leaks' is supported",
+            mode
+        ),
+        None => false,
+    };
+    img::check(pos[0].unwrap(), list.format_of("-f")?, repair)
+}
+
+fn map(args: &[String]) -> Result<i32> {
+    let list = parse_args(args, &["-f"], &[])?;
+    let pos = positional(&list, 1, 0)?;
+    let extents = img::map(pos[0].unwrap(), list.format_of("-f")?)?;
+    println!("{:<20}{:<20}{:<10}Mapped to", "Offset", "Length", "Type");
+    for (offset, len, status) in extents {
+        let (kind, mapped) = match status {
+            BlockStatus::Data(host) => ("data", format!("{:#x}", host)),
+            BlockStatus::Zero => ("zero", String::from("-")),
+            BlockStatus::Backing => ("backing", String::from("-")),
+        };
+        println!(
+            "{:<20}{:<20}{:<10}{}",
+            format!("{:#x}", offset),
+            format!("{:#x}", len),
+            kind,
+            mapped
+        );
+    }
+    Ok(0)
+}
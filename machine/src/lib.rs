@@ -21,8 +21,10 @@ use std::collections::{BTreeMap, HashMap};
 use std::fs::{remove_file, File};
 use std::net::TcpListener;
 use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixListener;
 use std::path::Path;
+use std::rc::Rc;
 use std::sync::{Arc, Barrier, Condvar, Mutex, Weak};
 
 #[cfg(not(target_env = "musl"))]
@@ -41,6 +43,9 @@ use address_space::{
 };
 pub use anyhow::Result;
 use anyhow::{anyhow, bail, Context};
+use block_backend::dirty_bitmap::save_dirty_bitmaps;
+use block_backend::io_error::{retry_stopped_block_requests, set_vm_stop_req};
+use block_backend::nbd::server::revoke_nbd_export_writable;
 #[cfg(target_arch = "aarch64")]
 use cpu::CPUFeatures;
 use cpu::{ArchCPU, CPUBootConfig, CPUInterface, CPUTopology, CPU};
@@ -48,6 +53,8 @@ use devices::legacy::FwCfgOps;
 #[cfg(target_arch = "aarch64")]
 use devices::InterruptController;
 
+use devices::ahci::AhciPciDevice;
+use devices::nvme::NvmePciDevice;
 #[cfg(not(target_env = "musl"))]
 use devices::usb::{
     camera::UsbCamera, keyboard::UsbKeyboard, storage::UsbStorage, tablet::UsbTablet,
@@ -56,19 +63,20 @@ use devices::usb::{
 use devices::ScsiDisk::{ScsiDevice, SCSI_TYPE_DISK, SCSI_TYPE_ROM};
 use hypervisor::kvm::KVM_FDS;
 use machine_manager::config::{
-    complete_numa_node, get_multi_function, get_pci_bdf, parse_balloon, parse_blk, parse_demo_dev,
-    parse_device_id, parse_fs, parse_net, parse_numa_distance, parse_numa_mem, parse_rng_dev,
-    parse_root_port, parse_scsi_controller, parse_scsi_device, parse_vfio,
-    parse_vhost_user_blk_pci, parse_virtconsole, parse_virtio_serial, parse_vsock, BootIndexInfo,
-    DriveFile, Incoming, MachineMemConfig, MigrateMode, NumaConfig, NumaDistance, NumaNode,
-    NumaNodes, PFlashConfig, PciBdf, SerialConfig, VfioConfig, VmConfig, FAST_UNPLUG_ON,
-    MAX_VIRTIO_QUEUE,
+    complete_numa_node, get_multi_function, get_pci_bdf, is_nbd_path, parse_ahci, parse_balloon,
+    parse_blk, parse_demo_dev, parse_device_id, parse_fs, parse_ide_device, parse_net,
+    parse_numa_distance, parse_numa_mem, parse_nvme, parse_nvme_ns, parse_rng_dev, parse_root_port,
+    parse_scsi_controller, parse_scsi_device, parse_vfio, parse_vhost_user_blk_pci,
+    parse_virtconsole, parse_virtio_serial, parse_vsock, BootIndexInfo, DriveFile, Incoming,
+    MachineMemConfig, MigrateMode, NumaConfig, NumaDistance, NumaNode, NumaNodes, PFlashConfig,
+    PciBdf, SerialConfig, VfioConfig, VmConfig, FAST_UNPLUG_ON, MAX_VIRTIO_QUEUE,
 };
 #[cfg(not(target_env = "musl"))]
 use machine_manager::config::{
     parse_gpu, parse_usb_camera, parse_usb_keyboard, parse_usb_storage, parse_usb_tablet,
     parse_xhci,
 };
+use machine_manager::event_loop::EventLoop;
 use machine_manager::machine::{KvmVmState, MachineInterface};
 use migration::MigrationManager;
 use pci::{demo_dev::DemoDev, PciBus, PciDevOps, PciHost, RootPort};
@@ -77,6 +85,7 @@ pub use standard_vm::StdMachine;
 use sysbus::{SysBus, SysBusDevOps};
 use util::{
     arg_parser,
+    loop_context::{read_fd, EventNotifier, NotifierCallback, NotifierOperation},
     seccomp::{BpfRule, SeccompOpt, SyscallFilter},
 };
 use vfio::{VfioDevice, VfioPciDevice};
@@ -88,6 +97,7 @@ use virtio::{
     VhostKern, VhostUser, VirtioConsoleState, VirtioDevice, VirtioMmioDevice, VirtioMmioState,
     VirtioNetState, VirtioPciDevice,
 };
+use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 
 pub trait MachineOps {
     /// Calculate the ranges of memory according to architecture.
@@ -741,22 +751,12 @@ pub trait MachineOps {
         let virtio_device = virtio_pcidev.get_virtio_device().lock().unwrap();
         let cntlr = virtio_device.as_any().downcast_ref::<ScsiCntlr>().unwrap();
 
-        let bus = cntlr.bus.as_ref().unwrap();
-        if bus
-            .lock()
-            .unwrap()
-            .devices
-            .contains_key(&(device_cfg.target, device_cfg.lun))
-        {
-            bail!("Wrong! Two scsi devices have the same scsi-id and lun");
+        let iothread = cntlr.config.iothread.clone();
+        device.lock().unwrap().realize(iothread)?;
+        if let Err(e) = cntlr.attach_device(device.clone()) {
+            device.lock().unwrap().unrealize();
+            return Err(e);
         }
-        bus.lock()
-            .unwrap()
-            .devices
-            .insert((device_cfg.target, device_cfg.lun), device.clone());
-        device.lock().unwrap().parent_bus = Arc::downgrade(bus);
-
-        device.lock().unwrap().realize()?;
 
         if let Some(bootindex) = device_cfg.boot_index {
             // Eg: OpenFirmware device path(virtio-scsi disk):
@@ -774,6 +774,93 @@ pub trait MachineOps {
         Ok(())
     }
 
+    fn add_nvme(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
+        let bdf = get_pci_bdf(cfg_args)?;
+        let device_cfg = parse_nvme(vm_config, cfg_args)?;
+        let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
+
+        let pcidev = NvmePciDevice::new(
+            &device_cfg,
+            devfn,
+            parent_bus,
+            self.get_sys_mem(),
+            self.get_drive_files(),
+        );
+        pcidev
+            .realize()
+            .with_context(|| "Failed to realize nvme device")?;
+        Ok(())
+    }
+
+    fn add_nvme_ns(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
+        let ns_cfg = parse_nvme_ns(vm_config, cfg_args)?;
+        let pci_dev = self
+            .get_pci_dev_by_id_and_type(vm_config, Some(&ns_cfg.cntlr), "nvme")
+            .with_context(|| format!("Can not find nvme controller {}", ns_cfg.cntlr))?;
+        let locked_pcidev = pci_dev.lock().unwrap();
+        let nvme = locked_pcidev
+            .as_any()
+            .downcast_ref::<NvmePciDevice>()
+            .unwrap();
+        nvme.add_namespace(ns_cfg)
+    }
+
+    fn add_ahci(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
+        let bdf = get_pci_bdf(cfg_args)?;
+        let multi_func = get_multi_function(cfg_args)?;
+        let device_cfg = parse_ahci(cfg_args)?;
+        let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
+
+        let pcidev = AhciPciDevice::new(
+            &device_cfg,
+            devfn,
+            parent_bus,
+            self.get_sys_mem(),
+            self.get_drive_files(),
+            multi_func,
+        );
+        pcidev
+            .realize()
+            .with_context(|| "Failed to realize ahci device")?;
+        Ok(())
+    }
+
+    fn add_ide_device(
+        &mut self,
+        vm_config: &mut VmConfig,
+        cfg_args: &str,
+        is_cd: bool,
+    ) -> Result<()> {
+        let device_cfg = parse_ide_device(vm_config, cfg_args, is_cd)?;
+        if let Some(bootindex) = device_cfg.boot_index {
+            self.check_bootindex(bootindex)
+                .with_context(|| "Failed to add ide device for invalid bootindex")?;
+        }
+        let pci_dev = self
+            .get_pci_dev_by_id_and_type(vm_config, Some(&device_cfg.cntlr), "ich9-ahci")
+            .with_context(|| format!("Can not find ahci controller {}", device_cfg.cntlr))?;
+        let locked_pcidev = pci_dev.lock().unwrap();
+        let ahci = locked_pcidev
+            .as_any()
+            .downcast_ref::<AhciPciDevice>()
+            .unwrap();
+        ahci.add_drive(device_cfg.clone(), is_cd)?;
+
+        if let Some(bootindex) = device_cfg.boot_index {
+            // Eg: OpenFirmware device path(ide disk):
+            // /pci@i0cf8/ide@1f,2/drive@2/disk@0
+            //   |             |       |
+            //   |             |   SATA port.
+            //   |         PCI slot,[function] holding AHCI controller.
+            //  PCI root as system bus port.
+            if let Some(prefix) = locked_pcidev.get_dev_path() {
+                let dev_path = format!("{}/drive@{:x}/disk@0", prefix, device_cfg.port);
+                self.add_bootindex_devices(bootindex, &dev_path, &device_cfg.id);
+            }
+        }
+        Ok(())
+    }
+
     fn add_virtio_pci_net(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
         let bdf = get_pci_bdf(cfg_args)?;
         let multi_func = get_multi_function(cfg_args)?;
@@ -1338,6 +1425,25 @@ pub trait MachineOps {
                 "scsi-cd" => {
                     self.add_scsi_device(vm_config, cfg_args, SCSI_TYPE_ROM)?;
                 }
+                "scsi-generic" | "scsi-block" => {
+                    // The device type is got from the host device when realized.
+                    self.add_scsi_device(vm_config, cfg_args, SCSI_TYPE_DISK)?;
+                }
+                "nvme" => {
+                    self.add_nvme(vm_config, cfg_args)?;
+                }
+                "nvme-ns" => {
+                    self.add_nvme_ns(vm_config, cfg_args)?;
+                }
+                "ich9-ahci" => {
+                    self.add_ahci(vm_config, cfg_args)?;
+                }
+                "ide-hd" => {
+                    self.add_ide_device(vm_config, cfg_args, false)?;
+                }
+                "ide-cd" => {
+                    self.add_ide_device(vm_config, cfg_args, true)?;
+                }
                 "virtio-net-device" => {
                     self.add_virtio_mmio_net(vm_config, cfg_args)?;
                 }
@@ -1481,6 +1587,9 @@ pub trait MachineOps {
         let files = self.get_drive_files();
         let mut drive_files = files.lock().unwrap();
         VmConfig::add_drive_file(&mut drive_files, path, read_only, direct)?;
+        if is_nbd_path(path) {
+            return Ok(());
+        }
 
         // Lock the added file if VM is running.
         let drive_file = drive_files.get_mut(path).unwrap();
@@ -1526,6 +1635,37 @@ pub trait MachineOps {
         Ok(())
     }
 
+    /// Register event notifier to pause the VM when the requests of the block devices
+    /// are stopped by I/O errors according to the `werror`/`rerror` policies.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The machine structure.
+    fn register_block_io_error_event(vm: Arc<Mutex<Self>>) -> Result<()>
+    where
+        Self: MachineInterface + Sized + 'static,
+    {
+        let stop_req = Arc::new(EventFd::new(libc::EFD_NONBLOCK).with_context(|| {
+            MachineError::InitEventFdErr("block_io_error_stop_req".to_string())
+        })?);
+        let stop_req_handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            vm.lock().unwrap().pause();
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            stop_req.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![stop_req_handler],
+        );
+        EventLoop::update_event(vec![notifier], None)
+            .with_context(|| "Failed to register event notifier.")?;
+        set_vm_stop_req(stop_req);
+        Ok(())
+    }
+
     /// Realize the machine.
     ///
     /// # Arguments
@@ -1613,6 +1753,8 @@ pub trait MachineOps {
     /// * `vm_state` - Vm kvm vm state.
     fn vm_resume(&self, cpus: &[Arc<CPU>], vm_state: &mut KvmVmState) -> Result<()> {
         self.active_drive_files()?;
+        // The disks can be written only by the guest once it runs.
+        revoke_nbd_export_writable();
 
         for (cpu_index, cpu) in cpus.iter().enumerate() {
             if let Err(e) = cpu.resume() {
@@ -1622,6 +1764,8 @@ pub trait MachineOps {
         }
 
         *vm_state = KvmVmState::Running;
+        // Retry the block requests which stopped the VM on I/O errors.
+        retry_stopped_block_requests();
 
         Ok(())
     }
@@ -1637,6 +1781,8 @@ pub trait MachineOps {
             cpu.destroy()
                 .with_context(|| format!("Failed to destroy vcpu{}", cpu_index))?;
         }
+        // The guest can't write the disks any more.
+        save_dirty_bitmaps();
 
         *vm_state = KvmVmState::Shutdown;
 
@@ -48,6 +48,13 @@ use std::sync::{Arc, Condvar, Mutex};
 use std::vec::Vec;
 
 use address_space::{AddressSpace, GuestAddress, Region};
+use block_backend::dirty_bitmap::{
+    qmp_block_dirty_bitmap_add, qmp_block_dirty_bitmap_clear, qmp_block_dirty_bitmap_remove,
+};
+use block_backend::{
+    qmp_block_resize, qmp_block_set_io_throttle, qmp_blockdev_change_medium, qmp_eject,
+    qmp_query_block, qmp_query_blockstats, qmp_query_named_block_nodes,
+};
 use boot_loader::{load_linux, BootLoaderConfig};
 #[cfg(target_arch = "aarch64")]
 use cpu::CPUFeatures;
@@ -67,9 +74,9 @@ use hypervisor::kvm::KVM_FDS;
 use kvm_bindings::{kvm_pit_config, KVM_PIT_SPEAKER_DUMMY};
 use machine_manager::{
     config::{
-        parse_blk, parse_incoming_uri, parse_net, BlkDevConfig, BootSource, ConfigCheck, DriveFile,
-        Incoming, MigrateMode, NetworkInterfaceConfig, SerialConfig, VmConfig,
-        DEFAULT_VIRTQUEUE_SIZE,
+        parse_blk, parse_incoming_uri, parse_net, BlkDevConfig, BlockErrorPolicy, BootSource,
+        ConfigCheck, DiskFormat, DriveFile, Incoming, MigrateMode, NetworkInterfaceConfig,
+        SerialConfig, ThrottleConfig, VmConfig, DEFAULT_VIRTQUEUE_SIZE,
     },
     event,
     machine::{
@@ -88,10 +95,12 @@ use syscall::syscall_whitelist;
 use util::device_tree::{self, CompileFDT, FdtBuilder};
 use util::{
     loop_context::EventLoopManager, num_ops::str_to_usize, seccomp::BpfRule, set_termi_canon_mode,
+    slirp::SlirpConfig,
 };
 use virtio::{
-    create_tap, qmp_balloon, qmp_query_balloon, Block, BlockState, Net, VhostKern, VirtioDevice,
-    VirtioMmioDevice, VirtioMmioState, VirtioNetState,
+    create_tap, qmp_balloon, qmp_netdev_capture_start, qmp_netdev_capture_stop, qmp_query_balloon,
+    Block, BlockState, Net, VhostKern, VirtioDevice, VirtioMmioDevice, VirtioMmioState,
+    VirtioNetState,
 };
 
 use super::{error::MachineError, MachineOps};
@@ -753,6 +762,8 @@ impl MachineOps for LightMachine {
     }
 
     fn realize(vm: &Arc<Mutex<Self>>, vm_config: &mut VmConfig) -> MachineResult<()> {
+        Self::register_block_io_error_event(vm.clone())
+            .with_context(|| "Fail to register block I/O error event")?;
         let mut locked_vm = vm.lock().unwrap();
 
         //trace for lightmachine
@@ -1092,6 +1103,21 @@ impl DeviceInterface for LightMachine {
         )
     }
 
+    fn query_block(&self) -> Response {
+        let vec_block = qmp_query_block();
+        Response::create_response(serde_json::to_value(vec_block).unwrap(), None)
+    }
+
+    fn query_named_block_nodes(&self) -> Response {
+        let vec_node = qmp_query_named_block_nodes();
+        Response::create_response(serde_json::to_value(vec_node).unwrap(), None)
+    }
+
+    fn query_blockstats(&self) -> Response {
+        let vec_stats = qmp_query_blockstats();
+        Response::create_response(serde_json::to_value(vec_stats).unwrap(), None)
+    }
+
     fn device_add(&mut self, args: Box<qmp_schema::DeviceAddArgument>) -> Response {
         // get slot of bus by addr or lun
         let mut slot = 0;
@@ -1152,6 +1178,24 @@ impl DeviceInterface for LightMachine {
             direct = false;
         }
 
+        let format = match args
+            .driver
+            .as_deref()
+            .unwrap_or("raw")
+            .parse::<DiskFormat>()
+        {
+            Ok(format) => format,
+            Err(_) => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(format!(
+                        "Invalid driver argument '{}', expected 'raw' or 'qcow2'",
+                        args.driver.unwrap_or_default()
+                    )),
+                    None,
+                );
+            }
+        };
+
         let config = BlkDevConfig {
             id: args.node_name.clone(),
             path_on_host: args.file.filename.clone(),
@@ -1159,7 +1203,8 @@ impl DeviceInterface for LightMachine {
             direct,
             serial_num: None,
             iothread: None,
-            iops: None,
+            throttle: ThrottleConfig::default(),
+            throttle_group: None,
             queues: 1,
             boot_index: None,
             chardev: None,
@@ -1173,6 +1218,9 @@ impl DeviceInterface for LightMachine {
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
             discard: false,
             write_zeroes: WriteZeroesState::Off,
+            format,
+            werror: BlockErrorPolicy::Enospc,
+            rerror: BlockErrorPolicy::Report,
         };
         if let Err(e) = config.check() {
             error!("{:?}", e);
@@ -1210,9 +1258,155 @@ impl DeviceInterface for LightMachine {
         )
     }
 
+    fn block_set_io_throttle(&self, args: Box<qmp_schema::BlockSetIoThrottleArgument>) -> Response {
+        match qmp_block_set_io_throttle(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_resize(&self, device: String, size: u64) -> Response {
+        match qmp_block_resize(&device, size, &self.get_drive_files()) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn eject(&self, args: qmp_schema::EjectArgument) -> Response {
+        match qmp_eject(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn blockdev_change_medium(&self, args: qmp_schema::BlockdevChangeMediumArgument) -> Response {
+        match qmp_blockdev_change_medium(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn nbd_server_start(
+        &self,
+        _addr: qmp_schema::AddrOptions,
+        _max_connections: Option<u32>,
+    ) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "nbd-server-start not supported yet for microVM".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn nbd_server_add(
+        &self,
+        _device: String,
+        _name: Option<String>,
+        _writable: Option<bool>,
+    ) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "nbd-server-add not supported yet for microVM".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn nbd_server_remove(&self, _name: String, _mode: Option<String>) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "nbd-server-remove not supported yet for microVM".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn drive_backup(&self, _args: Box<qmp_schema::DriveBackupArgument>) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "drive-backup not supported yet for microVM".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn block_job_cancel(&self, _device: String) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "block-job-cancel not supported yet for microVM".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn block_dirty_bitmap_add(&self, args: qmp_schema::BlockDirtyBitmapAddArgument) -> Response {
+        match qmp_block_dirty_bitmap_add(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_dirty_bitmap_remove(&self, node: String, name: String) -> Response {
+        match qmp_block_dirty_bitmap_remove(&node, &name) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_dirty_bitmap_clear(&self, node: String, name: String) -> Response {
+        match qmp_block_dirty_bitmap_clear(&node, &name) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
     fn netdev_add(&mut self, args: Box<qmp_schema::NetDevAddArgument>) -> Response {
         let mut config = NetworkInterfaceConfig {
             id: args.id.clone(),
+            netdev_id: args.id.clone(),
             host_dev_name: "".to_string(),
             mac: None,
             tap_fds: None,
@@ -1223,9 +1417,14 @@ impl DeviceInterface for LightMachine {
             mq: false,
             socket_path: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            user: None,
+            socket: None,
+            af_xdp: None,
         };
 
-        if let Some(fds) = args.fds {
+        if args.net_type.as_deref() == Some("user") {
+            config.user = Some(SlirpConfig::default());
+        } else if let Some(fds) = args.fds {
             let netdev_fd = if fds.contains(':') {
                 let col: Vec<_> = fds.split(':').collect();
                 String::from(col[col.len() - 1])
@@ -1285,6 +1484,32 @@ impl DeviceInterface for LightMachine {
         )
     }
 
+    fn netdev_capture_start(&self, args: qmp_schema::NetdevCaptureStartArgument) -> Response {
+        match qmp_netdev_capture_start(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn netdev_capture_stop(&self, netdev: String) -> Response {
+        match qmp_netdev_capture_stop(&netdev) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
     fn chardev_add(&mut self, _args: qmp_schema::CharDevAddArgument) -> Response {
         Response::create_error_response(
             qmp_schema::QmpErrorClass::GenericError(
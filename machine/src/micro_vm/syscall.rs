@@ -48,10 +48,10 @@ const KVM_RUN: u32 = 0xae80;
 ///
 /// # Notes
 /// This allowlist limit syscall with:
-/// * x86_64-unknown-gnu: 49 syscalls
-/// * x86_64-unknown-musl: 48 syscalls
-/// * aarch64-unknown-gnu: 47 syscalls
-/// * aarch64-unknown-musl: 47 syscalls
+/// * x86_64-unknown-gnu: 62 syscalls
+/// * x86_64-unknown-musl: 61 syscalls
+/// * aarch64-unknown-gnu: 60 syscalls
+/// * aarch64-unknown-musl: 60 syscalls
 /// To reduce performance losses, the syscall rules is ordered by frequency.
 pub fn syscall_whitelist() -> Vec<BpfRule> {
     vec![
@@ -78,6 +78,20 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_recvmsg),
         BpfRule::new(libc::SYS_sendmsg),
         BpfRule::new(libc::SYS_recvfrom),
+        BpfRule::new(libc::SYS_sendto),
+        // Reconnect to the NBD server.
+        BpfRule::new(libc::SYS_socket),
+        BpfRule::new(libc::SYS_connect),
+        BpfRule::new(libc::SYS_setsockopt),
+        // Sockets of user mode network.
+        BpfRule::new(libc::SYS_bind),
+        BpfRule::new(libc::SYS_listen),
+        BpfRule::new(libc::SYS_getsockopt),
+        BpfRule::new(libc::SYS_shutdown),
+        BpfRule::new(libc::SYS_socketpair),
+        BpfRule::new(libc::SYS_epoll_create1),
+        BpfRule::new(libc::SYS_timerfd_create),
+        BpfRule::new(libc::SYS_timerfd_settime),
         BpfRule::new(libc::SYS_mremap),
         BpfRule::new(libc::SYS_io_setup),
         BpfRule::new(libc::SYS_brk),
@@ -122,6 +136,10 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         #[cfg(target_arch = "aarch64")]
         BpfRule::new(libc::SYS_unlinkat),
         #[cfg(target_arch = "x86_64")]
+        BpfRule::new(libc::SYS_rename),
+        #[cfg(target_arch = "aarch64")]
+        BpfRule::new(libc::SYS_renameat),
+        #[cfg(target_arch = "x86_64")]
         BpfRule::new(libc::SYS_mkdir),
         #[cfg(target_arch = "aarch64")]
         BpfRule::new(libc::SYS_mkdirat),
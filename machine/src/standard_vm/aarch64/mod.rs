@@ -532,6 +532,8 @@ impl MachineOps for StdMachine {
 
         let nr_cpus = vm_config.machine_config.nr_cpus;
         let clone_vm = vm.clone();
+        Self::register_block_io_error_event(vm.clone())
+            .with_context(|| "Fail to register block I/O error event")?;
         let mut locked_vm = vm.lock().unwrap();
         locked_vm.init_global_config(vm_config)?;
         locked_vm
@@ -56,8 +56,8 @@ const KVM_RUN: u32 = 0xae80;
 ///
 /// # Notes
 /// This allowlist limit syscall with:
-/// * aarch64-unknown-gnu: 98 syscalls
-/// * aarch64-unknown-musl: 60 syscalls
+/// * aarch64-unknown-gnu: 103 syscalls
+/// * aarch64-unknown-musl: 69 syscalls
 /// To reduce performance losses, the syscall rules is ordered by frequency.
 pub fn syscall_whitelist() -> Vec<BpfRule> {
     vec![
@@ -121,11 +121,11 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_statx),
         BpfRule::new(libc::SYS_mkdirat),
         BpfRule::new(libc::SYS_unlinkat),
+        BpfRule::new(libc::SYS_renameat),
         madvise_rule(),
         BpfRule::new(libc::SYS_msync),
         BpfRule::new(libc::SYS_readlinkat),
         BpfRule::new(libc::SYS_socket),
-        #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_bind),
         BpfRule::new(libc::SYS_connect),
         BpfRule::new(libc::SYS_getcwd),
@@ -154,7 +154,6 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_getdents64),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_clock_gettime),
-        #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_getsockopt),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_uname),
@@ -163,10 +162,13 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_faccessat),
         BpfRule::new(libc::SYS_getrandom),
-        #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_shutdown),
         BpfRule::new(libc::SYS_rt_sigaction),
         BpfRule::new(libc::SYS_setsockopt),
+        BpfRule::new(libc::SYS_socketpair),
+        BpfRule::new(libc::SYS_epoll_create1),
+        BpfRule::new(libc::SYS_timerfd_create),
+        BpfRule::new(libc::SYS_timerfd_settime),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_set_robust_list),
         #[cfg(target_env = "gnu")]
@@ -192,7 +194,6 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_fstatfs),
         #[cfg(target_env = "gnu")]
         BpfRule::new(223),
-        #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_listen),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_fchmodat),
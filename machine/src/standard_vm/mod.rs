@@ -55,21 +55,33 @@ use address_space::{
 };
 pub use anyhow::Result;
 use anyhow::{bail, Context};
+use block_backend::backup::{qmp_block_job_cancel, qmp_drive_backup, qmp_query_block_jobs};
+use block_backend::dirty_bitmap::{
+    qmp_block_dirty_bitmap_add, qmp_block_dirty_bitmap_clear, qmp_block_dirty_bitmap_remove,
+};
+use block_backend::nbd::server::{qmp_nbd_server_add, qmp_nbd_server_remove, qmp_nbd_server_start};
+use block_backend::{
+    qmp_block_resize, qmp_block_set_io_throttle, qmp_blockdev_change_medium, qmp_eject,
+    qmp_query_block, qmp_query_blockstats, qmp_query_named_block_nodes,
+};
 use cpu::{CpuTopology, CPU};
 use devices::legacy::FwCfgOps;
+use devices::ScsiDisk::{SCSI_TYPE_DISK, SCSI_TYPE_ROM};
 use machine_manager::config::{
-    get_chardev_config, get_netdev_config, get_pci_df, BlkDevConfig, ChardevType, ConfigCheck,
-    DriveConfig, ExBool, NetworkInterfaceConfig, NumaNode, NumaNodes, PciBdf, ScsiCntlrConfig,
-    VmConfig, DEFAULT_VIRTQUEUE_SIZE, MAX_VIRTIO_QUEUE,
+    get_chardev_config, get_netdev_config, get_pci_df, BlkDevConfig, BlockErrorPolicy, ChardevType,
+    ConfigCheck, DiskFormat, DriveConfig, ExBool, NetworkInterfaceConfig, NumaNode, NumaNodes,
+    PciBdf, ScsiCntlrConfig, ThrottleConfig, ThrottleLimit, VmConfig, DEFAULT_VIRTQUEUE_SIZE,
+    MAX_VIRTIO_QUEUE,
 };
 use machine_manager::machine::{DeviceInterface, KvmVmState};
-use machine_manager::qmp::{qmp_schema, QmpChannel, Response};
+use machine_manager::qmp::{qmp_schema, send_device_deleted_msg, QmpChannel, Response};
 use migration::MigrationManager;
 use pci::hotplug::{handle_plug, handle_unplug_pci_request};
 use pci::PciBus;
 use util::byte_code::ByteCode;
 use virtio::{
-    qmp_balloon, qmp_query_balloon, Block, BlockState,
+    qmp_balloon, qmp_netdev_capture_start, qmp_netdev_capture_stop, qmp_query_balloon, Block,
+    BlockState,
     ScsiCntlr::{scsi_cntlr_create_scsi_bus, ScsiCntlr},
     VhostKern, VhostUser, VirtioDevice, VirtioNetState, VirtioPciDevice,
 };
@@ -756,7 +768,8 @@ impl StdMachine {
                 direct: conf.direct,
                 serial_num: args.serial_num.clone(),
                 iothread: args.iothread.clone(),
-                iops: conf.iops,
+                throttle: conf.throttle,
+                throttle_group: locked_vmconfig.get_throttle_group(&conf.throttle_group)?,
                 queues: args.queues.unwrap_or_else(|| {
                     VirtioPciDevice::virtio_pci_auto_queues_num(0, nr_cpus, MAX_VIRTIO_QUEUE)
                 }),
@@ -767,6 +780,9 @@ impl StdMachine {
                 queue_size,
                 discard: conf.discard,
                 write_zeroes: conf.write_zeroes,
+                format: conf.format,
+                werror: conf.werror,
+                rerror: conf.rerror,
             };
             dev.check()?;
             dev
@@ -912,6 +928,7 @@ impl StdMachine {
             }
             let dev = NetworkInterfaceConfig {
                 id: args.id.clone(),
+                netdev_id: netdev.clone(),
                 host_dev_name: conf.ifname.clone(),
                 mac: args.mac.clone(),
                 tap_fds: conf.tap_fds.clone(),
@@ -922,6 +939,9 @@ impl StdMachine {
                 mq: conf.queues > 2,
                 socket_path,
                 queue_size,
+                user: conf.user.clone(),
+                socket: conf.socket.clone(),
+                af_xdp: conf.af_xdp.clone(),
             };
             dev.check()?;
             dev
@@ -981,6 +1001,76 @@ impl StdMachine {
         Ok(())
     }
 
+    fn plug_scsi_device(&mut self, args: &qmp_schema::DeviceAddArgument) -> Result<()> {
+        let drive = args.drive.as_ref().with_context(|| "Drive not set")?;
+        let bus = args.bus.as_ref().with_context(|| "Bus not set")?;
+        let mut cfg_args = format!("{},id={},bus={},drive={}", args.driver, args.id, bus, drive);
+        if let Some(target) = args.scsi_id {
+            cfg_args = format!("{},scsi-id={}", cfg_args, target);
+        }
+        if let Some(lun) = args.lun {
+            cfg_args = format!("{},lun={}", cfg_args, lun);
+        }
+        if let Some(serial_num) = &args.serial_num {
+            cfg_args = format!("{},serial={}", cfg_args, serial_num);
+        }
+        if let Some(bootindex) = args.boot_index {
+            cfg_args = format!("{},bootindex={}", cfg_args, bootindex);
+        }
+        let scsi_type = match args.driver.as_str() {
+            "scsi-hd" => SCSI_TYPE_DISK,
+            _ => SCSI_TYPE_ROM,
+        };
+
+        let vm_config = self.get_vm_config();
+        let mut locked_vmconfig = vm_config.lock().unwrap();
+        if locked_vmconfig.get_scsi_device_addr(&args.id).is_some() {
+            bail!("Device id {} existed in scsi bus", args.id);
+        }
+        let drive_cfg = locked_vmconfig
+            .drives
+            .get(drive)
+            .cloned()
+            .with_context(|| "Drive not found")?;
+        let ret = self.add_scsi_device(&mut locked_vmconfig, &cfg_args, scsi_type);
+        // The drive is taken by the scsi device, keep it for blockdev-del.
+        locked_vmconfig.drives.insert(drive.clone(), drive_cfg);
+        ret?;
+        locked_vmconfig.add_device(&cfg_args)?;
+
+        Ok(())
+    }
+
+    /// Detach the scsi device from its controller. Return false if the device is not
+    /// a scsi device.
+    fn handle_unplug_scsi_request(&mut self, id: &str) -> Result<bool> {
+        let vm_config = self.get_vm_config();
+        let mut locked_vmconfig = vm_config.lock().unwrap();
+        let (cntlr_id, target, lun) = match locked_vmconfig.get_scsi_device_addr(id) {
+            Some(addr) => addr,
+            None => return Ok(false),
+        };
+        let pci_dev = self
+            .get_pci_dev_by_id_and_type(&mut locked_vmconfig, Some(&cntlr_id), "virtio-scsi-pci")
+            .with_context(|| format!("Can not find scsi controller {}", cntlr_id))?;
+        let locked_pcidev = pci_dev.lock().unwrap();
+        let virtio_pcidev = locked_pcidev
+            .as_any()
+            .downcast_ref::<VirtioPciDevice>()
+            .unwrap();
+        let virtio_device = virtio_pcidev.get_virtio_device().lock().unwrap();
+        let cntlr = virtio_device.as_any().downcast_ref::<ScsiCntlr>().unwrap();
+        cntlr.detach_device(target, lun)?;
+        drop(virtio_device);
+        drop(locked_pcidev);
+
+        locked_vmconfig.del_device_by_id(id.to_string());
+        drop(locked_vmconfig);
+        self.del_bootindex_devices(id);
+        send_device_deleted_msg(id);
+        Ok(true)
+    }
+
     fn plug_vfio_pci_device(
         &mut self,
         bdf: &PciBdf,
@@ -1105,6 +1195,26 @@ impl DeviceInterface for StdMachine {
         )
     }
 
+    fn query_block(&self) -> Response {
+        let vec_block = qmp_query_block();
+        Response::create_response(serde_json::to_value(vec_block).unwrap(), None)
+    }
+
+    fn query_named_block_nodes(&self) -> Response {
+        let vec_node = qmp_query_named_block_nodes();
+        Response::create_response(serde_json::to_value(vec_node).unwrap(), None)
+    }
+
+    fn query_blockstats(&self) -> Response {
+        let vec_stats = qmp_query_blockstats();
+        Response::create_response(serde_json::to_value(vec_stats).unwrap(), None)
+    }
+
+    fn query_block_jobs(&self) -> Response {
+        let vec_jobs = qmp_query_block_jobs();
+        Response::create_response(serde_json::to_value(vec_jobs).unwrap(), None)
+    }
+
     fn device_add(&mut self, args: Box<qmp_schema::DeviceAddArgument>) -> Response {
         if let Err(e) = self.check_device_id_existed(&args.id) {
             return Response::create_error_response(
@@ -1175,6 +1285,17 @@ impl DeviceInterface for StdMachine {
                     );
                 }
             }
+            "scsi-hd" | "scsi-cd" => {
+                if let Err(e) = self.plug_scsi_device(args.as_ref()) {
+                    error!("{:?}", e);
+                    let err_str = format!("Failed to add scsi device: {}", e);
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(err_str),
+                        None,
+                    );
+                }
+                return Response::create_empty_response();
+            }
             #[cfg(not(target_env = "musl"))]
             "usb-kbd" | "usb-tablet" => {
                 if let Err(e) = self.plug_usb_device(args.as_ref()) {
@@ -1255,7 +1376,18 @@ impl DeviceInterface for StdMachine {
         }
         drop(locked_pci_host);
 
-        // The device is not a pci device, assume it is a usb device.
+        match self.handle_unplug_scsi_request(&device_id) {
+            Ok(true) => return Response::create_empty_response(),
+            Ok(false) => {}
+            Err(e) => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+
+        // The device is neither a pci device nor a scsi device, assume it is a usb device.
         #[cfg(not(target_env = "musl"))]
         return match self.handle_unplug_usb_request(device_id) {
             Ok(()) => Response::create_empty_response(),
@@ -1278,13 +1410,36 @@ impl DeviceInterface for StdMachine {
             path_on_host: args.file.filename.clone(),
             read_only: args.read_only.unwrap_or(false),
             direct: true,
-            iops: args.iops,
+            throttle: ThrottleConfig {
+                iops_total: ThrottleLimit::new(args.iops.unwrap_or(0)),
+                ..Default::default()
+            },
+            throttle_group: None,
             // TODO Add aio option by qmp, now we set it based on "direct".
             aio: AioEngine::Native,
             media: "disk".to_string(),
             discard: false,
             write_zeroes: WriteZeroesState::Off,
+            format: DiskFormat::Raw,
+            werror: BlockErrorPolicy::Enospc,
+            rerror: BlockErrorPolicy::Report,
+            snapshot: false,
         };
+        if let Some(driver) = args.driver.as_ref() {
+            match driver.parse::<DiskFormat>() {
+                Ok(format) => config.format = format,
+                Err(_) => {
+                    let err_msg = format!(
+                        "Invalid driver argument '{}', expected 'raw' or 'qcow2'",
+                        driver
+                    );
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(err_msg),
+                        None,
+                    );
+                }
+            }
+        }
         if args.cache.is_some() && !args.cache.unwrap().direct.unwrap_or(true) {
             config.direct = false;
             config.aio = AioEngine::Off;
@@ -1380,6 +1535,178 @@ impl DeviceInterface for StdMachine {
         }
     }
 
+    fn block_set_io_throttle(&self, args: Box<qmp_schema::BlockSetIoThrottleArgument>) -> Response {
+        match qmp_block_set_io_throttle(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_resize(&self, device: String, size: u64) -> Response {
+        match qmp_block_resize(&device, size, &self.get_drive_files()) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn eject(&self, args: qmp_schema::EjectArgument) -> Response {
+        match qmp_eject(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn blockdev_change_medium(&self, args: qmp_schema::BlockdevChangeMediumArgument) -> Response {
+        match qmp_blockdev_change_medium(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn nbd_server_start(
+        &self,
+        addr: qmp_schema::AddrOptions,
+        max_connections: Option<u32>,
+    ) -> Response {
+        match qmp_nbd_server_start(&addr, max_connections) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn nbd_server_add(
+        &self,
+        device: String,
+        name: Option<String>,
+        writable: Option<bool>,
+    ) -> Response {
+        let vm_paused = *self.get_vm_state().deref().0.lock().unwrap() == KvmVmState::Paused;
+        match qmp_nbd_server_add(
+            &device,
+            name.as_deref(),
+            writable.unwrap_or(false),
+            vm_paused,
+            &self.get_drive_files(),
+        ) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn nbd_server_remove(&self, name: String, mode: Option<String>) -> Response {
+        match qmp_nbd_server_remove(&name, mode.as_deref()) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn drive_backup(&self, args: Box<qmp_schema::DriveBackupArgument>) -> Response {
+        match qmp_drive_backup(&args, &self.get_drive_files()) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_job_cancel(&self, device: String) -> Response {
+        match qmp_block_job_cancel(&device) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_dirty_bitmap_add(&self, args: qmp_schema::BlockDirtyBitmapAddArgument) -> Response {
+        match qmp_block_dirty_bitmap_add(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_dirty_bitmap_remove(&self, node: String, name: String) -> Response {
+        match qmp_block_dirty_bitmap_remove(&node, &name) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_dirty_bitmap_clear(&self, node: String, name: String) -> Response {
+        match qmp_block_dirty_bitmap_clear(&node, &name) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
     fn chardev_add(&mut self, args: qmp_schema::CharDevAddArgument) -> Response {
         let config = match get_chardev_config(args) {
             Ok(conf) => conf,
@@ -1457,6 +1784,32 @@ impl DeviceInterface for StdMachine {
         }
     }
 
+    fn netdev_capture_start(&self, args: qmp_schema::NetdevCaptureStartArgument) -> Response {
+        match qmp_netdev_capture_start(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn netdev_capture_stop(&self, netdev: String) -> Response {
+        match qmp_netdev_capture_stop(&netdev) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
     fn getfd(&self, fd_name: String, if_fd: Option<RawFd>) -> Response {
         if let Some(fd) = if_fd {
             QmpChannel::set_fd(fd_name, fd);
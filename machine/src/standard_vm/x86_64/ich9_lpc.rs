@@ -15,7 +15,6 @@ use std::sync::{
     Arc, Mutex, Weak,
 };
 
-use super::VENDOR_ID_INTEL;
 use crate::standard_vm::Result;
 use acpi::{AcpiPMTimer, AcpiPmCtrl, AcpiPmEvent};
 use address_space::{AddressSpace, GuestAddress, Region, RegionOps};
@@ -24,7 +23,7 @@ use log::error;
 use pci::config::CLASS_CODE_ISA_BRIDGE;
 use pci::config::{
     PciConfig, DEVICE_ID, HEADER_TYPE, HEADER_TYPE_BRIDGE, HEADER_TYPE_MULTIFUNC,
-    PCI_CONFIG_SPACE_SIZE, SUB_CLASS_CODE, VENDOR_ID,
+    PCI_CONFIG_SPACE_SIZE, PCI_VENDOR_ID_INTEL, SUB_CLASS_CODE, VENDOR_ID,
 };
 use pci::Result as PciResult;
 use pci::{le_write_u16, le_write_u32, ranges_overlap, PciBus, PciDevOps};
@@ -237,7 +236,11 @@ impl PciDevOps for LPCBridge {
         self.init_write_mask()?;
         self.init_write_clear_mask()?;
 
-        le_write_u16(&mut self.config.config, VENDOR_ID as usize, VENDOR_ID_INTEL)?;
+        le_write_u16(
+            &mut self.config.config,
+            VENDOR_ID as usize,
+            PCI_VENDOR_ID_INTEL,
+        )?;
         le_write_u16(
             &mut self.config.config,
             DEVICE_ID as usize,
@@ -17,14 +17,12 @@ use anyhow::{bail, Result};
 use log::error;
 use pci::{
     config::{
-        PciConfig, CLASS_CODE_HOST_BRIDGE, DEVICE_ID, PCI_CONFIG_SPACE_SIZE, SUB_CLASS_CODE,
-        VENDOR_ID,
+        PciConfig, CLASS_CODE_HOST_BRIDGE, DEVICE_ID, PCI_CONFIG_SPACE_SIZE, PCI_VENDOR_ID_INTEL,
+        SUB_CLASS_CODE, VENDOR_ID,
     },
     le_read_u64, le_write_u16, ranges_overlap, PciBus, PciDevOps, Result as PciResult,
 };
 
-use super::VENDOR_ID_INTEL;
-
 const DEVICE_ID_INTEL_Q35_MCH: u16 = 0x29c0;
 
 const PCIEXBAR: u8 = 0x60;
@@ -126,7 +124,11 @@ impl PciDevOps for Mch {
         self.init_write_mask()?;
         self.init_write_clear_mask()?;
 
-        le_write_u16(&mut self.config.config, VENDOR_ID as usize, VENDOR_ID_INTEL)?;
+        le_write_u16(
+            &mut self.config.config,
+            VENDOR_ID as usize,
+            PCI_VENDOR_ID_INTEL,
+        )?;
         le_write_u16(
             &mut self.config.config,
             DEVICE_ID as usize,
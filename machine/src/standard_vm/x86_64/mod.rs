@@ -67,7 +67,6 @@ use anyhow::{bail, Context, Result};
 #[cfg(not(target_env = "musl"))]
 use ui::{gtk::gtk_display_init, vnc::vnc_init};
 
-const VENDOR_ID_INTEL: u16 = 0x8086;
 const HOLE_640K_START: u64 = 0x000A_0000;
 const HOLE_640K_END: u64 = 0x0010_0000;
 
@@ -453,6 +452,8 @@ impl MachineOps for StdMachine {
     fn realize(vm: &Arc<Mutex<Self>>, vm_config: &mut VmConfig) -> Result<()> {
         let nr_cpus = vm_config.machine_config.nr_cpus;
         let clone_vm = vm.clone();
+        Self::register_block_io_error_event(vm.clone())
+            .with_context(|| "Fail to register block I/O error event")?;
         let mut locked_vm = vm.lock().unwrap();
         locked_vm.init_global_config(vm_config)?;
         locked_vm.numa_nodes = locked_vm.add_numa_nodes(vm_config)?;
@@ -56,8 +56,8 @@ const KVM_RUN: u32 = 0xae80;
 ///
 /// # Notes
 /// This allowlist limit syscall with:
-/// * x86_64-unknown-gnu: 96 syscalls
-/// * x86_64-unknown-musl: 63 syscalls
+/// * x86_64-unknown-gnu: 102 syscalls
+/// * x86_64-unknown-musl: 72 syscalls
 /// To reduce performance losses, the syscall rules is ordered by frequency.
 pub fn syscall_whitelist() -> Vec<BpfRule> {
     vec![
@@ -126,13 +126,14 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_statx),
         BpfRule::new(libc::SYS_mkdir),
         BpfRule::new(libc::SYS_unlink),
+        BpfRule::new(libc::SYS_rename),
         madvise_rule(),
         BpfRule::new(libc::SYS_msync),
         BpfRule::new(libc::SYS_readlinkat),
         BpfRule::new(libc::SYS_readlink),
         BpfRule::new(libc::SYS_socket),
-        #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_bind),
+        BpfRule::new(libc::SYS_listen),
         BpfRule::new(libc::SYS_connect),
         BpfRule::new(libc::SYS_getcwd),
         #[cfg(target_env = "musl")]
@@ -163,7 +164,6 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_getdents64),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_clock_gettime),
-        #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_getsockopt),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_uname),
@@ -171,10 +171,13 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_sysinfo),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_faccessat),
-        #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_shutdown),
         BpfRule::new(libc::SYS_getrandom),
         BpfRule::new(libc::SYS_setsockopt),
+        BpfRule::new(libc::SYS_socketpair),
+        BpfRule::new(libc::SYS_epoll_create1),
+        BpfRule::new(libc::SYS_timerfd_create),
+        BpfRule::new(libc::SYS_timerfd_settime),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_rt_sigaction),
         #[cfg(target_env = "gnu")]
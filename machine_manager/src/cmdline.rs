@@ -198,7 +198,8 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .multiple(true)
             .long("drive")
             .value_name("<parameters>")
-            .help("\n\t\tset block drive image: -drive id=<drive_id>,file=<path_on_host>[,readonly=on|off][,direct=on|off][,throttling.iops-total=<200>]; \
+            .help("\n\t\tset block drive image: -drive id=<drive_id>,file=<path_on_host>[,readonly=on|off][,direct=on|off][,throttling.{iops|bps}-{total|read|write}[-max[-length]]=<limit>][,throttling.group=<group_id>][,werror=report|ignore|stop|enospc][,rerror=report|ignore|stop|enospc]; \
+                   \n\t\tset NBD drive: -drive id=<drive_id>,file=nbd:unix:<socket_path>|nbd:<host>:<port>[:exportname=<export_name>]; \
                    \n\t\tset pflash drive image: -drive file=<pflash_path>,if=pflash,unit=0|1[,readonly=true|false]; \
                    \n\t\tset scsi drive image: -drive id=<drive-scsi0-0-0-0>,file=<path_on_host>[,readonly=true|false]")
             .takes_values(true),
@@ -308,7 +309,8 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
                    \n\t\tadd iothread object: -object iothread,id=<iothread_id>; \
                    \n\t\tadd rng object: -object rng-random,id=<rng_id>,filename=<file_path>; \
                    \n\t\tadd vnc tls object: -object tls-creds-x509,id=<vnc_id>,dir=</etc/pki/vnc>; \
-                   \n\t\tadd authz object: -object authz-simple,id=<authz_id>,identity=<username>")
+                   \n\t\tadd authz object: -object authz-simple,id=<authz_id>,identity=<username>; \
+                   \n\t\tadd throttle group object: -object throttle-group,id=<group_id>[,x-iops-total=<limit>][,x-bps-total=<limit>]")
             .takes_values(true),
         )
         .arg(
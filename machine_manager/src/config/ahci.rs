@@ -0,0 +1,258 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::{error::ConfigError, pci_args_check};
+use crate::config::{check_arg_too_long, CmdParser, ConfigCheck, DriveConfig, VmConfig};
+
+/// Number of SATA ports of the ICH9 AHCI controller.
+pub const AHCI_MAX_PORTS: u8 = 6;
+/// The serial number is a 20 bytes ASCII string in IDENTIFY DEVICE data.
+const IDE_MAX_SERIAL_LEN: usize = 20;
+
+#[derive(Debug, Clone, Default)]
+pub struct AhciConfig {
+    /// AHCI controller device id.
+    pub id: String,
+    /// Thread name of io handler.
+    pub iothread: Option<String>,
+}
+
+impl ConfigCheck for AhciConfig {
+    fn check(&self) -> Result<()> {
+        check_arg_too_long(&self.id, "ahci device id")?;
+
+        if let Some(iothread) = self.iothread.as_ref() {
+            check_arg_too_long(iothread, "iothread name")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IdeDevConfig {
+    /// IDE device id, the block device is named by it in qmp.
+    pub id: String,
+    /// AHCI controller which the device attaches to.
+    pub cntlr: String,
+    /// SATA port of the controller.
+    pub port: u8,
+    /// Serial number reported by IDENTIFY DEVICE.
+    pub serial: Option<String>,
+    /// Boot order.
+    pub boot_index: Option<u8>,
+    /// The drive of the device, the drive of the CD-ROM may have no medium.
+    pub drive: DriveConfig,
+}
+
+impl ConfigCheck for IdeDevConfig {
+    fn check(&self) -> Result<()> {
+        check_arg_too_long(&self.id, "ide device id")?;
+
+        if let Some(serial) = self.serial.as_ref() {
+            if serial.len() > IDE_MAX_SERIAL_LEN {
+                return Err(anyhow!(ConfigError::StringLengthTooLong(
+                    "ide serial".to_string(),
+                    IDE_MAX_SERIAL_LEN,
+                )));
+            }
+            if !serial.is_ascii() {
+                bail!("The serial of ide device {} must be ASCII", self.id);
+            }
+        }
+
+        if self.port >= AHCI_MAX_PORTS {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "port of ide device".to_string(),
+                0,
+                true,
+                (AHCI_MAX_PORTS - 1) as u64,
+                true,
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+pub fn parse_ahci(ahci_config: &str) -> Result<AhciConfig> {
+    let mut cmd_parser = CmdParser::new("ich9-ahci");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("bus")
+        .push("addr")
+        .push("multifunction")
+        .push("iothread");
+
+    cmd_parser.parse(ahci_config)?;
+
+    pci_args_check(&cmd_parser)?;
+
+    let id = cmd_parser
+        .get_value::<String>("id")?
+        .with_context(|| ConfigError::FieldIsMissing("id".to_string(), "ich9-ahci".to_string()))?;
+    let ahci_cfg = AhciConfig {
+        id,
+        iothread: cmd_parser.get_value::<String>("iothread")?,
+    };
+    ahci_cfg.check()?;
+    Ok(ahci_cfg)
+}
+
+pub fn parse_ide_device(
+    vm_config: &mut VmConfig,
+    dev_config: &str,
+    is_cd: bool,
+) -> Result<IdeDevConfig> {
+    let dev_type = if is_cd { "ide-cd" } else { "ide-hd" };
+    let mut cmd_parser = CmdParser::new(dev_type);
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("bus")
+        .push("drive")
+        .push("serial")
+        .push("bootindex");
+
+    cmd_parser.parse(dev_config)?;
+
+    let id = cmd_parser
+        .get_value::<String>("id")?
+        .with_context(|| ConfigError::FieldIsMissing("id".to_string(), dev_type.to_string()))?;
+    let bus = cmd_parser
+        .get_value::<String>("bus")?
+        .with_context(|| ConfigError::FieldIsMissing("bus".to_string(), dev_type.to_string()))?;
+    // Format "$parent_cntlr_name.$port" is required by the AHCI controller.
+    let strs = bus.split('.').collect::<Vec<&str>>();
+    if strs.len() != 2 || strs[0].is_empty() {
+        bail!("Invalid ide bus {}", bus);
+    }
+    let port = strs[1]
+        .parse::<u8>()
+        .with_context(|| format!("Invalid port of ide bus {}", bus))?;
+
+    let drive = cmd_parser
+        .get_value::<String>("drive")?
+        .with_context(|| ConfigError::FieldIsMissing("drive".to_string(), dev_type.to_string()))?;
+    let drive = vm_config
+        .drives
+        .remove(&drive)
+        .with_context(|| format!("No drive {} configured matched for {}", drive, dev_type))?;
+    // Only the CD-ROM can start without medium.
+    if !is_cd && drive.path_on_host.is_empty() {
+        return Err(anyhow!(ConfigError::FieldIsMissing(
+            "file".to_string(),
+            "ide-hd drive".to_string()
+        )));
+    }
+
+    let ide_cfg = IdeDevConfig {
+        id,
+        cntlr: strs[0].to_string(),
+        port,
+        serial: cmd_parser.get_value::<String>("serial")?,
+        boot_index: cmd_parser.get_value::<u8>("bootindex")?,
+        drive,
+    };
+    ide_cfg.check()?;
+    Ok(ide_cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ahci_config_cmdline_parser() {
+        let ahci_cfg = parse_ahci("ich9-ahci,id=ahci0,bus=pcie.0,addr=0x1f.0x2").unwrap();
+        assert_eq!(ahci_cfg.id, "ahci0");
+        assert!(ahci_cfg.iothread.is_none());
+        let ahci_cfg =
+            parse_ahci("ich9-ahci,id=ahci1,bus=pcie.0,addr=0x5,iothread=iothread1").unwrap();
+        assert_eq!(ahci_cfg.iothread, Some("iothread1".to_string()));
+
+        assert!(parse_ahci("ich9-ahci,bus=pcie.0,addr=0x5").is_err());
+        assert!(parse_ahci("ich9-ahci,id=ahci2,bus=pcie.0,addr=0x5,drive=drive0").is_err());
+    }
+
+    #[test]
+    fn test_ide_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=drive0,file=/path/to/disk0,direct=off,aio=off")
+            .is_ok());
+        assert!(vm_config
+            .add_drive("id=drive1,file=/path/to/cd,media=cdrom,readonly=on")
+            .is_ok());
+        let ide_cfg = parse_ide_device(
+            &mut vm_config,
+            "ide-hd,id=disk0,bus=ahci0.2,drive=drive0,serial=abc123,bootindex=1",
+            false,
+        )
+        .unwrap();
+        assert_eq!(ide_cfg.cntlr, "ahci0");
+        assert_eq!(ide_cfg.port, 2);
+        assert_eq!(ide_cfg.serial, Some("abc123".to_string()));
+        assert_eq!(ide_cfg.boot_index, Some(1));
+        assert_eq!(ide_cfg.drive.path_on_host, "/path/to/disk0");
+
+        // The drive has been used.
+        assert!(parse_ide_device(
+            &mut vm_config,
+            "ide-hd,id=disk1,bus=ahci0.3,drive=drive0",
+            false
+        )
+        .is_err());
+        // The hard disk requires a drive.
+        assert!(parse_ide_device(&mut vm_config, "ide-hd,id=disk1,bus=ahci0.3", false).is_err());
+        // Invalid port.
+        assert!(vm_config.add_drive("id=drive3,media=cdrom").is_ok());
+        assert!(parse_ide_device(
+            &mut vm_config,
+            "ide-cd,id=cd0,bus=ahci0.6,drive=drive3",
+            true
+        )
+        .is_err());
+        assert!(
+            parse_ide_device(&mut vm_config, "ide-cd,id=cd0,bus=ahci0,drive=drive1", true).is_err()
+        );
+
+        let ide_cfg = parse_ide_device(
+            &mut vm_config,
+            "ide-cd,id=cd0,bus=ahci0.5,drive=drive1",
+            true,
+        )
+        .unwrap();
+        assert_eq!(ide_cfg.port, 5);
+        assert_eq!(ide_cfg.drive.path_on_host, "/path/to/cd");
+
+        // The CD-ROM can be created without medium.
+        assert!(vm_config.add_drive("id=drive2,media=cdrom").is_ok());
+        assert!(vm_config.add_drive("id=drive4,media=cdrom").is_ok());
+        let ide_cfg = parse_ide_device(
+            &mut vm_config,
+            "ide-cd,id=cd1,bus=ahci0.4,drive=drive2",
+            true,
+        )
+        .unwrap();
+        assert!(ide_cfg.drive.path_on_host.is_empty());
+        assert!(parse_ide_device(
+            &mut vm_config,
+            "ide-cd,id=cd2,bus=ahci0.1,drive=drive4,serial=123456789012345678901",
+            true
+        )
+        .is_err());
+    }
+}
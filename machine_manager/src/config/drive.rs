@@ -13,6 +13,7 @@
 use std::fs::{metadata, File};
 use std::os::linux::fs::MetadataExt;
 use std::path::Path;
+use std::str::FromStr;
 
 use anyhow::{anyhow, bail, Context, Result};
 use log::error;
@@ -20,13 +21,16 @@ use serde::{Deserialize, Serialize};
 
 use super::{error::ConfigError, pci_args_check};
 use crate::config::{
-    check_arg_too_long, get_chardev_socket_path, CmdParser, ConfigCheck, ExBool, VmConfig,
-    DEFAULT_VIRTQUEUE_SIZE, MAX_PATH_LENGTH, MAX_STRING_LENGTH, MAX_VIRTIO_QUEUE,
+    check_arg_too_long, get_chardev_socket_path, CmdParser, ConfigCheck, ExBool,
+    ThrottleGroupConfig, VmConfig, DEFAULT_VIRTQUEUE_SIZE, MAX_PATH_LENGTH, MAX_STRING_LENGTH,
+    MAX_VIRTIO_QUEUE,
 };
 use crate::qmp::qmp_schema;
-use util::aio::{aio_probe, AioEngine, WriteZeroesState};
+use util::aio::{aio_probe, AioEngine, IoUringConfig, WriteZeroesState};
 const MAX_SERIAL_NUM: usize = 20;
 const MAX_IOPS: u64 = 1_000_000;
+const MAX_BPS: u64 = 1_000_000_000_000;
+const MAX_BURST_LENGTH: u64 = 3600;
 const MAX_UNIT_ID: usize = 2;
 
 // Seg_max = queue_size - 2. So, size of each virtqueue for virtio-blk should be larger than 2.
@@ -34,6 +38,331 @@ const MIN_QUEUE_SIZE_BLK: u16 = 2;
 // Max size of each virtqueue for virtio-blk.
 const MAX_QUEUE_SIZE_BLK: u16 = 1024;
 
+/// Format of the drive image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiskFormat {
+    Raw,
+    Qcow2,
+}
+
+impl FromStr for DiskFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(DiskFormat::Raw),
+            "qcow2" => Ok(DiskFormat::Qcow2),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for DiskFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiskFormat::Raw => write!(f, "raw"),
+            DiskFormat::Qcow2 => write!(f, "qcow2"),
+        }
+    }
+}
+
+/// Policy of handling the failed requests of the drive, set by `werror` and `rerror`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlockErrorPolicy {
+    /// Report the error to the guest.
+    Report,
+    /// Ignore the error and complete the request successfully.
+    Ignore,
+    /// Stop the VM and retry the request when the VM is resumed.
+    Stop,
+    /// Stop the VM if the host runs out of space, otherwise report the error.
+    Enospc,
+}
+
+impl FromStr for BlockErrorPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(BlockErrorPolicy::Report),
+            "ignore" => Ok(BlockErrorPolicy::Ignore),
+            "stop" => Ok(BlockErrorPolicy::Stop),
+            "enospc" => Ok(BlockErrorPolicy::Enospc),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for BlockErrorPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockErrorPolicy::Report => write!(f, "report"),
+            BlockErrorPolicy::Ignore => write!(f, "ignore"),
+            BlockErrorPolicy::Stop => write!(f, "stop"),
+            BlockErrorPolicy::Enospc => write!(f, "enospc"),
+        }
+    }
+}
+
+/// Prefix of the path of the drive exported by an NBD server.
+const NBD_PATH_PREFIX: &str = "nbd:";
+/// Max length of the NBD export name.
+const MAX_NBD_EXPORT_NAME_LEN: usize = 4096;
+
+/// Address of the NBD server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NbdServerAddr {
+    /// Path of the unix socket.
+    Unix(String),
+    /// Host and port of the TCP socket.
+    Tcp(String, u16),
+}
+
+impl std::fmt::Display for NbdServerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NbdServerAddr::Unix(path) => write!(f, "unix:{}", path),
+            NbdServerAddr::Tcp(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// Configuration of the drive exported by an NBD server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NbdConfig {
+    /// Address of the NBD server.
+    pub addr: NbdServerAddr,
+    /// Name of the export, empty for the default export.
+    pub export: String,
+}
+
+/// Return true if the drive is exported by an NBD server instead of a host file.
+pub fn is_nbd_path(path: &str) -> bool {
+    path.starts_with(NBD_PATH_PREFIX)
+}
+
+/// Parse the path of the drive exported by an NBD server, which is
+/// `nbd:unix:<socket_path>[:exportname=<name>]` or `nbd:<host>:<port>[:exportname=<name>]`.
+pub fn parse_nbd_path(path: &str) -> Result<NbdConfig> {
+    let invalid_path = || {
+        anyhow!(ConfigError::InvalidParam(
+            path.to_string(),
+            "file".to_string()
+        ))
+    };
+    let addr_export = path
+        .strip_prefix(NBD_PATH_PREFIX)
+        .ok_or_else(invalid_path)?;
+    let (addr, export) = match addr_export.split_once(":exportname=") {
+        Some((addr, export)) => (addr, export.to_string()),
+        None => (addr_export, String::new()),
+    };
+    if export.len() > MAX_NBD_EXPORT_NAME_LEN {
+        return Err(anyhow!(ConfigError::StringLengthTooLong(
+            "NBD export name".to_string(),
+            MAX_NBD_EXPORT_NAME_LEN,
+        )));
+    }
+
+    let addr = if let Some(socket_path) = addr.strip_prefix("unix:") {
+        if socket_path.is_empty() {
+            return Err(invalid_path());
+        }
+        if socket_path.len() > MAX_PATH_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "NBD socket path".to_string(),
+                MAX_PATH_LENGTH,
+            )));
+        }
+        NbdServerAddr::Unix(socket_path.to_string())
+    } else {
+        let (host, port) = addr.rsplit_once(':').ok_or_else(invalid_path)?;
+        let port = port.parse::<u16>().map_err(|_| invalid_path())?;
+        if host.is_empty() {
+            return Err(invalid_path());
+        }
+        check_arg_too_long(host, "NBD server host")?;
+        NbdServerAddr::Tcp(host.to_string(), port)
+    };
+
+    Ok(NbdConfig { addr, export })
+}
+
+/// Names of the throttled resources, used as the suffix of `throttling.` options.
+const THROTTLE_LIMIT_NAMES: [&str; 6] = [
+    "bps-total",
+    "bps-read",
+    "bps-write",
+    "iops-total",
+    "iops-read",
+    "iops-write",
+];
+
+/// Limit of one throttled resource, in bytes or operations per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThrottleLimit {
+    /// Average limit, zero means no limit.
+    pub avg: u64,
+    /// Burst limit, zero means no burst is allowed.
+    pub max: u64,
+    /// Seconds that the burst limit can last.
+    pub max_length: u64,
+}
+
+impl Default for ThrottleLimit {
+    fn default() -> Self {
+        ThrottleLimit {
+            avg: 0,
+            max: 0,
+            max_length: 1,
+        }
+    }
+}
+
+impl ThrottleLimit {
+    pub fn new(avg: u64) -> Self {
+        ThrottleLimit {
+            avg,
+            ..Default::default()
+        }
+    }
+
+    fn check(&self, name: &str, max_value: u64) -> Result<()> {
+        if self.avg > max_value || self.max > max_value {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                format!("{} of block device", name),
+                0,
+                true,
+                max_value,
+                true,
+            )));
+        }
+        if self.max != 0 && self.avg == 0 {
+            bail!("{}-max requires {} to be set", name, name);
+        }
+        if self.max != 0 && self.max < self.avg {
+            bail!("{}-max must be no less than {}", name, name);
+        }
+        if self.max_length < 1 || self.max_length > MAX_BURST_LENGTH {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                format!("{}-max-length of block device", name),
+                1,
+                true,
+                MAX_BURST_LENGTH,
+                true,
+            )));
+        }
+        if self.max_length > 1 && self.max == 0 {
+            bail!("{}-max-length requires {}-max to be set", name, name);
+        }
+        Ok(())
+    }
+}
+
+/// I/O throttling limits of a block device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThrottleConfig {
+    pub bps_total: ThrottleLimit,
+    pub bps_read: ThrottleLimit,
+    pub bps_write: ThrottleLimit,
+    pub iops_total: ThrottleLimit,
+    pub iops_read: ThrottleLimit,
+    pub iops_write: ThrottleLimit,
+}
+
+impl ThrottleConfig {
+    /// Get the limits in the order of `THROTTLE_LIMIT_NAMES`.
+    fn limits_mut(&mut self) -> [&mut ThrottleLimit; 6] {
+        [
+            &mut self.bps_total,
+            &mut self.bps_read,
+            &mut self.bps_write,
+            &mut self.iops_total,
+            &mut self.iops_read,
+            &mut self.iops_write,
+        ]
+    }
+
+    /// Return true if any limit is set.
+    pub fn is_enabled(&self) -> bool {
+        [
+            self.bps_total,
+            self.bps_read,
+            self.bps_write,
+            self.iops_total,
+            self.iops_read,
+            self.iops_write,
+        ]
+        .iter()
+        .any(|limit| limit.avg != 0)
+    }
+
+    /// Parse the limits from options named `<prefix><limit name>[-max[-length]]`.
+    pub fn parse(cmd_parser: &CmdParser, prefix: &str) -> Result<Self> {
+        let mut config = ThrottleConfig::default();
+        for (name, limit) in THROTTLE_LIMIT_NAMES.iter().zip(config.limits_mut()) {
+            let key = format!("{}{}", prefix, name);
+            if let Some(avg) = cmd_parser.get_value::<u64>(&key)? {
+                limit.avg = avg;
+            }
+            if let Some(max) = cmd_parser.get_value::<u64>(&format!("{}-max", key))? {
+                limit.max = max;
+            }
+            if let Some(max_length) = cmd_parser.get_value::<u64>(&format!("{}-max-length", key))? {
+                limit.max_length = max_length;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Build the limits from the arguments of qmp command `block_set_io_throttle`.
+    pub fn from_qmp_args(args: &qmp_schema::BlockSetIoThrottleArgument) -> Self {
+        let limit = |avg: u64, max: Option<u64>, max_length: Option<u64>| ThrottleLimit {
+            avg,
+            max: max.unwrap_or(0),
+            max_length: max_length.unwrap_or(1),
+        };
+        ThrottleConfig {
+            bps_total: limit(args.bps, args.bps_max, args.bps_max_length),
+            bps_read: limit(args.bps_rd, args.bps_rd_max, args.bps_rd_max_length),
+            bps_write: limit(args.bps_wr, args.bps_wr_max, args.bps_wr_max_length),
+            iops_total: limit(args.iops, args.iops_max, args.iops_max_length),
+            iops_read: limit(args.iops_rd, args.iops_rd_max, args.iops_rd_max_length),
+            iops_write: limit(args.iops_wr, args.iops_wr_max, args.iops_wr_max_length),
+        }
+    }
+
+    /// Push the options of the limits with `prefix` to the parser.
+    pub fn push_params(cmd_parser: &mut CmdParser, prefix: &str) {
+        for name in THROTTLE_LIMIT_NAMES.iter() {
+            let key = format!("{}{}", prefix, name);
+            cmd_parser
+                .push(&key)
+                .push(&format!("{}-max", key))
+                .push(&format!("{}-max-length", key));
+        }
+    }
+}
+
+impl ConfigCheck for ThrottleConfig {
+    fn check(&self) -> Result<()> {
+        self.bps_total.check("bps-total", MAX_BPS)?;
+        self.bps_read.check("bps-read", MAX_BPS)?;
+        self.bps_write.check("bps-write", MAX_BPS)?;
+        self.iops_total.check("iops-total", MAX_IOPS)?;
+        self.iops_read.check("iops-read", MAX_IOPS)?;
+        self.iops_write.check("iops-write", MAX_IOPS)?;
+
+        if self.bps_total.avg != 0 && (self.bps_read.avg != 0 || self.bps_write.avg != 0) {
+            bail!("bps-total and bps-read/bps-write cannot be used at the same time");
+        }
+        if self.iops_total.avg != 0 && (self.iops_read.avg != 0 || self.iops_write.avg != 0) {
+            bail!("iops-total and iops-read/iops-write cannot be used at the same time");
+        }
+        Ok(())
+    }
+}
+
 /// Represent a single drive backend file.
 pub struct DriveFile {
     /// The opened file.
@@ -61,7 +390,8 @@ pub struct BlkDevConfig {
     pub direct: bool,
     pub serial_num: Option<String>,
     pub iothread: Option<String>,
-    pub iops: Option<u64>,
+    pub throttle: ThrottleConfig,
+    pub throttle_group: Option<ThrottleGroupConfig>,
     pub queues: u16,
     pub boot_index: Option<u8>,
     pub chardev: Option<String>,
@@ -70,6 +400,10 @@ pub struct BlkDevConfig {
     pub queue_size: u16,
     pub discard: bool,
     pub write_zeroes: WriteZeroesState,
+    pub format: DiskFormat,
+    pub werror: BlockErrorPolicy,
+    pub rerror: BlockErrorPolicy,
+    pub uring: IoUringConfig,
 }
 
 #[derive(Debug, Clone)]
@@ -88,7 +422,8 @@ impl Default for BlkDevConfig {
             direct: true,
             serial_num: None,
             iothread: None,
-            iops: None,
+            throttle: ThrottleConfig::default(),
+            throttle_group: None,
             queues: 1,
             boot_index: None,
             chardev: None,
@@ -97,6 +432,10 @@ impl Default for BlkDevConfig {
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
             discard: false,
             write_zeroes: WriteZeroesState::Off,
+            format: DiskFormat::Raw,
+            werror: BlockErrorPolicy::Enospc,
+            rerror: BlockErrorPolicy::Report,
+            uring: IoUringConfig::default(),
         }
     }
 }
@@ -110,11 +449,19 @@ pub struct DriveConfig {
     pub path_on_host: String,
     pub read_only: bool,
     pub direct: bool,
-    pub iops: Option<u64>,
+    pub throttle: ThrottleConfig,
+    pub throttle_group: Option<String>,
     pub aio: AioEngine,
     pub media: String,
     pub discard: bool,
     pub write_zeroes: WriteZeroesState,
+    pub format: DiskFormat,
+    pub werror: BlockErrorPolicy,
+    pub rerror: BlockErrorPolicy,
+    /// Redirect the writes of the guest to a temporary overlay, the image is never modified.
+    pub snapshot: bool,
+    /// Tuning of the io_uring engine.
+    pub uring: IoUringConfig,
 }
 
 impl Default for DriveConfig {
@@ -124,11 +471,17 @@ impl Default for DriveConfig {
             path_on_host: "".to_string(),
             read_only: false,
             direct: true,
-            iops: None,
+            throttle: ThrottleConfig::default(),
+            throttle_group: None,
             aio: AioEngine::Native,
             media: "disk".to_string(),
             discard: false,
             write_zeroes: WriteZeroesState::Off,
+            format: DiskFormat::Raw,
+            werror: BlockErrorPolicy::Enospc,
+            rerror: BlockErrorPolicy::Report,
+            snapshot: false,
+            uring: IoUringConfig::default(),
         }
     }
 }
@@ -136,6 +489,11 @@ impl Default for DriveConfig {
 impl DriveConfig {
     /// Check whether the drive file path on the host is valid.
     pub fn check_path(&self) -> Result<()> {
+        // The export of the NBD server is checked when connecting to it, and the
+        // CD-ROM without medium has no file.
+        if is_nbd_path(&self.path_on_host) || self.path_on_host.is_empty() {
+            return Ok(());
+        }
         let blk = Path::new(&self.path_on_host);
         match metadata(blk) {
             Ok(meta) => {
@@ -183,13 +541,26 @@ impl ConfigCheck for DriveConfig {
                 MAX_PATH_LENGTH,
             )));
         }
-        if self.iops.is_some() && self.iops.unwrap() > MAX_IOPS {
-            return Err(anyhow!(ConfigError::IllegalValue(
-                "iops of block device".to_string(),
-                0,
-                true,
-                MAX_IOPS,
-                true,
+        self.throttle.check()?;
+        if is_nbd_path(&self.path_on_host) {
+            parse_nbd_path(&self.path_on_host)?;
+            if self.format != DiskFormat::Raw {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    "format".to_string(),
+                    "only raw format is supported for NBD drive".to_string(),
+                )));
+            }
+            if self.snapshot {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    "snapshot".to_string(),
+                    "snapshot is not supported for NBD drive".to_string(),
+                )));
+            }
+        }
+        if self.path_on_host.is_empty() && self.snapshot {
+            return Err(anyhow!(ConfigError::InvalidParam(
+                "snapshot".to_string(),
+                "snapshot is not supported for drive without medium".to_string(),
             )));
         }
         if self.aio != AioEngine::Off {
@@ -207,6 +578,8 @@ impl ConfigCheck for DriveConfig {
             )));
         }
 
+        check_uring_config(&self.uring, self.aio)?;
+
         if !["disk", "cdrom"].contains(&self.media.as_str()) {
             return Err(anyhow!(ConfigError::InvalidParam(
                 "media".to_string(),
@@ -262,8 +635,10 @@ impl ConfigCheck for BlkDevConfig {
         let fake_drive = DriveConfig {
             path_on_host: self.path_on_host.clone(),
             direct: self.direct,
-            iops: self.iops,
+            throttle: self.throttle,
             aio: self.aio,
+            format: self.format,
+            uring: self.uring,
             ..Default::default()
         };
         fake_drive.check()?;
@@ -276,21 +651,84 @@ impl ConfigCheck for BlkDevConfig {
     }
 }
 
+/// Parse the io_uring tuning of the drive, e.g. `io_uring.fixed-files=on`.
+fn parse_uring_config(cmd_parser: &CmdParser, prefix: &str) -> Result<IoUringConfig> {
+    let mut config = IoUringConfig::default();
+    let get_bool = |name: &str| -> Result<Option<bool>> {
+        Ok(cmd_parser
+            .get_value::<ExBool>(&format!("{}{}", prefix, name))?
+            .map(bool::from))
+    };
+    if let Some(fixed_files) = get_bool("fixed-files")? {
+        config.fixed_files = fixed_files;
+    }
+    if let Some(fixed_buffers) = get_bool("fixed-buffers")? {
+        config.fixed_buffers = fixed_buffers;
+    }
+    if let Some(sqpoll) = get_bool("sqpoll")? {
+        config.sqpoll = sqpoll;
+    }
+    if let Some(idle) = cmd_parser.get_value::<u32>(&format!("{}sqpoll-idle", prefix))? {
+        if !config.sqpoll {
+            bail!(
+                "{}sqpoll-idle is only valid with {}sqpoll on",
+                prefix,
+                prefix
+            );
+        }
+        config.sqpoll_idle = idle;
+    }
+    config.sqpoll_cpu = cmd_parser.get_value::<u32>(&format!("{}sqpoll-cpu", prefix))?;
+    if config.sqpoll_cpu.is_some() && !config.sqpoll {
+        bail!(
+            "{}sqpoll-cpu is only valid with {}sqpoll on",
+            prefix,
+            prefix
+        );
+    }
+    Ok(config)
+}
+
+fn push_uring_params(cmd_parser: &mut CmdParser, prefix: &str) {
+    for name in [
+        "fixed-files",
+        "fixed-buffers",
+        "sqpoll",
+        "sqpoll-idle",
+        "sqpoll-cpu",
+    ] {
+        cmd_parser.push(&format!("{}{}", prefix, name));
+    }
+}
+
+fn check_uring_config(config: &IoUringConfig, aio: AioEngine) -> Result<()> {
+    if config.is_enabled() && aio != AioEngine::IoUring {
+        return Err(anyhow!(ConfigError::InvalidParam(
+            "io_uring".to_string(),
+            "io_uring options should be used with \"aio=io_uring\"".to_string(),
+        )));
+    }
+    if config.sqpoll_idle == 0 {
+        return Err(anyhow!(ConfigError::InvalidParam(
+            "io_uring.sqpoll-idle".to_string(),
+            "idle time of the polling thread should be larger than 0".to_string(),
+        )));
+    }
+    Ok(())
+}
+
 fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
     let mut drive = DriveConfig::default();
 
-    if let Some(format) = cmd_parser.get_value::<String>("format")? {
-        if format.ne("raw") {
-            bail!("Only \'raw\' type of block is supported");
-        }
+    if let Some(format) = cmd_parser.get_value::<DiskFormat>("format")? {
+        drive.format = format;
     }
 
     drive.id = cmd_parser
         .get_value::<String>("id")?
         .with_context(|| ConfigError::FieldIsMissing("id".to_string(), "blk".to_string()))?;
-    drive.path_on_host = cmd_parser
-        .get_value::<String>("file")?
-        .with_context(|| ConfigError::FieldIsMissing("file".to_string(), "blk".to_string()))?;
+    // The CD-ROM can start without medium.
+    drive.path_on_host = cmd_parser.get_value::<String>("file")?.unwrap_or_default();
 
     if let Some(read_only) = cmd_parser.get_value::<ExBool>("readonly")? {
         drive.read_only = read_only.into();
@@ -298,7 +736,8 @@ fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
     if let Some(direct) = cmd_parser.get_value::<ExBool>("direct")? {
         drive.direct = direct.into();
     }
-    drive.iops = cmd_parser.get_value::<u64>("throttling.iops-total")?;
+    drive.throttle = ThrottleConfig::parse(&cmd_parser, "throttling.")?;
+    drive.throttle_group = cmd_parser.get_value::<String>("throttling.group")?;
     drive.aio = cmd_parser.get_value::<AioEngine>("aio")?.unwrap_or({
         if drive.direct {
             AioEngine::Native
@@ -309,12 +748,28 @@ fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
     drive.media = cmd_parser
         .get_value::<String>("media")?
         .unwrap_or_else(|| "disk".to_string());
+    if drive.path_on_host.is_empty() && drive.media != "cdrom" {
+        return Err(anyhow!(ConfigError::FieldIsMissing(
+            "file".to_string(),
+            "blk".to_string()
+        )));
+    }
     if let Some(discard) = cmd_parser.get_value::<ExBool>("discard")? {
         drive.discard = discard.into();
     }
     drive.write_zeroes = cmd_parser
         .get_value::<WriteZeroesState>("detect-zeroes")?
         .unwrap_or(WriteZeroesState::Off);
+    if let Some(werror) = cmd_parser.get_value::<BlockErrorPolicy>("werror")? {
+        drive.werror = werror;
+    }
+    if let Some(rerror) = cmd_parser.get_value::<BlockErrorPolicy>("rerror")? {
+        drive.rerror = rerror;
+    }
+    if let Some(snapshot) = cmd_parser.get_value::<ExBool>("snapshot")? {
+        drive.snapshot = snapshot.into();
+    }
+    drive.uring = parse_uring_config(&cmd_parser, "io_uring.")?;
 
     drive.check()?;
     #[cfg(not(test))]
@@ -380,10 +835,15 @@ pub fn parse_blk(
         blkdevcfg.path_on_host = drive_arg.path_on_host.clone();
         blkdevcfg.read_only = drive_arg.read_only;
         blkdevcfg.direct = drive_arg.direct;
-        blkdevcfg.iops = drive_arg.iops;
+        blkdevcfg.throttle = drive_arg.throttle;
+        blkdevcfg.throttle_group = vm_config.get_throttle_group(&drive_arg.throttle_group)?;
         blkdevcfg.aio = drive_arg.aio;
         blkdevcfg.discard = drive_arg.discard;
         blkdevcfg.write_zeroes = drive_arg.write_zeroes;
+        blkdevcfg.format = drive_arg.format;
+        blkdevcfg.werror = drive_arg.werror;
+        blkdevcfg.rerror = drive_arg.rerror;
+        blkdevcfg.uring = drive_arg.uring;
     } else {
         bail!("No drive configured matched for blk device");
     }
@@ -510,11 +970,16 @@ impl VmConfig {
             .push("direct")
             .push("format")
             .push("if")
-            .push("throttling.iops-total")
             .push("aio")
             .push("media")
             .push("discard")
-            .push("detect-zeroes");
+            .push("detect-zeroes")
+            .push("throttling.group")
+            .push("werror")
+            .push("rerror")
+            .push("snapshot");
+        ThrottleConfig::push_params(&mut cmd_parser, "throttling.");
+        push_uring_params(&mut cmd_parser, "io_uring.");
 
         cmd_parser.parse(block_config)?;
         let drive_cfg = parse_drive(cmd_parser)?;
@@ -522,6 +987,24 @@ impl VmConfig {
         Ok(drive_cfg)
     }
 
+    /// Get the config of the throttle group which the drive joins.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - The id of the throttle group.
+    pub fn get_throttle_group(
+        &self,
+        group: &Option<String>,
+    ) -> Result<Option<ThrottleGroupConfig>> {
+        match group {
+            Some(id) => match self.object.throttle_object.get(id) {
+                Some(config) => Ok(Some(config.clone())),
+                None => bail!("Throttle group {} not found", id),
+            },
+            None => Ok(None),
+        }
+    }
+
     /// Add drive config to vm config.
     ///
     /// # Arguments
@@ -678,6 +1161,7 @@ mod tests {
         assert_eq!(blk_device_config.read_only, false);
         assert_eq!(blk_device_config.serial_num, Some(String::from("111111")));
         assert_eq!(blk_device_config.queues, 4);
+        assert_eq!(blk_device_config.throttle.iops_total.avg, 200);
 
         let mut vm_config = VmConfig::default();
         assert!(vm_config
@@ -799,18 +1283,66 @@ mod tests {
         assert!(drive_conf.check().is_err());
 
         let mut drive_conf = DriveConfig::default();
-        drive_conf.iops = Some(MAX_IOPS);
+        drive_conf.throttle.iops_total.avg = MAX_IOPS;
         assert!(drive_conf.check().is_ok());
 
         let mut drive_conf = DriveConfig::default();
-        drive_conf.iops = None;
+        drive_conf.throttle.iops_total.avg = 0;
         assert!(drive_conf.check().is_ok());
 
         // Overflow
-        drive_conf.iops = Some(MAX_IOPS + 1);
+        drive_conf.throttle.iops_total.avg = MAX_IOPS + 1;
         assert!(drive_conf.check().is_err());
     }
 
+    #[test]
+    fn test_drive_throttle_config() {
+        let mut vm_config = VmConfig::default();
+        let drive_cfg = vm_config
+            .add_block_drive(
+                "id=drive0,file=/path/to/rootfs,throttling.bps-read=1048576,\
+                throttling.bps-write=2097152,throttling.bps-write-max=4194304,\
+                throttling.bps-write-max-length=10,throttling.iops-total=100",
+            )
+            .unwrap();
+        assert_eq!(drive_cfg.throttle.bps_read, ThrottleLimit::new(1048576));
+        assert_eq!(drive_cfg.throttle.bps_write.avg, 2097152);
+        assert_eq!(drive_cfg.throttle.bps_write.max, 4194304);
+        assert_eq!(drive_cfg.throttle.bps_write.max_length, 10);
+        assert_eq!(drive_cfg.throttle.iops_total, ThrottleLimit::new(100));
+        assert!(drive_cfg.throttle.is_enabled());
+
+        // Total limit conflicts with read/write limit.
+        assert!(vm_config
+            .add_block_drive(
+                "id=drive1,file=/path/to/rootfs,throttling.iops-total=100,throttling.iops-read=10"
+            )
+            .is_err());
+        // Burst limit without average limit.
+        assert!(vm_config
+            .add_block_drive("id=drive1,file=/path/to/rootfs,throttling.bps-total-max=100")
+            .is_err());
+        // Burst limit less than average limit.
+        assert!(vm_config
+            .add_block_drive(
+                "id=drive1,file=/path/to/rootfs,throttling.bps-total=100,throttling.bps-total-max=10"
+            )
+            .is_err());
+        // Burst length without burst limit.
+        assert!(vm_config
+            .add_block_drive(
+                "id=drive1,file=/path/to/rootfs,throttling.bps-total=100,throttling.bps-total-max-length=10"
+            )
+            .is_err());
+        // Zero burst length.
+        assert!(vm_config
+            .add_block_drive(
+                "id=drive1,file=/path/to/rootfs,throttling.bps-total=100,\
+                throttling.bps-total-max=200,throttling.bps-total-max-length=0"
+            )
+            .is_err());
+    }
+
     #[test]
     fn test_add_drive_with_config() {
         let mut vm_config = VmConfig::default();
@@ -899,4 +1431,130 @@ mod tests {
             .is_err();
         assert_eq!(ret, true);
     }
+
+    #[test]
+    fn test_drive_config_error_policy() {
+        let mut vm_config = VmConfig::default();
+        let drive_conf = vm_config
+            .add_block_drive("id=rootfs,file=/path/to/rootfs")
+            .unwrap();
+        assert_eq!(drive_conf.werror, BlockErrorPolicy::Enospc);
+        assert_eq!(drive_conf.rerror, BlockErrorPolicy::Report);
+
+        let mut vm_config = VmConfig::default();
+        let drive_conf = vm_config
+            .add_block_drive("id=rootfs,file=/path/to/rootfs,werror=stop,rerror=ignore")
+            .unwrap();
+        assert_eq!(drive_conf.werror, BlockErrorPolicy::Stop);
+        assert_eq!(drive_conf.rerror, BlockErrorPolicy::Ignore);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_block_drive("id=rootfs,file=/path/to/rootfs,werror=invalid")
+            .is_err());
+    }
+
+    #[test]
+    fn test_drive_config_snapshot() {
+        let mut vm_config = VmConfig::default();
+        let drive_conf = vm_config
+            .add_block_drive("id=rootfs,file=/path/to/rootfs")
+            .unwrap();
+        assert!(!drive_conf.snapshot);
+
+        let mut vm_config = VmConfig::default();
+        let drive_conf = vm_config
+            .add_block_drive("id=rootfs,file=/path/to/rootfs,snapshot=on")
+            .unwrap();
+        assert!(drive_conf.snapshot);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_block_drive("id=rootfs,file=nbd:unix:/tmp/nbd.sock,snapshot=on")
+            .is_err());
+    }
+
+    #[test]
+    fn test_drive_config_io_uring() {
+        let mut vm_config = VmConfig::default();
+        let drive_conf = vm_config
+            .add_block_drive("id=rootfs,file=/path/to/rootfs,aio=io_uring,io_uring.fixed-files=on,io_uring.fixed-buffers=on,io_uring.sqpoll=on,io_uring.sqpoll-idle=200,io_uring.sqpoll-cpu=3")
+            .unwrap();
+        assert!(drive_conf.uring.fixed_files);
+        assert!(drive_conf.uring.fixed_buffers);
+        assert!(drive_conf.uring.sqpoll);
+        assert_eq!(drive_conf.uring.sqpoll_idle, 200);
+        assert_eq!(drive_conf.uring.sqpoll_cpu, Some(3));
+
+        let mut vm_config = VmConfig::default();
+        let drive_conf = vm_config
+            .add_block_drive("id=rootfs,file=/path/to/rootfs,aio=io_uring")
+            .unwrap();
+        assert_eq!(drive_conf.uring, IoUringConfig::default());
+
+        // The options are only valid for io_uring, and sqpoll-idle/sqpoll-cpu need sqpoll.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_block_drive("id=rootfs,file=/path/to/rootfs,io_uring.fixed-files=on")
+            .is_err());
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_block_drive("id=rootfs,file=/path/to/rootfs,aio=io_uring,io_uring.sqpoll-cpu=1")
+            .is_err());
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_block_drive(
+                "id=rootfs,file=/path/to/rootfs,aio=io_uring,io_uring.sqpoll=on,io_uring.sqpoll-idle=0"
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_drive_config_without_medium() {
+        let mut vm_config = VmConfig::default();
+        let drive_conf = vm_config
+            .add_block_drive("id=cd0,media=cdrom,readonly=on,aio=off,direct=false")
+            .unwrap();
+        assert!(drive_conf.path_on_host.is_empty());
+        let drive_files = vm_config.init_drive_files().unwrap();
+        assert!(drive_files.is_empty());
+
+        // Only the CD-ROM can be empty.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_block_drive("id=rootfs").is_err());
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_block_drive("id=cd0,media=cdrom,aio=off,direct=false,snapshot=on")
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_nbd_path() {
+        let config = parse_nbd_path("nbd:unix:/tmp/nbd.sock:exportname=foo").unwrap();
+        assert_eq!(
+            config.addr,
+            NbdServerAddr::Unix("/tmp/nbd.sock".to_string())
+        );
+        assert_eq!(config.export, "foo");
+
+        let config = parse_nbd_path("nbd:127.0.0.1:10809").unwrap();
+        assert_eq!(
+            config.addr,
+            NbdServerAddr::Tcp("127.0.0.1".to_string(), 10809)
+        );
+        assert_eq!(config.export, "");
+
+        assert!(parse_nbd_path("nbd:unix:").is_err());
+        assert!(parse_nbd_path("nbd:127.0.0.1").is_err());
+        assert!(parse_nbd_path("nbd:127.0.0.1:port").is_err());
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_block_drive("id=nbd0,file=nbd:unix:/tmp/nbd.sock:exportname=foo")
+            .is_ok());
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_block_drive("id=nbd0,file=nbd:unix:/tmp/nbd.sock,format=qcow2")
+            .is_err());
+    }
 }
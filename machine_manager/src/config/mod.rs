@@ -10,6 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+pub use ahci::*;
 pub use balloon::*;
 pub use boot_source::*;
 pub use chardev::*;
@@ -25,16 +26,19 @@ pub use iothread::*;
 pub use machine_config::*;
 pub use network::*;
 pub use numa::*;
+pub use nvme::*;
 pub use pci::*;
 pub use ramfb::*;
 pub use rng::*;
 pub use sasl_auth::*;
 pub use scsi::*;
+pub use throttle_group::*;
 pub use tls_creds::*;
 pub use usb::*;
 pub use vfio::*;
 pub use vnc::*;
 
+mod ahci;
 mod balloon;
 mod boot_source;
 mod chardev;
@@ -50,12 +54,14 @@ mod iothread;
 mod machine_config;
 mod network;
 mod numa;
+mod nvme;
 mod pci;
 mod ramfb;
 mod rng;
 mod sasl_auth;
 pub mod scream;
 mod scsi;
+mod throttle_group;
 mod tls_creds;
 mod usb;
 mod vfio;
@@ -64,6 +70,7 @@ pub mod vnc;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileTypeExt;
 use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
@@ -98,6 +105,7 @@ pub struct ObjectConfig {
     pub mem_object: HashMap<String, MemZoneConfig>,
     pub tls_object: HashMap<String, TlsCredObjConfig>,
     pub sasl_object: HashMap<String, SaslAuthObjConfig>,
+    pub throttle_object: HashMap<String, ThrottleGroupConfig>,
 }
 
 /// This main config structure for Vm, contains Vm's basic configuration and devices.
@@ -218,6 +226,9 @@ impl VmConfig {
             "authz-simple" => {
                 self.add_saslauth(object_args)?;
             }
+            "throttle-group" => {
+                self.add_throttle_group(object_args)?;
+            }
             _ => {
                 bail!("Unknow object type: {:?}", &device_type);
             }
@@ -260,6 +271,10 @@ impl VmConfig {
         read_only: bool,
         direct: bool,
     ) -> Result<()> {
+        // The drive exported by an NBD server and the CD-ROM without medium have no host file.
+        if is_nbd_path(path) || path.is_empty() {
+            return Ok(());
+        }
         if let Some(drive_file) = drive_files.get_mut(path) {
             if drive_file.read_only && read_only {
                 // File can be shared with read_only.
@@ -281,9 +296,12 @@ impl VmConfig {
                 path
             );
         }
-        let file_size = file.seek(SeekFrom::End(0))?;
-        if file_size & (req_align as u64 - 1) != 0 {
-            bail!("The size of file {} is not aligned to {}.", path, req_align);
+        // The scsi generic character device used by scsi-generic can't be seeked.
+        if !file.metadata()?.file_type().is_char_device() {
+            let file_size = file.seek(SeekFrom::End(0))?;
+            if file_size & (req_align as u64 - 1) != 0 {
+                bail!("The size of file {} is not aligned to {}.", path, req_align);
+            }
         }
         let drive_file = DriveFile {
             file,
@@ -303,6 +321,9 @@ impl VmConfig {
         drive_files: &mut HashMap<String, DriveFile>,
         path: &str,
     ) -> Result<()> {
+        if is_nbd_path(path) || path.is_empty() {
+            return Ok(());
+        }
         if let Some(drive_file) = drive_files.get_mut(path) {
             drive_file.count -= 1;
             if drive_file.count == 0 {
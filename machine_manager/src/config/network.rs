@@ -10,6 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::net::Ipv4Addr;
 use std::os::unix::io::RawFd;
 
 use anyhow::{anyhow, bail, Context, Result};
@@ -22,6 +23,9 @@ use crate::config::{
     MAX_PATH_LENGTH, MAX_VIRTIO_QUEUE,
 };
 use crate::qmp::{qmp_schema, QmpChannel};
+use util::af_xdp::{AfXdpConfig, XdpMode};
+use util::net_backend::{DgramAddr, NetSocketConfig};
+use util::slirp::{parse_hostfwd, SlirpConfig};
 
 const MAC_ADDRESS_LENGTH: usize = 17;
 
@@ -29,6 +33,16 @@ const MAC_ADDRESS_LENGTH: usize = 17;
 pub const MAX_QUEUE_SIZE_NET: u16 = 4096;
 /// Max num of virtqueues.
 const MAX_QUEUE_PAIRS: usize = MAX_VIRTIO_QUEUE / 2;
+/// Arguments only supported by tap or vhost-user netdev.
+const TAP_NETDEV_ARGS: [&str; 7] = [
+    "fd", "fds", "vhost", "ifname", "vhostfd", "vhostfds", "chardev",
+];
+/// Arguments only supported by user netdev.
+const USER_NETDEV_ARGS: [&str; 5] = ["net", "host", "dhcpstart", "dns", "hostfwd"];
+/// Arguments only supported by stream or dgram netdev.
+const SOCKET_NETDEV_ARGS: [&str; 4] = ["path", "server", "local", "remote"];
+/// Arguments only supported by af-xdp netdev.
+const AF_XDP_NETDEV_ARGS: [&str; 3] = ["start-queue", "mode", "force-copy"];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetDevcfg {
@@ -39,6 +53,13 @@ pub struct NetDevcfg {
     pub ifname: String,
     pub queues: u16,
     pub chardev: Option<String>,
+    /// Config of the user mode network stack, which is used instead of tap.
+    pub user: Option<SlirpConfig>,
+    /// Config of the socket connected to other VMs, which is used instead of tap.
+    pub socket: Option<NetSocketConfig>,
+    /// Config of the AF_XDP sockets bound to the queues of a host interface, which
+    /// is used instead of tap.
+    pub af_xdp: Option<AfXdpConfig>,
 }
 
 impl Default for NetDevcfg {
@@ -51,6 +72,9 @@ impl Default for NetDevcfg {
             ifname: "".to_string(),
             queues: 2,
             chardev: None,
+            user: None,
+            socket: None,
+            af_xdp: None,
         }
     }
 }
@@ -86,6 +110,8 @@ impl ConfigCheck for NetDevcfg {
 #[serde(deny_unknown_fields)]
 pub struct NetworkInterfaceConfig {
     pub id: String,
+    /// The id of the netdev used by this device.
+    pub netdev_id: String,
     pub host_dev_name: String,
     pub mac: Option<String>,
     pub tap_fds: Option<Vec<i32>>,
@@ -97,12 +123,16 @@ pub struct NetworkInterfaceConfig {
     pub socket_path: Option<String>,
     /// All queues of a net device have the same queue size now.
     pub queue_size: u16,
+    pub user: Option<SlirpConfig>,
+    pub socket: Option<NetSocketConfig>,
+    pub af_xdp: Option<AfXdpConfig>,
 }
 
 impl Default for NetworkInterfaceConfig {
     fn default() -> Self {
         NetworkInterfaceConfig {
             id: "".to_string(),
+            netdev_id: "".to_string(),
             host_dev_name: "".to_string(),
             mac: None,
             tap_fds: None,
@@ -113,6 +143,9 @@ impl Default for NetworkInterfaceConfig {
             mq: false,
             socket_path: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            user: None,
+            socket: None,
+            af_xdp: None,
         }
     }
 }
@@ -172,18 +205,128 @@ fn parse_fds(cmd_parser: &CmdParser, name: &str) -> Result<Option<Vec<i32>>> {
     }
 }
 
-fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
-    let mut net = NetDevcfg::default();
-    let netdev_type = cmd_parser.get_value::<String>("")?.unwrap_or_default();
-    if netdev_type.ne("tap") && netdev_type.ne("vhost-user") {
-        bail!("Unsupported netdev type: {:?}", &netdev_type);
+fn check_netdev_args(cmd_parser: &CmdParser, netdev_type: &str, args: &[&str]) -> Result<()> {
+    for arg in args {
+        if cmd_parser.get_value::<String>(arg)?.is_some() {
+            bail!(
+                "Argument \'{}\' is not supported by {} netdev",
+                arg,
+                netdev_type
+            );
+        }
     }
-    net.id = cmd_parser
-        .get_value::<String>("id")?
-        .with_context(|| ConfigError::FieldIsMissing("id".to_string(), "netdev".to_string()))?;
-    if let Some(ifname) = cmd_parser.get_value::<String>("ifname")? {
-        net.ifname = ifname;
+    Ok(())
+}
+
+/// The netdevs implemented in StratoVirt itself have only one queue pair.
+fn check_single_queue_pair(cmd_parser: &CmdParser, netdev_type: &str) -> Result<()> {
+    if let Some(queue_pairs) = cmd_parser.get_value::<u16>("queues")? {
+        if queue_pairs != 1 {
+            bail!("{} netdev only supports one queue pair", netdev_type);
+        }
+    }
+    Ok(())
+}
+
+fn parse_user_netdev(cmd_parser: &CmdParser) -> Result<SlirpConfig> {
+    check_netdev_args(cmd_parser, "user", &TAP_NETDEV_ARGS)?;
+    check_netdev_args(cmd_parser, "user", &SOCKET_NETDEV_ARGS)?;
+    check_netdev_args(cmd_parser, "user", &AF_XDP_NETDEV_ARGS)?;
+    check_single_queue_pair(cmd_parser, "user")?;
+
+    let mut config = SlirpConfig::default();
+    if let Some(net) = cmd_parser.get_value::<String>("net")? {
+        config.set_net(&net)?;
+    }
+    if let Some(host) = cmd_parser.get_value::<Ipv4Addr>("host")? {
+        config.host = host;
+    }
+    if let Some(dhcp_start) = cmd_parser.get_value::<Ipv4Addr>("dhcpstart")? {
+        config.dhcp_start = dhcp_start;
     }
+    if let Some(dns) = cmd_parser.get_value::<Ipv4Addr>("dns")? {
+        config.dns = dns;
+    }
+    if let Some(hostfwd) = cmd_parser.get_value::<String>("hostfwd")? {
+        config.hostfwd = parse_hostfwd(&hostfwd)?;
+    }
+    config.check()?;
+
+    Ok(config)
+}
+
+fn parse_socket_netdev(cmd_parser: &CmdParser, netdev_type: &str) -> Result<NetSocketConfig> {
+    check_netdev_args(cmd_parser, netdev_type, &TAP_NETDEV_ARGS)?;
+    check_netdev_args(cmd_parser, netdev_type, &USER_NETDEV_ARGS)?;
+    check_netdev_args(cmd_parser, netdev_type, &AF_XDP_NETDEV_ARGS)?;
+    check_single_queue_pair(cmd_parser, netdev_type)?;
+
+    let check_path = |path: &str| {
+        if path.len() > MAX_PATH_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "socket path".to_string(),
+                MAX_PATH_LENGTH
+            )));
+        }
+        Ok(())
+    };
+    let config = if netdev_type.eq("stream") {
+        check_netdev_args(cmd_parser, netdev_type, &["local", "remote"])?;
+        let path = cmd_parser.get_value::<String>("path")?.with_context(|| {
+            ConfigError::FieldIsMissing("path".to_string(), "stream netdev".to_string())
+        })?;
+        check_path(&path)?;
+        let server = cmd_parser
+            .get_value::<ExBool>("server")?
+            .is_some_and(|server| server.inner);
+        NetSocketConfig::Stream { path, server }
+    } else {
+        check_netdev_args(cmd_parser, netdev_type, &["path", "server"])?;
+        let mut addrs = Vec::new();
+        for name in ["local", "remote"] {
+            let addr = cmd_parser.get_value::<DgramAddr>(name)?.with_context(|| {
+                ConfigError::FieldIsMissing(name.to_string(), "dgram netdev".to_string())
+            })?;
+            if let DgramAddr::Unix(path) = &addr {
+                check_path(path)?;
+            }
+            addrs.push(addr);
+        }
+        let remote = addrs.pop().unwrap();
+        let local = addrs.pop().unwrap();
+        NetSocketConfig::Dgram { local, remote }
+    };
+    config.check()?;
+
+    Ok(config)
+}
+
+fn parse_af_xdp_netdev(cmd_parser: &CmdParser) -> Result<AfXdpConfig> {
+    check_netdev_args(
+        cmd_parser,
+        "af-xdp",
+        &["fd", "fds", "vhost", "vhostfd", "vhostfds", "chardev"],
+    )?;
+    check_netdev_args(cmd_parser, "af-xdp", &USER_NETDEV_ARGS)?;
+    check_netdev_args(cmd_parser, "af-xdp", &SOCKET_NETDEV_ARGS)?;
+
+    let config = AfXdpConfig {
+        ifname: cmd_parser.get_value::<String>("ifname")?.with_context(|| {
+            ConfigError::FieldIsMissing("ifname".to_string(), "af-xdp netdev".to_string())
+        })?,
+        start_queue: cmd_parser.get_value::<u32>("start-queue")?.unwrap_or(0),
+        mode: cmd_parser.get_value::<XdpMode>("mode")?.unwrap_or_default(),
+        force_copy: cmd_parser
+            .get_value::<ExBool>("force-copy")?
+            .is_some_and(|force_copy| force_copy.inner),
+    };
+    config.check()?;
+
+    Ok(config)
+}
+
+/// Get the number of queues from the number of queue pairs.
+fn parse_netdev_queues(cmd_parser: &CmdParser) -> Result<Option<u16>> {
     if let Some(queue_pairs) = cmd_parser.get_value::<u16>("queues")? {
         let queues = queue_pairs.checked_mul(2);
         if queues.is_none() || !is_netdev_queues_valid(queues.unwrap()) {
@@ -195,8 +338,48 @@ fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
                 true,
             )));
         }
+        return Ok(queues);
+    }
+    Ok(None)
+}
 
-        net.queues = queues.unwrap();
+fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
+    let mut net = NetDevcfg::default();
+    let netdev_type = cmd_parser.get_value::<String>("")?.unwrap_or_default();
+    net.id = cmd_parser
+        .get_value::<String>("id")?
+        .with_context(|| ConfigError::FieldIsMissing("id".to_string(), "netdev".to_string()))?;
+    match netdev_type.as_str() {
+        "tap" | "vhost-user" => {
+            check_netdev_args(&cmd_parser, &netdev_type, &USER_NETDEV_ARGS)?;
+            check_netdev_args(&cmd_parser, &netdev_type, &SOCKET_NETDEV_ARGS)?;
+            check_netdev_args(&cmd_parser, &netdev_type, &AF_XDP_NETDEV_ARGS)?;
+        }
+        "user" => {
+            net.user = Some(parse_user_netdev(&cmd_parser)?);
+            net.check()?;
+            return Ok(net);
+        }
+        "stream" | "dgram" => {
+            net.socket = Some(parse_socket_netdev(&cmd_parser, &netdev_type)?);
+            net.check()?;
+            return Ok(net);
+        }
+        "af-xdp" => {
+            net.af_xdp = Some(parse_af_xdp_netdev(&cmd_parser)?);
+            if let Some(queues) = parse_netdev_queues(&cmd_parser)? {
+                net.queues = queues;
+            }
+            net.check()?;
+            return Ok(net);
+        }
+        _ => bail!("Unsupported netdev type: {:?}", &netdev_type),
+    }
+    if let Some(ifname) = cmd_parser.get_value::<String>("ifname")? {
+        net.ifname = ifname;
+    }
+    if let Some(queues) = parse_netdev_queues(&cmd_parser)? {
+        net.queues = queues;
     }
 
     if let Some(tap_fd) = parse_fds(&cmd_parser, "fd")? {
@@ -287,11 +470,15 @@ pub fn parse_net(vm_config: &mut VmConfig, net_config: &str) -> Result<NetworkIn
 
     if let Some(netcfg) = &vm_config.netdevs.remove(&netdev) {
         netdevinterfacecfg.id = netid;
+        netdevinterfacecfg.netdev_id = netdev;
         netdevinterfacecfg.host_dev_name = netcfg.ifname.clone();
         netdevinterfacecfg.tap_fds = netcfg.tap_fds.clone();
         netdevinterfacecfg.vhost_fds = netcfg.vhost_fds.clone();
         netdevinterfacecfg.vhost_type = netcfg.vhost_type.clone();
         netdevinterfacecfg.queues = netcfg.queues;
+        netdevinterfacecfg.user = netcfg.user.clone();
+        netdevinterfacecfg.socket = netcfg.socket.clone();
+        netdevinterfacecfg.af_xdp = netcfg.af_xdp.clone();
         if let Some(chardev) = &netcfg.chardev {
             netdevinterfacecfg.socket_path = Some(get_chardev_socket_path(chardev, vm_config)?);
         }
@@ -352,8 +539,29 @@ pub fn get_netdev_config(args: Box<qmp_schema::NetDevAddArgument>) -> Result<Net
         ifname: String::new(),
         queues,
         chardev: args.chardev,
+        user: None,
+        socket: None,
+        af_xdp: None,
     };
 
+    let netdev_type = args.net_type.unwrap_or_default();
+    if netdev_type.eq("user") {
+        if args.fd.is_some()
+            || args.fds.is_some()
+            || args.if_name.is_some()
+            || args.vhost.is_some()
+            || args.vhostfd.is_some()
+            || args.vhostfds.is_some()
+            || queues != 2
+        {
+            bail!("User netdev is conflict with fd/fds/ifname/vhost/vhostfd/vhostfds/queues");
+        }
+        config.user = Some(SlirpConfig::default());
+        return Ok(config);
+    } else if netdev_type.eq("stream") || netdev_type.eq("dgram") || netdev_type.eq("af-xdp") {
+        bail!("{} netdev is not supported by netdev_add", netdev_type);
+    }
+
     if let Some(tap_fd) = args.fd {
         if args.if_name.is_some()
             || args.script.is_some()
@@ -394,7 +602,6 @@ pub fn get_netdev_config(args: Box<qmp_schema::NetDevAddArgument>) -> Result<Net
     }
 
     // Get net device type.
-    let netdev_type = args.net_type.unwrap_or_default();
     let vhost = args.vhost.unwrap_or_default();
     if vhost {
         if netdev_type.ne("vhost-user") {
@@ -429,7 +636,19 @@ impl VmConfig {
             .push("vhostfd")
             .push("vhostfds")
             .push("queues")
-            .push("chardev");
+            .push("chardev")
+            .push("net")
+            .push("host")
+            .push("dhcpstart")
+            .push("dns")
+            .push("hostfwd")
+            .push("path")
+            .push("server")
+            .push("local")
+            .push("remote")
+            .push("start-queue")
+            .push("mode")
+            .push("force-copy");
 
         cmd_parser.parse(netdev_config)?;
         let drive_cfg = parse_netdev(cmd_parser)?;
@@ -750,6 +969,183 @@ mod tests {
             .is_err());
     }
 
+    #[test]
+    fn test_user_netdev_config() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_netdev("user,id=netdev0").is_ok());
+        let netdev = vm_config.netdevs.get("netdev0").unwrap();
+        assert_eq!(netdev.user, Some(SlirpConfig::default()));
+        assert!(netdev.tap_fds.is_none());
+        assert_eq!(netdev.queues, 2);
+
+        assert!(vm_config
+            .add_netdev(
+                "user,id=netdev1,net=192.168.100.0/24,dns=192.168.100.53,\
+                 hostfwd=tcp::2222-:22;udp:127.0.0.1:5353-192.168.100.20:53"
+            )
+            .is_ok());
+        let user = vm_config
+            .netdevs
+            .get("netdev1")
+            .unwrap()
+            .user
+            .clone()
+            .unwrap();
+        assert_eq!(user.net, Ipv4Addr::new(192, 168, 100, 0));
+        assert_eq!(user.host, Ipv4Addr::new(192, 168, 100, 2));
+        assert_eq!(user.dhcp_start, Ipv4Addr::new(192, 168, 100, 15));
+        assert_eq!(user.dns, Ipv4Addr::new(192, 168, 100, 53));
+        assert_eq!(user.hostfwd.len(), 2);
+        assert_eq!(user.hostfwd[0].host_port, 2222);
+        assert_eq!(user.hostfwd[1].guest_port, 53);
+
+        let net_cfg = "virtio-net-device,id=net1,netdev=netdev1";
+        let net_cfg = parse_net(&mut vm_config, net_cfg).unwrap();
+        assert_eq!(net_cfg.user, Some(user));
+
+        // Options of tap and user netdev can't be mixed.
+        assert!(vm_config.add_netdev("user,id=netdev2,ifname=tap0").is_err());
+        assert!(vm_config.add_netdev("user,id=netdev2,queues=2").is_err());
+        assert!(vm_config
+            .add_netdev("tap,id=netdev2,ifname=tap0,net=10.0.3.0/24")
+            .is_err());
+        // Addresses out of the network.
+        assert!(vm_config
+            .add_netdev("user,id=netdev2,net=10.0.3.0/24,host=10.0.2.2")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("user,id=netdev2,hostfwd=tcp::22")
+            .is_err());
+    }
+
+    #[test]
+    fn test_socket_netdev_config() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_netdev("stream,id=netdev0,path=/tmp/net0.sock,server=on")
+            .is_ok());
+        assert!(vm_config
+            .add_netdev("stream,id=netdev1,path=/tmp/net0.sock")
+            .is_ok());
+        assert!(vm_config
+            .add_netdev("dgram,id=netdev2,local=127.0.0.1:5000,remote=127.0.0.1:5001")
+            .is_ok());
+        assert!(vm_config
+            .add_netdev("dgram,id=netdev3,local=/tmp/net3.sock,remote=/tmp/net4.sock")
+            .is_ok());
+        let socket = |id: &str| vm_config.netdevs.get(id).unwrap().socket.clone().unwrap();
+        assert_eq!(
+            socket("netdev0"),
+            NetSocketConfig::Stream {
+                path: "/tmp/net0.sock".to_string(),
+                server: true
+            }
+        );
+        assert_eq!(
+            socket("netdev1"),
+            NetSocketConfig::Stream {
+                path: "/tmp/net0.sock".to_string(),
+                server: false
+            }
+        );
+        assert_eq!(
+            socket("netdev2"),
+            NetSocketConfig::Dgram {
+                local: DgramAddr::Udp("127.0.0.1:5000".parse().unwrap()),
+                remote: DgramAddr::Udp("127.0.0.1:5001".parse().unwrap()),
+            }
+        );
+        assert_eq!(
+            socket("netdev3"),
+            NetSocketConfig::Dgram {
+                local: DgramAddr::Unix("/tmp/net3.sock".to_string()),
+                remote: DgramAddr::Unix("/tmp/net4.sock".to_string()),
+            }
+        );
+
+        let net_cfg = "virtio-net-device,id=net0,netdev=netdev0";
+        let net_cfg = parse_net(&mut vm_config, net_cfg).unwrap();
+        assert!(net_cfg.socket.is_some());
+        assert!(net_cfg.tap_fds.is_none());
+
+        // Missing address.
+        assert!(vm_config.add_netdev("stream,id=netdev4").is_err());
+        assert!(vm_config
+            .add_netdev("dgram,id=netdev4,local=127.0.0.1:5000")
+            .is_err());
+        // Different type of local and remote address.
+        assert!(vm_config
+            .add_netdev("dgram,id=netdev4,local=127.0.0.1:5000,remote=/tmp/net4.sock")
+            .is_err());
+        // Mixed options.
+        assert!(vm_config
+            .add_netdev("stream,id=netdev4,path=/tmp/net4.sock,local=/tmp/net5.sock")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("stream,id=netdev4,path=/tmp/net4.sock,ifname=tap0")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("stream,id=netdev4,path=/tmp/net4.sock,queues=2")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("tap,id=netdev4,ifname=tap0,path=/tmp/net4.sock")
+            .is_err());
+    }
+
+    #[test]
+    fn test_af_xdp_netdev_config() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_netdev("af-xdp,id=netdev0,ifname=eth0,queues=2")
+            .is_ok());
+        assert!(vm_config
+            .add_netdev("af-xdp,id=netdev1,ifname=eth1,start-queue=4,mode=skb,force-copy=on")
+            .is_ok());
+        let netdev = vm_config.netdevs.get("netdev0").unwrap();
+        assert_eq!(netdev.queues, 4);
+        assert_eq!(
+            netdev.af_xdp,
+            Some(AfXdpConfig {
+                ifname: "eth0".to_string(),
+                start_queue: 0,
+                mode: XdpMode::Native,
+                force_copy: false,
+            })
+        );
+        let netdev = vm_config.netdevs.get("netdev1").unwrap();
+        assert_eq!(netdev.queues, 2);
+        assert_eq!(
+            netdev.af_xdp,
+            Some(AfXdpConfig {
+                ifname: "eth1".to_string(),
+                start_queue: 4,
+                mode: XdpMode::Skb,
+                force_copy: true,
+            })
+        );
+
+        let net_cfg = "virtio-net-pci,id=net0,netdev=netdev0,mq=on,bus=pcie.0,addr=0x2";
+        let net_cfg = parse_net(&mut vm_config, net_cfg).unwrap();
+        assert!(net_cfg.af_xdp.is_some());
+        assert!(net_cfg.host_dev_name.is_empty());
+        assert_eq!(net_cfg.queues, 4);
+
+        // Missing interface.
+        assert!(vm_config.add_netdev("af-xdp,id=netdev2").is_err());
+        // Invalid mode.
+        assert!(vm_config
+            .add_netdev("af-xdp,id=netdev2,ifname=eth0,mode=generic")
+            .is_err());
+        // Mixed options.
+        assert!(vm_config
+            .add_netdev("af-xdp,id=netdev2,ifname=eth0,vhost=on")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("tap,id=netdev2,ifname=tap0,start-queue=1")
+            .is_err());
+        assert!(vm_config.add_netdev("user,id=netdev2,mode=skb").is_err());
+    }
+
     #[test]
     fn test_add_netdev_with_config() {
         let mut vm_config = VmConfig::default();
@@ -976,5 +1372,24 @@ mod tests {
             MAX_QUEUE_PAIRS
         );
         check_err_msg(netdev, &err_msg);
+
+        // User netdev.
+        let netdev = Box::new(qmp_schema::NetDevAddArgument {
+            id: "netdev".to_string(),
+            net_type: Some("user".to_string()),
+            ..qmp_schema::NetDevAddArgument::default()
+        });
+        let net_cfg = get_netdev_config(netdev).unwrap();
+        assert_eq!(net_cfg.user, Some(SlirpConfig::default()));
+        let netdev = Box::new(qmp_schema::NetDevAddArgument {
+            id: "netdev".to_string(),
+            net_type: Some("user".to_string()),
+            if_name: Some("tap0".to_string()),
+            ..qmp_schema::NetDevAddArgument::default()
+        });
+        check_err_msg(
+            netdev,
+            "User netdev is conflict with fd/fds/ifname/vhost/vhostfd/vhostfds/queues",
+        );
     }
 }
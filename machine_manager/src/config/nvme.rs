@@ -0,0 +1,286 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::{error::ConfigError, pci_args_check};
+use crate::config::{check_arg_too_long, CmdParser, ConfigCheck, DriveConfig, VmConfig};
+
+/// Max number of I/O submission and completion queue pairs of the NVMe controller.
+pub const NVME_MAX_IOQPAIRS: u16 = 64;
+/// Max number of namespaces of the NVMe controller.
+pub const NVME_MAX_NAMESPACES: u32 = 256;
+/// The serial number is a 20 bytes ASCII string in Identify Controller data.
+const NVME_MAX_SERIAL_LEN: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct NvmeConfig {
+    /// NVMe controller device id.
+    pub id: String,
+    /// Serial number reported by Identify Controller.
+    pub serial: String,
+    /// Max number of I/O queue pairs which can be created by the guest.
+    pub queues: u16,
+    /// Thread name of io handler.
+    pub iothread: Option<String>,
+    /// Namespace 1 of the controller, None if the controller is created without drive.
+    pub namespace: Option<NvmeNsConfig>,
+}
+
+impl Default for NvmeConfig {
+    fn default() -> Self {
+        NvmeConfig {
+            id: "".to_string(),
+            serial: "".to_string(),
+            queues: NVME_MAX_IOQPAIRS,
+            iothread: None,
+            namespace: None,
+        }
+    }
+}
+
+impl ConfigCheck for NvmeConfig {
+    fn check(&self) -> Result<()> {
+        check_arg_too_long(&self.id, "nvme device id")?;
+
+        if self.serial.len() > NVME_MAX_SERIAL_LEN {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "nvme serial".to_string(),
+                NVME_MAX_SERIAL_LEN,
+            )));
+        }
+        if !self.serial.is_ascii() {
+            bail!("The serial of nvme device {} must be ASCII", self.id);
+        }
+
+        if let Some(iothread) = self.iothread.as_ref() {
+            check_arg_too_long(iothread, "iothread name")?;
+        }
+
+        if self.queues < 1 || self.queues > NVME_MAX_IOQPAIRS {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "queues number of nvme controller".to_string(),
+                1,
+                true,
+                NVME_MAX_IOQPAIRS as u64,
+                true,
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NvmeNsConfig {
+    /// Namespace device id, the block device is named by it in qmp.
+    pub id: String,
+    /// NVMe controller which the namespace attaches to.
+    pub cntlr: String,
+    /// Namespace identifier, 0 means the first free one.
+    pub nsid: u32,
+    /// The drive of the namespace.
+    pub drive: DriveConfig,
+}
+
+impl ConfigCheck for NvmeNsConfig {
+    fn check(&self) -> Result<()> {
+        check_arg_too_long(&self.id, "nvme-ns device id")?;
+
+        if self.nsid > NVME_MAX_NAMESPACES {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "nsid of nvme namespace".to_string(),
+                1,
+                true,
+                NVME_MAX_NAMESPACES as u64,
+                true,
+            )));
+        }
+
+        if self.drive.path_on_host.is_empty() {
+            return Err(anyhow!(ConfigError::FieldIsMissing(
+                "file".to_string(),
+                "nvme namespace drive".to_string()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_nvme_drive(vm_config: &mut VmConfig, drive: &str) -> Result<DriveConfig> {
+    vm_config
+        .drives
+        .remove(drive)
+        .with_context(|| format!("No drive {} configured matched for nvme device", drive))
+}
+
+pub fn parse_nvme(vm_config: &mut VmConfig, nvme_config: &str) -> Result<NvmeConfig> {
+    let mut cmd_parser = CmdParser::new("nvme");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("bus")
+        .push("addr")
+        .push("multifunction")
+        .push("drive")
+        .push("serial")
+        .push("num-queues")
+        .push("iothread");
+
+    cmd_parser.parse(nvme_config)?;
+
+    pci_args_check(&cmd_parser)?;
+
+    let id = cmd_parser
+        .get_value::<String>("id")?
+        .with_context(|| ConfigError::FieldIsMissing("id".to_string(), "nvme".to_string()))?;
+    let serial = cmd_parser
+        .get_value::<String>("serial")?
+        .with_context(|| ConfigError::FieldIsMissing("serial".to_string(), "nvme".to_string()))?;
+    let mut nvme_cfg = NvmeConfig {
+        id,
+        serial,
+        ..Default::default()
+    };
+
+    if let Some(queues) = cmd_parser.get_value::<u16>("num-queues")? {
+        nvme_cfg.queues = queues;
+    }
+
+    if let Some(iothread) = cmd_parser.get_value::<String>("iothread")? {
+        nvme_cfg.iothread = Some(iothread);
+    }
+
+    if let Some(drive) = cmd_parser.get_value::<String>("drive")? {
+        let ns_cfg = NvmeNsConfig {
+            id: nvme_cfg.id.clone(),
+            cntlr: nvme_cfg.id.clone(),
+            nsid: 1,
+            drive: parse_nvme_drive(vm_config, &drive)?,
+        };
+        ns_cfg.check()?;
+        nvme_cfg.namespace = Some(ns_cfg);
+    }
+
+    nvme_cfg.check()?;
+    Ok(nvme_cfg)
+}
+
+pub fn parse_nvme_ns(vm_config: &mut VmConfig, ns_config: &str) -> Result<NvmeNsConfig> {
+    let mut cmd_parser = CmdParser::new("nvme-ns");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("bus")
+        .push("drive")
+        .push("nsid");
+
+    cmd_parser.parse(ns_config)?;
+
+    let id = cmd_parser
+        .get_value::<String>("id")?
+        .with_context(|| ConfigError::FieldIsMissing("id".to_string(), "nvme-ns".to_string()))?;
+    let cntlr = cmd_parser
+        .get_value::<String>("bus")?
+        .with_context(|| ConfigError::FieldIsMissing("bus".to_string(), "nvme-ns".to_string()))?;
+    // Nsid 0 is reserved to pick the first free one.
+    let nsid = match cmd_parser.get_value::<u32>("nsid")? {
+        Some(0) => bail!("The nsid of nvme namespace {} can't be 0", id),
+        Some(nsid) => nsid,
+        None => 0,
+    };
+    let drive = cmd_parser
+        .get_value::<String>("drive")?
+        .with_context(|| ConfigError::FieldIsMissing("drive".to_string(), "nvme-ns".to_string()))?;
+
+    let ns_cfg = NvmeNsConfig {
+        id,
+        cntlr,
+        nsid,
+        drive: parse_nvme_drive(vm_config, &drive)?,
+    };
+    ns_cfg.check()?;
+    Ok(ns_cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nvme_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=drive0,file=/path/to/disk0,direct=off,aio=off")
+            .is_ok());
+        let nvme_cfg = parse_nvme(
+            &mut vm_config,
+            "nvme,id=nvme0,bus=pcie.0,addr=0x3,drive=drive0,serial=abc123,num-queues=8",
+        )
+        .unwrap();
+        assert_eq!(nvme_cfg.id, "nvme0");
+        assert_eq!(nvme_cfg.serial, "abc123");
+        assert_eq!(nvme_cfg.queues, 8);
+        let ns_cfg = nvme_cfg.namespace.unwrap();
+        assert_eq!(ns_cfg.nsid, 1);
+        assert_eq!(ns_cfg.id, "nvme0");
+        assert_eq!(ns_cfg.drive.path_on_host, "/path/to/disk0");
+
+        // The drive has been used.
+        assert!(parse_nvme(
+            &mut vm_config,
+            "nvme,id=nvme1,bus=pcie.0,addr=0x4,drive=drive0,serial=abc124"
+        )
+        .is_err());
+
+        // The controller can be created without drive, but the serial is required.
+        let nvme_cfg = parse_nvme(&mut vm_config, "nvme,id=nvme1,bus=pcie.0,addr=0x4,serial=x");
+        assert!(nvme_cfg.unwrap().namespace.is_none());
+        assert!(parse_nvme(&mut vm_config, "nvme,id=nvme2,bus=pcie.0,addr=0x5").is_err());
+        assert!(parse_nvme(
+            &mut vm_config,
+            "nvme,id=nvme2,bus=pcie.0,addr=0x5,serial=123456789012345678901"
+        )
+        .is_err());
+        assert!(parse_nvme(
+            &mut vm_config,
+            "nvme,id=nvme2,bus=pcie.0,addr=0x5,serial=x,num-queues=65"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_nvme_ns_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_drive("id=drive1,file=/path/to/disk1").is_ok());
+        assert!(vm_config.add_drive("id=drive2,file=/path/to/disk2").is_ok());
+        let ns_cfg = parse_nvme_ns(
+            &mut vm_config,
+            "nvme-ns,id=ns1,bus=nvme0,drive=drive1,nsid=3",
+        )
+        .unwrap();
+        assert_eq!(ns_cfg.cntlr, "nvme0");
+        assert_eq!(ns_cfg.nsid, 3);
+        assert_eq!(ns_cfg.drive.path_on_host, "/path/to/disk1");
+
+        assert!(parse_nvme_ns(&mut vm_config, "nvme-ns,id=ns2,drive=drive2").is_err());
+        assert!(parse_nvme_ns(
+            &mut vm_config,
+            "nvme-ns,id=ns2,bus=nvme0,drive=drive2,nsid=0"
+        )
+        .is_err());
+        let ns_cfg =
+            parse_nvme_ns(&mut vm_config, "nvme-ns,id=ns2,bus=nvme0,drive=drive2").unwrap();
+        assert_eq!(ns_cfg.nsid, 0);
+    }
+}
@@ -14,9 +14,10 @@ use anyhow::{anyhow, bail, Context, Result};
 
 use super::{error::ConfigError, pci_args_check};
 use crate::config::{
-    check_arg_too_long, CmdParser, ConfigCheck, VmConfig, DEFAULT_VIRTQUEUE_SIZE, MAX_VIRTIO_QUEUE,
+    check_arg_too_long, CmdParser, ConfigCheck, DiskFormat, VmConfig, DEFAULT_VIRTQUEUE_SIZE,
+    MAX_VIRTIO_QUEUE,
 };
-use util::aio::AioEngine;
+use util::aio::{AioEngine, IoUringConfig, WriteZeroesState};
 
 /// According to Virtio Spec.
 /// Max_channel should be 0.
@@ -140,6 +141,16 @@ pub fn parse_scsi_controller(
     Ok(cntlr_cfg)
 }
 
+/// The scsi device passes the commands through to the host scsi device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScsiPassthrough {
+    /// All the commands are passed through by SG_IO, used by scsi-generic.
+    Generic,
+    /// The read and write commands are handled by the block backend, the others are
+    /// passed through by SG_IO, used by scsi-block.
+    Block,
+}
+
 #[derive(Clone, Debug)]
 pub struct ScsiDevConfig {
     /// Scsi Device id.
@@ -156,12 +167,22 @@ pub struct ScsiDevConfig {
     pub direct: bool,
     /// Async IO type.
     pub aio_type: AioEngine,
+    /// Tuning of the io_uring engine.
+    pub uring: IoUringConfig,
+    /// Format of the image file.
+    pub format: DiskFormat,
+    /// Discard support, the space released by the guest is freed in the image file.
+    pub discard: bool,
+    /// The write zeroes state, the zero writes are detected or not.
+    pub write_zeroes: WriteZeroesState,
     /// Boot order.
     pub boot_index: Option<u8>,
     /// Scsi four level hierarchical address(host, channel, target, lun).
     pub channel: u8,
     pub target: u8,
     pub lun: u16,
+    /// The host scsi device which the commands are passed through to.
+    pub passthrough: Option<ScsiPassthrough>,
 }
 
 impl Default for ScsiDevConfig {
@@ -174,10 +195,15 @@ impl Default for ScsiDevConfig {
             read_only: false,
             direct: true,
             aio_type: AioEngine::Native,
+            uring: IoUringConfig::default(),
+            format: DiskFormat::Raw,
+            discard: false,
+            write_zeroes: WriteZeroesState::Off,
             boot_index: None,
             channel: 0,
             target: 0,
             lun: 0,
+            passthrough: None,
         }
     }
 }
@@ -257,12 +283,95 @@ pub fn parse_scsi_device(vm_config: &mut VmConfig, drive_config: &str) -> Result
         scsi_dev_cfg.lun = lun;
     }
 
+    scsi_dev_cfg.passthrough = match cmd_parser.get_value::<String>("")?.as_deref() {
+        Some("scsi-generic") => Some(ScsiPassthrough::Generic),
+        Some("scsi-block") => Some(ScsiPassthrough::Block),
+        _ => None,
+    };
     if let Some(drive_arg) = &vm_config.drives.remove(&scsi_drive) {
         scsi_dev_cfg.path_on_host = drive_arg.path_on_host.clone();
         scsi_dev_cfg.read_only = drive_arg.read_only;
         scsi_dev_cfg.direct = drive_arg.direct;
         scsi_dev_cfg.aio_type = drive_arg.aio;
+        scsi_dev_cfg.uring = drive_arg.uring;
+        scsi_dev_cfg.format = drive_arg.format;
+        scsi_dev_cfg.discard = drive_arg.discard;
+        scsi_dev_cfg.write_zeroes = drive_arg.write_zeroes;
+    }
+    if scsi_dev_cfg.passthrough.is_some() {
+        if scsi_dev_cfg.path_on_host.is_empty() {
+            bail!(
+                "The host device of passthrough scsi device {} is not set",
+                scsi_dev_cfg.id
+            );
+        }
+        if scsi_dev_cfg.format != DiskFormat::Raw {
+            bail!(
+                "Only raw format is supported by passthrough scsi device {}",
+                scsi_dev_cfg.id
+            );
+        }
     }
 
     Ok(scsi_dev_cfg)
 }
+
+impl VmConfig {
+    /// Get the controller, scsi-id and lun of the scsi device by its id.
+    pub fn get_scsi_device_addr(&self, id: &str) -> Option<(String, u8, u16)> {
+        for (dev_type, dev_cfg) in &self.devices {
+            if !matches!(
+                dev_type.as_str(),
+                "scsi-hd" | "scsi-cd" | "scsi-generic" | "scsi-block"
+            ) {
+                continue;
+            }
+            let mut cmd_parser = CmdParser::new("scsi-device");
+            cmd_parser
+                .push("id")
+                .push("bus")
+                .push("scsi-id")
+                .push("lun");
+            cmd_parser.get_parameters(dev_cfg).ok()?;
+            if cmd_parser.get_value::<String>("id").ok()?.as_deref() != Some(id) {
+                continue;
+            }
+            let bus = cmd_parser.get_value::<String>("bus").ok()??;
+            let cntlr = bus.split('.').next()?.to_string();
+            let target = cmd_parser.get_value::<u8>("scsi-id").ok()?.unwrap_or(0);
+            let lun = cmd_parser.get_value::<u16>("lun").ok()?.unwrap_or(0);
+            return Some((cntlr, target, lun));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_scsi_device_addr() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_device("scsi-hd,id=disk0,bus=scsi0.0,scsi-id=1,lun=2,drive=drive0")
+            .is_ok());
+        assert!(vm_config
+            .add_device("scsi-cd,id=cd0,bus=scsi1.0,drive=drive1")
+            .is_ok());
+        assert!(vm_config
+            .add_device("virtio-blk-pci,id=blk0,bus=pcie.0,addr=0x3,drive=drive2")
+            .is_ok());
+
+        assert_eq!(
+            vm_config.get_scsi_device_addr("disk0"),
+            Some(("scsi0".to_string(), 1, 2))
+        );
+        assert_eq!(
+            vm_config.get_scsi_device_addr("cd0"),
+            Some(("scsi1".to_string(), 0, 0))
+        );
+        assert_eq!(vm_config.get_scsi_device_addr("blk0"), None);
+        assert_eq!(vm_config.get_scsi_device_addr("disk1"), None);
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright (c) 2022 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::hash_map::Entry;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{
+    check_arg_too_long, CmdParser, ConfigCheck, ConfigError, ThrottleConfig, VmConfig,
+};
+
+/// Config struct for `throttle-group` object. The block devices which join the
+/// same group share the I/O limits of the group.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ThrottleGroupConfig {
+    /// Object Id.
+    pub id: String,
+    /// I/O limits shared by the block devices of the group.
+    pub throttle: ThrottleConfig,
+}
+
+impl ConfigCheck for ThrottleGroupConfig {
+    fn check(&self) -> Result<()> {
+        check_arg_too_long(&self.id, "throttle-group id")?;
+        self.throttle.check()
+    }
+}
+
+impl VmConfig {
+    /// Add '-object throttle-group ...' to `VmConfig`.
+    pub fn add_throttle_group(&mut self, throttle_group_config: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::new("throttle-group");
+        cmd_parser.push("").push("id");
+        ThrottleConfig::push_params(&mut cmd_parser, "x-");
+        cmd_parser.parse(throttle_group_config)?;
+
+        let group = ThrottleGroupConfig {
+            id: cmd_parser.get_value::<String>("id")?.with_context(|| {
+                ConfigError::FieldIsMissing("id".to_string(), "throttle-group".to_string())
+            })?,
+            throttle: ThrottleConfig::parse(&cmd_parser, "x-")?,
+        };
+        group.check()?;
+
+        match self.object.throttle_object.entry(group.id.clone()) {
+            Entry::Occupied(e) => Err(anyhow!(ConfigError::IdRepeat(
+                "throttle-group".to_string(),
+                e.key().clone()
+            ))),
+            Entry::Vacant(e) => {
+                e.insert(group);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_blk;
+
+    #[test]
+    fn test_add_throttle_group() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_object(
+                "throttle-group,id=group0,x-iops-total=1000,x-bps-read=1048576,x-bps-read-max=2097152"
+            )
+            .is_ok());
+        let group = vm_config.object.throttle_object.get("group0").unwrap();
+        assert_eq!(group.throttle.iops_total.avg, 1000);
+        assert_eq!(group.throttle.bps_read.avg, 1048576);
+        assert_eq!(group.throttle.bps_read.max, 2097152);
+        assert_eq!(group.throttle.bps_read.max_length, 1);
+
+        // Repeated id.
+        assert!(vm_config
+            .add_object("throttle-group,id=group0,x-iops-total=100")
+            .is_err());
+        // Missing id.
+        assert!(vm_config
+            .add_object("throttle-group,x-iops-total=100")
+            .is_err());
+        // Invalid limits.
+        assert!(vm_config
+            .add_object("throttle-group,id=group1,x-iops-total=100,x-iops-write=10")
+            .is_err());
+
+        // Join the group.
+        assert!(vm_config
+            .add_drive("id=drive0,file=/path/to/rootfs,throttling.group=group0")
+            .is_ok());
+        let blk_cfg = parse_blk(
+            &mut vm_config,
+            "virtio-blk-pci,id=blk0,bus=pcie.0,addr=0x1.0x0,drive=drive0",
+            None,
+        )
+        .unwrap();
+        assert_eq!(blk_cfg.throttle_group.unwrap().id, "group0");
+
+        assert!(vm_config
+            .add_drive("id=drive1,file=/path/to/rootfs,throttling.group=group1")
+            .is_ok());
+        assert!(parse_blk(
+            &mut vm_config,
+            "virtio-blk-pci,id=blk1,bus=pcie.0,addr=0x2.0x0,drive=drive1",
+            None,
+        )
+        .is_err());
+    }
+}
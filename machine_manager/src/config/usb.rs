@@ -288,6 +288,8 @@ pub fn parse_usb_storage(vm_config: &mut VmConfig, drive_config: &str) -> Result
 
     let mut dev = UsbStorageConfig::new();
     dev.id = cmd_parser.get_value::<String>("id")?;
+    // The scsi device is reported to qmp with the id of the usb storage.
+    dev.scsi_cfg.id = dev.id.clone().unwrap_or_default();
 
     let storage_drive = cmd_parser.get_value::<String>("drive")?.with_context(|| {
         ConfigError::FieldIsMissing("drive".to_string(), "usb storage device".to_string())
@@ -301,6 +303,7 @@ pub fn parse_usb_storage(vm_config: &mut VmConfig, drive_config: &str) -> Result
     dev.scsi_cfg.read_only = drive_arg.read_only;
     dev.scsi_cfg.aio_type = drive_arg.aio;
     dev.scsi_cfg.direct = drive_arg.direct;
+    dev.scsi_cfg.format = drive_arg.format;
     dev.media = drive_arg.media.clone();
 
     dev.check()?;
@@ -18,10 +18,12 @@ use strum::VariantNames;
 
 use crate::config::ShutdownAction;
 use crate::qmp::qmp_schema::{
-    BlockDevAddArgument, CharDevAddArgument, ChardevInfo, Cmd, CmdLine, CmdParameter,
-    DeviceAddArgument, DeviceProps, Events, GicCap, HumanMonitorCmdArgument, IothreadInfo, KvmInfo,
-    MachineInfo, MigrateCapabilities, NetDevAddArgument, PropList, QmpCommand, QmpErrorClass,
-    QmpEvent, Target, TypeLists, UpdateRegionArgument,
+    AddrOptions, BlockDevAddArgument, BlockDeviceInfo, BlockDirtyBitmapAddArgument, BlockInfo,
+    BlockJobInfo, BlockSetIoThrottleArgument, BlockStats, BlockdevChangeMediumArgument,
+    CharDevAddArgument, ChardevInfo, Cmd, CmdLine, CmdParameter, DeviceAddArgument, DeviceProps,
+    DriveBackupArgument, EjectArgument, Events, GicCap, HumanMonitorCmdArgument, IothreadInfo,
+    KvmInfo, MachineInfo, MigrateCapabilities, NetDevAddArgument, NetdevCaptureStartArgument,
+    PropList, QmpCommand, QmpErrorClass, QmpEvent, Target, TypeLists, UpdateRegionArgument,
 };
 use crate::qmp::{Response, Version};
 
@@ -170,11 +172,59 @@ pub trait DeviceInterface {
     /// Delete a block device.
     fn blockdev_del(&self, node_name: String) -> Response;
 
+    /// Change the I/O throttling limits of a block device.
+    fn block_set_io_throttle(&self, args: Box<BlockSetIoThrottleArgument>) -> Response;
+
+    /// Grow the disk of a block device to `size` bytes.
+    fn block_resize(&self, device: String, size: u64) -> Response;
+
+    /// Open the tray of a removable block device and remove its medium.
+    fn eject(&self, args: EjectArgument) -> Response;
+
+    /// Replace the medium of a removable block device.
+    fn blockdev_change_medium(&self, args: BlockdevChangeMediumArgument) -> Response;
+
+    /// Start the NBD server listening on `addr`, serving at most `max_connections`
+    /// connections at the same time.
+    fn nbd_server_start(&self, addr: AddrOptions, max_connections: Option<u32>) -> Response;
+
+    /// Export the disk of the block device `device` by the NBD server.
+    fn nbd_server_add(
+        &self,
+        device: String,
+        name: Option<String>,
+        writable: Option<bool>,
+    ) -> Response;
+
+    /// Remove the export `name` of the NBD server.
+    fn nbd_server_remove(&self, name: String, mode: Option<String>) -> Response;
+
+    /// Start a backup job of the block device.
+    fn drive_backup(&self, args: Box<DriveBackupArgument>) -> Response;
+
+    /// Cancel the block job `device`.
+    fn block_job_cancel(&self, device: String) -> Response;
+
+    /// Create a dirty bitmap of the block device.
+    fn block_dirty_bitmap_add(&self, args: BlockDirtyBitmapAddArgument) -> Response;
+
+    /// Remove the dirty bitmap `name` of the block device `node`.
+    fn block_dirty_bitmap_remove(&self, node: String, name: String) -> Response;
+
+    /// Clear the dirty bitmap `name` of the block device `node`.
+    fn block_dirty_bitmap_clear(&self, node: String, name: String) -> Response;
+
     /// Create a new network device.
     fn netdev_add(&mut self, args: Box<NetDevAddArgument>) -> Response;
 
     fn netdev_del(&mut self, id: String) -> Response;
 
+    /// Start capturing the frames of the virtio-net device using the netdev.
+    fn netdev_capture_start(&self, args: NetdevCaptureStartArgument) -> Response;
+
+    /// Stop capturing the frames of the virtio-net device using netdev `netdev`.
+    fn netdev_capture_stop(&self, netdev: String) -> Response;
+
     /// Create a new chardev device.
     fn chardev_add(&mut self, _args: CharDevAddArgument) -> Response;
 
@@ -309,6 +359,11 @@ pub trait DeviceInterface {
             ("usb-tablet", "usb-hid"),
             ("usb-kbd", "usb-hid"),
             ("usb-storage", "usb-storage-dev"),
+            ("nvme", "pci-device"),
+            ("nvme-ns", "device"),
+            ("ich9-ahci", "pci-device"),
+            ("ide-hd", "device"),
+            ("ide-cd", "device"),
             ("virtio-gpu-pci", "virtio-gpu"),
         ];
 
@@ -413,24 +468,23 @@ pub trait DeviceInterface {
     }
 
     fn query_block(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(vec_cmd).unwrap(), None)
+        let vec_block: Vec<BlockInfo> = Vec::new();
+        Response::create_response(serde_json::to_value(vec_block).unwrap(), None)
     }
 
     fn query_named_block_nodes(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(vec_cmd).unwrap(), None)
+        let vec_node: Vec<BlockDeviceInfo> = Vec::new();
+        Response::create_response(serde_json::to_value(vec_node).unwrap(), None)
     }
 
     fn query_blockstats(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(vec_cmd).unwrap(), None)
+        let vec_stats: Vec<BlockStats> = Vec::new();
+        Response::create_response(serde_json::to_value(vec_stats).unwrap(), None)
     }
 
     fn query_block_jobs(&self) -> Response {
-        // Fix me: qmp command call, return none temporarily.
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(vec_cmd).unwrap(), None)
+        let vec_jobs: Vec<BlockJobInfo> = Vec::new();
+        Response::create_response(serde_json::to_value(vec_jobs).unwrap(), None)
     }
 
     fn query_gic_capabilities(&self) -> Response {
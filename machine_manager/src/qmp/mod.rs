@@ -450,13 +450,27 @@ fn qmp_command_exec(
         (device_list_properties, device_list_properties, typename),
         (device_del, device_del, id),
         (blockdev_del, blockdev_del, node_name),
+        (block_resize, block_resize, device, size),
+        (nbd_server_start, nbd_server_start, addr, max_connections),
+        (nbd_server_add, nbd_server_add, device, name, writable),
+        (nbd_server_remove, nbd_server_remove, name, mode),
+        (block_job_cancel, block_job_cancel, device),
+        (block_dirty_bitmap_remove, block_dirty_bitmap_remove, node, name),
+        (block_dirty_bitmap_clear, block_dirty_bitmap_clear, node, name),
         (netdev_del, netdev_del, id),
+        (netdev_capture_stop, netdev_capture_stop, netdev),
         (chardev_remove, chardev_remove, id),
         (balloon, balloon, value),
         (migrate, migrate, uri);
         (device_add, device_add),
         (blockdev_add, blockdev_add),
+        (block_set_io_throttle, block_set_io_throttle),
+        (eject, eject),
+        (blockdev_change_medium, blockdev_change_medium),
+        (drive_backup, drive_backup),
+        (block_dirty_bitmap_add, block_dirty_bitmap_add),
         (netdev_add, netdev_add),
+        (netdev_capture_start, netdev_capture_start),
         (chardev_add, chardev_add),
         (update_region, update_region),
         (human_monitor_command, human_monitor_command)
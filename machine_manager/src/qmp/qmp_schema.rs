@@ -124,6 +124,20 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "netdev-capture-start")]
+    #[strum(serialize = "netdev-capture-start")]
+    netdev_capture_start {
+        arguments: netdev_capture_start,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "netdev-capture-stop")]
+    #[strum(serialize = "netdev-capture-stop")]
+    netdev_capture_stop {
+        arguments: netdev_capture_stop,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-hotpluggable-cpus")]
     #[strum(serialize = "query-hotpluggable-cpus")]
     query_hotpluggable_cpus {
@@ -164,6 +178,84 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    block_set_io_throttle {
+        arguments: Box<block_set_io_throttle>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    block_resize {
+        arguments: block_resize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    eject {
+        arguments: eject,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "blockdev-change-medium")]
+    #[strum(serialize = "blockdev-change-medium")]
+    blockdev_change_medium {
+        arguments: blockdev_change_medium,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "nbd-server-start")]
+    #[strum(serialize = "nbd-server-start")]
+    nbd_server_start {
+        arguments: nbd_server_start,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "nbd-server-add")]
+    #[strum(serialize = "nbd-server-add")]
+    nbd_server_add {
+        arguments: nbd_server_add,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "nbd-server-remove")]
+    #[strum(serialize = "nbd-server-remove")]
+    nbd_server_remove {
+        arguments: nbd_server_remove,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "drive-backup")]
+    #[strum(serialize = "drive-backup")]
+    drive_backup {
+        arguments: Box<drive_backup>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-job-cancel")]
+    #[strum(serialize = "block-job-cancel")]
+    block_job_cancel {
+        arguments: block_job_cancel,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-dirty-bitmap-add")]
+    #[strum(serialize = "block-dirty-bitmap-add")]
+    block_dirty_bitmap_add {
+        arguments: block_dirty_bitmap_add,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-dirty-bitmap-remove")]
+    #[strum(serialize = "block-dirty-bitmap-remove")]
+    block_dirty_bitmap_remove {
+        arguments: block_dirty_bitmap_remove,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-dirty-bitmap-clear")]
+    #[strum(serialize = "block-dirty-bitmap-clear")]
+    block_dirty_bitmap_clear {
+        arguments: block_dirty_bitmap_clear,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "balloon")]
     balloon {
         #[serde(default)]
@@ -568,6 +660,8 @@ pub struct device_add {
     pub driver: String,
     #[serde(rename = "addr")]
     pub addr: Option<String>,
+    #[serde(rename = "scsi-id")]
+    pub scsi_id: Option<u8>,
     #[serde(rename = "lun")]
     pub lun: Option<usize>,
     #[serde(rename = "drive")]
@@ -939,6 +1033,460 @@ impl Command for blockdev_del {
     }
 }
 
+/// block_set_io_throttle
+///
+/// Change the I/O throttling limits of a block device, zero means no limit.
+///
+/// # Arguments
+///
+/// * `device` - The name of the block device.
+/// * `id` - The id of the block device, used if `device` is not set.
+/// * `bps`/`bps_rd`/`bps_wr` - Total/read/write bytes per second.
+/// * `iops`/`iops_rd`/`iops_wr` - Total/read/write operations per second.
+/// * `*_max` - Burst limit of the corresponding limit.
+/// * `*_max_length` - Seconds that the burst limit can last.
+/// * `group` - The throttle group which the block device joins, its limits are
+///   changed instead of the limits of the block device.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block_set_io_throttle",
+///      "arguments": { "device": "drive-0", "bps": 1048576, "bps_rd": 0, "bps_wr": 0,
+///                     "iops": 0, "iops_rd": 100, "iops_wr": 50 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_set_io_throttle {
+    pub device: Option<String>,
+    pub id: Option<String>,
+    pub bps: u64,
+    pub bps_rd: u64,
+    pub bps_wr: u64,
+    pub iops: u64,
+    pub iops_rd: u64,
+    pub iops_wr: u64,
+    pub bps_max: Option<u64>,
+    pub bps_rd_max: Option<u64>,
+    pub bps_wr_max: Option<u64>,
+    pub iops_max: Option<u64>,
+    pub iops_rd_max: Option<u64>,
+    pub iops_wr_max: Option<u64>,
+    pub bps_max_length: Option<u64>,
+    pub bps_rd_max_length: Option<u64>,
+    pub bps_wr_max_length: Option<u64>,
+    pub iops_max_length: Option<u64>,
+    pub iops_rd_max_length: Option<u64>,
+    pub iops_wr_max_length: Option<u64>,
+    pub group: Option<String>,
+}
+
+pub type BlockSetIoThrottleArgument = block_set_io_throttle;
+
+impl Command for block_set_io_throttle {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block_resize
+///
+/// Grow the disk of a block device online, shrinking is not supported.
+///
+/// # Arguments
+///
+/// * `device` - The name of the block device.
+/// * `size` - The new size of the disk in bytes, it must be a multiple of 512.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block_resize",
+///      "arguments": { "device": "drive-0", "size": 21474836480 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_resize {
+    pub device: String,
+    pub size: u64,
+}
+
+impl Command for block_resize {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// eject
+///
+/// Open the tray of a removable block device and remove its medium.
+///
+/// # Arguments
+///
+/// * `device` - The name of the block device.
+/// * `id` - The id of the block device, used if `device` is not set.
+/// * `force` - Remove the medium even if it is locked by the guest. Default is false.
+///
+/// # Notes
+///
+/// If the medium is locked by the guest and `force` is not set, the guest is
+/// asked to eject the medium and the command fails. It can be tried again
+/// after the guest opens the tray.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "eject", "arguments": { "id": "cd0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct eject {
+    pub device: Option<String>,
+    pub id: Option<String>,
+    pub force: Option<bool>,
+}
+
+pub type EjectArgument = eject;
+
+impl Command for eject {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// blockdev-change-medium
+///
+/// Replace the medium of a removable block device with an image file, and
+/// close the tray.
+///
+/// # Arguments
+///
+/// * `device` - The name of the block device.
+/// * `id` - The id of the block device, used if `device` is not set.
+/// * `filename` - The path of the image file.
+/// * `format` - The format of the image file, "raw" or "qcow2". Default is "raw".
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "blockdev-change-medium",
+///      "arguments": { "id": "cd0", "filename": "/path/to/drivers.iso" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct blockdev_change_medium {
+    pub device: Option<String>,
+    pub id: Option<String>,
+    pub filename: String,
+    pub format: Option<String>,
+}
+
+pub type BlockdevChangeMediumArgument = blockdev_change_medium;
+
+impl Command for blockdev_change_medium {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// nbd-server-start
+///
+/// Start the built-in NBD server to export the disks of the VM.
+///
+/// # Arguments
+///
+/// * `addr` - The address the server listens on, only unix socket is supported.
+/// * `max-connections` - The max number of the connections served at the same
+///   time. Default is 16.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "nbd-server-start",
+///      "arguments": { "addr": { "type": "unix",
+///                               "data": { "path": "/tmp/nbd.sock" } } } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct nbd_server_start {
+    pub addr: AddrOptions,
+    #[serde(rename = "max-connections")]
+    pub max_connections: Option<u32>,
+}
+
+impl Command for nbd_server_start {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// nbd-server-add
+///
+/// Export the disk of a block device by the NBD server.
+///
+/// # Arguments
+///
+/// * `device` - The name of the block device, only raw disk is supported.
+/// * `name` - The export name, default to the name of the block device.
+/// * `writable` - Allow the clients to write the disk, only when the VM is
+///   paused. Default is false.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "nbd-server-add",
+///      "arguments": { "device": "drive-0", "name": "disk0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct nbd_server_add {
+    pub device: String,
+    pub name: Option<String>,
+    pub writable: Option<bool>,
+}
+
+impl Command for nbd_server_add {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// nbd-server-remove
+///
+/// Remove an export of the NBD server.
+///
+/// # Arguments
+///
+/// * `name` - The export name.
+/// * `mode` - `safe` fails if the export is in use, `hard` disconnects the
+///   clients. Default is `safe`.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "nbd-server-remove",
+///      "arguments": { "name": "disk0", "mode": "hard" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct nbd_server_remove {
+    pub name: String,
+    pub mode: Option<String>,
+}
+
+impl Command for nbd_server_remove {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// drive-backup
+///
+/// Start a backup job copying the point-in-time content of the disk to the
+/// target file, while the guest keeps writing the disk.
+///
+/// # Arguments
+///
+/// * `job-id` - The id of the job, default to the name of the block device.
+/// * `device` - The name of the block device, only raw disk is supported.
+/// * `target` - The path of the target file.
+/// * `sync` - What parts of the disk to copy, `full` copies the whole disk,
+///   `incremental` copies the ranges marked dirty in the bitmap.
+/// * `mode` - `absolute-paths` creates the target file, `existing` uses the
+///   existing one. Default is `absolute-paths`.
+/// * `format` - The format of the target file, only `raw` is supported.
+/// * `speed` - The maximum speed in bytes per second, 0 means unlimited.
+/// * `bitmap` - The dirty bitmap used by the `incremental` sync. It's cleared
+///   when the job starts, and the dirty ranges are merged back if the job
+///   fails or is cancelled.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "drive-backup",
+///      "arguments": { "device": "drive-0", "target": "/path/to/backup.img",
+///                     "sync": "full" } }
+/// <- { "return": {} }
+/// -> { "execute": "drive-backup",
+///      "arguments": { "device": "drive-0", "target": "/path/to/inc.img",
+///                     "sync": "incremental", "bitmap": "bitmap0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct drive_backup {
+    #[serde(rename = "job-id")]
+    pub job_id: Option<String>,
+    pub device: String,
+    pub target: String,
+    pub sync: String,
+    pub mode: Option<String>,
+    pub format: Option<String>,
+    pub speed: Option<u64>,
+    pub bitmap: Option<String>,
+}
+
+pub type DriveBackupArgument = drive_backup;
+
+impl Command for drive_backup {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-job-cancel
+///
+/// Cancel a running block job, the `BLOCK_JOB_CANCELLED` event is emitted
+/// when the job stops.
+///
+/// # Arguments
+///
+/// * `device` - The id of the job.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-job-cancel", "arguments": { "device": "drive-0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_job_cancel {
+    pub device: String,
+}
+
+impl Command for block_job_cancel {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-dirty-bitmap-add
+///
+/// Create a dirty bitmap tracking the ranges of the disk written by the guest.
+///
+/// # Arguments
+///
+/// * `node` - The name of the block device.
+/// * `name` - The name of the bitmap.
+/// * `granularity` - The size of the range tracked by one bit, it should be
+///   a power of 2 between 512 and 64M. Default is 64K.
+/// * `persistent` - Whether to save the bitmap next to the image when the VM
+///   shuts down, and load it when the VM starts. Default is false.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-dirty-bitmap-add",
+///      "arguments": { "node": "drive-0", "name": "bitmap0", "persistent": true } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_dirty_bitmap_add {
+    pub node: String,
+    pub name: String,
+    pub granularity: Option<u64>,
+    pub persistent: Option<bool>,
+}
+
+pub type BlockDirtyBitmapAddArgument = block_dirty_bitmap_add;
+
+impl Command for block_dirty_bitmap_add {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-dirty-bitmap-remove
+///
+/// Remove a dirty bitmap, the persistent one is not saved any more.
+///
+/// # Arguments
+///
+/// * `node` - The name of the block device.
+/// * `name` - The name of the bitmap.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-dirty-bitmap-remove",
+///      "arguments": { "node": "drive-0", "name": "bitmap0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_dirty_bitmap_remove {
+    pub node: String,
+    pub name: String,
+}
+
+impl Command for block_dirty_bitmap_remove {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-dirty-bitmap-clear
+///
+/// Clear all the dirty bits of a dirty bitmap.
+///
+/// # Arguments
+///
+/// * `node` - The name of the block device.
+/// * `name` - The name of the bitmap.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-dirty-bitmap-clear",
+///      "arguments": { "node": "drive-0", "name": "bitmap0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_dirty_bitmap_clear {
+    pub node: String,
+    pub name: String,
+}
+
+impl Command for block_dirty_bitmap_clear {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
 /// netdev_del
 ///
 /// Remove a network backend.
@@ -971,6 +1519,83 @@ impl Command for netdev_del {
     }
 }
 
+/// netdev-capture-start
+///
+/// Start capturing the frames received and sent by the guest through the
+/// virtio-net device into a pcapng file, the virtio net header is stripped.
+///
+/// # Arguments
+///
+/// * `netdev` - The id of the netdev used by the virtio-net device.
+/// * `file` - The path of the pcapng file, it's truncated if it exists.
+/// * `snaplen` - The max length of the captured data of each frame, default
+///   is 65535.
+/// * `max-size` - The max size of the file in bytes, the file is rotated to
+///   `<file>.1`, `<file>.2`, ... when it's exceeded. Default is unlimited.
+/// * `max-files` - The number of files kept when the file is rotated, including
+///   the current one. Default is 2.
+/// * `direction` - `rx` captures the frames received by the guest, `tx` captures
+///   the frames sent by the guest, default is `both`.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "netdev-capture-start",
+///      "arguments": { "netdev": "net-0", "file": "/tmp/net-0.pcapng",
+///                     "snaplen": 128, "direction": "rx" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct netdev_capture_start {
+    pub netdev: String,
+    pub file: String,
+    pub snaplen: Option<u32>,
+    #[serde(rename = "max-size")]
+    pub max_size: Option<u64>,
+    #[serde(rename = "max-files")]
+    pub max_files: Option<u32>,
+    pub direction: Option<String>,
+}
+
+pub type NetdevCaptureStartArgument = netdev_capture_start;
+
+impl Command for netdev_capture_start {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// netdev-capture-stop
+///
+/// Stop capturing the frames of the virtio-net device.
+///
+/// # Arguments
+///
+/// * `netdev` - The id of the netdev used by the virtio-net device.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "netdev-capture-stop", "arguments": { "netdev": "net-0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct netdev_capture_stop {
+    pub netdev: String,
+}
+
+impl Command for netdev_capture_stop {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
 /// query-hotpluggable-cpus:
 ///
 /// # Returns
@@ -1377,6 +2002,123 @@ pub struct DeviceDeleted {
     pub path: String,
 }
 
+/// BlockIoError
+///
+/// Emitted when a read or write request of the block device fails, the action
+/// is decided by the `rerror`/`werror` policy of the drive.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_IO_ERROR",
+///      "data": { "device": "drive-0", "node-name": "drive-0",
+///                "operation": "write", "action": "stop", "nospace": true,
+///                "reason": "No space left on device (os error 28)", "errno": 28 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockIoError {
+    /// Id of the drive.
+    pub device: String,
+    /// Node name of the drive.
+    #[serde(rename = "node-name")]
+    pub node_name: String,
+    /// I/O operation of the failed request, "read" or "write".
+    pub operation: String,
+    /// Action that has been taken, "report", "ignore" or "stop".
+    pub action: String,
+    /// True if the request failed for lack of space on the host.
+    pub nospace: bool,
+    /// Human readable description of the error.
+    pub reason: String,
+    /// Errno of the host.
+    pub errno: i32,
+}
+
+/// BlockJobCompleted
+///
+/// Emitted when a block job has completed, `error` is set if the job failed.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_JOB_COMPLETED",
+///      "data": { "type": "backup", "device": "drive-0", "len": 10737418240,
+///                "offset": 10737418240, "speed": 0 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockJobCompleted {
+    /// Type of the job.
+    #[serde(rename = "type")]
+    pub job_type: String,
+    /// Id of the job.
+    pub device: String,
+    /// Length of the data to process.
+    pub len: u64,
+    /// Length of the processed data.
+    pub offset: u64,
+    /// Speed limit of the job in bytes per second.
+    pub speed: u64,
+    /// Human readable description of the error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// BlockJobCancelled
+///
+/// Emitted when a block job has been cancelled.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_JOB_CANCELLED",
+///      "data": { "type": "backup", "device": "drive-0", "len": 10737418240,
+///                "offset": 134217728, "speed": 0 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockJobCancelled {
+    /// Type of the job.
+    #[serde(rename = "type")]
+    pub job_type: String,
+    /// Id of the job.
+    pub device: String,
+    /// Length of the data to process.
+    pub len: u64,
+    /// Length of the processed data.
+    pub offset: u64,
+    /// Speed limit of the job in bytes per second.
+    pub speed: u64,
+}
+
+/// DeviceTrayMoved
+///
+/// Emitted when the tray of a removable block device is opened or closed,
+/// by the guest or the qmp commands.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "DEVICE_TRAY_MOVED",
+///      "data": { "device": "cd0", "id": "cd0", "tray-open": true },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceTrayMoved {
+    /// Name of the block device.
+    pub device: String,
+    /// Id of the device.
+    pub id: String,
+    /// The tray is open or not.
+    #[serde(rename = "tray-open")]
+    pub tray_open: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, EnumIter, EnumVariantNames, EnumString)]
 #[serde(tag = "event")]
 pub enum QmpEvent {
@@ -1415,6 +2157,26 @@ pub enum QmpEvent {
         data: BalloonInfo,
         timestamp: TimeStamp,
     },
+    #[serde(rename = "BLOCK_IO_ERROR")]
+    BlockIoError {
+        data: BlockIoError,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "BLOCK_JOB_COMPLETED")]
+    BlockJobCompleted {
+        data: BlockJobCompleted,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "BLOCK_JOB_CANCELLED")]
+    BlockJobCancelled {
+        data: BlockJobCancelled,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "DEVICE_TRAY_MOVED")]
+    DeviceTrayMoved {
+        data: DeviceTrayMoved,
+        timestamp: TimeStamp,
+    },
 }
 
 /// query-balloon:
@@ -1663,7 +2425,9 @@ impl Command for query_machines {
 /// -> { "execute": "query-events" }
 /// <- {"return":[{"name":"Shutdown"},{"name":"Reset"},
 /// {"name":"Stop"},{"name":"Resume"},{"name":"DeviceDeleted"},
-/// {"name":"BalloonChanged"}]}
+/// {"name":"BalloonChanged"},{"name":"BlockIoError"},
+/// {"name":"BlockJobCompleted"},{"name":"BlockJobCancelled"},
+/// {"name":"DeviceTrayMoved"}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Events {
@@ -1993,34 +2757,123 @@ impl Command for qom_get {
 ///
 /// ```text
 /// -> { "execute": "query-block" }
-/// <- {"return":[]}
+/// <- {"return":[{"device":"drive-0","qdev":"blk-0","type":"unknown",
+///                "removable":false,"locked":false,
+///                "inserted":{"file":"/path/to/block","node-name":"drive-0","ro":false,
+///                            "drv":"raw","aio":"native","encrypted":false,
+///                            "detect_zeroes":"off","bps":0,"bps_rd":0,"bps_wr":0,
+///                            "iops":0,"iops_rd":0,"iops_wr":0,
+///                            "cache":{"writeback":true,"direct":true,"no-flush":false}}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_block {}
 
 impl Command for query_block {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockInfo> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDeviceCacheInfo {
+    pub writeback: bool,
+    pub direct: bool,
+    #[serde(rename = "no-flush")]
+    pub no_flush: bool,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDeviceInfo {
+    pub file: String,
+    #[serde(rename = "node-name")]
+    pub node_name: String,
+    pub ro: bool,
+    pub drv: String,
+    pub aio: String,
+    pub encrypted: bool,
+    pub detect_zeroes: String,
+    pub bps: u64,
+    pub bps_rd: u64,
+    pub bps_wr: u64,
+    pub iops: u64,
+    pub iops_rd: u64,
+    pub iops_wr: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_rd_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_wr_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_rd_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_wr_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_max_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_rd_max_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_wr_max_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_max_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_rd_max_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_wr_max_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    pub cache: BlockDeviceCacheInfo,
+    #[serde(rename = "dirty-bitmaps", skip_serializing_if = "Option::is_none")]
+    pub dirty_bitmaps: Option<Vec<BlockDirtyInfo>>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDirtyInfo {
+    pub name: String,
+    pub count: u64,
+    pub granularity: u64,
+    pub recording: bool,
+    pub busy: bool,
+    pub persistent: bool,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockInfo {
+    pub device: String,
+    pub qdev: String,
+    #[serde(rename = "type")]
+    pub block_type: String,
+    pub removable: bool,
+    pub locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tray_open: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inserted: Option<BlockDeviceInfo>,
+}
+
 /// Query named block node.
 ///
 /// # Example
 ///
 /// ```text
 /// -> { "execute": "query-named-block-nodes" }
-/// <- {"return":[]}
+/// <- {"return":[{"file":"/path/to/block","node-name":"drive-0","ro":false,
+///                "drv":"raw","aio":"native","encrypted":false,
+///                "detect_zeroes":"off","bps":0,"bps_rd":0,"bps_wr":0,
+///                "iops":0,"iops_rd":0,"iops_wr":0,
+///                "cache":{"writeback":true,"direct":true,"no-flush":false}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_named_block_nodes {}
 
 impl Command for query_named_block_nodes {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockDeviceInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockDeviceInfo> {
         Default::default()
     }
 }
@@ -2031,38 +2884,99 @@ impl Command for query_named_block_nodes {
 ///
 /// ```text
 /// -> { "execute": "query-blockstats" }
-/// <- {"return":[]}
+/// <- {"return":[{"device":"drive-0","qdev":"blk-0","node-name":"drive-0",
+///                "stats":{"rd_bytes":4096,"wr_bytes":0,"rd_operations":1,...}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_blockstats {}
 
 impl Command for query_blockstats {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockStats>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockStats> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockLatencyHistogramInfo {
+    pub boundaries: Vec<u64>,
+    pub bins: Vec<u64>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDeviceStats {
+    pub rd_bytes: u64,
+    pub wr_bytes: u64,
+    pub unmap_bytes: u64,
+    pub rd_operations: u64,
+    pub wr_operations: u64,
+    pub flush_operations: u64,
+    pub unmap_operations: u64,
+    pub rd_merged: u64,
+    pub wr_merged: u64,
+    pub unmap_merged: u64,
+    pub rd_total_time_ns: u64,
+    pub wr_total_time_ns: u64,
+    pub flush_total_time_ns: u64,
+    pub unmap_total_time_ns: u64,
+    pub failed_rd_operations: u64,
+    pub failed_wr_operations: u64,
+    pub failed_flush_operations: u64,
+    pub failed_unmap_operations: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_time_ns: Option<u64>,
+    pub account_failed: bool,
+    pub rd_latency_histogram: BlockLatencyHistogramInfo,
+    pub wr_latency_histogram: BlockLatencyHistogramInfo,
+    pub flush_latency_histogram: BlockLatencyHistogramInfo,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockStats {
+    pub device: String,
+    pub qdev: String,
+    #[serde(rename = "node-name")]
+    pub node_name: String,
+    pub stats: BlockDeviceStats,
+}
+
 /// Query jobs of blocks.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-block_jobs" }
-/// <- {"return":[]}
+/// -> { "execute": "query-block-jobs" }
+/// <- {"return":[{"type":"backup","device":"drive-0","len":10737418240,
+///     "offset":1048576,"busy":true,"paused":false,"speed":0,
+///     "io-status":"ok","ready":false}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_block_jobs {}
 
 impl Command for query_block_jobs {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockJobInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockJobInfo> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockJobInfo {
+    #[serde(rename = "type")]
+    pub job_type: String,
+    pub device: String,
+    pub len: u64,
+    pub offset: u64,
+    pub busy: bool,
+    pub paused: bool,
+    pub speed: u64,
+    #[serde(rename = "io-status")]
+    pub io_status: String,
+    pub ready: bool,
+}
+
 /// Query capabilities of gic.
 ///
 /// # Example
@@ -90,6 +90,8 @@ pub const HEADER_TYPE_MULTIFUNC: u8 = 0x80;
 pub const PCI_VENDOR_ID_REDHAT_QUMRANET: u16 = 0x1af4;
 /// The vendor ID for PCI devices other than virtio.
 pub const PCI_VENDOR_ID_REDHAT: u16 = 0x1b36;
+/// The vendor ID for Intel.
+pub const PCI_VENDOR_ID_INTEL: u16 = 0x8086;
 
 const PCI_CONFIG_HEAD_END: u8 = 64;
 const NEXT_CAP_OFFSET: u8 = 0x01;
@@ -297,8 +299,14 @@ pub const PCI_EXP_HP_EV_CCI: u16 = PCI_EXP_SLTCTL_CCIE;
 
 // XHCI device id
 pub const PCI_DEVICE_ID_REDHAT_XHCI: u16 = 0x000d;
+// NVMe device id
+pub const PCI_DEVICE_ID_REDHAT_NVME: u16 = 0x0010;
+// ICH9 AHCI device id
+pub const PCI_DEVICE_ID_INTEL_ICH9_AHCI: u16 = 0x2922;
 
 /* Device classes and subclasses */
+pub const PCI_CLASS_STORAGE_SATA: u16 = 0x0106;
+pub const PCI_CLASS_STORAGE_EXPRESS: u16 = 0x0108;
 pub const PCI_CLASS_MEMORY_RAM: u16 = 0x0500;
 pub const PCI_CLASS_SERIAL_USB: u16 = 0x0c03;
 
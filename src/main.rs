@@ -15,6 +15,7 @@ use std::os::unix::fs::OpenOptionsExt;
 use std::sync::{Arc, Mutex};
 
 use anyhow::{bail, Context, Result};
+use block_backend::snapshot::create_drive_snapshots;
 use log::{error, info};
 use machine::{LightMachine, MachineOps, StdMachine};
 use machine_manager::{
@@ -172,6 +173,8 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
         bail!("-pidfile must be used with -daemonize together.");
     }
 
+    create_drive_snapshots(vm_config).with_context(|| "Failed to create drive snapshots")?;
+
     QmpChannel::object_init();
     EventLoop::object_init(&vm_config.iothreads)?;
     register_kill_signal();
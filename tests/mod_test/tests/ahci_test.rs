@@ -0,0 +1,276 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::{thread, time};
+
+use devices::ahci::{
+    AhciCmdHeader, AhciPrd, AHCI_CMD_FIS_SIZE, AHCI_CMD_TBL_CFIS, AHCI_CMD_TBL_PRDT, AHCI_GHC_AE,
+    AHCI_PORT_CI, AHCI_PORT_CLB, AHCI_PORT_CMD, AHCI_PORT_CMD_FRE, AHCI_PORT_CMD_ST, AHCI_PORT_FB,
+    AHCI_PORT_IS, AHCI_PORT_IS_SDBS, AHCI_PORT_IS_TFES, AHCI_PORT_REG_BASE, AHCI_PORT_REG_SIZE,
+    AHCI_PORT_SACT, AHCI_PORT_SIG, AHCI_PORT_SSTS, AHCI_PORT_SSTS_ONLINE, AHCI_PORT_TFD,
+    AHCI_REG_CAP, AHCI_REG_GHC, AHCI_REG_PI, AHCI_REG_VS, AHCI_RX_FIS_SDB, AHCI_SIG_ATA,
+    AHCI_SIG_ATAPI, AHCI_VERSION, ATA_CMD_IDENTIFY_DEVICE, ATA_CMD_READ_DMA_EXT,
+    ATA_CMD_READ_FPDMA_QUEUED, ATA_CMD_WRITE_DMA_EXT, ATA_ERROR_ABRT, ATA_ERROR_IDNF,
+    ATA_IDENTIFY_DATA_SIZE, ATA_STATUS_ERR, SATA_FIS_REG_H2D_C, SATA_FIS_TYPE_REG_H2D,
+};
+use mod_test::libdriver::machine::TestStdMachine;
+use mod_test::libdriver::malloc::GuestAllocator;
+use mod_test::libdriver::pci::{PCIBarAddr, TestPciDev};
+use mod_test::libtest::{test_init, TestState};
+use mod_test::utils::{cleanup_img, create_img, TEST_IMAGE_SIZE};
+use util::byte_code::ByteCode;
+
+const AHCI_PCI_SLOT: u8 = 0x4;
+const AHCI_ABAR: u8 = 5;
+const DISK_PORT: u64 = 0;
+const CD_PORT: u64 = 1;
+const PAGE_SIZE: u64 = 4096;
+const TIMEOUT_MS: u64 = 1000;
+
+struct TestAhciDev {
+    pci_dev: TestPciDev,
+    bar_addr: PCIBarAddr,
+    state: Rc<RefCell<TestState>>,
+    allocator: Rc<RefCell<GuestAllocator>>,
+    /// Command list and received FIS of the port.
+    clb: u64,
+    fb: u64,
+}
+
+impl TestAhciDev {
+    fn new(machine: &TestStdMachine, state: Rc<RefCell<TestState>>) -> Self {
+        let mut pci_dev = TestPciDev::new(machine.pci_bus.clone());
+        assert!(pci_dev.find_pci_device(AHCI_PCI_SLOT << 3));
+        pci_dev.enable();
+        let bar_addr = pci_dev.io_map(AHCI_ABAR);
+        pci_dev.enable_msix(Some(bar_addr));
+        let allocator = machine.allocator.clone();
+        let clb = allocator.borrow_mut().alloc(PAGE_SIZE);
+        let fb = allocator.borrow_mut().alloc(PAGE_SIZE);
+
+        Self {
+            pci_dev,
+            bar_addr,
+            state,
+            allocator,
+            clb,
+            fb,
+        }
+    }
+
+    fn readl(&self, offset: u64) -> u32 {
+        self.pci_dev.io_readl(self.bar_addr, offset)
+    }
+
+    fn writel(&self, offset: u64, value: u32) {
+        self.pci_dev.io_writel(self.bar_addr, offset, value);
+    }
+
+    fn port_readl(&self, port: u64, offset: u64) -> u32 {
+        self.readl(AHCI_PORT_REG_BASE + port * AHCI_PORT_REG_SIZE + offset)
+    }
+
+    fn port_writel(&self, port: u64, offset: u64, value: u32) {
+        self.writel(
+            AHCI_PORT_REG_BASE + port * AHCI_PORT_REG_SIZE + offset,
+            value,
+        );
+    }
+
+    /// Configure the command list and the received FIS area, and start the port.
+    fn start_port(&self, port: u64) {
+        self.port_writel(port, AHCI_PORT_CLB, self.clb as u32);
+        self.port_writel(port, AHCI_PORT_FB, self.fb as u32);
+        self.port_writel(port, AHCI_PORT_IS, u32::MAX);
+        self.port_writel(port, AHCI_PORT_CMD, AHCI_PORT_CMD_FRE | AHCI_PORT_CMD_ST);
+    }
+
+    /// Issue the command to slot 0 of the port and wait for its completion.
+    fn submit(&self, port: u64, fis: [u8; AHCI_CMD_FIS_SIZE], buf: u64, len: u32) -> u32 {
+        let ctba = self.allocator.borrow_mut().alloc(PAGE_SIZE);
+        let state = self.state.borrow();
+        state.memwrite(ctba + AHCI_CMD_TBL_CFIS, &fis);
+        let prd = AhciPrd {
+            dba: buf,
+            rsvd: 0,
+            dbc: len - 1,
+        };
+        state.memwrite(ctba + AHCI_CMD_TBL_PRDT, prd.as_bytes());
+        let header = AhciCmdHeader {
+            flags: (AHCI_CMD_FIS_SIZE / 4) as u16,
+            prdtl: if len == 0 { 0 } else { 1 },
+            ctba,
+            ..Default::default()
+        };
+        state.memwrite(self.clb, header.as_bytes());
+        drop(state);
+        self.port_writel(port, AHCI_PORT_CI, 1);
+
+        let start = time::Instant::now();
+        while self.port_readl(port, AHCI_PORT_CI) & 1 != 0 {
+            assert!(start.elapsed() < time::Duration::from_millis(TIMEOUT_MS));
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        self.port_readl(port, AHCI_PORT_TFD)
+    }
+}
+
+fn build_fis(command: u8, lba: u64, count: u16, features: u16) -> [u8; AHCI_CMD_FIS_SIZE] {
+    let mut fis = [0_u8; AHCI_CMD_FIS_SIZE];
+    fis[0] = SATA_FIS_TYPE_REG_H2D;
+    fis[1] = SATA_FIS_REG_H2D_C;
+    fis[2] = command;
+    fis[3] = features as u8;
+    fis[4..7].copy_from_slice(&lba.to_le_bytes()[0..3]);
+    // LBA mode.
+    fis[7] = 1 << 6;
+    fis[8..11].copy_from_slice(&lba.to_le_bytes()[3..6]);
+    fis[11] = (features >> 8) as u8;
+    fis[12..14].copy_from_slice(&count.to_le_bytes());
+    fis
+}
+
+fn set_up(image_path: &str) -> (TestAhciDev, Rc<RefCell<TestState>>) {
+    let args = format!(
+        "-machine virt \
+         -drive id=drv0,if=none,file={},format=raw,direct=false,aio=off \
+         -drive id=drv1,if=none,media=cdrom \
+         -device ich9-ahci,id=ahci0,bus=pcie.0,addr={} \
+         -device ide-hd,id=sata0,bus=ahci0.{},drive=drv0,serial=sata-serial \
+         -device ide-cd,id=cd0,bus=ahci0.{},drive=drv1",
+        image_path, AHCI_PCI_SLOT, DISK_PORT, CD_PORT,
+    );
+    let extra_args: Vec<&str> = args.split(' ').filter(|s| !s.is_empty()).collect();
+    let test_state = Rc::new(RefCell::new(test_init(extra_args)));
+    let machine = TestStdMachine::new(test_state.clone());
+    let ahci = TestAhciDev::new(&machine, test_state.clone());
+    (ahci, test_state)
+}
+
+/// Identify the HBA and the attached devices.
+/// TestStep:
+///   1. Read CAP, GHC, PI and VS of the HBA.
+///   2. Read the signature and the link status of the ports.
+///   3. IDENTIFY DEVICE on the disk, check the serial number and the capacity.
+///   4. IDENTIFY DEVICE on the CD-ROM.
+/// Expect:
+///   1/2/3: success.
+///   4: the command is aborted.
+#[test]
+fn ahci_identify_test() {
+    let image_path = create_img(TEST_IMAGE_SIZE, 0);
+    let (ahci, test_state) = set_up(&image_path);
+
+    // CAP.NP is zero based, NCQ and 64-bit addressing are supported.
+    let cap = ahci.readl(AHCI_REG_CAP);
+    assert_eq!(cap & 0x1f, 5);
+    assert_eq!((cap >> 8) & 0x1f, 31);
+    assert_ne!(cap & (1 << 30), 0);
+    assert_ne!(cap & (1 << 31), 0);
+    assert_eq!(ahci.readl(AHCI_REG_GHC) & AHCI_GHC_AE, AHCI_GHC_AE);
+    assert_eq!(ahci.readl(AHCI_REG_PI), 0x3f);
+    assert_eq!(ahci.readl(AHCI_REG_VS), AHCI_VERSION);
+
+    ahci.start_port(DISK_PORT);
+    assert_eq!(
+        ahci.port_readl(DISK_PORT, AHCI_PORT_SSTS),
+        AHCI_PORT_SSTS_ONLINE
+    );
+    assert_eq!(ahci.port_readl(DISK_PORT, AHCI_PORT_SIG), AHCI_SIG_ATA);
+    ahci.start_port(CD_PORT);
+    assert_eq!(ahci.port_readl(CD_PORT, AHCI_PORT_SIG), AHCI_SIG_ATAPI);
+    // No device is attached to port 2.
+    assert_eq!(ahci.port_readl(2, AHCI_PORT_SSTS), 0);
+
+    let buf = ahci.allocator.borrow_mut().alloc(PAGE_SIZE);
+    let fis = build_fis(ATA_CMD_IDENTIFY_DEVICE, 0, 0, 0);
+    let tfd = ahci.submit(DISK_PORT, fis, buf, ATA_IDENTIFY_DATA_SIZE as u32);
+    assert_eq!(tfd & ATA_STATUS_ERR as u32, 0);
+    let data = test_state
+        .borrow()
+        .memread(buf, ATA_IDENTIFY_DATA_SIZE as u64);
+    // The ATA strings are byte swapped.
+    let mut serial = data[20..40].to_vec();
+    serial.chunks_exact_mut(2).for_each(|pair| pair.swap(0, 1));
+    assert_eq!(&serial, b"sata-serial         ");
+    let sectors = u64::from_le_bytes(data[200..208].try_into().unwrap());
+    assert_eq!(sectors, TEST_IMAGE_SIZE >> 9);
+    // The sum of the IDENTIFY data is zero.
+    let sum = data.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+    assert_eq!(sum, 0);
+
+    let tfd = ahci.submit(CD_PORT, fis, buf, ATA_IDENTIFY_DATA_SIZE as u32);
+    assert_eq!(tfd & ATA_STATUS_ERR as u32, ATA_STATUS_ERR as u32);
+    assert_eq!((tfd >> 8) as u8, ATA_ERROR_ABRT);
+
+    test_state.borrow_mut().stop();
+    cleanup_img(image_path);
+}
+
+/// Read and write the disk by DMA and NCQ commands.
+/// TestStep:
+///   1. Start the port of the disk.
+///   2. WRITE DMA EXT one page and READ DMA EXT it back.
+///   3. READ FPDMA QUEUED the page.
+///   4. Read beyond the end of the disk.
+/// Expect:
+///   1/2/3: success.
+///   4: the command fails with IDNF and TFES is set.
+#[test]
+fn ahci_rw_test() {
+    let image_path = create_img(TEST_IMAGE_SIZE, 0);
+    let (ahci, test_state) = set_up(&image_path);
+    ahci.start_port(DISK_PORT);
+
+    let count = (PAGE_SIZE >> 9) as u16;
+    let wbuf = ahci.allocator.borrow_mut().alloc(PAGE_SIZE);
+    let rbuf = ahci.allocator.borrow_mut().alloc(PAGE_SIZE);
+    test_state.borrow().memset(wbuf, PAGE_SIZE, &[0x5a, 0xa5]);
+    let fis = build_fis(ATA_CMD_WRITE_DMA_EXT, 8, count, 0);
+    let tfd = ahci.submit(DISK_PORT, fis, wbuf, PAGE_SIZE as u32);
+    assert_eq!(tfd & ATA_STATUS_ERR as u32, 0);
+    let fis = build_fis(ATA_CMD_READ_DMA_EXT, 8, count, 0);
+    let tfd = ahci.submit(DISK_PORT, fis, rbuf, PAGE_SIZE as u32);
+    assert_eq!(tfd & ATA_STATUS_ERR as u32, 0);
+    assert_eq!(
+        test_state.borrow().memread(rbuf, PAGE_SIZE),
+        test_state.borrow().memread(wbuf, PAGE_SIZE)
+    );
+
+    // The sector count of NCQ commands is in the Features register, the tag is 0.
+    test_state.borrow().memset(rbuf, PAGE_SIZE, &[0]);
+    ahci.port_writel(DISK_PORT, AHCI_PORT_SACT, 1);
+    let fis = build_fis(ATA_CMD_READ_FPDMA_QUEUED, 8, 0, count);
+    ahci.submit(DISK_PORT, fis, rbuf, PAGE_SIZE as u32);
+    assert_eq!(ahci.port_readl(DISK_PORT, AHCI_PORT_SACT), 0);
+    let is = ahci.port_readl(DISK_PORT, AHCI_PORT_IS);
+    assert_eq!(is & AHCI_PORT_IS_SDBS, AHCI_PORT_IS_SDBS);
+    let sdb = test_state.borrow().memread(ahci.fb + AHCI_RX_FIS_SDB, 8);
+    assert_eq!(u32::from_le_bytes(sdb[4..8].try_into().unwrap()), 1);
+    assert_eq!(
+        test_state.borrow().memread(rbuf, PAGE_SIZE),
+        test_state.borrow().memread(wbuf, PAGE_SIZE)
+    );
+
+    let last_lba = (TEST_IMAGE_SIZE >> 9) - 1;
+    let fis = build_fis(ATA_CMD_READ_DMA_EXT, last_lba, count, 0);
+    let tfd = ahci.submit(DISK_PORT, fis, rbuf, PAGE_SIZE as u32);
+    assert_eq!(tfd & ATA_STATUS_ERR as u32, ATA_STATUS_ERR as u32);
+    assert_eq!((tfd >> 8) as u8, ATA_ERROR_IDNF);
+    let is = ahci.port_readl(DISK_PORT, AHCI_PORT_IS);
+    assert_eq!(is & AHCI_PORT_IS_TFES, AHCI_PORT_IS_TFES);
+
+    test_state.borrow_mut().stop();
+    cleanup_img(image_path);
+}
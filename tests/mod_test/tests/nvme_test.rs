@@ -0,0 +1,303 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::{thread, time};
+
+use devices::nvme::{
+    NvmeCqe, NvmeSqe, NVME_ADM_CMD_CREATE_CQ, NVME_ADM_CMD_CREATE_SQ, NVME_ADM_CMD_IDENTIFY,
+    NVME_CC_EN, NVME_CMD_READ, NVME_CMD_WRITE, NVME_CMD_WRITE_ZEROES, NVME_CQE_SIZE, NVME_CSTS_RDY,
+    NVME_DNR, NVME_INVALID_OPCODE, NVME_LBA_RANGE, NVME_PAGE_SIZE, NVME_REG_ACQ, NVME_REG_AQA,
+    NVME_REG_ASQ, NVME_REG_CAP, NVME_REG_CC, NVME_REG_CSTS, NVME_REG_DBS, NVME_REG_VS,
+    NVME_SQE_SIZE, NVME_SUCCESS, NVME_VERSION,
+};
+use mod_test::libdriver::machine::TestStdMachine;
+use mod_test::libdriver::malloc::GuestAllocator;
+use mod_test::libdriver::pci::{PCIBarAddr, TestPciDev};
+use mod_test::libtest::{test_init, TestState};
+use mod_test::utils::{cleanup_img, create_img, TEST_IMAGE_SIZE};
+use util::byte_code::ByteCode;
+
+const NVME_PCI_SLOT: u8 = 0x4;
+const QUEUE_SIZE: u32 = 16;
+const IO_QUEUE_ID: u16 = 1;
+const TIMEOUT_MS: u64 = 1000;
+
+struct NvmeQueue {
+    sq_addr: u64,
+    cq_addr: u64,
+    sq_tail: u32,
+    cq_head: u32,
+    phase: u16,
+    cid: u16,
+}
+
+impl NvmeQueue {
+    fn new(allocator: &Rc<RefCell<GuestAllocator>>) -> Self {
+        let mut allocator = allocator.borrow_mut();
+        Self {
+            sq_addr: allocator.alloc(NVME_PAGE_SIZE),
+            cq_addr: allocator.alloc(NVME_PAGE_SIZE),
+            sq_tail: 0,
+            cq_head: 0,
+            phase: 1,
+            cid: 0,
+        }
+    }
+}
+
+struct TestNvmeDev {
+    pci_dev: TestPciDev,
+    bar_addr: PCIBarAddr,
+    state: Rc<RefCell<TestState>>,
+    allocator: Rc<RefCell<GuestAllocator>>,
+    queues: Vec<NvmeQueue>,
+}
+
+impl TestNvmeDev {
+    fn new(machine: &TestStdMachine, state: Rc<RefCell<TestState>>) -> Self {
+        let mut pci_dev = TestPciDev::new(machine.pci_bus.clone());
+        assert!(pci_dev.find_pci_device(NVME_PCI_SLOT << 3));
+        pci_dev.enable();
+        let bar_addr = pci_dev.io_map(0);
+        pci_dev.enable_msix(Some(bar_addr));
+
+        Self {
+            pci_dev,
+            bar_addr,
+            state,
+            allocator: machine.allocator.clone(),
+            queues: Vec::new(),
+        }
+    }
+
+    fn readl(&self, offset: u64) -> u32 {
+        self.pci_dev.io_readl(self.bar_addr, offset)
+    }
+
+    fn writel(&self, offset: u64, value: u32) {
+        self.pci_dev.io_writel(self.bar_addr, offset, value);
+    }
+
+    fn writeq(&self, offset: u64, value: u64) {
+        self.pci_dev.io_writeq(self.bar_addr, offset, value);
+    }
+
+    /// Configure the admin queues and enable the controller.
+    fn enable(&mut self) {
+        let admin = NvmeQueue::new(&self.allocator);
+        self.writel(NVME_REG_AQA, (QUEUE_SIZE - 1) << 16 | (QUEUE_SIZE - 1));
+        self.writeq(NVME_REG_ASQ, admin.sq_addr);
+        self.writeq(NVME_REG_ACQ, admin.cq_addr);
+        self.queues.push(admin);
+        self.writel(NVME_REG_CC, NVME_CC_EN);
+        assert_eq!(self.readl(NVME_REG_CSTS) & NVME_CSTS_RDY, NVME_CSTS_RDY);
+    }
+
+    /// Submit the command to the queue and wait for its completion.
+    fn submit(&mut self, qid: u16, mut sqe: NvmeSqe) -> NvmeCqe {
+        let queue = &mut self.queues[qid as usize];
+        sqe.cid = queue.cid;
+        queue.cid = queue.cid.wrapping_add(1);
+        self.state.borrow().memwrite(
+            queue.sq_addr + queue.sq_tail as u64 * NVME_SQE_SIZE,
+            sqe.as_bytes(),
+        );
+        queue.sq_tail = (queue.sq_tail + 1) % QUEUE_SIZE;
+        let sq_tail = queue.sq_tail;
+        self.writel(NVME_REG_DBS + 8 * qid as u64, sq_tail);
+
+        let queue = &mut self.queues[qid as usize];
+        let cqe_addr = queue.cq_addr + queue.cq_head as u64 * NVME_CQE_SIZE;
+        let start = time::Instant::now();
+        let cqe = loop {
+            let data = self.state.borrow().memread(cqe_addr, NVME_CQE_SIZE);
+            let cqe = *NvmeCqe::from_bytes(&data).unwrap();
+            if cqe.status & 0x1 == queue.phase {
+                break cqe;
+            }
+            assert!(start.elapsed() < time::Duration::from_millis(TIMEOUT_MS));
+            thread::sleep(time::Duration::from_millis(1));
+        };
+        assert_eq!(cqe.cid, sqe.cid);
+        queue.cq_head = (queue.cq_head + 1) % QUEUE_SIZE;
+        if queue.cq_head == 0 {
+            queue.phase ^= 1;
+        }
+        let cq_head = queue.cq_head;
+        self.writel(NVME_REG_DBS + 8 * qid as u64 + 4, cq_head);
+        cqe
+    }
+
+    fn create_io_queues(&mut self) {
+        let queue = NvmeQueue::new(&self.allocator);
+        let (sq_addr, cq_addr) = (queue.sq_addr, queue.cq_addr);
+        self.queues.push(queue);
+
+        let sqe = NvmeSqe {
+            opcode: NVME_ADM_CMD_CREATE_CQ,
+            prp1: cq_addr,
+            cdw10: (QUEUE_SIZE - 1) << 16 | IO_QUEUE_ID as u32,
+            // Physically contiguous, interrupt enabled, vector 1.
+            cdw11: 1 << 16 | 0x3,
+            ..Default::default()
+        };
+        assert_eq!(self.submit(0, sqe).status >> 1, NVME_SUCCESS);
+
+        let sqe = NvmeSqe {
+            opcode: NVME_ADM_CMD_CREATE_SQ,
+            prp1: sq_addr,
+            cdw10: (QUEUE_SIZE - 1) << 16 | IO_QUEUE_ID as u32,
+            cdw11: (IO_QUEUE_ID as u32) << 16 | 0x1,
+            ..Default::default()
+        };
+        assert_eq!(self.submit(0, sqe).status >> 1, NVME_SUCCESS);
+    }
+
+    fn rw(&mut self, opcode: u8, slba: u64, buf: u64, nlb: u32) -> u16 {
+        let sqe = NvmeSqe {
+            opcode,
+            nsid: 1,
+            prp1: buf,
+            cdw10: slba as u32,
+            cdw11: (slba >> 32) as u32,
+            cdw12: nlb - 1,
+            ..Default::default()
+        };
+        self.submit(IO_QUEUE_ID, sqe).status >> 1
+    }
+}
+
+fn set_up(image_path: &str) -> (TestNvmeDev, Rc<RefCell<TestState>>) {
+    let args = format!(
+        "-machine virt \
+         -drive id=drv0,if=none,file={},format=raw,direct=false,aio=off \
+         -device nvme,id=nvme0,bus=pcie.0,addr={},drive=drv0,serial=nvme-serial",
+        image_path, NVME_PCI_SLOT,
+    );
+    let extra_args: Vec<&str> = args.split(' ').filter(|s| !s.is_empty()).collect();
+    let test_state = Rc::new(RefCell::new(test_init(extra_args)));
+    let machine = TestStdMachine::new(test_state.clone());
+    let nvme = TestNvmeDev::new(&machine, test_state.clone());
+    (nvme, test_state)
+}
+
+/// Identify the controller and the namespace.
+/// TestStep:
+///   1. Read CAP and VS, enable the controller.
+///   2. Identify Controller, check the vendor id and serial number.
+///   3. Identify Namespace 1, check the size of the namespace.
+/// Expect:
+///   1/2/3: success.
+#[test]
+fn nvme_identify_test() {
+    let image_path = create_img(TEST_IMAGE_SIZE, 0);
+    let (mut nvme, test_state) = set_up(&image_path);
+
+    // CAP.CQR is set and CAP.MQES is zero based.
+    let cap = nvme.readl(NVME_REG_CAP);
+    assert_eq!(cap & 0xffff, 2047);
+    assert_eq!((cap >> 16) & 0x1, 1);
+    assert_eq!(nvme.readl(NVME_REG_VS), NVME_VERSION);
+    nvme.enable();
+
+    let buf = nvme.allocator.borrow_mut().alloc(NVME_PAGE_SIZE);
+    let sqe = NvmeSqe {
+        opcode: NVME_ADM_CMD_IDENTIFY,
+        prp1: buf,
+        cdw10: 1,
+        ..Default::default()
+    };
+    assert_eq!(nvme.submit(0, sqe).status >> 1, NVME_SUCCESS);
+    let data = test_state.borrow().memread(buf, 64);
+    assert_eq!(u16::from_le_bytes([data[0], data[1]]), 0x1b36);
+    assert_eq!(&data[4..24], b"nvme-serial         ");
+
+    let sqe = NvmeSqe {
+        opcode: NVME_ADM_CMD_IDENTIFY,
+        nsid: 1,
+        prp1: buf,
+        cdw10: 0,
+        ..Default::default()
+    };
+    assert_eq!(nvme.submit(0, sqe).status >> 1, NVME_SUCCESS);
+    let data = test_state.borrow().memread(buf, 8);
+    let nsze = u64::from_le_bytes(data.try_into().unwrap());
+    assert_eq!(nsze, TEST_IMAGE_SIZE >> 9);
+
+    // Unsupported admin command.
+    let sqe = NvmeSqe {
+        opcode: 0x7f,
+        ..Default::default()
+    };
+    assert_eq!(
+        nvme.submit(0, sqe).status >> 1,
+        NVME_INVALID_OPCODE | NVME_DNR
+    );
+
+    test_state.borrow_mut().stop();
+    cleanup_img(image_path);
+}
+
+/// Read and write the namespace through the I/O queue.
+/// TestStep:
+///   1. Enable the controller and create the I/O queue pair.
+///   2. Write one page and read it back.
+///   3. Write zeroes to the blocks and read them back.
+///   4. Read beyond the end of the namespace.
+/// Expect:
+///   1/2/3: success.
+///   4: LBA Out of Range.
+#[test]
+fn nvme_rw_test() {
+    let image_path = create_img(TEST_IMAGE_SIZE, 0);
+    let (mut nvme, test_state) = set_up(&image_path);
+    nvme.enable();
+    nvme.create_io_queues();
+
+    let nlb = (NVME_PAGE_SIZE >> 9) as u32;
+    let wbuf = nvme.allocator.borrow_mut().alloc(NVME_PAGE_SIZE);
+    let rbuf = nvme.allocator.borrow_mut().alloc(NVME_PAGE_SIZE);
+    test_state
+        .borrow()
+        .memset(wbuf, NVME_PAGE_SIZE, &[0x5a, 0xa5]);
+    assert_eq!(nvme.rw(NVME_CMD_WRITE, 8, wbuf, nlb), NVME_SUCCESS);
+    assert_eq!(nvme.rw(NVME_CMD_READ, 8, rbuf, nlb), NVME_SUCCESS);
+    assert_eq!(
+        test_state.borrow().memread(rbuf, NVME_PAGE_SIZE),
+        test_state.borrow().memread(wbuf, NVME_PAGE_SIZE)
+    );
+
+    let sqe = NvmeSqe {
+        opcode: NVME_CMD_WRITE_ZEROES,
+        nsid: 1,
+        cdw10: 8,
+        cdw12: nlb - 1,
+        ..Default::default()
+    };
+    assert_eq!(nvme.submit(IO_QUEUE_ID, sqe).status >> 1, NVME_SUCCESS);
+    assert_eq!(nvme.rw(NVME_CMD_READ, 8, rbuf, nlb), NVME_SUCCESS);
+    assert_eq!(
+        test_state.borrow().memread(rbuf, NVME_PAGE_SIZE),
+        vec![0_u8; NVME_PAGE_SIZE as usize]
+    );
+
+    let last_lba = (TEST_IMAGE_SIZE >> 9) - 1;
+    assert_eq!(
+        nvme.rw(NVME_CMD_READ, last_lba, rbuf, nlb),
+        NVME_LBA_RANGE | NVME_DNR
+    );
+
+    test_state.borrow_mut().stop();
+    cleanup_img(image_path);
+}
@@ -17,6 +17,7 @@ use std::slice::from_raw_parts;
 use std::{thread, time};
 
 use rand::Rng;
+use serde_json::{json, Value};
 use util::aio::{aio_probe, AioEngine};
 use util::byte_code::ByteCode;
 use util::offset_of;
@@ -53,16 +54,28 @@ const TEST_UNIT_READY: u8 = 0x00;
 const INQUIRY: u8 = 0x12;
 const REPORT_LUNS: u8 = 0xa0;
 const READ_CAPACITY_10: u8 = 0x25;
+const UNMAP: u8 = 0x42;
+const WRITE_SAME_16: u8 = 0x93;
+const SERVICE_ACTION_IN_16: u8 = 0x9e;
+const SUBCODE_READ_CAPACITY_16: u8 = 0x10;
 const MODE_SENSE: u8 = 0x1a;
 const REQUEST_SENSE: u8 = 0x03;
 const GET_CONFIGURATION: u8 = 0x46;
 const READ_DISC_INFORMATION: u8 = 0x51;
 const GET_EVENT_STATUS_NOTIFICATION: u8 = 0x4a;
 const READ_TOC: u8 = 0x43;
+const START_STOP: u8 = 0x1b;
+const ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
 
 const VIRTIO_SCSI_S_OK: u8 = 0;
 const VIRTIO_SCSI_S_BAD_TARGET: u8 = 3;
 
+/// Event of the event queue: type, lun and reason.
+const VIRTIO_SCSI_EVENT_LEN: u64 = 16;
+const VIRTIO_SCSI_T_TRANSPORT_RESET: u32 = 1;
+const VIRTIO_SCSI_EVT_RESET_RESCAN: u32 = 1;
+const VIRTIO_SCSI_EVT_RESET_REMOVED: u32 = 2;
+
 /// Mode page codes for mode sense/set.
 const MODE_PAGE_CACHING: u8 = 0x08;
 const MODE_PAGE_CAPABILITIES: u8 = 0x2a;
@@ -148,6 +161,7 @@ impl VirtioScsiTest {
             direct: false,
             aio: TestAioType::AioOff,
             serial: Some(DEFAULT_SCSI_SERIAL.to_string()),
+            discard: false,
         }];
 
         let (cntlr, state, alloc) = scsi_test_init(cntlrcfg, scsi_devices.clone());
@@ -425,6 +439,42 @@ const SCSI_SENSE_IO_ERROR: ScsiSense = ScsiSense {
     ascq: 0x06,
 };
 
+const SCSI_SENSE_UNIT_ATTENTION_NO_MEDIUM: ScsiSense = ScsiSense {
+    key: 0x06,
+    asc: 0x3a,
+    ascq: 0x00,
+};
+
+const SCSI_SENSE_NO_MEDIUM: ScsiSense = ScsiSense {
+    key: 0x02,
+    asc: 0x3a,
+    ascq: 0x00,
+};
+
+const SCSI_SENSE_MEDIUM_CHANGED: ScsiSense = ScsiSense {
+    key: 0x06,
+    asc: 0x28,
+    ascq: 0x00,
+};
+
+const SCSI_SENSE_ILLEGAL_REQ_REMOVAL_PREVENTED: ScsiSense = ScsiSense {
+    key: 0x05,
+    asc: 0x53,
+    ascq: 0x02,
+};
+
+const SCSI_SENSE_LBA_OUT_OF_RANGE: ScsiSense = ScsiSense {
+    key: 0x05,
+    asc: 0x21,
+    ascq: 0x00,
+};
+
+const SCSI_SENSE_REPORTED_LUNS_CHANGED: ScsiSense = ScsiSense {
+    key: 0x06,
+    asc: 0x3f,
+    ascq: 0x0e,
+};
+
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, Default)]
 struct TestVirtioScsiCmdReq {
@@ -540,6 +590,7 @@ struct ScsiDeviceConfig {
     direct: bool,
     aio: TestAioType,
     serial: Option<String>,
+    discard: bool,
 }
 
 impl ScsiDeviceConfig {
@@ -556,9 +607,17 @@ impl ScsiDeviceConfig {
             self.lun, serial_args,
         );
 
+        let discard_args = if self.discard { ",discard=unmap" } else { "" };
+
         let drive_args = format!(
-            "-drive file={},id=drive-scsi0-0-{}-{},direct={},readonly={},aio={}",
-            self.image_path, self.target, self.lun, self.direct, self.read_only, self.aio,
+            "-drive file={},id=drive-scsi0-0-{}-{},direct={},readonly={},aio={}{}",
+            self.image_path,
+            self.target,
+            self.lun,
+            self.direct,
+            self.read_only,
+            self.aio,
+            discard_args,
         );
 
         format!("{} {} ", device_args, drive_args)
@@ -1005,7 +1064,8 @@ fn scsi_hd_basic_test() {
     // Byte5: LBPU(bit 7) / LBPWS / LBPWS10 / LBPRZ / ANC_SUP / DP.
     // Byte6: Threshold percentage / Provisioning Type.
     // Byte7: Threshold percentage.
-    let expect_result_vec = vec![0, 0xb2, 0, 0x4, 0, 0x60, 0x1, 0];
+    // Logical block provisioning is not supported without discard.
+    let expect_result_vec = vec![0, 0xb2, 0, 0x4, 0, 0, 0, 0];
     let cdb_test_args = CdbTest {
         cdb: inquiry_cdb,
         target,
@@ -1386,6 +1446,149 @@ fn scsi_cd_basic_test() {
     vst.testcase_tear_down();
 }
 
+/// Send the qmp command and skip the events sent before the response.
+fn scsi_qmp_command(state: &Rc<RefCell<TestState>>, cmd: &str) -> Value {
+    let mut ret = state.borrow().qmp(cmd);
+    while ret.get("event").is_some() {
+        ret = state.borrow().qmp_read();
+    }
+    ret
+}
+
+/// Virtio Scsi CD-ROM removable medium test.
+/// TestStep:
+///   0. Init process.
+///   1. Lock the medium and try to eject it by the guest and qmp.
+///   2. Unlock the medium and eject it by qmp.
+///   3. Insert a new medium by qmp.
+///   4. Basic IO test.
+///   5. Test ends. Destroy device.
+/// Expect:
+///   0/2/3/4/5: success.
+///   1: failure, the guest is asked to eject the medium.
+#[test]
+fn scsi_cd_removable_medium_test() {
+    let target = 0;
+    let lun = 3;
+    let mut vst = VirtioScsiTest::general_testcase_run(ScsiDeviceType::ScsiCd, target, lun);
+    let id = format!("scsi0-0-{}-{}", target, lun);
+
+    let scsi_cmd = |vst: &mut VirtioScsiTest, cdb, status, sense: Option<ScsiSense>| {
+        let cdb_test_args = CdbTest {
+            cdb,
+            target,
+            lun,
+            data_out: None,
+            data_in_length: 0,
+            expect_response: VIRTIO_SCSI_S_OK,
+            expect_status: status,
+            expect_result_data: None,
+            expect_sense: sense.map(get_sense_bytes),
+        };
+        vst.scsi_cdb_test(cdb_test_args);
+    };
+    let gesn_test = |vst: &mut VirtioScsiTest, event_code: u8, media_status: u8| {
+        let mut gesn_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+        gesn_cdb[0] = GET_EVENT_STATUS_NOTIFICATION;
+        gesn_cdb[1] = 1;
+        gesn_cdb[4] = 0x10;
+        gesn_cdb[8] = GET_EVENT_STATUS_NOTIFICATION_DATA_LEN;
+        let cdb_test_args = CdbTest {
+            cdb: gesn_cdb,
+            target,
+            lun,
+            data_out: None,
+            data_in_length: GET_EVENT_STATUS_NOTIFICATION_DATA_LEN as u32,
+            expect_response: VIRTIO_SCSI_S_OK,
+            expect_status: GOOD,
+            expect_result_data: Some(vec![0, 6, 4, 0x10, event_code, media_status, 0, 0]),
+            expect_sense: None,
+        };
+        vst.scsi_cdb_test(cdb_test_args);
+    };
+    let mut tur_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    tur_cdb[0] = TEST_UNIT_READY;
+
+    // Test 1: lock the medium by ALLOW_MEDIUM_REMOVAL(prevent = 1).
+    // Test 1 Result: the medium can't be ejected by START_STOP(LOEJ = 1, START = 0) or qmp
+    // without force, and GET_EVENT_STATUS_NOTIFICATION reports the eject request.
+    let mut lock_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    lock_cdb[0] = ALLOW_MEDIUM_REMOVAL;
+    lock_cdb[4] = 1;
+    scsi_cmd(&mut vst, lock_cdb, GOOD, None);
+    let mut eject_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    eject_cdb[0] = START_STOP;
+    eject_cdb[4] = 2;
+    scsi_cmd(
+        &mut vst,
+        eject_cdb,
+        CHECK_CONDITION,
+        Some(SCSI_SENSE_ILLEGAL_REQ_REMOVAL_PREVENTED),
+    );
+    let eject_cmd = format!(
+        "{{\"execute\": \"eject\", \"arguments\": {{\"id\": \"{}\"}}}}",
+        id
+    );
+    let ret = scsi_qmp_command(&vst.state, &eject_cmd);
+    assert!(ret.get("error").is_some());
+    // Event code: EJECTREQUEST(1). Media status: media present.
+    gesn_test(&mut vst, 1, 2);
+
+    // Test 2: unlock the medium and eject it by qmp.
+    // Test 2 Result: the guest is notified by NO MEDIUM unit attention, and the following
+    // commands which access the medium fail with NO MEDIUM.
+    lock_cdb[4] = 0;
+    scsi_cmd(&mut vst, lock_cdb, GOOD, None);
+    let ret = scsi_qmp_command(&vst.state, &eject_cmd);
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    scsi_cmd(
+        &mut vst,
+        tur_cdb,
+        CHECK_CONDITION,
+        Some(SCSI_SENSE_UNIT_ATTENTION_NO_MEDIUM),
+    );
+    scsi_cmd(
+        &mut vst,
+        tur_cdb,
+        CHECK_CONDITION,
+        Some(SCSI_SENSE_NO_MEDIUM),
+    );
+    // Event code: NOCHG(0). Media status: tray open.
+    gesn_test(&mut vst, 0, 1);
+
+    // Test 3: insert a new medium by qmp.
+    // Test 3 Result: the guest is notified by NO MEDIUM and MEDIUM CHANGED unit attention,
+    // and GET_EVENT_STATUS_NOTIFICATION reports the new medium.
+    let image_path = Rc::new(create_img(TEST_IMAGE_SIZE, 1));
+    let change_cmd = format!(
+        "{{\"execute\": \"blockdev-change-medium\", \"arguments\": {{\"id\": \"{}\", \"filename\": \"{}\"}}}}",
+        id, image_path
+    );
+    let ret = scsi_qmp_command(&vst.state, &change_cmd);
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    // Event code: NEWMEDIA(2). Media status: media present.
+    gesn_test(&mut vst, 2, 2);
+    scsi_cmd(
+        &mut vst,
+        tur_cdb,
+        CHECK_CONDITION,
+        Some(SCSI_SENSE_UNIT_ATTENTION_NO_MEDIUM),
+    );
+    scsi_cmd(
+        &mut vst,
+        tur_cdb,
+        CHECK_CONDITION,
+        Some(SCSI_SENSE_MEDIUM_CHANGED),
+    );
+    scsi_cmd(&mut vst, tur_cdb, GOOD, None);
+
+    // Test 4: basic io test.
+    vst.scsi_try_io(target, lun, ScsiDeviceType::ScsiCd);
+
+    vst.testcase_tear_down();
+    cleanup_img(image_path.to_string());
+}
+
 /// Virtio Scsi target cdb test. Test some commands no matter it's right or wrong.
 /// Target cdb means that the target has at least one lun but the lun id of cdb will not
 /// be found in target's all luns' id.
@@ -1875,6 +2078,7 @@ fn aio_model_test() {
             direct: false,
             aio: TestAioType::AioIOUring,
             serial: None,
+            discard: false,
         });
 
         // Scsi Disk 2. AIO io_uring. Direct true.
@@ -1890,6 +2094,7 @@ fn aio_model_test() {
             direct: true,
             aio: TestAioType::AioIOUring,
             serial: None,
+            discard: false,
         });
     }
 
@@ -1909,6 +2114,7 @@ fn aio_model_test() {
         direct: false,
         aio: TestAioType::AioOff,
         serial: None,
+        discard: false,
     });
     // Scsi Disk 5. AIO native. Direct false. This is not allowed.
     // Stratovirt will report "native aio type should be used with direct on"
@@ -1927,6 +2133,7 @@ fn aio_model_test() {
             direct: true,
             aio: TestAioType::AioNative,
             serial: None,
+            discard: false,
         });
     }
 
@@ -2420,3 +2627,315 @@ fn wrong_io_test() {
 
     vst.testcase_tear_down();
 }
+
+/// Virtio Scsi hard disk discard test.
+/// TestStep:
+///   1. Init process with discard=unmap.
+///   2. Check the logical block provisioning in INQUIRY and READ CAPACITY(16).
+///   3. Write non-zero data to LBA 0 and 1.
+///   4. Unmap LBA 0 by UNMAP, and zero LBA 1 by WRITE SAME(16) with the UNMAP bit.
+///   5. Read LBA 0 and 1.
+///   6. Unmap the blocks out of the disk.
+///   7. Destroy device.
+/// Expect:
+///   1/2/3/4/5/7: success.
+///   5: The data is zeroes.
+///   6: Return LBA OUT OF RANGE.
+#[test]
+fn scsi_hd_discard_test() {
+    let target = 0x1;
+    let lun = 0x2;
+    let cntlrcfg = CntlrConfig {
+        id: 0,
+        use_iothread: false,
+    };
+    let image_path = Rc::new(create_img(TEST_IMAGE_SIZE, 0));
+    let device_vec = vec![ScsiDeviceConfig {
+        cntlr_id: 0,
+        device_type: ScsiDeviceType::ScsiHd,
+        image_path: image_path.clone(),
+        target,
+        lun,
+        read_only: false,
+        direct: false,
+        aio: TestAioType::AioOff,
+        serial: None,
+        discard: true,
+    }];
+    let (cntlr, state, alloc) = scsi_test_init(cntlrcfg, device_vec.clone());
+    let features = virtio_scsi_default_feature(cntlr.clone());
+    let queues = cntlr
+        .borrow_mut()
+        .init_device(state.clone(), alloc.clone(), features, 3);
+    let mut vst = VirtioScsiTest {
+        cntlr,
+        scsi_devices: device_vec,
+        state,
+        alloc,
+        queues,
+    };
+
+    // Test 2.1: INQUIRY Logical Block Provisioning VPD page.
+    // Byte5: LBPU | LBPWS | LBPWS10 | LBPRZ. Byte6: Provisioning Type: thin provisioned.
+    let mut inquiry_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    inquiry_cdb[0] = INQUIRY;
+    inquiry_cdb[1] = 0x1;
+    inquiry_cdb[2] = 0xb2;
+    inquiry_cdb[4] = INQUIRY_LOGICAL_BLOCK_PROVISIONING_DATA_LEN;
+    let cdb_test_args = CdbTest {
+        cdb: inquiry_cdb,
+        target,
+        lun,
+        data_out: None,
+        data_in_length: INQUIRY_LOGICAL_BLOCK_PROVISIONING_DATA_LEN as u32,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_status: GOOD,
+        expect_result_data: Some(vec![0, 0xb2, 0, 0x4, 0, 0xe4, 0x1, 0]),
+        expect_sense: None,
+    };
+    vst.scsi_cdb_test(cdb_test_args);
+
+    // Test 2.2: READ CAPACITY(16). Byte14: LBPME | LBPRZ.
+    let mut read_capacity_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    read_capacity_cdb[0] = SERVICE_ACTION_IN_16;
+    read_capacity_cdb[1] = SUBCODE_READ_CAPACITY_16;
+    read_capacity_cdb[13] = 32;
+    let cdb_test_args = CdbTest {
+        cdb: read_capacity_cdb,
+        target,
+        lun,
+        data_out: None,
+        data_in_length: 32,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_status: GOOD,
+        expect_result_data: None,
+        expect_sense: None,
+    };
+    let data_in = vst.scsi_cdb_test(cdb_test_args).unwrap();
+    assert_eq!(data_in[14], 0xc0);
+
+    // Test 3: Write 2 sectors of non-zero data to LBA 0.
+    let mut write_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    write_cdb[0] = WRITE_10;
+    write_cdb[8] = 0x2;
+    let write_data = String::from_utf8(vec![0x8; 1024]).unwrap();
+    let cdb_test_args = CdbTest {
+        cdb: write_cdb,
+        target,
+        lun,
+        data_out: Some(write_data),
+        data_in_length: 0,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_status: GOOD,
+        expect_result_data: None,
+        expect_sense: None,
+    };
+    vst.scsi_cdb_test(cdb_test_args);
+
+    // Test 4.1: UNMAP LBA 0, 1 block.
+    // Parameter list: 8 bytes header and one 16 bytes block descriptor.
+    let mut unmap_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    unmap_cdb[0] = UNMAP;
+    unmap_cdb[8] = 24;
+    let mut param = vec![0_u8; 24];
+    param[1] = 22; // Unmap data length.
+    param[3] = 16; // Unmap block descriptor data length.
+    param[19] = 1; // Number of logical blocks.
+    let cdb_test_args = CdbTest {
+        cdb: unmap_cdb,
+        target,
+        lun,
+        data_out: Some(String::from_utf8(param).unwrap()),
+        data_in_length: 0,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_status: GOOD,
+        expect_result_data: None,
+        expect_sense: None,
+    };
+    vst.scsi_cdb_test(cdb_test_args);
+
+    // Test 4.2: WRITE SAME(16) with UNMAP bit, LBA 1, 1 block of zeroes.
+    let mut write_same_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    write_same_cdb[0] = WRITE_SAME_16;
+    write_same_cdb[1] = 0x08;
+    write_same_cdb[9] = 1;
+    write_same_cdb[13] = 1;
+    let cdb_test_args = CdbTest {
+        cdb: write_same_cdb,
+        target,
+        lun,
+        data_out: Some(String::from_utf8(vec![0; 512]).unwrap()),
+        data_in_length: 0,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_status: GOOD,
+        expect_result_data: None,
+        expect_sense: None,
+    };
+    vst.scsi_cdb_test(cdb_test_args);
+
+    // Test 5: Read LBA 0 and 1, they are zeroes.
+    let mut read_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    read_cdb[0] = READ_10;
+    read_cdb[8] = 0x2;
+    let cdb_test_args = CdbTest {
+        cdb: read_cdb,
+        target,
+        lun,
+        data_out: None,
+        data_in_length: 1024,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_status: GOOD,
+        expect_result_data: Some(vec![0; 1024]),
+        expect_sense: None,
+    };
+    vst.scsi_cdb_test(cdb_test_args);
+
+    // Test 6: UNMAP the block after the last block.
+    let nb_blocks = TEST_IMAGE_SIZE / 512;
+    let mut param = vec![0_u8; 24];
+    param[1] = 22;
+    param[3] = 16;
+    param[8..16].copy_from_slice(&nb_blocks.to_be_bytes());
+    param[19] = 1;
+    let cdb_test_args = CdbTest {
+        cdb: unmap_cdb,
+        target,
+        lun,
+        data_out: Some(String::from_utf8(param).unwrap()),
+        data_in_length: 0,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_status: CHECK_CONDITION,
+        expect_result_data: None,
+        expect_sense: Some(get_sense_bytes(SCSI_SENSE_LBA_OUT_OF_RANGE)),
+    };
+    vst.scsi_cdb_test(cdb_test_args);
+
+    vst.testcase_tear_down();
+}
+
+/// Virtio Scsi device hotplug test.
+/// TestStep:
+///   0. Init process.
+///   1. Hotplug a scsi-hd to lun 1 by qmp.
+///   2. Basic IO test of the hotplugged device.
+///   3. Hot-unplug the scsi-hd by qmp.
+///   4. Test ends. Destroy device.
+/// Expect:
+///   0/1/2/3/4: success.
+///   1/3: The guest gets the transport reset event and the REPORTED LUNS DATA HAS CHANGED
+///        unit attention from lun 0.
+#[test]
+fn scsi_hotplug_test() {
+    let target = 0;
+    let lun = 0;
+    let hotplug_lun = 1;
+    let mut vst = VirtioScsiTest::general_testcase_run(ScsiDeviceType::ScsiHd, target, lun);
+
+    // Provide a buffer in the event queue for the next event.
+    let add_event_buf = |vst: &mut VirtioScsiTest| {
+        let event_queue = vst.queues[1].clone();
+        let event_addr = vst.alloc.borrow_mut().alloc(VIRTIO_SCSI_EVENT_LEN);
+        let free_head = event_queue.borrow_mut().add(
+            vst.state.clone(),
+            event_addr,
+            VIRTIO_SCSI_EVENT_LEN as u32,
+            true,
+        );
+        vst.cntlr
+            .borrow()
+            .kick_virtqueue(vst.state.clone(), event_queue.clone());
+        (event_addr, free_head)
+    };
+    let wait_event = |vst: &mut VirtioScsiTest, event: (u64, u32), reason: u32| {
+        let mut len = Some(0);
+        vst.cntlr.borrow().poll_used_elem(
+            vst.state.clone(),
+            vst.queues[1].clone(),
+            event.1,
+            TIMEOUT_US,
+            &mut len,
+            true,
+        );
+        assert_eq!(len, Some(VIRTIO_SCSI_EVENT_LEN as u32));
+        let buf = vst.state.borrow().memread(event.0, VIRTIO_SCSI_EVENT_LEN);
+        assert_eq!(
+            u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            VIRTIO_SCSI_T_TRANSPORT_RESET
+        );
+        assert_eq!(buf[4..12], [1, target, 0, hotplug_lun as u8, 0, 0, 0, 0]);
+        assert_eq!(u32::from_le_bytes(buf[12..16].try_into().unwrap()), reason);
+    };
+    let tur_test = |vst: &mut VirtioScsiTest, status, sense: Option<ScsiSense>| {
+        let mut tur_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+        tur_cdb[0] = TEST_UNIT_READY;
+        let cdb_test_args = CdbTest {
+            cdb: tur_cdb,
+            target,
+            lun,
+            data_out: None,
+            data_in_length: 0,
+            expect_response: VIRTIO_SCSI_S_OK,
+            expect_status: status,
+            expect_result_data: None,
+            expect_sense: sense.map(get_sense_bytes),
+        };
+        vst.scsi_cdb_test(cdb_test_args);
+    };
+
+    // Test 1: hotplug a scsi-hd to lun 1.
+    let image_path = Rc::new(create_img(TEST_IMAGE_SIZE, 1));
+    let event = add_event_buf(&mut vst);
+    let blockdev_cmd = format!(
+        "{{\"execute\": \"blockdev-add\", \"arguments\": {{\"node-name\": \"drive-hotplug\", \
+        \"file\": {{\"driver\": \"file\", \"filename\": \"{}\"}}, \"cache\": {{\"direct\": false}}}}}}",
+        image_path
+    );
+    let ret = scsi_qmp_command(&vst.state, &blockdev_cmd);
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    let device_add_cmd = format!(
+        "{{\"execute\": \"device_add\", \"arguments\": {{\"id\": \"scsi-hotplug\", \
+        \"driver\": \"scsi-hd\", \"bus\": \"scsi0.0\", \"scsi-id\": {}, \"lun\": {}, \
+        \"drive\": \"drive-hotplug\"}}}}",
+        target, hotplug_lun
+    );
+    let ret = scsi_qmp_command(&vst.state, &device_add_cmd);
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    // The id and the address are in use.
+    let ret = scsi_qmp_command(&vst.state, &device_add_cmd);
+    assert!(ret.get("error").is_some());
+
+    // Test 1 Result: the rescan event is reported, and the unit attention is reported once.
+    wait_event(&mut vst, event, VIRTIO_SCSI_EVT_RESET_RESCAN);
+    tur_test(
+        &mut vst,
+        CHECK_CONDITION,
+        Some(SCSI_SENSE_REPORTED_LUNS_CHANGED),
+    );
+    tur_test(&mut vst, GOOD, None);
+
+    // Test 2: basic io test of the hotplugged device.
+    vst.scsi_try_io(target, hotplug_lun, ScsiDeviceType::ScsiHd);
+
+    // Test 3: hot-unplug the scsi-hd.
+    // Test 3 Result: the remove event is reported, and the unit attention is reported again.
+    let event = add_event_buf(&mut vst);
+    let ret = scsi_qmp_command(
+        &vst.state,
+        "{\"execute\": \"device_del\", \"arguments\": {\"id\": \"scsi-hotplug\"}}",
+    );
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    wait_event(&mut vst, event, VIRTIO_SCSI_EVT_RESET_REMOVED);
+    tur_test(
+        &mut vst,
+        CHECK_CONDITION,
+        Some(SCSI_SENSE_REPORTED_LUNS_CHANGED),
+    );
+    let ret = scsi_qmp_command(
+        &vst.state,
+        "{\"execute\": \"blockdev-del\", \"arguments\": {\"node-name\": \"drive-hotplug\"}}",
+    );
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+
+    vst.testcase_tear_down();
+    cleanup_img(image_path.to_string());
+}
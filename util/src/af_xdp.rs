@@ -0,0 +1,948 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! AF_XDP backend of virtio-net, which exchanges the frames with the queues of a
+//! host interface through the UMEM shared with the kernel.
+//!
+//! A minimal XDP program is attached to the interface, which redirects the frames
+//! received by the bound queues to the sockets, and passes the frames of other
+//! queues to the kernel stack. Each queue pair of the device has its own socket
+//! and UMEM, the UMEM is used by the driver directly if it supports zero copy.
+
+use std::fs::{read_to_string, File};
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr::{null, null_mut};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::aio::{iov_from_buf_direct, iov_slice, iov_to_buf_direct, Iovec};
+use crate::net_backend::{empty_vnet_hdr, to_iovecs, NetBackend};
+
+const SOL_XDP: libc::c_int = 283;
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_OPTIONS: libc::c_int = 8;
+const XDP_OPTIONS_ZEROCOPY: u32 = 1;
+
+/// Flags of binding the socket.
+const XDP_COPY: u16 = 1 << 1;
+const XDP_USE_NEED_WAKEUP: u16 = 1 << 3;
+/// Flag of the ring which is set if the kernel needs a syscall to process it.
+const XDP_RING_NEED_WAKEUP: u32 = 1;
+
+/// Offsets to mmap the rings of the socket.
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x8000_0000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x1_0000_0000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x1_8000_0000;
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+
+/// Opcodes of the eBPF instructions used by the XDP program.
+const BPF_LDX_MEM_W: u8 = 0x61;
+const BPF_LD_IMM64: u8 = 0x18;
+const BPF_MOV64_IMM: u8 = 0xb7;
+const BPF_CALL: u8 = 0x85;
+const BPF_EXIT: u8 = 0x95;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+/// Offset of `rx_queue_index` in `struct xdp_md`.
+const XDP_MD_RX_QUEUE_INDEX: i16 = 16;
+
+/// Size of each frame in UMEM, the larger frames are dropped.
+const FRAME_SIZE: u32 = 4096;
+/// Number of the descriptors of each ring.
+const RING_SIZE: u32 = 2048;
+/// The first half of the frames in UMEM are used to receive, and the others to send.
+const NUM_FRAMES: u32 = RING_SIZE * 2;
+/// Max length of the interface name, excluding the terminating null byte.
+const MAX_IFNAME_LEN: usize = 15;
+
+/// Mode of the XDP program attached to the interface.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum XdpMode {
+    /// The program runs in the driver, which is required by zero copy.
+    #[default]
+    Native,
+    /// The program runs after the skb is allocated, which works for all drivers.
+    Skb,
+}
+
+impl FromStr for XdpMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode {
+            "native" => Ok(XdpMode::Native),
+            "skb" => Ok(XdpMode::Skb),
+            _ => Err(anyhow!("Unknown XDP mode {:?}, use native or skb", mode)),
+        }
+    }
+}
+
+impl XdpMode {
+    fn attach_flags(&self) -> u32 {
+        match self {
+            XdpMode::Native => XDP_FLAGS_DRV_MODE,
+            XdpMode::Skb => XDP_FLAGS_SKB_MODE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AfXdpConfig {
+    pub ifname: String,
+    /// The first queue of the interface used by the netdev, the queue pairs of the
+    /// device are bound to the consecutive queues from it.
+    pub start_queue: u32,
+    pub mode: XdpMode,
+    /// Use copy mode even if the driver supports zero copy.
+    pub force_copy: bool,
+}
+
+impl AfXdpConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.ifname.is_empty() || self.ifname.len() > MAX_IFNAME_LEN || self.ifname.contains('/')
+        {
+            bail!("Invalid interface name {:?} of af-xdp netdev", self.ifname);
+        }
+        Ok(())
+    }
+
+    fn ifindex(&self) -> Result<u32> {
+        let path = format!("/sys/class/net/{}/ifindex", self.ifname);
+        let ifindex = read_to_string(&path)
+            .with_context(|| format!("Failed to find interface {}", self.ifname))?;
+        ifindex
+            .trim()
+            .parse::<u32>()
+            .with_context(|| format!("Invalid ifindex {:?} of {}", ifindex, self.ifname))
+    }
+}
+
+/// Create one backend for each queue pair of the device.
+pub fn create_af_xdp_backends(
+    config: &AfXdpConfig,
+    queue_pairs: u16,
+    vnet_hdr_len: usize,
+) -> Result<Vec<Box<dyn NetBackend>>> {
+    config.check()?;
+    let ifindex = config.ifindex()?;
+    let end_queue = config
+        .start_queue
+        .checked_add(u32::from(queue_pairs))
+        .with_context(|| format!("Invalid start queue {}", config.start_queue))?;
+    let program = Arc::new(
+        XdpProgram::attach(ifindex, config.mode, end_queue)
+            .with_context(|| format!("Failed to attach XDP program to {}", config.ifname))?,
+    );
+
+    let mut backends: Vec<Box<dyn NetBackend>> = Vec::with_capacity(queue_pairs as usize);
+    for queue in config.start_queue..end_queue {
+        let socket =
+            XskSocket::new(config, ifindex, queue, program.clone()).with_context(|| {
+                format!(
+                    "Failed to create AF_XDP socket of {} queue {}",
+                    config.ifname, queue
+                )
+            })?;
+        backends.push(Box::new(AfXdpBackend {
+            fd: socket.fd.as_raw_fd(),
+            vnet_hdr_len,
+            socket: Arc::new(Mutex::new(socket)),
+        }));
+    }
+    Ok(backends)
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfMapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfMapUpdateAttr {
+    map_fd: u32,
+    pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfLinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BpfInsn {
+    code: u8,
+    /// Destination register in the low 4 bits, and source register in the high 4 bits.
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl BpfInsn {
+    fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        BpfInsn {
+            code,
+            regs: dst | (src << 4),
+            off,
+            imm,
+        }
+    }
+}
+
+/// The program redirects the frame to the socket of its receiving queue in the map,
+/// or passes it to the kernel stack if there is no such socket.
+fn redirect_program(map_fd: RawFd) -> [BpfInsn; 6] {
+    [
+        // r2 = ctx->rx_queue_index
+        BpfInsn::new(BPF_LDX_MEM_W, 2, 1, XDP_MD_RX_QUEUE_INDEX, 0),
+        // r1 = map, the 64 bits immediate takes two instructions.
+        BpfInsn::new(BPF_LD_IMM64, 1, BPF_PSEUDO_MAP_FD, 0, map_fd),
+        BpfInsn::new(0, 0, 0, 0, 0),
+        // r3 = XDP_PASS, the action if the lookup fails.
+        BpfInsn::new(BPF_MOV64_IMM, 3, 0, 0, XDP_PASS),
+        // return bpf_redirect_map(r1, r2, r3)
+        BpfInsn::new(BPF_CALL, 0, 0, 0, BPF_FUNC_REDIRECT_MAP),
+        BpfInsn::new(BPF_EXIT, 0, 0, 0, 0),
+    ]
+}
+
+fn bpf<T>(cmd: libc::c_long, attr: &mut T) -> IoResult<libc::c_long> {
+    // SAFETY: attr is the attribute of the command, whose size is passed together.
+    let ret = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, size_of::<T>()) };
+    if ret < 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(ret)
+}
+
+/// Run the bpf command which creates a new fd.
+fn bpf_fd<T>(cmd: libc::c_long, attr: &mut T) -> IoResult<File> {
+    let fd = bpf(cmd, attr)?;
+    // SAFETY: the fd is newly created by the kernel and owned by the file only.
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+/// XDP program attached to the interface with a bpf link, which is detached when
+/// all the sockets are dropped.
+struct XdpProgram {
+    map: File,
+    _prog: File,
+    _link: File,
+}
+
+impl XdpProgram {
+    fn attach(ifindex: u32, mode: XdpMode, max_queues: u32) -> Result<Self> {
+        let mut attr = BpfMapCreateAttr {
+            map_type: BPF_MAP_TYPE_XSKMAP,
+            key_size: size_of::<u32>() as u32,
+            value_size: size_of::<u32>() as u32,
+            max_entries: max_queues,
+            ..Default::default()
+        };
+        let map = bpf_fd(BPF_MAP_CREATE, &mut attr).with_context(|| "Failed to create xsk map")?;
+
+        let insns = redirect_program(map.as_raw_fd());
+        let license = b"GPL\0";
+        let mut prog_name = [0_u8; 16];
+        prog_name[..12].copy_from_slice(b"xsk_redirect");
+        let mut attr = BpfProgLoadAttr {
+            prog_type: BPF_PROG_TYPE_XDP,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: license.as_ptr() as u64,
+            prog_name,
+            expected_attach_type: BPF_XDP,
+            ..Default::default()
+        };
+        let prog =
+            bpf_fd(BPF_PROG_LOAD, &mut attr).with_context(|| "Failed to load XDP program")?;
+
+        let mut attr = BpfLinkCreateAttr {
+            prog_fd: prog.as_raw_fd() as u32,
+            target_ifindex: ifindex,
+            attach_type: BPF_XDP,
+            flags: mode.attach_flags(),
+        };
+        let link = bpf_fd(BPF_LINK_CREATE, &mut attr).with_context(|| {
+            format!(
+                "Failed to link XDP program in {:?} mode, the interface may have one already",
+                mode
+            )
+        })?;
+
+        Ok(XdpProgram {
+            map,
+            _prog: prog,
+            _link: link,
+        })
+    }
+
+    /// Redirect the frames received by `queue` to the socket.
+    fn add_socket(&self, queue: u32, fd: RawFd) -> Result<()> {
+        let mut attr = BpfMapUpdateAttr {
+            map_fd: self.map.as_raw_fd() as u32,
+            key: &queue as *const u32 as u64,
+            value: &fd as *const RawFd as u64,
+            ..Default::default()
+        };
+        bpf(BPF_MAP_UPDATE_ELEM, &mut attr)
+            .map(|_| ())
+            .with_context(|| format!("Failed to add the socket of queue {} to xsk map", queue))
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fill: XdpRingOffset,
+    completion: XdpRingOffset,
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+    family: u16,
+    flags: u16,
+    ifindex: u32,
+    queue_id: u32,
+    shared_umem_fd: u32,
+}
+
+/// Descriptor of the frame in rx and tx ring, the entries of fill and completion
+/// ring are the addresses of frames only.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+/// Single producer single consumer ring shared with the kernel, the producer and
+/// the consumer are free running indexes.
+struct XskRing {
+    map: *mut libc::c_void,
+    map_len: usize,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    flags: *const AtomicU32,
+    desc: *mut u8,
+    size: u32,
+}
+
+impl XskRing {
+    fn map(
+        fd: RawFd,
+        offset: &XdpRingOffset,
+        pgoff: libc::off_t,
+        entry_size: usize,
+    ) -> Result<Self> {
+        let map_len = offset.desc as usize + RING_SIZE as usize * entry_size;
+        // SAFETY: the ring of the socket is mapped, which is unmapped when dropped.
+        let map = unsafe {
+            libc::mmap(
+                null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                pgoff,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(anyhow!(IoError::last_os_error())).with_context(|| "Failed to mmap ring");
+        }
+        // SAFETY: the offsets are got from the kernel, which are in the range of map.
+        let mut ring = unsafe { Self::new(map as *mut u8, offset) };
+        ring.map = map;
+        ring.map_len = map_len;
+        Ok(ring)
+    }
+
+    /// # Safety
+    ///
+    /// The offsets must be in the range of the memory at `base`, whose descriptors
+    /// are enough for `RING_SIZE` entries.
+    unsafe fn new(base: *mut u8, offset: &XdpRingOffset) -> Self {
+        XskRing {
+            map: null_mut(),
+            map_len: 0,
+            producer: base.add(offset.producer as usize) as *const AtomicU32,
+            consumer: base.add(offset.consumer as usize) as *const AtomicU32,
+            flags: base.add(offset.flags as usize) as *const AtomicU32,
+            desc: base.add(offset.desc as usize),
+            size: RING_SIZE,
+        }
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        // SAFETY: the index is in the mapped ring during the lifetime of self.
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        // SAFETY: the index is in the mapped ring during the lifetime of self.
+        unsafe { &*self.consumer }
+    }
+
+    fn need_wakeup(&self) -> bool {
+        // SAFETY: the flags are in the mapped ring during the lifetime of self.
+        unsafe { &*self.flags }.load(Ordering::Relaxed) & XDP_RING_NEED_WAKEUP != 0
+    }
+
+    fn entry<T>(&self, index: u32) -> *mut T {
+        // SAFETY: the index is masked in the range of the descriptors.
+        unsafe { (self.desc as *mut T).add((index & (self.size - 1)) as usize) }
+    }
+
+    /// Produce one entry, return false if the ring is full.
+    fn push<T>(&mut self, entry: T) -> bool {
+        let prod = self.producer().load(Ordering::Relaxed);
+        if prod.wrapping_sub(self.consumer().load(Ordering::Acquire)) >= self.size {
+            return false;
+        }
+        // SAFETY: the entry is not owned by the consumer until the producer is updated.
+        unsafe { self.entry::<T>(prod).write_volatile(entry) };
+        self.producer()
+            .store(prod.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Consume one entry, return None if the ring is empty.
+    fn pop<T>(&mut self) -> Option<T> {
+        let cons = self.consumer().load(Ordering::Relaxed);
+        if self.producer().load(Ordering::Acquire) == cons {
+            return None;
+        }
+        // SAFETY: the entry is not reused by the producer until the consumer is updated.
+        let entry = unsafe { self.entry::<T>(cons).read_volatile() };
+        self.consumer()
+            .store(cons.wrapping_add(1), Ordering::Release);
+        Some(entry)
+    }
+}
+
+impl Drop for XskRing {
+    fn drop(&mut self) {
+        if !self.map.is_null() {
+            // SAFETY: the ring is mapped in XskRing::map.
+            unsafe { libc::munmap(self.map, self.map_len) };
+        }
+    }
+}
+
+/// Memory of the frames registered to the socket.
+struct Umem {
+    addr: *mut u8,
+    len: usize,
+}
+
+impl Umem {
+    fn new(len: usize) -> Result<Self> {
+        // SAFETY: anonymous memory is mapped, which is unmapped when dropped.
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(anyhow!(IoError::last_os_error())).with_context(|| "Failed to mmap UMEM");
+        }
+        Ok(Umem {
+            addr: addr as *mut u8,
+            len,
+        })
+    }
+
+    fn check_range(&self, addr: u64, len: usize) -> IoResult<()> {
+        if addr as usize + len > self.len {
+            return Err(IoError::new(ErrorKind::InvalidData, "Frame is out of UMEM"));
+        }
+        Ok(())
+    }
+
+    /// Get the frame at `addr` of UMEM.
+    fn frame(&self, addr: u64, len: usize) -> IoResult<&[u8]> {
+        self.check_range(addr, len)?;
+        // SAFETY: the frame is in the range of UMEM, and it is owned by the user
+        // space when it is accessed.
+        Ok(unsafe { std::slice::from_raw_parts(self.addr.add(addr as usize), len) })
+    }
+
+    fn frame_mut(&mut self, addr: u64, len: usize) -> IoResult<&mut [u8]> {
+        self.check_range(addr, len)?;
+        // SAFETY: same as frame().
+        Ok(unsafe { std::slice::from_raw_parts_mut(self.addr.add(addr as usize), len) })
+    }
+}
+
+impl Drop for Umem {
+    fn drop(&mut self) {
+        // SAFETY: UMEM is mapped in Umem::new.
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.len) };
+    }
+}
+
+fn set_opt<T>(fd: RawFd, opt: libc::c_int, value: &T) -> IoResult<()> {
+    // SAFETY: value is valid with the size passed together.
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_XDP,
+            opt,
+            value as *const T as *const libc::c_void,
+            size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
+
+fn get_opt<T>(fd: RawFd, opt: libc::c_int, value: &mut T) -> IoResult<()> {
+    let mut len = size_of::<T>() as libc::socklen_t;
+    // SAFETY: value is valid with the size passed together.
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            SOL_XDP,
+            opt,
+            value as *mut T as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
+
+struct XskSocket {
+    fd: File,
+    umem: Umem,
+    rx: XskRing,
+    tx: XskRing,
+    fill: XskRing,
+    completion: XskRing,
+    /// Frames of UMEM which are free to send.
+    free_frames: Vec<u64>,
+    _program: Arc<XdpProgram>,
+}
+
+// SAFETY: the memory pointed by UMEM and rings is owned by the socket, which is
+// only accessed with the lock of the socket held.
+unsafe impl Send for XskSocket {}
+
+impl XskSocket {
+    fn new(
+        config: &AfXdpConfig,
+        ifindex: u32,
+        queue: u32,
+        program: Arc<XdpProgram>,
+    ) -> Result<Self> {
+        // SAFETY: the socket is owned by the file only.
+        let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(anyhow!(IoError::last_os_error()))
+                .with_context(|| "Failed to create AF_XDP socket");
+        }
+        // SAFETY: the fd is newly created above.
+        let fd = unsafe { File::from_raw_fd(fd) };
+        let raw_fd = fd.as_raw_fd();
+
+        let umem = Umem::new((NUM_FRAMES * FRAME_SIZE) as usize)?;
+        let reg = XdpUmemReg {
+            addr: umem.addr as u64,
+            len: umem.len as u64,
+            chunk_size: FRAME_SIZE,
+            ..Default::default()
+        };
+        set_opt(raw_fd, XDP_UMEM_REG, &reg).with_context(|| "Failed to register UMEM")?;
+        for opt in [
+            XDP_UMEM_FILL_RING,
+            XDP_UMEM_COMPLETION_RING,
+            XDP_RX_RING,
+            XDP_TX_RING,
+        ] {
+            set_opt(raw_fd, opt, &RING_SIZE).with_context(|| "Failed to set ring size")?;
+        }
+        let mut offsets = XdpMmapOffsets::default();
+        get_opt(raw_fd, XDP_MMAP_OFFSETS, &mut offsets)
+            .with_context(|| "Failed to get mmap offsets of rings")?;
+
+        let desc_size = size_of::<XdpDesc>();
+        let addr_size = size_of::<u64>();
+        let mut socket = XskSocket {
+            rx: XskRing::map(raw_fd, &offsets.rx, XDP_PGOFF_RX_RING, desc_size)?,
+            tx: XskRing::map(raw_fd, &offsets.tx, XDP_PGOFF_TX_RING, desc_size)?,
+            fill: XskRing::map(raw_fd, &offsets.fill, XDP_UMEM_PGOFF_FILL_RING, addr_size)?,
+            completion: XskRing::map(
+                raw_fd,
+                &offsets.completion,
+                XDP_UMEM_PGOFF_COMPLETION_RING,
+                addr_size,
+            )?,
+            fd,
+            umem,
+            free_frames: (RING_SIZE..NUM_FRAMES)
+                .map(|frame| u64::from(frame * FRAME_SIZE))
+                .collect(),
+            _program: program.clone(),
+        };
+        for frame in 0..RING_SIZE {
+            socket.fill.push(u64::from(frame * FRAME_SIZE));
+        }
+
+        // Zero copy is tried first unless copy mode is required, the kernel falls
+        // back to copy mode if the driver doesn't support it.
+        let mut flags = XDP_USE_NEED_WAKEUP;
+        if config.force_copy || config.mode == XdpMode::Skb {
+            flags |= XDP_COPY;
+        }
+        let addr = SockaddrXdp {
+            family: libc::AF_XDP as u16,
+            flags,
+            ifindex,
+            queue_id: queue,
+            shared_umem_fd: 0,
+        };
+        // SAFETY: addr is valid with the size passed together.
+        let ret = unsafe {
+            libc::bind(
+                raw_fd,
+                &addr as *const SockaddrXdp as *const libc::sockaddr,
+                size_of::<SockaddrXdp>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(anyhow!(IoError::last_os_error()))
+                .with_context(|| "Failed to bind AF_XDP socket");
+        }
+        let mut options = 0_u32;
+        get_opt(raw_fd, XDP_OPTIONS, &mut options)
+            .with_context(|| "Failed to get options of AF_XDP socket")?;
+        info!(
+            "AF_XDP socket of {} queue {} works in {} mode",
+            config.ifname,
+            queue,
+            if options & XDP_OPTIONS_ZEROCOPY != 0 {
+                "zero copy"
+            } else {
+                "copy"
+            }
+        );
+
+        program.add_socket(queue, raw_fd)?;
+        Ok(socket)
+    }
+
+    /// Notify the kernel to fill the rx ring.
+    fn kick_rx(&self) {
+        // SAFETY: no buffer is accessed by the syscall.
+        let ret = unsafe {
+            libc::recvfrom(
+                self.fd.as_raw_fd(),
+                null_mut(),
+                0,
+                libc::MSG_DONTWAIT,
+                null_mut(),
+                null_mut(),
+            )
+        };
+        if ret < 0 {
+            Self::check_kick_error("rx");
+        }
+    }
+
+    /// Notify the kernel to send the frames in the tx ring.
+    fn kick_tx(&self) {
+        // SAFETY: no buffer is accessed by the syscall.
+        let ret = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                null(),
+                0,
+                libc::MSG_DONTWAIT,
+                null(),
+                0,
+            )
+        };
+        if ret < 0 {
+            Self::check_kick_error("tx");
+        }
+    }
+
+    fn check_kick_error(ring: &str) {
+        let err = IoError::last_os_error();
+        match err.raw_os_error() {
+            // The kernel is busy, and the ring is processed later.
+            Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) | Some(libc::EINTR) => {}
+            _ => warn!("Failed to kick {} ring of AF_XDP socket: {:?}", ring, err),
+        }
+    }
+
+    /// Copy the received frame to `iovecs` after an empty virtio net header.
+    fn copy_rx_frame(
+        &self,
+        desc: &XdpDesc,
+        iovecs: &[libc::iovec],
+        vnet_hdr_len: usize,
+    ) -> Result<usize> {
+        let frame = self.umem.frame(desc.addr, desc.len as usize)?;
+        let iovecs = to_iovecs(iovecs);
+        let hdr_len = iov_from_buf_direct(&iovecs, &empty_vnet_hdr(vnet_hdr_len))?;
+        let payload = iov_slice(&iovecs, hdr_len as u64, u64::MAX);
+        Ok(hdr_len + iov_from_buf_direct(&payload, frame)?)
+    }
+
+    /// Copy the frame after the virtio net header in `iovecs` to UMEM at `addr`.
+    fn copy_tx_frame(
+        &mut self,
+        addr: u64,
+        iovecs: &[Iovec],
+        vnet_hdr_len: usize,
+        frame_len: u64,
+    ) -> Result<()> {
+        let payload = iov_slice(iovecs, vnet_hdr_len as u64, frame_len);
+        iov_to_buf_direct(&payload, self.umem.frame_mut(addr, frame_len as usize)?)?;
+        Ok(())
+    }
+
+    /// Reclaim the frames which have been sent.
+    fn reclaim(&mut self) {
+        while let Some(addr) = self.completion.pop::<u64>() {
+            self.free_frames.push(addr);
+        }
+    }
+}
+
+/// Backend of one queue pair bound to AF_XDP socket, the frames are copied between
+/// the guest buffers and UMEM.
+pub struct AfXdpBackend {
+    fd: RawFd,
+    vnet_hdr_len: usize,
+    socket: Arc<Mutex<XskSocket>>,
+}
+
+impl AsRawFd for AfXdpBackend {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl NetBackend for AfXdpBackend {
+    fn recv_frame(&mut self, iovecs: &[libc::iovec]) -> IoResult<usize> {
+        let mut socket = self.socket.lock().unwrap();
+        let desc = match socket.rx.pop::<XdpDesc>() {
+            Some(desc) => desc,
+            None => {
+                if socket.fill.need_wakeup() {
+                    socket.kick_rx();
+                }
+                return Err(ErrorKind::WouldBlock.into());
+            }
+        };
+
+        let ret = socket
+            .copy_rx_frame(&desc, iovecs, self.vnet_hdr_len)
+            .map_err(|e| IoError::other(e.to_string()));
+        // The frame is given back to the kernel to receive again, the fill ring
+        // never overflows as it has the same size as the frames for rx.
+        let addr = desc.addr - desc.addr % u64::from(FRAME_SIZE);
+        socket.fill.push(addr);
+        ret
+    }
+
+    fn send_frame(&mut self, iovecs: &[libc::iovec]) -> IoResult<usize> {
+        let mut socket = self.socket.lock().unwrap();
+        socket.reclaim();
+
+        let iovecs = to_iovecs(iovecs);
+        let len = iovecs.iter().map(|iov| iov.iov_len).sum::<u64>();
+        let frame_len = len
+            .checked_sub(self.vnet_hdr_len as u64)
+            .ok_or_else(|| IoError::new(ErrorKind::InvalidInput, "Frame is too short"))?;
+        if frame_len > u64::from(FRAME_SIZE) {
+            return Err(IoError::new(ErrorKind::InvalidInput, "Frame is too large"));
+        }
+        let addr = match socket.free_frames.pop() {
+            Some(addr) => addr,
+            None => {
+                // All the frames are being sent, retry after they are completed.
+                socket.kick_tx();
+                return Err(ErrorKind::WouldBlock.into());
+            }
+        };
+        if let Err(e) = socket.copy_tx_frame(addr, &iovecs, self.vnet_hdr_len, frame_len) {
+            socket.free_frames.push(addr);
+            return Err(IoError::other(e.to_string()));
+        }
+
+        let desc = XdpDesc {
+            addr,
+            len: frame_len as u32,
+            options: 0,
+        };
+        if !socket.tx.push(desc) {
+            socket.free_frames.push(addr);
+            socket.kick_tx();
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        if socket.tx.need_wakeup() {
+            socket.kick_tx();
+        }
+        Ok(len as usize)
+    }
+
+    fn try_clone(&self) -> IoResult<Box<dyn NetBackend>> {
+        Ok(Box::new(AfXdpBackend {
+            fd: self.fd,
+            vnet_hdr_len: self.vnet_hdr_len,
+            socket: self.socket.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_af_xdp_config() {
+        assert_eq!("native".parse::<XdpMode>().unwrap(), XdpMode::Native);
+        assert_eq!("skb".parse::<XdpMode>().unwrap(), XdpMode::Skb);
+        assert!("generic".parse::<XdpMode>().is_err());
+
+        let mut config = AfXdpConfig {
+            ifname: "eth0".to_string(),
+            start_queue: 0,
+            mode: XdpMode::Native,
+            force_copy: false,
+        };
+        assert!(config.check().is_ok());
+        config.ifname = "../eth0".to_string();
+        assert!(config.check().is_err());
+        config.ifname = "a".repeat(MAX_IFNAME_LEN + 1);
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn test_xsk_ring() {
+        // Layout of the ring: producer, consumer, flags, descriptors.
+        let offset = XdpRingOffset {
+            producer: 0,
+            consumer: 4,
+            flags: 8,
+            desc: 16,
+        };
+        let mut buf = vec![0_u64; 2 + RING_SIZE as usize];
+        // SAFETY: the buffer is large enough for the ring, and lives longer than it.
+        let mut ring = unsafe { XskRing::new(buf.as_mut_ptr() as *mut u8, &offset) };
+
+        assert!(ring.pop::<u64>().is_none());
+        for i in 0..RING_SIZE {
+            assert!(ring.push(u64::from(i)));
+        }
+        assert!(!ring.push(0_u64));
+        assert_eq!(ring.pop::<u64>(), Some(0));
+        // The index wraps around the ring.
+        assert!(ring.push(u64::from(RING_SIZE)));
+        for i in 1..=RING_SIZE {
+            assert_eq!(ring.pop::<u64>(), Some(u64::from(i)));
+        }
+        assert!(ring.pop::<u64>().is_none());
+        assert!(!ring.need_wakeup());
+        // SAFETY: the flags are in the buffer.
+        unsafe { &*ring.flags }.store(XDP_RING_NEED_WAKEUP, Ordering::Relaxed);
+        assert!(ring.need_wakeup());
+    }
+}
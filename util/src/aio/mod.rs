@@ -17,7 +17,8 @@ mod uring;
 use std::clone::Clone;
 use std::io::Write;
 use std::os::unix::io::RawFd;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::{cmp, str::FromStr};
 
 use libc::c_void;
@@ -44,6 +45,8 @@ const AIO_NATIVE: &str = "native";
 const AIO_IOURING: &str = "io_uring";
 /// Max bytes of bounce buffer for misaligned IO.
 const MAX_LEN_BOUNCE_BUFF: u64 = 1 << 20;
+/// Default idle time in milliseconds of the io_uring polling thread.
+pub const DEFAULT_SQPOLL_IDLE_MS: u32 = 1000;
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
 pub enum AioEngine {
@@ -65,6 +68,70 @@ impl FromStr for AioEngine {
     }
 }
 
+/// Tuning of the io_uring engine, which reduces the syscall overhead of each request.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IoUringConfig {
+    /// Register the image files as fixed files.
+    pub fixed_files: bool,
+    /// Register the guest memory as fixed buffers.
+    pub fixed_buffers: bool,
+    /// Submit the requests by the kernel polling thread instead of syscalls.
+    pub sqpoll: bool,
+    /// Idle time in milliseconds before the polling thread goes to sleep.
+    pub sqpoll_idle: u32,
+    /// Host cpu which the polling thread is bound to.
+    pub sqpoll_cpu: Option<u32>,
+}
+
+impl Default for IoUringConfig {
+    fn default() -> Self {
+        IoUringConfig {
+            fixed_files: false,
+            fixed_buffers: false,
+            sqpoll: false,
+            sqpoll_idle: DEFAULT_SQPOLL_IDLE_MS,
+            sqpoll_cpu: None,
+        }
+    }
+}
+
+impl IoUringConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.fixed_files || self.fixed_buffers || self.sqpoll
+    }
+}
+
+/// Host memory regions used as the fixed buffers of io_uring. They are updated by the
+/// memory listener of the device, and picked up by the aio before submitting requests.
+#[derive(Default)]
+pub struct FixedBuffers {
+    regions: Mutex<Vec<Iovec>>,
+    /// Increased each time the regions change.
+    generation: AtomicU64,
+}
+
+impl FixedBuffers {
+    pub fn add_region(&self, base: u64, len: u64) {
+        let mut regions = self.regions.lock().unwrap();
+        regions.push(Iovec::new(base, len));
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn del_region(&self, base: u64, len: u64) {
+        let mut regions = self.regions.lock().unwrap();
+        regions.retain(|r| r.iov_base != base || r.iov_len != len);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    fn regions(&self) -> Vec<Iovec> {
+        self.regions.lock().unwrap().clone()
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum WriteZeroesState {
     Off,
@@ -106,6 +173,16 @@ trait AioContext<T: Clone> {
     fn submit(&mut self, iocbp: &[*const AioCb<T>]) -> Result<usize>;
     /// Get the IO events of the requests submitted earlier.
     fn get_events(&mut self) -> &[AioEvent];
+    /// Register the file as fixed file, which is only supported by io_uring.
+    fn register_file(&mut self, _fd: RawFd) -> Result<()> {
+        Ok(())
+    }
+    /// Register the memory regions as fixed buffers, which is only supported by io_uring.
+    fn register_buffers(&mut self, _bufs: &[Iovec]) -> Result<()> {
+        Ok(())
+    }
+    /// Stop using the registered fixed buffers, which may be stale.
+    fn invalidate_buffers(&mut self) {}
 }
 
 pub struct AioEvent {
@@ -138,6 +215,9 @@ pub struct AioCb<T: Clone> {
     pub discard: bool,
     pub write_zeroes: WriteZeroesState,
     pub write_zeroes_unmap: bool,
+    /// Shared by all the sub requests split from one request, the counter of
+    /// uncompleted sub requests and the result of the whole request.
+    pub combine_req: Option<(Arc<AtomicU32>, Arc<AtomicI64>)>,
 }
 
 pub type AioCompleteFunc<T> = fn(&AioCb<T>, i64) -> Result<()>;
@@ -150,6 +230,11 @@ pub struct Aio<T: Clone + 'static> {
     pub aio_in_flight: CbList<T>,
     max_events: usize,
     complete_func: Arc<AioCompleteFunc<T>>,
+    uring_config: IoUringConfig,
+    /// Memory regions registered as the fixed buffers of io_uring.
+    fixed_buffers: Option<Arc<FixedBuffers>>,
+    /// Generation of the fixed buffers registered to the io_uring.
+    buffers_generation: u64,
 }
 
 pub fn aio_probe(engine: AioEngine) -> Result<()> {
@@ -169,12 +254,25 @@ pub fn aio_probe(engine: AioEngine) -> Result<()> {
 
 impl<T: Clone + 'static> Aio<T> {
     pub fn new(func: Arc<AioCompleteFunc<T>>, engine: AioEngine) -> Result<Self> {
+        Self::new_with_uring_config(func, engine, IoUringConfig::default())
+    }
+
+    /// Create the aio, `uring_config` is only used by the io_uring engine.
+    pub fn new_with_uring_config(
+        func: Arc<AioCompleteFunc<T>>,
+        engine: AioEngine,
+        uring_config: IoUringConfig,
+    ) -> Result<Self> {
         let max_events: usize = 128;
         let fd = EventFd::new(libc::EFD_NONBLOCK)?;
         let ctx: Option<Box<dyn AioContext<T>>> = match engine {
             AioEngine::Off => None,
             AioEngine::Native => Some(Box::new(LibaioContext::new(max_events as u32, &fd)?)),
-            AioEngine::IoUring => Some(Box::new(IoUringContext::new(max_events as u32, &fd)?)),
+            AioEngine::IoUring => Some(Box::new(IoUringContext::new(
+                max_events as u32,
+                &fd,
+                &uring_config,
+            )?)),
         };
 
         Ok(Aio {
@@ -185,6 +283,9 @@ impl<T: Clone + 'static> Aio<T> {
             aio_in_flight: List::new(),
             max_events,
             complete_func: func,
+            uring_config,
+            fixed_buffers: None,
+            buffers_generation: 0,
         })
     }
 
@@ -192,6 +293,52 @@ impl<T: Clone + 'static> Aio<T> {
         self.engine
     }
 
+    /// Register the image file as the fixed file of io_uring if it is enabled. The file
+    /// must be kept open as long as the aio.
+    pub fn register_file(&mut self, fd: RawFd) -> Result<()> {
+        match self.ctx.as_mut() {
+            Some(ctx) if self.uring_config.fixed_files => ctx.register_file(fd),
+            _ => Ok(()),
+        }
+    }
+
+    /// Use the memory regions as the fixed buffers of io_uring if it is enabled.
+    pub fn set_fixed_buffers(&mut self, buffers: Arc<FixedBuffers>) {
+        if self.uring_config.fixed_buffers {
+            self.fixed_buffers = Some(buffers);
+            self.buffers_generation = 0;
+        }
+    }
+
+    /// Re-register the fixed buffers after the memory regions change. The requests in
+    /// flight may still use the old buffers, so the fixed buffers are not used until all
+    /// of them finish.
+    fn update_fixed_buffers(&mut self) {
+        let (buffers, ctx) = match (self.fixed_buffers.as_ref(), self.ctx.as_mut()) {
+            (Some(buffers), Some(ctx)) => (buffers, ctx),
+            _ => return,
+        };
+        let generation = buffers.generation();
+        if generation == self.buffers_generation {
+            return;
+        }
+        if self.aio_in_flight.len != 0 {
+            ctx.invalidate_buffers();
+            return;
+        }
+        if let Err(e) = ctx.register_buffers(&buffers.regions()) {
+            warn!("{:?}", e);
+        }
+        self.buffers_generation = generation;
+    }
+
+    /// Call the complete function of the request. If the request is one of the
+    /// sub requests split from a bigger one, the complete function is called only
+    /// once when the last sub request finishes, with the first error encountered.
+    pub fn complete_func(&self, cb: &AioCb<T>, res: i64) -> Result<()> {
+        combine_complete(&self.complete_func, cb, res)
+    }
+
     pub fn submit_request(&mut self, mut cb: AioCb<T>) -> Result<()> {
         if self.request_misaligned(&cb) {
             let max_len = round_down(cb.nbytes + cb.req_align as u64 * 2, cb.req_align as u64)
@@ -204,20 +351,20 @@ impl<T: Clone + 'static> Aio<T> {
                 unsafe { libc::memalign(host_page_size() as usize, buff_len as usize) };
             if bounce_buffer.is_null() {
                 error!("Failed to alloc memory for misaligned read/write.");
-                return (self.complete_func)(&cb, -1);
+                return self.complete_func(&cb, -i64::from(libc::ENOMEM));
             }
 
             let res = match self.handle_misaligned_rw(&mut cb, bounce_buffer, buff_len) {
                 Ok(()) => 0,
                 Err(e) => {
                     error!("{:?}", e);
-                    -1
+                    -i64::from(libc::EIO)
                 }
             };
 
             // SAFETY: the memory is allocated by us and will not be used anymore.
             unsafe { libc::free(bounce_buffer) };
-            return (self.complete_func)(&cb, res);
+            return self.complete_func(&cb, res);
         }
 
         if cb.opcode == OpCode::Pwritev
@@ -277,10 +424,10 @@ impl<T: Clone + 'static> Aio<T> {
                         "Async IO request failed, status {} res {}",
                         evt.status, evt.res
                     );
-                    -1
+                    failed_result(&(*node).value, evt.res)
                 };
 
-                (self.complete_func)(&(*node).value, res)?;
+                combine_complete(&self.complete_func, &(*node).value, res)?;
                 self.aio_in_flight.unlink(&(*node));
                 // Construct Box to free mem automatically.
                 drop(Box::from_raw(node));
@@ -295,6 +442,7 @@ impl<T: Clone + 'static> Aio<T> {
             warn!("Can not process aio list with invalid ctx.");
             return Ok(());
         }
+        self.update_fixed_buffers();
         while self.aio_in_queue.len > 0 && self.aio_in_flight.len < self.max_events {
             let mut iocbs = Vec::new();
 
@@ -330,7 +478,7 @@ impl<T: Clone + 'static> Aio<T> {
             if is_err {
                 // Fail one request, retry the rest.
                 if let Some(node) = self.aio_in_queue.pop_tail() {
-                    (self.complete_func)(&(node).value, -1)?;
+                    self.complete_func(&(node).value, -i64::from(libc::EIO))?;
                 }
             } else if nr == 0 {
                 // If can't submit any request, break the loop
@@ -357,15 +505,15 @@ impl<T: Clone + 'static> Aio<T> {
         let mut ret = match cb.opcode {
             OpCode::Preadv => raw_readv(cb.file_fd, &cb.iovec, cb.offset),
             OpCode::Pwritev => raw_writev(cb.file_fd, &cb.iovec, cb.offset),
-            _ => -1,
+            _ => -i64::from(libc::EINVAL),
         };
         if ret < 0 {
             error!("Failed to do sync read/write.");
         } else if ret as u64 != cb.nbytes {
             error!("Incomplete sync read/write.");
-            ret = -1;
+            ret = failed_result(&cb, ret);
         }
-        (self.complete_func)(&cb, ret)
+        self.complete_func(&cb, ret)
     }
 
     fn request_misaligned(&self, cb: &AioCb<T>) -> bool {
@@ -534,7 +682,7 @@ impl<T: Clone + 'static> Aio<T> {
         if ret < 0 {
             error!("Failed to do sync flush.");
         }
-        (self.complete_func)(&cb, ret)
+        self.complete_func(&cb, ret)
     }
 
     fn discard_sync(&mut self, cb: AioCb<T>) -> Result<()> {
@@ -542,7 +690,7 @@ impl<T: Clone + 'static> Aio<T> {
         if ret < 0 {
             error!("Failed to do sync discard.");
         }
-        (self.complete_func)(&cb, ret)
+        self.complete_func(&cb, ret)
     }
 
     fn write_zeroes_sync(&mut self, cb: AioCb<T>) -> Result<()> {
@@ -550,17 +698,43 @@ impl<T: Clone + 'static> Aio<T> {
         if cb.write_zeroes_unmap {
             ret = raw_discard(cb.file_fd, cb.offset, cb.nbytes);
             if ret == 0 {
-                return (self.complete_func)(&cb, ret);
+                return self.complete_func(&cb, ret);
             }
         }
         ret = raw_write_zeroes(cb.file_fd, cb.offset, cb.nbytes);
         if ret < 0 {
             error!("Failed to do sync write zeroes.");
         }
-        (self.complete_func)(&cb, ret)
+        self.complete_func(&cb, ret)
+    }
+}
+
+/// Get the result of the failed request from the result `res` returned by the host,
+/// which is the negative errno or the number of bytes transferred. Short writes are
+/// treated as running out of space, like the host kernel does for the next write.
+fn failed_result<T: Clone>(cb: &AioCb<T>, res: i64) -> i64 {
+    if res < 0 {
+        res
+    } else if cb.opcode == OpCode::Pwritev {
+        -i64::from(libc::ENOSPC)
+    } else {
+        -i64::from(libc::EIO)
     }
 }
 
+fn combine_complete<T: Clone>(func: &AioCompleteFunc<T>, cb: &AioCb<T>, res: i64) -> Result<()> {
+    if let Some((cnt, total)) = cb.combine_req.as_ref() {
+        if res < 0 {
+            let _ = total.compare_exchange(0, res, Ordering::SeqCst, Ordering::SeqCst);
+        }
+        if cnt.fetch_sub(1, Ordering::SeqCst) > 1 {
+            return Ok(());
+        }
+        return func(cb, total.load(Ordering::SeqCst));
+    }
+    func(cb, res)
+}
+
 pub fn mem_from_buf(buf: &[u8], hva: u64) -> Result<()> {
     // SAFETY: all callers have valid hva address.
     let mut slice = unsafe { std::slice::from_raw_parts_mut(hva as *mut u8, buf.len()) };
@@ -623,6 +797,34 @@ pub fn iov_discard_front_direct(iovec: &mut [Iovec], mut size: u64) -> Option<&m
     None
 }
 
+/// Get the part of `iovec` in the range [start, start + len).
+pub fn iov_slice(iovec: &[Iovec], mut start: u64, mut len: u64) -> Vec<Iovec> {
+    let mut res = Vec::new();
+    for iov in iovec {
+        if len == 0 {
+            break;
+        }
+        if start >= iov.iov_len {
+            start -= iov.iov_len;
+            continue;
+        }
+        let size = (iov.iov_len - start).min(len);
+        res.push(Iovec::new(iov.iov_base + start, size));
+        start = 0;
+        len -= size;
+    }
+    res
+}
+
+/// Get the total length of iovec.
+pub fn get_iov_size(iovecs: &[Iovec]) -> u64 {
+    let mut sum = 0;
+    for iov in iovecs {
+        sum += iov.iov_len;
+    }
+    sum
+}
+
 fn iovec_is_zero(iovecs: &[Iovec]) -> bool {
     let size = std::mem::size_of::<u64>() as u64;
     for iov in iovecs {
@@ -696,6 +898,7 @@ mod tests {
             discard: false,
             write_zeroes: WriteZeroesState::Off,
             write_zeroes_unmap: false,
+            combine_req: None,
         };
         let mut aio = Aio::new(
             Arc::new(|_: &AioCb<i32>, _: i64| -> Result<()> { Ok(()) }),
@@ -768,4 +971,73 @@ mod tests {
         test_sync_rw_all_align(OpCode::Preadv, false);
         test_sync_rw_all_align(OpCode::Pwritev, false);
     }
+
+    #[test]
+    fn test_iouring_fixed_rw() {
+        if aio_probe(AioEngine::IoUring).is_err() {
+            return;
+        }
+        let config = IoUringConfig {
+            fixed_files: true,
+            fixed_buffers: true,
+            ..Default::default()
+        };
+        let mut aio = Aio::new_with_uring_config(
+            Arc::new(|cb: &AioCb<Arc<AtomicI64>>, res: i64| -> Result<()> {
+                cb.iocompletecb.store(res, Ordering::SeqCst);
+                Ok(())
+            }),
+            AioEngine::IoUring,
+            config,
+        )
+        .unwrap();
+        let file = TempFile::new().unwrap().into_file();
+        aio.register_file(file.as_raw_fd()).unwrap();
+        let mut buf = vec![0x5a_u8; 8192];
+        let buffers = Arc::new(FixedBuffers::default());
+        buffers.add_region(buf.as_mut_ptr() as u64, buf.len() as u64);
+        aio.set_fixed_buffers(buffers);
+
+        // The first request uses the fixed buffer, the second one uses iovecs.
+        let split = vec![
+            Iovec::new(buf.as_ptr() as u64, 4096),
+            Iovec::new(buf.as_ptr() as u64 + 4096, 4096),
+        ];
+        let whole = vec![Iovec::new(buf.as_ptr() as u64, 8192)];
+        for (iovec, offset) in [(whole, 0), (split, 8192)] {
+            let res = Arc::new(AtomicI64::new(i64::MIN));
+            let aiocb = AioCb {
+                direct: false,
+                req_align: 1,
+                buf_align: 1,
+                file_fd: file.as_raw_fd(),
+                opcode: OpCode::Pwritev,
+                iovec,
+                offset,
+                nbytes: 8192,
+                user_data: 0,
+                iocompletecb: res.clone(),
+                discard: false,
+                write_zeroes: WriteZeroesState::Off,
+                write_zeroes_unmap: false,
+                combine_req: None,
+            };
+            aio.submit_request(aiocb).unwrap();
+            aio.flush_request().unwrap();
+            while res.load(Ordering::SeqCst) == i64::MIN {
+                aio.handle_complete().unwrap();
+            }
+            assert_eq!(res.load(Ordering::SeqCst), 8192);
+        }
+
+        let mut content = vec![0_u8; 16384];
+        let ret = raw_read(
+            file.as_raw_fd(),
+            content.as_mut_ptr() as u64,
+            content.len(),
+            0,
+        );
+        assert_eq!(ret, 16384);
+        assert!(content.iter().all(|b| *b == 0x5a));
+    }
 }
@@ -35,13 +35,12 @@ pub fn raw_read(fd: RawFd, buf: u64, size: usize, offset: usize) -> i64 {
         }
     }
     if ret < 0 {
+        let errno = errno::errno().0;
         error!(
             "Failed to pread: buf{}, size{}, offset{}, errno{}.",
-            buf,
-            size,
-            offset,
-            errno::errno().0
+            buf, size, offset, errno
         );
+        ret = -i64::from(errno);
     }
     ret
 }
@@ -63,11 +62,9 @@ pub fn raw_readv(fd: RawFd, iovec: &[Iovec], offset: usize) -> i64 {
         }
     }
     if ret < 0 {
-        error!(
-            "Failed to preadv: offset{}, errno{}.",
-            offset,
-            errno::errno().0,
-        );
+        let errno = errno::errno().0;
+        error!("Failed to preadv: offset{}, errno{}.", offset, errno);
+        ret = -i64::from(errno);
     }
     ret
 }
@@ -89,13 +86,12 @@ pub fn raw_write(fd: RawFd, buf: u64, size: usize, offset: usize) -> i64 {
         }
     }
     if ret < 0 {
+        let errno = errno::errno().0;
         error!(
             "Failed to pwrite: buf{}, size{}, offset{}, errno{}.",
-            buf,
-            size,
-            offset,
-            errno::errno().0,
+            buf, size, offset, errno
         );
+        ret = -i64::from(errno);
     }
     ret
 }
@@ -117,20 +113,20 @@ pub fn raw_writev(fd: RawFd, iovec: &[Iovec], offset: usize) -> i64 {
         }
     }
     if ret < 0 {
-        error!(
-            "Failed to pwritev: offset{}, errno{}.",
-            offset,
-            errno::errno().0,
-        );
+        let errno = errno::errno().0;
+        error!("Failed to pwritev: offset{}, errno{}.", offset, errno);
+        ret = -i64::from(errno);
     }
     ret
 }
 
 pub fn raw_datasync(fd: RawFd) -> i64 {
     // SAFETY: fd is valid.
-    let ret = unsafe { i64::from(fdatasync(fd)) };
+    let mut ret = unsafe { i64::from(fdatasync(fd)) };
     if ret < 0 {
-        error!("Failed to fdatasync: errno{}.", errno::errno().0);
+        let errno = errno::errno().0;
+        error!("Failed to fdatasync: errno{}.", errno);
+        ret = -i64::from(errno);
     }
     ret
 }
@@ -152,11 +148,9 @@ pub fn raw_discard(fd: RawFd, offset: usize, size: u64) -> i64 {
         }
     }
     if ret < 0 {
-        error!(
-            "Failed to fallocate for {}, errno {}.",
-            fd,
-            errno::errno().0,
-        );
+        let errno = errno::errno().0;
+        error!("Failed to fallocate for {}, errno {}.", fd, errno);
+        ret = -i64::from(errno);
     }
     ret
 }
@@ -178,11 +172,12 @@ pub fn raw_write_zeroes(fd: RawFd, offset: usize, size: u64) -> i64 {
         }
     }
     if ret < 0 {
+        let errno = errno::errno().0;
         error!(
             "Failed to fallocate zero range for fd {}, errno {}.",
-            fd,
-            errno::errno().0,
+            fd, errno
         );
+        ret = -i64::from(errno);
     }
     ret
 }
@@ -11,18 +11,63 @@
 // See the Mulan PSL v2 for more details.
 
 use libc;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use anyhow::{bail, Context};
 use io_uring::{opcode, squeue, types, IoUring};
 use vmm_sys_util::eventfd::EventFd;
 
-use super::{AioCb, AioContext, AioEvent, OpCode, Result};
+use super::{AioCb, AioContext, AioEvent, IoUringConfig, Iovec, OpCode, Result};
+
+/// Max number of the fixed files of one io_uring.
+const MAX_FIXED_FILES: usize = 16;
+/// Max number of the fixed buffers of one io_uring, limited by the kernel.
+const MAX_FIXED_BUFFERS: usize = 1024;
+/// Max size of one fixed buffer, limited by the kernel.
+const MAX_FIXED_BUFFER_SIZE: u64 = 1 << 30;
+
+/// Build the sqe of the request, `$fd` is `types::Fd` or `types::Fixed`, and `$buf_index`
+/// is the index of the fixed buffer which contains the data of the request.
+macro_rules! build_sqe {
+    ($fd:expr, $cb:expr, $buf_index:expr) => {{
+        let offset = $cb.offset as libc::off_t;
+        let iovs = $cb.iovec.as_ptr() as *const libc::iovec;
+        let len = $cb.iovec.len() as u32;
+        match ($cb.opcode, $buf_index) {
+            (OpCode::Preadv, Some(index)) => opcode::ReadFixed::new(
+                $fd,
+                $cb.iovec[0].iov_base as *mut u8,
+                $cb.iovec[0].iov_len as u32,
+                index,
+            )
+            .offset64(offset)
+            .build(),
+            (OpCode::Pwritev, Some(index)) => opcode::WriteFixed::new(
+                $fd,
+                $cb.iovec[0].iov_base as *const u8,
+                $cb.iovec[0].iov_len as u32,
+                index,
+            )
+            .offset64(offset)
+            .build(),
+            (OpCode::Preadv, None) => opcode::Readv::new($fd, iovs, len).offset(offset).build(),
+            (OpCode::Pwritev, None) => opcode::Writev::new($fd, iovs, len).offset(offset).build(),
+            (OpCode::Fdsync, _) => opcode::Fsync::new($fd).build(),
+            _ => bail!("Invalid entry code"),
+        }
+    }};
+}
 
 /// The io-uring context.
 pub(crate) struct IoUringContext {
     ring: IoUring,
     events: Vec<AioEvent>,
+    /// Fds of the fixed files, the position is the index of the fixed file.
+    files: Vec<RawFd>,
+    /// The fixed buffers in use, the position is the index of the fixed buffer.
+    buffers: Vec<Iovec>,
+    /// Whether the fixed buffers are registered to the kernel, they may be stale.
+    buffers_registered: bool,
 }
 
 impl IoUringContext {
@@ -30,19 +75,49 @@ impl IoUringContext {
         IoUring::new(entries).with_context(|| "Failed to create io_uring instance.")
     }
 
-    pub fn new(entries: u32, eventfd: &EventFd) -> Result<Self> {
+    pub fn new(entries: u32, eventfd: &EventFd, config: &IoUringConfig) -> Result<Self> {
         let tmp_entries = entries as i32;
         // Ensure the power of 2.
         if (tmp_entries & -tmp_entries) != tmp_entries || tmp_entries == 0 {
             bail!("Entries must be the power of 2 and larger than 0");
         }
-        let ring = Self::probe(entries)?;
+        let ring = if config.sqpoll {
+            let mut builder = IoUring::builder();
+            builder.setup_sqpoll(config.sqpoll_idle);
+            if let Some(cpu) = config.sqpoll_cpu {
+                builder.setup_sqpoll_cpu(cpu);
+            }
+            builder
+                .build(entries)
+                .with_context(|| "Failed to create io_uring instance with sqpoll.")?
+        } else {
+            Self::probe(entries)?
+        };
 
         ring.submitter()
             .register_eventfd(eventfd.as_raw_fd())
             .with_context(|| "Failed to register event fd")?;
         let events = Vec::with_capacity(entries as usize);
-        Ok(IoUringContext { ring, events })
+        Ok(IoUringContext {
+            ring,
+            events,
+            files: Vec::new(),
+            buffers: Vec::new(),
+            buffers_registered: false,
+        })
+    }
+
+    /// Find the fixed buffer containing the data, only the request with single iovec
+    /// can use the fixed buffer.
+    fn find_buffer(&self, iovec: &[Iovec]) -> Option<u16> {
+        if iovec.len() != 1 || iovec[0].iov_len > u64::from(u32::MAX) {
+            return None;
+        }
+        let (base, len) = (iovec[0].iov_base, iovec[0].iov_len);
+        self.buffers
+            .iter()
+            .position(|buf| base >= buf.iov_base && base + len <= buf.iov_base + buf.iov_len)
+            .map(|index| index as u16)
     }
 }
 
@@ -51,30 +126,16 @@ impl<T: Clone> AioContext<T> for IoUringContext {
         for iocb in iocbp.iter() {
             // SAFETY: iocb is valid until request is finished.
             let cb = unsafe { &*(*iocb) };
-            let offset = cb.offset as libc::off_t;
-            let data = cb.user_data;
-            let len = cb.iovec.len();
-            let iovs = cb.iovec.as_ptr();
-            let fd = types::Fd(cb.file_fd);
-            let entry = match cb.opcode {
-                OpCode::Preadv => opcode::Readv::new(fd, iovs as *const libc::iovec, len as u32)
-                    .offset(offset)
-                    .build()
-                    .flags(squeue::Flags::ASYNC)
-                    .user_data(data),
-                OpCode::Pwritev => opcode::Writev::new(fd, iovs as *const libc::iovec, len as u32)
-                    .offset(offset)
-                    .build()
-                    .flags(squeue::Flags::ASYNC)
-                    .user_data(data),
-                OpCode::Fdsync => opcode::Fsync::new(fd)
-                    .build()
-                    .flags(squeue::Flags::ASYNC)
-                    .user_data(data),
-                _ => {
-                    bail!("Invalid entry code");
-                }
+            let buf_index = match cb.opcode {
+                OpCode::Preadv | OpCode::Pwritev => self.find_buffer(&cb.iovec),
+                _ => None,
             };
+            let entry = match self.files.iter().position(|fd| *fd == cb.file_fd) {
+                Some(index) => build_sqe!(types::Fixed(index as u32), cb, buf_index),
+                None => build_sqe!(types::Fd(cb.file_fd), cb, buf_index),
+            }
+            .flags(squeue::Flags::ASYNC)
+            .user_data(cb.user_data);
             // SAFETY: parameters of the entry are valid until request is finished.
             unsafe {
                 self.ring
@@ -98,4 +159,69 @@ impl<T: Clone> AioContext<T> for IoUringContext {
         }
         &self.events
     }
+
+    fn register_file(&mut self, fd: RawFd) -> Result<()> {
+        if self.files.contains(&fd) {
+            return Ok(());
+        }
+        if self.files.len() >= MAX_FIXED_FILES {
+            bail!("Too many fixed files of io_uring");
+        }
+        let submitter = self.ring.submitter();
+        if !self.files.is_empty() {
+            submitter
+                .unregister_files()
+                .with_context(|| "Failed to unregister fixed files")?;
+        }
+        self.files.push(fd);
+        if let Err(e) = submitter.register_files(&self.files) {
+            self.files.clear();
+            return Err(e).with_context(|| "Failed to register fixed files");
+        }
+        Ok(())
+    }
+
+    fn register_buffers(&mut self, bufs: &[Iovec]) -> Result<()> {
+        self.buffers.clear();
+        let submitter = self.ring.submitter();
+        if self.buffers_registered {
+            submitter
+                .unregister_buffers()
+                .with_context(|| "Failed to unregister fixed buffers")?;
+            self.buffers_registered = false;
+        }
+
+        let mut buffers = Vec::new();
+        for buf in bufs {
+            let mut pos = 0;
+            while pos < buf.iov_len {
+                let len = (buf.iov_len - pos).min(MAX_FIXED_BUFFER_SIZE);
+                buffers.push(Iovec::new(buf.iov_base + pos, len));
+                pos += len;
+            }
+        }
+        if buffers.is_empty() {
+            return Ok(());
+        }
+        if buffers.len() > MAX_FIXED_BUFFERS {
+            bail!("Too many fixed buffers of io_uring: {}", buffers.len());
+        }
+        let iovecs: Vec<libc::iovec> = buffers
+            .iter()
+            .map(|buf| libc::iovec {
+                iov_base: buf.iov_base as *mut libc::c_void,
+                iov_len: buf.iov_len as usize,
+            })
+            .collect();
+        submitter.register_buffers(&iovecs).with_context(|| {
+            "Failed to register fixed buffers, the limit of locked memory may be too small"
+        })?;
+        self.buffers = buffers;
+        self.buffers_registered = true;
+        Ok(())
+    }
+
+    fn invalidate_buffers(&mut self) {
+        self.buffers.clear();
+    }
 }
@@ -11,6 +11,7 @@
 // See the Mulan PSL v2 for more details.
 
 /// We use Leaky Bucket Algorithm to limit iops of block device and qmp.
+use std::cmp;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -24,15 +25,120 @@ use anyhow::Result;
 
 /// Used to improve the accuracy of bucket level.
 const ACCURACY_SCALE: u64 = 1000;
+/// The burst bucket holds the units of 1/BURST_BUCKET_DIVISOR seconds at the max rate.
+const BURST_BUCKET_DIVISOR: u128 = 10;
+
+/// Water level of a leaky bucket, which leaks at the average rate `avg`. If the
+/// burst rate `max` is set, the bucket can hold the units of `max_length` seconds
+/// at the `max` rate, otherwise it holds the units of one second at the `avg` rate.
+/// Zero `avg` means no limit.
+pub struct Bucket {
+    /// Average units per second.
+    avg: u64,
+    /// Burst units per second.
+    max: u64,
+    /// Seconds that the burst rate can last.
+    max_length: u64,
+    /// Current water level.
+    level: u128,
+    /// Current water level of the burst bucket, which leaks at the `max` rate.
+    burst_level: u128,
+    /// Internal used to calculate the leaked units.
+    prev_time: Instant,
+}
+
+impl Bucket {
+    pub fn new(avg: u64, max: u64, max_length: u64) -> Self {
+        Bucket {
+            avg,
+            max,
+            max_length,
+            level: 0,
+            burst_level: 0,
+            prev_time: get_current_time(),
+        }
+    }
+
+    /// Change the limits of the bucket, the current level is kept.
+    pub fn set_limit(&mut self, avg: u64, max: u64, max_length: u64) {
+        self.leak();
+        self.avg = avg;
+        self.max = max;
+        self.max_length = max_length;
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.avg != 0
+    }
+
+    fn capacity(&self) -> u128 {
+        if self.max == 0 {
+            u128::from(self.avg) * u128::from(ACCURACY_SCALE)
+        } else {
+            u128::from(self.max) * u128::from(self.max_length) * u128::from(ACCURACY_SCALE)
+        }
+    }
+
+    fn burst_capacity(&self) -> u128 {
+        u128::from(self.max) * u128::from(ACCURACY_SCALE) / BURST_BUCKET_DIVISOR
+    }
+
+    fn leak(&mut self) {
+        let now = get_current_time();
+        let nanos = (now - self.prev_time).as_nanos();
+        let ns_per_sec = u128::from(NANOSECONDS_PER_SECOND);
+        let leaked = nanos * u128::from(self.avg) * u128::from(ACCURACY_SCALE) / ns_per_sec;
+        self.level = self.level.saturating_sub(leaked);
+        let leaked = nanos * u128::from(self.max) * u128::from(ACCURACY_SCALE) / ns_per_sec;
+        self.burst_level = self.burst_level.saturating_sub(leaked);
+        self.prev_time = now;
+    }
+
+    /// Return the time to wait before the bucket can accept more units, None if
+    /// the bucket is not full.
+    pub fn wait_time(&mut self) -> Option<Duration> {
+        if !self.is_limited() {
+            return None;
+        }
+        self.leak();
+
+        let ns_per_sec = u128::from(NANOSECONDS_PER_SECOND);
+        let scale = u128::from(ACCURACY_SCALE);
+        let mut wait = 0;
+        let capacity = self.capacity();
+        if self.level > capacity {
+            wait = (self.level - capacity) * ns_per_sec / (u128::from(self.avg) * scale);
+        }
+        let burst_capacity = self.burst_capacity();
+        if self.max != 0 && self.burst_level > burst_capacity {
+            let burst_wait =
+                (self.burst_level - burst_capacity) * ns_per_sec / (u128::from(self.max) * scale);
+            wait = cmp::max(wait, burst_wait);
+        }
+
+        if wait == 0 {
+            return None;
+        }
+        Some(Duration::from_nanos(wait as u64))
+    }
+
+    /// Put `units` into the bucket.
+    pub fn consume(&mut self, units: u64) {
+        if !self.is_limited() {
+            return;
+        }
+        let units = u128::from(units) * u128::from(ACCURACY_SCALE);
+        self.level += units;
+        if self.max != 0 {
+            self.burst_level += units;
+        }
+    }
+}
 
 /// Structure used to describe a Leaky Bucket.
 pub struct LeakBucket {
-    /// Indicate the capacity of bucket, which is config by user.
-    capacity: u64,
-    /// Current water level.
-    level: u64,
-    /// Internal used to calculate the delay of timer.
-    prev_time: Instant,
+    /// Water level of the bucket, the capacity is config by user.
+    bucket: Bucket,
     /// Indicate whether the timer started.
     timer_started: bool,
     /// When bucket is ready for allowing more IO operation, the internal callback will write this FD.
@@ -48,9 +154,7 @@ impl LeakBucket {
     /// * `units_ps` - units per second.
     pub fn new(units_ps: u64) -> Result<Self> {
         Ok(LeakBucket {
-            capacity: units_ps * ACCURACY_SCALE,
-            level: 0,
-            prev_time: get_current_time(),
+            bucket: Bucket::new(units_ps, 0, 0),
             timer_started: false,
             timer_wakeup: Arc::new(EventFd::new(libc::EFD_NONBLOCK)?),
         })
@@ -64,26 +168,15 @@ impl LeakBucket {
     /// * `loop_context` - used for delay function call.
     pub fn throttled(&mut self, loop_context: &mut EventLoopContext, need_units: u64) -> bool {
         // capacity value is zero, indicating that there is no need to limit
-        if self.capacity == 0 {
+        if !self.bucket.is_limited() {
             return false;
         }
         if self.timer_started {
             return true;
         }
 
-        // update the water level
-        let now = get_current_time();
-        let nanos = (now - self.prev_time).as_nanos();
-        if nanos > (self.level * NANOSECONDS_PER_SECOND / self.capacity) as u128 {
-            self.level = 0;
-        } else {
-            self.level -= nanos as u64 * self.capacity / NANOSECONDS_PER_SECOND;
-        }
-
-        self.prev_time = now;
-
         // need to be throttled
-        if self.level > self.capacity {
+        if let Some(delay) = self.bucket.wait_time() {
             let wakeup_clone = self.timer_wakeup.clone();
             let func = Box::new(move || {
                 wakeup_clone
@@ -91,19 +184,14 @@ impl LeakBucket {
                     .unwrap_or_else(|e| error!("LeakBucket send event to device failed {:?}", e));
             });
 
-            loop_context.delay_call(
-                func,
-                Duration::from_nanos(
-                    (self.level - self.capacity) * NANOSECONDS_PER_SECOND / self.capacity,
-                ),
-            );
+            loop_context.delay_call(func, delay);
 
             self.timer_started = true;
 
             return true;
         }
 
-        self.level += need_units * ACCURACY_SCALE;
+        self.bucket.consume(need_units);
 
         false
     }
@@ -10,6 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+pub mod af_xdp;
 pub mod aio;
 pub mod arg_parser;
 pub mod bitmap;
@@ -25,12 +26,15 @@ pub mod leak_bucket;
 mod link_list;
 pub mod logger;
 pub mod loop_context;
+pub mod net_backend;
 pub mod num_ops;
 pub mod offsetof;
+pub mod pcapng;
 #[cfg(not(target_env = "musl"))]
 pub mod pixman;
 pub mod reader;
 pub mod seccomp;
+pub mod slirp;
 pub mod syscall;
 pub mod tap;
 pub mod test_helper;
@@ -0,0 +1,707 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Backends of virtio-net, which exchange the ethernet frames prefixed with the
+//! virtio net header with host.
+
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+
+use crate::aio::{iov_from_buf_direct, iov_to_buf_direct, Iovec};
+use crate::tap::Tap;
+
+/// Max length of the ethernet frame exchanged by socket backends.
+const MAX_FRAME_LEN: usize = 65536;
+/// Length of the prefix of the frame in stream socket.
+const STREAM_LEN_PREFIX: usize = 4;
+/// Offset of `num_buffers` in virtio net header.
+const NUM_BUFFERS_OFFSET: usize = 10;
+
+pub trait NetBackend: AsRawFd + Send + Sync {
+    /// Receive one frame into `iovecs`, and return its length including the virtio
+    /// net header. `WouldBlock` is returned if there is no frame to receive.
+    fn recv_frame(&mut self, iovecs: &[libc::iovec]) -> IoResult<usize>;
+
+    /// Send the frame in `iovecs`. `WouldBlock` is returned if the frame needs to
+    /// be sent again later.
+    fn send_frame(&mut self, iovecs: &[libc::iovec]) -> IoResult<usize>;
+
+    /// Get another handle of the backend which shares the same connection.
+    fn try_clone(&self) -> IoResult<Box<dyn NetBackend>>;
+}
+
+impl AsRawFd for Tap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl NetBackend for Tap {
+    fn recv_frame(&mut self, iovecs: &[libc::iovec]) -> IoResult<usize> {
+        // SAFETY: the iovecs point to the memory of guest which has been checked.
+        let size = unsafe {
+            libc::readv(
+                self.file.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as libc::c_int,
+            )
+        };
+        if size >= 0 {
+            return Ok(size as usize);
+        }
+
+        let e = IoError::last_os_error();
+        if e.kind() != ErrorKind::WouldBlock {
+            // If the backend tap device is removed, readv returns less than 0.
+            // At this time, the content in the tap needs to be cleaned up.
+            // Here, read is called to process, otherwise handle_rx may be triggered all the time.
+            let mut buf = [0; 1024];
+            match self.read(&mut buf) {
+                Ok(cnt) => error!("Failed to call readv but tap read is ok: cnt {}", cnt),
+                // When the backend tap device is abnormally removed, read return EBADFD.
+                Err(e) => error!("Failed to read tap: {:?}", e),
+            }
+        }
+        Err(e)
+    }
+
+    fn send_frame(&mut self, iovecs: &[libc::iovec]) -> IoResult<usize> {
+        loop {
+            // SAFETY: the iovecs point to the memory of guest which has been checked.
+            let size = unsafe {
+                libc::writev(
+                    self.file.as_raw_fd(),
+                    iovecs.as_ptr(),
+                    iovecs.len() as libc::c_int,
+                )
+            };
+            if size >= 0 {
+                return Ok(size as usize);
+            }
+            let e = IoError::last_os_error();
+            if e.kind() != ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    fn try_clone(&self) -> IoResult<Box<dyn NetBackend>> {
+        Ok(Box::new(Tap {
+            file: self.file.try_clone()?,
+        }))
+    }
+}
+
+pub(crate) fn to_iovecs(iovecs: &[libc::iovec]) -> Vec<Iovec> {
+    iovecs
+        .iter()
+        .map(|iov| Iovec::new(iov.iov_base as u64, iov.iov_len as u64))
+        .collect()
+}
+
+/// Virtio net header without any offload, whose frame uses one buffer.
+pub(crate) fn empty_vnet_hdr(vnet_hdr_len: usize) -> Vec<u8> {
+    let mut hdr = vec![0_u8; vnet_hdr_len];
+    if vnet_hdr_len >= NUM_BUFFERS_OFFSET + 2 {
+        LittleEndian::write_u16(&mut hdr[NUM_BUFFERS_OFFSET..], 1);
+    }
+    hdr
+}
+
+/// Get the frame without virtio net header from `iovecs`.
+fn gather_frame(iovecs: &[libc::iovec], vnet_hdr_len: usize) -> IoResult<Vec<u8>> {
+    let iovecs = to_iovecs(iovecs);
+    let len = iovecs.iter().map(|iov| iov.iov_len as usize).sum::<usize>();
+    if len < vnet_hdr_len {
+        return Err(IoError::new(ErrorKind::InvalidInput, "Frame is too short"));
+    }
+    let mut buf = vec![0_u8; len];
+    iov_to_buf_direct(&iovecs, &mut buf).map_err(|e| IoError::other(e.to_string()))?;
+    buf.drain(..vnet_hdr_len);
+    Ok(buf)
+}
+
+/// Write the frame into `iovecs` after an empty virtio net header, return the
+/// length written. The frame is truncated if `iovecs` is too small.
+fn scatter_frame(iovecs: &[libc::iovec], vnet_hdr_len: usize, frame: &[u8]) -> IoResult<usize> {
+    let mut buf = empty_vnet_hdr(vnet_hdr_len);
+    buf.extend_from_slice(frame);
+    iov_from_buf_direct(&to_iovecs(iovecs), &buf).map_err(|e| IoError::other(e.to_string()))
+}
+
+/// Address of datagram socket, `ip:port` for udp and the path for unix socket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DgramAddr {
+    Udp(SocketAddr),
+    Unix(String),
+}
+
+impl FromStr for DgramAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(addr: &str) -> Result<Self> {
+        if addr.is_empty() {
+            bail!("Empty address of datagram socket");
+        }
+        Ok(match SocketAddr::from_str(addr) {
+            Ok(addr) => DgramAddr::Udp(addr),
+            Err(_) => DgramAddr::Unix(addr.to_string()),
+        })
+    }
+}
+
+/// Config of the netdev connected to other VMs by socket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetSocketConfig {
+    /// Unix stream socket, which listens on the path if `server` is set. Each frame
+    /// is prefixed with its length of 4 bytes in network byte order.
+    Stream { path: String, server: bool },
+    /// Udp or unix datagram socket, each datagram carries one frame.
+    Dgram { local: DgramAddr, remote: DgramAddr },
+}
+
+impl NetSocketConfig {
+    pub fn check(&self) -> Result<()> {
+        if let NetSocketConfig::Dgram { local, remote } = self {
+            match (local, remote) {
+                (DgramAddr::Udp(_), DgramAddr::Udp(_))
+                | (DgramAddr::Unix(_), DgramAddr::Unix(_)) => {}
+                _ => bail!("Local and remote address of dgram netdev should be the same type"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Path of the unix socket created on host, which should be removed at exit.
+    pub fn socket_path(&self) -> Option<&str> {
+        match self {
+            NetSocketConfig::Stream { path, server: true } => Some(path),
+            NetSocketConfig::Dgram {
+                local: DgramAddr::Unix(path),
+                ..
+            } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// Create the backend with the socket config, `vnet_hdr_len` is the length of virtio
+/// net header before each frame of the guest.
+pub fn create_socket_backend(
+    config: &NetSocketConfig,
+    vnet_hdr_len: usize,
+) -> Result<Box<dyn NetBackend>> {
+    config.check()?;
+    Ok(match config {
+        NetSocketConfig::Stream { path, server } => {
+            Box::new(StreamBackend::new(path, *server, vnet_hdr_len)?)
+        }
+        NetSocketConfig::Dgram { local, remote } => {
+            Box::new(DgramBackend::new(local, remote, vnet_hdr_len)?)
+        }
+    })
+}
+
+struct StreamState {
+    listener: Option<UnixListener>,
+    conn: Option<UnixStream>,
+    /// The frame being received, including the length prefix.
+    rx_buf: Vec<u8>,
+    /// The frame not sent completely, including the length prefix.
+    tx_buf: Vec<u8>,
+}
+
+/// Backend of unix stream socket. Only one peer is connected at a time, and the
+/// server accepts the next peer after the current one disconnects.
+pub struct StreamBackend {
+    /// Epoll of the listener and the connection, which is polled by the event loop,
+    /// as the connection changes when the peer reconnects.
+    epoll: Arc<Epoll>,
+    vnet_hdr_len: usize,
+    state: Arc<Mutex<StreamState>>,
+}
+
+impl StreamBackend {
+    fn new(path: &str, server: bool, vnet_hdr_len: usize) -> Result<Self> {
+        let epoll = Epoll::new().with_context(|| "Failed to create epoll of stream netdev")?;
+        let mut state = StreamState {
+            listener: None,
+            conn: None,
+            rx_buf: Vec::new(),
+            tx_buf: Vec::new(),
+        };
+        if server {
+            let listener = UnixListener::bind(path).with_context(|| {
+                format!("Failed to bind socket for stream netdev, path:{}", path)
+            })?;
+            listener.set_nonblocking(true)?;
+            epoll.ctl(
+                ControlOperation::Add,
+                listener.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, listener.as_raw_fd() as u64),
+            )?;
+            state.listener = Some(listener);
+        } else {
+            let conn = UnixStream::connect(path).with_context(|| {
+                format!("Failed to connect socket for stream netdev, path:{}", path)
+            })?;
+            Self::add_conn(&epoll, &mut state, conn)?;
+        }
+
+        Ok(StreamBackend {
+            epoll: Arc::new(epoll),
+            vnet_hdr_len,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    fn add_conn(epoll: &Epoll, state: &mut StreamState, conn: UnixStream) -> IoResult<()> {
+        conn.set_nonblocking(true)?;
+        epoll.ctl(
+            ControlOperation::Add,
+            conn.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, conn.as_raw_fd() as u64),
+        )?;
+        state.conn = Some(conn);
+        Ok(())
+    }
+
+    /// Accept the pending peers, only the first one is kept if not connected.
+    fn accept(&self, state: &mut StreamState) {
+        while let Some(listener) = &state.listener {
+            match listener.accept() {
+                Ok((conn, _)) => {
+                    if state.conn.is_some() {
+                        warn!("Stream netdev is connected, the new peer is refused");
+                    } else if let Err(e) = Self::add_conn(&self.epoll, state, conn) {
+                        error!("Failed to add the peer of stream netdev: {:?}", e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("Failed to accept the peer of stream netdev: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn disconnect(&self, state: &mut StreamState) {
+        if let Some(conn) = state.conn.take() {
+            if let Err(e) = self.epoll.ctl(
+                ControlOperation::Delete,
+                conn.as_raw_fd(),
+                EpollEvent::default(),
+            ) {
+                error!("Failed to delete the peer of stream netdev: {:?}", e);
+            }
+            warn!("The peer of stream netdev is disconnected");
+        }
+        state.rx_buf.clear();
+        state.tx_buf.clear();
+    }
+
+    /// Send the rest of the frame in `tx_buf`.
+    fn flush(&self, state: &mut StreamState) -> IoResult<()> {
+        while !state.tx_buf.is_empty() {
+            let conn = match state.conn.as_mut() {
+                Some(conn) => conn,
+                None => return Ok(()),
+            };
+            match conn.write(&state.tx_buf) {
+                Ok(0) => self.disconnect(state),
+                Ok(len) => {
+                    state.tx_buf.drain(..len);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Err(e),
+                Err(_) => self.disconnect(state),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for StreamBackend {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+impl NetBackend for StreamBackend {
+    fn recv_frame(&mut self, iovecs: &[libc::iovec]) -> IoResult<usize> {
+        let mut locked_state = self.state.lock().unwrap();
+        let state = &mut *locked_state;
+        self.accept(state);
+        loop {
+            let conn = match state.conn.as_mut() {
+                Some(conn) => conn,
+                None => return Err(ErrorKind::WouldBlock.into()),
+            };
+            let need = if state.rx_buf.len() < STREAM_LEN_PREFIX {
+                STREAM_LEN_PREFIX
+            } else {
+                STREAM_LEN_PREFIX + BigEndian::read_u32(&state.rx_buf) as usize
+            };
+            if need > STREAM_LEN_PREFIX && state.rx_buf.len() == need {
+                let ret = scatter_frame(
+                    iovecs,
+                    self.vnet_hdr_len,
+                    &state.rx_buf[STREAM_LEN_PREFIX..],
+                );
+                state.rx_buf.clear();
+                return ret;
+            }
+
+            let start = state.rx_buf.len();
+            state.rx_buf.resize(need, 0);
+            match conn.read(&mut state.rx_buf[start..]) {
+                Ok(0) => {
+                    self.disconnect(state);
+                    self.accept(state);
+                }
+                Ok(len) => {
+                    state.rx_buf.truncate(start + len);
+                    if state.rx_buf.len() != STREAM_LEN_PREFIX {
+                        continue;
+                    }
+                    match BigEndian::read_u32(&state.rx_buf) as usize {
+                        0 => state.rx_buf.clear(),
+                        len if len > MAX_FRAME_LEN => {
+                            error!("Invalid frame length {} of stream netdev", len);
+                            self.disconnect(state);
+                            self.accept(state);
+                        }
+                        _ => {}
+                    }
+                }
+                Err(e) => {
+                    state.rx_buf.truncate(start);
+                    match e.kind() {
+                        ErrorKind::Interrupted => {}
+                        ErrorKind::WouldBlock => return Err(e),
+                        _ => {
+                            self.disconnect(state);
+                            self.accept(state);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn send_frame(&mut self, iovecs: &[libc::iovec]) -> IoResult<usize> {
+        let mut locked_state = self.state.lock().unwrap();
+        let state = &mut *locked_state;
+        self.accept(state);
+        // The frame is dropped if no peer is connected.
+        if state.conn.is_none() {
+            return Ok(0);
+        }
+        self.flush(state)?;
+
+        let frame = gather_frame(iovecs, self.vnet_hdr_len)?;
+        let mut prefix = [0_u8; STREAM_LEN_PREFIX];
+        BigEndian::write_u32(&mut prefix, frame.len() as u32);
+        state.tx_buf.extend_from_slice(&prefix);
+        state.tx_buf.extend_from_slice(&frame);
+        match self.flush(state) {
+            // The rest of the frame is sent before the next one.
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(frame.len()),
+            ret => ret.map(|_| frame.len()),
+        }
+    }
+
+    fn try_clone(&self) -> IoResult<Box<dyn NetBackend>> {
+        Ok(Box::new(StreamBackend {
+            epoll: self.epoll.clone(),
+            vnet_hdr_len: self.vnet_hdr_len,
+            state: self.state.clone(),
+        }))
+    }
+}
+
+enum DgramSocket {
+    Udp(UdpSocket, SocketAddr),
+    Unix(UnixDatagram, String),
+}
+
+/// Backend of udp or unix datagram socket, which sends the frames to the remote
+/// address and receives the frames from any peer.
+pub struct DgramBackend {
+    socket: DgramSocket,
+    vnet_hdr_len: usize,
+}
+
+impl DgramBackend {
+    fn new(local: &DgramAddr, remote: &DgramAddr, vnet_hdr_len: usize) -> Result<Self> {
+        let socket = match (local, remote) {
+            (DgramAddr::Udp(local), DgramAddr::Udp(remote)) => {
+                let socket = UdpSocket::bind(local).with_context(|| {
+                    format!("Failed to bind socket for dgram netdev, addr:{}", local)
+                })?;
+                socket.set_nonblocking(true)?;
+                DgramSocket::Udp(socket, *remote)
+            }
+            (DgramAddr::Unix(local), DgramAddr::Unix(remote)) => {
+                let socket = UnixDatagram::bind(local).with_context(|| {
+                    format!("Failed to bind socket for dgram netdev, path:{}", local)
+                })?;
+                socket.set_nonblocking(true)?;
+                DgramSocket::Unix(socket, remote.clone())
+            }
+            _ => bail!("Local and remote address of dgram netdev should be the same type"),
+        };
+        Ok(DgramBackend {
+            socket,
+            vnet_hdr_len,
+        })
+    }
+}
+
+impl AsRawFd for DgramBackend {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.socket {
+            DgramSocket::Udp(socket, _) => socket.as_raw_fd(),
+            DgramSocket::Unix(socket, _) => socket.as_raw_fd(),
+        }
+    }
+}
+
+impl NetBackend for DgramBackend {
+    fn recv_frame(&mut self, iovecs: &[libc::iovec]) -> IoResult<usize> {
+        let mut buf = vec![0_u8; MAX_FRAME_LEN];
+        loop {
+            let ret = match &self.socket {
+                DgramSocket::Udp(socket, remote) => match socket.recv_from(&mut buf) {
+                    // Drop the datagrams which are not sent by the remote.
+                    Ok((_, addr)) if addr != *remote => continue,
+                    ret => ret.map(|(len, _)| len),
+                },
+                DgramSocket::Unix(socket, _) => socket.recv(&mut buf),
+            };
+            match ret {
+                Ok(len) => return scatter_frame(iovecs, self.vnet_hdr_len, &buf[..len]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn send_frame(&mut self, iovecs: &[libc::iovec]) -> IoResult<usize> {
+        let frame = gather_frame(iovecs, self.vnet_hdr_len)?;
+        loop {
+            let ret = match &self.socket {
+                DgramSocket::Udp(socket, remote) => socket.send_to(&frame, remote),
+                DgramSocket::Unix(socket, remote) => socket.send_to(&frame, remote),
+            };
+            match ret {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // The frame is dropped if the remote is not ready, as on the real network.
+                Err(e)
+                    if e.kind() == ErrorKind::ConnectionRefused
+                        || e.kind() == ErrorKind::NotFound =>
+                {
+                    return Ok(0)
+                }
+                ret => return ret,
+            }
+        }
+    }
+
+    fn try_clone(&self) -> IoResult<Box<dyn NetBackend>> {
+        let socket = match &self.socket {
+            DgramSocket::Udp(socket, remote) => DgramSocket::Udp(socket.try_clone()?, *remote),
+            DgramSocket::Unix(socket, remote) => {
+                DgramSocket::Unix(socket.try_clone()?, remote.clone())
+            }
+        };
+        Ok(Box::new(DgramBackend {
+            socket,
+            vnet_hdr_len: self.vnet_hdr_len,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VNET_HDR_LEN: usize = 12;
+
+    fn iovec(buf: &mut [u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }
+
+    fn send(backend: &mut dyn NetBackend, frame: &[u8]) -> IoResult<usize> {
+        // Split the header and the frame into different iovecs.
+        let mut hdr = [0xff_u8; VNET_HDR_LEN + 2];
+        let mut data = frame[2..].to_vec();
+        hdr[VNET_HDR_LEN..].copy_from_slice(&frame[..2]);
+        backend.send_frame(&[iovec(&mut hdr), iovec(&mut data)])
+    }
+
+    fn recv(backend: &mut dyn NetBackend) -> IoResult<Vec<u8>> {
+        let mut hdr = [0xff_u8; 8];
+        let mut data = [0_u8; 2048];
+        let len = backend.recv_frame(&[iovec(&mut hdr), iovec(&mut data)])?;
+        let mut frame = hdr.to_vec();
+        frame.extend_from_slice(&data[..len - hdr.len()]);
+        assert_eq!(&frame[..NUM_BUFFERS_OFFSET], &[0; NUM_BUFFERS_OFFSET]);
+        assert_eq!(&frame[NUM_BUFFERS_OFFSET..VNET_HDR_LEN], &[1, 0]);
+        Ok(frame[VNET_HDR_LEN..].to_vec())
+    }
+
+    fn recv_wait(backend: &mut dyn NetBackend) -> Vec<u8> {
+        for _ in 0..100 {
+            match recv(backend) {
+                Ok(frame) => return frame,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    std::thread::sleep(std::time::Duration::from_millis(10))
+                }
+                Err(e) => panic!("Failed to receive frame: {:?}", e),
+            }
+        }
+        panic!("No frame is received");
+    }
+
+    #[test]
+    fn test_dgram_addr() {
+        assert_eq!(
+            DgramAddr::from_str("127.0.0.1:5000").unwrap(),
+            DgramAddr::Udp("127.0.0.1:5000".parse().unwrap())
+        );
+        assert_eq!(
+            DgramAddr::from_str("/tmp/net.sock").unwrap(),
+            DgramAddr::Unix("/tmp/net.sock".to_string())
+        );
+        assert!(DgramAddr::from_str("").is_err());
+        let config = NetSocketConfig::Dgram {
+            local: DgramAddr::from_str("127.0.0.1:5000").unwrap(),
+            remote: DgramAddr::from_str("/tmp/net.sock").unwrap(),
+        };
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn test_stream_backend() {
+        let path = format!("/tmp/test_stream_netdev_{}.sock", std::process::id());
+        let server_cfg = NetSocketConfig::Stream {
+            path: path.clone(),
+            server: true,
+        };
+        let mut server = create_socket_backend(&server_cfg, VNET_HDR_LEN).unwrap();
+        // Frames are dropped without peer.
+        assert_eq!(send(server.as_mut(), b"dropped").unwrap(), 0);
+        assert_eq!(
+            recv(server.as_mut()).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+
+        let client_cfg = NetSocketConfig::Stream {
+            path: path.clone(),
+            server: false,
+        };
+        let mut client = create_socket_backend(&client_cfg, VNET_HDR_LEN).unwrap();
+        let frame1 = vec![0x5a_u8; 1500];
+        let frame2 = b"second frame".to_vec();
+        assert_eq!(send(client.as_mut(), &frame1).unwrap(), frame1.len());
+        assert_eq!(send(client.as_mut(), &frame2).unwrap(), frame2.len());
+        let mut cloned = server.try_clone().unwrap();
+        assert_eq!(recv_wait(cloned.as_mut()), frame1);
+        assert_eq!(recv_wait(server.as_mut()), frame2);
+        assert_eq!(send(server.as_mut(), &frame2).unwrap(), frame2.len());
+        assert_eq!(recv_wait(client.as_mut()), frame2);
+
+        // The server accepts the next peer after the current one disconnects.
+        drop(client);
+        assert_eq!(
+            recv(server.as_mut()).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        let mut client = create_socket_backend(&client_cfg, VNET_HDR_LEN).unwrap();
+        assert_eq!(send(client.as_mut(), &frame1).unwrap(), frame1.len());
+        assert_eq!(recv_wait(server.as_mut()), frame1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_dgram_backend() {
+        let socket1 = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket2 = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr1 = DgramAddr::Udp(socket1.local_addr().unwrap());
+        let addr2 = DgramAddr::Udp(socket2.local_addr().unwrap());
+        drop((socket1, socket2));
+
+        let mut backend1 = create_socket_backend(
+            &NetSocketConfig::Dgram {
+                local: addr1.clone(),
+                remote: addr2.clone(),
+            },
+            VNET_HDR_LEN,
+        )
+        .unwrap();
+        let mut backend2 = create_socket_backend(
+            &NetSocketConfig::Dgram {
+                local: addr2,
+                remote: addr1,
+            },
+            VNET_HDR_LEN,
+        )
+        .unwrap();
+        let frame = vec![0xa5_u8; 1000];
+        assert_eq!(send(backend1.as_mut(), &frame).unwrap(), frame.len());
+        assert_eq!(recv_wait(backend2.as_mut()), frame);
+        assert_eq!(send(backend2.as_mut(), b"reply").unwrap(), 5);
+        assert_eq!(recv_wait(backend1.as_mut()), b"reply");
+
+        let path1 = format!("/tmp/test_dgram_netdev1_{}.sock", std::process::id());
+        let path2 = format!("/tmp/test_dgram_netdev2_{}.sock", std::process::id());
+        let mut backend1 = create_socket_backend(
+            &NetSocketConfig::Dgram {
+                local: DgramAddr::Unix(path1.clone()),
+                remote: DgramAddr::Unix(path2.clone()),
+            },
+            VNET_HDR_LEN,
+        )
+        .unwrap();
+        // The frame is dropped before the remote is ready.
+        assert_eq!(send(backend1.as_mut(), &frame).unwrap(), 0);
+        let mut backend2 = create_socket_backend(
+            &NetSocketConfig::Dgram {
+                local: DgramAddr::Unix(path2.clone()),
+                remote: DgramAddr::Unix(path1.clone()),
+            },
+            VNET_HDR_LEN,
+        )
+        .unwrap();
+        assert_eq!(send(backend1.as_mut(), &frame).unwrap(), frame.len());
+        assert_eq!(recv_wait(backend2.as_mut()), frame);
+        std::fs::remove_file(path1).unwrap();
+        std::fs::remove_file(path2).unwrap();
+    }
+}
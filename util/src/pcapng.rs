@@ -0,0 +1,261 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Writer of the ethernet frames captured into pcapng file, which can be read by
+//! tcpdump and wireshark.
+//!
+//! Each file has one section with one interface, and each frame is written as an
+//! enhanced packet block with its direction. The file is rotated to `<path>.1`,
+//! `<path>.2`, ... when it's larger than the max size.
+
+use std::fs::{remove_file, rename, File, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const LINKTYPE_ETHERNET: u16 = 1;
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+/// Length of the section header block and the interface description block.
+const FILE_HEADER_LEN: u64 = 28 + 20;
+/// Length of the enhanced packet block without packet data.
+const PACKET_BLOCK_LEN: usize = 28 + 12 + 4;
+/// The default max length of the captured data of each frame.
+pub const DEFAULT_SNAPLEN: u32 = 65535;
+/// The default number of files kept when the file is rotated.
+pub const DEFAULT_MAX_FILES: u32 = 2;
+
+/// The direction of the captured frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketDirection {
+    Inbound,
+    Outbound,
+}
+
+impl PacketDirection {
+    /// The direction bits of the flags option of enhanced packet block.
+    fn flags(self) -> u32 {
+        match self {
+            PacketDirection::Inbound => 1,
+            PacketDirection::Outbound => 2,
+        }
+    }
+}
+
+pub struct PcapngWriter {
+    path: String,
+    file: File,
+    /// The written bytes of current file.
+    size: u64,
+    /// The max length of the captured data of each frame.
+    snaplen: u32,
+    /// The max size of each file, 0 means unlimited.
+    max_size: u64,
+    /// The number of files kept when the file is rotated, including current one.
+    max_files: u32,
+}
+
+impl PcapngWriter {
+    pub fn new(path: &str, snaplen: u32, max_size: u64, max_files: u32) -> Result<Self> {
+        if snaplen == 0 {
+            bail!("The snap length of capture should not be 0");
+        }
+        if max_size != 0 && max_size < FILE_HEADER_LEN + PACKET_BLOCK_LEN as u64 {
+            bail!("The max size {} of capture file is too small", max_size);
+        }
+        if max_files == 0 {
+            bail!("The number of capture files should not be 0");
+        }
+        Ok(PcapngWriter {
+            path: path.to_string(),
+            file: Self::create_file(path, snaplen)?,
+            size: FILE_HEADER_LEN,
+            snaplen,
+            max_size,
+            max_files,
+        })
+    }
+
+    pub fn snaplen(&self) -> u32 {
+        self.snaplen
+    }
+
+    /// Write the frame whose original length is `orig_len`, `data` is truncated
+    /// to the snap length.
+    pub fn write_packet(
+        &mut self,
+        data: &[u8],
+        orig_len: usize,
+        direction: PacketDirection,
+    ) -> Result<()> {
+        let data = &data[..data.len().min(self.snaplen as usize)];
+        let padded_len = (data.len() + 3) & !3;
+        let block_len = PACKET_BLOCK_LEN + padded_len;
+        if self.max_size != 0 && self.size + block_len as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_micros() as u64);
+        let mut block = vec![0_u8; block_len];
+        LittleEndian::write_u32(&mut block[0..4], ENHANCED_PACKET_BLOCK);
+        LittleEndian::write_u32(&mut block[4..8], block_len as u32);
+        // Interface id is 0.
+        LittleEndian::write_u32(&mut block[12..16], (timestamp >> 32) as u32);
+        LittleEndian::write_u32(&mut block[16..20], timestamp as u32);
+        LittleEndian::write_u32(&mut block[20..24], data.len() as u32);
+        LittleEndian::write_u32(&mut block[24..28], orig_len as u32);
+        block[28..28 + data.len()].copy_from_slice(data);
+        let opts = &mut block[28 + padded_len..];
+        LittleEndian::write_u16(&mut opts[0..2], OPT_EPB_FLAGS);
+        LittleEndian::write_u16(&mut opts[2..4], 4);
+        LittleEndian::write_u32(&mut opts[4..8], direction.flags());
+        LittleEndian::write_u16(&mut opts[8..10], OPT_ENDOFOPT);
+        LittleEndian::write_u32(&mut opts[12..16], block_len as u32);
+
+        self.file
+            .write_all(&block)
+            .with_context(|| format!("Failed to write capture file {}", self.path))?;
+        self.size += block_len as u64;
+        Ok(())
+    }
+
+    /// Create the file with the section header block and the interface description
+    /// block.
+    fn create_file(path: &str, snaplen: u32) -> Result<File> {
+        let mut header = [0_u8; FILE_HEADER_LEN as usize];
+        let (shb, idb) = header.split_at_mut(28);
+        LittleEndian::write_u32(&mut shb[0..4], SECTION_HEADER_BLOCK);
+        LittleEndian::write_u32(&mut shb[4..8], 28);
+        LittleEndian::write_u32(&mut shb[8..12], BYTE_ORDER_MAGIC);
+        // Version 1.0.
+        LittleEndian::write_u16(&mut shb[12..14], 1);
+        // The length of section is unknown.
+        LittleEndian::write_i64(&mut shb[16..24], -1);
+        LittleEndian::write_u32(&mut shb[24..28], 28);
+        LittleEndian::write_u32(&mut idb[0..4], INTERFACE_DESCRIPTION_BLOCK);
+        LittleEndian::write_u32(&mut idb[4..8], 20);
+        LittleEndian::write_u16(&mut idb[8..10], LINKTYPE_ETHERNET);
+        LittleEndian::write_u32(&mut idb[12..16], snaplen);
+        LittleEndian::write_u32(&mut idb[16..20], 20);
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| format!("Failed to create capture file {}", path))?;
+        file.write_all(&header)
+            .with_context(|| format!("Failed to write capture file {}", path))?;
+        Ok(file)
+    }
+
+    /// Move the file `<path>.N` to `<path>.N+1` and the current file to `<path>.1`,
+    /// the oldest one is removed, then start a new file.
+    fn rotate(&mut self) -> Result<()> {
+        let rotated = |index: u32| format!("{}.{}", self.path, index);
+        if self.max_files > 1 {
+            // The oldest file may not exist.
+            let _ = remove_file(rotated(self.max_files - 1));
+            for index in (1..self.max_files - 1).rev() {
+                let _ = rename(rotated(index), rotated(index + 1));
+            }
+            rename(&self.path, rotated(1))
+                .with_context(|| format!("Failed to rotate capture file {}", self.path))?;
+        }
+        self.file = Self::create_file(&self.path, self.snaplen)?;
+        self.size = FILE_HEADER_LEN;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{metadata, read};
+
+    use super::*;
+
+    #[test]
+    fn test_pcapng_writer() {
+        let path = "/tmp/test_pcapng_writer.pcapng";
+        assert!(PcapngWriter::new(path, 0, 0, 1).is_err());
+        assert!(PcapngWriter::new(path, 64, 0, 0).is_err());
+        assert!(PcapngWriter::new(path, 64, 32, 1).is_err());
+
+        let mut writer = PcapngWriter::new(path, 16, 0, 1).unwrap();
+        writer
+            .write_packet(&[0xa5; 60], 60, PacketDirection::Outbound)
+            .unwrap();
+        let content = read(path).unwrap();
+        assert_eq!(
+            content.len(),
+            FILE_HEADER_LEN as usize + PACKET_BLOCK_LEN + 16
+        );
+        assert_eq!(LittleEndian::read_u32(&content[0..4]), SECTION_HEADER_BLOCK);
+        assert_eq!(LittleEndian::read_u32(&content[8..12]), BYTE_ORDER_MAGIC);
+        assert_eq!(LittleEndian::read_u16(&content[36..38]), LINKTYPE_ETHERNET);
+        assert_eq!(LittleEndian::read_u32(&content[40..44]), 16);
+        let epb = &content[FILE_HEADER_LEN as usize..];
+        assert_eq!(LittleEndian::read_u32(&epb[0..4]), ENHANCED_PACKET_BLOCK);
+        assert_eq!(LittleEndian::read_u32(&epb[4..8]) as usize, epb.len());
+        // Captured length and original length.
+        assert_eq!(LittleEndian::read_u32(&epb[20..24]), 16);
+        assert_eq!(LittleEndian::read_u32(&epb[24..28]), 60);
+        assert_eq!(epb[28..44], [0xa5; 16]);
+        assert_eq!(LittleEndian::read_u32(&epb[48..52]), 2);
+        assert_eq!(
+            LittleEndian::read_u32(&epb[epb.len() - 4..]) as usize,
+            epb.len()
+        );
+
+        // The data is padded to 4 bytes.
+        let mut writer = PcapngWriter::new(path, 64, 0, 1).unwrap();
+        writer
+            .write_packet(&[0x5a; 13], 13, PacketDirection::Inbound)
+            .unwrap();
+        let epb_len = PACKET_BLOCK_LEN + 16;
+        assert_eq!(
+            metadata(path).unwrap().len(),
+            FILE_HEADER_LEN + epb_len as u64
+        );
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pcapng_rotate() {
+        let path = "/tmp/test_pcapng_rotate.pcapng";
+        let epb_len = (PACKET_BLOCK_LEN + 64) as u64;
+        // Each file has 2 frames.
+        let max_size = FILE_HEADER_LEN + 2 * epb_len;
+        let mut writer = PcapngWriter::new(path, 64, max_size, 3).unwrap();
+        for _ in 0..7 {
+            writer
+                .write_packet(&[0; 64], 64, PacketDirection::Inbound)
+                .unwrap();
+        }
+        assert_eq!(metadata(path).unwrap().len(), FILE_HEADER_LEN + epb_len);
+        for index in 1..3 {
+            let rotated = format!("{}.{}", path, index);
+            assert_eq!(metadata(&rotated).unwrap().len(), max_size);
+            remove_file(rotated).unwrap();
+        }
+        assert!(metadata(format!("{}.3", path)).is_err());
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Minimal DHCP server which leases one address to each mac address.
+
+use std::net::Ipv4Addr;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::packet::MAC_LEN;
+use super::SlirpConfig;
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTP_REQUEST: u8 = 1;
+const BOOTP_REPLY: u8 = 2;
+const BOOTP_HTYPE_ETH: u8 = 1;
+const BOOTP_CHADDR_OFFSET: usize = 28;
+const DHCP_MAGIC_OFFSET: usize = 236;
+const DHCP_MAGIC: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+const DHCP_OPTIONS_OFFSET: usize = 240;
+/// Some clients drop the BOOTP message shorter than 300 bytes.
+const BOOTP_MIN_LEN: usize = 300;
+
+const DHCP_OPT_PAD: u8 = 0;
+const DHCP_OPT_NETMASK: u8 = 1;
+const DHCP_OPT_ROUTER: u8 = 3;
+const DHCP_OPT_DNS: u8 = 6;
+const DHCP_OPT_REQUESTED_IP: u8 = 50;
+const DHCP_OPT_LEASE_TIME: u8 = 51;
+const DHCP_OPT_MSG_TYPE: u8 = 53;
+const DHCP_OPT_SERVER_ID: u8 = 54;
+const DHCP_OPT_END: u8 = 255;
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_NAK: u8 = 6;
+const DHCP_INFORM: u8 = 8;
+
+const DHCP_LEASE_TIME: u32 = 86400;
+
+#[derive(Default)]
+pub struct DhcpServer {
+    leases: Vec<([u8; MAC_LEN], Ipv4Addr)>,
+}
+
+impl DhcpServer {
+    /// Handle the DHCP message from client, return the reply message.
+    pub fn handle(&mut self, config: &SlirpConfig, msg: &[u8]) -> Option<Vec<u8>> {
+        if msg.len() < DHCP_OPTIONS_OFFSET
+            || msg[0] != BOOTP_REQUEST
+            || msg[1] != BOOTP_HTYPE_ETH
+            || usize::from(msg[2]) != MAC_LEN
+            || msg[DHCP_MAGIC_OFFSET..DHCP_OPTIONS_OFFSET] != DHCP_MAGIC
+        {
+            return None;
+        }
+        let mut mac = [0_u8; MAC_LEN];
+        mac.copy_from_slice(&msg[BOOTP_CHADDR_OFFSET..BOOTP_CHADDR_OFFSET + MAC_LEN]);
+
+        let mut msg_type = None;
+        let mut requested_ip = None;
+        let mut opts = &msg[DHCP_OPTIONS_OFFSET..];
+        while let Some(&code) = opts.first() {
+            match code {
+                DHCP_OPT_END => break,
+                DHCP_OPT_PAD => opts = &opts[1..],
+                _ => {
+                    let len = usize::from(*opts.get(1)?);
+                    let value = opts.get(2..2 + len)?;
+                    match code {
+                        DHCP_OPT_MSG_TYPE if len == 1 => msg_type = Some(value[0]),
+                        DHCP_OPT_REQUESTED_IP if len == 4 => {
+                            requested_ip =
+                                Some(Ipv4Addr::new(value[0], value[1], value[2], value[3]))
+                        }
+                        _ => {}
+                    }
+                    opts = &opts[2 + len..];
+                }
+            }
+        }
+
+        let ciaddr = Ipv4Addr::new(msg[12], msg[13], msg[14], msg[15]);
+        let (reply_type, yiaddr) = match msg_type? {
+            DHCP_DISCOVER => (DHCP_OFFER, self.lease(config, &mac)?),
+            DHCP_REQUEST => {
+                let ip = self.lease(config, &mac)?;
+                let requested = requested_ip.unwrap_or(ciaddr);
+                if requested == ip || requested.is_unspecified() {
+                    (DHCP_ACK, ip)
+                } else {
+                    (DHCP_NAK, Ipv4Addr::UNSPECIFIED)
+                }
+            }
+            // The client has got its address, only the parameters are replied.
+            DHCP_INFORM => (DHCP_ACK, Ipv4Addr::UNSPECIFIED),
+            _ => return None,
+        };
+        Some(Self::build_reply(config, msg, reply_type, yiaddr))
+    }
+
+    /// Get the address leased to `mac`, allocate one if there is none.
+    fn lease(&mut self, config: &SlirpConfig, mac: &[u8; MAC_LEN]) -> Option<Ipv4Addr> {
+        if let Some((_, ip)) = self.leases.iter().find(|(m, _)| m == mac) {
+            return Some(*ip);
+        }
+        let last = u32::from(config.broadcast()) - 1;
+        let ip = (u32::from(config.dhcp_start)..=last)
+            .map(Ipv4Addr::from)
+            .find(|ip| {
+                *ip != config.host && *ip != config.dns && self.leases.iter().all(|(_, l)| l != ip)
+            })?;
+        self.leases.push((*mac, ip));
+        Some(ip)
+    }
+
+    fn build_reply(config: &SlirpConfig, req: &[u8], reply_type: u8, yiaddr: Ipv4Addr) -> Vec<u8> {
+        let mut reply = vec![0_u8; DHCP_OPTIONS_OFFSET];
+        reply[0] = BOOTP_REPLY;
+        reply[1] = BOOTP_HTYPE_ETH;
+        reply[2] = MAC_LEN as u8;
+        // Transaction id, seconds and flags.
+        reply[4..12].copy_from_slice(&req[4..12]);
+        reply[16..20].copy_from_slice(&yiaddr.octets());
+        reply[BOOTP_CHADDR_OFFSET..BOOTP_CHADDR_OFFSET + 16]
+            .copy_from_slice(&req[BOOTP_CHADDR_OFFSET..BOOTP_CHADDR_OFFSET + 16]);
+        reply[DHCP_MAGIC_OFFSET..DHCP_OPTIONS_OFFSET].copy_from_slice(&DHCP_MAGIC);
+
+        reply.extend_from_slice(&[DHCP_OPT_MSG_TYPE, 1, reply_type]);
+        reply.extend_from_slice(&[DHCP_OPT_SERVER_ID, 4]);
+        reply.extend_from_slice(&config.host.octets());
+        if reply_type != DHCP_NAK {
+            if !yiaddr.is_unspecified() {
+                let mut lease_time = [0_u8; 4];
+                BigEndian::write_u32(&mut lease_time, DHCP_LEASE_TIME);
+                reply.extend_from_slice(&[DHCP_OPT_LEASE_TIME, 4]);
+                reply.extend_from_slice(&lease_time);
+            }
+            reply.extend_from_slice(&[DHCP_OPT_NETMASK, 4]);
+            reply.extend_from_slice(&config.netmask().octets());
+            reply.extend_from_slice(&[DHCP_OPT_ROUTER, 4]);
+            reply.extend_from_slice(&config.host.octets());
+            reply.extend_from_slice(&[DHCP_OPT_DNS, 4]);
+            reply.extend_from_slice(&config.dns.octets());
+        }
+        reply.push(DHCP_OPT_END);
+        if reply.len() < BOOTP_MIN_LEN {
+            reply.resize(BOOTP_MIN_LEN, 0);
+        }
+        reply
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Build the DHCP message sent by the client.
+    pub fn build_request(
+        mac: &[u8; MAC_LEN],
+        msg_type: u8,
+        requested: Option<Ipv4Addr>,
+    ) -> Vec<u8> {
+        let mut msg = vec![0_u8; DHCP_OPTIONS_OFFSET];
+        msg[0] = BOOTP_REQUEST;
+        msg[1] = BOOTP_HTYPE_ETH;
+        msg[2] = MAC_LEN as u8;
+        msg[4..8].copy_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+        msg[BOOTP_CHADDR_OFFSET..BOOTP_CHADDR_OFFSET + MAC_LEN].copy_from_slice(mac);
+        msg[DHCP_MAGIC_OFFSET..DHCP_OPTIONS_OFFSET].copy_from_slice(&DHCP_MAGIC);
+        msg.extend_from_slice(&[DHCP_OPT_MSG_TYPE, 1, msg_type]);
+        if let Some(ip) = requested {
+            msg.extend_from_slice(&[DHCP_OPT_REQUESTED_IP, 4]);
+            msg.extend_from_slice(&ip.octets());
+        }
+        msg.push(DHCP_OPT_END);
+        msg
+    }
+
+    /// Get the message type and the assigned address of the reply.
+    pub fn parse_reply(reply: &[u8]) -> (u8, Ipv4Addr) {
+        assert_eq!(reply[0], BOOTP_REPLY);
+        assert_eq!(reply[DHCP_MAGIC_OFFSET..DHCP_OPTIONS_OFFSET], DHCP_MAGIC);
+        assert_eq!(reply[DHCP_OPTIONS_OFFSET], DHCP_OPT_MSG_TYPE);
+        (
+            reply[DHCP_OPTIONS_OFFSET + 2],
+            Ipv4Addr::new(reply[16], reply[17], reply[18], reply[19]),
+        )
+    }
+
+    #[test]
+    fn test_dhcp_lease() {
+        let config = SlirpConfig::default();
+        let mut server = DhcpServer::default();
+        let mac1 = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let mac2 = [0x52, 0x54, 0x00, 0x12, 0x34, 0x57];
+
+        let reply = server
+            .handle(&config, &build_request(&mac1, DHCP_DISCOVER, None))
+            .unwrap();
+        assert_eq!(parse_reply(&reply), (DHCP_OFFER, config.dhcp_start));
+        assert!(reply.len() >= BOOTP_MIN_LEN);
+
+        let reply = server
+            .handle(
+                &config,
+                &build_request(&mac1, DHCP_REQUEST, Some(config.dhcp_start)),
+            )
+            .unwrap();
+        assert_eq!(parse_reply(&reply), (DHCP_ACK, config.dhcp_start));
+
+        // The second client gets the next address, and can't take the leased one.
+        let reply = server
+            .handle(
+                &config,
+                &build_request(&mac2, DHCP_REQUEST, Some(config.dhcp_start)),
+            )
+            .unwrap();
+        assert_eq!(parse_reply(&reply).0, DHCP_NAK);
+        let reply = server
+            .handle(&config, &build_request(&mac2, DHCP_DISCOVER, None))
+            .unwrap();
+        assert_eq!(
+            parse_reply(&reply),
+            (DHCP_OFFER, Ipv4Addr::new(10, 0, 2, 16))
+        );
+
+        // Invalid message.
+        let mut msg = build_request(&mac1, DHCP_DISCOVER, None);
+        msg[DHCP_MAGIC_OFFSET] = 0;
+        assert!(server.handle(&config, &msg).is_none());
+    }
+}
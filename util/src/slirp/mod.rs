@@ -0,0 +1,1310 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! User mode network stack.
+//!
+//! The guest is connected to a virtual network with a router, a DHCP server and a DNS
+//! server. The TCP and UDP traffic of guest is relayed through the sockets of host, so
+//! no privilege or tap device is needed.
+
+mod dhcp;
+mod packet;
+mod tcp;
+mod udp;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vmm_sys_util::timerfd::TimerFd;
+
+use crate::loop_context::{
+    EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+use dhcp::{DhcpServer, DHCP_CLIENT_PORT, DHCP_SERVER_PORT};
+use packet::*;
+use tcp::TcpConn;
+use udp::{UdpFlow, UdpForward, UdpPeer};
+
+/// Mac address of the virtual router.
+const ROUTER_MAC: [u8; MAC_LEN] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const LINK_MTU: usize = 1500;
+/// Offset of `num_buffers` in the virtio net header.
+const VNET_HDR_NUM_BUFFERS_OFFSET: usize = 10;
+const MAX_FRAME_SIZE: usize = 65536;
+const MAX_SOCKET_EVENTS: usize = 64;
+/// Interval of the timer which drives the retransmission and the expiration.
+const TICK_INTERVAL_MS: u64 = 100;
+/// Ports allocated for the connections forwarded from host.
+const FORWARD_PORT_START: u16 = 49152;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Configuration of the virtual network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlirpConfig {
+    /// Address of the virtual network.
+    pub net: Ipv4Addr,
+    pub prefix_len: u8,
+    /// Address of the virtual router, which stands for the host.
+    pub host: Ipv4Addr,
+    /// The first address leased by the DHCP server.
+    pub dhcp_start: Ipv4Addr,
+    /// Address of the virtual DNS server, which forwards to the resolver of host.
+    pub dns: Ipv4Addr,
+    /// Rules forwarding the ports of host to the guest.
+    pub hostfwd: Vec<HostFwd>,
+}
+
+impl Default for SlirpConfig {
+    fn default() -> Self {
+        let mut config = SlirpConfig {
+            net: Ipv4Addr::UNSPECIFIED,
+            prefix_len: 0,
+            host: Ipv4Addr::UNSPECIFIED,
+            dhcp_start: Ipv4Addr::UNSPECIFIED,
+            dns: Ipv4Addr::UNSPECIFIED,
+            hostfwd: Vec::new(),
+        };
+        // The default network is valid.
+        config.set_net("10.0.2.0/24").unwrap();
+        config
+    }
+}
+
+impl SlirpConfig {
+    /// Set the virtual network in the form of `addr[/prefix_len]`, the addresses of host,
+    /// DNS and DHCP are reset to the 2nd, 3rd and 15th address of the network.
+    pub fn set_net(&mut self, net: &str) -> Result<()> {
+        let (addr, prefix_len) = net.split_once('/').unwrap_or((net, "24"));
+        let addr = Ipv4Addr::from_str(addr)
+            .with_context(|| format!("Invalid address of user network {}", net))?;
+        let prefix_len = prefix_len
+            .parse::<u8>()
+            .with_context(|| format!("Invalid prefix length of user network {}", net))?;
+        if !(8..=30).contains(&prefix_len) {
+            bail!(
+                "Invalid prefix length {} of user network, expect 8 to 30",
+                prefix_len
+            );
+        }
+        let base = u32::from(addr) & (u32::MAX << (32 - prefix_len));
+        self.net = Ipv4Addr::from(base);
+        self.prefix_len = prefix_len;
+        self.host = Ipv4Addr::from(base + 2);
+        self.dns = Ipv4Addr::from(base + 3);
+        self.dhcp_start = Ipv4Addr::from(base + 15);
+        Ok(())
+    }
+
+    fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::MAX << (32 - self.prefix_len))
+    }
+
+    fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.net) | !u32::from(self.netmask()))
+    }
+
+    /// Whether `addr` is in the virtual network.
+    fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & u32::from(self.netmask()) == u32::from(self.net)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        for (name, addr) in [
+            ("host", self.host),
+            ("dns", self.dns),
+            ("dhcpstart", self.dhcp_start),
+        ] {
+            if !self.contains(addr) || addr == self.net || addr == self.broadcast() {
+                bail!(
+                    "The {} address {} is not a valid address in user network {}/{}",
+                    name,
+                    addr,
+                    self.net,
+                    self.prefix_len
+                );
+            }
+        }
+        if self.host == self.dns || self.dhcp_start == self.host || self.dhcp_start == self.dns {
+            bail!("The host, dns and dhcpstart addresses of user network must differ");
+        }
+        for rule in &self.hostfwd {
+            if let Some(addr) = rule.guest_addr {
+                if !self.contains(addr) {
+                    bail!(
+                        "The guest address {} of hostfwd is not in user network {}/{}",
+                        addr,
+                        self.net,
+                        self.prefix_len
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FwdProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Rule forwarding the port of host to the guest, in the form of
+/// `[tcp|udp]:[hostaddr]:hostport-[guestaddr]:guestport`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostFwd {
+    pub protocol: FwdProtocol,
+    /// Address listened on host, all the addresses if not set.
+    pub host_addr: Ipv4Addr,
+    pub host_port: u16,
+    /// Address of guest, the first address leased by DHCP if not set.
+    pub guest_addr: Option<Ipv4Addr>,
+    pub guest_port: u16,
+}
+
+impl FromStr for HostFwd {
+    type Err = anyhow::Error;
+
+    fn from_str(rule: &str) -> Result<Self> {
+        let err = || {
+            format!(
+                "Invalid hostfwd rule {}, expect [tcp|udp]:[hostaddr]:hostport-[guestaddr]:guestport",
+                rule
+            )
+        };
+        let (host, guest) = rule.split_once('-').with_context(err)?;
+        let host: Vec<&str> = host.split(':').collect();
+        if host.len() != 3 {
+            bail!(err());
+        }
+        let protocol = match host[0] {
+            "" | "tcp" => FwdProtocol::Tcp,
+            "udp" => FwdProtocol::Udp,
+            _ => bail!(err()),
+        };
+        let host_addr = match host[1] {
+            "" => Ipv4Addr::UNSPECIFIED,
+            addr => Ipv4Addr::from_str(addr).with_context(err)?,
+        };
+        let host_port = host[2].parse::<u16>().with_context(err)?;
+        let (guest_addr, guest_port) = guest.split_once(':').with_context(err)?;
+        let guest_addr = match guest_addr {
+            "" => None,
+            addr => Some(Ipv4Addr::from_str(addr).with_context(err)?),
+        };
+        let guest_port = guest_port.parse::<u16>().with_context(err)?;
+        if host_port == 0 || guest_port == 0 {
+            bail!(err());
+        }
+        Ok(HostFwd {
+            protocol,
+            host_addr,
+            host_port,
+            guest_addr,
+            guest_port,
+        })
+    }
+}
+
+/// Parse the hostfwd rules separated by semicolon.
+pub fn parse_hostfwd(rules: &str) -> Result<Vec<HostFwd>> {
+    rules
+        .split(';')
+        .filter(|rule| !rule.is_empty())
+        .map(HostFwd::from_str)
+        .collect()
+}
+
+/// The flow is identified by the endpoint of guest and the endpoint seen by the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FlowKey {
+    guest: SocketAddrV4,
+    remote: SocketAddrV4,
+}
+
+#[derive(Clone, Copy)]
+enum SocketOwner {
+    Tcp(FlowKey),
+    Udp(FlowKey),
+    TcpForward(usize),
+    UdpForward(usize),
+}
+
+struct TcpForward {
+    listener: TcpListener,
+    guest: SocketAddrV4,
+}
+
+/// The link to the guest, on which each message is one ethernet frame prefixed with
+/// the virtio net header.
+struct Link {
+    file: File,
+    vnet_hdr_len: usize,
+    host: Ipv4Addr,
+    guest_mac: Option<[u8; MAC_LEN]>,
+    ip_id: u16,
+}
+
+impl Link {
+    fn send_frame(
+        &mut self,
+        dst_mac: &[u8; MAC_LEN],
+        ethertype: u16,
+        len: usize,
+        fill: impl FnOnce(&mut [u8]),
+    ) {
+        let hdr_len = self.vnet_hdr_len;
+        let mut frame = vec![0_u8; hdr_len + ETH_HDR_LEN + len];
+        if hdr_len >= VNET_HDR_NUM_BUFFERS_OFFSET + 2 {
+            LittleEndian::write_u16(
+                &mut frame[VNET_HDR_NUM_BUFFERS_OFFSET..VNET_HDR_NUM_BUFFERS_OFFSET + 2],
+                1,
+            );
+        }
+        build_eth_header(&mut frame[hdr_len..], dst_mac, &ROUTER_MAC, ethertype);
+        fill(&mut frame[hdr_len + ETH_HDR_LEN..]);
+        if let Err(e) = self.file.write(&frame) {
+            // The frame is dropped if the guest doesn't receive in time.
+            if e.kind() != ErrorKind::WouldBlock {
+                error!("Failed to send frame to guest: {:?}", e);
+            }
+        }
+    }
+
+    fn send_ipv4(
+        &mut self,
+        protocol: u8,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        len: usize,
+        fill: impl FnOnce(&mut [u8]),
+    ) {
+        let dst_mac = if dst == Ipv4Addr::BROADCAST {
+            BROADCAST_MAC
+        } else if let Some(mac) = self.guest_mac {
+            mac
+        } else {
+            // The packet is dropped, and the upper layer will retry.
+            let host = self.host;
+            self.send_frame(&BROADCAST_MAC, ETH_P_ARP, ARP_LEN, |buf| {
+                build_arp(buf, ARP_OP_REQUEST, &ROUTER_MAC, host, &[0; MAC_LEN], dst)
+            });
+            return;
+        };
+        let id = self.ip_id;
+        self.ip_id = self.ip_id.wrapping_add(1);
+
+        if len <= LINK_MTU - IPV4_HDR_LEN {
+            self.send_frame(&dst_mac, ETH_P_IP, IPV4_HDR_LEN + len, |buf| {
+                build_ipv4_header(buf, id, protocol, src, dst, len, IPV4_FLAG_DF);
+                fill(&mut buf[IPV4_HDR_LEN..]);
+            });
+            return;
+        }
+        // Large UDP datagrams are fragmented, the offset is in 8 bytes units.
+        let mut payload = vec![0_u8; len];
+        fill(&mut payload);
+        let frag_size = (LINK_MTU - IPV4_HDR_LEN) & !7;
+        for (index, chunk) in payload.chunks(frag_size).enumerate() {
+            let offset = index * frag_size;
+            let mut frag = (offset / 8) as u16;
+            if offset + chunk.len() < len {
+                frag |= IPV4_FLAG_MF;
+            }
+            self.send_frame(&dst_mac, ETH_P_IP, IPV4_HDR_LEN + chunk.len(), |buf| {
+                build_ipv4_header(buf, id, protocol, src, dst, chunk.len(), frag);
+                buf[IPV4_HDR_LEN..].copy_from_slice(chunk);
+            });
+        }
+    }
+
+    fn send_udp(&mut self, src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) {
+        let len = UDP_HDR_LEN + payload.len();
+        if len + IPV4_HDR_LEN > usize::from(u16::MAX) {
+            return;
+        }
+        self.send_ipv4(IPPROTO_UDP, *src.ip(), *dst.ip(), len, |buf| {
+            buf[UDP_HDR_LEN..].copy_from_slice(payload);
+            build_udp_header(buf, src, dst);
+        });
+    }
+
+    fn send_tcp(&mut self, hdr: &TcpHeader, payload: &[u8]) {
+        let hdr_len = hdr.len();
+        self.send_ipv4(
+            IPPROTO_TCP,
+            *hdr.src.ip(),
+            *hdr.dst.ip(),
+            hdr_len + payload.len(),
+            |buf| {
+                buf[hdr_len..].copy_from_slice(payload);
+                hdr.build(buf);
+            },
+        );
+    }
+}
+
+/// The user mode network stack connected to one guest.
+pub struct Slirp {
+    config: SlirpConfig,
+    link: Link,
+    /// The DNS server of host which the queries to the virtual DNS server go to.
+    dns_server: Ipv4Addr,
+    dhcp: DhcpServer,
+    /// Epoll of all the sockets on host, which is polled in the event loop.
+    epoll: Epoll,
+    timer: TimerFd,
+    sockets: HashMap<RawFd, SocketOwner>,
+    tcp_conns: HashMap<FlowKey, TcpConn>,
+    udp_flows: HashMap<FlowKey, UdpFlow>,
+    tcp_forwards: Vec<TcpForward>,
+    udp_forwards: Vec<UdpForward>,
+    /// The flows of the peers sending to the UDP forward rules.
+    udp_forward_peers: HashMap<(usize, SocketAddr), FlowKey>,
+    next_port: u16,
+    next_iss: u32,
+}
+
+impl Slirp {
+    /// Create the network stack. Returns it with the other end of the link, which is
+    /// used as a tap with `vnet_hdr_len` bytes of virtio net header.
+    pub fn new(config: &SlirpConfig, vnet_hdr_len: usize) -> Result<(Self, File)> {
+        config.check()?;
+
+        let mut fds = [0 as RawFd; 2];
+        // SAFETY: the arguments are valid and fds has room for two fds.
+        let ret = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        if ret < 0 {
+            bail!(
+                "Failed to create the link of user network: {:?}",
+                std::io::Error::last_os_error()
+            );
+        }
+        // SAFETY: the fds are just created and owned by nobody else.
+        let (link_file, guest_file) =
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        let epoll = Epoll::new().with_context(|| "Failed to create epoll of user network")?;
+        let mut timer = TimerFd::new().with_context(|| "Failed to create timer of user network")?;
+        let tick = Duration::from_millis(TICK_INTERVAL_MS);
+        timer
+            .reset(tick, Some(tick))
+            .with_context(|| "Failed to set timer of user network")?;
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.subsec_nanos());
+
+        let mut slirp = Slirp {
+            config: config.clone(),
+            link: Link {
+                file: link_file,
+                vnet_hdr_len,
+                host: config.host,
+                guest_mac: None,
+                ip_id: 0,
+            },
+            dns_server: host_dns_server(),
+            dhcp: DhcpServer::default(),
+            epoll,
+            timer,
+            sockets: HashMap::new(),
+            tcp_conns: HashMap::new(),
+            udp_flows: HashMap::new(),
+            tcp_forwards: Vec::new(),
+            udp_forwards: Vec::new(),
+            udp_forward_peers: HashMap::new(),
+            next_port: FORWARD_PORT_START,
+            next_iss: seed,
+        };
+        for rule in &config.hostfwd {
+            slirp.add_forward(rule)?;
+        }
+
+        Ok((slirp, guest_file))
+    }
+
+    fn add_forward(&mut self, rule: &HostFwd) -> Result<()> {
+        let host = SocketAddrV4::new(rule.host_addr, rule.host_port);
+        let guest = SocketAddrV4::new(
+            rule.guest_addr.unwrap_or(self.config.dhcp_start),
+            rule.guest_port,
+        );
+        match rule.protocol {
+            FwdProtocol::Tcp => {
+                let listener = TcpListener::bind(host)
+                    .with_context(|| format!("Failed to listen on tcp {} for hostfwd", host))?;
+                listener.set_nonblocking(true)?;
+                let owner = SocketOwner::TcpForward(self.tcp_forwards.len());
+                self.add_socket(listener.as_raw_fd(), owner)?;
+                self.tcp_forwards.push(TcpForward { listener, guest });
+            }
+            FwdProtocol::Udp => {
+                let socket = UdpSocket::bind(host)
+                    .with_context(|| format!("Failed to bind udp {} for hostfwd", host))?;
+                socket.set_nonblocking(true)?;
+                let owner = SocketOwner::UdpForward(self.udp_forwards.len());
+                self.add_socket(socket.as_raw_fd(), owner)?;
+                self.udp_forwards.push(UdpForward { socket, guest });
+            }
+        }
+        Ok(())
+    }
+
+    fn add_socket(&mut self, fd: RawFd, owner: SocketOwner) -> std::io::Result<()> {
+        self.epoll.ctl(
+            ControlOperation::Add,
+            fd,
+            EpollEvent::new(
+                EventSet::IN | EventSet::OUT | EventSet::EDGE_TRIGGERED,
+                fd as u64,
+            ),
+        )?;
+        self.sockets.insert(fd, owner);
+        Ok(())
+    }
+
+    /// Remove the socket from epoll before it's closed.
+    fn del_socket(&mut self, fd: RawFd) {
+        if let Err(e) = self
+            .epoll
+            .ctl(ControlOperation::Delete, fd, EpollEvent::default())
+        {
+            warn!("Failed to delete socket {} from epoll: {:?}", fd, e);
+        }
+        self.sockets.remove(&fd);
+    }
+
+    fn remove_tcp(&mut self, key: &FlowKey) {
+        if let Some(conn) = self.tcp_conns.remove(key) {
+            self.del_socket(conn.stream.as_raw_fd());
+        }
+    }
+
+    fn remove_udp(&mut self, key: &FlowKey) {
+        match self.udp_flows.remove(key).map(|flow| flow.peer) {
+            Some(UdpPeer::Socket(socket)) => self.del_socket(socket.as_raw_fd()),
+            Some(UdpPeer::Forward { rule, peer }) => {
+                self.udp_forward_peers.remove(&(rule, peer));
+            }
+            None => {}
+        }
+    }
+
+    fn gen_iss(&mut self) -> u32 {
+        self.next_iss = self.next_iss.wrapping_add(64000);
+        self.next_iss
+    }
+
+    /// Allocate the port of host address for the flow forwarded to the guest.
+    fn alloc_port(&mut self) -> Option<u16> {
+        for _ in FORWARD_PORT_START..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = if port == u16::MAX {
+                FORWARD_PORT_START
+            } else {
+                port + 1
+            };
+            let remote = SocketAddrV4::new(self.config.host, port);
+            if !self.tcp_conns.keys().any(|key| key.remote == remote)
+                && !self.udp_flows.keys().any(|key| key.remote == remote)
+            {
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    /// Translate the destination of guest to the address on host.
+    fn translate(&self, dst: SocketAddrV4) -> Option<SocketAddrV4> {
+        let ip = *dst.ip();
+        if ip == self.config.host {
+            Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, dst.port()))
+        } else if ip == self.config.dns {
+            // Only the DNS service is provided.
+            (dst.port() == 53).then(|| SocketAddrV4::new(self.dns_server, 53))
+        } else if self.config.contains(ip)
+            || ip.is_broadcast()
+            || ip.is_multicast()
+            || ip.is_unspecified()
+            || ip.is_loopback()
+        {
+            None
+        } else {
+            Some(dst)
+        }
+    }
+
+    /// Handle the frames sent by the guest.
+    fn handle_link(&mut self) {
+        let mut buf = vec![0_u8; self.link.vnet_hdr_len + MAX_FRAME_SIZE];
+        loop {
+            match self.link.file.read(&mut buf) {
+                Ok(len) if len > self.link.vnet_hdr_len => {
+                    self.input_frame(&buf[self.link.vnet_hdr_len..len]);
+                }
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        error!("Failed to receive frame from guest: {:?}", e);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    fn input_frame(&mut self, frame: &[u8]) {
+        let eth = match EthFrame::parse(frame) {
+            Some(eth) => eth,
+            None => return,
+        };
+        // Multicast bit is not set in the mac address of guest.
+        if eth.src[0] & 1 == 0 {
+            self.link.guest_mac = Some(eth.src);
+        }
+        if eth.dst != ROUTER_MAC && eth.dst != BROADCAST_MAC {
+            return;
+        }
+        match eth.ethertype {
+            ETH_P_ARP => self.input_arp(eth.payload),
+            ETH_P_IP => self.input_ipv4(eth.payload),
+            _ => {}
+        }
+    }
+
+    fn input_arp(&mut self, payload: &[u8]) {
+        let arp = match ArpPacket::parse(payload) {
+            Some(arp) => arp,
+            None => return,
+        };
+        let target = arp.target_ip;
+        if !arp.is_request() || (target != self.config.host && target != self.config.dns) {
+            return;
+        }
+        self.link
+            .send_frame(&arp.sender_mac, ETH_P_ARP, ARP_LEN, |buf| {
+                build_arp(
+                    buf,
+                    ARP_OP_REPLY,
+                    &ROUTER_MAC,
+                    target,
+                    &arp.sender_mac,
+                    arp.sender_ip,
+                )
+            });
+    }
+
+    fn input_ipv4(&mut self, payload: &[u8]) {
+        // Fragmented packets are not supported.
+        let ip = match Ipv4Packet::parse(payload) {
+            Some(ip) => ip,
+            None => return,
+        };
+        match ip.protocol {
+            IPPROTO_TCP => self.input_tcp(&ip),
+            IPPROTO_UDP => self.input_udp(&ip),
+            IPPROTO_ICMP => self.input_icmp(&ip),
+            _ => {}
+        }
+    }
+
+    /// Reply the ping to the virtual router and DNS server.
+    fn input_icmp(&mut self, ip: &Ipv4Packet) {
+        let request = ip.payload;
+        if (ip.dst != self.config.host && ip.dst != self.config.dns)
+            || request.len() < ICMP_HDR_LEN
+            || request[0] != ICMP_ECHO_REQUEST
+        {
+            return;
+        }
+        self.link
+            .send_ipv4(IPPROTO_ICMP, ip.dst, ip.src, request.len(), |buf| {
+                buf.copy_from_slice(request);
+                buf[0] = ICMP_ECHO_REPLY;
+                BigEndian::write_u16(&mut buf[2..4], 0);
+                let csum = checksum(buf, 0);
+                BigEndian::write_u16(&mut buf[2..4], csum);
+            });
+    }
+
+    fn input_udp(&mut self, ip: &Ipv4Packet) {
+        let udp = match UdpDatagram::parse(ip.payload) {
+            Some(udp) => udp,
+            None => return,
+        };
+        if udp.dst_port == DHCP_SERVER_PORT
+            && (ip.dst == Ipv4Addr::BROADCAST || ip.dst == self.config.host)
+        {
+            if let Some(reply) = self.dhcp.handle(&self.config, udp.payload) {
+                self.link.send_udp(
+                    SocketAddrV4::new(self.config.host, DHCP_SERVER_PORT),
+                    SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT),
+                    &reply,
+                );
+            }
+            return;
+        }
+
+        let key = FlowKey {
+            guest: SocketAddrV4::new(ip.src, udp.src_port),
+            remote: SocketAddrV4::new(ip.dst, udp.dst_port),
+        };
+        if !self.udp_flows.contains_key(&key) {
+            let dest = match self.translate(key.remote) {
+                Some(dest) => dest,
+                None => return,
+            };
+            let flow = match UdpFlow::connect(dest) {
+                Ok(flow) => flow,
+                Err(e) => {
+                    warn!("Failed to create udp socket to {}: {:?}", dest, e);
+                    return;
+                }
+            };
+            if let UdpPeer::Socket(socket) = &flow.peer {
+                if let Err(e) = self.add_socket(socket.as_raw_fd(), SocketOwner::Udp(key)) {
+                    warn!("Failed to poll udp socket: {:?}", e);
+                    return;
+                }
+            }
+            self.udp_flows.insert(key, flow);
+        }
+        // The flow exists as it's checked or inserted above.
+        let flow = self.udp_flows.get_mut(&key).unwrap();
+        flow.send(udp.payload, &self.udp_forwards);
+    }
+
+    fn input_tcp(&mut self, ip: &Ipv4Packet) {
+        let seg = match TcpSegment::parse(ip.payload) {
+            Some(seg) => seg,
+            None => return,
+        };
+        let key = FlowKey {
+            guest: SocketAddrV4::new(ip.src, seg.src_port),
+            remote: SocketAddrV4::new(ip.dst, seg.dst_port),
+        };
+        if let Some(conn) = self.tcp_conns.get_mut(&key) {
+            if !conn.input(&seg, &key, &mut self.link) {
+                self.remove_tcp(&key);
+            }
+            return;
+        }
+
+        if seg.flags & (TCP_SYN | TCP_ACK | TCP_RST) != TCP_SYN {
+            tcp::reset_segment(&seg, &key, &mut self.link);
+            return;
+        }
+        let dest = match self.translate(key.remote) {
+            Some(dest) => dest,
+            None => {
+                tcp::reset_segment(&seg, &key, &mut self.link);
+                return;
+            }
+        };
+        let iss = self.gen_iss();
+        let conn = match TcpConn::connect(dest, &seg, iss) {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Failed to connect tcp {}: {:?}", dest, e);
+                tcp::reset_segment(&seg, &key, &mut self.link);
+                return;
+            }
+        };
+        if let Err(e) = self.add_socket(conn.stream.as_raw_fd(), SocketOwner::Tcp(key)) {
+            warn!("Failed to poll tcp socket: {:?}", e);
+            return;
+        }
+        self.tcp_conns.insert(key, conn);
+    }
+
+    /// Handle the events of the sockets on host.
+    fn handle_sockets(&mut self) {
+        let mut events = vec![EpollEvent::default(); MAX_SOCKET_EVENTS];
+        loop {
+            let count = match self.epoll.wait(0, &mut events) {
+                Ok(count) => count,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to wait sockets of user network: {:?}", e);
+                    return;
+                }
+            };
+            for event in &events[..count] {
+                self.handle_socket(event.fd(), event.event_set());
+            }
+            if count < MAX_SOCKET_EVENTS {
+                break;
+            }
+        }
+    }
+
+    fn handle_socket(&mut self, fd: RawFd, events: EventSet) {
+        let owner = match self.sockets.get(&fd) {
+            Some(owner) => *owner,
+            None => return,
+        };
+        match owner {
+            SocketOwner::Tcp(key) => {
+                let conn = match self.tcp_conns.get_mut(&key) {
+                    Some(conn) => conn,
+                    None => return,
+                };
+                let mut alive = true;
+                if events.intersects(EventSet::OUT | EventSet::ERROR | EventSet::HANG_UP) {
+                    alive = conn.host_writable(&key, &mut self.link);
+                }
+                if alive && events.intersects(EventSet::IN | EventSet::ERROR | EventSet::HANG_UP) {
+                    alive = conn.host_readable(&key, &mut self.link);
+                }
+                if !alive {
+                    self.remove_tcp(&key);
+                }
+            }
+            SocketOwner::Udp(key) => {
+                if let Some(flow) = self.udp_flows.get_mut(&key) {
+                    flow.receive(&key, &mut self.link);
+                }
+            }
+            SocketOwner::TcpForward(index) => self.accept_forward(index),
+            SocketOwner::UdpForward(index) => self.receive_forward(index),
+        }
+    }
+
+    fn accept_forward(&mut self, index: usize) {
+        loop {
+            let stream = match self.tcp_forwards[index].listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        warn!("Failed to accept the connection of hostfwd: {:?}", e);
+                    }
+                    break;
+                }
+            };
+            if stream.set_nonblocking(true).is_err() {
+                continue;
+            }
+            let port = match self.alloc_port() {
+                Some(port) => port,
+                None => {
+                    warn!("No free port for the connection of hostfwd");
+                    continue;
+                }
+            };
+            let key = FlowKey {
+                guest: self.tcp_forwards[index].guest,
+                remote: SocketAddrV4::new(self.config.host, port),
+            };
+            if let Err(e) = self.add_socket(stream.as_raw_fd(), SocketOwner::Tcp(key)) {
+                warn!("Failed to poll tcp socket: {:?}", e);
+                continue;
+            }
+            let iss = self.gen_iss();
+            let conn = TcpConn::accept(stream, iss, &key, &mut self.link);
+            self.tcp_conns.insert(key, conn);
+        }
+    }
+
+    fn receive_forward(&mut self, index: usize) {
+        let mut buf = udp::datagram_buf();
+        while let Some((len, peer)) =
+            udp::recv_forwarded(&self.udp_forwards[index].socket, &mut buf)
+        {
+            let key = match self.udp_forward_peers.get(&(index, peer)) {
+                Some(key) => *key,
+                None => {
+                    let port = match self.alloc_port() {
+                        Some(port) => port,
+                        None => continue,
+                    };
+                    let key = FlowKey {
+                        guest: self.udp_forwards[index].guest,
+                        remote: SocketAddrV4::new(self.config.host, port),
+                    };
+                    let flow = UdpFlow {
+                        peer: UdpPeer::Forward { rule: index, peer },
+                        idle_ticks: 0,
+                    };
+                    self.udp_flows.insert(key, flow);
+                    self.udp_forward_peers.insert((index, peer), key);
+                    key
+                }
+            };
+            if let Some(flow) = self.udp_flows.get_mut(&key) {
+                flow.idle_ticks = 0;
+            }
+            self.link.send_udp(key.remote, key.guest, &buf[..len]);
+        }
+    }
+
+    fn handle_timer(&mut self) {
+        let ticks = match self.timer.wait() {
+            Ok(ticks) => ticks as u32,
+            Err(e) => {
+                error!("Failed to read timer of user network: {:?}", e);
+                return;
+            }
+        };
+
+        let keys: Vec<FlowKey> = self.tcp_conns.keys().copied().collect();
+        for key in keys {
+            // The connection exists as the keys are just collected.
+            let conn = self.tcp_conns.get_mut(&key).unwrap();
+            if !conn.tick(&key, &mut self.link) {
+                self.remove_tcp(&key);
+            }
+        }
+
+        let mut expired = Vec::new();
+        for (key, flow) in self.udp_flows.iter_mut() {
+            flow.idle_ticks = flow.idle_ticks.saturating_add(ticks);
+            if flow.idle_ticks >= udp::UDP_IDLE_TICKS {
+                expired.push(*key);
+            }
+        }
+        for key in expired {
+            self.remove_udp(&key);
+        }
+    }
+}
+
+impl EventNotifierHelper for Slirp {
+    fn internal_notifiers(slirp: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut notifiers = Vec::new();
+        let locked_slirp = slirp.lock().unwrap();
+
+        let cloned_slirp = slirp.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            cloned_slirp.lock().unwrap().handle_link();
+            None
+        });
+        notifiers.push(EventNotifier::new(
+            NotifierOperation::AddShared,
+            locked_slirp.link.file.as_raw_fd(),
+            None,
+            EventSet::IN | EventSet::EDGE_TRIGGERED,
+            vec![handler],
+        ));
+
+        let cloned_slirp = slirp.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            cloned_slirp.lock().unwrap().handle_sockets();
+            None
+        });
+        notifiers.push(EventNotifier::new(
+            NotifierOperation::AddShared,
+            locked_slirp.epoll.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![handler],
+        ));
+
+        let cloned_slirp = slirp.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            cloned_slirp.lock().unwrap().handle_timer();
+            None
+        });
+        notifiers.push(EventNotifier::new(
+            NotifierOperation::AddShared,
+            locked_slirp.timer.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![handler],
+        ));
+
+        notifiers
+    }
+}
+
+/// Get the first IPv4 name server of host.
+fn host_dns_server() -> Ipv4Addr {
+    if let Ok(content) = std::fs::read_to_string(RESOLV_CONF) {
+        for line in content.lines() {
+            let mut words = line.split_whitespace();
+            if words.next() != Some("nameserver") {
+                continue;
+            }
+            if let Some(Ok(addr)) = words.next().map(Ipv4Addr::from_str) {
+                return addr;
+            }
+        }
+    }
+    warn!(
+        "No IPv4 nameserver in {}, use {} for user network",
+        RESOLV_CONF,
+        Ipv4Addr::LOCALHOST
+    );
+    Ipv4Addr::LOCALHOST
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpStream, UdpSocket};
+    use std::thread::sleep;
+
+    use super::*;
+
+    const GUEST_MAC: [u8; MAC_LEN] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    const GUEST_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+    const HOST_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+    const VNET_HDR_LEN: usize = 12;
+
+    fn send_frame(guest: &mut File, dst_mac: &[u8; MAC_LEN], ethertype: u16, payload: &[u8]) {
+        let mut frame = vec![0_u8; VNET_HDR_LEN + ETH_HDR_LEN + payload.len()];
+        build_eth_header(&mut frame[VNET_HDR_LEN..], dst_mac, &GUEST_MAC, ethertype);
+        frame[VNET_HDR_LEN + ETH_HDR_LEN..].copy_from_slice(payload);
+        guest.write_all(&frame).unwrap();
+    }
+
+    fn send_ipv4(guest: &mut File, protocol: u8, src: Ipv4Addr, dst: Ipv4Addr, l4: &[u8]) {
+        let mut packet = vec![0_u8; IPV4_HDR_LEN + l4.len()];
+        build_ipv4_header(&mut packet, 0, protocol, src, dst, l4.len(), IPV4_FLAG_DF);
+        packet[IPV4_HDR_LEN..].copy_from_slice(l4);
+        let dst_mac = if dst == Ipv4Addr::BROADCAST {
+            BROADCAST_MAC
+        } else {
+            ROUTER_MAC
+        };
+        send_frame(guest, &dst_mac, ETH_P_IP, &packet);
+    }
+
+    fn send_udp(guest: &mut File, src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) {
+        let mut buf = vec![0_u8; UDP_HDR_LEN + payload.len()];
+        buf[UDP_HDR_LEN..].copy_from_slice(payload);
+        build_udp_header(&mut buf, src, dst);
+        send_ipv4(guest, IPPROTO_UDP, *src.ip(), *dst.ip(), &buf);
+    }
+
+    fn send_tcp(guest: &mut File, hdr: &TcpHeader, payload: &[u8]) {
+        let mut buf = vec![0_u8; hdr.len() + payload.len()];
+        buf[hdr.len()..].copy_from_slice(payload);
+        hdr.build(&mut buf);
+        send_ipv4(guest, IPPROTO_TCP, *hdr.src.ip(), *hdr.dst.ip(), &buf);
+    }
+
+    fn tcp_header(
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+    ) -> TcpHeader {
+        TcpHeader {
+            src,
+            dst,
+            seq,
+            ack,
+            flags,
+            window: 65535,
+            mss: None,
+        }
+    }
+
+    fn recv_frame(guest: &mut File) -> Option<Vec<u8>> {
+        let mut buf = vec![0_u8; VNET_HDR_LEN + MAX_FRAME_SIZE];
+        let len = guest.read(&mut buf).ok()?;
+        Some(buf[VNET_HDR_LEN..len].to_vec())
+    }
+
+    /// Poll the sockets of host until the guest receives one frame.
+    fn wait_frame(slirp: &mut Slirp, guest: &mut File) -> Vec<u8> {
+        for _ in 0..200 {
+            slirp.handle_sockets();
+            if let Some(frame) = recv_frame(guest) {
+                return frame;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        panic!("No frame is received by guest");
+    }
+
+    /// Get the protocol, source, destination and payload of the IPv4 packet in frame.
+    fn parse_ipv4(frame: &[u8]) -> (u8, Ipv4Addr, Ipv4Addr, Vec<u8>) {
+        let eth = EthFrame::parse(frame).unwrap();
+        assert_eq!(eth.src, ROUTER_MAC);
+        assert_eq!(eth.ethertype, ETH_P_IP);
+        let ip = Ipv4Packet::parse(eth.payload).unwrap();
+        (ip.protocol, ip.src, ip.dst, ip.payload.to_vec())
+    }
+
+    /// Get the flags, seq, ack and payload of the TCP segment in frame.
+    fn parse_tcp(frame: &[u8]) -> (u8, u32, u32, Vec<u8>) {
+        let (protocol, _, dst, payload) = parse_ipv4(frame);
+        assert_eq!((protocol, dst), (IPPROTO_TCP, GUEST_IP));
+        let seg = TcpSegment::parse(&payload).unwrap();
+        (seg.flags, seg.seq, seg.ack, seg.payload.to_vec())
+    }
+
+    #[test]
+    fn test_slirp_config() {
+        let rule = HostFwd::from_str("tcp:127.0.0.1:2222-10.0.2.15:22").unwrap();
+        assert_eq!(rule.protocol, FwdProtocol::Tcp);
+        assert_eq!(rule.host_addr, Ipv4Addr::LOCALHOST);
+        assert_eq!(rule.host_port, 2222);
+        assert_eq!(rule.guest_addr, Some(GUEST_IP));
+        assert_eq!(rule.guest_port, 22);
+        let rules = parse_hostfwd("::8080-:80;udp::5353-:53").unwrap();
+        assert_eq!(rules[0].protocol, FwdProtocol::Tcp);
+        assert_eq!(rules[0].host_addr, Ipv4Addr::UNSPECIFIED);
+        assert_eq!(rules[0].guest_addr, None);
+        assert_eq!(rules[1].protocol, FwdProtocol::Udp);
+        assert_eq!(rules[1].guest_port, 53);
+        for rule in [
+            "tcp:2222-:22",
+            "sctp::2222-:22",
+            "tcp::2222:22",
+            "tcp::0-:22",
+            "tcp::2222-:70000",
+            "tcp:localhost:2222-:22",
+        ] {
+            assert!(HostFwd::from_str(rule).is_err());
+        }
+
+        let mut config = SlirpConfig::default();
+        assert!(config.check().is_ok());
+        assert_eq!(config.host, HOST_IP);
+        assert_eq!(config.dns, Ipv4Addr::new(10, 0, 2, 3));
+        assert_eq!(config.dhcp_start, GUEST_IP);
+        config.set_net("192.168.76.1/16").unwrap();
+        assert_eq!(config.net, Ipv4Addr::new(192, 168, 0, 0));
+        assert_eq!(config.host, Ipv4Addr::new(192, 168, 0, 2));
+        assert_eq!(config.netmask(), Ipv4Addr::new(255, 255, 0, 0));
+        assert!(config.check().is_ok());
+        config.dns = Ipv4Addr::new(10, 0, 2, 3);
+        assert!(config.check().is_err());
+        config.dns = config.host;
+        assert!(config.check().is_err());
+        assert!(config.set_net("10.0.2.0/31").is_err());
+        assert!(config.set_net("10.0.2/24").is_err());
+    }
+
+    #[test]
+    fn test_slirp_local_services() {
+        let (mut slirp, mut guest) = Slirp::new(&SlirpConfig::default(), VNET_HDR_LEN).unwrap();
+
+        // ARP of the virtual router.
+        let mut arp = [0_u8; ARP_LEN];
+        build_arp(
+            &mut arp,
+            ARP_OP_REQUEST,
+            &GUEST_MAC,
+            GUEST_IP,
+            &[0; MAC_LEN],
+            HOST_IP,
+        );
+        send_frame(&mut guest, &BROADCAST_MAC, ETH_P_ARP, &arp);
+        slirp.handle_link();
+        let frame = recv_frame(&mut guest).unwrap();
+        let eth = EthFrame::parse(&frame).unwrap();
+        assert_eq!((eth.dst, eth.ethertype), (GUEST_MAC, ETH_P_ARP));
+        let reply = ArpPacket::parse(eth.payload).unwrap();
+        assert_eq!(reply.op, ARP_OP_REPLY);
+        assert_eq!(reply.sender_mac, ROUTER_MAC);
+        assert_eq!(reply.sender_ip, HOST_IP);
+        assert_eq!(reply.target_ip, GUEST_IP);
+
+        // DHCP discover.
+        let discover = dhcp::tests::build_request(&GUEST_MAC, 1, None);
+        send_udp(
+            &mut guest,
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DHCP_CLIENT_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_SERVER_PORT),
+            &discover,
+        );
+        slirp.handle_link();
+        let (protocol, src, dst, payload) = parse_ipv4(&recv_frame(&mut guest).unwrap());
+        assert_eq!(
+            (protocol, src, dst),
+            (IPPROTO_UDP, HOST_IP, Ipv4Addr::BROADCAST)
+        );
+        let udp = UdpDatagram::parse(&payload).unwrap();
+        assert_eq!(
+            (udp.src_port, udp.dst_port),
+            (DHCP_SERVER_PORT, DHCP_CLIENT_PORT)
+        );
+        assert_eq!(dhcp::tests::parse_reply(udp.payload), (2, GUEST_IP));
+
+        // Ping the virtual router.
+        let mut echo = [0_u8; ICMP_HDR_LEN + 4];
+        echo[0] = ICMP_ECHO_REQUEST;
+        echo[ICMP_HDR_LEN..].copy_from_slice(b"ping");
+        let csum = checksum(&echo, 0);
+        BigEndian::write_u16(&mut echo[2..4], csum);
+        send_ipv4(&mut guest, IPPROTO_ICMP, GUEST_IP, HOST_IP, &echo);
+        slirp.handle_link();
+        let (protocol, src, dst, payload) = parse_ipv4(&recv_frame(&mut guest).unwrap());
+        assert_eq!((protocol, src, dst), (IPPROTO_ICMP, HOST_IP, GUEST_IP));
+        assert_eq!(payload[0], ICMP_ECHO_REPLY);
+        assert_eq!(&payload[ICMP_HDR_LEN..], b"ping");
+        assert_eq!(checksum(&payload, 0), 0);
+
+        // Other addresses in the virtual network are unreachable.
+        send_ipv4(
+            &mut guest,
+            IPPROTO_ICMP,
+            GUEST_IP,
+            Ipv4Addr::new(10, 0, 2, 100),
+            &echo,
+        );
+        slirp.handle_link();
+        assert!(recv_frame(&mut guest).is_none());
+    }
+
+    #[test]
+    fn test_slirp_udp() {
+        let (mut slirp, mut guest) = Slirp::new(&SlirpConfig::default(), VNET_HDR_LEN).unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        let guest_addr = SocketAddrV4::new(GUEST_IP, 5000);
+        let remote = SocketAddrV4::new(HOST_IP, port);
+        send_udp(&mut guest, guest_addr, remote, b"ping");
+        slirp.handle_link();
+        let mut buf = [0_u8; 16];
+        let (len, peer) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+
+        server.send_to(b"pong", peer).unwrap();
+        let (protocol, src, dst, payload) = parse_ipv4(&wait_frame(&mut slirp, &mut guest));
+        assert_eq!((protocol, src, dst), (IPPROTO_UDP, HOST_IP, GUEST_IP));
+        let udp = UdpDatagram::parse(&payload).unwrap();
+        assert_eq!((udp.src_port, udp.dst_port), (port, 5000));
+        assert_eq!(udp.payload, b"pong");
+
+        // The idle flow expires.
+        assert_eq!(slirp.udp_flows.len(), 1);
+        for flow in slirp.udp_flows.values_mut() {
+            flow.idle_ticks = udp::UDP_IDLE_TICKS;
+        }
+        slirp.handle_timer();
+        assert!(slirp.udp_flows.is_empty());
+        assert!(slirp.sockets.is_empty());
+    }
+
+    #[test]
+    fn test_slirp_tcp() {
+        let (mut slirp, mut guest) = Slirp::new(&SlirpConfig::default(), VNET_HDR_LEN).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let guest_addr = SocketAddrV4::new(GUEST_IP, 40000);
+        let remote = SocketAddrV4::new(HOST_IP, port);
+
+        // Handshake, SYN-ACK is replied after connected to host.
+        let mut syn = tcp_header(guest_addr, remote, 1000, 0, TCP_SYN);
+        syn.mss = Some(1460);
+        send_tcp(&mut guest, &syn, &[]);
+        slirp.handle_link();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (flags, iss, ack, _) = parse_tcp(&wait_frame(&mut slirp, &mut guest));
+        assert_eq!((flags, ack), (TCP_SYN | TCP_ACK, 1001));
+
+        // Data from guest to host.
+        let hdr = tcp_header(guest_addr, remote, 1001, iss + 1, TCP_ACK | TCP_PSH);
+        send_tcp(&mut guest, &hdr, b"hello");
+        slirp.handle_link();
+        let mut buf = [0_u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        let (flags, _, ack, _) = parse_tcp(&recv_frame(&mut guest).unwrap());
+        assert_eq!((flags, ack), (TCP_ACK, 1006));
+
+        // Data from host to guest, followed by FIN.
+        stream.write_all(b"world").unwrap();
+        let (flags, seq, _, payload) = parse_tcp(&wait_frame(&mut slirp, &mut guest));
+        assert_eq!(
+            (flags, seq, payload.as_slice()),
+            (TCP_ACK | TCP_PSH, iss + 1, &b"world"[..])
+        );
+        drop(stream);
+        let (flags, seq, _, _) = parse_tcp(&wait_frame(&mut slirp, &mut guest));
+        assert_eq!((flags, seq), (TCP_FIN | TCP_ACK, iss + 6));
+
+        // The connection is removed after the FIN of guest.
+        let hdr = tcp_header(guest_addr, remote, 1006, iss + 7, TCP_FIN | TCP_ACK);
+        send_tcp(&mut guest, &hdr, &[]);
+        slirp.handle_link();
+        let (flags, _, ack, _) = parse_tcp(&recv_frame(&mut guest).unwrap());
+        assert_eq!((flags, ack), (TCP_ACK, 1007));
+        assert!(slirp.tcp_conns.is_empty());
+        assert!(slirp.sockets.is_empty());
+
+        // Segment out of connection is reset.
+        send_tcp(&mut guest, &hdr, &[]);
+        slirp.handle_link();
+        let (flags, seq, _, _) = parse_tcp(&recv_frame(&mut guest).unwrap());
+        assert_eq!((flags, seq), (TCP_RST, iss + 7));
+    }
+
+    #[test]
+    fn test_slirp_hostfwd() {
+        // Get a free port of host.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = SlirpConfig {
+            hostfwd: parse_hostfwd(&format!("tcp:127.0.0.1:{}-:22", port)).unwrap(),
+            ..Default::default()
+        };
+        let (mut slirp, mut guest) = Slirp::new(&config, VNET_HDR_LEN).unwrap();
+
+        // The mac address of guest is learned from its frames.
+        send_frame(&mut guest, &ROUTER_MAC, 0x86dd, &[0_u8; 40]);
+        slirp.handle_link();
+
+        let _client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let frame = wait_frame(&mut slirp, &mut guest);
+        let (protocol, src, _, payload) = parse_ipv4(&frame);
+        assert_eq!((protocol, src), (IPPROTO_TCP, HOST_IP));
+        let seg = TcpSegment::parse(&payload).unwrap();
+        assert_eq!((seg.flags, seg.dst_port), (TCP_SYN, 22));
+        assert_eq!(seg.mss, Some(1460));
+
+        let guest_addr = SocketAddrV4::new(GUEST_IP, 22);
+        let remote = SocketAddrV4::new(HOST_IP, seg.src_port);
+        let hdr = tcp_header(guest_addr, remote, 5000, seg.seq + 1, TCP_SYN | TCP_ACK);
+        send_tcp(&mut guest, &hdr, &[]);
+        slirp.handle_link();
+        let (flags, _, ack, _) = parse_tcp(&recv_frame(&mut guest).unwrap());
+        assert_eq!((flags, ack), (TCP_ACK, 5001));
+    }
+}
@@ -0,0 +1,461 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Parsing and building of the ethernet, ARP, IPv4, UDP, TCP and ICMP headers.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use byteorder::{BigEndian, ByteOrder};
+
+pub const ETH_HDR_LEN: usize = 14;
+pub const ETH_P_IP: u16 = 0x0800;
+pub const ETH_P_ARP: u16 = 0x0806;
+pub const MAC_LEN: usize = 6;
+pub const BROADCAST_MAC: [u8; MAC_LEN] = [0xff; MAC_LEN];
+
+pub const ARP_LEN: usize = 28;
+const ARP_HTYPE_ETH: u16 = 1;
+pub const ARP_OP_REQUEST: u16 = 1;
+pub const ARP_OP_REPLY: u16 = 2;
+
+pub const IPV4_HDR_LEN: usize = 20;
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+const IPV4_DEFAULT_TTL: u8 = 64;
+/// Don't fragment flag in the fragment field of the IPv4 header.
+pub const IPV4_FLAG_DF: u16 = 0x4000;
+/// More fragments flag in the fragment field of the IPv4 header.
+pub const IPV4_FLAG_MF: u16 = 0x2000;
+/// Fragment offset in 8 bytes units in the fragment field of the IPv4 header.
+const IPV4_FRAG_OFFSET_MASK: u16 = 0x1fff;
+
+pub const UDP_HDR_LEN: usize = 8;
+pub const TCP_HDR_LEN: usize = 20;
+pub const ICMP_HDR_LEN: usize = 8;
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+/// Kind of the max segment size option of TCP.
+const TCP_OPT_MSS: u8 = 2;
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+
+/// Parsed ethernet frame.
+pub struct EthFrame<'a> {
+    pub dst: [u8; MAC_LEN],
+    pub src: [u8; MAC_LEN],
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthFrame<'a> {
+    pub fn parse(frame: &'a [u8]) -> Option<Self> {
+        if frame.len() < ETH_HDR_LEN {
+            return None;
+        }
+        let mut dst = [0_u8; MAC_LEN];
+        let mut src = [0_u8; MAC_LEN];
+        dst.copy_from_slice(&frame[0..6]);
+        src.copy_from_slice(&frame[6..12]);
+        Some(EthFrame {
+            dst,
+            src,
+            ethertype: BigEndian::read_u16(&frame[12..14]),
+            payload: &frame[ETH_HDR_LEN..],
+        })
+    }
+}
+
+/// Build the ethernet header in the front of `buf`.
+pub fn build_eth_header(buf: &mut [u8], dst: &[u8; MAC_LEN], src: &[u8; MAC_LEN], ethertype: u16) {
+    buf[0..6].copy_from_slice(dst);
+    buf[6..12].copy_from_slice(src);
+    BigEndian::write_u16(&mut buf[12..14], ethertype);
+}
+
+/// Parsed ARP packet for IPv4 over ethernet.
+pub struct ArpPacket {
+    pub op: u16,
+    pub sender_mac: [u8; MAC_LEN],
+    pub sender_ip: Ipv4Addr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < ARP_LEN
+            || BigEndian::read_u16(&buf[0..2]) != ARP_HTYPE_ETH
+            || BigEndian::read_u16(&buf[2..4]) != ETH_P_IP
+        {
+            return None;
+        }
+        let mut sender_mac = [0_u8; MAC_LEN];
+        sender_mac.copy_from_slice(&buf[8..14]);
+        Some(ArpPacket {
+            op: BigEndian::read_u16(&buf[6..8]),
+            sender_mac,
+            sender_ip: read_ip(&buf[14..18]),
+            target_ip: read_ip(&buf[24..28]),
+        })
+    }
+
+    pub fn is_request(&self) -> bool {
+        self.op == ARP_OP_REQUEST
+    }
+}
+
+/// Build the ARP packet sent by `ip` at `mac`.
+pub fn build_arp(
+    buf: &mut [u8],
+    op: u16,
+    mac: &[u8; MAC_LEN],
+    ip: Ipv4Addr,
+    target_mac: &[u8; MAC_LEN],
+    target_ip: Ipv4Addr,
+) {
+    BigEndian::write_u16(&mut buf[0..2], ARP_HTYPE_ETH);
+    BigEndian::write_u16(&mut buf[2..4], ETH_P_IP);
+    buf[4] = MAC_LEN as u8;
+    buf[5] = 4;
+    BigEndian::write_u16(&mut buf[6..8], op);
+    buf[8..14].copy_from_slice(mac);
+    buf[14..18].copy_from_slice(&ip.octets());
+    buf[18..24].copy_from_slice(target_mac);
+    buf[24..28].copy_from_slice(&target_ip.octets());
+}
+
+/// Parsed IPv4 packet, the fragments are not supported.
+pub struct Ipv4Packet<'a> {
+    pub protocol: u8,
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < IPV4_HDR_LEN || buf[0] >> 4 != 4 {
+            return None;
+        }
+        let hdr_len = usize::from(buf[0] & 0xf) * 4;
+        let total_len = usize::from(BigEndian::read_u16(&buf[2..4]));
+        if hdr_len < IPV4_HDR_LEN || total_len < hdr_len || total_len > buf.len() {
+            return None;
+        }
+        let frag = BigEndian::read_u16(&buf[6..8]);
+        if frag & (IPV4_FLAG_MF | IPV4_FRAG_OFFSET_MASK) != 0 {
+            return None;
+        }
+        Some(Ipv4Packet {
+            protocol: buf[9],
+            src: read_ip(&buf[12..16]),
+            dst: read_ip(&buf[16..20]),
+            payload: &buf[hdr_len..total_len],
+        })
+    }
+}
+
+/// Build the IPv4 header without options in the front of `buf`, `payload_len` is the
+/// length of the data following the header, and `frag` is the fragment field.
+pub fn build_ipv4_header(
+    buf: &mut [u8],
+    id: u16,
+    protocol: u8,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    payload_len: usize,
+    frag: u16,
+) {
+    buf[0] = 0x45;
+    buf[1] = 0;
+    BigEndian::write_u16(&mut buf[2..4], (IPV4_HDR_LEN + payload_len) as u16);
+    BigEndian::write_u16(&mut buf[4..6], id);
+    BigEndian::write_u16(&mut buf[6..8], frag);
+    buf[8] = IPV4_DEFAULT_TTL;
+    buf[9] = protocol;
+    BigEndian::write_u16(&mut buf[10..12], 0);
+    buf[12..16].copy_from_slice(&src.octets());
+    buf[16..20].copy_from_slice(&dst.octets());
+    let csum = checksum(&buf[..IPV4_HDR_LEN], 0);
+    BigEndian::write_u16(&mut buf[10..12], csum);
+}
+
+/// Parsed UDP datagram.
+pub struct UdpDatagram<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> UdpDatagram<'a> {
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < UDP_HDR_LEN {
+            return None;
+        }
+        let len = usize::from(BigEndian::read_u16(&buf[4..6]));
+        if len < UDP_HDR_LEN || len > buf.len() {
+            return None;
+        }
+        Some(UdpDatagram {
+            src_port: BigEndian::read_u16(&buf[0..2]),
+            dst_port: BigEndian::read_u16(&buf[2..4]),
+            payload: &buf[UDP_HDR_LEN..len],
+        })
+    }
+}
+
+/// Build the UDP header in the front of `buf`, the payload must follow the header.
+pub fn build_udp_header(buf: &mut [u8], src: SocketAddrV4, dst: SocketAddrV4) {
+    let len = buf.len();
+    BigEndian::write_u16(&mut buf[0..2], src.port());
+    BigEndian::write_u16(&mut buf[2..4], dst.port());
+    BigEndian::write_u16(&mut buf[4..6], len as u16);
+    BigEndian::write_u16(&mut buf[6..8], 0);
+    let mut csum = checksum(
+        buf,
+        pseudo_header_sum(*src.ip(), *dst.ip(), IPPROTO_UDP, len),
+    );
+    // Zero checksum means no checksum for UDP.
+    if csum == 0 {
+        csum = 0xffff;
+    }
+    BigEndian::write_u16(&mut buf[6..8], csum);
+}
+
+/// Parsed TCP segment.
+pub struct TcpSegment<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    /// The max segment size option, only in SYN segments.
+    pub mss: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> TcpSegment<'a> {
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < TCP_HDR_LEN {
+            return None;
+        }
+        let hdr_len = usize::from(buf[12] >> 4) * 4;
+        if hdr_len < TCP_HDR_LEN || hdr_len > buf.len() {
+            return None;
+        }
+        let mut mss = None;
+        let mut opts = &buf[TCP_HDR_LEN..hdr_len];
+        while let Some(&kind) = opts.first() {
+            match kind {
+                TCP_OPT_END => break,
+                TCP_OPT_NOP => opts = &opts[1..],
+                _ => {
+                    let len = usize::from(*opts.get(1)?);
+                    if len < 2 || len > opts.len() {
+                        break;
+                    }
+                    if kind == TCP_OPT_MSS && len == 4 {
+                        mss = Some(BigEndian::read_u16(&opts[2..4]));
+                    }
+                    opts = &opts[len..];
+                }
+            }
+        }
+        Some(TcpSegment {
+            src_port: BigEndian::read_u16(&buf[0..2]),
+            dst_port: BigEndian::read_u16(&buf[2..4]),
+            seq: BigEndian::read_u32(&buf[4..8]),
+            ack: BigEndian::read_u32(&buf[8..12]),
+            flags: buf[13],
+            window: BigEndian::read_u16(&buf[14..16]),
+            mss,
+            payload: &buf[hdr_len..],
+        })
+    }
+
+    /// Length of the sequence space used by the segment, SYN and FIN take one each.
+    pub fn seq_len(&self) -> u32 {
+        let mut len = self.payload.len() as u32;
+        if self.flags & TCP_SYN != 0 {
+            len += 1;
+        }
+        if self.flags & TCP_FIN != 0 {
+            len += 1;
+        }
+        len
+    }
+}
+
+/// Header fields of the TCP segment to be built.
+pub struct TcpHeader {
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+}
+
+impl TcpHeader {
+    /// Length of the header with the options.
+    pub fn len(&self) -> usize {
+        if self.mss.is_some() {
+            TCP_HDR_LEN + 4
+        } else {
+            TCP_HDR_LEN
+        }
+    }
+
+    /// Build the TCP header in the front of `buf`, the payload must follow the header.
+    pub fn build(&self, buf: &mut [u8]) {
+        let hdr_len = self.len();
+        BigEndian::write_u16(&mut buf[0..2], self.src.port());
+        BigEndian::write_u16(&mut buf[2..4], self.dst.port());
+        BigEndian::write_u32(&mut buf[4..8], self.seq);
+        BigEndian::write_u32(&mut buf[8..12], self.ack);
+        buf[12] = ((hdr_len / 4) as u8) << 4;
+        buf[13] = self.flags;
+        BigEndian::write_u16(&mut buf[14..16], self.window);
+        BigEndian::write_u16(&mut buf[16..18], 0);
+        BigEndian::write_u16(&mut buf[18..20], 0);
+        if let Some(mss) = self.mss {
+            buf[20] = TCP_OPT_MSS;
+            buf[21] = 4;
+            BigEndian::write_u16(&mut buf[22..24], mss);
+        }
+        let csum = checksum(
+            buf,
+            pseudo_header_sum(*self.src.ip(), *self.dst.ip(), IPPROTO_TCP, buf.len()),
+        );
+        BigEndian::write_u16(&mut buf[16..18], csum);
+    }
+}
+
+fn read_ip(buf: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3])
+}
+
+/// Sum of the pseudo header used by the checksum of TCP and UDP.
+fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let mut sum = 0_u32;
+    for ip in [src, dst] {
+        let octets = ip.octets();
+        sum += u32::from(BigEndian::read_u16(&octets[0..2]));
+        sum += u32::from(BigEndian::read_u16(&octets[2..4]));
+    }
+    sum + u32::from(protocol) + len as u32
+}
+
+/// The internet checksum of `buf`, `sum` is the initial sum, e.g. of the pseudo header.
+pub fn checksum(buf: &[u8], mut sum: u32) -> u16 {
+    let mut chunks = buf.chunks_exact(2);
+    for chunk in chunks.by_ref() {
+        sum += u32::from(BigEndian::read_u16(chunk));
+    }
+    if let Some(&last) = chunks.remainder().first() {
+        sum += u32::from(last) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Whether sequence number `a` is before `b`.
+pub fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Whether sequence number `a` is before or equal to `b`.
+pub fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        // The example of RFC 1071.
+        let buf = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&buf, 0), !0xddf2);
+        // The checksum of the data with its checksum is zero.
+        let mut hdr = [0_u8; IPV4_HDR_LEN];
+        build_ipv4_header(
+            &mut hdr,
+            1,
+            IPPROTO_UDP,
+            Ipv4Addr::new(10, 0, 2, 2),
+            Ipv4Addr::new(10, 0, 2, 15),
+            8,
+            IPV4_FLAG_DF,
+        );
+        assert_eq!(checksum(&hdr, 0), 0);
+        let packet = Ipv4Packet::parse(&[&hdr[..], &[0_u8; 8]].concat())
+            .map(|p| (p.protocol, p.src, p.dst, p.payload.len()))
+            .unwrap();
+        assert_eq!(
+            packet,
+            (
+                IPPROTO_UDP,
+                Ipv4Addr::new(10, 0, 2, 2),
+                Ipv4Addr::new(10, 0, 2, 15),
+                8
+            )
+        );
+    }
+
+    #[test]
+    fn test_tcp_segment() {
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 40000);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 2), 22);
+        let hdr = TcpHeader {
+            src,
+            dst,
+            seq: 100,
+            ack: 200,
+            flags: TCP_SYN | TCP_ACK,
+            window: 65535,
+            mss: Some(1460),
+        };
+        let mut buf = vec![0_u8; hdr.len() + 3];
+        buf[hdr.len()..].copy_from_slice(b"abc");
+        hdr.build(&mut buf);
+        assert_eq!(
+            checksum(
+                &buf,
+                pseudo_header_sum(*src.ip(), *dst.ip(), IPPROTO_TCP, buf.len())
+            ),
+            0
+        );
+
+        let seg = TcpSegment::parse(&buf).unwrap();
+        assert_eq!((seg.src_port, seg.dst_port), (40000, 22));
+        assert_eq!((seg.seq, seg.ack, seg.window), (100, 200, 65535));
+        assert_eq!(seg.mss, Some(1460));
+        assert_eq!(seg.payload, b"abc");
+        assert_eq!(seg.seq_len(), 4);
+
+        assert!(seq_lt(u32::MAX, 1));
+        assert!(!seq_lt(1, u32::MAX));
+        assert!(seq_le(5, 5));
+    }
+}
@@ -0,0 +1,514 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Termination of the TCP connections of guest, the data is relayed through the
+//! stream sockets of host.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddrV4, TcpStream};
+use std::os::unix::io::FromRawFd;
+
+use super::packet::{
+    seq_le, seq_lt, TcpHeader, TcpSegment, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN,
+};
+use super::{FlowKey, Link};
+
+/// Max bytes buffered in each direction of the connection.
+const TCP_BUF_SIZE: usize = 256 * 1024;
+/// Window scaling is not offered, so the window can't exceed 64K.
+const TCP_MAX_WINDOW: usize = 65535;
+const TCP_DEFAULT_MSS: u16 = 536;
+const TCP_MAX_MSS: u16 = 1460;
+/// Retransmission timeout in ticks, which is doubled on each retransmission.
+const TCP_INITIAL_RTO: u32 = 2;
+const TCP_MAX_RTO: u32 = 64;
+/// The connection is reset after so many retransmissions without progress.
+const TCP_MAX_RETRIES: u32 = 12;
+const TCP_READ_CHUNK: usize = 16384;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TcpState {
+    /// The SYN of guest is received, and the socket of host is connecting.
+    Connecting,
+    /// SYN-ACK is sent to the guest after the socket of host connected.
+    SynReceived,
+    /// SYN is sent to the guest for the connection accepted on the forwarded port.
+    SynSent,
+    Established,
+}
+
+pub struct TcpConn {
+    pub stream: TcpStream,
+    state: TcpState,
+    /// The oldest sequence number not acked by the guest, which is the sequence number
+    /// of the first byte of `send_buf`.
+    snd_una: u32,
+    snd_nxt: u32,
+    /// The highest sequence number sent, `snd_nxt` goes back on retransmission.
+    snd_max: u32,
+    snd_wnd: u32,
+    /// Data read from host which is not acked by the guest.
+    send_buf: VecDeque<u8>,
+    /// The host has closed its side, FIN follows the data in `send_buf`.
+    host_eof: bool,
+    fin_acked: bool,
+    rcv_nxt: u32,
+    /// Data from the guest which is not written to host.
+    recv_buf: VecDeque<u8>,
+    guest_fin: bool,
+    host_shutdown: bool,
+    /// The window in the last segment sent to the guest.
+    adv_wnd: u32,
+    ack_pending: bool,
+    mss: u16,
+    rto: u32,
+    rtx_ticks: Option<u32>,
+    retries: u32,
+}
+
+impl TcpConn {
+    fn new(stream: TcpStream, state: TcpState, iss: u32) -> Self {
+        TcpConn {
+            stream,
+            state,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_max: iss,
+            snd_wnd: 0,
+            send_buf: VecDeque::new(),
+            host_eof: false,
+            fin_acked: false,
+            rcv_nxt: 0,
+            recv_buf: VecDeque::new(),
+            guest_fin: false,
+            host_shutdown: false,
+            adv_wnd: 0,
+            ack_pending: false,
+            mss: TCP_DEFAULT_MSS,
+            rto: TCP_INITIAL_RTO,
+            rtx_ticks: None,
+            retries: 0,
+        }
+    }
+
+    /// Start connecting `dest` on host for the SYN of guest, the connection is not
+    /// replied until the socket becomes writable.
+    pub fn connect(dest: SocketAddrV4, syn: &TcpSegment, iss: u32) -> std::io::Result<Self> {
+        // SAFETY: the arguments are valid.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_INET,
+                libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // SAFETY: the fd is just created and owned by nobody else.
+        let stream = unsafe { TcpStream::from_raw_fd(fd) };
+        let addr = libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: dest.port().to_be(),
+            sin_addr: libc::in_addr {
+                s_addr: u32::from(*dest.ip()).to_be(),
+            },
+            sin_zero: [0; 8],
+        };
+        // SAFETY: the fd is valid, and the address is a valid sockaddr_in.
+        let ret = unsafe {
+            libc::connect(
+                fd,
+                &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EINPROGRESS) {
+                return Err(e);
+            }
+        }
+
+        let mut conn = TcpConn::new(stream, TcpState::Connecting, iss);
+        conn.rcv_nxt = syn.seq.wrapping_add(1);
+        conn.snd_wnd = u32::from(syn.window);
+        conn.mss = min(syn.mss.unwrap_or(TCP_DEFAULT_MSS), TCP_MAX_MSS);
+        Ok(conn)
+    }
+
+    /// Create the connection accepted on the forwarded port, and send SYN to the guest.
+    pub fn accept(stream: TcpStream, iss: u32, key: &FlowKey, link: &mut Link) -> Self {
+        let mut conn = TcpConn::new(stream, TcpState::SynSent, iss);
+        conn.send_syn(key, link);
+        conn
+    }
+
+    fn window(&self) -> u32 {
+        min(TCP_BUF_SIZE - self.recv_buf.len(), TCP_MAX_WINDOW) as u32
+    }
+
+    fn send(&mut self, key: &FlowKey, link: &mut Link, flags: u8, seq: u32, payload: &[u8]) {
+        let window = self.window();
+        let mss = if flags & TCP_SYN != 0 {
+            Some(TCP_MAX_MSS)
+        } else {
+            None
+        };
+        let hdr = TcpHeader {
+            src: key.remote,
+            dst: key.guest,
+            seq,
+            ack: if flags & TCP_ACK != 0 {
+                self.rcv_nxt
+            } else {
+                0
+            },
+            flags,
+            window: window as u16,
+            mss,
+        };
+        link.send_tcp(&hdr, payload);
+        if flags & TCP_ACK != 0 {
+            self.adv_wnd = window;
+            self.ack_pending = false;
+        }
+    }
+
+    fn send_syn(&mut self, key: &FlowKey, link: &mut Link) {
+        let flags = match self.state {
+            TcpState::SynSent => TCP_SYN,
+            _ => TCP_SYN | TCP_ACK,
+        };
+        self.send(key, link, flags, self.snd_una, &[]);
+        self.snd_nxt = self.snd_una.wrapping_add(1);
+        self.snd_max = self.snd_nxt;
+        self.rtx_ticks.get_or_insert(self.rto);
+    }
+
+    fn send_rst(&mut self, key: &FlowKey, link: &mut Link) {
+        self.send(key, link, TCP_RST | TCP_ACK, self.snd_nxt, &[]);
+    }
+
+    /// Whether both sides are closed and all the data is delivered.
+    fn is_closed(&self) -> bool {
+        self.fin_acked && self.guest_fin && self.host_shutdown
+    }
+
+    /// Handle the segment from the guest. Returns false if the connection is closed.
+    pub fn input(&mut self, seg: &TcpSegment, key: &FlowKey, link: &mut Link) -> bool {
+        if seg.flags & TCP_RST != 0 {
+            return false;
+        }
+        match self.state {
+            // The guest retransmits SYN, wait for the host.
+            TcpState::Connecting => return true,
+            TcpState::SynSent => {
+                if seg.flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK
+                    && seg.ack == self.snd_una.wrapping_add(1)
+                {
+                    self.rcv_nxt = seg.seq.wrapping_add(1);
+                    self.mss = min(seg.mss.unwrap_or(TCP_DEFAULT_MSS), TCP_MAX_MSS);
+                    self.establish(seg);
+                    self.ack_pending = true;
+                    self.output(key, link, false);
+                    self.flush_ack(key, link);
+                }
+                return true;
+            }
+            TcpState::SynReceived => {
+                if seg.flags & TCP_SYN != 0 {
+                    self.send_syn(key, link);
+                    return true;
+                }
+                if seg.flags & TCP_ACK == 0 || seg.ack != self.snd_una.wrapping_add(1) {
+                    return true;
+                }
+                self.establish(seg);
+            }
+            TcpState::Established => {
+                // SYN-ACK is retransmitted if the ACK is lost.
+                if seg.flags & TCP_SYN != 0 {
+                    self.ack_pending = true;
+                    self.flush_ack(key, link);
+                    return true;
+                }
+            }
+        }
+
+        if seg.flags & TCP_ACK != 0 {
+            self.handle_ack(seg);
+        }
+        if !self.receive(seg, key, link) {
+            return false;
+        }
+        // Acked data leaves room for reading more from host.
+        if !self.read_host(key, link) {
+            return false;
+        }
+        self.output(key, link, false);
+        self.flush_ack(key, link);
+        !self.is_closed()
+    }
+
+    fn establish(&mut self, seg: &TcpSegment) {
+        self.state = TcpState::Established;
+        self.snd_una = seg.ack;
+        self.snd_nxt = seg.ack;
+        self.snd_max = seg.ack;
+        self.snd_wnd = u32::from(seg.window);
+        self.rto = TCP_INITIAL_RTO;
+        self.rtx_ticks = None;
+        self.retries = 0;
+    }
+
+    fn handle_ack(&mut self, seg: &TcpSegment) {
+        if seq_lt(self.snd_una, seg.ack) && seq_le(seg.ack, self.snd_max) {
+            let acked = seg.ack.wrapping_sub(self.snd_una) as usize;
+            let data = min(acked, self.send_buf.len());
+            self.send_buf.drain(..data);
+            if acked > data {
+                self.fin_acked = true;
+            }
+            self.snd_una = seg.ack;
+            if seq_lt(self.snd_nxt, self.snd_una) {
+                self.snd_nxt = self.snd_una;
+            }
+            self.rto = TCP_INITIAL_RTO;
+            self.retries = 0;
+            self.rtx_ticks = if self.snd_max != self.snd_una {
+                Some(self.rto)
+            } else {
+                None
+            };
+        }
+        if seq_le(seg.ack, self.snd_max) && seq_le(self.snd_una, seg.ack) {
+            self.snd_wnd = u32::from(seg.window);
+            // The guest is alive while answering the probes of zero window.
+            if self.snd_wnd == 0 {
+                self.retries = 0;
+            }
+        }
+    }
+
+    /// Receive the data and FIN of the segment. Returns false if the connection is reset.
+    fn receive(&mut self, seg: &TcpSegment, key: &FlowKey, link: &mut Link) -> bool {
+        if seg.seq_len() == 0 {
+            return true;
+        }
+        self.ack_pending = true;
+        // Out of order segments are dropped, the guest will retransmit them.
+        if !seq_le(seg.seq, self.rcv_nxt) || self.guest_fin {
+            return true;
+        }
+        let offset = self.rcv_nxt.wrapping_sub(seg.seq) as usize;
+        if offset > seg.payload.len() {
+            return true;
+        }
+        let payload = &seg.payload[offset..];
+        let accepted = min(payload.len(), TCP_BUF_SIZE - self.recv_buf.len());
+        self.recv_buf.extend(&payload[..accepted]);
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(accepted as u32);
+        if seg.flags & TCP_FIN != 0 && accepted == payload.len() {
+            self.guest_fin = true;
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+        }
+        self.flush_host(key, link)
+    }
+
+    fn flush_ack(&mut self, key: &FlowKey, link: &mut Link) {
+        if self.ack_pending && self.state == TcpState::Established {
+            self.send(key, link, TCP_ACK, self.snd_nxt, &[]);
+        }
+    }
+
+    /// Send the data and FIN to the guest in the window.
+    fn output(&mut self, key: &FlowKey, link: &mut Link, probe: bool) {
+        if self.state != TcpState::Established {
+            return;
+        }
+        // Send one byte beyond the zero window to probe it.
+        let window = if probe {
+            self.snd_wnd.max(1)
+        } else {
+            self.snd_wnd
+        };
+        loop {
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let unsent = self.send_buf.len().saturating_sub(in_flight);
+            if unsent == 0 {
+                if self.host_eof && !self.fin_acked && in_flight == self.send_buf.len() {
+                    self.send(key, link, TCP_FIN | TCP_ACK, self.snd_nxt, &[]);
+                    self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                }
+                break;
+            }
+            let room = (window as usize).saturating_sub(in_flight);
+            if room == 0 {
+                break;
+            }
+            let len = min(min(unsent, room), usize::from(self.mss));
+            let payload: Vec<u8> = self
+                .send_buf
+                .range(in_flight..in_flight + len)
+                .copied()
+                .collect();
+            self.send(key, link, TCP_ACK | TCP_PSH, self.snd_nxt, &payload);
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+        }
+        if seq_lt(self.snd_max, self.snd_nxt) {
+            self.snd_max = self.snd_nxt;
+        }
+        // The timer also probes the zero window.
+        if self.snd_max != self.snd_una || self.send_buf.len() > self.snd_wnd as usize {
+            self.rtx_ticks.get_or_insert(self.rto);
+        }
+    }
+
+    /// Read the data from host as much as the buffer holds. Returns false if the
+    /// connection is reset.
+    fn read_host(&mut self, key: &FlowKey, link: &mut Link) -> bool {
+        let mut buf = [0_u8; TCP_READ_CHUNK];
+        while !self.host_eof && self.send_buf.len() < TCP_BUF_SIZE {
+            let len = min(buf.len(), TCP_BUF_SIZE - self.send_buf.len());
+            match self.stream.read(&mut buf[..len]) {
+                Ok(0) => self.host_eof = true,
+                Ok(n) => self.send_buf.extend(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.send_rst(key, link);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Write the data from guest to host. Returns false if the connection is reset.
+    fn flush_host(&mut self, key: &FlowKey, link: &mut Link) -> bool {
+        while !self.recv_buf.is_empty() {
+            let (data, _) = self.recv_buf.as_slices();
+            match self.stream.write(data) {
+                Ok(n) => {
+                    self.recv_buf.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.send_rst(key, link);
+                    return false;
+                }
+            }
+        }
+        if self.recv_buf.is_empty() && self.guest_fin && !self.host_shutdown {
+            // The host may have closed the connection, which is not an error.
+            let _ = self.stream.shutdown(Shutdown::Write);
+            self.host_shutdown = true;
+        }
+        // Tell the guest that the window is open again.
+        if self.state == TcpState::Established
+            && self.window() >= self.adv_wnd + 2 * u32::from(self.mss)
+        {
+            self.ack_pending = true;
+        }
+        true
+    }
+
+    /// Handle the readable socket of host. Returns false if the connection is closed.
+    pub fn host_readable(&mut self, key: &FlowKey, link: &mut Link) -> bool {
+        if self.state == TcpState::Connecting {
+            return true;
+        }
+        if !self.read_host(key, link) {
+            return false;
+        }
+        self.output(key, link, false);
+        !self.is_closed()
+    }
+
+    /// Handle the writable socket of host. Returns false if the connection is closed.
+    pub fn host_writable(&mut self, key: &FlowKey, link: &mut Link) -> bool {
+        if self.state == TcpState::Connecting {
+            match self.stream.take_error() {
+                Ok(None) => {
+                    self.state = TcpState::SynReceived;
+                    self.send_syn(key, link);
+                    return true;
+                }
+                _ => {
+                    // Refuse the SYN of guest.
+                    self.send(key, link, TCP_RST | TCP_ACK, 0, &[]);
+                    return false;
+                }
+            }
+        }
+        if !self.flush_host(key, link) {
+            return false;
+        }
+        self.flush_ack(key, link);
+        !self.is_closed()
+    }
+
+    /// Handle the tick of timer. Returns false if the connection is closed.
+    pub fn tick(&mut self, key: &FlowKey, link: &mut Link) -> bool {
+        match self.rtx_ticks {
+            None => return true,
+            Some(ticks) if ticks > 1 => {
+                self.rtx_ticks = Some(ticks - 1);
+                return true;
+            }
+            Some(_) => {}
+        }
+        self.retries += 1;
+        if self.retries > TCP_MAX_RETRIES {
+            self.send_rst(key, link);
+            return false;
+        }
+        self.rto = min(self.rto * 2, TCP_MAX_RTO);
+        self.rtx_ticks = Some(self.rto);
+        match self.state {
+            TcpState::SynReceived | TcpState::SynSent => self.send_syn(key, link),
+            TcpState::Established => {
+                // Go back to the oldest unacked data.
+                self.snd_nxt = self.snd_una;
+                self.output(key, link, true);
+            }
+            TcpState::Connecting => {}
+        }
+        true
+    }
+}
+
+/// Reply RST for the segment which doesn't belong to any connection.
+pub fn reset_segment(seg: &TcpSegment, key: &FlowKey, link: &mut Link) {
+    if seg.flags & TCP_RST != 0 {
+        return;
+    }
+    let (seq, ack, flags) = if seg.flags & TCP_ACK != 0 {
+        (seg.ack, 0, TCP_RST)
+    } else {
+        (0, seg.seq.wrapping_add(seg.seq_len()), TCP_RST | TCP_ACK)
+    };
+    let hdr = TcpHeader {
+        src: key.remote,
+        dst: key.guest,
+        seq,
+        ack,
+        flags,
+        window: 0,
+        mss: None,
+    };
+    link.send_tcp(&hdr, &[]);
+}
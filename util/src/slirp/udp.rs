@@ -0,0 +1,112 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! NAT of the UDP flows between the guest and the host sockets.
+
+use std::io::ErrorKind;
+use std::net::{SocketAddr, SocketAddrV4, UdpSocket};
+
+use log::warn;
+
+use super::{FlowKey, Link};
+
+/// The flow is removed after being idle for 60 seconds.
+pub const UDP_IDLE_TICKS: u32 = 600;
+const UDP_MAX_DATAGRAM: usize = 65536;
+
+/// The socket bound on host for the forward rule.
+pub struct UdpForward {
+    pub socket: UdpSocket,
+    pub guest: SocketAddrV4,
+}
+
+pub enum UdpPeer {
+    /// The flow started by the guest has its own connected socket.
+    Socket(UdpSocket),
+    /// The flow forwarded from host port, which shares the socket of the rule.
+    Forward { rule: usize, peer: SocketAddr },
+}
+
+pub struct UdpFlow {
+    pub peer: UdpPeer,
+    pub idle_ticks: u32,
+}
+
+impl UdpFlow {
+    /// Create the flow from the guest to `dest` on host.
+    pub fn connect(dest: SocketAddrV4) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+        socket.connect(dest)?;
+        Ok(UdpFlow {
+            peer: UdpPeer::Socket(socket),
+            idle_ticks: 0,
+        })
+    }
+
+    /// Send the datagram from the guest to the peer on host.
+    pub fn send(&mut self, payload: &[u8], forwards: &[UdpForward]) {
+        self.idle_ticks = 0;
+        let ret = match &self.peer {
+            UdpPeer::Socket(socket) => socket.send(payload),
+            UdpPeer::Forward { rule, peer } => forwards[*rule].socket.send_to(payload, peer),
+        };
+        // The datagram may be lost as on the real network.
+        if let Err(e) = ret {
+            if e.kind() != ErrorKind::WouldBlock {
+                warn!("Failed to send the udp datagram of guest: {:?}", e);
+            }
+        }
+    }
+
+    /// Forward all the datagrams received by the socket of the flow to the guest.
+    pub fn receive(&mut self, key: &FlowKey, link: &mut Link) {
+        let socket = match &self.peer {
+            UdpPeer::Socket(socket) => socket,
+            UdpPeer::Forward { .. } => return,
+        };
+        let mut buf = vec![0_u8; UDP_MAX_DATAGRAM];
+        loop {
+            match socket.recv(&mut buf) {
+                Ok(len) => {
+                    self.idle_ticks = 0;
+                    link.send_udp(key.remote, key.guest, &buf[..len]);
+                }
+                // ICMP errors, e.g. port unreachable, are reported on the connected
+                // socket, which are ignored as the guest will time out.
+                Err(e)
+                    if e.kind() == ErrorKind::Interrupted
+                        || e.kind() == ErrorKind::ConnectionRefused =>
+                {
+                    continue
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Receive one datagram from the socket of forward rule.
+pub fn recv_forwarded(socket: &UdpSocket, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+    loop {
+        match socket.recv_from(buf) {
+            Ok(ret) => return Some(ret),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => return None,
+        }
+    }
+}
+
+/// Allocate the buffer for receiving one datagram.
+pub fn datagram_buf() -> Vec<u8> {
+    vec![0_u8; UDP_MAX_DATAGRAM]
+}
@@ -0,0 +1,265 @@
+// Copyright (c) 2022 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use util::aio::{aio_probe, AioEngine, WriteZeroesState};
+use util::arg_parser::{Arg, ArgMatches, ArgParser};
+
+// Read the programe version in `Cargo.toml`.
+const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+const MAX_PATH_LENGTH: usize = 4096;
+// Maximum length of the socket path is restricted by linux.
+const MAX_SOCK_PATH_LENGTH: usize = 108;
+/// The maximum number of virtio queues.
+const MAX_QUEUE_NUM: u16 = 32;
+/// The maximum length of the serial number.
+const MAX_SERIAL_NUM_LENGTH: usize = 20;
+
+/// This function is to define all command line arguments.
+pub fn create_args_parser<'a>() -> ArgParser<'a> {
+    ArgParser::new("VhostUserBlk")
+        .version(VERSION.unwrap_or("unknown"))
+        .author("Huawei Technologies Co., Ltd")
+        .about("The process of vhost-user block backend for StratoVirt.")
+        .arg(
+            Arg::with_name("image")
+                .long("image")
+                .value_name("image_path")
+                .help("set the raw image file or block device in host")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("socket path")
+                .long("socket-path")
+                .value_name("socket_path")
+                .help("vhost-user socket path which communicates with StratoVirt")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("read only")
+                .long("read-only")
+                .value_name("")
+                .help("export the image as a read-only disk")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("direct")
+                .long("direct")
+                .value_name("")
+                .help("open the image with O_DIRECT")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("aio")
+                .long("aio")
+                .value_name("[off | native | io_uring]")
+                .help("set the aio engine, the default is native with direct and off otherwise")
+                .takes_value(true)
+                .possible_values(vec!["off", "native", "io_uring"]),
+        )
+        .arg(
+            Arg::with_name("num queues")
+                .long("num-queues")
+                .value_name("num")
+                .help("set the number of virtio queues, the default is 1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("discard")
+                .long("discard")
+                .value_name("")
+                .help("support the discard requests from the guest")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("write zeroes")
+                .long("write-zeroes")
+                .value_name("[off | on | unmap]")
+                .help("support the write-zeroes requests from the guest")
+                .takes_value(true)
+                .possible_values(vec!["off", "on", "unmap"]),
+        )
+        .arg(
+            Arg::with_name("serial")
+                .long("serial")
+                .value_name("serial_num")
+                .help("set the serial number of the disk")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("display log")
+                .long("D")
+                .value_name("log_path")
+                .help("output log to logfile")
+                .takes_value(true)
+                .can_no_value(true),
+        )
+        .arg(
+            Arg::with_name("seccomp")
+                .long("seccomp")
+                .value_name("[allow | kill | log | trap]")
+                .help("limit syscall(allow, kill, log, trap) eg: -seccomp kill")
+                .takes_value(true)
+                .possible_values(vec!["allow", "kill", "log", "trap"]),
+        )
+        .arg(
+            Arg::with_name("sandbox")
+                .long("sandbox")
+                .value_name("[chroot | namespace]")
+                .help("isolate the daemon process(chroot, namespace). eg: -sandbox namespace")
+                .takes_value(true)
+                .possible_values(vec!["namespace", "chroot"]),
+        )
+}
+
+/// Block device configuration parsed from command line for the process.
+#[derive(Debug, Clone)]
+pub struct BlkConfig {
+    /// The path of the raw image file or block device in host.
+    pub image: String,
+    /// The path of socket file which communicates with StratoVirt.
+    pub sock_path: String,
+    /// Export the image as a read-only disk.
+    pub read_only: bool,
+    /// Open the image with O_DIRECT.
+    pub direct: bool,
+    /// The aio engine of the image.
+    pub aio: AioEngine,
+    /// The number of virtio queues.
+    pub queues: u16,
+    /// Support the discard requests or not.
+    pub discard: bool,
+    /// The write-zeroes state.
+    pub write_zeroes: WriteZeroesState,
+    /// The serial number of the disk.
+    pub serial_num: Option<String>,
+}
+
+impl Default for BlkConfig {
+    fn default() -> Self {
+        BlkConfig {
+            image: String::new(),
+            sock_path: String::new(),
+            read_only: false,
+            direct: false,
+            aio: AioEngine::Off,
+            queues: 1,
+            discard: false,
+            write_zeroes: WriteZeroesState::Off,
+            serial_num: None,
+        }
+    }
+}
+
+impl BlkConfig {
+    /// The directory of the image, which is the root of the sandbox.
+    pub fn image_dir(&self) -> String {
+        Path::new(&self.image)
+            .parent()
+            .map(|dir| dir.to_string_lossy().to_string())
+            .filter(|dir| !dir.is_empty())
+            .unwrap_or_else(|| ".".to_string())
+    }
+
+    fn check_config(&self) -> Result<()> {
+        if self.image.len() > MAX_PATH_LENGTH {
+            bail!("The length of image path is too long {}", self.image.len());
+        }
+
+        if self.sock_path.len() > MAX_SOCK_PATH_LENGTH {
+            bail!(
+                "The length of socket file path is too long {}",
+                self.sock_path.len()
+            );
+        }
+
+        if self.queues < 1 || self.queues > MAX_QUEUE_NUM {
+            bail!(
+                "The number of queues {} is out of range [1, {}]",
+                self.queues,
+                MAX_QUEUE_NUM
+            );
+        }
+
+        if let Some(serial_num) = &self.serial_num {
+            if serial_num.len() > MAX_SERIAL_NUM_LENGTH {
+                bail!(
+                    "The length of serial number is too long {}",
+                    serial_num.len()
+                );
+            }
+        }
+
+        if self.aio != AioEngine::Off {
+            if self.aio == AioEngine::Native && !self.direct {
+                bail!("native aio type should be used with \"direct\" on");
+            }
+            aio_probe(self.aio)?;
+        } else if self.direct {
+            bail!("low performance expected when use sync io with \"direct\" on");
+        }
+
+        Ok(())
+    }
+}
+
+/// Construct a block device configuration parsed from command line.
+///
+/// # Arguments
+/// * `args` - The collection of information about the arguments from command line.
+pub fn create_blk_config(args: &ArgMatches) -> Result<BlkConfig> {
+    let mut blk_config = BlkConfig::default();
+
+    if let Some(image) = args.value_of("image") {
+        blk_config.image = image;
+    }
+
+    if let Some(sock_path) = args.value_of("socket path") {
+        blk_config.sock_path = sock_path;
+    }
+
+    blk_config.read_only = args.is_present("read only");
+    blk_config.direct = args.is_present("direct");
+    blk_config.aio = match args.value_of("aio") {
+        Some(aio) => AioEngine::from_str(&aio).map_err(|_| anyhow!("Invalid aio {}", aio))?,
+        None if blk_config.direct => AioEngine::Native,
+        None => AioEngine::Off,
+    };
+
+    if let Some(queues) = args.value_of("num queues") {
+        blk_config.queues = queues
+            .parse::<u16>()
+            .with_context(|| "Failed to parse num queues")?;
+    }
+
+    blk_config.discard = args.is_present("discard");
+    if let Some(write_zeroes) = args.value_of("write zeroes") {
+        blk_config.write_zeroes = WriteZeroesState::from_str(&write_zeroes)
+            .map_err(|_| anyhow!("Invalid write-zeroes {}", write_zeroes))?;
+    }
+    blk_config.serial_num = args.value_of("serial");
+
+    blk_config
+        .check_config()
+        .with_context(|| "Precheck failed, Config is unhealthy, stop running")?;
+
+    Ok(blk_config)
+}
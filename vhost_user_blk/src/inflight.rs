@@ -0,0 +1,335 @@
+// Copyright (c) 2022 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! The shared memory which tracks the requests in flight, so that the requests
+//! popped but not completed by a crashed backend can be resubmitted after
+//! StratoVirt reconnects to the restarted backend.
+//!
+//! The layout of each queue follows the split virtqueue layout of libvhost-user:
+//! a `QueueRegionSplit` header followed by one `DescStateSplit` per descriptor,
+//! the region of each queue is aligned to 64 bytes.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr::{addr_of_mut, read_volatile, write_volatile};
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+
+use util::unix::do_mmap;
+
+/// Version of the layout of the inflight region.
+const INFLIGHT_VERSION: u16 = 1;
+/// The region of each queue is aligned to this size.
+const INFLIGHT_ALIGNMENT: u64 = 64;
+
+/// The state of a descriptor chain, which is indexed by its head descriptor.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct DescStateSplit {
+    /// Whether the descriptor chain is in flight.
+    inflight: u8,
+    /// Reserved data.
+    padding: [u8; 5],
+    /// Reserved for the batch of used descriptors.
+    next: u16,
+    /// The order in which the descriptor chain is popped.
+    counter: u64,
+}
+
+/// The header of the inflight region of a split virtqueue.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct QueueRegionSplit {
+    /// Reserved features.
+    features: u64,
+    /// Version of the layout.
+    version: u16,
+    /// The number of descriptors.
+    desc_num: u16,
+    /// The head of the descriptor chain which is being pushed to the used ring.
+    last_batch_head: u16,
+    /// The index of the used ring after the last descriptor chain is pushed.
+    used_idx: u16,
+}
+
+fn queue_region_size(queue_size: u16) -> u64 {
+    let size = size_of::<QueueRegionSplit>() + size_of::<DescStateSplit>() * queue_size as usize;
+    (size as u64).div_ceil(INFLIGHT_ALIGNMENT) * INFLIGHT_ALIGNMENT
+}
+
+/// The inflight region of all queues, shared with StratoVirt.
+pub struct InflightRegion {
+    /// The file of the shared memory.
+    file: File,
+    /// Host address of the mapping.
+    addr: u64,
+    /// Size of the mapping.
+    size: u64,
+    /// The number of queues.
+    queue_num: u16,
+    /// The size of queues.
+    queue_size: u16,
+}
+
+impl InflightRegion {
+    /// Allocate a new inflight region in memfd.
+    ///
+    /// # Arguments
+    ///
+    /// * `queue_num` - The number of queues.
+    /// * `queue_size` - The size of queues.
+    pub fn new(queue_num: u16, queue_size: u16) -> Result<Self> {
+        if queue_num == 0 || queue_size == 0 {
+            bail!(
+                "Invalid inflight region, queue num {}, queue size {}",
+                queue_num,
+                queue_size
+            );
+        }
+
+        let name = CString::new("vhost_user_blk_inflight").unwrap();
+        // SAFETY: name is a valid C string.
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            bail!(
+                "Failed to create memfd for inflight region, {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        // SAFETY: fd is created above and owned by the file from now on.
+        let file = unsafe { File::from_raw_fd(fd) };
+        let size = queue_region_size(queue_size) * u64::from(queue_num);
+        file.set_len(size)
+            .with_context(|| format!("Failed to set the size {} of inflight region", size))?;
+
+        let region = Self::map(file, size, 0, queue_num, queue_size)?;
+        for index in 0..queue_num {
+            let header = region.queue_header(index);
+            // SAFETY: the header is in the mapping of the region.
+            unsafe {
+                write_volatile(addr_of_mut!((*header).version), INFLIGHT_VERSION);
+                write_volatile(addr_of_mut!((*header).desc_num), queue_size);
+            }
+        }
+        Ok(region)
+    }
+
+    /// Map the inflight region from StratoVirt, which may be allocated by the previous backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - The file descriptor of the region.
+    /// * `size` - The size of the region.
+    /// * `offset` - The offset of the region in the file.
+    /// * `queue_num` - The number of queues.
+    /// * `queue_size` - The size of queues.
+    pub fn from_fd(
+        fd: RawFd,
+        size: u64,
+        offset: u64,
+        queue_num: u16,
+        queue_size: u16,
+    ) -> Result<Self> {
+        // SAFETY: fd is sent by StratoVirt and owned by the file from now on.
+        let file = unsafe { File::from_raw_fd(fd) };
+        if queue_num == 0
+            || queue_size == 0
+            || size < queue_region_size(queue_size) * u64::from(queue_num)
+        {
+            bail!(
+                "Invalid inflight region, size {}, queue num {}, queue size {}",
+                size,
+                queue_num,
+                queue_size
+            );
+        }
+
+        let region = Self::map(file, size, offset, queue_num, queue_size)?;
+        for index in 0..queue_num {
+            let header = region.queue_header(index);
+            // SAFETY: the header is in the mapping of the region.
+            let (version, desc_num) = unsafe {
+                (
+                    read_volatile(addr_of_mut!((*header).version)),
+                    read_volatile(addr_of_mut!((*header).desc_num)),
+                )
+            };
+            if version != INFLIGHT_VERSION || desc_num != queue_size {
+                bail!(
+                    "Invalid inflight region of queue {}, version {}, desc num {}",
+                    index,
+                    version,
+                    desc_num
+                );
+            }
+        }
+        Ok(region)
+    }
+
+    fn map(file: File, size: u64, offset: u64, queue_num: u16, queue_size: u16) -> Result<Self> {
+        let addr = do_mmap(&Some(&file), size, offset, false, true, false)
+            .with_context(|| "Failed to map inflight region")?;
+        Ok(InflightRegion {
+            file,
+            addr,
+            size,
+            queue_num,
+            queue_size,
+        })
+    }
+
+    fn queue_header(&self, index: u16) -> *mut QueueRegionSplit {
+        (self.addr + queue_region_size(self.queue_size) * u64::from(index)) as *mut QueueRegionSplit
+    }
+
+    /// Get the file descriptor of the region.
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    /// Get the size of the region.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get the inflight tracker of the queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the queue.
+    /// * `size` - The size of the queue set by StratoVirt.
+    pub fn queue(self: &Arc<Self>, index: usize, size: u16) -> Result<InflightQueue> {
+        if index >= self.queue_num as usize || size > self.queue_size {
+            bail!(
+                "The queue {} with size {} is out of inflight region, queue num {}, queue size {}",
+                index,
+                size,
+                self.queue_num,
+                self.queue_size
+            );
+        }
+        Ok(InflightQueue {
+            region: self.clone(),
+            header: self.queue_header(index as u16),
+        })
+    }
+}
+
+impl Drop for InflightRegion {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is created in `map` and not used anymore.
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.size as libc::size_t) };
+    }
+}
+
+/// The inflight tracker of a queue.
+#[derive(Clone)]
+pub struct InflightQueue {
+    /// Keep the region mapped.
+    region: Arc<InflightRegion>,
+    /// The header of the queue region.
+    header: *mut QueueRegionSplit,
+}
+
+// SAFETY: the region is shared memory which lives as long as the tracker, and it is
+// only accessed in the main loop.
+unsafe impl Send for InflightQueue {}
+// SAFETY: same as above.
+unsafe impl Sync for InflightQueue {}
+
+impl InflightQueue {
+    fn desc(&self, head: u16) -> *mut DescStateSplit {
+        debug_assert!(head < self.region.queue_size);
+        // SAFETY: the descriptor state is in the mapping of the region, as the head is
+        // less than the queue size.
+        unsafe { (self.header.add(1) as *mut DescStateSplit).add(head as usize) }
+    }
+
+    /// Mark the descriptor chain as in flight after it is popped from the available ring.
+    ///
+    /// # Arguments
+    ///
+    /// * `head` - The head of the descriptor chain.
+    /// * `counter` - The order in which the descriptor chain is popped.
+    pub fn get(&self, head: u16, counter: u64) {
+        let desc = self.desc(head);
+        // SAFETY: the descriptor state is in the mapping of the region.
+        unsafe {
+            write_volatile(addr_of_mut!((*desc).counter), counter);
+            write_volatile(addr_of_mut!((*desc).inflight), 1);
+        }
+    }
+
+    /// Record the descriptor chain which is being pushed to the used ring.
+    pub fn pre_put(&self, head: u16) {
+        // SAFETY: the header is in the mapping of the region.
+        unsafe { write_volatile(addr_of_mut!((*self.header).last_batch_head), head) };
+    }
+
+    /// Clear the descriptor chain after it is pushed to the used ring.
+    ///
+    /// # Arguments
+    ///
+    /// * `head` - The head of the descriptor chain.
+    /// * `used_idx` - The index of the used ring after the descriptor chain is pushed.
+    pub fn post_put(&self, head: u16, used_idx: u16) {
+        let desc = self.desc(head);
+        // SAFETY: the descriptor state and the header are in the mapping of the region.
+        unsafe {
+            write_volatile(addr_of_mut!((*desc).inflight), 0);
+            fence(Ordering::SeqCst);
+            write_volatile(addr_of_mut!((*self.header).used_idx), used_idx);
+        }
+    }
+
+    /// Get the descriptor chains in flight in the order in which they were popped, and
+    /// the last counter. The descriptor chain which was being pushed when the previous
+    /// backend crashed is fixed up by the index of the used ring.
+    ///
+    /// # Arguments
+    ///
+    /// * `used_idx` - The index of the used ring in guest memory.
+    /// * `size` - The size of the queue.
+    pub fn inflight_descs(&self, used_idx: u16, size: u16) -> (Vec<u16>, u64) {
+        // SAFETY: the descriptor states and the header are in the mapping of the region.
+        unsafe {
+            if read_volatile(addr_of_mut!((*self.header).used_idx)) != used_idx {
+                let head = read_volatile(addr_of_mut!((*self.header).last_batch_head));
+                if head < size {
+                    write_volatile(addr_of_mut!((*self.desc(head)).inflight), 0);
+                }
+                fence(Ordering::SeqCst);
+                write_volatile(addr_of_mut!((*self.header).used_idx), used_idx);
+            }
+
+            let mut descs = Vec::new();
+            let mut last_counter = 0;
+            for head in 0..size {
+                let desc = self.desc(head);
+                let counter = read_volatile(addr_of_mut!((*desc).counter));
+                last_counter = last_counter.max(counter);
+                if read_volatile(addr_of_mut!((*desc).inflight)) == 1 {
+                    descs.push((counter, head));
+                }
+            }
+            descs.sort_unstable();
+            (
+                descs.into_iter().map(|(_, head)| head).collect(),
+                last_counter,
+            )
+        }
+    }
+}
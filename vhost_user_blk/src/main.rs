@@ -0,0 +1,156 @@
+// Copyright (c) 2022 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+pub mod cmdline;
+pub mod inflight;
+pub mod securecomputing;
+pub mod vhost_user_blk;
+pub mod virtio_blk;
+
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use log::{error, info};
+
+use crate::cmdline::{create_args_parser, create_blk_config, BlkConfig};
+use crate::securecomputing::seccomp_filter;
+use crate::vhost_user_blk::VhostUserBlk;
+use machine_manager::event_loop::EventLoop;
+use machine_manager::signal_handler;
+use machine_manager::temp_cleaner::TempCleaner;
+use util::{arg_parser, logger, seccomp::SeccompOpt};
+use vhost_user_fs::sandbox::Sandbox;
+use vhost_user_fs::securecomputing::string_to_seccompopt;
+
+pub trait ExitCode {
+    /// Returns the value to use as the exit status.
+    fn code(self) -> i32;
+}
+
+impl ExitCode for i32 {
+    fn code(self) -> i32 {
+        self
+    }
+}
+
+impl ExitCode for () {
+    fn code(self) -> i32 {
+        0
+    }
+}
+
+fn main() {
+    ::std::process::exit(match run() {
+        Ok(ret) => ExitCode::code(ret),
+        Err(ref e) => {
+            write!(&mut ::std::io::stderr(), "{}", format_args!("{:?}\r\n", e))
+                .expect("Error writing to stderr");
+
+            1
+        }
+    });
+}
+
+fn run() -> Result<()> {
+    let cmd_args = create_args_parser().get_matches()?;
+
+    if let Some(logfile_path) = cmd_args.value_of("display log") {
+        init_log(logfile_path)?;
+    }
+    signal_handler::register_kill_signal();
+    set_panic_hook();
+    match real_main(&cmd_args) {
+        Ok(()) => info!("EventLoop over, Vm exit"),
+        Err(ref e) => {
+            error!("{:?}", e);
+        }
+    }
+
+    Ok(())
+}
+
+fn real_main(cmd_args: &arg_parser::ArgMatches) -> Result<()> {
+    TempCleaner::object_init();
+
+    let blk_config: BlkConfig = create_blk_config(cmd_args)?;
+    info!("BlkConfig is {:?}", blk_config);
+
+    // The image is opened and the socket is listened on before entering the sandbox,
+    // so that the daemon only needs to access the directory of the image afterwards.
+    let mut sandbox = Sandbox::new(blk_config.image_dir());
+    let vhost_user_blk = Arc::new(Mutex::new(
+        VhostUserBlk::new(blk_config).with_context(|| "Failed to create vhost user blk")?,
+    ));
+    if let Some(sandbox_value) = cmd_args.value_of("sandbox") {
+        match sandbox_value.as_str() {
+            "chroot" => sandbox.enable_chroot(),
+            "namespace" => sandbox.enable_namespace(),
+            _ => Ok(()),
+        }?;
+    };
+
+    EventLoop::object_init(&None)?;
+    EventLoop::set_manager(vhost_user_blk.clone(), None);
+    {
+        let locked_blk = vhost_user_blk.lock().unwrap();
+        locked_blk.realize()?;
+        locked_blk
+            .add_event_notifier()
+            .with_context(|| "Failed to add event")?;
+    }
+
+    if let Some(seccomp) = cmd_args.value_of("seccomp") {
+        let seccomp_opt = string_to_seccompopt(seccomp);
+        match seccomp_opt {
+            SeccompOpt::Allow => {}
+            _ => seccomp_filter(seccomp_opt)?,
+        }
+    }
+
+    EventLoop::loop_run().with_context(|| "EventLoop exits unexpectedly: error occurs")?;
+    Ok(())
+}
+
+fn init_log(logfile_path: String) -> Result<()> {
+    if logfile_path.is_empty() {
+        logger::init_logger_with_env(Some(Box::new(std::io::stdout())))
+            .with_context(|| "Failed to init logger")?;
+    } else {
+        let logfile = std::fs::OpenOptions::new()
+            .read(false)
+            .write(true)
+            .append(true)
+            .create(true)
+            .mode(0o640)
+            .open(logfile_path.clone())
+            .with_context(|| format!("Failed to open log file {}", logfile_path))?;
+        logger::init_logger_with_env(Some(Box::new(logfile)))
+            .with_context(|| format!("Failed to init logger {}", logfile_path))?;
+    }
+
+    Ok(())
+}
+
+fn set_panic_hook() {
+    std::panic::set_hook(Box::new(|panic_msg| {
+        TempCleaner::clean();
+        let panic_file = panic_msg.location().map_or("", |loc| loc.file());
+        let panic_line = panic_msg.location().map_or(0, |loc| loc.line());
+        if let Some(msg) = panic_msg.payload().downcast_ref::<&str>() {
+            error!("Panic at [{}: {}]: {}.", panic_file, panic_line, msg);
+        } else {
+            error!("Panic at [{}: {}].", panic_file, panic_line);
+        }
+    }));
+}
@@ -0,0 +1,99 @@
+// Copyright (c) 2022 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{Context, Result};
+
+use util::seccomp::{BpfRule, SeccompOpt, SyscallFilter};
+
+fn syscall_whitelist() -> Vec<i64> {
+    let mut v = vec![libc::SYS_accept4];
+    v.push(libc::SYS_brk);
+    v.push(libc::SYS_clock_gettime);
+    v.push(libc::SYS_clone);
+    v.push(libc::SYS_clone3);
+    v.push(libc::SYS_close);
+    v.push(libc::SYS_epoll_ctl);
+    v.push(libc::SYS_epoll_pwait);
+    #[cfg(target_arch = "x86_64")]
+    v.push(libc::SYS_epoll_wait);
+    v.push(libc::SYS_eventfd2);
+    v.push(libc::SYS_exit);
+    v.push(libc::SYS_exit_group);
+    v.push(libc::SYS_fallocate);
+    v.push(libc::SYS_fcntl);
+    v.push(libc::SYS_fdatasync);
+    v.push(libc::SYS_flock);
+    v.push(libc::SYS_fstat);
+    v.push(libc::SYS_fsync);
+    v.push(libc::SYS_ftruncate);
+    v.push(libc::SYS_futex);
+    v.push(libc::SYS_getpid);
+    v.push(libc::SYS_getrandom);
+    v.push(libc::SYS_gettid);
+    v.push(libc::SYS_gettimeofday);
+    v.push(libc::SYS_io_destroy);
+    v.push(libc::SYS_io_getevents);
+    v.push(libc::SYS_io_setup);
+    v.push(libc::SYS_io_submit);
+    v.push(libc::SYS_io_uring_enter);
+    v.push(libc::SYS_io_uring_register);
+    v.push(libc::SYS_io_uring_setup);
+    v.push(libc::SYS_ioctl);
+    v.push(libc::SYS_lseek);
+    v.push(libc::SYS_madvise);
+    v.push(libc::SYS_memfd_create);
+    v.push(libc::SYS_mmap);
+    v.push(libc::SYS_mprotect);
+    v.push(libc::SYS_munmap);
+    v.push(libc::SYS_newfstatat);
+    v.push(libc::SYS_preadv);
+    v.push(libc::SYS_pread64);
+    v.push(libc::SYS_pwritev);
+    v.push(libc::SYS_pwrite64);
+    v.push(libc::SYS_read);
+    v.push(libc::SYS_recvmsg);
+    v.push(libc::SYS_rt_sigaction);
+    v.push(libc::SYS_rt_sigprocmask);
+    v.push(libc::SYS_rt_sigreturn);
+    v.push(libc::SYS_sched_getaffinity);
+    v.push(libc::SYS_sendmsg);
+    v.push(libc::SYS_set_robust_list);
+    v.push(libc::SYS_sigaltstack);
+    v.push(libc::SYS_statx);
+    #[cfg(target_arch = "x86_64")]
+    v.push(libc::SYS_time);
+    v.push(libc::SYS_tgkill);
+    #[cfg(target_arch = "x86_64")]
+    v.push(libc::SYS_unlink);
+    v.push(libc::SYS_unlinkat);
+    v.push(libc::SYS_write);
+    v.push(libc::SYS_writev);
+    v
+}
+
+/// Enable seccomp to limit syscall. It is enabled after the image is opened and
+/// the event loop is initialized, so the syscalls used for setting up are not allowed.
+///
+/// # Arguments
+///
+/// * `action` - The default action.
+pub fn seccomp_filter(action: SeccompOpt) -> Result<()> {
+    let mut seccomp_filter = SyscallFilter::new(action);
+    let allowed_syscalls = syscall_whitelist();
+    for call in allowed_syscalls {
+        seccomp_filter.push(&mut BpfRule::new(call));
+    }
+    seccomp_filter
+        .realize()
+        .with_context(|| "Failed to realize seccomp filter.")?;
+    Ok(())
+}
@@ -0,0 +1,96 @@
+// Copyright (c) 2022 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use anyhow::{Context, Result};
+
+use crate::cmdline::BlkConfig;
+use crate::virtio_blk::VirtioBlk;
+use machine_manager::{event_loop::EventLoop, temp_cleaner::TempCleaner};
+use util::loop_context::{EventLoopManager, EventNotifierHelper};
+use vhost_user_fs::vhost_user_server::VhostUserServerHandler;
+
+/// The vhost-user block device contains virtio block device and the vhost-user
+/// server which can be connected with the vhost-user client in StratoVirt.
+pub struct VhostUserBlk {
+    /// Used to communicate with StratoVirt.
+    server_handler: VhostUserServerHandler,
+    /// The virtio block device which processes the requests from the guest.
+    virtio_blk: Arc<Mutex<VirtioBlk>>,
+    /// Used to determine whether the process should be terminated.
+    should_exit: Arc<AtomicBool>,
+}
+
+impl VhostUserBlk {
+    /// Create a new vhost-user block device, open the image and listen on the socket.
+    ///
+    /// # Arguments
+    ///
+    /// * `blk_config` - Configuration of the vhost-user block device.
+    pub fn new(blk_config: BlkConfig) -> Result<Self> {
+        let should_exit = Arc::new(AtomicBool::new(false));
+
+        let sock_path = blk_config.sock_path.clone();
+        let virtio_blk = Arc::new(Mutex::new(
+            VirtioBlk::new(blk_config).with_context(|| "Failed to create virtio block")?,
+        ));
+
+        let server_handler = VhostUserServerHandler::new(
+            sock_path.as_str(),
+            virtio_blk.clone(),
+            should_exit.clone(),
+        )
+        .with_context(|| "Failed to create vhost user server")?;
+
+        Ok(VhostUserBlk {
+            server_handler,
+            virtio_blk,
+            should_exit,
+        })
+    }
+
+    /// Create the backend of the image, it must be called after the event loop is initialized.
+    pub fn realize(&self) -> Result<()> {
+        self.virtio_blk
+            .lock()
+            .unwrap()
+            .realize()
+            .with_context(|| "Failed to realize virtio block")
+    }
+
+    /// Add events to epoll handler for the vhost-user block device.
+    pub fn add_event_notifier(&self) -> Result<()> {
+        EventLoop::update_event(
+            EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(
+                self.server_handler.clone(),
+            ))),
+            None,
+        )?;
+
+        Ok(())
+    }
+}
+
+impl EventLoopManager for VhostUserBlk {
+    fn loop_should_exit(&self) -> bool {
+        self.should_exit.load(Ordering::Acquire)
+    }
+
+    fn loop_cleanup(&self) -> util::Result<()> {
+        TempCleaner::clean();
+        Ok(())
+    }
+}
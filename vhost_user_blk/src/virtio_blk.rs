@@ -0,0 +1,1012 @@
+// Copyright (c) 2022 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp;
+use std::fs::File;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::error;
+
+use crate::cmdline::BlkConfig;
+use crate::inflight::{InflightQueue, InflightRegion};
+use address_space::{AddressSpace, FileBackend, GuestAddress, HostMemMapping, Region};
+use block_backend::{create_block_backend, BlockDriverOps, BlockProperty};
+use machine_manager::config::DiskFormat;
+use machine_manager::event_loop::EventLoop;
+use util::aio::{
+    get_iov_size, iov_from_buf_direct, iov_to_buf_direct, raw_datasync, Aio, AioCb, Iovec, OpCode,
+    WriteZeroesState,
+};
+use util::byte_code::ByteCode;
+use util::file::{get_file_alignment, lock_file, open_file};
+use util::loop_context::{
+    gen_delete_notifiers, read_fd, EventNotifier, EventNotifierHelper, NotifierCallback,
+    NotifierOperation,
+};
+use vhost_user_fs::vhost_user_server::VhostUserReqHandler;
+use virtio::device::block::VirtioBlkConfig;
+use virtio::vhost::user::RegionMemInfo;
+use virtio::VhostUser::{
+    VhostUserInflight, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_CONFIG,
+    VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD, VHOST_USER_PROTOCOL_F_MQ,
+};
+use virtio::{
+    iov_discard_back, iov_discard_front, iov_to_buf, virtio_has_feature, Element, Queue,
+    QueueConfig, QUEUE_TYPE_SPLIT_VRING, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ,
+    VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_ID_BYTES,
+    VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_T_DISCARD,
+    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+    VIRTIO_BLK_T_WRITE_ZEROES, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP, VIRTIO_F_RING_EVENT_IDX,
+    VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
+};
+use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
+
+/// The max queue size.
+const VIRTIO_BLK_MAX_QUEUE_SIZE: u16 = 1024;
+/// The maximum number of segments, which fits the default queue size of StratoVirt.
+const VIRTIO_BLK_SEG_MAX: u32 = 254;
+/// Used to compute the number of sectors.
+const SECTOR_SHIFT: u8 = 9;
+/// Size of a sector of the block device.
+const SECTOR_SIZE: u64 = (0x01_u64) << SECTOR_SHIFT;
+/// Max number sectors of per request.
+const MAX_REQUEST_SECTORS: u32 = u32::MAX >> SECTOR_SHIFT;
+/// For VHOST_USER_SET_VRING_KICK and VHOST_USER_SET_VRING_CALL, Bits (0-7) of the
+/// payload contain the vring index. Bit 8 is the invalid FD flag.
+const VIRTIO_BLK_VRING_IDX_MASK: usize = 0xff;
+const VIRTIO_BLK_VRING_NO_FD_MASK: usize = 0x1 << 8;
+
+type BlockBackend = Arc<Mutex<dyn BlockDriverOps<AioCompleteCb>>>;
+
+fn get_serial_num_config(serial_num: &str) -> Vec<u8> {
+    let mut id_bytes = vec![0; VIRTIO_BLK_ID_BYTES as usize];
+    let bytes_to_copy = cmp::min(serial_num.len(), VIRTIO_BLK_ID_BYTES as usize);
+
+    let serial_bytes = serial_num.as_bytes();
+    id_bytes[..bytes_to_copy].clone_from_slice(&serial_bytes[..bytes_to_copy]);
+    id_bytes
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct RequestOutHeader {
+    request_type: u32,
+    io_prio: u32,
+    sector: u64,
+}
+
+impl ByteCode for RequestOutHeader {}
+
+/// The request of discard and write-zeroes use the same struct.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct DiscardWriteZeroesSeg {
+    /// The start sector for discard or write-zeroes.
+    sector: u64,
+    /// The number of sectors for discard or write-zeroes.
+    num_sectors: u32,
+    /// The flags used for this range.
+    flags: u32,
+}
+
+impl ByteCode for DiscardWriteZeroesSeg {}
+
+/// The callback of the io completion, which pushes the request to the used ring.
+#[derive(Clone)]
+struct AioCompleteCb {
+    queue: Arc<Mutex<Queue>>,
+    mem_space: Arc<AddressSpace>,
+    call_evt: Arc<EventFd>,
+    driver_features: u64,
+    inflight: Option<InflightQueue>,
+    desc_index: u16,
+    in_len: u32,
+    in_header: GuestAddress,
+}
+
+impl AioCompleteCb {
+    fn complete_request(&self, status: u8) -> Result<()> {
+        self.mem_space
+            .write_object(&status, self.in_header)
+            .with_context(|| "Failed to write the status (blk io completion)")?;
+
+        let mut queue_lock = self.queue.lock().unwrap();
+        if let Some(inflight) = self.inflight.as_ref() {
+            inflight.pre_put(self.desc_index);
+        }
+        queue_lock
+            .vring
+            .add_used(&self.mem_space, self.desc_index, self.in_len)
+            .with_context(|| {
+                format!(
+                    "Failed to add used ring(blk io completion), index {}, len {}",
+                    self.desc_index, self.in_len
+                )
+            })?;
+        if let Some(inflight) = self.inflight.as_ref() {
+            let used_idx = queue_lock.vring.get_queue_config().get_next_used();
+            inflight.post_put(self.desc_index, used_idx);
+        }
+
+        if queue_lock
+            .vring
+            .should_notify(&self.mem_space, self.driver_features)
+        {
+            self.call_evt
+                .write(1)
+                .with_context(|| "Failed to write call fd")?;
+        }
+        Ok(())
+    }
+}
+
+fn complete_func(aiocb: &AioCb<AioCompleteCb>, ret: i64) -> Result<()> {
+    let mut ret = ret;
+    let complete_cb = &aiocb.iocompletecb;
+    // When driver does not accept FLUSH feature, the device must be of
+    // writethrough cache type, so flush data before updating used ring.
+    if !virtio_has_feature(complete_cb.driver_features, VIRTIO_BLK_F_FLUSH)
+        && aiocb.opcode == OpCode::Pwritev
+        && ret >= 0
+    {
+        let flush_ret = raw_datasync(aiocb.file_fd);
+        if flush_ret < 0 {
+            error!("Failed to flush data before send response to guest.");
+            ret = flush_ret;
+        }
+    }
+
+    let status = if ret < 0 {
+        error!(
+            "Failed to handle block request {:?}, ret {}",
+            aiocb.opcode, ret
+        );
+        VIRTIO_BLK_S_IOERR
+    } else {
+        VIRTIO_BLK_S_OK
+    };
+    complete_cb.complete_request(status)
+}
+
+struct Request {
+    out_header: RequestOutHeader,
+    iovec: Vec<Iovec>,
+    data_len: u64,
+    in_len: u32,
+    in_header: GuestAddress,
+}
+
+impl Request {
+    fn new(handler: &BlkIoHandler, elem: &mut Element, status: &mut u8) -> Result<Self> {
+        if elem.out_iovec.is_empty() || elem.in_iovec.is_empty() {
+            bail!(
+                "Missed header for block request: out {} in {} desc num {}",
+                elem.out_iovec.len(),
+                elem.in_iovec.len(),
+                elem.desc_num
+            );
+        }
+
+        let mut out_header = RequestOutHeader::default();
+        let size = iov_to_buf(
+            &handler.mem_space,
+            &elem.out_iovec,
+            out_header.as_mut_bytes(),
+        )?;
+        if size < size_of::<RequestOutHeader>() {
+            bail!("Invalid out header for block request: length {}", size);
+        }
+        out_header.request_type = u32::from_le(out_header.request_type);
+        out_header.sector = u64::from_le(out_header.sector);
+
+        let in_iov_elem = elem.in_iovec.last().unwrap();
+        if in_iov_elem.len < 1 {
+            bail!(
+                "Invalid in header for block request: length {}",
+                in_iov_elem.len
+            );
+        }
+        // Note: addr plus len has been checked not overflow in virtqueue.
+        let in_header = GuestAddress(in_iov_elem.addr.0 + in_iov_elem.len as u64 - 1);
+
+        let mut request = Request {
+            out_header,
+            iovec: Vec::with_capacity(elem.desc_num as usize),
+            data_len: 0,
+            in_len: 0,
+            in_header,
+        };
+        // We always write the last status byte, so count all in_iovs.
+        for in_iov in elem.in_iovec.iter() {
+            request.in_len += in_iov.len;
+        }
+
+        match out_header.request_type {
+            VIRTIO_BLK_T_IN
+            | VIRTIO_BLK_T_GET_ID
+            | VIRTIO_BLK_T_OUT
+            | VIRTIO_BLK_T_DISCARD
+            | VIRTIO_BLK_T_WRITE_ZEROES => {
+                let data_iovec = match out_header.request_type {
+                    VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                        iov_discard_front(&mut elem.out_iovec, size_of::<RequestOutHeader>() as u64)
+                    }
+                    // Otherwise discard the last "status" byte.
+                    _ => iov_discard_back(&mut elem.in_iovec, 1),
+                }
+                .with_context(|| "Empty data for block request")?;
+                for elem_iov in data_iovec {
+                    let hva = handler
+                        .mem_space
+                        .get_host_address(elem_iov.addr)
+                        .with_context(|| format!("Map desc base {:?} failed", elem_iov.addr))?;
+                    request.iovec.push(Iovec::new(hva, u64::from(elem_iov.len)));
+                    request.data_len += u64::from(elem_iov.len);
+                }
+            }
+            VIRTIO_BLK_T_FLUSH => (),
+            others => {
+                error!("Request type {} is not supported for block", others);
+                *status = VIRTIO_BLK_S_UNSUPP;
+            }
+        }
+
+        if !request.io_range_valid(handler.disk_sectors) {
+            *status = VIRTIO_BLK_S_IOERR;
+        }
+
+        Ok(request)
+    }
+
+    fn io_range_valid(&self, disk_sectors: u64) -> bool {
+        match self.out_header.request_type {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
+                if self.data_len % SECTOR_SIZE != 0 {
+                    error!("Failed to process block request with size not aligned to 512B");
+                    return false;
+                }
+                if (self.data_len / SECTOR_SIZE)
+                    .checked_add(self.out_header.sector)
+                    .filter(|&off| off <= disk_sectors)
+                    .is_none()
+                {
+                    error!(
+                        "offset {} invalid, disk sector {}",
+                        self.out_header.sector, disk_sectors
+                    );
+                    return false;
+                }
+                true
+            }
+            _ => true,
+        }
+    }
+
+    fn execute(&self, handler: &BlkIoHandler, aiocompletecb: AioCompleteCb) -> Result<()> {
+        let request_type = self.out_header.request_type;
+        if handler.read_only
+            && matches!(
+                request_type,
+                VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES
+            )
+        {
+            error!("Failed to write the read-only block device");
+            return aiocompletecb.complete_request(VIRTIO_BLK_S_IOERR);
+        }
+
+        let offset = (self.out_header.sector << SECTOR_SHIFT) as usize;
+        let mut locked_backend = handler.backend.lock().unwrap();
+        match request_type {
+            VIRTIO_BLK_T_IN => {
+                locked_backend
+                    .read_vectored(self.iovec.clone(), offset, aiocompletecb)
+                    .with_context(|| "Failed to process block request for reading")?;
+            }
+            VIRTIO_BLK_T_OUT => {
+                locked_backend
+                    .write_vectored(self.iovec.clone(), offset, aiocompletecb)
+                    .with_context(|| "Failed to process block request for writing")?;
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                locked_backend
+                    .datasync(aiocompletecb)
+                    .with_context(|| "Failed to process block request for flushing")?;
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                let serial = handler.serial_num.clone().unwrap_or_default();
+                let serial_vec = get_serial_num_config(&serial);
+                let status = iov_from_buf_direct(&self.iovec, &serial_vec).map_or_else(
+                    |e| {
+                        error!("Failed to process block request for getting id, {:?}", e);
+                        VIRTIO_BLK_S_IOERR
+                    },
+                    |_| VIRTIO_BLK_S_OK,
+                );
+                aiocompletecb.complete_request(status)?;
+            }
+            VIRTIO_BLK_T_DISCARD => {
+                if !handler.discard {
+                    error!("Device does not support discard");
+                    return aiocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
+                }
+                self.handle_discard_write_zeroes_req(
+                    handler,
+                    &mut *locked_backend,
+                    aiocompletecb,
+                    OpCode::Discard,
+                )?;
+            }
+            VIRTIO_BLK_T_WRITE_ZEROES => {
+                if handler.write_zeroes == WriteZeroesState::Off {
+                    error!("Device does not support write-zeroes");
+                    return aiocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
+                }
+                self.handle_discard_write_zeroes_req(
+                    handler,
+                    &mut *locked_backend,
+                    aiocompletecb,
+                    OpCode::WriteZeroes,
+                )?;
+            }
+            // The illegal request type has been handled in method new().
+            _ => {}
+        };
+        Ok(())
+    }
+
+    fn handle_discard_write_zeroes_req(
+        &self,
+        handler: &BlkIoHandler,
+        block_backend: &mut dyn BlockDriverOps<AioCompleteCb>,
+        aiocompletecb: AioCompleteCb,
+        opcode: OpCode,
+    ) -> Result<()> {
+        let size = size_of::<DiscardWriteZeroesSeg>() as u64;
+        // Just support one segment per request.
+        if self.data_len > size {
+            error!("More than one discard or write-zeroes segment is not supported");
+            return aiocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
+        }
+
+        let mut segment = DiscardWriteZeroesSeg::default();
+        let len = iov_to_buf_direct(&self.iovec, segment.as_mut_bytes())?;
+        if len as u64 != size {
+            return Err(anyhow!("Invalid discard segment size {}", len));
+        }
+        let sector = u64::from_le(segment.sector);
+        let num_sectors = u32::from_le(segment.num_sectors);
+        if sector
+            .checked_add(num_sectors as u64)
+            .filter(|&off| off <= handler.disk_sectors)
+            .is_none()
+            || num_sectors > MAX_REQUEST_SECTORS
+        {
+            error!(
+                "Invalid discard or write zeroes request, sector offset {}, num_sectors {}",
+                sector, num_sectors
+            );
+            return aiocompletecb.complete_request(VIRTIO_BLK_S_IOERR);
+        }
+        let flags = u32::from_le(segment.flags);
+        if flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 {
+            error!("Invalid unmap flags 0x{:x}", flags);
+            return aiocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
+        }
+
+        let offset = (sector as usize) << SECTOR_SHIFT;
+        let nbytes = (num_sectors as u64) << SECTOR_SHIFT;
+        if opcode == OpCode::Discard {
+            if flags == VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP {
+                error!("Discard request must not set unmap flags");
+                return aiocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
+            }
+            block_backend
+                .discard(offset, nbytes, aiocompletecb)
+                .with_context(|| "Failed to process block request for discard")
+        } else {
+            let unmap = flags == VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP && handler.discard;
+            block_backend
+                .write_zeroes(offset, nbytes, aiocompletecb, unmap)
+                .with_context(|| "Failed to process block request for write-zeroes")
+        }
+    }
+}
+
+/// The handler of a virtio queue.
+struct BlkIoHandler {
+    queue: Arc<Mutex<Queue>>,
+    kick_evt: Arc<EventFd>,
+    call_evt: Arc<EventFd>,
+    mem_space: Arc<AddressSpace>,
+    driver_features: u64,
+    backend: BlockBackend,
+    disk_sectors: u64,
+    read_only: bool,
+    discard: bool,
+    write_zeroes: WriteZeroesState,
+    serial_num: Option<String>,
+    /// The inflight tracker of the queue.
+    inflight: Option<InflightQueue>,
+    /// The order of the last popped descriptor chain.
+    inflight_counter: u64,
+}
+
+impl BlkIoHandler {
+    fn handle_element(&self, mut elem: Element) -> Result<()> {
+        let mut status = VIRTIO_BLK_S_OK;
+        let req = Request::new(self, &mut elem, &mut status)?;
+        let aiocompletecb = AioCompleteCb {
+            queue: self.queue.clone(),
+            mem_space: self.mem_space.clone(),
+            call_evt: self.call_evt.clone(),
+            driver_features: self.driver_features,
+            inflight: self.inflight.clone(),
+            desc_index: elem.index,
+            in_len: req.in_len,
+            in_header: req.in_header,
+        };
+        if status != VIRTIO_BLK_S_OK {
+            return aiocompletecb.complete_request(status);
+        }
+        req.execute(self, aiocompletecb)
+    }
+
+    fn process_queue(&mut self) -> Result<()> {
+        loop {
+            let elem = self
+                .queue
+                .lock()
+                .unwrap()
+                .vring
+                .pop_avail(&self.mem_space, self.driver_features)
+                .with_context(|| "Failed to pop avail ring for process block queue")?;
+            if elem.desc_num == 0 {
+                break;
+            }
+
+            if let Some(inflight) = self.inflight.as_ref() {
+                self.inflight_counter += 1;
+                inflight.get(elem.index, self.inflight_counter);
+            }
+            self.handle_element(elem)?;
+        }
+
+        self.backend.lock().unwrap().flush_request()
+    }
+
+    /// Resubmit the requests which were popped but not completed by the previous backend.
+    fn resubmit_inflight(&mut self, heads: Vec<u16>) -> Result<()> {
+        for head in heads {
+            let elem = self
+                .queue
+                .lock()
+                .unwrap()
+                .vring
+                .get_desc_chain(&self.mem_space, head)
+                .with_context(|| format!("Failed to get the inflight descriptor chain {}", head))?;
+            self.handle_element(elem)?;
+        }
+
+        self.backend.lock().unwrap().flush_request()
+    }
+
+    fn delete_notifiers(&self) -> Vec<EventNotifier> {
+        gen_delete_notifiers(&[self.kick_evt.as_raw_fd()])
+    }
+}
+
+impl EventNotifierHelper for BlkIoHandler {
+    fn internal_notifiers(blk_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut notifiers = Vec::new();
+
+        let blk_handler_clone = blk_handler.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            if let Err(e) = blk_handler_clone.lock().unwrap().process_queue() {
+                error!("Failed to process block queue, {:?}", e);
+            }
+            None
+        });
+        notifiers.push(EventNotifier::new(
+            NotifierOperation::AddShared,
+            blk_handler.lock().unwrap().kick_evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![handler],
+        ));
+
+        notifiers
+    }
+}
+
+struct QueueInfo {
+    config: QueueConfig,
+    kick_evt: Option<Arc<EventFd>>,
+    call_evt: Option<Arc<EventFd>>,
+}
+
+impl QueueInfo {
+    fn new(queue_size: u16) -> Self {
+        QueueInfo {
+            config: QueueConfig::new(queue_size),
+            kick_evt: None,
+            call_evt: None,
+        }
+    }
+}
+
+/// The virtio block device contains the configuration of virtio block, the raw file
+/// backend of the image and the handlers used to process requests in virtio queues
+/// from the guest.
+pub struct VirtioBlk {
+    /// The configuration from command line.
+    blk_config: BlkConfig,
+    /// The opened image, which is handed over to the backend when realizing.
+    file: Option<File>,
+    /// The raw file backend of the image.
+    backend: Option<BlockBackend>,
+    /// The capacity of the disk in sectors.
+    disk_sectors: u64,
+    /// The virtio block configuration space.
+    config_space: VirtioBlkConfig,
+    /// Bitmask of features supported by the device.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// The configuration of virtio queues.
+    queues_info: Vec<QueueInfo>,
+    /// Block handlers of virtio queues.
+    blk_handlers: Vec<Option<Arc<Mutex<BlkIoHandler>>>>,
+    /// Address space mapped with StratoVirt.
+    sys_mem: Arc<AddressSpace>,
+    /// The memory regions mapped with StratoVirt.
+    mem_regions: Vec<Region>,
+    /// The guest memory region information.
+    mem_info: Vec<RegionMemInfo>,
+    /// The shared memory which tracks the requests in flight.
+    inflight: Option<Arc<InflightRegion>>,
+}
+
+impl VirtioBlk {
+    /// Construct a virtio block device and open the image.
+    ///
+    /// # Arguments
+    ///
+    /// * `blk_config` - The configuration from command line.
+    pub fn new(blk_config: BlkConfig) -> Result<Self> {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value()))
+            .with_context(|| "Failed to create address space")?;
+
+        let file = open_file(&blk_config.image, blk_config.read_only, blk_config.direct)?;
+        lock_file(&file, &blk_config.image, blk_config.read_only)?;
+
+        let mut queues_info = Vec::new();
+        let mut blk_handlers = Vec::new();
+        for _i in 0..blk_config.queues {
+            queues_info.push(QueueInfo::new(VIRTIO_BLK_MAX_QUEUE_SIZE));
+            blk_handlers.push(None);
+        }
+
+        Ok(VirtioBlk {
+            blk_config,
+            file: Some(file),
+            backend: None,
+            disk_sectors: 0,
+            config_space: VirtioBlkConfig::default(),
+            device_features: 0,
+            driver_features: 0,
+            queues_info,
+            blk_handlers,
+            sys_mem,
+            mem_regions: Vec::new(),
+            mem_info: Vec::new(),
+            inflight: None,
+        })
+    }
+
+    /// Create the raw file backend of the image and build the device features and
+    /// configuration space. It must be called after the event loop is initialized.
+    pub fn realize(&mut self) -> Result<()> {
+        let file = self
+            .file
+            .take()
+            .with_context(|| "The virtio block device has been realized")?;
+        let (req_align, buf_align) = get_file_alignment(&file, self.blk_config.direct);
+        let aio = Aio::new(Arc::new(complete_func), self.blk_config.aio)?;
+        let conf = BlockProperty {
+            id: "vhost-user-blk".to_string(),
+            path: self.blk_config.image.clone(),
+            format: DiskFormat::Raw,
+            iothread: None,
+            direct: self.blk_config.direct,
+            req_align,
+            buf_align,
+            discard: self.blk_config.discard,
+            write_zeroes: self.blk_config.write_zeroes,
+        };
+        let backend = create_block_backend(file, aio, conf)?;
+        let disk_size = {
+            let mut locked_backend = backend.lock().unwrap();
+            locked_backend.register_io_event(
+                Arc::new(AtomicBool::new(false)),
+                Arc::new(|| error!("Failed to handle the io completion of vhost-user-blk")),
+            )?;
+            locked_backend.disk_size()?
+        };
+        self.disk_sectors = disk_size >> SECTOR_SHIFT;
+        self.backend = Some(backend);
+
+        self.device_features = 1_u64 << VIRTIO_F_VERSION_1
+            | 1_u64 << VIRTIO_F_RING_INDIRECT_DESC
+            | 1_u64 << VIRTIO_F_RING_EVENT_IDX
+            | 1_u64 << VIRTIO_BLK_F_FLUSH
+            | 1_u64 << VIRTIO_BLK_F_SEG_MAX
+            | 1_u64 << VHOST_USER_F_PROTOCOL_FEATURES;
+        self.config_space.capacity = self.disk_sectors;
+        self.config_space.seg_max = VIRTIO_BLK_SEG_MAX;
+        if self.blk_config.read_only {
+            self.device_features |= 1_u64 << VIRTIO_BLK_F_RO;
+        }
+        if self.blk_config.queues > 1 {
+            self.device_features |= 1_u64 << VIRTIO_BLK_F_MQ;
+        }
+        self.config_space.num_queues = self.blk_config.queues;
+        if self.blk_config.discard {
+            self.device_features |= 1_u64 << VIRTIO_BLK_F_DISCARD;
+            // Just support one segment per request.
+            self.config_space.max_discard_seg = 1;
+            // The default discard alignment is 1 sector.
+            self.config_space.discard_sector_alignment = 1;
+            self.config_space.max_discard_sectors = MAX_REQUEST_SECTORS;
+        }
+        if self.blk_config.write_zeroes != WriteZeroesState::Off {
+            self.device_features |= 1_u64 << VIRTIO_BLK_F_WRITE_ZEROES;
+            // Just support one segment per request.
+            self.config_space.max_write_zeroes_seg = 1;
+            self.config_space.max_write_zeroes_sectors = MAX_REQUEST_SECTORS;
+            self.config_space.write_zeroes_may_unmap = 1;
+        }
+
+        Ok(())
+    }
+
+    fn get_guest_address(&self, addr: u64) -> Result<u64> {
+        for info in self.mem_info.iter() {
+            if addr >= info.userspace_addr && addr < info.userspace_addr + info.memory_size {
+                return Ok(info.guest_phys_addr + addr - info.userspace_addr);
+            }
+        }
+
+        bail!("Failed to find the guest address for addr: 0x{:X}", addr);
+    }
+
+    fn get_mut_queue_info(&mut self, queue_index: usize) -> Result<&mut QueueInfo> {
+        self.queues_info
+            .get_mut(queue_index)
+            .with_context(|| format!("The select index of queue {} overflows", queue_index))
+    }
+
+    /// Stop processing the virtio queue and save its state.
+    fn stop_queue(&mut self, queue_index: usize) -> Result<()> {
+        if let Some(blk_handler) = self.blk_handlers[queue_index].take() {
+            let locked_handler = blk_handler.lock().unwrap();
+            EventLoop::update_event(locked_handler.delete_notifiers(), None)
+                .with_context(|| "Failed to update event for queue status which is not ready")?;
+            let config = locked_handler
+                .queue
+                .lock()
+                .unwrap()
+                .vring
+                .get_queue_config();
+            self.queues_info[queue_index].config = config;
+        }
+        Ok(())
+    }
+
+    fn start_queue(&mut self, queue_index: usize) -> Result<()> {
+        let backend = self
+            .backend
+            .clone()
+            .with_context(|| "The virtio block device is not realized")?;
+        let driver_features = self.driver_features;
+        let queue_info = &mut self.queues_info[queue_index];
+        if queue_info.kick_evt.is_none() || queue_info.call_evt.is_none() {
+            bail!(
+                "The event for kicking {} or calling {} is none",
+                queue_info.kick_evt.is_none(),
+                queue_info.call_evt.is_none(),
+            );
+        }
+
+        let inflight = match self.inflight.as_ref() {
+            Some(region) => Some(region.queue(queue_index, queue_info.config.size)?),
+            None => None,
+        };
+        let mut resubmit = Vec::new();
+        let mut inflight_counter = 0;
+        if let Some(inflight) = inflight.as_ref() {
+            // Continue from the used ring of the previous backend and resubmit the
+            // requests it left in flight.
+            let used_idx = self
+                .sys_mem
+                .read_object_direct::<u16>(queue_info.config.addr_cache.used_ring_host + 2)
+                .with_context(|| "Failed to read the index of used ring")?;
+            (resubmit, inflight_counter) =
+                inflight.inflight_descs(used_idx, queue_info.config.size);
+            queue_info
+                .config
+                .set_next_idx(used_idx.wrapping_add(resubmit.len() as u16), used_idx);
+        }
+
+        let queue = Queue::new(queue_info.config, QUEUE_TYPE_SPLIT_VRING)
+            .with_context(|| "Failed to create virtual queue")?;
+        if !queue.is_valid(&self.sys_mem) {
+            bail!("Invalid queue for block handler");
+        }
+        let blk_handler = Arc::new(Mutex::new(BlkIoHandler {
+            queue: Arc::new(Mutex::new(queue)),
+            kick_evt: queue_info.kick_evt.as_ref().unwrap().clone(),
+            call_evt: queue_info.call_evt.as_ref().unwrap().clone(),
+            mem_space: self.sys_mem.clone(),
+            driver_features,
+            backend,
+            disk_sectors: self.disk_sectors,
+            read_only: self.blk_config.read_only,
+            discard: self.blk_config.discard,
+            write_zeroes: self.blk_config.write_zeroes,
+            serial_num: self.blk_config.serial_num.clone(),
+            inflight,
+            inflight_counter,
+        }));
+        if !resubmit.is_empty() {
+            blk_handler
+                .lock()
+                .unwrap()
+                .resubmit_inflight(resubmit)
+                .with_context(|| "Failed to resubmit the inflight requests")?;
+        }
+
+        self.blk_handlers[queue_index] = Some(blk_handler.clone());
+        EventLoop::update_event(EventNotifierHelper::internal_notifiers(blk_handler), None)
+            .with_context(|| "Failed to update event for queue status which is ready")?;
+        Ok(())
+    }
+}
+
+impl VhostUserReqHandler for VirtioBlk {
+    fn set_owner(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_features(&self) -> Result<u64> {
+        Ok(self.device_features)
+    }
+
+    fn set_features(&mut self, features: u64) -> Result<()> {
+        self.driver_features = features;
+        Ok(())
+    }
+
+    fn set_mem_table(&mut self, regions: &[RegionMemInfo], fds: &[RawFd]) -> Result<()> {
+        for region in &self.mem_regions {
+            if let Err(e) = self.sys_mem.root().delete_subregion(region) {
+                error!("Failed to delete subregion for setting mem table, {:?}", e);
+            }
+        }
+        self.mem_regions = Vec::new();
+        self.mem_info = regions.to_vec();
+
+        for (index, region_config) in regions.iter().enumerate() {
+            // SAFETY: the fd is sent by StratoVirt and owned by the file from now on.
+            let file = unsafe { File::from_raw_fd(fds[index]) };
+            let fileback = FileBackend {
+                file: Arc::new(file),
+                offset: region_config.mmap_offset,
+                page_size: 0_u64,
+            };
+            let mmap = Arc::new(
+                HostMemMapping::new(
+                    GuestAddress(region_config.guest_phys_addr),
+                    None,
+                    region_config.memory_size,
+                    Some(fileback),
+                    false,
+                    true,
+                    false,
+                )
+                .with_context(|| {
+                    format!(
+                        "Failed to create the mapping of host memory for setting mem table, addr: 0x{:X}, size: {}, offset: {}",
+                        region_config.guest_phys_addr, region_config.memory_size, region_config.mmap_offset,
+                    )
+                })?,
+            );
+
+            let region = Region::init_ram_region(mmap.clone());
+            self.sys_mem
+                .root()
+                .add_subregion(region.clone(), mmap.start_address().raw_value())
+                .with_context(|| "Failed to add subregion for setting mem table")?;
+            self.mem_regions.push(region);
+        }
+
+        Ok(())
+    }
+
+    fn set_vring_num(&mut self, queue_index: usize, num: u16) -> Result<()> {
+        if num == 0 || num > VIRTIO_BLK_MAX_QUEUE_SIZE {
+            bail!(
+                "Failed to set vring num, index: {}, num: {}",
+                queue_index,
+                num
+            );
+        }
+        self.get_mut_queue_info(queue_index)?.config.size = num;
+        Ok(())
+    }
+
+    fn set_vring_addr(
+        &mut self,
+        queue_index: usize,
+        _flags: u32,
+        desc_table: u64,
+        used_ring: u64,
+        avail_ring: u64,
+        _log: u64,
+    ) -> Result<()> {
+        let desc_addr = self.get_guest_address(desc_table)?;
+        let used_addr = self.get_guest_address(used_ring)?;
+        let avail_addr = self.get_guest_address(avail_ring)?;
+        let sys_mem = self.sys_mem.clone();
+        let config = &mut self.get_mut_queue_info(queue_index)?.config;
+
+        config.desc_table = GuestAddress(desc_addr);
+        config.avail_ring = GuestAddress(avail_addr);
+        config.used_ring = GuestAddress(used_addr);
+        config.addr_cache.desc_table_host =
+            sys_mem.get_host_address(config.desc_table).unwrap_or(0);
+        config.addr_cache.avail_ring_host =
+            sys_mem.get_host_address(config.avail_ring).unwrap_or(0);
+        config.addr_cache.used_ring_host = sys_mem.get_host_address(config.used_ring).unwrap_or(0);
+        if config.addr_cache.desc_table_host == 0
+            || config.addr_cache.avail_ring_host == 0
+            || config.addr_cache.used_ring_host == 0
+        {
+            bail!(
+                "Failed to set vring addr, got host address failed. Index: {}, desc: 0x{:X}, avail: 0x{:X}, used: 0x{:X}",
+                queue_index,
+                desc_addr,
+                avail_addr,
+                used_addr
+            );
+        }
+
+        Ok(())
+    }
+
+    fn set_vring_base(&mut self, queue_index: usize, num: u16) -> Result<()> {
+        // The used ring is always consumed by the guest before the vring is restarted.
+        self.get_mut_queue_info(queue_index)?
+            .config
+            .set_next_idx(num, num);
+        Ok(())
+    }
+
+    fn set_vring_call(&mut self, queue_index: usize, fd: RawFd) -> Result<()> {
+        // SAFETY: the fd is sent by StratoVirt and owned by the eventfd from now on.
+        let call_evt = unsafe { EventFd::from_raw_fd(fd) };
+        if (queue_index & VIRTIO_BLK_VRING_NO_FD_MASK) != 0 {
+            bail!("The polling mode is not supported");
+        }
+        let index = queue_index & VIRTIO_BLK_VRING_IDX_MASK;
+        self.get_mut_queue_info(index)?.call_evt = Some(Arc::new(call_evt));
+        Ok(())
+    }
+
+    fn set_vring_kick(&mut self, queue_index: usize, fd: RawFd) -> Result<()> {
+        // SAFETY: the fd is sent by StratoVirt and owned by the eventfd from now on.
+        let kick_evt = unsafe { EventFd::from_raw_fd(fd) };
+        if (queue_index & VIRTIO_BLK_VRING_NO_FD_MASK) != 0 {
+            bail!("The polling mode is not supported");
+        }
+        let index = queue_index & VIRTIO_BLK_VRING_IDX_MASK;
+        self.get_mut_queue_info(index)?.kick_evt = Some(Arc::new(kick_evt));
+        Ok(())
+    }
+
+    fn set_vring_enable(&mut self, queue_index: usize, status: u32) -> Result<()> {
+        self.get_mut_queue_info(queue_index)?.config.ready = status == 1;
+
+        // Before setting up new notifiers, we should remove old ones.
+        self.stop_queue(queue_index)?;
+        if status == 1 {
+            self.start_queue(queue_index)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_protocol_features(&self) -> Result<u64> {
+        Ok(1_u64 << VHOST_USER_PROTOCOL_F_MQ
+            | 1_u64 << VHOST_USER_PROTOCOL_F_CONFIG
+            | 1_u64 << VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD)
+    }
+
+    fn set_protocol_features(&mut self, _features: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_queue_num(&self) -> Result<u64> {
+        Ok(u64::from(self.blk_config.queues))
+    }
+
+    fn get_config(&self, offset: u32, size: u32) -> Result<Vec<u8>> {
+        let config = self.config_space.as_bytes();
+        let start = offset as usize;
+        let end = start
+            .checked_add(size as usize)
+            .filter(|&end| end <= config.len())
+            .with_context(|| {
+                format!(
+                    "Failed to read config, offset {} size {} overflows",
+                    offset, size
+                )
+            })?;
+        Ok(config[start..end].to_vec())
+    }
+
+    fn set_config(&mut self, _offset: u32, _data: &[u8]) -> Result<()> {
+        // The writeback mode is not supported, so writing to the read-only
+        // configuration space is ignored.
+        Ok(())
+    }
+
+    fn get_vring_base(&mut self, queue_index: usize) -> Result<u16> {
+        self.get_mut_queue_info(queue_index)?.config.ready = false;
+        self.stop_queue(queue_index)?;
+        Ok(self.queues_info[queue_index].config.get_next_avail())
+    }
+
+    fn get_inflight_fd(
+        &mut self,
+        queue_num: u16,
+        queue_size: u16,
+    ) -> Result<(VhostUserInflight, RawFd)> {
+        let region = Arc::new(InflightRegion::new(queue_num, queue_size)?);
+        let inflight = VhostUserInflight {
+            mmap_size: region.size(),
+            mmap_offset: 0,
+            queue_num,
+            queue_size,
+        };
+        let fd = region.as_raw_fd();
+        self.inflight = Some(region);
+        Ok((inflight, fd))
+    }
+
+    fn set_inflight_fd(&mut self, inflight: &VhostUserInflight, fd: RawFd) -> Result<()> {
+        let region = InflightRegion::from_fd(
+            fd,
+            inflight.mmap_size,
+            inflight.mmap_offset,
+            inflight.queue_num,
+            inflight.queue_size,
+        )?;
+        self.inflight = Some(Arc::new(region));
+        Ok(())
+    }
+}
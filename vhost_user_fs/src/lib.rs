@@ -0,0 +1,24 @@
+// Copyright (c) 2022 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+pub mod cmdline;
+pub mod error;
+pub mod fs;
+pub mod fs_ops;
+pub mod fuse_msg;
+pub mod fuse_proc;
+pub mod fuse_req;
+pub mod sandbox;
+pub mod securecomputing;
+pub mod vhost_user_fs;
+pub mod vhost_user_server;
+pub mod virtio_fs;
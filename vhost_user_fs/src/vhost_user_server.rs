@@ -22,9 +22,19 @@ use machine_manager::temp_cleaner::TempCleaner;
 use util::unix::limit_permission;
 use virtio::vhost::user::{
     RegionMemInfo, VhostUserHdrFlag, VhostUserMemHdr, VhostUserMsgHdr, VhostUserMsgReq,
-    VhostUserVringAddr, VhostUserVringState, MAX_ATTACHED_FD_ENTRIES,
+    VhostUserVringAddr, VhostUserVringState, MAX_ATTACHED_FD_ENTRIES, VHOST_USER_MAX_CONFIG_SIZE,
 };
-use virtio::VhostUser::VhostUserSock;
+use virtio::VhostUser::{VhostUserInflight, VhostUserSock};
+
+/// The header of the payload for VHOST_USER_GET_CONFIG and VHOST_USER_SET_CONFIG,
+/// it is followed by `size` bytes of the device configuration space.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VhostUserConfigHdr {
+    offset: u32,
+    size: u32,
+    flags: u32,
+}
 
 /// The trait for dealing with vhost-user request in the server.
 pub trait VhostUserReqHandler: Send + Sync {
@@ -111,6 +121,81 @@ pub trait VhostUserReqHandler: Send + Sync {
     /// * `queue_index` - The index of virtio queue.
     /// * `status` - The status of virtio queue.
     fn set_vring_enable(&mut self, queue_index: usize, status: u32) -> Result<()>;
+
+    /// Get a bitmask of supported vhost-user protocol features.
+    fn get_protocol_features(&self) -> Result<u64> {
+        bail!("Getting protocol features is not supported");
+    }
+
+    /// Inform the backend which protocol features to enable.
+    ///
+    /// # Arguments
+    ///
+    /// * `features` - The protocol features from the vhost-user client in StratoVirt.
+    fn set_protocol_features(&mut self, _features: u64) -> Result<()> {
+        bail!("Setting protocol features is not supported");
+    }
+
+    /// Get the maximum number of virtio queues supported by the backend.
+    fn get_queue_num(&self) -> Result<u64> {
+        bail!("Getting queue num is not supported");
+    }
+
+    /// Read the device configuration space.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset in the configuration space.
+    /// * `size` - The length of data to read.
+    fn get_config(&self, _offset: u32, _size: u32) -> Result<Vec<u8>> {
+        bail!("Getting config is not supported");
+    }
+
+    /// Write the device configuration space.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset in the configuration space.
+    /// * `data` - The data to write.
+    fn set_config(&mut self, _offset: u32, _data: &[u8]) -> Result<()> {
+        bail!("Setting config is not supported");
+    }
+
+    /// Stop the virtio queue and get the next index of the available ring to process.
+    ///
+    /// # Arguments
+    ///
+    /// * `queue_index` - The index of virtio queue.
+    fn get_vring_base(&mut self, _queue_index: usize) -> Result<u16> {
+        bail!("Getting vring base is not supported");
+    }
+
+    /// Allocate the shared memory to track the requests in flight, return its layout
+    /// and the file descriptor of it.
+    ///
+    /// # Arguments
+    ///
+    /// * `queue_num` - The number of virtio queues.
+    /// * `queue_size` - The size of virtio queues.
+    fn get_inflight_fd(
+        &mut self,
+        _queue_num: u16,
+        _queue_size: u16,
+    ) -> Result<(VhostUserInflight, RawFd)> {
+        bail!("Getting inflight fd is not supported");
+    }
+
+    /// Set the shared memory which tracks the requests in flight, it may be allocated
+    /// by the previous backend which is disconnected.
+    ///
+    /// # Arguments
+    ///
+    /// * `inflight` - The layout of the shared memory.
+    /// * `fd` - The file descriptor of the shared memory.
+    fn set_inflight_fd(&mut self, _inflight: &VhostUserInflight, fd: RawFd) -> Result<()> {
+        close_fds(vec![fd]);
+        bail!("Setting inflight fd is not supported");
+    }
 }
 
 /// The vhost-user server handler can communicate with StratoVirt and set the data of requests
@@ -137,6 +222,7 @@ fn is_invalid_fds(hdr: &mut VhostUserMsgHdr, rfds: Option<Vec<RawFd>>) -> Result
         VhostUserMsgReq::SetVringCall => Ok(()),
         VhostUserMsgReq::SetVringKick => Ok(()),
         VhostUserMsgReq::SetSlaveReqFd => Ok(()),
+        VhostUserMsgReq::SetInflightFd => Ok(()),
         _ => {
             if rfds.is_some() {
                 if let Some(fds) = rfds {
@@ -272,6 +358,31 @@ impl VhostUserServerHandler {
         Ok(())
     }
 
+    fn get_config_hdr(
+        &self,
+        hdr: &VhostUserMsgHdr,
+        buf: &[u8],
+        len: usize,
+    ) -> Result<VhostUserConfigHdr> {
+        let hdr_size = size_of::<VhostUserConfigHdr>();
+        if len < hdr_size || hdr.size as usize != len || hdr.is_reply() {
+            bail!("The length of config msg is invalid {}", len);
+        }
+        // SAFETY: the length of buf has been checked.
+        let config_hdr =
+            unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const VhostUserConfigHdr) };
+        if config_hdr.size > VHOST_USER_MAX_CONFIG_SIZE
+            || hdr_size + config_hdr.size as usize != len
+        {
+            bail!(
+                "The size {} of config is invalid, msg length {}",
+                config_hdr.size,
+                len
+            );
+        }
+        Ok(config_hdr)
+    }
+
     #[cfg_attr(feature = "cargo-clippy", allow(clippy::cast_ptr_alignment))]
     fn set_msg_mem_table(
         &mut self,
@@ -451,6 +562,133 @@ impl VhostUserServerHandler {
                     bail!("The length of fds for calling is null");
                 }
             }
+            VhostUserMsgReq::GetProtocolFeatures => {
+                if !self.is_valid_request(hdr, len, 0) {
+                    bail!("Invalid request size of GetProtocolFeatures");
+                }
+
+                let features = self.backend.lock().unwrap().get_protocol_features()?;
+                if hdr.need_reply() {
+                    self.send_ack_msg(VhostUserMsgReq::GetProtocolFeatures as u32, features, &[])
+                        .with_context(|| "Failed to send ack msg for getting protocol features")?;
+                }
+            }
+            VhostUserMsgReq::SetProtocolFeatures => {
+                let features = self
+                    .get_msg_body::<u64>(hdr, buf, len)
+                    .with_context(|| "Failed to get msg body for setting protocol features")?;
+                self.backend
+                    .lock()
+                    .unwrap()
+                    .set_protocol_features(*features)?;
+            }
+            VhostUserMsgReq::GetQueueNum => {
+                if !self.is_valid_request(hdr, len, 0) {
+                    bail!("Invalid request size of GetQueueNum");
+                }
+
+                let queue_num = self.backend.lock().unwrap().get_queue_num()?;
+                if hdr.need_reply() {
+                    self.send_ack_msg(VhostUserMsgReq::GetQueueNum as u32, queue_num, &[])
+                        .with_context(|| "Failed to send ack msg for getting queue num")?;
+                }
+            }
+            VhostUserMsgReq::GetConfig => {
+                let config_hdr = self
+                    .get_config_hdr(hdr, buf, len)
+                    .with_context(|| "Failed to get msg body for getting config")?;
+                let config = self
+                    .backend
+                    .lock()
+                    .unwrap()
+                    .get_config(config_hdr.offset, config_hdr.size)?;
+                if config.len() != config_hdr.size as usize {
+                    bail!(
+                        "The length {} of config is invalid, expected {}",
+                        config.len(),
+                        config_hdr.size
+                    );
+                }
+                // The reply carries the same config header and the data of config space.
+                let mut payload = buf[..size_of::<VhostUserConfigHdr>()].to_vec();
+                payload.extend_from_slice(&config);
+                let reply_hdr = VhostUserMsgHdr::new(
+                    VhostUserMsgReq::GetConfig as u32,
+                    VhostUserHdrFlag::Reply as u32,
+                    payload.len() as u32,
+                );
+                let body_opt: Option<&u32> = None;
+                self.sock
+                    .send_msg(Some(&reply_hdr), body_opt, Some(&payload), &[])
+                    .with_context(|| "Failed to send ack msg for getting config")?;
+            }
+            VhostUserMsgReq::SetConfig => {
+                let config_hdr = self
+                    .get_config_hdr(hdr, buf, len)
+                    .with_context(|| "Failed to get msg body for setting config")?;
+                self.backend
+                    .lock()
+                    .unwrap()
+                    .set_config(config_hdr.offset, &buf[size_of::<VhostUserConfigHdr>()..])?;
+            }
+            VhostUserMsgReq::GetVringBase => {
+                let vringstate = self
+                    .get_msg_body::<VhostUserVringState>(hdr, buf, len)
+                    .with_context(|| "Failed to get msg body for getting vring base")?;
+                let index = vringstate.index;
+                let base = self
+                    .backend
+                    .lock()
+                    .unwrap()
+                    .get_vring_base(index as usize)?;
+                if hdr.need_reply() {
+                    self.send_ack_msg(
+                        VhostUserMsgReq::GetVringBase as u32,
+                        VhostUserVringState::new(index, u32::from(base)),
+                        &[],
+                    )
+                    .with_context(|| "Failed to send ack msg for getting vring base")?;
+                }
+            }
+            VhostUserMsgReq::GetInflightFd => {
+                let inflight = self
+                    .get_msg_body::<VhostUserInflight>(hdr, buf, len)
+                    .with_context(|| "Failed to get msg body for getting inflight fd")?;
+                let (inflight, fd) = self
+                    .backend
+                    .lock()
+                    .unwrap()
+                    .get_inflight_fd(inflight.queue_num, inflight.queue_size)?;
+                if hdr.need_reply() {
+                    self.send_ack_msg(VhostUserMsgReq::GetInflightFd as u32, inflight, &[fd])
+                        .with_context(|| "Failed to send ack msg for getting inflight fd")?;
+                }
+            }
+            VhostUserMsgReq::SetInflightFd => {
+                let inflight = match self.get_msg_body::<VhostUserInflight>(hdr, buf, len) {
+                    Ok(inflight) => inflight,
+                    Err(e) => {
+                        if let Some(fds) = rfds {
+                            close_fds(fds);
+                        }
+                        return Err(e.context("Failed to get msg body for setting inflight fd"));
+                    }
+                };
+                match rfds {
+                    Some(fds) if fds.len() == 1 => {
+                        self.backend
+                            .lock()
+                            .unwrap()
+                            .set_inflight_fd(inflight, fds[0])?;
+                    }
+                    Some(fds) => {
+                        let fds_len = fds.len();
+                        close_fds(fds);
+                        bail!("The length {} of fds for inflight is invalid", fds_len);
+                    }
+                    None => bail!("The length of fds for inflight is null"),
+                }
+            }
             _ => {
                 bail!("The request {} is unknown", hdr.request);
             }
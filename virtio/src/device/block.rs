@@ -12,12 +12,11 @@
 
 use std::cmp;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::Write;
 use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
@@ -33,11 +32,27 @@ use crate::{
     VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC,
     VIRTIO_F_VERSION_1, VIRTIO_TYPE_BLOCK,
 };
-use address_space::{AddressSpace, GuestAddress};
+use address_space::{
+    AddressSpace, FlatRange, GuestAddress, Listener, ListenerReqType, RegionIoEventFd, RegionType,
+};
 use anyhow::{anyhow, bail, Context, Result};
+use block_backend::backup::CopyBeforeWrite;
+use block_backend::dirty_bitmap::BlockDirtyBitmaps;
+use block_backend::io_error::{
+    cancel_stopped_block_requests, report_block_io_error, stop_vm_for_block_io_error,
+    BlockErrorAction,
+};
+use block_backend::stats::{BlockAcctCookie, BlockAcctType, BlockStats};
+use block_backend::throttle::{throttle_group, throttle_request, Throttle};
+use block_backend::{
+    create_block_backend, create_nbd_backend, register_block_device, unregister_block_device,
+    BlockDevInfo, BlockDriverOps, BlockIoErrorCallback, BlockProperty, BlockResizeCallback,
+};
 use byteorder::{ByteOrder, LittleEndian};
 use log::{error, warn};
-use machine_manager::config::{BlkDevConfig, ConfigCheck, DriveFile, VmConfig};
+use machine_manager::config::{
+    is_nbd_path, BlkDevConfig, BlockErrorPolicy, ConfigCheck, DriveFile, VmConfig,
+};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper, EventLoop};
 use migration::{
     migration::Migratable, DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager,
@@ -45,11 +60,10 @@ use migration::{
 };
 use migration_derive::{ByteCode, Desc};
 use util::aio::{
-    iov_from_buf_direct, iov_to_buf_direct, raw_datasync, Aio, AioCb, AioEngine, Iovec, OpCode,
-    WriteZeroesState,
+    get_iov_size, iov_from_buf_direct, iov_to_buf_direct, raw_datasync, Aio, AioCb, FixedBuffers,
+    Iovec, OpCode, WriteZeroesState,
 };
 use util::byte_code::ByteCode;
-use util::leak_bucket::LeakBucket;
 use util::loop_context::{
     read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
 };
@@ -76,13 +90,9 @@ const MAX_MILLIS_TIME_PROCESS_QUEUE: u16 = 100;
 const MAX_REQUEST_SECTORS: u32 = u32::MAX >> SECTOR_SHIFT;
 
 type SenderConfig = (
-    Option<Arc<File>>,
-    u32,
-    u32,
-    u64,
+    Option<Arc<Mutex<dyn BlockDriverOps<AioCompleteCb>>>>,
     Option<String>,
-    bool,
-    AioEngine,
+    Option<Arc<Mutex<Throttle>>>,
 );
 
 fn get_serial_num_config(serial_num: &str) -> Vec<u8> {
@@ -118,6 +128,32 @@ struct DiscardWriteZeroesSeg {
 
 impl ByteCode for DiscardWriteZeroesSeg {}
 
+/// Handle the failed requests of a virtqueue according to the `werror`/`rerror` policies.
+struct IoErrorHandler {
+    /// Id of the block device.
+    id: String,
+    /// Policy for the failed write, flush, discard and write-zeroes requests.
+    werror: BlockErrorPolicy,
+    /// Policy for the failed read requests.
+    rerror: BlockErrorPolicy,
+    /// Eventfd written to retry the stopped requests after the VM is resumed.
+    retry_evt: Arc<EventFd>,
+    /// Requests stopped by I/O errors, with their accounting information.
+    stopped_reqs: Mutex<Vec<(Request, Option<BlockAcctCookie>)>>,
+}
+
+impl IoErrorHandler {
+    fn new(blk_cfg: &BlkDevConfig, retry_evt: Arc<EventFd>) -> Self {
+        IoErrorHandler {
+            id: blk_cfg.id.clone(),
+            werror: blk_cfg.werror,
+            rerror: blk_cfg.rerror,
+            retry_evt,
+            stopped_reqs: Mutex::new(Vec::new()),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct AioCompleteCb {
     queue: Arc<Mutex<Queue>>,
@@ -126,6 +162,12 @@ pub struct AioCompleteCb {
     req: Rc<Request>,
     interrupt_cb: Arc<VirtioInterrupt>,
     driver_features: u64,
+    /// I/O statistics of the block device.
+    stats: Arc<BlockStats>,
+    /// Accounting information of the request, None if it is not accounted.
+    acct: Option<BlockAcctCookie>,
+    /// Handler of the failed request.
+    io_error: Arc<IoErrorHandler>,
 }
 
 impl AioCompleteCb {
@@ -135,6 +177,8 @@ impl AioCompleteCb {
         req: Rc<Request>,
         interrupt_cb: Arc<VirtioInterrupt>,
         driver_features: u64,
+        stats: Arc<BlockStats>,
+        io_error: Arc<IoErrorHandler>,
     ) -> Self {
         AioCompleteCb {
             queue,
@@ -142,10 +186,43 @@ impl AioCompleteCb {
             req,
             interrupt_cb,
             driver_features,
+            stats,
+            acct: None,
+            io_error,
+        }
+    }
+
+    /// Handle the request failed with `errno` according to the error policy. Return the
+    /// status to complete the request with, or None if the request is stopped.
+    fn handle_io_error(&self, errno: i32) -> Option<u8> {
+        let is_write = self.req.out_header.request_type != VIRTIO_BLK_T_IN;
+        let policy = if is_write {
+            self.io_error.werror
+        } else {
+            self.io_error.rerror
+        };
+        let action = BlockErrorAction::new(policy, errno);
+        report_block_io_error(&self.io_error.id, is_write, action, errno);
+        match action {
+            BlockErrorAction::Report => Some(VIRTIO_BLK_S_IOERR),
+            BlockErrorAction::Ignore => Some(VIRTIO_BLK_S_OK),
+            BlockErrorAction::Stop => {
+                self.io_error
+                    .stopped_reqs
+                    .lock()
+                    .unwrap()
+                    .push((self.req.as_ref().clone(), self.acct));
+                stop_vm_for_block_io_error(&self.io_error.retry_evt);
+                None
+            }
         }
     }
 
     fn complete_request(&self, status: u8) -> Result<()> {
+        if let Some(acct) = self.acct.as_ref() {
+            self.stats.account_done(acct, status != VIRTIO_BLK_S_OK);
+        }
+
         let mut req = Some(self.req.as_ref());
         while let Some(req_raw) = req {
             self.complete_one_request(req_raw, status)?;
@@ -287,7 +364,7 @@ impl Request {
             }
         }
 
-        if !request.io_range_valid(handler.disk_sectors) {
+        if !request.io_range_valid(handler.disk_sectors.load(Ordering::Acquire)) {
             *status = VIRTIO_BLK_S_IOERR;
         }
 
@@ -297,8 +374,10 @@ impl Request {
     fn execute(
         &self,
         iohandler: &mut BlockIoHandler,
-        mut aiocb: AioCb<AioCompleteCb>,
+        block_backend: Arc<Mutex<dyn BlockDriverOps<AioCompleteCb>>>,
+        aiocompletecb: AioCompleteCb,
     ) -> Result<()> {
+        let mut iovecs = Vec::new();
         let mut req = Some(self);
         while let Some(req_raw) = req {
             for iov in req_raw.iovec.iter() {
@@ -306,41 +385,42 @@ impl Request {
                     iov_base: iov.iov_base,
                     iov_len: iov.iov_len,
                 };
-                aiocb.iovec.push(iovec);
-                // Note: total len of each req is no more than 1<<32,
-                // and reqs count is no more than 1024.
-                aiocb.nbytes += iov.iov_len;
+                iovecs.push(iovec);
             }
             req = req_raw.next.as_ref().as_ref();
         }
+        let offset = (self.out_header.sector << SECTOR_SHIFT) as usize;
 
         let request_type = self.out_header.request_type;
         if MigrationManager::is_active()
             && (request_type == VIRTIO_BLK_T_IN || request_type == VIRTIO_BLK_T_GET_ID)
         {
             // FIXME: mark dirty page needs to be managed by `AddressSpace` crate.
-            for iov in aiocb.iovec.iter() {
+            for iov in iovecs.iter() {
                 // Mark vmm dirty page manually if live migration is active.
                 MigrationManager::mark_dirty_log(iov.iov_base, iov.iov_len);
             }
         }
 
-        let aio = &mut iohandler.aio;
         let serial_num = &iohandler.serial_num;
+        let mut locked_backend = block_backend.lock().unwrap();
         match request_type {
             VIRTIO_BLK_T_IN => {
-                aiocb.opcode = OpCode::Preadv;
-                aio.submit_request(aiocb)
+                locked_backend
+                    .read_vectored(iovecs, offset, aiocompletecb)
                     .with_context(|| "Failed to process block request for reading")?;
             }
             VIRTIO_BLK_T_OUT => {
-                aiocb.opcode = OpCode::Pwritev;
-                aio.submit_request(aiocb)
+                let len = get_iov_size(&iovecs);
+                iohandler.backup.before_write(offset as u64, len);
+                iohandler.dirty_bitmaps.mark_dirty(offset as u64, len);
+                locked_backend
+                    .write_vectored(iovecs, offset, aiocompletecb)
                     .with_context(|| "Failed to process block request for writing")?;
             }
             VIRTIO_BLK_T_FLUSH => {
-                aiocb.opcode = OpCode::Fdsync;
-                aio.submit_request(aiocb)
+                locked_backend
+                    .datasync(aiocompletecb)
                     .with_context(|| "Failed to process block request for flushing")?;
             }
             VIRTIO_BLK_T_GET_ID => {
@@ -353,23 +433,31 @@ impl Request {
                     },
                     |_| VIRTIO_BLK_S_OK,
                 );
-                aiocb.iocompletecb.complete_request(status)?;
+                aiocompletecb.complete_request(status)?;
             }
             VIRTIO_BLK_T_DISCARD => {
                 if !iohandler.discard {
                     error!("Device does not support discard");
-                    return aiocb.iocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
+                    return aiocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
                 }
-                aiocb.opcode = OpCode::Discard;
-                self.handle_discard_write_zeroes_req(iohandler, aiocb)?;
+                self.handle_discard_write_zeroes_req(
+                    iohandler,
+                    &mut *locked_backend,
+                    aiocompletecb,
+                    OpCode::Discard,
+                )?;
             }
             VIRTIO_BLK_T_WRITE_ZEROES => {
                 if iohandler.write_zeroes == WriteZeroesState::Off {
                     error!("Device does not support write-zeroes");
-                    return aiocb.iocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
+                    return aiocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
                 }
-                aiocb.opcode = OpCode::WriteZeroes;
-                self.handle_discard_write_zeroes_req(iohandler, aiocb)?;
+                self.handle_discard_write_zeroes_req(
+                    iohandler,
+                    &mut *locked_backend,
+                    aiocompletecb,
+                    OpCode::WriteZeroes,
+                )?;
             }
             // The illegal request type has been handled in method new().
             _ => {}
@@ -379,14 +467,16 @@ impl Request {
 
     fn handle_discard_write_zeroes_req(
         &self,
-        iohandler: &mut BlockIoHandler,
-        mut aiocb: AioCb<AioCompleteCb>,
+        iohandler: &BlockIoHandler,
+        block_backend: &mut dyn BlockDriverOps<AioCompleteCb>,
+        aiocompletecb: AioCompleteCb,
+        opcode: OpCode,
     ) -> Result<()> {
         let size = size_of::<DiscardWriteZeroesSeg>() as u64;
         // Just support one segment per request.
         if self.data_len > size {
             error!("More than one discard or write-zeroes segment is not supported");
-            return aiocb.iocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
+            return aiocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
         }
 
         // Get and check the discard segment.
@@ -402,7 +492,7 @@ impl Request {
         let num_sectors = LittleEndian::read_u32(segment.num_sectors.as_bytes());
         if sector
             .checked_add(num_sectors as u64)
-            .filter(|&off| off <= iohandler.disk_sectors)
+            .filter(|&off| off <= iohandler.disk_sectors.load(Ordering::Acquire))
             .is_none()
             || num_sectors > MAX_REQUEST_SECTORS
         {
@@ -410,32 +500,32 @@ impl Request {
                 "Invalid discard or write zeroes request, sector offset {}, num_sectors {}",
                 sector, num_sectors
             );
-            return aiocb.iocompletecb.complete_request(VIRTIO_BLK_S_IOERR);
+            return aiocompletecb.complete_request(VIRTIO_BLK_S_IOERR);
         }
         let flags = LittleEndian::read_u32(segment.flags.as_bytes());
         if flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 {
             error!("Invalid unmap flags 0x{:x}", flags);
-            return aiocb.iocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
+            return aiocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
         }
 
-        if aiocb.opcode == OpCode::Discard {
+        let offset = (sector as usize) << SECTOR_SHIFT;
+        let nbytes = (num_sectors as u64) << SECTOR_SHIFT;
+        iohandler.backup.before_write(offset as u64, nbytes);
+        iohandler.dirty_bitmaps.mark_dirty(offset as u64, nbytes);
+        if opcode == OpCode::Discard {
             if flags == VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP {
                 error!("Discard request must not set unmap flags");
-                return aiocb.iocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
+                return aiocompletecb.complete_request(VIRTIO_BLK_S_UNSUPP);
             }
-        } else if aiocb.opcode == OpCode::WriteZeroes
-            && flags == VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP
-            && iohandler.discard
-        {
-            aiocb.write_zeroes_unmap = true;
+            block_backend
+                .discard(offset, nbytes, aiocompletecb)
+                .with_context(|| "Failed to process block request for discard")
+        } else {
+            let unmap = flags == VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP && iohandler.discard;
+            block_backend
+                .write_zeroes(offset, nbytes, aiocompletecb, unmap)
+                .with_context(|| "Failed to process block request for write-zeroes")
         }
-
-        aiocb.offset = (sector as usize) << SECTOR_SHIFT;
-        aiocb.nbytes = (num_sectors as u64) << SECTOR_SHIFT;
-        iohandler
-            .aio
-            .submit_request(aiocb)
-            .with_context(|| "Failed to process block request for discard or write-zeroes")
     }
 
     fn io_range_valid(&self, disk_sectors: u64) -> bool {
@@ -466,6 +556,53 @@ impl Request {
     fn get_req_sector_num(&self) -> u64 {
         self.data_len / SECTOR_SIZE
     }
+
+    /// Get the accounting type of the request, None if it is not accounted.
+    fn acct_type(&self) -> Option<BlockAcctType> {
+        match self.out_header.request_type {
+            VIRTIO_BLK_T_IN => Some(BlockAcctType::Read),
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_WRITE_ZEROES => Some(BlockAcctType::Write),
+            VIRTIO_BLK_T_FLUSH => Some(BlockAcctType::Flush),
+            VIRTIO_BLK_T_DISCARD => Some(BlockAcctType::Unmap),
+            _ => None,
+        }
+    }
+
+    /// Get the number of bytes accessed by the request and the requests merged into it.
+    fn acct_bytes(&self) -> u64 {
+        match self.out_header.request_type {
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                let mut segment = DiscardWriteZeroesSeg::default();
+                match iov_to_buf_direct(&self.iovec, segment.as_mut_bytes()) {
+                    Ok(_) => {
+                        u64::from(LittleEndian::read_u32(segment.num_sectors.as_bytes()))
+                            << SECTOR_SHIFT
+                    }
+                    Err(_) => 0,
+                }
+            }
+            VIRTIO_BLK_T_FLUSH => 0,
+            _ => {
+                let mut bytes = 0;
+                let mut req = Some(self);
+                while let Some(req_raw) = req {
+                    bytes += req_raw.data_len;
+                    req = req_raw.next.as_ref().as_ref();
+                }
+                bytes
+            }
+        }
+    }
+
+    fn merged_num(&self) -> u64 {
+        let mut num = 0;
+        let mut req = self.next.as_ref().as_ref();
+        while let Some(req_raw) = req {
+            num += 1;
+            req = req_raw.next.as_ref().as_ref();
+        }
+        num
+    }
 }
 
 /// Control block of Block IO.
@@ -476,20 +613,12 @@ struct BlockIoHandler {
     queue_evt: Arc<EventFd>,
     /// The address space to which the block device belongs.
     mem_space: Arc<AddressSpace>,
-    /// The image file opened by the block device.
-    disk_image: Option<Arc<File>>,
-    /// The align requirement of request(offset/len).
-    pub req_align: u32,
-    /// The align requirement of buffer(iova_base).
-    pub buf_align: u32,
-    /// The number of sectors of the disk image.
-    disk_sectors: u64,
+    /// The block backend opened by the block device.
+    block_backend: Option<Arc<Mutex<dyn BlockDriverOps<AioCompleteCb>>>>,
+    /// The number of sectors of the disk image, shared by all queues of the block device.
+    disk_sectors: Arc<AtomicU64>,
     /// Serial number of the block device.
     serial_num: Option<String>,
-    /// If use direct access io.
-    direct: bool,
-    /// Aio context.
-    aio: Box<Aio<AioCompleteCb>>,
     /// Bit mask of features negotiated by the backend and the frontend.
     driver_features: u64,
     /// The receiving half of Rust's channel to receive the image file.
@@ -502,12 +631,24 @@ struct BlockIoHandler {
     interrupt_cb: Arc<VirtioInterrupt>,
     /// thread name of io handler
     iothread: Option<String>,
-    /// Using the leak bucket to implement IO limits
-    leak_bucket: Option<LeakBucket>,
+    /// I/O throttling shared by all queues of the block device.
+    throttle: Arc<Mutex<Throttle>>,
+    /// I/O throttling shared by all block devices of the throttle group.
+    throttle_group: Option<Arc<Mutex<Throttle>>>,
+    /// Eventfd written when the throttled requests can be processed again.
+    throttle_evt: Arc<EventFd>,
     /// Supporting discard or not.
     discard: bool,
     /// The write-zeroes state.
     write_zeroes: WriteZeroesState,
+    /// I/O statistics of the block device.
+    stats: Arc<BlockStats>,
+    /// Handler of the failed requests.
+    io_error: Arc<IoErrorHandler>,
+    /// Hook to copy the old data for the backup job, shared by all queues.
+    backup: Arc<CopyBeforeWrite>,
+    /// Dirty bitmaps tracking the writes, shared by all queues.
+    dirty_bitmaps: Arc<BlockDirtyBitmaps>,
 }
 
 impl BlockIoHandler {
@@ -556,6 +697,44 @@ impl BlockIoHandler {
         merge_req_queue
     }
 
+    /// Return true if the read/write request must wait for the I/O throttling
+    /// of the block device or the throttle group.
+    fn throttled(&self, req: &Request) -> bool {
+        let is_write = match req.out_header.request_type {
+            VIRTIO_BLK_T_IN => false,
+            VIRTIO_BLK_T_OUT => true,
+            _ => return false,
+        };
+        let ctx = match EventLoop::get_ctx(self.iothread.as_ref()) {
+            Some(ctx) => ctx,
+            None => return false,
+        };
+
+        throttle_request(
+            &self.throttle,
+            self.throttle_group.as_deref(),
+            ctx,
+            is_write,
+            req.data_len,
+            &self.throttle_evt,
+        )
+    }
+
+    /// Return true if the handler is waiting for the I/O throttling.
+    fn is_throttling(&self) -> bool {
+        self.throttle.lock().unwrap().is_waiting(&self.throttle_evt)
+            || self.throttle_group.as_ref().map_or(false, |group| {
+                group.lock().unwrap().is_waiting(&self.throttle_evt)
+            })
+    }
+
+    fn set_throttle_group(&mut self, throttle_group: Option<Arc<Mutex<Throttle>>>) {
+        if let Some(group) = self.throttle_group.as_ref() {
+            group.lock().unwrap().remove_waiter(&self.throttle_evt);
+        }
+        self.throttle_group = throttle_group;
+    }
+
     fn process_queue_internal(&mut self) -> Result<bool> {
         let mut req_queue = Vec::new();
         let mut done = false;
@@ -569,19 +748,16 @@ impl BlockIoHandler {
                 break;
             }
 
-            // limit io operations if iops is configured
-            if let Some(lb) = self.leak_bucket.as_mut() {
-                if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
-                    if lb.throttled(ctx, 1_u64) {
-                        queue.vring.push_back();
-                        break;
-                    }
-                };
-            }
-
             // Init and put valid request into request queue.
             let mut status = VIRTIO_BLK_S_OK;
             let req = Request::new(self, &mut elem, &mut status)?;
+
+            // limit io operations if I/O throttling is configured
+            if status == VIRTIO_BLK_S_OK && self.throttled(&req) {
+                queue.vring.push_back();
+                break;
+            }
+
             if status != VIRTIO_BLK_S_OK {
                 let aiocompletecb = AioCompleteCb::new(
                     self.queue.clone(),
@@ -589,6 +765,8 @@ impl BlockIoHandler {
                     Rc::new(req),
                     self.interrupt_cb.clone(),
                     self.driver_features,
+                    self.stats.clone(),
+                    self.io_error.clone(),
                 );
                 // unlock queue, because it will be hold below.
                 drop(queue);
@@ -608,38 +786,35 @@ impl BlockIoHandler {
         }
 
         let merge_req_queue = self.merge_req_queue(req_queue);
+        let block_backend = self.block_backend.clone();
         for req in merge_req_queue.into_iter() {
             let req_rc = Rc::new(req);
-            let aiocompletecb = AioCompleteCb::new(
+            let mut aiocompletecb = AioCompleteCb::new(
                 self.queue.clone(),
                 self.mem_space.clone(),
                 req_rc.clone(),
                 self.interrupt_cb.clone(),
                 self.driver_features,
+                self.stats.clone(),
+                self.io_error.clone(),
             );
-            if let Some(disk_img) = self.disk_image.as_ref() {
-                let aiocb = AioCb {
-                    direct: self.direct,
-                    req_align: self.req_align,
-                    buf_align: self.buf_align,
-                    file_fd: disk_img.as_raw_fd(),
-                    opcode: OpCode::Noop,
-                    iovec: Vec::new(),
-                    offset: (req_rc.out_header.sector << SECTOR_SHIFT) as usize,
-                    nbytes: 0,
-                    user_data: 0,
-                    iocompletecb: aiocompletecb,
-                    discard: self.discard,
-                    write_zeroes: self.write_zeroes,
-                    write_zeroes_unmap: false,
-                };
-                req_rc.execute(self, aiocb)?;
+            if let Some(acct_type) = req_rc.acct_type() {
+                let merged = req_rc.merged_num();
+                if merged != 0 {
+                    self.stats.account_merged(acct_type, merged);
+                }
+                aiocompletecb.acct = Some(BlockAcctCookie::new(acct_type, req_rc.acct_bytes()));
+            }
+            if let Some(block_backend) = block_backend.as_ref() {
+                req_rc.execute(self, block_backend.clone(), aiocompletecb)?;
             } else {
-                warn!("Failed to execute block request, disk_img not specified");
+                warn!("Failed to execute block request, block backend not specified");
                 aiocompletecb.complete_request(VIRTIO_BLK_S_IOERR)?;
             }
         }
-        self.aio.flush_request()?;
+        if let Some(block_backend) = block_backend.as_ref() {
+            block_backend.lock().unwrap().flush_request()?;
+        }
 
         Ok(done)
     }
@@ -679,12 +854,8 @@ impl BlockIoHandler {
             )?;
 
             // See whether we have been throttled.
-            if let Some(lb) = self.leak_bucket.as_mut() {
-                if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
-                    if lb.throttled(ctx, 0) {
-                        break;
-                    }
-                }
+            if self.is_throttling() {
+                break;
             }
         }
         Ok(done)
@@ -704,79 +875,90 @@ impl BlockIoHandler {
     }
 
     fn complete_func(aiocb: &AioCb<AioCompleteCb>, ret: i64) -> Result<()> {
-        let mut status = if ret < 0 {
-            VIRTIO_BLK_S_IOERR
-        } else {
-            VIRTIO_BLK_S_OK
-        };
-
+        let mut ret = ret;
         let complete_cb = &aiocb.iocompletecb;
         // When driver does not accept FLUSH feature, the device must be of
         // writethrough cache type, so flush data before updating used ring.
+        // The requests without host file, e.g. on an NBD export, can not be flushed here.
         if !virtio_has_feature(complete_cb.driver_features, VIRTIO_BLK_F_FLUSH)
             && aiocb.opcode == OpCode::Pwritev
+            && aiocb.file_fd >= 0
             && ret >= 0
-            && raw_datasync(aiocb.file_fd) < 0
         {
-            error!("Failed to flush data before send response to guest.");
-            status = VIRTIO_BLK_S_IOERR;
+            let flush_ret = raw_datasync(aiocb.file_fd);
+            if flush_ret < 0 {
+                error!("Failed to flush data before send response to guest.");
+                ret = flush_ret;
+            }
         }
 
+        let status = if ret < 0 {
+            match complete_cb.handle_io_error(-ret as i32) {
+                Some(status) => status,
+                // The request will be retried after the VM is resumed.
+                None => return Ok(()),
+            }
+        } else {
+            VIRTIO_BLK_S_OK
+        };
+
         complete_cb.complete_request(status)
     }
 
-    fn aio_complete_handler(&mut self) -> Result<bool> {
-        self.aio.handle_complete().map_err(|e| {
-            report_virtio_error(
+    /// Retry the requests stopped by I/O errors after the VM is resumed.
+    fn retry_stopped_requests(&mut self) -> Result<()> {
+        let stopped_reqs: Vec<(Request, Option<BlockAcctCookie>)> = self
+            .io_error
+            .stopped_reqs
+            .lock()
+            .unwrap()
+            .drain(..)
+            .collect();
+        if stopped_reqs.is_empty() {
+            return Ok(());
+        }
+
+        let block_backend = self.block_backend.clone();
+        for (req, acct) in stopped_reqs {
+            let req_rc = Rc::new(req);
+            let mut aiocompletecb = AioCompleteCb::new(
+                self.queue.clone(),
+                self.mem_space.clone(),
+                req_rc.clone(),
                 self.interrupt_cb.clone(),
                 self.driver_features,
-                &self.device_broken,
+                self.stats.clone(),
+                self.io_error.clone(),
             );
-            e
-        })
+            aiocompletecb.acct = acct;
+            if let Some(block_backend) = block_backend.as_ref() {
+                req_rc.execute(self, block_backend.clone(), aiocompletecb)?;
+            } else {
+                warn!("Failed to retry block request, block backend not specified");
+                aiocompletecb.complete_request(VIRTIO_BLK_S_IOERR)?;
+            }
+        }
+        if let Some(block_backend) = block_backend.as_ref() {
+            block_backend.lock().unwrap().flush_request()?;
+        }
+        Ok(())
     }
 
     fn update_evt_handler(&mut self) {
-        let aio_engine;
         match self.receiver.recv() {
-            Ok((image, req_align, buf_align, disk_sectors, serial_num, direct, aio)) => {
-                self.disk_sectors = disk_sectors;
-                self.disk_image = image;
-                self.req_align = req_align;
-                self.buf_align = buf_align;
+            Ok((block_backend, serial_num, throttle_group)) => {
+                self.block_backend = block_backend;
                 self.serial_num = serial_num;
-                self.direct = direct;
-                aio_engine = aio;
+                self.set_throttle_group(throttle_group);
             }
             Err(e) => {
                 error!("Failed to receive config in updating handler {:?}", e);
-                self.disk_sectors = 0;
-                self.disk_image = None;
-                self.req_align = 1;
-                self.buf_align = 1;
+                self.block_backend = None;
                 self.serial_num = None;
-                self.direct = true;
-                aio_engine = AioEngine::Native;
+                self.set_throttle_group(None);
             }
         };
 
-        if self.aio.get_engine() != aio_engine {
-            match Aio::new(Arc::new(Self::complete_func), aio_engine) {
-                Ok(aio) => {
-                    self.aio = Box::new(aio);
-                }
-                Err(e) => {
-                    error!("{:?}", e);
-                    report_virtio_error(
-                        self.interrupt_cb.clone(),
-                        self.driver_features,
-                        &self.device_broken,
-                    );
-                    return;
-                }
-            }
-        }
-
         if let Err(e) = (self.interrupt_cb)(&VirtioInterruptType::Config, None, false) {
             error!(
                 "{:?}. {:?}",
@@ -874,27 +1056,7 @@ impl EventNotifierHelper for BlockIoHandler {
             Some(handler_iopoll),
         ));
 
-        // Register timer event notifier for IO limits
-        if let Some(lb) = handler_raw.leak_bucket.as_ref() {
-            let h_clone = handler.clone();
-            let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
-                read_fd(fd);
-                let mut h_lock = h_clone.lock().unwrap();
-                if h_lock.device_broken.load(Ordering::SeqCst) {
-                    return None;
-                }
-                if let Some(lb) = h_lock.leak_bucket.as_mut() {
-                    lb.clear_timer();
-                }
-                if let Err(ref e) = h_lock.process_queue() {
-                    error!("Failed to handle block IO {:?}", e);
-                }
-                None
-            });
-            notifiers.push(build_event_notifier(lb.as_raw_fd(), vec![h], None));
-        }
-
-        // Register event notifier for aio.
+        // Register event notifier for IO limits
         let h_clone = handler.clone();
         let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
             read_fd(fd);
@@ -902,38 +1064,39 @@ impl EventNotifierHelper for BlockIoHandler {
             if h_lock.device_broken.load(Ordering::SeqCst) {
                 return None;
             }
-            if let Err(ref e) = h_lock.aio_complete_handler() {
-                error!("Failed to handle aio {:?}", e);
+            if let Err(ref e) = h_lock.process_queue() {
+                error!("Failed to handle block IO {:?}", e);
             }
             None
         });
+        notifiers.push(build_event_notifier(
+            handler_raw.throttle_evt.as_raw_fd(),
+            vec![h],
+            None,
+        ));
+
+        // Register event notifier for retrying the stopped requests.
         let h_clone = handler.clone();
-        let handler_iopoll: Box<NotifierCallback> = Box::new(move |_, _fd: RawFd| {
+        let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
             let mut h_lock = h_clone.lock().unwrap();
             if h_lock.device_broken.load(Ordering::SeqCst) {
                 return None;
             }
-            if h_lock.aio.get_engine() == AioEngine::Off {
-                return None;
-            }
-            match h_lock.aio_complete_handler() {
-                Ok(done) => {
-                    if done {
-                        Some(Vec::new())
-                    } else {
-                        None
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to handle aio {:?}", e);
-                    None
-                }
+            if let Err(ref e) = h_lock.retry_stopped_requests() {
+                error!("Failed to retry the stopped block requests {:?}", e);
+                report_virtio_error(
+                    h_lock.interrupt_cb.clone(),
+                    h_lock.driver_features,
+                    &h_lock.device_broken,
+                );
             }
+            None
         });
         notifiers.push(build_event_notifier(
-            handler_raw.aio.fd.as_raw_fd(),
+            handler_raw.io_error.retry_evt.as_raw_fd(),
             vec![h],
-            Some(handler_iopoll),
+            None,
         ));
 
         notifiers
@@ -954,7 +1117,7 @@ impl ByteCode for VirtioBlkGeometry {}
 #[derive(Copy, Clone, Debug, Default)]
 pub struct VirtioBlkConfig {
     /// The capacity in 512 byte sectors.
-    capacity: u64,
+    pub capacity: u64,
     /// The maximum segment size.
     size_max: u32,
     /// Tne maximum number of segments.
@@ -995,6 +1158,62 @@ pub struct VirtioBlkConfig {
 
 impl ByteCode for VirtioBlkConfig {}
 
+/// Memory listener which keeps the host addresses of the guest ram, which are used as the
+/// fixed buffers of io_uring.
+struct FixedBuffersListener {
+    buffers: Arc<FixedBuffers>,
+    enabled: bool,
+}
+
+impl FixedBuffersListener {
+    fn new(buffers: Arc<FixedBuffers>) -> Self {
+        FixedBuffersListener {
+            buffers,
+            enabled: false,
+        }
+    }
+}
+
+impl Listener for FixedBuffersListener {
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    fn disable(&mut self) {
+        self.enabled = false;
+    }
+
+    fn handle_request(
+        &self,
+        range: Option<&FlatRange>,
+        _evtfd: Option<&RegionIoEventFd>,
+        req_type: ListenerReqType,
+    ) -> Result<()> {
+        let fr = match range {
+            Some(fr) if fr.owner.region_type() == RegionType::Ram => fr,
+            _ => return Ok(()),
+        };
+        let host_addr = match fr.owner.get_host_address() {
+            Some(addr) => addr + fr.offset_in_region,
+            None => return Ok(()),
+        };
+        match req_type {
+            ListenerReqType::AddRegion => self.buffers.add_region(host_addr, fr.addr_range.size),
+            ListenerReqType::DeleteRegion => self.buffers.del_region(host_addr, fr.addr_range.size),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
 /// State of block device.
 #[repr(C)]
 #[derive(Clone, Copy, Desc, ByteCode)]
@@ -1014,18 +1233,14 @@ pub struct BlockState {
 pub struct Block {
     /// Configuration of the block device.
     blk_cfg: BlkDevConfig,
-    /// Image file opened.
-    disk_image: Option<Arc<File>>,
-    /// The align requirement of request(offset/len).
-    pub req_align: u32,
-    /// The align requirement of buffer(iova_base).
-    pub buf_align: u32,
-    /// Number of sectors of the image file.
-    disk_sectors: u64,
+    /// Block backend opened by the block device.
+    block_backend: Option<Arc<Mutex<dyn BlockDriverOps<AioCompleteCb>>>>,
+    /// Number of sectors of the image file, it's changed when the disk is resized.
+    disk_sectors: Arc<AtomicU64>,
     /// Status of block device.
     state: BlockState,
-    /// Callback to trigger interrupt.
-    interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    /// Callback to trigger interrupt, it's set when the device is activated.
+    interrupt_cb: Arc<Mutex<Option<Arc<VirtioInterrupt>>>>,
     /// The sending half of Rust's channel to send the image file.
     senders: Vec<Sender<SenderConfig>>,
     /// Eventfd for config space update.
@@ -1036,6 +1251,24 @@ pub struct Block {
     broken: Arc<AtomicBool>,
     /// Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    /// I/O statistics of the block device.
+    stats: Arc<BlockStats>,
+    /// I/O throttling of the block device.
+    throttle: Arc<Mutex<Throttle>>,
+    /// I/O throttling of the throttle group which the block device joins.
+    throttle_group: Option<Arc<Mutex<Throttle>>>,
+    /// Eventfd for waking up the throttled handlers.
+    throttle_evts: Vec<Arc<EventFd>>,
+    /// Eventfd for retrying the requests stopped by I/O errors.
+    retry_evts: Vec<Arc<EventFd>>,
+    /// Hook to copy the old data for the backup job.
+    backup: Arc<CopyBeforeWrite>,
+    /// Dirty bitmaps tracking the writes of the guest.
+    dirty_bitmaps: Arc<BlockDirtyBitmaps>,
+    /// Guest memory used as the fixed buffers of io_uring.
+    fixed_buffers: Arc<FixedBuffers>,
+    /// Memory listener updating the fixed buffers, it's registered when the device is activated.
+    mem_listener: Option<(Arc<AddressSpace>, Arc<Mutex<FixedBuffersListener>>)>,
 }
 
 impl Block {
@@ -1043,19 +1276,27 @@ impl Block {
         blk_cfg: BlkDevConfig,
         drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
     ) -> Block {
+        let throttle = Arc::new(Mutex::new(Throttle::new(&blk_cfg.throttle)));
         Self {
             blk_cfg,
-            disk_image: None,
-            req_align: 1,
-            buf_align: 1,
-            disk_sectors: 0,
+            block_backend: None,
+            disk_sectors: Arc::new(AtomicU64::new(0)),
             state: BlockState::default(),
-            interrupt_cb: None,
+            interrupt_cb: Arc::new(Mutex::new(None)),
             senders: Vec::new(),
             update_evts: Vec::new(),
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
             drive_files,
+            stats: Arc::new(BlockStats::default()),
+            throttle,
+            throttle_group: None,
+            throttle_evts: Vec::new(),
+            retry_evts: Vec::new(),
+            backup: Arc::new(CopyBeforeWrite::default()),
+            dirty_bitmaps: Arc::new(BlockDirtyBitmaps::default()),
+            fixed_buffers: Arc::new(FixedBuffers::default()),
+            mem_listener: None,
         }
     }
 
@@ -1093,6 +1334,40 @@ impl Block {
             offset_of!(VirtioBlkConfig, max_discard_sectors) as u64
         }
     }
+
+    fn gen_error_cb(&self, interrupt_cb: Arc<VirtioInterrupt>) -> BlockIoErrorCallback {
+        let cloned_features = self.state.driver_features;
+        let clone_broken = self.broken.clone();
+        Arc::new(move || {
+            report_virtio_error(interrupt_cb.clone(), cloned_features, &clone_broken);
+        })
+    }
+
+    fn gen_resize_cb(
+        &self,
+        block_backend: Arc<Mutex<dyn BlockDriverOps<AioCompleteCb>>>,
+    ) -> BlockResizeCallback {
+        let disk_sectors = self.disk_sectors.clone();
+        let interrupt_cb = self.interrupt_cb.clone();
+        Arc::new(move |size: u64| {
+            block_backend.lock().unwrap().resize(size)?;
+            disk_sectors.store(size >> SECTOR_SHIFT, Ordering::Release);
+            // Notify the guest to read the new capacity if the device is activated.
+            if let Some(interrupt_cb) = interrupt_cb.lock().unwrap().as_ref() {
+                interrupt_cb(&VirtioInterruptType::Config, None, false).with_context(|| {
+                    VirtioError::InterruptTrigger("block", VirtioInterruptType::Config)
+                })?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Get the config space with the current capacity of the disk.
+    fn config_space(&self) -> VirtioBlkConfig {
+        let mut config_space = self.state.config_space;
+        config_space.capacity = self.disk_sectors.load(Ordering::Acquire);
+        config_space
+    }
 }
 
 impl VirtioDevice for Block {
@@ -1123,30 +1398,88 @@ impl VirtioDevice for Block {
             self.state.config_space.num_queues = self.blk_cfg.queues;
         }
 
-        self.disk_image = None;
-        self.disk_sectors = DUMMY_IMG_SIZE >> SECTOR_SHIFT;
-        self.req_align = 1;
-        self.buf_align = 1;
+        self.throttle
+            .lock()
+            .unwrap()
+            .set_config(&self.blk_cfg.throttle);
+        self.throttle_group = self.blk_cfg.throttle_group.as_ref().map(throttle_group);
+        self.block_backend = None;
+        let mut disk_sectors = DUMMY_IMG_SIZE >> SECTOR_SHIFT;
         if !self.blk_cfg.path_on_host.is_empty() {
             let drive_files = self.drive_files.lock().unwrap();
-            let mut file = VmConfig::fetch_drive_file(&drive_files, &self.blk_cfg.path_on_host)?;
-            let alignments = VmConfig::fetch_drive_align(&drive_files, &self.blk_cfg.path_on_host)?;
-            let disk_size = file
-                .seek(SeekFrom::End(0))
-                .with_context(|| "Failed to seek the end for block")?;
-
-            self.disk_image = Some(Arc::new(file));
-            self.disk_sectors = disk_size >> SECTOR_SHIFT;
-            self.req_align = alignments.0;
-            self.buf_align = alignments.1;
+            let is_nbd = is_nbd_path(&self.blk_cfg.path_on_host);
+            // The disk exported by an NBD server has no host file nor alignment requirement.
+            let (file, alignments) = if is_nbd {
+                (None, (1, 1))
+            } else {
+                (
+                    Some(VmConfig::fetch_drive_file(
+                        &drive_files,
+                        &self.blk_cfg.path_on_host,
+                    )?),
+                    VmConfig::fetch_drive_align(&drive_files, &self.blk_cfg.path_on_host)?,
+                )
+            };
+            let mut aio = Aio::new_with_uring_config(
+                Arc::new(BlockIoHandler::complete_func),
+                self.blk_cfg.aio,
+                self.blk_cfg.uring,
+            )?;
+            aio.set_fixed_buffers(self.fixed_buffers.clone());
+            let conf = BlockProperty {
+                id: self.blk_cfg.id.clone(),
+                path: self.blk_cfg.path_on_host.clone(),
+                format: self.blk_cfg.format,
+                iothread: self.blk_cfg.iothread.clone(),
+                direct: self.blk_cfg.direct,
+                req_align: alignments.0,
+                buf_align: alignments.1,
+                discard: self.blk_cfg.discard,
+                write_zeroes: self.blk_cfg.write_zeroes,
+            };
+            let block_backend = match file {
+                Some(file) => create_block_backend(file, aio, conf.clone())?,
+                None => create_nbd_backend(aio, conf.clone())?,
+            };
+            let disk_size = block_backend.lock().unwrap().disk_size()?;
+
+            disk_sectors = disk_size >> SECTOR_SHIFT;
+            // The dirty bitmaps of the disk exported by an NBD server are not tracked.
+            if is_nbd {
+                self.dirty_bitmaps.detach();
+            } else {
+                self.dirty_bitmaps
+                    .attach(&self.blk_cfg.path_on_host, disk_size);
+            }
+            let resize_cb = self.gen_resize_cb(block_backend.clone());
+            self.block_backend = Some(block_backend);
+            register_block_device(BlockDevInfo {
+                prop: conf,
+                read_only: self.blk_cfg.read_only,
+                aio: self.blk_cfg.aio,
+                throttle: Some(self.throttle.clone()),
+                throttle_group: self.blk_cfg.throttle_group.as_ref().map(|g| g.id.clone()),
+                removable: false,
+                stats: self.stats.clone(),
+                resize: Some(resize_cb),
+                backup: Some(self.backup.clone()),
+                dirty_bitmaps: (!is_nbd).then(|| self.dirty_bitmaps.clone()),
+                medium: None,
+            });
+        } else {
+            unregister_block_device(&self.blk_cfg.id);
+            self.dirty_bitmaps.detach();
         }
-        self.state.config_space.capacity = self.disk_sectors;
+        self.disk_sectors.store(disk_sectors, Ordering::Release);
+        self.state.config_space.capacity = disk_sectors;
 
         Ok(())
     }
 
     fn unrealize(&mut self) -> Result<()> {
         MigrationManager::unregister_device_instance(BlockState::descriptor(), &self.blk_cfg.id);
+        unregister_block_device(&self.blk_cfg.id);
+        self.dirty_bitmaps.detach();
         Ok(())
     }
 
@@ -1192,7 +1525,8 @@ impl VirtioDevice for Block {
             return Err(anyhow!(VirtioError::DevConfigOverflow(offset, config_len)));
         }
 
-        let config_slice = self.state.config_space.as_bytes();
+        let config_space = self.config_space();
+        let config_slice = config_space.as_bytes();
         data.write_all(&config_slice[(offset as usize)..read_end])?;
 
         Ok(())
@@ -1223,41 +1557,47 @@ impl VirtioDevice for Block {
         queues: &[Arc<Mutex<Queue>>],
         queue_evts: Vec<Arc<EventFd>>,
     ) -> Result<()> {
-        self.interrupt_cb = Some(interrupt_cb.clone());
+        *self.interrupt_cb.lock().unwrap() = Some(interrupt_cb.clone());
+        if self.blk_cfg.uring.fixed_buffers && self.mem_listener.is_none() {
+            let listener = Arc::new(Mutex::new(FixedBuffersListener::new(
+                self.fixed_buffers.clone(),
+            )));
+            mem_space
+                .register_listener(listener.clone())
+                .with_context(|| "Failed to register memory listener of io_uring fixed buffers")?;
+            self.mem_listener = Some((mem_space.clone(), listener));
+        }
         for (index, queue) in queues.iter().enumerate() {
             if !queue.lock().unwrap().is_enabled() {
                 continue;
             }
             let (sender, receiver) = channel();
             let update_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
-            let aio = Box::new(Aio::new(
-                Arc::new(BlockIoHandler::complete_func),
-                self.blk_cfg.aio,
-            )?);
+            let throttle_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
+            let retry_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
             let driver_features = self.state.driver_features;
             let handler = BlockIoHandler {
                 queue: queue.clone(),
                 queue_evt: queue_evts[index].clone(),
                 mem_space: mem_space.clone(),
-                disk_image: self.disk_image.clone(),
-                req_align: self.req_align,
-                buf_align: self.buf_align,
-                disk_sectors: self.disk_sectors,
-                direct: self.blk_cfg.direct,
+                block_backend: self.block_backend.clone(),
+                disk_sectors: self.disk_sectors.clone(),
                 serial_num: self.blk_cfg.serial_num.clone(),
-                aio,
                 driver_features,
                 receiver,
                 update_evt: update_evt.clone(),
                 device_broken: self.broken.clone(),
                 interrupt_cb: interrupt_cb.clone(),
                 iothread: self.blk_cfg.iothread.clone(),
-                leak_bucket: match self.blk_cfg.iops {
-                    Some(iops) => Some(LeakBucket::new(iops)?),
-                    None => None,
-                },
+                throttle: self.throttle.clone(),
+                throttle_group: self.throttle_group.clone(),
+                throttle_evt: throttle_evt.clone(),
                 discard: self.blk_cfg.discard,
                 write_zeroes: self.blk_cfg.write_zeroes,
+                stats: self.stats.clone(),
+                io_error: Arc::new(IoErrorHandler::new(&self.blk_cfg, retry_evt.clone())),
+                backup: self.backup.clone(),
+                dirty_bitmaps: self.dirty_bitmaps.clone(),
             };
 
             let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
@@ -1267,8 +1607,22 @@ impl VirtioDevice for Block {
                 &mut self.deactivate_evts,
             )?;
             self.update_evts.push(update_evt);
+            self.throttle_evts.push(throttle_evt);
+            self.retry_evts.push(retry_evt);
             self.senders.push(sender);
         }
+        if let Some(block_backend) = self.block_backend.as_ref() {
+            let err_cb = self.gen_error_cb(interrupt_cb);
+            block_backend
+                .lock()
+                .unwrap()
+                .register_io_event(self.broken.clone(), err_cb)?;
+        } else {
+            warn!(
+                "No disk image when block device {} activate",
+                self.blk_cfg.id
+            );
+        }
         self.broken.store(false, Ordering::SeqCst);
 
         Ok(())
@@ -1276,12 +1630,37 @@ impl VirtioDevice for Block {
 
     fn deactivate(&mut self) -> Result<()> {
         unregister_event_helper(self.blk_cfg.iothread.as_ref(), &mut self.deactivate_evts)?;
+        if let Some(block_backend) = self.block_backend.as_ref() {
+            block_backend.lock().unwrap().unregister_io_event()?;
+        }
+        for throttle_evt in self.throttle_evts.drain(..) {
+            self.throttle.lock().unwrap().remove_waiter(&throttle_evt);
+            if let Some(group) = self.throttle_group.as_ref() {
+                group.lock().unwrap().remove_waiter(&throttle_evt);
+            }
+        }
+        // The stopped requests are dropped with the handlers.
+        for retry_evt in self.retry_evts.drain(..) {
+            cancel_stopped_block_requests(&retry_evt);
+        }
         self.update_evts.clear();
         self.senders.clear();
+        *self.interrupt_cb.lock().unwrap() = None;
+        if let Some((mem_space, listener)) = self.mem_listener.take() {
+            mem_space.unregister_listener(listener)?;
+        }
         Ok(())
     }
 
     fn update_config(&mut self, dev_config: Option<Arc<dyn ConfigCheck>>) -> Result<()> {
+        // The io events of the old backend are registered only if the device is activated.
+        let activated = !self.senders.is_empty();
+        if activated {
+            if let Some(block_backend) = self.block_backend.as_ref() {
+                block_backend.lock().unwrap().unregister_io_event()?;
+            }
+        }
+
         if let Some(conf) = dev_config {
             self.blk_cfg = conf
                 .as_any()
@@ -1296,16 +1675,25 @@ impl VirtioDevice for Block {
 
         self.realize()?;
 
+        if activated {
+            if let (Some(block_backend), Some(interrupt_cb)) = (
+                self.block_backend.as_ref(),
+                self.interrupt_cb.lock().unwrap().clone(),
+            ) {
+                let err_cb = self.gen_error_cb(interrupt_cb);
+                block_backend
+                    .lock()
+                    .unwrap()
+                    .register_io_event(self.broken.clone(), err_cb)?;
+            }
+        }
+
         for sender in &self.senders {
             sender
                 .send((
-                    self.disk_image.clone(),
-                    self.req_align,
-                    self.buf_align,
-                    self.disk_sectors,
+                    self.block_backend.clone(),
                     self.blk_cfg.serial_num.clone(),
-                    self.blk_cfg.direct,
-                    self.blk_cfg.aio,
+                    self.throttle_group.clone(),
                 ))
                 .with_context(|| VirtioError::ChannelSend("image fd".to_string()))?;
         }
@@ -1327,6 +1715,7 @@ unsafe impl Sync for Block {}
 impl StateTransfer for Block {
     fn get_state_vec(&self) -> migration::Result<Vec<u8>> {
         let mut state = self.state;
+        state.config_space = self.config_space();
         state.broken = self.broken.load(Ordering::SeqCst);
         Ok(state.as_bytes().to_vec())
     }
@@ -1353,7 +1742,9 @@ mod tests {
     use super::*;
     use crate::*;
     use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
-    use machine_manager::config::{IothreadConfig, VmConfig, DEFAULT_VIRTQUEUE_SIZE};
+    use machine_manager::config::{
+        IothreadConfig, ThrottleLimit, VmConfig, DEFAULT_VIRTQUEUE_SIZE,
+    };
     use std::sync::atomic::{AtomicU32, Ordering};
     use std::{thread, time::Duration};
     use vmm_sys_util::tempfile::TempFile;
@@ -1368,17 +1759,24 @@ mod tests {
         fn default() -> Self {
             Block {
                 blk_cfg: Default::default(),
-                disk_image: None,
-                req_align: 1,
-                buf_align: 1,
-                disk_sectors: 0,
+                block_backend: None,
+                disk_sectors: Arc::new(AtomicU64::new(0)),
                 state: BlockState::default(),
-                interrupt_cb: None,
+                interrupt_cb: Arc::new(Mutex::new(None)),
                 senders: Vec::new(),
                 update_evts: Vec::new(),
                 deactivate_evts: Vec::new(),
                 broken: Arc::new(AtomicBool::new(false)),
                 drive_files: Arc::new(Mutex::new(HashMap::new())),
+                stats: Arc::new(BlockStats::default()),
+                throttle: Arc::new(Mutex::new(Throttle::new(&Default::default()))),
+                throttle_group: None,
+                throttle_evts: Vec::new(),
+                retry_evts: Vec::new(),
+                backup: Arc::new(CopyBeforeWrite::default()),
+                dirty_bitmaps: Arc::new(BlockDirtyBitmaps::default()),
+                fixed_buffers: Arc::new(FixedBuffers::default()),
+                mem_listener: None,
             }
         }
     }
@@ -1414,12 +1812,12 @@ mod tests {
     fn test_block_init() {
         // New block device
         let mut block = Block::default();
-        assert_eq!(block.disk_sectors, 0);
+        assert_eq!(block.disk_sectors.load(Ordering::SeqCst), 0);
         assert_eq!(block.state.device_features, 0);
         assert_eq!(block.state.driver_features, 0);
         assert_eq!(block.state.config_space.as_bytes().len(), CONFIG_SPACE_SIZE);
-        assert!(block.disk_image.is_none());
-        assert!(block.interrupt_cb.is_none());
+        assert!(block.block_backend.is_none());
+        assert!(block.interrupt_cb.lock().unwrap().is_none());
         assert!(block.senders.is_empty());
 
         // Realize block device: create TempFile as backing file.
@@ -1563,7 +1961,12 @@ mod tests {
 
         // config iothread and iops
         block.blk_cfg.iothread = Some(thread_name);
-        block.blk_cfg.iops = Some(100);
+        block.blk_cfg.throttle.iops_total = ThrottleLimit::new(100);
+        block
+            .throttle
+            .lock()
+            .unwrap()
+            .set_config(&block.blk_cfg.throttle);
 
         VmConfig::add_drive_file(
             &mut block.drive_files.lock().unwrap(),
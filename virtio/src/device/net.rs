@@ -26,7 +26,8 @@ use crate::{
     Element, Queue, VirtioDevice, VirtioError, VirtioInterrupt, VirtioInterruptType, VirtioNetHdr,
     VirtioTrace, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
     VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET, VIRTIO_NET_CTRL_MAC_TABLE_SET,
-    VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
+    VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_HASH_CONFIG, VIRTIO_NET_CTRL_MQ_RSS_CONFIG,
+    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
     VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI,
     VIRTIO_NET_CTRL_RX_ALLUNI, VIRTIO_NET_CTRL_RX_NOBCAST, VIRTIO_NET_CTRL_RX_NOMULTI,
     VIRTIO_NET_CTRL_RX_NOUNI, VIRTIO_NET_CTRL_RX_PROMISC, VIRTIO_NET_CTRL_VLAN,
@@ -34,8 +35,9 @@ use crate::{
     VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_RX, VIRTIO_NET_F_CTRL_RX_EXTRA,
     VIRTIO_NET_F_CTRL_VLAN, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_ECN,
     VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO,
-    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC,
-    VIRTIO_NET_F_MQ, VIRTIO_NET_OK, VIRTIO_TYPE_NET,
+    VIRTIO_NET_F_HASH_REPORT, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_TSO6,
+    VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ, VIRTIO_NET_F_RSS, VIRTIO_NET_OK,
+    VIRTIO_TYPE_NET,
 };
 use address_space::{AddressSpace, RegionCache};
 use anyhow::{anyhow, bail, Context, Result};
@@ -44,18 +46,25 @@ use machine_manager::event_loop::{register_event_helper, unregister_event_helper
 use machine_manager::{
     config::{ConfigCheck, NetworkInterfaceConfig},
     event_loop::EventLoop,
+    qmp::qmp_schema::NetdevCaptureStartArgument,
+    temp_cleaner::TempCleaner,
 };
 use migration::{
     migration::Migratable, DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager,
     StateTransfer,
 };
 use migration_derive::{ByteCode, Desc};
+use util::af_xdp::create_af_xdp_backends;
+use util::aio::mem_from_buf;
 use util::byte_code::ByteCode;
 use util::loop_context::gen_delete_notifiers;
 use util::loop_context::{
     read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
 };
+use util::net_backend::{create_socket_backend, NetBackend};
 use util::num_ops::{read_u32, str_to_usize};
+use util::pcapng::{PacketDirection, PcapngWriter, DEFAULT_MAX_FILES, DEFAULT_SNAPLEN};
+use util::slirp::Slirp;
 use util::tap::{
     Tap, IFF_MULTI_QUEUE, TUN_F_CSUM, TUN_F_TSO4, TUN_F_TSO6, TUN_F_TSO_ECN, TUN_F_UFO,
 };
@@ -78,14 +87,56 @@ const NET_HDR_LENGTH: usize = mem::size_of::<VirtioNetHdr>();
 const VLAN_TAG_LENGTH: usize = 4;
 /// The offset of vlan tpid for 802.1Q tag.
 const VLAN_TPID_LENGTH: usize = 2;
-
-type SenderConfig = Option<Tap>;
+/// The length of hash value and hash report following the header of virtio net
+/// packet, which are only used if VIRTIO_NET_F_HASH_REPORT is negotiated.
+const NET_HASH_LENGTH: usize = 8;
+/// The max length of the headers used to calculate the hash of packet: ethernet
+/// header with vlan tag, IPv4 header with options and the ports of TCP/UDP.
+const HASH_HDR_LENGTH: usize = ETHERNET_HDR_LENGTH + VLAN_TAG_LENGTH + 60 + 4;
+/// The max length of the key of Toeplitz hash.
+const RSS_MAX_KEY_SIZE: u8 = 40;
+/// The max length of the indirection table for receive side scaling.
+const RSS_MAX_INDIRECTION_TABLE_LEN: u16 = 128;
+/// Hash types of the packet, the ones with IPv6 extension headers are not supported.
+const VIRTIO_NET_RSS_HASH_TYPE_IPV4: u32 = 1 << 0;
+const VIRTIO_NET_RSS_HASH_TYPE_TCPV4: u32 = 1 << 1;
+const VIRTIO_NET_RSS_HASH_TYPE_UDPV4: u32 = 1 << 2;
+const VIRTIO_NET_RSS_HASH_TYPE_IPV6: u32 = 1 << 3;
+const VIRTIO_NET_RSS_HASH_TYPE_TCPV6: u32 = 1 << 4;
+const VIRTIO_NET_RSS_HASH_TYPE_UDPV6: u32 = 1 << 5;
+const SUPPORTED_HASH_TYPES: u32 = VIRTIO_NET_RSS_HASH_TYPE_IPV4
+    | VIRTIO_NET_RSS_HASH_TYPE_TCPV4
+    | VIRTIO_NET_RSS_HASH_TYPE_UDPV4
+    | VIRTIO_NET_RSS_HASH_TYPE_IPV6
+    | VIRTIO_NET_RSS_HASH_TYPE_TCPV6
+    | VIRTIO_NET_RSS_HASH_TYPE_UDPV6;
+/// The reported type of the hash value.
+const VIRTIO_NET_HASH_REPORT_NONE: u16 = 0;
+const VIRTIO_NET_HASH_REPORT_IPV4: u16 = 1;
+const VIRTIO_NET_HASH_REPORT_TCPV4: u16 = 2;
+const VIRTIO_NET_HASH_REPORT_UDPV4: u16 = 3;
+const VIRTIO_NET_HASH_REPORT_IPV6: u16 = 4;
+const VIRTIO_NET_HASH_REPORT_TCPV6: u16 = 5;
+const VIRTIO_NET_HASH_REPORT_UDPV6: u16 = 6;
+/// Ether types and IP protocols used to classify the packet.
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+const ETH_P_8021Q: u16 = 0x8100;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+type SenderConfig = Option<Box<dyn NetBackend>>;
+/// The capture shared by the device and its handlers, None if it's not started.
+type SharedCapture = Arc<Mutex<Option<NetCapture>>>;
 
 /// The first default mac address.
 const FIRST_DEFAULT_MAC: [u8; MAC_ADDR_LEN] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
 /// Used to mark if the last byte of the mac address is used.
 static USED_MAC_TABLE: Lazy<Arc<Mutex<[i8; MAX_MAC_ADDR_NUM]>>> =
     Lazy::new(|| Arc::new(Mutex::new([0_i8; MAX_MAC_ADDR_NUM])));
+/// The packet captures of virtio-net devices, indexed by the id of netdev.
+static NET_CAPTURES: Lazy<Mutex<HashMap<String, SharedCapture>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
 /// Configuration of virtio-net devices.
 #[repr(C, packed)]
@@ -108,6 +159,40 @@ pub struct VirtioNetConfig {
 
 impl ByteCode for VirtioNetConfig {}
 
+/// Configuration of receive side scaling and hash reporting, which follows
+/// `VirtioNetConfig` in the config space. It's not migrated, as it's decided
+/// by the configuration of the device.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct VirtioNetRssConfig {
+    /// Maximum length of the key for receive side scaling and hash reporting.
+    pub rss_max_key_size: u8,
+    /// Maximum length of the indirection table for receive side scaling.
+    pub rss_max_indirection_table_length: u16,
+    /// Bit mask of the supported hash types.
+    pub supported_hash_types: u32,
+}
+
+impl ByteCode for VirtioNetRssConfig {}
+
+/// Capture of the frames received and sent by the guest.
+struct NetCapture {
+    writer: PcapngWriter,
+    /// Capture the frames received by the guest.
+    rx: bool,
+    /// Capture the frames sent by the guest.
+    tx: bool,
+}
+
+impl NetCapture {
+    fn is_captured(&self, direction: PacketDirection) -> bool {
+        match direction {
+            PacketDirection::Inbound => self.rx,
+            PacketDirection::Outbound => self.tx,
+        }
+    }
+}
+
 /// The control mode used for packet receive filtering.
 pub struct CtrlRxMode {
     /// If the device should receive all incoming packets.
@@ -157,6 +242,122 @@ struct CtrlMacInfo {
     multi_mac_of: bool,
 }
 
+/// The hash calculation and receive side scaling configured by the driver.
+#[derive(Default)]
+struct RssConfig {
+    /// Bit mask of the enabled hash types, no hash is calculated if it is 0.
+    hash_types: u32,
+    /// The rx queues indexed by the low bits of hash value, it is empty if
+    /// receive side scaling is disabled.
+    indirection_table: Vec<u16>,
+    /// The rx queue of the packets without hash value.
+    unclassified_queue: u16,
+    /// The key of Toeplitz hash.
+    key: Vec<u8>,
+}
+
+impl RssConfig {
+    /// Calculate the hash of the ethernet frame, return the hash value and the
+    /// reported hash type.
+    fn hash(&self, frame: &[u8]) -> (u32, u16) {
+        let none = (0, VIRTIO_NET_HASH_REPORT_NONE);
+        if self.hash_types == 0 || frame.len() < ETHERNET_HDR_LENGTH {
+            return none;
+        }
+
+        let mut offset = ETHERNET_HDR_LENGTH;
+        let mut ether_type = u16::from_be_bytes([frame[12], frame[13]]);
+        if ether_type == ETH_P_8021Q {
+            match frame.get(offset + VLAN_TPID_LENGTH..offset + VLAN_TAG_LENGTH) {
+                Some(tci) => ether_type = u16::from_be_bytes([tci[0], tci[1]]),
+                None => return none,
+            }
+            offset += VLAN_TAG_LENGTH;
+        }
+        let ip = &frame[offset..];
+
+        // The input of hash is source address, destination address, source port and
+        // destination port.
+        let mut input = Vec::with_capacity(36);
+        let (proto, ports, hash_types) = match ether_type {
+            ETH_P_IP if ip.len() >= 20 && ip[0] >> 4 == 4 => {
+                let ihl = usize::from(ip[0] & 0xf) * 4;
+                input.extend_from_slice(&ip[12..20]);
+                // The ports are only in the first fragment, skip all the fragments to
+                // keep the hash of one flow unchanged.
+                let fragment = u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff != 0;
+                let ports = ip.get(ihl..ihl + 4).filter(|_| ihl >= 20 && !fragment);
+                let hash_types = [
+                    (VIRTIO_NET_RSS_HASH_TYPE_TCPV4, VIRTIO_NET_HASH_REPORT_TCPV4),
+                    (VIRTIO_NET_RSS_HASH_TYPE_UDPV4, VIRTIO_NET_HASH_REPORT_UDPV4),
+                    (VIRTIO_NET_RSS_HASH_TYPE_IPV4, VIRTIO_NET_HASH_REPORT_IPV4),
+                ];
+                (ip[9], ports, hash_types)
+            }
+            ETH_P_IPV6 if ip.len() >= 40 && ip[0] >> 4 == 6 => {
+                input.extend_from_slice(&ip[8..40]);
+                let hash_types = [
+                    (VIRTIO_NET_RSS_HASH_TYPE_TCPV6, VIRTIO_NET_HASH_REPORT_TCPV6),
+                    (VIRTIO_NET_RSS_HASH_TYPE_UDPV6, VIRTIO_NET_HASH_REPORT_UDPV6),
+                    (VIRTIO_NET_RSS_HASH_TYPE_IPV6, VIRTIO_NET_HASH_REPORT_IPV6),
+                ];
+                (ip[6], ip.get(40..44), hash_types)
+            }
+            _ => return none,
+        };
+
+        let l4_type = match proto {
+            IPPROTO_TCP => Some(hash_types[0]),
+            IPPROTO_UDP => Some(hash_types[1]),
+            _ => None,
+        };
+        if let (Some((hash_type, report)), Some(ports)) = (l4_type, ports) {
+            if self.hash_types & hash_type != 0 {
+                input.extend_from_slice(ports);
+                return (toeplitz_hash(&self.key, &input), report);
+            }
+        }
+        let (hash_type, report) = hash_types[2];
+        if self.hash_types & hash_type != 0 {
+            return (toeplitz_hash(&self.key, &input), report);
+        }
+        none
+    }
+
+    /// Get the rx queue of the packet with the hash, return None if receive side
+    /// scaling is disabled.
+    fn steer(&self, hash: u32, report: u16) -> Option<usize> {
+        if self.indirection_table.is_empty() {
+            return None;
+        }
+        if report == VIRTIO_NET_HASH_REPORT_NONE {
+            return Some(usize::from(self.unclassified_queue));
+        }
+        let index = hash as usize & (self.indirection_table.len() - 1);
+        Some(usize::from(self.indirection_table[index]))
+    }
+}
+
+/// Toeplitz hash defined by Microsoft RSS specification: for each bit set in the
+/// input, xor the 32 bits of the key starting from that bit into the result.
+fn toeplitz_hash(key: &[u8], input: &[u8]) -> u32 {
+    let key_bit = |bit: usize| {
+        key.get(bit / 8)
+            .map_or(0, |b| u32::from(b >> (7 - bit % 8)) & 1)
+    };
+    let mut window = (0..32).fold(0_u32, |window, bit| window << 1 | key_bit(bit));
+    let mut hash = 0;
+    for (index, byte) in input.iter().enumerate() {
+        for bit in 0..8 {
+            if byte & (0x80 >> bit) != 0 {
+                hash ^= window;
+            }
+            window = window << 1 | key_bit(32 + index * 8 + bit);
+        }
+    }
+    hash
+}
+
 pub struct CtrlInfo {
     /// The control rx mode for packet receive filtering.
     rx_mode: CtrlRxMode,
@@ -164,6 +365,8 @@ pub struct CtrlInfo {
     mac_info: CtrlMacInfo,
     /// The map of all the vlan ids.
     vlan_map: HashMap<u16, u32>,
+    /// The hash calculation and receive side scaling.
+    rss: RssConfig,
     /// The net device status.
     state: Arc<Mutex<VirtioNetState>>,
 }
@@ -174,6 +377,7 @@ impl CtrlInfo {
             rx_mode: CtrlRxMode::default(),
             mac_info: CtrlMacInfo::default(),
             vlan_map: HashMap::new(),
+            rss: RssConfig::default(),
             state,
         }
     }
@@ -364,33 +568,144 @@ impl CtrlInfo {
         data_iovec: &mut Vec<ElemIovec>,
     ) -> u8 {
         let mut ack = VIRTIO_NET_OK;
-        if cmd as u16 == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET {
-            let mut queue_pairs: u16 = 0;
-            *data_iovec = get_buf_and_discard(mem_space, data_iovec, queue_pairs.as_mut_bytes())
-                .unwrap_or_else(|e| {
-                    error!("Failed to get queue pairs {:?}", e);
+        match cmd as u16 {
+            VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET => {
+                let mut queue_pairs: u16 = 0;
+                *data_iovec =
+                    get_buf_and_discard(mem_space, data_iovec, queue_pairs.as_mut_bytes())
+                        .unwrap_or_else(|e| {
+                            error!("Failed to get queue pairs {:?}", e);
+                            ack = VIRTIO_NET_ERR;
+                            Vec::new()
+                        });
+                if ack == VIRTIO_NET_ERR {
+                    return ack;
+                }
+
+                if !(VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN..=VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX)
+                    .contains(&queue_pairs)
+                {
+                    error!("Invalid queue pairs {}", queue_pairs);
                     ack = VIRTIO_NET_ERR;
-                    Vec::new()
-                });
-            if ack == VIRTIO_NET_ERR {
-                return ack;
+                } else {
+                    // Setting the queue pairs disables receive side scaling.
+                    self.rss.indirection_table.clear();
+                }
+            }
+            VIRTIO_NET_CTRL_MQ_RSS_CONFIG | VIRTIO_NET_CTRL_MQ_HASH_CONFIG => {
+                ack = self
+                    .handle_rss(mem_space, cmd as u16, data_iovec)
+                    .unwrap_or_else(|e| {
+                        error!("Failed to handle rss config {:?}", e);
+                        VIRTIO_NET_ERR
+                    });
             }
+            _ => {
+                error!("Invalid cmd {} when handling control mq", cmd);
+                ack = VIRTIO_NET_ERR;
+            }
+        }
 
-            if !(VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN..=VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX)
-                .contains(&queue_pairs)
+        ack
+    }
+
+    fn handle_rss(
+        &mut self,
+        mem_space: &AddressSpace,
+        cmd: u16,
+        data_iovec: &mut Vec<ElemIovec>,
+    ) -> Result<u8> {
+        let (driver_features, max_pairs) = {
+            let locked_state = self.state.lock().unwrap();
+            (
+                locked_state.driver_features,
+                cmp::max(locked_state.config_space.max_virtqueue_pairs, 1),
+            )
+        };
+        let steering = cmd == VIRTIO_NET_CTRL_MQ_RSS_CONFIG;
+        let feature = if steering {
+            VIRTIO_NET_F_RSS
+        } else {
+            VIRTIO_NET_F_HASH_REPORT
+        };
+        if !virtio_has_feature(driver_features, feature) {
+            error!("Feature {} is not negotiated for rss cmd {}", feature, cmd);
+            return Ok(VIRTIO_NET_ERR);
+        }
+
+        let mut hash_types: u32 = 0;
+        *data_iovec = get_buf_and_discard(mem_space, data_iovec, hash_types.as_mut_bytes())?;
+        let mut indirection_table = Vec::new();
+        let mut unclassified_queue: u16 = 0;
+        if steering {
+            let mut table_mask: u16 = 0;
+            *data_iovec = get_buf_and_discard(mem_space, data_iovec, table_mask.as_mut_bytes())?;
+            *data_iovec =
+                get_buf_and_discard(mem_space, data_iovec, unclassified_queue.as_mut_bytes())?;
+            let table_len = usize::from(table_mask) + 1;
+            if !table_len.is_power_of_two()
+                || table_len > usize::from(RSS_MAX_INDIRECTION_TABLE_LEN)
             {
-                error!("Invalid queue pairs {}", queue_pairs);
-                ack = VIRTIO_NET_ERR;
+                error!("Invalid indirection table length {}", table_len);
+                return Ok(VIRTIO_NET_ERR);
+            }
+            let mut table = vec![0_u8; table_len * mem::size_of::<u16>()];
+            *data_iovec = get_buf_and_discard(mem_space, data_iovec, &mut table)?;
+            indirection_table = table
+                .chunks_exact(mem::size_of::<u16>())
+                .map(|queue| u16::from_le_bytes([queue[0], queue[1]]))
+                .collect();
+            let mut max_tx_vq: u16 = 0;
+            *data_iovec = get_buf_and_discard(mem_space, data_iovec, max_tx_vq.as_mut_bytes())?;
+
+            if indirection_table
+                .iter()
+                .chain([unclassified_queue].iter())
+                .any(|queue| *queue >= max_pairs)
+                || max_tx_vq == 0
+                || max_tx_vq > max_pairs
+            {
+                error!(
+                    "Invalid rx queue in indirection table or max tx queue {}",
+                    max_tx_vq
+                );
+                return Ok(VIRTIO_NET_ERR);
             }
         } else {
-            error!(
-                "Control queue header command can't match {}",
-                VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET
-            );
-            ack = VIRTIO_NET_ERR;
+            // Reserved fields of hash config.
+            let mut reserved = [0_u8; 8];
+            *data_iovec = get_buf_and_discard(mem_space, data_iovec, &mut reserved)?;
         }
 
-        ack
+        let mut key_len: u8 = 0;
+        *data_iovec = get_buf_and_discard(mem_space, data_iovec, key_len.as_mut_bytes())?;
+        if key_len > RSS_MAX_KEY_SIZE {
+            error!("Invalid rss key length {}", key_len);
+            return Ok(VIRTIO_NET_ERR);
+        }
+        let mut key = vec![0_u8; usize::from(key_len)];
+        *data_iovec = get_buf_and_discard(mem_space, data_iovec, &mut key)?;
+
+        if hash_types & !SUPPORTED_HASH_TYPES != 0 {
+            error!("Unsupported hash types {:#x}", hash_types);
+            return Ok(VIRTIO_NET_ERR);
+        }
+        if hash_types != 0 && key.is_empty() {
+            error!("No rss key is provided for hash types {:#x}", hash_types);
+            return Ok(VIRTIO_NET_ERR);
+        }
+        self.rss = if hash_types == 0 {
+            RssConfig::default()
+        } else {
+            RssConfig {
+                hash_types,
+                indirection_table,
+                unclassified_queue,
+                key,
+            }
+        };
+
+        Ok(VIRTIO_NET_OK)
     }
 
     fn filter_packets(&mut self, buf: &[u8]) -> bool {
@@ -688,8 +1003,8 @@ impl RxVirtio {
 struct NetIoHandler {
     rx: RxVirtio,
     tx: TxVirtio,
-    tap: Option<Tap>,
-    tap_fd: RawFd,
+    backend: Option<Box<dyn NetBackend>>,
+    backend_fd: RawFd,
     mem_space: Arc<AddressSpace>,
     interrupt_cb: Arc<VirtioInterrupt>,
     driver_features: u64,
@@ -699,41 +1014,13 @@ struct NetIoHandler {
     is_listening: bool,
     ctrl_info: Arc<Mutex<CtrlInfo>>,
     queue_size: u16,
+    /// The rx queues of all the queue pairs, which the received packets are steered
+    /// to by receive side scaling.
+    rx_queues: Vec<Arc<Mutex<Queue>>>,
+    capture: SharedCapture,
 }
 
 impl NetIoHandler {
-    fn read_from_tap(iovecs: &[libc::iovec], tap: &mut Tap) -> i32 {
-        // SAFETY: the arguments of readv has been checked and is correct.
-        let size = unsafe {
-            libc::readv(
-                tap.as_raw_fd() as libc::c_int,
-                iovecs.as_ptr() as *const libc::iovec,
-                iovecs.len() as libc::c_int,
-            )
-        } as i32;
-        if size < 0 {
-            let e = std::io::Error::last_os_error();
-            if e.kind() == std::io::ErrorKind::WouldBlock {
-                return size;
-            }
-
-            // If the backend tap device is removed, readv returns less than 0.
-            // At this time, the content in the tap needs to be cleaned up.
-            // Here, read is called to process, otherwise handle_rx may be triggered all the time.
-            let mut buf = [0; 1024];
-            match tap.read(&mut buf) {
-                Ok(cnt) => error!("Failed to call readv but tap read is ok: cnt {}", cnt),
-                Err(e) => {
-                    // When the backend tap device is abnormally removed, read return EBADFD.
-                    error!("Failed to read tap: {:?}", e);
-                }
-            }
-            error!("Failed to call readv for net handle_rx: {:?}", e);
-        }
-
-        size
-    }
-
     fn get_libc_iovecs(
         mem_space: &Arc<AddressSpace>,
         cache: &Option<RegionCache>,
@@ -757,9 +1044,10 @@ impl NetIoHandler {
     fn handle_rx(&mut self) -> Result<()> {
         self.trace_request("Net".to_string(), "to rx".to_string());
         let mut queue = self.rx.queue.lock().unwrap();
+        let hash_report = virtio_has_feature(self.driver_features, VIRTIO_NET_F_HASH_REPORT);
 
         let mut rx_packets = 0;
-        while let Some(tap) = self.tap.as_mut() {
+        while let Some(backend) = self.backend.as_mut() {
             let elem = queue
                 .vring
                 .pop_avail(&self.mem_space, self.driver_features)
@@ -770,28 +1058,34 @@ impl NetIoHandler {
             } else if elem.in_iovec.is_empty() {
                 bail!("The length of in iovec is 0");
             }
-            let iovecs = NetIoHandler::get_libc_iovecs(
+            let guest_iovecs = NetIoHandler::get_libc_iovecs(
                 &self.mem_space,
                 queue.vring.get_cache(),
                 &elem.in_iovec,
             );
+            mark_dirty_iovecs(&guest_iovecs);
+            // The backend knows nothing about the hash fields following the header.
+            let iovecs = if hash_report {
+                iov_cut(&guest_iovecs, NET_HDR_LENGTH, NET_HASH_LENGTH)
+            } else {
+                guest_iovecs.clone()
+            };
 
-            if MigrationManager::is_active() {
-                // FIXME: mark dirty page needs to be managed by `AddressSpace` crate.
-                for iov in iovecs.iter() {
-                    // Mark vmm dirty page manually if live migration is active.
-                    MigrationManager::mark_dirty_log(iov.iov_base as u64, iov.iov_len as u64);
+            // Read the data from the backend.
+            let size = match backend.recv_frame(&iovecs) {
+                Ok(size) if size >= NET_HDR_LENGTH + ETHERNET_HDR_LENGTH + VLAN_TAG_LENGTH => size,
+                ret => {
+                    if let Err(e) = ret {
+                        if e.kind() != ErrorKind::WouldBlock {
+                            error!("Failed to receive frame for net handle_rx: {:?}", e);
+                        }
+                    }
+                    queue.vring.push_back();
+                    break;
                 }
-            }
-
-            // Read the data from the tap device.
-            let size = NetIoHandler::read_from_tap(&iovecs, tap);
-            if size < (NET_HDR_LENGTH + ETHERNET_HDR_LENGTH + VLAN_TAG_LENGTH) as i32 {
-                queue.vring.push_back();
-                break;
-            }
+            };
 
-            let mut buf = vec![0_u8; NET_HDR_LENGTH + ETHERNET_HDR_LENGTH + VLAN_TAG_LENGTH];
+            let mut buf = vec![0_u8; cmp::min(size, NET_HDR_LENGTH + HASH_HDR_LENGTH)];
             get_net_header(&iovecs, &mut buf).and_then(|size| {
                 if size != buf.len() {
                     bail!(
@@ -802,35 +1096,43 @@ impl NetIoHandler {
                 }
                 Ok(())
             })?;
-            if self
-                .ctrl_info
-                .lock()
-                .unwrap()
-                .filter_packets(&buf[NET_HDR_LENGTH..])
-            {
+            let mut ctrl_info = self.ctrl_info.lock().unwrap();
+            if ctrl_info.filter_packets(&buf[NET_HDR_LENGTH..]) {
                 queue.vring.push_back();
                 continue;
             }
+            let (hash, report) = ctrl_info.rss.hash(&buf[NET_HDR_LENGTH..]);
+            let target = ctrl_info.rss.steer(hash, report);
+            drop(ctrl_info);
+            self.capture_packet(&iovecs, size, PacketDirection::Inbound);
+
+            let mut size = size;
+            if hash_report {
+                let mut hash_buf = [0_u8; NET_HASH_LENGTH];
+                hash_buf[..4].copy_from_slice(&hash.to_le_bytes());
+                hash_buf[4..6].copy_from_slice(&report.to_le_bytes());
+                set_net_buf(&guest_iovecs, NET_HDR_LENGTH, &hash_buf)?;
+                size += NET_HASH_LENGTH;
+            }
 
-            queue
-                .vring
-                .add_used(&self.mem_space, elem.index, size as u32)
-                .with_context(|| {
-                    format!(
-                        "Failed to add used ring for net rx, index: {}, len: {}",
-                        elem.index, size
-                    )
-                })?;
-
-            if queue
-                .vring
-                .should_notify(&self.mem_space, self.driver_features)
-            {
-                (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&queue), false)
+            let target_queue = target
+                .and_then(|index| self.rx_queues.get(index))
+                .filter(|target_queue| !Arc::ptr_eq(target_queue, &self.rx.queue));
+            if let Some(target_queue) = target_queue {
+                // The buffer of this queue is reused after the packet is copied.
+                queue.vring.push_back();
+                self.steer_packet(target_queue, &guest_iovecs, size)?;
+            } else {
+                queue
+                    .vring
+                    .add_used(&self.mem_space, elem.index, size as u32)
                     .with_context(|| {
-                        VirtioError::InterruptTrigger("net", VirtioInterruptType::Vring)
+                        format!(
+                            "Failed to add used ring for net rx, index: {}, len: {}",
+                            elem.index, size
+                        )
                     })?;
-                self.trace_send_interrupt("Net".to_string());
+                self.notify_rx(&mut queue)?;
             }
 
             rx_packets += 1;
@@ -846,26 +1148,88 @@ impl NetIoHandler {
         Ok(())
     }
 
-    fn send_packets(&self, tap_fd: libc::c_int, iovecs: &[libc::iovec]) -> i8 {
-        loop {
-            // SAFETY: the arguments of writev has been checked and is correct.
-            let size = unsafe {
-                libc::writev(
-                    tap_fd,
-                    iovecs.as_ptr() as *const libc::iovec,
-                    iovecs.len() as libc::c_int,
+    /// Copy the received packet to the rx queue selected by receive side scaling, the
+    /// packet is dropped if that queue has no available buffer.
+    fn steer_packet(
+        &self,
+        target_queue: &Arc<Mutex<Queue>>,
+        iovecs: &[libc::iovec],
+        size: usize,
+    ) -> Result<()> {
+        let mut queue = target_queue.lock().unwrap();
+        if !queue.is_enabled() {
+            return Ok(());
+        }
+        let elem = queue
+            .vring
+            .pop_avail(&self.mem_space, self.driver_features)
+            .with_context(|| "Failed to pop avail ring for net rx steering")?;
+        if elem.desc_num == 0 {
+            return Ok(());
+        } else if elem.in_iovec.is_empty() {
+            bail!("The length of in iovec is 0");
+        }
+        let target_iovecs =
+            NetIoHandler::get_libc_iovecs(&self.mem_space, queue.vring.get_cache(), &elem.in_iovec);
+        if target_iovecs.iter().map(|iov| iov.iov_len).sum::<usize>() < size {
+            queue.vring.push_back();
+            return Ok(());
+        }
+        mark_dirty_iovecs(&target_iovecs);
+
+        let mut packet = vec![0_u8; size];
+        get_net_header(iovecs, &mut packet)?;
+        set_net_buf(&target_iovecs, 0, &packet)?;
+        queue
+            .vring
+            .add_used(&self.mem_space, elem.index, size as u32)
+            .with_context(|| {
+                format!(
+                    "Failed to add used ring for net rx steering, index: {}, len: {}",
+                    elem.index, size
                 )
-            };
-            if size < 0 {
-                let e = std::io::Error::last_os_error();
-                match e.kind() {
-                    ErrorKind::Interrupted => continue,
-                    ErrorKind::WouldBlock => return -1_i8,
-                    // Ignore other errors which can not be handled.
-                    _ => error!("Failed to call writev for net handle_tx: {:?}", e),
-                }
+            })?;
+        self.notify_rx(&mut queue)
+    }
+
+    fn notify_rx(&self, queue: &mut Queue) -> Result<()> {
+        if queue
+            .vring
+            .should_notify(&self.mem_space, self.driver_features)
+        {
+            (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(queue), false).with_context(
+                || VirtioError::InterruptTrigger("net", VirtioInterruptType::Vring),
+            )?;
+            self.trace_send_interrupt("Net".to_string());
+        }
+        Ok(())
+    }
+
+    /// Write the frame of `size` bytes in `iovecs` to the capture file, without the
+    /// virtio net header. The capture is stopped if it fails.
+    fn capture_packet(&self, iovecs: &[libc::iovec], size: usize, direction: PacketDirection) {
+        let mut locked_capture = self.capture.lock().unwrap();
+        let capture = match locked_capture.as_mut() {
+            Some(capture) if capture.is_captured(direction) => capture,
+            _ => return,
+        };
+        let frame_len = size.saturating_sub(NET_HDR_LENGTH);
+        let mut frame = vec![0_u8; cmp::min(frame_len, capture.writer.snaplen() as usize)];
+        let ret = get_net_header(&iov_cut(iovecs, 0, NET_HDR_LENGTH), &mut frame)
+            .and_then(|_| capture.writer.write_packet(&frame, frame_len, direction));
+        if let Err(e) = ret {
+            error!("Failed to capture packet, the capture is stopped: {:?}", e);
+            *locked_capture = None;
+        }
+    }
+
+    fn send_packets(backend: &mut dyn NetBackend, iovecs: &[libc::iovec]) -> i8 {
+        if let Err(e) = backend.send_frame(iovecs) {
+            match e.kind() {
+                ErrorKind::WouldBlock => return -1_i8,
+                // Ignore other errors which can not be handled.
+                _ => error!("Failed to send frame for net handle_tx: {:?}", e),
             }
-            break;
         }
         0_i8
     }
@@ -886,23 +1250,27 @@ impl NetIoHandler {
                 bail!("The length of out iovec is 0");
             }
 
-            let iovecs = NetIoHandler::get_libc_iovecs(
+            let mut iovecs = NetIoHandler::get_libc_iovecs(
                 &self.mem_space,
                 queue.vring.get_cache(),
                 &elem.out_iovec,
             );
-            let tap_fd = if let Some(tap) = self.tap.as_mut() {
-                tap.as_raw_fd() as libc::c_int
-            } else {
-                -1_i32
+            if virtio_has_feature(self.driver_features, VIRTIO_NET_F_HASH_REPORT) {
+                iovecs = iov_cut(&iovecs, NET_HDR_LENGTH, NET_HASH_LENGTH);
+            }
+            let blocked = match self.backend.as_mut() {
+                Some(backend) => NetIoHandler::send_packets(backend.as_mut(), &iovecs) == -1,
+                None => false,
             };
-            if tap_fd != -1 && self.send_packets(tap_fd, &iovecs) == -1 {
+            if blocked {
                 queue.vring.push_back();
                 self.tx.queue_evt.write(1).with_context(|| {
                     "Failed to trigger tx queue event when writev blocked".to_string()
                 })?;
                 return Ok(());
             }
+            let size = iovecs.iter().map(|iov| iov.iov_len).sum();
+            self.capture_packet(&iovecs, size, PacketDirection::Outbound);
 
             queue
                 .vring
@@ -934,17 +1302,17 @@ impl NetIoHandler {
 
     fn update_evt_handler(net_io: &Arc<Mutex<Self>>) -> Vec<EventNotifier> {
         let mut locked_net_io = net_io.lock().unwrap();
-        locked_net_io.tap = match locked_net_io.receiver.recv() {
-            Ok(tap) => tap,
+        locked_net_io.backend = match locked_net_io.receiver.recv() {
+            Ok(backend) => backend,
             Err(e) => {
-                error!("Failed to receive the tap {:?}", e);
+                error!("Failed to receive the backend {:?}", e);
                 None
             }
         };
-        let old_tap_fd = locked_net_io.tap_fd;
-        locked_net_io.tap_fd = -1;
-        if let Some(tap) = locked_net_io.tap.as_ref() {
-            locked_net_io.tap_fd = tap.as_raw_fd();
+        let old_backend_fd = locked_net_io.backend_fd;
+        locked_net_io.backend_fd = -1;
+        if let Some(backend) = locked_net_io.backend.as_ref() {
+            locked_net_io.backend_fd = backend.as_raw_fd();
         }
 
         let mut notifiers_fds = vec![
@@ -952,8 +1320,8 @@ impl NetIoHandler {
             locked_net_io.rx.queue_evt.as_raw_fd(),
             locked_net_io.tx.queue_evt.as_raw_fd(),
         ];
-        if old_tap_fd != -1 {
-            notifiers_fds.push(old_tap_fd);
+        if old_backend_fd != -1 {
+            notifiers_fds.push(old_backend_fd);
         }
         let mut notifiers = gen_delete_notifiers(&notifiers_fds);
         drop(locked_net_io);
@@ -981,6 +1349,63 @@ fn get_net_header(iovec: &[libc::iovec], buf: &mut [u8]) -> Result<usize> {
     Ok(end)
 }
 
+/// Write `buf` to `iovec` from `offset`, return the written number of bytes.
+fn set_net_buf(iovec: &[libc::iovec], mut offset: usize, buf: &[u8]) -> Result<usize> {
+    let mut written = 0;
+    for elem in iovec {
+        if offset >= elem.iov_len {
+            offset -= elem.iov_len;
+            continue;
+        }
+        let len = cmp::min(elem.iov_len - offset, buf.len() - written);
+        mem_from_buf(
+            &buf[written..written + len],
+            elem.iov_base as u64 + offset as u64,
+        )?;
+        written += len;
+        offset = 0;
+        if written >= buf.len() {
+            break;
+        }
+    }
+    Ok(written)
+}
+
+/// Get the iovec without the `len` bytes from `offset`.
+fn iov_cut(iovec: &[libc::iovec], offset: usize, len: usize) -> Vec<libc::iovec> {
+    let mut res = Vec::new();
+    let mut start = 0;
+    for elem in iovec {
+        let end = start + elem.iov_len;
+        if start < offset {
+            res.push(libc::iovec {
+                iov_base: elem.iov_base,
+                iov_len: cmp::min(end, offset) - start,
+            });
+        }
+        if end > offset + len {
+            let skip = (offset + len).saturating_sub(start);
+            res.push(libc::iovec {
+                // SAFETY: skip is less than the length of this iovec.
+                iov_base: unsafe { elem.iov_base.add(skip) },
+                iov_len: elem.iov_len - skip,
+            });
+        }
+        start = end;
+    }
+    res
+}
+
+fn mark_dirty_iovecs(iovecs: &[libc::iovec]) {
+    if MigrationManager::is_active() {
+        // FIXME: mark dirty page needs to be managed by `AddressSpace` crate.
+        for iov in iovecs.iter() {
+            // Mark vmm dirty page manually if live migration is active.
+            MigrationManager::mark_dirty_log(iov.iov_base as u64, iov.iov_len as u64);
+        }
+    }
+}
+
 fn build_event_notifier(
     fd: RawFd,
     handler: Option<Rc<NotifierCallback>>,
@@ -1026,11 +1451,11 @@ impl EventNotifierHelper for NetIoHandler {
             if locked_net_io.device_broken.load(Ordering::SeqCst) {
                 return None;
             }
-            if let Some(tap) = locked_net_io.tap.as_ref() {
+            if let Some(backend) = locked_net_io.backend.as_ref() {
                 if !locked_net_io.is_listening {
                     let notifier = vec![EventNotifier::new(
                         NotifierOperation::Resume,
-                        tap.as_raw_fd(),
+                        backend.as_raw_fd(),
                         None,
                         EventSet::IN | EventSet::EDGE_TRIGGERED,
                         Vec::new(),
@@ -1075,9 +1500,9 @@ impl EventNotifierHelper for NetIoHandler {
             EventSet::IN,
         ));
 
-        // Register event notifier for tap.
+        // Register event notifier for backend.
         let cloned_net_io = net_io.clone();
-        if let Some(tap) = locked_net_io.tap.as_ref() {
+        if let Some(backend) = locked_net_io.backend.as_ref() {
             let handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
                 let mut locked_net_io = cloned_net_io.lock().unwrap();
                 if locked_net_io.device_broken.load(Ordering::SeqCst) {
@@ -1085,7 +1510,7 @@ impl EventNotifierHelper for NetIoHandler {
                 }
 
                 if let Err(ref e) = locked_net_io.handle_rx() {
-                    error!("Failed to handle rx(tap event), {:?}", e);
+                    error!("Failed to handle rx(backend event), {:?}", e);
                     report_virtio_error(
                         locked_net_io.interrupt_cb.clone(),
                         locked_net_io.driver_features,
@@ -1094,11 +1519,11 @@ impl EventNotifierHelper for NetIoHandler {
                     return None;
                 }
 
-                if let Some(tap) = locked_net_io.tap.as_ref() {
+                if let Some(backend) = locked_net_io.backend.as_ref() {
                     if locked_net_io.rx.queue_full {
                         let notifier = vec![EventNotifier::new(
                             NotifierOperation::Park,
-                            tap.as_raw_fd(),
+                            backend.as_raw_fd(),
                             None,
                             EventSet::IN | EventSet::EDGE_TRIGGERED,
                             Vec::new(),
@@ -1110,9 +1535,9 @@ impl EventNotifierHelper for NetIoHandler {
                 }
                 None
             });
-            let tap_fd = tap.as_raw_fd();
+            let backend_fd = backend.as_raw_fd();
             notifiers.push(build_event_notifier(
-                tap_fd,
+                backend_fd,
                 Some(handler),
                 NotifierOperation::AddShared,
                 EventSet::IN | EventSet::EDGE_TRIGGERED,
@@ -1156,6 +1581,19 @@ pub struct Net {
     broken: Arc<AtomicBool>,
     /// The information about control command.
     ctrl_info: Option<Arc<Mutex<CtrlInfo>>>,
+    /// User mode network stack, which is used instead of tap.
+    slirp: Option<Arc<Mutex<Slirp>>>,
+    /// The device end of the link to user mode network stack.
+    slirp_link: Option<Tap>,
+    /// Eventfds of the user mode network stack.
+    slirp_evts: Vec<RawFd>,
+    /// Backends of socket or AF_XDP which are used instead of tap, the socket
+    /// connected to other VMs has only one queue pair.
+    backends: Option<Vec<Box<dyn NetBackend>>>,
+    /// Capture of the frames, which is started and stopped by QMP.
+    capture: SharedCapture,
+    /// Configuration of receive side scaling in the config space.
+    rss_config: VirtioNetRssConfig,
 }
 
 impl Default for Net {
@@ -1169,6 +1607,12 @@ impl Default for Net {
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
             ctrl_info: None,
+            slirp: None,
+            slirp_link: None,
+            slirp_evts: Vec::new(),
+            backends: None,
+            capture: Arc::new(Mutex::new(None)),
+            rss_config: VirtioNetRssConfig::default(),
         }
     }
 }
@@ -1184,7 +1628,104 @@ impl Net {
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
             ctrl_info: None,
+            slirp: None,
+            slirp_link: None,
+            slirp_evts: Vec::new(),
+            backends: None,
+            capture: Arc::new(Mutex::new(None)),
+            rss_config: VirtioNetRssConfig::default(),
+        }
+    }
+
+    /// Create the user mode network stack if the netdev is configured as user.
+    fn realize_slirp(&mut self) -> Result<()> {
+        let config = match &self.net_cfg.user {
+            Some(config) => config,
+            None => return self.release_slirp(),
+        };
+        // For microvm which will call realize() twice for one virtio-net-device.
+        if self.slirp.is_some() {
+            return Ok(());
         }
+
+        let (slirp, link) = Slirp::new(config, NET_HDR_LENGTH)
+            .with_context(|| "Failed to create user mode network")?;
+        let slirp = Arc::new(Mutex::new(slirp));
+        let notifiers = EventNotifierHelper::internal_notifiers(slirp.clone());
+        register_event_helper(
+            notifiers,
+            self.net_cfg.iothread.as_ref(),
+            &mut self.slirp_evts,
+        )?;
+        self.slirp = Some(slirp);
+        self.slirp_link = Some(Tap { file: link });
+        Ok(())
+    }
+
+    fn release_slirp(&mut self) -> Result<()> {
+        if self.slirp.take().is_some() {
+            unregister_event_helper(self.net_cfg.iothread.as_ref(), &mut self.slirp_evts)?;
+            self.slirp_link = None;
+        }
+        Ok(())
+    }
+
+    /// Stop the capture and remove it from the captures of netdev.
+    fn release_capture(&mut self) {
+        *self.capture.lock().unwrap() = None;
+        let mut captures = NET_CAPTURES.lock().unwrap();
+        if let Some(capture) = captures.get(&self.net_cfg.netdev_id) {
+            if Arc::ptr_eq(capture, &self.capture) {
+                captures.remove(&self.net_cfg.netdev_id);
+            }
+        }
+    }
+
+    /// Create the backends if the netdev is configured as stream, dgram or af-xdp.
+    fn realize_backends(&mut self) -> Result<()> {
+        if self.net_cfg.socket.is_none() && self.net_cfg.af_xdp.is_none() {
+            self.backends = None;
+            return Ok(());
+        }
+        if self.backends.is_some() {
+            return Ok(());
+        }
+
+        if let Some(config) = &self.net_cfg.socket {
+            let socket = create_socket_backend(config, NET_HDR_LENGTH)
+                .with_context(|| "Failed to create socket netdev")?;
+            if let Some(path) = config.socket_path() {
+                // Add file to temporary pool, so it could be cleaned when vm exit.
+                TempCleaner::add_path(path.to_string());
+            }
+            self.backends = Some(vec![socket]);
+        } else if let Some(config) = &self.net_cfg.af_xdp {
+            let queue_pairs = self.net_cfg.queues / 2;
+            self.backends = Some(
+                create_af_xdp_backends(config, queue_pairs, NET_HDR_LENGTH)
+                    .with_context(|| "Failed to create af-xdp netdev")?,
+            );
+        }
+        Ok(())
+    }
+
+    /// Get the backend of the queue pair `index`.
+    fn backend(&self, index: usize) -> Result<Option<Box<dyn NetBackend>>> {
+        let backend = match (&self.backends, &self.taps) {
+            (Some(backends), _) => Some(
+                backends
+                    .get(index)
+                    .with_context(|| format!("Failed to get index {} backend", index))?
+                    .try_clone()?,
+            ),
+            (None, Some(taps)) => Some(
+                taps.get(index)
+                    .with_context(|| format!("Failed to get index {} tap", index))?
+                    .try_clone()?,
+            ),
+            (None, None) => None,
+        };
+        Ok(backend)
     }
 }
 
@@ -1208,6 +1749,49 @@ pub fn build_device_config_space(device_config: &mut VirtioNetConfig, mac: &str)
     1 << VIRTIO_NET_F_MAC
 }
 
+/// Start capturing the frames of the virtio-net device which uses netdev `args.netdev`.
+pub fn qmp_netdev_capture_start(args: &NetdevCaptureStartArgument) -> Result<()> {
+    let capture = get_net_capture(&args.netdev)?;
+    let (rx, tx) = match args.direction.as_deref() {
+        None | Some("both") => (true, true),
+        Some("rx") => (true, false),
+        Some("tx") => (false, true),
+        Some(direction) => bail!(
+            "Invalid capture direction {}, it should be rx, tx or both",
+            direction
+        ),
+    };
+    let mut locked_capture = capture.lock().unwrap();
+    if locked_capture.is_some() {
+        bail!("The capture of netdev {} is already started", args.netdev);
+    }
+    let writer = PcapngWriter::new(
+        &args.file,
+        args.snaplen.unwrap_or(DEFAULT_SNAPLEN),
+        args.max_size.unwrap_or(0),
+        args.max_files.unwrap_or(DEFAULT_MAX_FILES),
+    )?;
+    *locked_capture = Some(NetCapture { writer, rx, tx });
+    Ok(())
+}
+
+/// Stop capturing the frames of the virtio-net device which uses netdev `netdev`.
+pub fn qmp_netdev_capture_stop(netdev: &str) -> Result<()> {
+    if get_net_capture(netdev)?.lock().unwrap().take().is_none() {
+        bail!("The capture of netdev {} is not started", netdev);
+    }
+    Ok(())
+}
+
+fn get_net_capture(netdev: &str) -> Result<SharedCapture> {
+    NET_CAPTURES
+        .lock()
+        .unwrap()
+        .get(netdev)
+        .cloned()
+        .with_context(|| format!("Netdev {} is not used by any virtio-net device", netdev))
+}
+
 /// Mark the mac table used or free.
 fn mark_mac_table(mac: &[u8], used: bool) {
     if mac[..MAC_ADDR_LEN - 1] != FIRST_DEFAULT_MAC[..MAC_ADDR_LEN - 1] {
@@ -1359,6 +1943,15 @@ impl VirtioDevice for Net {
             );
         }
 
+        self.realize_slirp()?;
+        self.realize_backends()?;
+        if !self.net_cfg.netdev_id.is_empty() {
+            NET_CAPTURES
+                .lock()
+                .unwrap()
+                .insert(self.net_cfg.netdev_id.clone(), self.capture.clone());
+        }
+
         let mut locked_state = self.state.lock().unwrap();
         locked_state.device_features = 1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_NET_F_CSUM
@@ -1384,9 +1977,21 @@ impl VirtioDevice for Net {
         {
             locked_state.device_features |= 1 << VIRTIO_NET_F_MQ;
             locked_state.config_space.max_virtqueue_pairs = queue_pairs;
+            // Receive side scaling and hash reporting are handled by the device, instead
+            // of the backend.
+            locked_state.device_features |= 1 << VIRTIO_NET_F_RSS | 1 << VIRTIO_NET_F_HASH_REPORT;
+            self.rss_config = VirtioNetRssConfig {
+                rss_max_key_size: RSS_MAX_KEY_SIZE,
+                rss_max_indirection_table_length: RSS_MAX_INDIRECTION_TABLE_LEN,
+                supported_hash_types: SUPPORTED_HASH_TYPES,
+            };
         }
 
-        if !self.net_cfg.host_dev_name.is_empty() {
+        if let Some(link) = &self.slirp_link {
+            self.taps = Some(vec![link.clone()]);
+        } else if self.backends.is_some() {
+            self.taps = None;
+        } else if !self.net_cfg.host_dev_name.is_empty() {
             self.taps = None;
             self.taps = create_tap(None, Some(&self.net_cfg.host_dev_name), queue_pairs)
                 .with_context(|| "Failed to open tap with file path")?;
@@ -1408,8 +2013,19 @@ impl VirtioDevice for Net {
             self.taps = None;
         }
 
-        // Using the first tap to test if all the taps have ufo.
-        if let Some(tap) = self.taps.as_ref().map(|t| &t[0]) {
+        if self.slirp.is_some() || self.backends.is_some() {
+            // User mode network stack, the peer of socket and the host interface of
+            // AF_XDP only handle the complete packets with checksum.
+            locked_state.device_features &= !(1 << VIRTIO_NET_F_CSUM
+                | 1 << VIRTIO_NET_F_GUEST_CSUM
+                | 1 << VIRTIO_NET_F_GUEST_TSO4
+                | 1 << VIRTIO_NET_F_GUEST_TSO6
+                | 1 << VIRTIO_NET_F_GUEST_UFO
+                | 1 << VIRTIO_NET_F_HOST_TSO4
+                | 1 << VIRTIO_NET_F_HOST_TSO6
+                | 1 << VIRTIO_NET_F_HOST_UFO);
+        } else if let Some(tap) = self.taps.as_ref().map(|t| &t[0]) {
+            // Using the first tap to test if all the taps have ufo.
             if !tap.has_ufo() {
                 locked_state.device_features &=
                     !(1 << VIRTIO_NET_F_GUEST_UFO | 1 << VIRTIO_NET_F_HOST_UFO);
@@ -1434,6 +2050,9 @@ impl VirtioDevice for Net {
     }
 
     fn unrealize(&mut self) -> Result<()> {
+        self.release_slirp()?;
+        self.backends = None;
+        self.release_capture();
         mark_mac_table(&self.state.lock().unwrap().config_space.mac, false);
         MigrationManager::unregister_device_instance(
             VirtioNetState::descriptor(),
@@ -1478,9 +2097,9 @@ impl VirtioDevice for Net {
 
     /// Read data of config from guest.
     fn read_config(&self, offset: u64, mut data: &mut [u8]) -> Result<()> {
-        let locked_state = self.state.lock().unwrap();
-        let config_slice = locked_state.config_space.as_bytes();
-        let config_len = config_slice.len() as u64;
+        let mut config = self.state.lock().unwrap().config_space.as_bytes().to_vec();
+        config.extend_from_slice(self.rss_config.as_bytes());
+        let config_len = config.len() as u64;
         if offset
             .checked_add(data.len() as u64)
             .filter(|&end| end <= config_len)
@@ -1488,7 +2107,7 @@ impl VirtioDevice for Net {
         {
             return Err(anyhow!(VirtioError::DevConfigOverflow(offset, config_len)));
         }
-        data.write_all(&config_slice[offset as usize..(offset as usize + data.len())])?;
+        data.write_all(&config[offset as usize..(offset as usize + data.len())])?;
 
         Ok(())
     }
@@ -1561,6 +2180,9 @@ impl VirtioDevice for Net {
 
         let mut senders = Vec::new();
         let queue_pairs = queue_num / 2;
+        let rx_queues: Vec<Arc<Mutex<Queue>>> = (0..queue_pairs)
+            .map(|index| queues[index * 2].clone())
+            .collect();
         for index in 0..queue_pairs {
             let rx_queue = queues[index * 2].clone();
             let rx_queue_evt = queue_evts[index * 2].clone();
@@ -1570,8 +2192,9 @@ impl VirtioDevice for Net {
             let (sender, receiver) = channel();
             senders.push(sender);
 
-            if let Some(tap) = self.taps.as_ref().map(|t| t[index].clone()) {
-                tap.set_offload(flags)
+            if let Some(tap) = self.taps.as_ref().filter(|_| self.slirp.is_none()) {
+                tap[index]
+                    .set_offload(flags)
                     .with_context(|| "Failed to set tap offload")?;
             }
 
@@ -1579,8 +2202,8 @@ impl VirtioDevice for Net {
             let mut handler = NetIoHandler {
                 rx: RxVirtio::new(rx_queue, rx_queue_evt),
                 tx: TxVirtio::new(tx_queue, tx_queue_evt),
-                tap: self.taps.as_ref().map(|t| t[index].clone()),
-                tap_fd: -1,
+                backend: self.backend(index)?,
+                backend_fd: -1,
                 mem_space: mem_space.clone(),
                 interrupt_cb: interrupt_cb.clone(),
                 driver_features,
@@ -1590,9 +2213,11 @@ impl VirtioDevice for Net {
                 is_listening: true,
                 ctrl_info: ctrl_info.clone(),
                 queue_size: self.queue_size(),
+                rx_queues: rx_queues.clone(),
+                capture: self.capture.clone(),
             };
-            if let Some(tap) = &handler.tap {
-                handler.tap_fd = tap.as_raw_fd();
+            if let Some(backend) = &handler.backend {
+                handler.backend_fd = backend.as_raw_fd();
             }
 
             let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
@@ -1610,6 +2235,12 @@ impl VirtioDevice for Net {
     }
 
     fn update_config(&mut self, dev_config: Option<Arc<dyn ConfigCheck>>) -> Result<()> {
+        // The new netdev always starts with a fresh user mode network stack.
+        let user_net = self.slirp.is_some();
+        self.release_slirp()?;
+        self.backends = None;
+        self.release_capture();
+
         if let Some(conf) = dev_config {
             self.net_cfg = conf
                 .as_any()
@@ -1621,7 +2252,7 @@ impl VirtioDevice for Net {
             // The features about offload is included in bits 0 to 31.
             let features = self.get_driver_features(0_u32);
             let flags = get_tap_offload_flags(features as u64);
-            if let Some(taps) = &self.taps {
+            if let Some(taps) = self.taps.as_ref().filter(|_| !user_net) {
                 for (_, tap) in taps.iter().enumerate() {
                     tap.set_offload(flags)
                         .with_context(|| "Failed to set tap offload")?;
@@ -1635,20 +2266,9 @@ impl VirtioDevice for Net {
 
         if let Some(senders) = &self.senders {
             for (index, sender) in senders.iter().enumerate() {
-                match self.taps.take() {
-                    Some(taps) => {
-                        let tap = taps
-                            .get(index)
-                            .cloned()
-                            .with_context(|| format!("Failed to get index {} tap", index))?;
-                        sender
-                            .send(Some(tap))
-                            .with_context(|| VirtioError::ChannelSend("tap fd".to_string()))?;
-                    }
-                    None => sender
-                        .send(None)
-                        .with_context(|| "Failed to send status of None to channel".to_string())?,
-                }
+                sender
+                    .send(self.backend(index)?)
+                    .with_context(|| VirtioError::ChannelSend("backend".to_string()))?;
             }
 
             for update_evt in &self.update_evts {
@@ -1741,7 +2361,7 @@ mod tests {
         // test boundary condition of offset and data parameters
         let locked_state = net.state.lock().unwrap();
         let device_config = locked_state.config_space.as_bytes();
-        let len = device_config.len() as u64;
+        let len = (device_config.len() + mem::size_of::<VirtioNetRssConfig>()) as u64;
         drop(locked_state);
 
         let mut data: Vec<u8> = vec![0; 10];
@@ -1819,6 +2439,153 @@ mod tests {
         assert_eq!(ctrl_info.filter_packets(&buf), false);
     }
 
+    /// Build the ethernet frame of TCP packet with the ip header.
+    fn build_tcp_frame(ether_type: u16, ip_hdr: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0_u8; ETHERNET_HDR_LENGTH];
+        frame[12..14].copy_from_slice(&ether_type.to_be_bytes());
+        frame.extend_from_slice(ip_hdr);
+        frame.extend_from_slice(&2794_u16.to_be_bytes());
+        frame.extend_from_slice(&1766_u16.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_net_rss_hash() {
+        // The verification suite of Microsoft RSS specification.
+        let key = [
+            0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3,
+            0x8f, 0xb0, 0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3,
+            0x80, 0x30, 0xf2, 0x0c, 0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+        ];
+        let mut rss = RssConfig {
+            hash_types: SUPPORTED_HASH_TYPES,
+            indirection_table: Vec::new(),
+            unclassified_queue: 0,
+            key: key.to_vec(),
+        };
+
+        let mut ipv4_hdr = vec![0_u8; 20];
+        ipv4_hdr[0] = 0x45;
+        ipv4_hdr[9] = IPPROTO_TCP;
+        ipv4_hdr[12..16].copy_from_slice(&[66, 9, 149, 187]);
+        ipv4_hdr[16..20].copy_from_slice(&[161, 142, 100, 80]);
+        let frame = build_tcp_frame(ETH_P_IP, &ipv4_hdr);
+        assert_eq!(rss.hash(&frame), (0x51ccc178, VIRTIO_NET_HASH_REPORT_TCPV4));
+        // The fragment has no hash of ports.
+        let mut fragment = frame.clone();
+        fragment[ETHERNET_HDR_LENGTH + 6] = 0x20;
+        assert_eq!(
+            rss.hash(&fragment),
+            (0x323e8fc2, VIRTIO_NET_HASH_REPORT_IPV4)
+        );
+
+        let mut ipv6_hdr = vec![0_u8; 40];
+        ipv6_hdr[0] = 0x60;
+        ipv6_hdr[6] = IPPROTO_TCP;
+        let src: std::net::Ipv6Addr = "3ffe:2501:200:1fff::7".parse().unwrap();
+        let dst: std::net::Ipv6Addr = "3ffe:2501:200:3::1".parse().unwrap();
+        ipv6_hdr[8..24].copy_from_slice(&src.octets());
+        ipv6_hdr[24..40].copy_from_slice(&dst.octets());
+        let frame = build_tcp_frame(ETH_P_IPV6, &ipv6_hdr);
+        assert_eq!(rss.hash(&frame), (0x40207d3d, VIRTIO_NET_HASH_REPORT_TCPV6));
+
+        // Only the hash of address is enabled.
+        rss.hash_types = VIRTIO_NET_RSS_HASH_TYPE_IPV6;
+        assert_eq!(rss.hash(&frame), (0x2cc18cd5, VIRTIO_NET_HASH_REPORT_IPV6));
+        rss.hash_types = VIRTIO_NET_RSS_HASH_TYPE_IPV4;
+        assert_eq!(rss.hash(&frame), (0, VIRTIO_NET_HASH_REPORT_NONE));
+
+        // The packet with vlan tag.
+        let mut vlan_frame = frame[..12].to_vec();
+        vlan_frame.extend_from_slice(&[0x81, 0x00, 0x00, 0x01]);
+        vlan_frame.extend_from_slice(&frame[12..]);
+        rss.hash_types = SUPPORTED_HASH_TYPES;
+        assert_eq!(
+            rss.hash(&vlan_frame),
+            (0x40207d3d, VIRTIO_NET_HASH_REPORT_TCPV6)
+        );
+
+        // Steering is disabled without indirection table.
+        assert_eq!(rss.steer(0x40207d3d, VIRTIO_NET_HASH_REPORT_TCPV6), None);
+        rss.indirection_table = vec![0, 1, 2, 3];
+        rss.unclassified_queue = 1;
+        assert_eq!(rss.steer(0x40207d3d, VIRTIO_NET_HASH_REPORT_TCPV6), Some(1));
+        assert_eq!(rss.steer(0x2cc18cd6, VIRTIO_NET_HASH_REPORT_IPV6), Some(2));
+        assert_eq!(rss.steer(0, VIRTIO_NET_HASH_REPORT_NONE), Some(1));
+    }
+
+    #[test]
+    fn test_net_iov_cut() {
+        let mut buf = [0_u8; 32];
+        let base = buf.as_mut_ptr();
+        let (head, tail) = buf.split_at_mut(10);
+        let iovecs = [
+            libc::iovec {
+                iov_base: head.as_mut_ptr() as *mut libc::c_void,
+                iov_len: head.len(),
+            },
+            libc::iovec {
+                iov_base: tail.as_mut_ptr() as *mut libc::c_void,
+                iov_len: tail.len(),
+            },
+        ];
+
+        // The hole crosses the two iovecs.
+        let cut = iov_cut(&iovecs, 8, 4);
+        assert_eq!(cut.len(), 2);
+        assert_eq!((cut[0].iov_base, cut[0].iov_len), (iovecs[0].iov_base, 8));
+        assert_eq!(cut[1].iov_base as usize, base as usize + 12);
+        assert_eq!(cut[1].iov_len, 20);
+
+        // The hole is inside one iovec.
+        let cut = iov_cut(&iovecs, 12, 8);
+        assert_eq!(cut.len(), 3);
+        assert_eq!(cut[1].iov_len, 2);
+        assert_eq!(cut[2].iov_base as usize, base as usize + 20);
+        assert_eq!(cut[2].iov_len, 12);
+
+        assert_eq!(set_net_buf(&iovecs, 8, &[1_u8; 4]).unwrap(), 4);
+        assert_eq!(set_net_buf(&iovecs, 30, &[2_u8; 4]).unwrap(), 2);
+        assert_eq!(buf[7..13], [0, 1, 1, 1, 1, 0]);
+        assert_eq!(buf[30..], [2, 2]);
+    }
+
+    #[test]
+    fn test_net_capture() {
+        let file = "/tmp/test_net_capture.pcapng";
+        let mut net = Net::new(NetworkInterfaceConfig {
+            netdev_id: "netdev-capture".to_string(),
+            mac: Some("52:54:00:12:34:99".to_string()),
+            ..Default::default()
+        });
+        let mut args = NetdevCaptureStartArgument {
+            netdev: "netdev-capture".to_string(),
+            file: file.to_string(),
+            ..Default::default()
+        };
+        // The netdev is not used by any device.
+        assert!(qmp_netdev_capture_start(&args).is_err());
+
+        net.realize().unwrap();
+        args.direction = Some("in".to_string());
+        assert!(qmp_netdev_capture_start(&args).is_err());
+        args.direction = Some("tx".to_string());
+        qmp_netdev_capture_start(&args).unwrap();
+        assert!(qmp_netdev_capture_start(&args).is_err());
+        let locked_capture = net.capture.lock().unwrap();
+        let capture = locked_capture.as_ref().unwrap();
+        assert!(!capture.is_captured(PacketDirection::Inbound));
+        assert!(capture.is_captured(PacketDirection::Outbound));
+        drop(locked_capture);
+
+        qmp_netdev_capture_stop("netdev-capture").unwrap();
+        assert!(qmp_netdev_capture_stop("netdev-capture").is_err());
+        assert!(net.capture.lock().unwrap().is_none());
+        net.release_capture();
+        assert!(qmp_netdev_capture_start(&args).is_err());
+        fs::remove_file(file).unwrap();
+    }
+
     #[test]
     fn test_net_config_space() {
         let mut net_config = VirtioNetConfig::default();
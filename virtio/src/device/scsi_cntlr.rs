@@ -21,22 +21,25 @@ use std::sync::{Arc, Mutex};
 use anyhow::{anyhow, bail, Context, Result};
 
 use crate::{
-    iov_to_buf, report_virtio_error, ElemIovec, Element, Queue, VirtioDevice, VirtioError,
-    VirtioInterrupt, VirtioInterruptType, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC,
-    VIRTIO_F_VERSION_1, VIRTIO_TYPE_SCSI,
+    iov_to_buf, report_virtio_error, virtio_has_feature, ElemIovec, Element, Queue, VirtioDevice,
+    VirtioError, VirtioInterrupt, VirtioInterruptType, VIRTIO_F_RING_EVENT_IDX,
+    VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_SCSI_F_HOTPLUG, VIRTIO_TYPE_SCSI,
 };
 use address_space::{AddressSpace, GuestAddress};
+use block_backend::BlockIoErrorCallback;
 use devices::ScsiBus::{
-    aio_complete_cb, ScsiBus, ScsiCompleteCb, ScsiRequest, ScsiRequestOps, ScsiSense, ScsiXferMode,
-    CHECK_CONDITION, EMULATE_SCSI_OPS, SCSI_CMD_BUF_SIZE, SCSI_SENSE_INVALID_OPCODE,
+    ScsiBus, ScsiRequest, ScsiRequestOps, ScsiSense, ScsiXferMode, CHECK_CONDITION,
+    EMULATE_SCSI_OPS, SCSI_CMD_BUF_SIZE, SCSI_SENSE_INVALID_OPCODE,
+    SCSI_SENSE_REPORTED_LUNS_CHANGED,
 };
+use devices::ScsiDisk::ScsiDevice;
 use log::{debug, error, info, warn};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
 use machine_manager::{
     config::{ScsiCntlrConfig, VIRTIO_SCSI_MAX_LUN, VIRTIO_SCSI_MAX_TARGET},
     event_loop::EventLoop,
 };
-use util::aio::{Aio, Iovec};
+use util::aio::Iovec;
 use util::byte_code::ByteCode;
 use util::loop_context::{
     read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
@@ -76,6 +79,18 @@ pub const VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET: u32 = 5;
 pub const VIRTIO_SCSI_T_TMF_QUERY_TASK: u32 = 6;
 pub const VIRTIO_SCSI_T_TMF_QUERY_TASK_SET: u32 = 7;
 
+/// Event types of the event queue.
+/// The bus is changed, such as a lun is attached or detached.
+const VIRTIO_SCSI_T_TRANSPORT_RESET: u32 = 1;
+/// Set in the event type if some events are dropped for lack of buffers.
+const VIRTIO_SCSI_T_EVENTS_MISSED: u32 = 0x8000_0000;
+
+/// Reasons of the transport reset event.
+/// The lun is attached and the driver should rescan it.
+const VIRTIO_SCSI_EVT_RESET_RESCAN: u32 = 1;
+/// The lun is detached.
+const VIRTIO_SCSI_EVT_RESET_REMOVED: u32 = 2;
+
 /// Command-specific response values.
 /// The request was completed and the status byte if filled with a SCSI status code.
 const VIRTIO_SCSI_S_OK: u8 = 0;
@@ -116,6 +131,14 @@ pub struct ScsiCntlrState {
     config_space: VirtioScsiConfig,
 }
 
+/// Resources of the activated controller which are used by the device hotplug.
+struct ScsiHotplugContext {
+    /// Handler of the event queue to report the hotplug events.
+    event_handler: Arc<Mutex<ScsiEventQueueHandler>>,
+    /// The interrupt callback function.
+    interrupt_cb: Arc<VirtioInterrupt>,
+}
+
 /// Virtio Scsi Controller device structure.
 pub struct ScsiCntlr {
     /// Configuration of the virtio scsi controller.
@@ -128,6 +151,8 @@ pub struct ScsiCntlr {
     deactivate_evts: Vec<RawFd>,
     /// Device is broken or not.
     broken: Arc<AtomicBool>,
+    /// Hotplug context, only exists when the controller is activated.
+    hotplug_ctx: Mutex<Option<ScsiHotplugContext>>,
 }
 
 impl ScsiCntlr {
@@ -138,7 +163,84 @@ impl ScsiCntlr {
             bus: None,
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
+            hotplug_ctx: Mutex::new(None),
+        }
+    }
+
+    /// Attach the realized scsi device to the bus. If the controller is activated, the
+    /// device is hotplugged and the guest is notified to rescan the lun.
+    pub fn attach_device(&self, device: Arc<Mutex<ScsiDevice>>) -> Result<()> {
+        let (target, lun) = {
+            let locked_dev = device.lock().unwrap();
+            (locked_dev.config.target, locked_dev.config.lun)
+        };
+        let bus = self.bus.as_ref().unwrap();
+        let mut locked_bus = bus.lock().unwrap();
+        if locked_bus.devices.contains_key(&(target, lun)) {
+            bail!("Wrong! Two scsi devices have the same scsi-id and lun");
+        }
+        device.lock().unwrap().parent_bus = Arc::downgrade(bus);
+
+        let locked_ctx = self.hotplug_ctx.lock().unwrap();
+        if let Some(ctx) = locked_ctx.as_ref() {
+            let err_cb = self.gen_error_cb(ctx.interrupt_cb.clone());
+            device
+                .lock()
+                .unwrap()
+                .register_io_event(self.broken.clone(), err_cb)?;
+            report_luns_changed(&locked_bus);
+        }
+        locked_bus.devices.insert((target, lun), device);
+        drop(locked_bus);
+
+        if let Some(ctx) = locked_ctx.as_ref() {
+            ctx.event_handler.lock().unwrap().send_event(
+                VIRTIO_SCSI_T_TRANSPORT_RESET,
+                target,
+                lun,
+                VIRTIO_SCSI_EVT_RESET_RESCAN,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Detach the scsi device from the bus and notify the guest that the lun is removed.
+    pub fn detach_device(&self, target: u8, lun: u16) -> Result<()> {
+        let bus = self.bus.as_ref().unwrap();
+        let mut locked_bus = bus.lock().unwrap();
+        let device = locked_bus
+            .devices
+            .remove(&(target, lun))
+            .with_context(|| format!("Scsi device {}:{} is not found", target, lun))?;
+
+        let locked_ctx = self.hotplug_ctx.lock().unwrap();
+        if locked_ctx.is_some() {
+            report_luns_changed(&locked_bus);
         }
+        drop(locked_bus);
+
+        let mut locked_dev = device.lock().unwrap();
+        locked_dev.unregister_io_event()?;
+        locked_dev.unrealize();
+        drop(locked_dev);
+
+        if let Some(ctx) = locked_ctx.as_ref() {
+            ctx.event_handler.lock().unwrap().send_event(
+                VIRTIO_SCSI_T_TRANSPORT_RESET,
+                target,
+                lun,
+                VIRTIO_SCSI_EVT_RESET_REMOVED,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn gen_error_cb(&self, interrupt_cb: Arc<VirtioInterrupt>) -> BlockIoErrorCallback {
+        let cloned_features = self.state.driver_features;
+        let clone_broken = self.broken.clone();
+        Arc::new(move || {
+            report_virtio_error(interrupt_cb.clone(), cloned_features, &clone_broken);
+        })
     }
 }
 
@@ -164,17 +266,24 @@ impl VirtioDevice for ScsiCntlr {
         self.state.config_space.seg_max = self.queue_size() as u32 - 2;
         self.state.config_space.max_target = VIRTIO_SCSI_MAX_TARGET;
         self.state.config_space.max_lun = VIRTIO_SCSI_MAX_LUN as u32;
+        self.state.config_space.event_info_size = size_of::<VirtioScsiEvent>() as u32;
         // num_queues: request queues number.
         self.state.config_space.num_queues = self.config.queues;
 
         self.state.device_features |= (1_u64 << VIRTIO_F_VERSION_1)
             | (1_u64 << VIRTIO_F_RING_EVENT_IDX)
-            | (1_u64 << VIRTIO_F_RING_INDIRECT_DESC);
+            | (1_u64 << VIRTIO_F_RING_INDIRECT_DESC)
+            | (1_u64 << VIRTIO_SCSI_F_HOTPLUG);
 
         Ok(())
     }
 
     fn unrealize(&mut self) -> Result<()> {
+        if let Some(bus) = self.bus.as_ref() {
+            for device in bus.lock().unwrap().devices.values() {
+                device.lock().unwrap().unrealize();
+            }
+        }
         Ok(())
     }
 
@@ -276,16 +385,16 @@ impl VirtioDevice for ScsiCntlr {
         // Register event notifier for event queue.
         let event_queue = queues[1].clone();
         let event_queue_evt = queue_evts[1].clone();
-        let event_handler = ScsiEventQueueHandler {
-            _queue: event_queue,
+        let event_handler = Arc::new(Mutex::new(ScsiEventQueueHandler {
+            queue: event_queue,
             queue_evt: event_queue_evt,
-            _mem_space: mem_space.clone(),
-            _interrupt_cb: interrupt_cb.clone(),
-            _driver_features: self.state.driver_features,
+            mem_space: mem_space.clone(),
+            interrupt_cb: interrupt_cb.clone(),
+            driver_features: self.state.driver_features,
             device_broken: self.broken.clone(),
-        };
-        let notifiers =
-            EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(event_handler)));
+            events_dropped: false,
+        }));
+        let notifiers = EventNotifierHelper::internal_notifiers(event_handler.clone());
         register_event_helper(
             notifiers,
             self.config.iothread.as_ref(),
@@ -307,9 +416,6 @@ impl VirtioDevice for ScsiCntlr {
 
             let notifiers =
                 EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(cmd_handler)));
-            if notifiers.is_empty() {
-                bail!("Error in creating scsi device aio!");
-            }
 
             register_event_helper(
                 notifiers,
@@ -317,13 +423,35 @@ impl VirtioDevice for ScsiCntlr {
                 &mut self.deactivate_evts,
             )?;
         }
+
+        // Register event notifier for the block backends of scsi devices.
+        let bus = self.bus.as_ref().unwrap().lock().unwrap();
+        for device in bus.devices.values() {
+            let err_cb = self.gen_error_cb(interrupt_cb.clone());
+            device
+                .lock()
+                .unwrap()
+                .register_io_event(self.broken.clone(), err_cb)?;
+        }
+        drop(bus);
+        *self.hotplug_ctx.lock().unwrap() = Some(ScsiHotplugContext {
+            event_handler,
+            interrupt_cb,
+        });
         self.broken.store(false, Ordering::SeqCst);
 
         Ok(())
     }
 
     fn deactivate(&mut self) -> Result<()> {
-        unregister_event_helper(self.config.iothread.as_ref(), &mut self.deactivate_evts)
+        *self.hotplug_ctx.lock().unwrap() = None;
+        unregister_event_helper(self.config.iothread.as_ref(), &mut self.deactivate_evts)?;
+        if let Some(bus) = self.bus.as_ref() {
+            for device in bus.lock().unwrap().devices.values() {
+                device.lock().unwrap().unregister_io_event()?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -737,19 +865,32 @@ impl EventNotifierHelper for ScsiCtrlQueueHandler {
     }
 }
 
+/// Event of the event queue.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioScsiEvent {
+    event: u32,
+    lun: [u8; 8],
+    reason: u32,
+}
+
+impl ByteCode for VirtioScsiEvent {}
+
 pub struct ScsiEventQueueHandler {
     /// The Event virtqueue.
-    _queue: Arc<Mutex<Queue>>,
+    queue: Arc<Mutex<Queue>>,
     /// EventFd for the Event virtqueue.
     queue_evt: Arc<EventFd>,
     /// The address space to which the scsi HBA belongs.
-    _mem_space: Arc<AddressSpace>,
+    mem_space: Arc<AddressSpace>,
     /// The interrupt callback function.
-    _interrupt_cb: Arc<VirtioInterrupt>,
+    interrupt_cb: Arc<VirtioInterrupt>,
     /// Bit mask of features negotiated by the backend and the frontend.
-    _driver_features: u64,
+    driver_features: u64,
     /// Device is broken or not.
     device_broken: Arc<AtomicBool>,
+    /// Some events are dropped as there is no buffer in the event queue.
+    events_dropped: bool,
 }
 
 impl EventNotifierHelper for ScsiEventQueueHandler {
@@ -777,10 +918,94 @@ impl EventNotifierHelper for ScsiEventQueueHandler {
 
 impl ScsiEventQueueHandler {
     fn handle_event(&mut self) -> Result<()> {
+        // Tell the guest that some events are missed once it provides new buffers.
+        if self.events_dropped {
+            self.send_event(0, 0, 0, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Report the event of the lun to the guest. If there is no buffer in the event
+    /// queue, the event is dropped and VIRTIO_SCSI_T_EVENTS_MISSED is reported later.
+    fn send_event(&mut self, event: u32, target: u8, lun: u16, reason: u32) -> Result<()> {
+        if !virtio_has_feature(self.driver_features, VIRTIO_SCSI_F_HOTPLUG)
+            || self.device_broken.load(Ordering::SeqCst)
+        {
+            return Ok(());
+        }
+
+        let mut queue_lock = self.queue.lock().unwrap();
+        let elem = queue_lock
+            .vring
+            .pop_avail(&self.mem_space, self.driver_features)
+            .with_context(|| "Failed to pop avail ring for scsi event")?;
+        if elem.desc_num == 0 {
+            self.events_dropped = true;
+            return Ok(());
+        }
+        if elem.in_iovec.is_empty()
+            || (elem.in_iovec[0].len as usize) < size_of::<VirtioScsiEvent>()
+        {
+            bail!("Invalid buffer for scsi event, desc num {}", elem.desc_num);
+        }
+
+        let mut scsi_event = VirtioScsiEvent {
+            event,
+            reason,
+            ..Default::default()
+        };
+        if self.events_dropped {
+            scsi_event.event |= VIRTIO_SCSI_T_EVENTS_MISSED;
+            self.events_dropped = false;
+        }
+        if event != 0 {
+            // Single level lun format, see virtio_scsi_get_lun_id.
+            scsi_event.lun[0] = 1;
+            scsi_event.lun[1] = target;
+            if lun >= 256 {
+                scsi_event.lun[2] = (lun >> 8) as u8 | 0x40;
+            }
+            scsi_event.lun[3] = (lun & 0xff) as u8;
+        }
+        self.mem_space
+            .write_object(&scsi_event, elem.in_iovec[0].addr)
+            .with_context(|| "Failed to write the scsi event")?;
+        queue_lock
+            .vring
+            .add_used(
+                &self.mem_space,
+                elem.index,
+                size_of::<VirtioScsiEvent>() as u32,
+            )
+            .with_context(|| {
+                format!("Failed to add used ring(scsi event), index {}", elem.index)
+            })?;
+
+        if queue_lock
+            .vring
+            .should_notify(&self.mem_space, self.driver_features)
+        {
+            (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&queue_lock), false)
+                .with_context(|| {
+                    VirtioError::InterruptTrigger("scsi event", VirtioInterruptType::Vring)
+                })?;
+        }
+
         Ok(())
     }
 }
 
+/// Raise REPORTED LUNS DATA HAS CHANGED unit attention on the devices of the bus after
+/// a lun is attached or detached.
+fn report_luns_changed(bus: &ScsiBus) {
+    for device in bus.devices.values() {
+        let mut locked_dev = device.lock().unwrap();
+        if locked_dev.unit_attention.is_none() {
+            locked_dev.unit_attention = Some(SCSI_SENSE_REPORTED_LUNS_CHANGED);
+        }
+    }
+}
+
 impl ScsiRequestOps for CmdQueueRequest {
     fn scsi_request_complete_cb(&mut self, status: u8, scsisense: Option<ScsiSense>) -> Result<()> {
         if let Some(sense) = scsisense {
@@ -792,6 +1017,24 @@ impl ScsiRequestOps for CmdQueueRequest {
 
         Ok(())
     }
+
+    fn scsi_request_complete_passthrough(
+        &mut self,
+        status: u8,
+        sense: &[u8],
+        resid: u32,
+    ) -> Result<()> {
+        // The sense data of the host device is returned to the guest as it is.
+        let sense_len = cmp::min(sense.len(), VIRTIO_SCSI_SENSE_DEFAULT_SIZE);
+        self.resp.sense[..sense_len].copy_from_slice(&sense[..sense_len]);
+        self.resp.sense_len = sense_len as u32;
+        self.resp.resid = resid;
+        self.resp.response = VIRTIO_SCSI_S_OK;
+        self.resp.status = status;
+        self.complete()?;
+
+        Ok(())
+    }
 }
 
 //   lun: [u8, 8]
@@ -839,56 +1082,11 @@ impl EventNotifierHelper for ScsiCmdQueueHandler {
         });
         notifiers.push(build_event_notifier(h_locked.queue_evt.as_raw_fd(), h));
 
-        // Register event notifier for device aio.
-        let locked_bus = h_locked.scsibus.lock().unwrap();
-        for device in locked_bus.devices.values() {
-            let mut locked_device = device.lock().unwrap();
-
-            let aio = if let Ok(engine_aio) =
-                Aio::new(Arc::new(aio_complete_cb), locked_device.config.aio_type)
-            {
-                engine_aio
-            } else {
-                return Vec::new();
-            };
-            let dev_aio = Arc::new(Mutex::new(aio));
-            let dev_aio_h = dev_aio.clone();
-            locked_device.aio = Some(dev_aio.clone());
-
-            let h_clone = handler.clone();
-            let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
-                read_fd(fd);
-                let mut h_lock = h_clone.lock().unwrap();
-                if h_lock.device_broken.load(Ordering::SeqCst) {
-                    return None;
-                }
-                if let Err(ref e) = h_lock.aio_complete_handler(&dev_aio_h) {
-                    error!("Failed to handle aio {:?}", e);
-                }
-                None
-            });
-            notifiers.push(build_event_notifier(
-                (*dev_aio).lock().unwrap().fd.as_raw_fd(),
-                h,
-            ));
-        }
-
         notifiers
     }
 }
 
 impl ScsiCmdQueueHandler {
-    fn aio_complete_handler(&mut self, aio: &Arc<Mutex<Aio<ScsiCompleteCb>>>) -> Result<bool> {
-        aio.lock().unwrap().handle_complete().map_err(|e| {
-            report_virtio_error(
-                self.interrupt_cb.clone(),
-                self.driver_features,
-                &self.device_broken,
-            );
-            e
-        })
-    }
-
     fn handle_cmd(&mut self) -> Result<()> {
         let result = self.handle_cmd_queue_requests();
         if result.is_err() {
@@ -133,6 +133,10 @@ pub const VIRTIO_NET_F_CTRL_RX_EXTRA: u32 = 20;
 pub const VIRTIO_NET_F_MQ: u32 = 22;
 /// Set Mac Address through control channel.
 pub const VIRTIO_NET_F_CTRL_MAC_ADDR: u32 = 23;
+/// Device can report the hash value and type of each received packet.
+pub const VIRTIO_NET_F_HASH_REPORT: u32 = 57;
+/// Device supports receive side scaling with Toeplitz hash.
+pub const VIRTIO_NET_F_RSS: u32 = 60;
 /// Configuration cols and rows are valid.
 pub const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
 /// Maximum size of any single segment is in size_max.
@@ -200,6 +204,10 @@ pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u16 = 0;
 pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN: u16 = 1;
 /// The maximum pairs of multiple queue.
 pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX: u16 = 0x8000;
+/// The driver configures receive side scaling.
+pub const VIRTIO_NET_CTRL_MQ_RSS_CONFIG: u16 = 1;
+/// The driver configures the hash calculation for hash reporting.
+pub const VIRTIO_NET_CTRL_MQ_HASH_CONFIG: u16 = 2;
 /// Support more than one virtqueue.
 pub const VIRTIO_BLK_F_MQ: u32 = 12;
 
@@ -161,6 +161,16 @@ pub trait VringOps {
 
     /// Get the region cache information of the SplitVring.
     fn get_cache(&self) -> &Option<RegionCache>;
+
+    /// Assemble an IO request element with the descriptor chain whose head is `index`,
+    /// without touching the available vring. It is used to resubmit the requests which
+    /// were popped but not completed, e.g. by the previous vhost-user backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `sys_mem` - Address space to which the vring belongs.
+    /// * `index` - Index of the head descriptor in the virqueue descriptor table.
+    fn get_desc_chain(&mut self, sys_mem: &Arc<AddressSpace>, index: u16) -> Result<Element>;
 }
 
 /// Virtio queue.
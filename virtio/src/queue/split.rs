@@ -117,6 +117,28 @@ impl QueueConfig {
     pub fn reset(&mut self) {
         *self = Self::new(self.max_size);
     }
+
+    /// Get the next index which can be popped in the available vring.
+    pub fn get_next_avail(&self) -> u16 {
+        self.next_avail.0
+    }
+
+    /// Get the next index which can be pushed in the used vring.
+    pub fn get_next_used(&self) -> u16 {
+        self.next_used.0
+    }
+
+    /// Restore the next indexes of the available vring and the used vring, e.g. when
+    /// a vhost-user backend takes over the vring from the previous backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `next_avail` - The next index which can be popped in the available vring.
+    /// * `next_used` - The next index which can be pushed in the used vring.
+    pub fn set_next_idx(&mut self, next_avail: u16, next_used: u16) {
+        self.next_avail = Wrapping(next_avail);
+        self.next_used = Wrapping(next_used);
+    }
 }
 
 /// Virtio used element.
@@ -836,6 +858,27 @@ impl VringOps for SplitVring {
     fn get_cache(&self) -> &Option<RegionCache> {
         &self.cache
     }
+
+    fn get_desc_chain(&mut self, sys_mem: &Arc<AddressSpace>, index: u16) -> Result<Element> {
+        let desc = SplitVringDesc::new(
+            sys_mem,
+            self.addr_cache.desc_table_host,
+            self.actual_size(),
+            index,
+            &mut self.cache,
+        )?;
+        let desc_info = DescInfo {
+            table_host: self.addr_cache.desc_table_host,
+            size: self.actual_size(),
+            index,
+            desc,
+        };
+        let mut element = Element::new(index);
+        SplitVringDesc::get_element(sys_mem, &desc_info, &mut self.cache, &mut element)
+            .with_context(|| format!("Failed to get element from descriptor chain {}", index))?;
+
+        Ok(element)
+    }
 }
 
 #[cfg(test)]
@@ -435,6 +435,7 @@ mod tests {
     fn test_vhost_net_realize() {
         let net1 = NetworkInterfaceConfig {
             id: "eth1".to_string(),
+            netdev_id: "".to_string(),
             host_dev_name: "tap1".to_string(),
             mac: Some("1F:2C:3E:4A:5B:6D".to_string()),
             vhost_type: Some("vhost-kernel".to_string()),
@@ -445,6 +446,9 @@ mod tests {
             mq: false,
             socket_path: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            user: None,
+            socket: None,
+            af_xdp: None,
         };
         let conf = vec![net1];
         let confs = Some(conf);
@@ -457,6 +461,7 @@ mod tests {
 
         let net1 = NetworkInterfaceConfig {
             id: "eth0".to_string(),
+            netdev_id: "".to_string(),
             host_dev_name: "".to_string(),
             mac: Some("1A:2B:3C:4D:5E:6F".to_string()),
             vhost_type: Some("vhost-kernel".to_string()),
@@ -467,6 +472,9 @@ mod tests {
             mq: false,
             socket_path: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            user: None,
+            socket: None,
+            af_xdp: None,
         };
         let conf = vec![net1];
         let confs = Some(conf);